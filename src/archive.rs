@@ -0,0 +1,208 @@
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::hashing::{self, HashOptions};
+
+/// What separates an archive's stored path from the path of a file inside
+/// it, as in `/backup/2019.zip!/photos/a.jpg`.
+pub const MEMBER_MARKER: char = '!';
+
+/// The kinds of archive `--scan-archives` looks inside.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl Kind {
+    fn of(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".zip") {
+            Some(Self::Zip)
+        } else if name.ends_with(".tar") {
+            Some(Self::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::TarGz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether the file at `path` is an archive `hash_members` can read, going
+/// by its name.
+pub fn is_archive(path: &Path) -> bool {
+    Kind::of(path).is_some()
+}
+
+/// The stored path of the file at `inner` (as the archive names it) inside
+/// the archive stored as `archive`.
+pub fn member_path(archive: &str, inner: &str) -> String {
+    let sep = std::path::MAIN_SEPARATOR;
+    let inner = inner
+        .trim_start_matches(['/', '\\'])
+        .replace('/', &sep.to_string());
+    format!("{archive}{MEMBER_MARKER}{sep}{inner}")
+}
+
+/// A file inside an archive.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Member {
+    /// Its path as the archive names it
+    pub name: String,
+    pub hash: String,
+    pub size: u64,
+}
+
+/// Hash every regular file inside the zip, tar or gzipped tar archive at
+/// `path`, in the order the archive lists them. Directories, links and
+/// device entries are left out, and so are archives within the archive:
+/// their own bytes are hashed, not their contents. Files are decompressed as
+/// they are hashed, so none has to fit in memory.
+pub fn hash_members(path: &Path, opts: &HashOptions) -> Result<Vec<Member>> {
+    let kind = Kind::of(path).context("not a zip or tar archive")?;
+    let file = BufReader::new(File::open(path)?);
+    match kind {
+        Kind::Zip => zip_members(file, opts),
+        Kind::Tar => tar_members(file, opts),
+        Kind::TarGz => tar_members(flate2::read::MultiGzDecoder::new(file), opts),
+    }
+}
+
+fn zip_members(file: BufReader<File>, opts: &HashOptions) -> Result<Vec<Member>> {
+    let mut archive = zip::ZipArchive::new(file)?;
+    let mut members = Vec::new();
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        if !entry.is_file() {
+            continue;
+        }
+        let name = entry.name().to_string();
+        let hash = hashing::compute_reader_hash(&mut entry, opts)
+            .with_context(|| format!("couldn't read {name}"))?;
+        members.push(Member {
+            name,
+            hash,
+            size: entry.size(),
+        });
+    }
+    Ok(members)
+}
+
+fn tar_members(reader: impl Read, opts: &HashOptions) -> Result<Vec<Member>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut members = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let size = entry.size();
+        let hash = hashing::compute_reader_hash(&mut entry, opts)
+            .with_context(|| format!("couldn't read {name}"))?;
+        members.push(Member { name, hash, size });
+    }
+    Ok(members)
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn write_zip(path: &Path, files: &[(&str, &[u8])]) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        zip.add_directory("dir/", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        for (name, data) in files {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn tar_bytes(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, *data).unwrap();
+        }
+        tar.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_is_archive_goes_by_name() {
+        assert!(is_archive(Path::new("/b/Backup.ZIP")));
+        assert!(is_archive(Path::new("/b/a.tar")));
+        assert!(is_archive(Path::new("/b/a.tar.gz")));
+        assert!(is_archive(Path::new("/b/a.tgz")));
+        assert!(!is_archive(Path::new("/b/a.gz")));
+        assert!(!is_archive(Path::new("/b/zip")));
+    }
+
+    #[test]
+    fn test_member_path_joins_the_archive_and_inner_path() {
+        let sep = std::path::MAIN_SEPARATOR;
+        assert_eq!(
+            member_path("/b/a.zip", "/photos/x.jpg"),
+            format!("/b/a.zip!{sep}photos{sep}x.jpg")
+        );
+    }
+
+    #[test]
+    fn test_hash_members_reads_zip_tar_and_tar_gz() {
+        let tmp = tempfile::tempdir().unwrap();
+        let files: &[(&str, &[u8])] = &[("dir/a.txt", b"alpha"), ("b.txt", b"")];
+        let zip = tmp.path().join("a.zip");
+        write_zip(&zip, files);
+        let tar = tmp.path().join("a.tar");
+        std::fs::write(&tar, tar_bytes(files)).unwrap();
+        let tgz = tmp.path().join("a.tgz");
+        let mut gz = flate2::write::GzEncoder::new(
+            File::create(&tgz).unwrap(),
+            flate2::Compression::default(),
+        );
+        gz.write_all(&tar_bytes(files)).unwrap();
+        gz.finish().unwrap();
+
+        let opts = HashOptions::default();
+        let loose = tmp.path().join("a.txt");
+        std::fs::write(&loose, "alpha").unwrap();
+        let expected = vec![
+            Member {
+                name: "dir/a.txt".into(),
+                hash: hashing::compute_file_hash(&loose, &opts).unwrap(),
+                size: 5,
+            },
+            Member {
+                name: "b.txt".into(),
+                hash: opts.algorithm.empty_hash(),
+                size: 0,
+            },
+        ];
+        for archive in [&zip, &tar, &tgz] {
+            assert_eq!(hash_members(archive, &opts).unwrap(), expected);
+        }
+
+        let broken = tmp.path().join("broken.zip");
+        std::fs::write(&broken, "not a zip").unwrap();
+        assert!(hash_members(&broken, &opts).is_err());
+    }
+}
@@ -0,0 +1,439 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use rusqlite::Connection;
+use rusty_chromaprint::{Configuration, Fingerprinter};
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as DecodeError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::{db, lookalike, utils};
+
+/// Rows written per transaction while storing fingerprints.
+const TRANSACTION_BATCH_SIZE: usize = 1000;
+
+/// Extensions of the audio formats this build decodes (lowercase).
+const AUDIO_EXTENSIONS: &[&str] = &["mp3", "flac", "ogg", "oga", "wav"];
+
+/// Seconds of audio fingerprinted from the start of each file, as `fpcalc`
+/// does by default: enough to tell songs apart, without decoding whole
+/// albums.
+const FINGERPRINT_SECONDS: u64 = 120;
+
+/// Most a track's fingerprint is shifted against another's when looking
+/// for their best alignment, in fingerprint items (about 1/8 s each): room
+/// for encoder delay and a little leading silence.
+const MAX_OFFSET: isize = 40;
+
+/// Fewest fingerprint items two tracks must overlap by to be compared at
+/// all, about three seconds.
+const MIN_OVERLAP: usize = 24;
+
+/// Most two tracks' lengths may differ by, in seconds, to count as the same
+/// recording.
+pub const DURATION_TOLERANCE: f64 = 3.0;
+
+/// Whether the file at `path` is audio `fingerprint` can read, going by its
+/// extension.
+pub fn is_audio(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| AUDIO_EXTENSIONS.contains(&e.as_str()))
+}
+
+/// A track's acoustic fingerprint and its length.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    /// Chromaprint's items: 32 bits describing each 1/8 s or so of the
+    /// opening `FINGERPRINT_SECONDS`
+    pub items: Vec<u32>,
+    pub duration: f64,
+}
+
+/// The Chromaprint fingerprint of the audio file at `path`. It comes from
+/// the decoded sound, not the bytes of the file, so the same recording
+/// encoded as MP3 and FLAC, at another bitrate, or with other tags, gets
+/// nearly the same one.
+pub fn fingerprint(path: &Path) -> Result<Fingerprint> {
+    let file = File::open(path)?;
+    let stream = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .context("no audio track")?;
+    let track_id = track.id;
+    let stated_frames = track.codec_params.n_frames;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let config = Configuration::default();
+    let mut printer = Fingerprinter::new(&config);
+    let mut sample_rate = 0;
+    let mut frames = 0u64;
+    let mut samples: Option<SampleBuffer<i16>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(DecodeError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // A corrupt frame is skipped, as players do
+            Err(DecodeError::DecodeError(_)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let spec = *decoded.spec();
+        if samples.is_none() {
+            sample_rate = spec.rate;
+            printer.start(sample_rate, spec.channels.count() as u32)?;
+        }
+        let buffer =
+            samples.get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, spec));
+        if buffer.capacity() < decoded.capacity() * spec.channels.count() {
+            *buffer = SampleBuffer::new(decoded.capacity() as u64, spec);
+        }
+        buffer.copy_interleaved_ref(decoded);
+        printer.consume(buffer.samples());
+        frames += (buffer.samples().len() / spec.channels.count()) as u64;
+        if frames >= FINGERPRINT_SECONDS * u64::from(sample_rate) {
+            break;
+        }
+    }
+    anyhow::ensure!(sample_rate > 0, "no audio in the file");
+    printer.finish();
+    Ok(Fingerprint {
+        items: printer.fingerprint().to_vec(),
+        duration: stated_frames.unwrap_or(frames) as f64 / f64::from(sample_rate),
+    })
+}
+
+/// The fraction of their bits two fingerprints share where they line up
+/// best, shifting either by up to `MAX_OFFSET` items: near 1 for the same
+/// recording, around 0.5 to 0.65 for unrelated ones. 0 when they are too
+/// short to overlap enough to tell.
+pub fn similarity(a: &[u32], b: &[u32]) -> f64 {
+    let mut best = 0.0f64;
+    for offset in -MAX_OFFSET..=MAX_OFFSET {
+        let shift = offset.unsigned_abs();
+        let (a, b) = if offset >= 0 {
+            (a.get(shift..).unwrap_or(&[]), b)
+        } else {
+            (a, b.get(shift..).unwrap_or(&[]))
+        };
+        let overlap = a.len().min(b.len());
+        if overlap < MIN_OVERLAP {
+            continue;
+        }
+        let differing: u32 = a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum();
+        best = best.max(1.0 - f64::from(differing) / (overlap as f64 * 32.0));
+    }
+    best
+}
+
+/// One distinct recording: every file with the same content counts once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    /// The files holding it, sorted
+    pub paths: Vec<String>,
+    pub size: i64,
+    pub fingerprint: Fingerprint,
+}
+
+/// Tracks that sound alike, the largest file first: at the same length
+/// that is usually the best encoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster {
+    pub tracks: Vec<Track>,
+}
+
+impl Cluster {
+    pub fn total_size(&self) -> i64 {
+        self.tracks
+            .iter()
+            .map(|t| t.size * t.paths.len() as i64)
+            .sum()
+    }
+}
+
+/// What `find_similar_audio` found.
+pub struct SimilarAudio {
+    /// Largest first
+    pub clusters: Vec<Cluster>,
+    /// Audio files that couldn't be decoded, and so aren't in any cluster
+    pub unreadable: usize,
+}
+
+/// Cluster the audio files among `lookalike::candidates` whose lengths are
+/// within `DURATION_TOLERANCE` of each other and whose fingerprints have a
+/// `similarity` of at least `threshold`. A cluster links tracks through any
+/// chain of such pairs. Files are only decoded when the database holds no
+/// fingerprint for their current content; those fingerprints are stored for
+/// next time.
+pub fn find_similar_audio(
+    conn: &Connection,
+    threshold: f64,
+    scope: &[&Path],
+    on_progress: impl Fn(usize, usize) + Sync,
+) -> Result<SimilarAudio> {
+    let files = lookalike::candidates(conn, scope, |_, path| is_audio(path))?;
+
+    let mut stored = db::audio_fingerprints(conn)?;
+    let stale: Vec<&db::FileRecord> = files
+        .iter()
+        .filter(|f| stored.get(&f.path).is_none_or(|s| s.hash != f.hash))
+        .collect();
+    let done = AtomicUsize::new(0);
+    let fresh: Vec<(&db::FileRecord, Option<Fingerprint>)> = stale
+        .par_iter()
+        .map(|&file| {
+            let fingerprint = fingerprint(&utils::path_from_db(&file.path)).ok();
+            on_progress(done.fetch_add(1, Ordering::Relaxed) + 1, stale.len());
+            (file, fingerprint)
+        })
+        .collect();
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    for (file, fingerprint) in fresh {
+        let record = db::AudioFingerprintRecord {
+            hash: file.hash.clone(),
+            duration: fingerprint.as_ref().map_or(0.0, |f| f.duration),
+            fingerprint: fingerprint.map(|f| f.items),
+        };
+        db::set_audio_fingerprint(conn, &file.path, &record)?;
+        stored.insert(file.path.clone(), record);
+        batch.tick()?;
+    }
+    db::prune_audio_fingerprints(conn)?;
+    batch.commit()?;
+
+    let mut unreadable = 0;
+    let mut tracks = Vec::new();
+    for copies in lookalike::by_content(&files) {
+        let file = copies[0];
+        let Some((items, record)) = stored
+            .get(&file.path)
+            .and_then(|s| s.fingerprint.as_ref().map(|items| (items, s)))
+        else {
+            unreadable += copies.len();
+            continue;
+        };
+        tracks.push(Track {
+            paths: copies.iter().map(|f| f.path.clone()).collect(),
+            size: file.size,
+            fingerprint: Fingerprint {
+                items: items.clone(),
+                duration: record.duration,
+            },
+        });
+    }
+    Ok(SimilarAudio {
+        clusters: cluster(tracks, threshold),
+        unreadable,
+    })
+}
+
+/// Group `tracks` into clusters of two or more linked by pairs of about the
+/// same length at least `threshold` alike.
+fn cluster(mut tracks: Vec<Track>, threshold: f64) -> Vec<Cluster> {
+    // Sorted by length, only neighbours within the tolerance are compared
+    tracks.sort_by(|a, b| a.fingerprint.duration.total_cmp(&b.fingerprint.duration));
+    let mut sets = utils::DisjointSets::new(tracks.len());
+    for (i, track) in tracks.iter().enumerate() {
+        for (j, other) in tracks.iter().enumerate().skip(i + 1) {
+            if other.fingerprint.duration - track.fingerprint.duration > DURATION_TOLERANCE {
+                break;
+            }
+            if similarity(&track.fingerprint.items, &other.fingerprint.items) >= threshold {
+                sets.union(i, j);
+            }
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<Track>> = HashMap::new();
+    for (index, mut track) in tracks.into_iter().enumerate() {
+        track.paths.sort();
+        members.entry(sets.find(index)).or_default().push(track);
+    }
+    let mut clusters: Vec<Cluster> = members
+        .into_values()
+        .filter(|tracks| tracks.len() > 1)
+        .map(|mut tracks| {
+            tracks.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.paths.cmp(&b.paths)));
+            Cluster { tracks }
+        })
+        .collect();
+    clusters.sort_by(|a, b| {
+        b.total_size()
+            .cmp(&a.total_size())
+            .then_with(|| a.tracks[0].paths.cmp(&b.tracks[0].paths))
+    });
+    clusters
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Write `seconds` of a tune to a 16-bit PCM WAV file: a note from
+    /// `notes` every half second, picked by a little generator seeded with
+    /// `seed`, so every seed gives a different tune.
+    fn write_tune(path: &Path, seed: u32, seconds: u32, rate: u32, channels: u16) {
+        let notes = [
+            220.0, 247.0, 262.0, 294.0, 330.0, 349.0, 392.0, 440.0, 494.0, 523.0,
+        ];
+        let mut state = seed;
+        let mut pitches = Vec::new();
+        for _ in 0..seconds * 2 {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            pitches.push(notes[(state >> 16) as usize % notes.len()]);
+        }
+        let mut data = Vec::new();
+        for n in 0..seconds * rate {
+            let t = f64::from(n) / f64::from(rate);
+            let pitch = pitches[(n * 2 / rate) as usize];
+            let sample = (t * pitch * std::f64::consts::TAU).sin() * 8000.0;
+            for _ in 0..channels {
+                data.extend_from_slice(&(sample as i16).to_le_bytes());
+            }
+        }
+        let block = u32::from(channels) * 2;
+        let mut file = File::create(path).unwrap();
+        file.write_all(b"RIFF").unwrap();
+        file.write_all(&(36 + data.len() as u32).to_le_bytes())
+            .unwrap();
+        file.write_all(b"WAVEfmt ").unwrap();
+        file.write_all(&16u32.to_le_bytes()).unwrap();
+        file.write_all(&1u16.to_le_bytes()).unwrap();
+        file.write_all(&channels.to_le_bytes()).unwrap();
+        file.write_all(&rate.to_le_bytes()).unwrap();
+        file.write_all(&(rate * block).to_le_bytes()).unwrap();
+        file.write_all(&(block as u16).to_le_bytes()).unwrap();
+        file.write_all(&16u16.to_le_bytes()).unwrap();
+        file.write_all(b"data").unwrap();
+        file.write_all(&(data.len() as u32).to_le_bytes()).unwrap();
+        file.write_all(&data).unwrap();
+    }
+
+    fn track(path: &str, size: i64, items: Vec<u32>, duration: f64) -> Track {
+        Track {
+            paths: vec![path.to_string()],
+            size,
+            fingerprint: Fingerprint { items, duration },
+        }
+    }
+
+    #[test]
+    fn test_fingerprint_matches_the_same_tune_at_another_rate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let (a, b, c) = (
+            tmp.path().join("a.wav"),
+            tmp.path().join("b.wav"),
+            tmp.path().join("c.wav"),
+        );
+        write_tune(&a, 1, 12, 22050, 2);
+        write_tune(&b, 1, 12, 11025, 1);
+        write_tune(&c, 2, 12, 22050, 2);
+
+        let a = fingerprint(&a).unwrap();
+        let b = fingerprint(&b).unwrap();
+        let c = fingerprint(&c).unwrap();
+        assert!((a.duration - 12.0).abs() < 0.01, "{}", a.duration);
+        assert!(similarity(&a.items, &b.items) > 0.9);
+        assert!(similarity(&a.items, &c.items) < 0.8);
+        assert!(fingerprint(&tmp.path().join("missing.mp3")).is_err());
+    }
+
+    #[test]
+    fn test_similarity_finds_the_best_alignment() {
+        let items: Vec<u32> = (0..200u32).map(|i| i.wrapping_mul(0x9E37_79B9)).collect();
+        assert_eq!(similarity(&items, &items), 1.0);
+        assert_eq!(similarity(&items[7..], &items), 1.0);
+        assert_eq!(similarity(&items, &items[30..]), 1.0);
+        let inverted: Vec<u32> = items.iter().map(|i| !i).collect();
+        assert!(similarity(&items, &inverted) < 0.7);
+        // Too short to tell
+        assert_eq!(similarity(&items[..10], &items[..10]), 0.0);
+    }
+
+    #[test]
+    fn test_cluster_needs_both_a_similar_length_and_a_similar_sound() {
+        let song: Vec<u32> = (0..100u32).map(|i| i.wrapping_mul(0x9E37_79B9)).collect();
+        let mut nearly = song.clone();
+        nearly[3] ^= 0xFF;
+        let other: Vec<u32> = song.iter().map(|i| i.rotate_left(7)).collect();
+        let clusters = cluster(
+            vec![
+                track("/a.mp3", 10, song.clone(), 200.0),
+                track("/a.flac", 30, nearly, 201.0),
+                track("/remix.mp3", 10, song, 260.0),
+                track("/b.mp3", 10, other, 200.5),
+            ],
+            0.9,
+        );
+        assert_eq!(clusters.len(), 1);
+        let paths: Vec<&str> = clusters[0]
+            .tracks
+            .iter()
+            .map(|t| t.paths[0].as_str())
+            .collect();
+        assert_eq!(paths, vec!["/a.flac", "/a.mp3"]);
+    }
+
+    #[test]
+    fn test_find_similar_audio_stores_fingerprints_and_skips_what_it_cannot_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        write_tune(&tmp.path().join("a.wav"), 1, 8, 22050, 2);
+        write_tune(&tmp.path().join("b.wav"), 1, 8, 11025, 1);
+        write_tune(&tmp.path().join("c.wav"), 3, 8, 11025, 1);
+        std::fs::write(tmp.path().join("broken.mp3"), "not an mp3").unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        let opts = crate::scan::ScanOptions::default();
+        crate::scan::scan_directory(&conn, tmp.path(), 4, &opts, |_, _, _| {}).unwrap();
+
+        let found = find_similar_audio(&conn, 0.85, &[], |_, _| {}).unwrap();
+        assert_eq!(found.unreadable, 1);
+        assert_eq!(found.clusters.len(), 1);
+        assert_eq!(found.clusters[0].tracks.len(), 2);
+        assert_eq!(db::audio_fingerprints(&conn).unwrap().len(), 4);
+
+        // Stored fingerprints are used rather than decoding the files again
+        std::fs::remove_file(tmp.path().join("b.wav")).unwrap();
+        let again = find_similar_audio(&conn, 0.85, &[], |_, _| {}).unwrap();
+        assert_eq!(again.clusters, found.clusters);
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+use rayon::prelude::*;
+use rusqlite::Connection;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{db, hashing, lookalike, utils};
+
+/// Rows written per transaction while storing chunk lists.
+const TRANSACTION_BATCH_SIZE: usize = 100;
+
+/// Bytes a chunk holds at least, but for the last one of a file.
+const MIN_CHUNK: usize = 16 * 1024;
+
+/// Bytes a chunk holds on average. Chunks are cut more eagerly past this
+/// size and more reluctantly before it, which keeps most of them close to it.
+const AVERAGE_CHUNK: usize = 64 * 1024;
+
+/// Bytes a chunk holds at most.
+const MAX_CHUNK: usize = 256 * 1024;
+
+/// Cut points before and after `AVERAGE_CHUNK`: a gear hash whose top 17
+/// (then 15) bits are all zero ends a chunk, one in 2^17 (then 2^15) bytes.
+const MASK_SMALL: u64 = !0 << (64 - 17);
+const MASK_LARGE: u64 = !0 << (64 - 15);
+
+/// Most distinct files a chunk can be in and still count towards their
+/// shared bytes. A chunk found in more (a run of zeros every disk image
+/// holds) would otherwise pair each of them with all the rest.
+const MAX_SHARERS: usize = 100;
+
+/// A random 64-bit value for every byte, the gear hash rolls them in.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0x2545_F491_4F6C_DD1Du64;
+    let mut i = 0;
+    while i < 256 {
+        // SplitMix64
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// A piece of a file, cut where its content says rather than at a fixed
+/// offset, so bytes inserted or removed early in a file only change the
+/// chunks around them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    /// The xxh3 hash of its bytes
+    pub hash: u64,
+    pub length: u32,
+}
+
+/// Where a chunk ends, as FastCDC finds it: a gear hash of the bytes since
+/// `MIN_CHUNK` into the chunk, checked against a stricter mask before
+/// `AVERAGE_CHUNK` and a looser one after.
+#[derive(Default)]
+struct Chunker {
+    length: usize,
+    gear: u64,
+}
+
+impl Chunker {
+    /// How many bytes of `data` finish the current chunk, or `None` if all
+    /// of them belong to it and it goes on.
+    fn cut(&mut self, data: &[u8]) -> Option<usize> {
+        for (i, &byte) in data.iter().enumerate() {
+            self.length += 1;
+            if self.length <= MIN_CHUNK {
+                continue;
+            }
+            self.gear = (self.gear << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if self.length < AVERAGE_CHUNK {
+                MASK_SMALL
+            } else {
+                MASK_LARGE
+            };
+            if self.gear & mask == 0 || self.length >= MAX_CHUNK {
+                *self = Self::default();
+                return Some(i + 1);
+            }
+        }
+        None
+    }
+}
+
+/// Split everything `reader` yields into content-defined chunks, in order.
+pub fn chunk_reader(mut reader: impl Read) -> Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    let mut chunker = Chunker::default();
+    let mut hasher = Xxh3::new();
+    let mut length = 0usize;
+    let mut buffer = vec![0u8; 1024 * 1024];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        let mut data = &buffer[..read];
+        while let Some(end) = chunker.cut(data) {
+            hasher.update(&data[..end]);
+            chunks.push(Chunk {
+                hash: hasher.digest(),
+                length: (length + end) as u32,
+            });
+            hasher.reset();
+            length = 0;
+            data = &data[end..];
+        }
+        hasher.update(data);
+        length += data.len();
+    }
+    if length > 0 {
+        chunks.push(Chunk {
+            hash: hasher.digest(),
+            length: length as u32,
+        });
+    }
+    Ok(chunks)
+}
+
+/// One distinct large file: every file with the same content counts once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkedFile {
+    /// The files holding it, sorted
+    pub paths: Vec<String>,
+    pub size: i64,
+}
+
+/// Two large files much of whose content is the same.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedPair {
+    /// The smaller file, whose share is the one reported
+    pub smaller: ChunkedFile,
+    pub larger: ChunkedFile,
+    /// Bytes of the distinct chunks both hold: an estimate of how much of
+    /// their content they have in common
+    pub shared_bytes: i64,
+}
+
+impl SharedPair {
+    /// The fraction of the smaller file that is also in the larger one.
+    pub fn fraction(&self) -> f64 {
+        self.shared_bytes as f64 / self.smaller.size.max(1) as f64
+    }
+}
+
+/// What `find_shared_chunks` found.
+pub struct SharedChunks {
+    /// Most shared bytes first
+    pub pairs: Vec<SharedPair>,
+    /// Files that couldn't be read, and so aren't in any pair
+    pub unreadable: usize,
+}
+
+/// Find pairs of files of at least `min_size` bytes among
+/// `lookalike::candidates` that share at least `threshold` of the smaller
+/// one's bytes in content-defined chunks: disk images of the same system, a
+/// mailbox and an older copy of it, two versions of a video project. Files
+/// are only chunked when the database holds no chunk list for their current
+/// content; those are stored for next time. Files `--prefilter` left with a
+/// provisional hash are left out.
+pub fn find_shared_chunks(
+    conn: &Connection,
+    min_size: i64,
+    threshold: f64,
+    scope: &[&Path],
+    on_progress: impl Fn(usize, usize) + Sync,
+) -> Result<SharedChunks> {
+    let files = lookalike::candidates(conn, scope, |f, _| {
+        f.size > 0 && f.size >= min_size && !hashing::is_provisional(&f.hash)
+    })?;
+
+    let mut stored = db::file_chunks(conn)?;
+    let stale: Vec<&db::FileRecord> = files
+        .iter()
+        .filter(|f| stored.get(&f.path).is_none_or(|s| s.hash != f.hash))
+        .collect();
+    let done = AtomicUsize::new(0);
+    let fresh: Vec<(&db::FileRecord, Option<Vec<Chunk>>)> = stale
+        .par_iter()
+        .map(|&file| {
+            let chunks = File::open(utils::path_from_db(&file.path))
+                .map_err(anyhow::Error::from)
+                .and_then(chunk_reader)
+                .ok();
+            on_progress(done.fetch_add(1, Ordering::Relaxed) + 1, stale.len());
+            (file, chunks)
+        })
+        .collect();
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    for (file, chunks) in fresh {
+        let record = db::FileChunksRecord {
+            hash: file.hash.clone(),
+            chunks: chunks.map(|c| c.iter().map(|c| (c.hash, c.length)).collect()),
+        };
+        db::set_file_chunks(conn, &file.path, &record)?;
+        stored.insert(file.path.clone(), record);
+        batch.tick()?;
+    }
+    db::prune_file_chunks(conn)?;
+    batch.commit()?;
+
+    // Every distinct content once, with the bytes of each distinct chunk
+    let mut unreadable = 0;
+    let mut contents: Vec<(ChunkedFile, HashMap<u64, u32>)> = Vec::new();
+    for copies in lookalike::by_content(&files) {
+        let file = copies[0];
+        let Some(chunks) = stored.get(&file.path).and_then(|s| s.chunks.as_ref()) else {
+            unreadable += copies.len();
+            continue;
+        };
+        let chunked = ChunkedFile {
+            paths: copies.iter().map(|f| f.path.clone()).collect(),
+            size: file.size,
+        };
+        contents.push((chunked, chunks.iter().copied().collect()));
+    }
+
+    let mut sharers: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (id, (_, chunks)) in contents.iter().enumerate() {
+        for &chunk in chunks.keys() {
+            sharers.entry(chunk).or_default().push(id);
+        }
+    }
+    let mut shared: HashMap<(usize, usize), i64> = HashMap::new();
+    for (chunk, list) in &sharers {
+        if list.len() < 2 || list.len() > MAX_SHARERS {
+            continue;
+        }
+        let length = i64::from(contents[list[0]].1[chunk]);
+        for (i, &a) in list.iter().enumerate() {
+            for &b in &list[i + 1..] {
+                *shared.entry((a.min(b), a.max(b))).or_default() += length;
+            }
+        }
+    }
+
+    let mut pairs: Vec<SharedPair> = shared
+        .into_iter()
+        .filter_map(|((a, b), shared_bytes)| {
+            let (a, b) = (&contents[a].0, &contents[b].0);
+            let (smaller, larger) = if (a.size, &a.paths) <= (b.size, &b.paths) {
+                (a, b)
+            } else {
+                (b, a)
+            };
+            let pair = SharedPair {
+                smaller: smaller.clone(),
+                larger: larger.clone(),
+                shared_bytes,
+            };
+            (pair.fraction() >= threshold).then_some(pair)
+        })
+        .collect();
+    for pair in &mut pairs {
+        pair.smaller.paths.sort();
+        pair.larger.paths.sort();
+    }
+    pairs.sort_by(|a, b| {
+        b.shared_bytes
+            .cmp(&a.shared_bytes)
+            .then_with(|| a.smaller.paths.cmp(&b.smaller.paths))
+            .then_with(|| a.larger.paths.cmp(&b.larger.paths))
+    });
+    Ok(SharedChunks { pairs, unreadable })
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` bytes of noise, different for every seed.
+    fn noise(seed: u64, len: usize) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk_reader_cuts_by_content_within_the_limits() {
+        let data = noise(1, 4 * 1024 * 1024);
+        let chunks = chunk_reader(&data[..]).unwrap();
+        let total: usize = chunks.iter().map(|c| c.length as usize).sum();
+        assert_eq!(total, data.len());
+        assert!(chunks[..chunks.len() - 1]
+            .iter()
+            .all(|c| (MIN_CHUNK..=MAX_CHUNK).contains(&(c.length as usize))));
+        // Around the average, not pinned to a limit
+        assert!((20..200).contains(&chunks.len()), "{}", chunks.len());
+
+        // Bytes inserted near the start only change the chunks around them
+        let mut edited = data[..1000].to_vec();
+        edited.extend_from_slice(b"inserted");
+        edited.extend_from_slice(&data[1000..]);
+        let again = chunk_reader(&edited[..]).unwrap();
+        let same = again.iter().filter(|c| chunks.contains(c)).count();
+        assert!(same >= chunks.len() - 2, "{same} of {}", chunks.len());
+
+        assert!(chunk_reader(&[][..]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_find_shared_chunks_pairs_files_sharing_most_of_their_bytes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = noise(2, 2 * 1024 * 1024);
+        let mut grown = base.clone();
+        grown.extend_from_slice(&noise(3, 512 * 1024));
+        std::fs::write(tmp.path().join("disk.img"), &base).unwrap();
+        std::fs::write(tmp.path().join("disk-copy.img"), &base).unwrap();
+        std::fs::write(tmp.path().join("disk-later.img"), &grown).unwrap();
+        std::fs::write(tmp.path().join("other.img"), noise(4, 2 * 1024 * 1024)).unwrap();
+        std::fs::write(tmp.path().join("small.txt"), "small").unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        let opts = crate::scan::ScanOptions::default();
+        crate::scan::scan_directory(&conn, tmp.path(), 4, &opts, |_, _, _| {}).unwrap();
+
+        let found = find_shared_chunks(&conn, 1024 * 1024, 0.5, &[], |_, _| {}).unwrap();
+        assert_eq!(found.unreadable, 0);
+        assert_eq!(found.pairs.len(), 1);
+        let pair = &found.pairs[0];
+        assert_eq!(pair.smaller.paths.len(), 2);
+        assert_eq!(pair.smaller.size, base.len() as i64);
+        assert!(pair.larger.paths[0].ends_with("disk-later.img"));
+        // All but the last chunk or so of the smaller file
+        assert!(pair.fraction() > 0.85, "{}", pair.fraction());
+        assert_eq!(db::file_chunks(&conn).unwrap().len(), 4);
+
+        // Stored chunk lists are used rather than reading the files again
+        std::fs::remove_file(tmp.path().join("disk-later.img")).unwrap();
+        let again = find_shared_chunks(&conn, 1024 * 1024, 0.5, &[], |_, _| {}).unwrap();
+        assert_eq!(again.pairs, found.pairs);
+    }
+}
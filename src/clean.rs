@@ -0,0 +1,183 @@
+use std::path::PathBuf;
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::{db, hashing, scan, utils};
+
+/// What `prune_missing` removed and repaired.
+pub struct CleanStats {
+    pub files_removed: usize,
+    pub dirs_removed: usize,
+    pub dirs_rehashed: usize,
+}
+
+/// Check every stored file and directory path against the disk, drop the rows
+/// whose path no longer exists, and recompute the hash of every surviving
+/// directory above them so duplicate reports stop matching on stale content.
+/// Rows merged in from another database, and rows listed on another machine
+/// or in a bucket, describe another disk and are kept. Files inside an
+/// archive go when the archive does.
+pub fn prune_missing(conn: &Connection, algorithm: hashing::HashAlgorithm) -> Result<CleanStats> {
+    let mut removed: Vec<PathBuf> = Vec::new();
+    let merged = db::merged_sources(conn)?;
+    let members = db::archive_members(conn)?;
+    let remote = db::remote_paths(conn)?;
+
+    let mut files_removed = 0usize;
+    for record in db::all_files(conn)? {
+        let path = utils::path_from_db(&record.path);
+        let exists = match members.get(&record.path) {
+            Some(archive) => utils::path_from_db(archive).is_file(),
+            None => path.is_file(),
+        };
+        if !exists && !merged.contains_key(&record.path) && !remote.contains(&record.path) {
+            db::remove_file(conn, &path)?;
+            // The archive's own row is gone too, and rehashes its parents
+            if !members.contains_key(&record.path) {
+                removed.push(path);
+            }
+            files_removed += 1;
+        }
+    }
+
+    let mut dirs_removed = 0usize;
+    for dir in db::all_directory_paths(conn)? {
+        let path = utils::path_from_db(&dir);
+        if !path.is_dir() && !merged.contains_key(&dir) && !remote.contains(&dir) {
+            db::remove_directory(conn, &path)?;
+            removed.push(path);
+            dirs_removed += 1;
+        }
+    }
+
+    let dirs_rehashed = scan::rehash_ancestors(conn, &removed, algorithm)?;
+
+    Ok(CleanStats {
+        files_removed,
+        dirs_removed,
+        dirs_rehashed,
+    })
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    fn scan(conn: &Connection, root: &Path) {
+        scan::scan_directory(conn, root, 0, &scan::ScanOptions::default(), |_, _, _| ()).unwrap();
+    }
+
+    #[test]
+    fn test_prune_missing_nothing_to_do() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "a").unwrap();
+        let conn = open_test_db();
+        scan(&conn, dir.path());
+
+        let stats = prune_missing(&conn, hashing::HashAlgorithm::Sha256).unwrap();
+        assert_eq!(stats.files_removed, 0);
+        assert_eq!(stats.dirs_removed, 0);
+        assert_eq!(stats.dirs_rehashed, 0);
+    }
+
+    #[test]
+    fn test_prune_missing_removes_deleted_file_and_rehashes_parents() {
+        let root = tempdir().unwrap();
+        let sub = root.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        fs::write(sub.join("keep.txt"), "keep").unwrap();
+        fs::write(sub.join("gone.txt"), "gone").unwrap();
+
+        let conn = open_test_db();
+        scan(&conn, root.path());
+        fs::remove_file(sub.join("gone.txt")).unwrap();
+
+        let stats = prune_missing(&conn, hashing::HashAlgorithm::Sha256).unwrap();
+        assert_eq!(stats.files_removed, 1);
+        assert!(db::get_file(&conn, &sub.join("gone.txt"))
+            .unwrap()
+            .is_none());
+        assert!(db::get_file(&conn, &sub.join("keep.txt"))
+            .unwrap()
+            .is_some());
+
+        // Directory hashes must now match a fresh scan of what's on disk
+        let fresh = open_test_db();
+        scan(&fresh, root.path());
+        for dir in [&sub, &root.path().to_path_buf()] {
+            assert_eq!(
+                db::get_directory(&conn, dir).unwrap().unwrap(),
+                db::get_directory(&fresh, dir).unwrap().unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_prune_missing_keeps_archive_members_until_the_archive_goes() {
+        let dir = tempdir().unwrap();
+        let zip = dir.path().join("a.zip");
+        let mut writer = zip::ZipWriter::new(fs::File::create(&zip).unwrap());
+        writer
+            .start_file("x.txt", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(&mut writer, b"x").unwrap();
+        writer.finish().unwrap();
+        let conn = open_test_db();
+        let opts = scan::ScanOptions {
+            scan_archives: true,
+            ..Default::default()
+        };
+        scan::scan_directory(&conn, dir.path(), 0, &opts, |_, _, _| ()).unwrap();
+
+        let stats = prune_missing(&conn, hashing::HashAlgorithm::Sha256).unwrap();
+        assert_eq!(stats.files_removed, 0);
+
+        fs::remove_file(&zip).unwrap();
+        let stats = prune_missing(&conn, hashing::HashAlgorithm::Sha256).unwrap();
+        assert_eq!(stats.files_removed, 2);
+        assert!(db::all_files(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_prune_missing_removes_deleted_directory_tree() {
+        let root = tempdir().unwrap();
+        let nested = root.path().join("a/b");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(nested.join("f.txt"), "f").unwrap();
+        fs::write(root.path().join("top.txt"), "t").unwrap();
+
+        let conn = open_test_db();
+        scan(&conn, root.path());
+        fs::remove_dir_all(root.path().join("a")).unwrap();
+
+        let stats = prune_missing(&conn, hashing::HashAlgorithm::Sha256).unwrap();
+        assert_eq!(stats.files_removed, 1);
+        assert_eq!(stats.dirs_removed, 2);
+        assert!(db::get_directory(&conn, &nested).unwrap().is_none());
+
+        let fresh = open_test_db();
+        scan(&fresh, root.path());
+        assert_eq!(
+            db::get_directory(&conn, root.path()).unwrap().unwrap(),
+            db::get_directory(&fresh, root.path()).unwrap().unwrap()
+        );
+    }
+}
@@ -0,0 +1,2861 @@
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+
+use anyhow::{bail, Result};
+use clap::{Args, Parser, Subcommand};
+use crate::{
+    daemon, db, dedupe, duplicates, events, file_system, hashing, hooks, ignore_rules, logging,
+    profile, remote, report, s3, scan, script, serve, throttle, tui, ui, utils, walk, xattr,
+    HashStore,
+};
+
+#[derive(Parser, Debug)]
+#[command(name = "deduplifier")]
+#[command(about = "Scan directories, compute hashes, and find duplicates", long_about = None)]
+struct Cli {
+    /// database file path [default: deduplifier.db]
+    #[arg(long, global = true, long_help = "\
+Path to the SQLite database file used to cache file hashes and directory \
+metadata between runs. Defaults to the configuration file's database, or else \
+deduplifier.db in the current working directory. Specify a custom path to \
+maintain separate databases for different sets of directories, or to keep the \
+database next to the files being managed.")]
+    database: Option<PathBuf>,
+
+    /// take defaults from this profile of the configuration file
+    #[arg(long, global = true, value_name = "NAME", long_help = "\
+Take the defaults for this run from [profile.NAME] in the configuration file \
+(~/.config/deduplifier/config.toml, or $XDG_CONFIG_HOME/deduplifier/config.toml, \
+or the file DEDUPLIFIER_CONFIG names) instead of from its top. A profile can \
+name its own database and roots, which commands that scan take when no \
+directories are given, and adds its exclude, include, exclude_regex and ext \
+patterns to the top's. Options given on the command line win over both.")]
+    profile: Option<String>,
+
+    /// only print results, warnings and errors
+    #[arg(long, short, global = true, long_help = "\
+Only print results, warnings and errors: no progress lines, section headers \
+or stale-entry prompts (stale entries are kept; run `clean` to drop them). \
+Combine with the exit status to run from cron or CI: 0 means no duplicates \
+were found, 1 that duplicates were found (or, for verify, corrupt files), and \
+2 that a file could not be scanned or the command failed.")]
+    quiet: bool,
+
+    /// log what is being done: -v for each phase and how long it took, -vv more
+    #[arg(long, short, global = true, action = clap::ArgAction::Count, long_help = "\
+Log what the run is doing on standard error, on top of its usual output. With \
+-v, each phase (walking a tree, hashing its files, hashing its directories, \
+building a report) is logged with how long it took, and every scanned root \
+with its counts; -vv adds when each phase starts, moved files and files that \
+couldn't be hashed; -vvv every file as it is hashed. Each line starts with the \
+seconds since the run began. Warnings are printed either way.")]
+    verbose: u8,
+
+    /// append a JSON log of the run to this file
+    #[arg(long, global = true, value_name = "PATH", long_help = "\
+Append the log to PATH as JSON lines, one object per record with timestamp \
+(UTC), level, target (the module), message, its fields, and the span (phase) \
+and spans it happened in, for running unattended or timing slow scans \
+afterwards; each phase also gets a \"close\" record with its time.busy and \
+time.idle. The file gets at least the -v records, and more with -vv or -vvv.")]
+    log_file: Option<PathBuf>,
+
+    /// run CMD for every duplicate group found, with the group as JSON on stdin
+    #[arg(long, global = true, value_name = "CMD", long_help = "\
+Run CMD through the shell for every duplicate group dup-files, dup-dirs or \
+report lists, with one line of JSON on its standard input: {\"event\": \
+\"duplicate_group\", \"kind\": \"files\" or \"directories\", \"group\": ...}, \
+the group as report --format json has it (hash, size, wasted, and the files \
+or directories with their paths and locations). The event's name is also in \
+DEDUPLIFIER_EVENT. A hook that fails is warned about and the run goes on.")]
+    on_duplicate_group: Option<String>,
+
+    /// run CMD after every scan, with a summary of it as JSON on stdin
+    #[arg(long, global = true, value_name = "CMD", long_help = "\
+Run CMD through the shell once a scan has finished, with one line of JSON \
+on its standard input: {\"event\": \"post_scan\", \"scan_id\", \"started\", \
+\"elapsed_secs\", \"roots\", \"added\", \"changed\", \"removed\", \
+\"errors\": [{\"path\", \"kind\", \"message\"}], \"total_files\", \
+\"total_bytes\"}. Every command that scans runs it, once per scan. A hook \
+that fails is warned about and the run goes on.")]
+    post_scan: Option<String>,
+
+    /// stream progress and result events as JSON lines to stdout
+    #[arg(long, global = true, value_name = "FORMAT", long_help = "\
+Write a machine-readable stream of what the run does as it happens, one JSON \
+object per line with its name under \"event\": scan_started (scan_id, root, \
+files, bytes), file_hashed (path, size, hash), error (path, kind, message; \
+kind fatal when the run itself fails), duplicate_group (kind and group, as \
+--on-duplicate-group gets it) and scan_finished (scan_id, root, errors, stale, \
+moved, interrupted, elapsed_secs), for GUIs and wrappers that drive \
+deduplifier. The stream goes to standard output, and everything else the run \
+prints goes to standard error instead (on Windows they share standard \
+output); see --events-file.")]
+    events: Option<events::EventFormat>,
+
+    /// write the --events stream to this file instead of stdout
+    #[arg(long, global = true, value_name = "PATH", requires = "events")]
+    events_file: Option<PathBuf>,
+
+    /// show what would be deleted, moved or linked without changing anything
+    #[arg(long, global = true, long_help = "\
+Print every file operation a command would carry out (deletions, moves, \
+copies, links, reflinks, trashing), one per line with the bytes involved, \
+without changing any file. The database isn't changed either: the command \
+works on an in-memory copy of it, so a dry run still scans and hashes, but \
+throws the results away. Prompts are still asked, so the run shows what your \
+answers would do; the final go-ahead prompts of dedupe are skipped.")]
+    dry_run: bool,
+
+    /// open (or create) the database encrypted with SQLCipher
+    #[arg(long, global = true, long_help = "\
+Keep the database encrypted with SQLCipher, so the listing of every file name \
+it holds can't be read without the key, e.g. when it lives on a shared \
+drive. The key is taken from the DEDUPLIFIER_KEY environment variable, or \
+asked for when that isn't set (twice, when the database is new). Give \
+--encrypted every time the database is used; databases read by merge-db must \
+have the same key. An existing unencrypted database can't be opened this way: \
+start a new one and scan again. Only available in builds with the sqlcipher \
+feature (cargo build --features sqlcipher).")]
+    encrypted: bool,
+
+    /// wait for another run that is changing the database to finish
+    #[arg(long, global = true, long_help = "\
+Commands that change the database take turns: while one runs, another is \
+refused with an error naming it and when it started. With --wait, it waits \
+for the other run to finish instead. Commands that only read the database \
+never wait; they warn that what they show may be incomplete.")]
+    wait: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// The database used when neither `--database` nor the configuration file
+/// names one.
+const DEFAULT_DATABASE: &str = "deduplifier.db";
+
+impl Cli {
+    fn database(&self) -> &Path {
+        self.database
+            .as_deref()
+            .unwrap_or(Path::new(DEFAULT_DATABASE))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// hash directories into the database without reporting anything
+    #[command(long_about = "\
+Hash the given directories into the database and stop. Run this once (or on \
+a schedule) to bring the database up to date, then run dup-dirs, dup-files or \
+similar with --no-scan to analyse it as often as you like without walking the \
+filesystem again.")]
+    Scan {
+        #[command(flatten)]
+        scan: ScanArgs,
+    },
+
+    /// keep the database up to date as files change, until Ctrl-C
+    #[command(long_about = "\
+Scan the directories, then keep watching them and bring the database up to \
+date whenever something under them is created, changed, moved or deleted, so \
+later commands can use --no-scan without it going stale. Changes are picked \
+up in batches, once nothing has changed for --settle seconds; each batch \
+rescans the directories it touched, which only reads the files whose size or \
+modification time changed and rehashes the directories above them. Files \
+that are gone are taken out of the database without asking. On Linux the \
+kernel reports changes as they happen (each directory takes up one inotify \
+watch); elsewhere the directories are rescanned every 30 seconds. With \
+--duplicates, every file that turns up with the same content as one already \
+in the database is printed with its copies. Stop with Ctrl-C.")]
+    Watch {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// start watching without scanning first
+        #[arg(long, long_help = "\
+Skip the first scan and start watching straight away, trusting the database \
+to be up to date already, e.g. after a separate `deduplifier scan`.")]
+        no_scan: bool,
+
+        /// print files that turn out to be copies of others as they appear
+        #[arg(long)]
+        duplicates: bool,
+
+        /// seconds without a change before a batch of changes is picked up
+        #[arg(long, value_name = "SECONDS", default_value_t = 2)]
+        settle: u64,
+    },
+
+    /// scan the directories in a configuration file on their own schedules
+    #[command(long_about = "\
+Keep running and scan each [[root]] of the --config file whenever its cron \
+schedule comes round, logging what each scan found, e.g. nightly on a NAS so \
+reports can use --no-scan. A scan that comes due while another command is \
+changing the database is skipped until its next time. The file also names the \
+database (--database when it doesn't), the log (standard error when it \
+doesn't, rotated once it reaches log_max_mib) and the hash algorithm and \
+threads to scan with. Times are UTC. Stop with Ctrl-C or SIGTERM; a scan in \
+progress stops at the next file and can be finished by the next run.")]
+    Daemon {
+        /// the configuration file with the roots and their schedules
+        #[arg(long, value_name = "PATH")]
+        config: PathBuf,
+
+        /// carry on in the background, logging to the configured log
+        #[arg(long, long_help = "\
+Fork into the background, print the daemon's process id and return. The \
+configuration must set `log`, since there is no terminal to write to.")]
+        detach: bool,
+
+        /// check the configuration and show when each root is next scanned
+        #[arg(long)]
+        check: bool,
+    },
+
+    /// list and hash a directory for a scan of an ssh:// root (run over ssh)
+    #[command(hide = true)]
+    RemoteHelper {
+        #[command(flatten)]
+        scan: ScanArgs,
+    },
+
+    /// answer HTTP requests for the duplicates, stats and scans as JSON
+    #[command(long_about = "\
+Serve the database over HTTP until Ctrl-C, so duplicate results can be \
+browsed or scripted against without a shell on the machine. Every endpoint \
+answers with JSON: GET /api/groups (what `report --format json` prints; \
+min_wasted, include_empty and top_level filter it), GET /api/stats, GET \
+/api/scans, and GET /api/files?path=...&hash=... to search by part of a path \
+or the start of a hash. With --allow-scan, POST /api/scan?path=DIR rescans a \
+directory at or under one scanned before, with the options it was last \
+scanned with, in the background; GET /api/scan says how it is doing. Each \
+connection is answered on a thread of its own, up to 16 at once, and one \
+scan runs at a time. There is no authentication: anyone who \
+can reach --listen can read every path in the database.")]
+    Serve {
+        /// the address and port to listen on
+        #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+        listen: std::net::SocketAddr,
+
+        /// accept POST /api/scan to rescan directories already in the database
+        #[arg(long)]
+        allow_scan: bool,
+    },
+
+    /// find duplicate directories and optionally delete them (see --delete)
+    #[command(long_about = "\
+Find duplicate directories and optionally delete them (see --delete). \
+Two directories are considered duplicates when their combined file hashes are \
+identical — meaning they contain exactly the same set of files with the same \
+content, regardless of filenames inside. Only top-level duplicate groups are \
+reported; subdirectories that are already covered by a parent duplicate are \
+suppressed. Use --delete to enter an interactive deletion session, and --canon \
+to automatically designate one copy as the keeper.")]
+    DupDirs {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+
+        /// canonical directory: auto-selects the keeper for duplicates
+        #[arg(long, long_help = "\
+Designates one directory as the canonical copy. With --delete, when a \
+duplicate group contains a directory under --canon, that copy is \
+automatically kept and the others are deleted without prompting (unless the \
+group has no canon member, in which case you are still asked). Canon is \
+always scanned first so its hashes are in the database before any other \
+directory is processed.")]
+        canon: Option<PathBuf>,
+
+        /// interactively delete duplicate directories
+        #[arg(long, long_help = "\
+Enable interactive deletion. For each duplicate group you are shown the \
+directories involved and asked which one to keep; the rest are deleted along \
+with their contents. If --canon is provided and one of the duplicates lives \
+under it, that copy is selected automatically and you are only prompted to \
+confirm (unless --no-confirmation is also given).")]
+        delete: bool,
+
+        /// skip per-deletion confirmation prompts when --canon has auto-selected the keeper
+        #[arg(long, long_help = "\
+Skip the per-deletion confirmation prompt in cases where --canon has \
+unambiguously identified the keeper. Without this flag you are still asked to \
+confirm each auto-selected deletion. With this flag those deletions proceed \
+silently. You are still prompted for groups where no canon member exists.")]
+        no_confirmation: bool,
+
+        /// also list directories that hold only empty files
+        #[arg(long, long_help = "\
+Also report duplicate directories whose files are all empty. Such directories \
+free nothing when deleted and tend to match one another by accident (empty \
+package markers, placeholder files), so they are left out by default. Empty \
+files are also left out of directory hashes unless this is given, so a stray \
+one doesn't keep two copies of a tree apart; switching rehashes every \
+directory from the database.")]
+        include_empty: bool,
+    },
+
+    /// find duplicate files
+    #[command(long_about = "\
+Find duplicate files across the given directories. Files are grouped by \
+content hash; any hash that appears more than once is reported along with every \
+path that holds that content and the total wasted space. This operation does not \
+delete anything — it is read-only and safe to run at any time.")]
+    DupFiles {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+
+        /// output format
+        #[arg(long, value_enum, default_value_t = report::DupFilesFormat::Text, long_help = "\
+Output format. text (the default) is meant for reading; fdupes prints one path \
+per line with a blank line after each group, like fdupes -r and jdupes, so \
+existing scripts can consume it. Pair with --no-scan after a separate scan to \
+keep scan progress out of the output.")]
+        format: report::DupFilesFormat,
+
+        /// with --format fdupes, print "N bytes each:" above every group (fdupes -S)
+        #[arg(short = 'S', long)]
+        size: bool,
+
+        /// also list groups of empty files
+        #[arg(long, long_help = "\
+Also report the group of empty files. Every zero-byte file shares the same \
+hash, so without this flag they are left out instead of forming one large \
+group that wastes no space.")]
+        include_empty: bool,
+    },
+
+    /// walk through duplicate files and choose which copies to delete
+    #[command(long_about = "\
+Remove the extra copies of duplicate files that have a copy under the \
+directories. With --interactive, every group is shown with each copy's path, \
+size and modification time, and you choose which copies to keep: their \
+numbers, f to keep the first, a to keep all, s to skip the group, or q to \
+stop; nothing is deleted until the whole list of deletions is confirmed. With \
+--auto, the --rule options pick the survivors and the rest are deleted \
+without prompting. Deleted files are removed from the database, the \
+directories that held them are rehashed, and every decision is recorded in \
+the database's dedupe_log table. With --emit-script, nothing is changed: the \
+commands are written to a shell script to review and run yourself.")]
+    #[command(group(clap::ArgGroup::new("mode").required(true).args(["interactive", "auto"])))]
+    #[command(group(
+        clap::ArgGroup::new("go_ahead").multiple(true).args(["delete", "emit_script"])
+    ))]
+    Dedupe {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long)]
+        no_scan: bool,
+
+        /// choose the copies to keep at a prompt for each group
+        #[arg(long)]
+        interactive: bool,
+
+        /// pick the copies to keep with --rule and --keep-n, without prompting
+        #[arg(long, requires = "go_ahead")]
+        auto: bool,
+
+        /// a rule for --auto; repeat to break ties in order
+        #[arg(long = "rule", value_name = "RULE", long_help = "\
+A rule --auto uses to rank the copies in each group: keep-newest, keep-oldest, \
+keep-shortest-path, or prefer-path=PREFIX (copies under PREFIX first). Repeat \
+to combine; each rule only breaks the ties left by the ones before it, and \
+the path breaks any that remain, so the same database always gives the same \
+result.")]
+        rules: Vec<dedupe::KeepRule>,
+
+        /// how many copies --auto keeps in each group
+        #[arg(long, default_value_t = 1, value_name = "COPIES")]
+        keep_n: usize,
+
+        /// confirm that --auto may delete or replace files without prompting
+        /// (required unless --emit-script)
+        #[arg(long)]
+        delete: bool,
+
+        /// what to do with the copies that aren't kept
+        #[arg(long, value_enum, default_value_t, value_name = "ACTION", long_help = "\
+What to do with the copies that aren't kept: delete them (the default), or \
+hardlink to replace each with a hardlink to the kept copy, so every path keeps \
+working but the data is stored once. Hardlinks only work within one \
+filesystem; copies on another one than the kept copy are skipped. Note that \
+hardlinked paths share their data, so editing one edits them all. symlink and \
+relative-symlink replace each copy with a symbolic link to the kept copy, \
+holding its absolute path or the path relative to the copy's directory; they \
+work across filesystems, but the links break if the kept copy is moved or \
+deleted. Each replacement is recorded in the dedupe_log table. reflink keeps \
+every path and file as it is but has the copies share the kept copy's storage \
+with the FIDEDUPERANGE ioctl, on copy-on-write filesystems such as btrfs and \
+XFS (Linux only); the kernel compares the contents before sharing anything. \
+Reflinked copies still count as duplicates in reports.")]
+        action: dedupe::Action,
+
+        /// delete copies outright instead of moving them to the trash
+        #[arg(long, long_help = "\
+With --action delete (the default), unlink the copies instead of moving them \
+to the system trash. Trashed files can be restored, but take up space until \
+the trash is emptied; some places, e.g. network mounts, have no trash.")]
+        permanent: bool,
+
+        /// move copies into DIR instead of deleting them
+        #[arg(long, value_name = "DIR", conflicts_with = "permanent", long_help = "\
+With --action delete (the default), move the copies into DIR instead of the \
+trash, each at its absolute path mirrored below DIR (/photos/a.jpg goes to \
+DIR/photos/a.jpg), so copies from different places never collide and the \
+layout shows where each came from. The moves are recorded in the actions \
+table, so `deduplifier undo` can put them back; once satisfied with the \
+result, delete DIR. Keep DIR outside the scanned directories, or the next \
+scan finds the quarantined copies again.")]
+        quarantine: Option<PathBuf>,
+
+        /// trust equal hashes instead of comparing each copy byte by byte
+        #[arg(long, long_help = "\
+Skip the full byte-by-byte comparison that otherwise runs between the kept \
+copy and every copy about to be deleted or replaced with a link. When a copy \
+turns out to differ despite the equal hash, a collision or a file changed \
+since the scan, its whole group is left alone with a warning. The check \
+reads every copy once more; reflinks never need it, as the kernel compares \
+the data itself.")]
+        no_verify: bool,
+
+        /// write the commands to a script instead of running them
+        #[arg(long, value_name = "PATH", long_help = "\
+Write the commands that would carry out the plan (rm, ln, cp --reflink, or a \
+trash helper) to a script at PATH instead of running them, so it can be \
+reviewed and edited first. Paths are quoted for the shell, and every command \
+is guarded so it is skipped if the copy being kept is gone. Nothing is \
+changed and nothing is logged; rescan after running the script.")]
+        emit_script: Option<PathBuf>,
+
+        /// the shell --emit-script writes for
+        #[arg(long, value_enum, default_value_t, value_name = "SHELL", requires = "emit_script")]
+        script_shell: script::Shell,
+    },
+
+    /// go through the duplicate file groups full-screen, marking what to keep
+    #[command(long_about = "\
+Show the duplicate file groups under the directories full-screen, those that \
+waste the most first, with every copy's path, size and modification time, \
+and mark each copy to keep, delete or replace with a link to the kept copy \
+with the keyboard. Every copy starts out kept, protected copies can't be \
+marked, and every group keeps at least one copy. Nothing changes until the \
+marks are applied with `a` and confirmed; deletions then go to the trash \
+(see --permanent and --quarantine) and links are hardlinks (see --link), \
+exactly as `dedupe` carries them out, copies checked byte by byte first and \
+everything recorded for `undo`. Keys: arrows, Page Up/Down, Home and End to \
+move, Tab to switch between the groups and their copies, k, d and l to mark \
+the selected copy, o to keep only it and delete the rest (O to link the \
+rest), r to reset the group, a to apply and q to quit.")]
+    Tui {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long)]
+        no_scan: bool,
+
+        /// what the copies marked link are replaced with
+        #[arg(long, value_enum, default_value = "hardlink", value_name = "ACTION")]
+        link: dedupe::Action,
+
+        /// delete copies outright instead of moving them to the trash
+        #[arg(long)]
+        permanent: bool,
+
+        /// move deleted copies into DIR instead of the trash
+        #[arg(long, value_name = "DIR", conflicts_with = "permanent")]
+        quarantine: Option<PathBuf>,
+
+        /// trust equal hashes instead of comparing each copy byte by byte
+        #[arg(long)]
+        no_verify: bool,
+    },
+
+    /// find and interactively merge similar (but non-identical) directories
+    #[command(long_about = "\
+Find and interactively merge similar but non-identical directories. Similarity \
+is measured as the fraction of files (by hash) that two directories share. For \
+each flagged pair you are shown the overlap and can choose to merge them: \
+unique files from the source are copied into the destination, and true \
+duplicates in the source are deleted. After a merge you should run dup-dirs \
+to detect any new exact duplicates that were created.")]
+    Similar {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+
+        /// minimum similarity to flag a pair (0.0–1.0)
+        #[arg(long, default_value_t = 0.85, value_name = "THRESHOLD", long_help = "\
+Minimum similarity (0.0–1.0, default 0.85) two directories must have to be \
+flagged as a pair.")]
+        threshold: f64,
+    },
+
+    /// find directories most of whose files are also in another one
+    #[command(long_about = "\
+Find pairs of directories that share most of their files, wherever the files \
+sit in each: a backup folder whose photos are nearly all in the library, only \
+renamed and sorted into other folders, or a copy of a project that has since \
+gained and lost a few files. For each pair the report says how much of each \
+side is in the other, e.g. \"Backup2019 is 94% contained in Photos\", counting \
+distinct file contents by hash at any depth. Only the top-most pairs are \
+shown, not every pair of subdirectories inside them; identical trees are left \
+to dup-dirs, and empty files don't count. Nothing is changed on disk.")]
+    Overlap {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+
+        /// least share of one directory's files the other must hold (0.0–1.0)
+        #[arg(long, default_value_t = 0.9, value_name = "THRESHOLD", long_help = "\
+Least fraction (0.0–1.0, default 0.9) of the distinct file contents of one \
+directory that must also be somewhere under the other for the pair to be \
+reported. The other directory may hold any amount more.")]
+        threshold: f64,
+    },
+
+    /// find directories all of whose files also exist somewhere else
+    #[command(long_about = "\
+Find directories under DIRECTORIES every file of which, at any depth, has a \
+copy by content outside the directory, even where the copies sit among other \
+files: \"everything in OldDrive/Docs also exists under ~/Documents\", so the \
+directory could go without losing anything. Only the top-most such \
+directories are listed, each with the deepest directory holding all of the \
+copies when one does. A hardlink is not a copy, and files the ignore rules \
+match have none. The copies may be anywhere in the database, or only under \
+the --in paths. Nothing is changed on disk.")]
+    Contained {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+
+        /// only count copies under this path (repeatable); it is scanned too
+        #[arg(long = "in", value_name = "PATH")]
+        within: Vec<PathBuf>,
+    },
+
+    /// find images that look alike but aren't identical files
+    #[command(long_about = "\
+Find photos and other images that look the same without being byte-for-byte \
+copies: the same picture resized, re-encoded at another quality, or saved in \
+another format. Each image is shrunk to a tiny grey thumbnail and reduced to a \
+64-bit perceptual hash (dHash); images whose hashes differ in at most \
+--threshold bits are reported together, in clusters, the one with the most \
+pixels first. Hashes are stored in the database and only recomputed when a \
+file's content changes. Exact copies are listed once per cluster and are \
+otherwise left to dup-files. JPEG, PNG, GIF, BMP, TIFF and WebP files are \
+read; nothing is changed on disk.")]
+    SimilarImages {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+
+        /// most bits two images' hashes may differ in (0–64)
+        #[arg(long, default_value_t = 8, value_name = "BITS",
+            value_parser = clap::value_parser!(u32).range(0..=64), long_help = "\
+Most bits (0–64, default 8) in which two images' perceptual hashes may differ \
+for them to count as the same picture. 0 finds only images that hash \
+identically; up to about 10 finds resized and recompressed copies; much higher \
+starts to pair up pictures that merely have a similar layout.")]
+        threshold: u32,
+    },
+
+    /// find recordings that sound alike but aren't identical files
+    #[command(long_about = "\
+Find music and other recordings that sound the same without being \
+byte-for-byte copies: the same song encoded as MP3 and FLAC, at another \
+bitrate, or with different tags. The opening two minutes of every audio file \
+are decoded and reduced to a Chromaprint fingerprint; files of about the same \
+length (within 3 seconds) whose fingerprints agree in at least --threshold of \
+their bits are reported together, in clusters, the largest file first. \
+Fingerprints are stored in the database and only recomputed when a file's \
+content changes. Exact copies are listed once per cluster and are otherwise \
+left to dup-files. MP3, FLAC, Ogg Vorbis and WAV files are read; nothing is \
+changed on disk.")]
+    SimilarAudio {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+
+        /// least fraction of fingerprint bits two tracks must share (0.0–1.0)
+        #[arg(long, default_value_t = 0.85, value_name = "THRESHOLD", long_help = "\
+Least fraction (0.0–1.0, default 0.85) of their fingerprint bits two tracks \
+must share, where they line up best, to count as the same recording. Unrelated \
+music shares about half to two thirds; re-encodings of one recording usually \
+more than nine tenths.")]
+        threshold: f64,
+    },
+
+    /// find text files and source code that are nearly the same
+    #[command(long_about = "\
+Find documents and source files that are slightly edited copies of one \
+another: a report saved again with a paragraph changed, a config file with \
+one setting different, a source file copied into another project and \
+patched. Each plain-text file is split into overlapping runs of five words \
+(case, punctuation and spacing ignored) and reduced to a 128-value MinHash \
+signature; files whose signatures agree in at least --threshold of their \
+values, which estimates the share of those runs they have in common, are \
+reported together in clusters, the largest file first. Signatures are stored \
+in the database and only recomputed when a file's content changes. Exact \
+copies are listed once per cluster and are otherwise left to dup-files. Files \
+are picked by extension (.txt, .md, .csv, .html, .json, .rs, .py and other \
+text and source formats), and ones holding NUL bytes or over 16 MiB are left \
+out; nothing is changed on disk.")]
+    SimilarText {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+
+        /// least estimated share of text two files must have in common (0.0–1.0)
+        #[arg(long, default_value_t = 0.8, value_name = "THRESHOLD", long_help = "\
+Least fraction (0.0–1.0, default 0.8) of their signature values two files must \
+share to be reported together, an estimate of how much of their text they \
+have in common. An edited word or two in a page keeps a copy above nine \
+tenths; unrelated texts share next to none.")]
+        threshold: f64,
+    },
+
+    /// find large files that share most of their bytes
+    #[command(long_about = "\
+Find pairs of large files that hold much of the same content without being \
+identical: disk images of the same system, a mailbox and an older copy of it, \
+two versions of a video project. Every file of at least --min-size is cut \
+into content-defined chunks of about 64 KiB (FastCDC-style, so bytes inserted \
+or removed early in a file only change the chunks around them), and pairs \
+whose common chunks make up at least --threshold of the smaller file are \
+reported, with an estimate of the bytes they share. Chunk lists are stored in \
+the database and only recomputed when a file's content changes. Exact copies \
+are listed once per pair and are otherwise left to dup-files. Nothing is \
+changed on disk.")]
+    SharedChunks {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+
+        /// only chunk files at least this many MiB
+        #[arg(long, default_value_t = 16, value_name = "MIB", long_help = "\
+Only chunk and compare files at least this large (in MiB, default 16). Every \
+file chunked is read in full, so a low limit on a large library takes a \
+while; smaller files are seldom worth it.")]
+        min_size: u64,
+
+        /// least share of the smaller file the two must have in common (0.0–1.0)
+        #[arg(long, default_value_t = 0.5, value_name = "THRESHOLD", long_help = "\
+Least fraction (0.0–1.0, default 0.5) of the smaller file's bytes that must \
+be in chunks the larger file holds too for the pair to be reported.")]
+        threshold: f64,
+    },
+
+    /// find files with the same name but different content
+    #[command(long_about = "\
+Group the files under the directories by file name, ignoring case, and list \
+the names whose files don't all hold the same content: five copies of \
+report_final.docx that are really three different documents. When \
+consolidating drives these need a look as much as exact duplicates do, since \
+keeping one of them could lose the others. Each file is listed with its \
+version (files with the same content share one), size and modification time, \
+newest first. With --stem, names are compared without their extension, so \
+report_final.docx and report_final.pdf go together. Only the database is \
+read after the scan; nothing is changed on disk.")]
+    SameName {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+
+        /// compare names without their extension
+        #[arg(long)]
+        stem: bool,
+    },
+
+    /// find re-encoded copies of the same video
+    #[command(long_about = "\
+Find videos that show the same footage without being byte-for-byte copies: \
+the same clip re-encoded at another bitrate or resolution, converted to \
+another format, or remuxed with other metadata. Each video's length and frame \
+size are read with ffprobe and eight frames, evenly spread over its length, \
+are decoded with ffmpeg and reduced to perceptual hashes; videos of about the \
+same length (within 2 seconds, or 1% for long ones) whose frames look alike \
+are reported together in clusters, the one with the most pixels first, each \
+with a confidence score. Requires ffmpeg and ffprobe on the PATH. Signatures \
+are stored in the database and only recomputed when a file's content changes. \
+Exact copies are listed once per cluster and are otherwise left to dup-files. \
+Nothing is changed on disk.")]
+    SimilarVideos {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+
+        /// least confidence two videos must reach to be reported (0.0–1.0)
+        #[arg(long, default_value_t = 0.8, value_name = "THRESHOLD", long_help = "\
+Least confidence (0.0–1.0, default 0.8) that two videos are the same footage \
+for them to be reported together. Re-encodings of one video usually score \
+above nine tenths; unrelated videos near nothing.")]
+        threshold: f64,
+    },
+
+    /// find photos that differ only in their metadata
+    #[command(long_about = "\
+Find JPEG and PNG photos that hold exactly the same picture but differ as \
+files, because tags were added, a date fixed, or the EXIF orientation flag set \
+in one copy. Each photo's image data is hashed without its metadata (JPEG APPn \
+segments such as EXIF, XMP, IPTC and ICC profiles, and comments; PNG text, \
+EXIF and time chunks), and photos whose image data hashes the same are \
+reported together, each copy with its size and modification time, so the \
+version with the metadata you want can be kept. Groups of byte-for-byte copies \
+alone are left to dup-files. Payload hashes are stored in the database and \
+only recomputed when a file's content changes; nothing is changed on disk.")]
+    DupPhotos {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+    },
+
+    /// find files that hold the same content under different compression
+    #[command(long_about = "\
+Find files that hold the same content once decompressed, but differ as files: \
+a file and its gzipped copy, the same file gzipped twice (gzip stores a \
+timestamp and the original name in its header), or compressed with xz in one \
+place and zstd in another. Files ending in .gz, .bz2, .xz and .zst (and the \
+compressed tarballs .tgz, .tbz2, .txz and .tzst) are decompressed and hashed \
+as they are read, and reported with every other file whose content hashes \
+the same. Groups of byte-for-byte copies alone are left to dup-files. \
+Decompressed hashes are stored in the database and only recomputed when a \
+file's content changes; nothing is changed on disk.")]
+    DupCompressed {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// use the database as it is instead of scanning first
+        #[arg(long, long_help = "\
+Skip the scan and work from what is already in the database, e.g. after a \
+separate `deduplifier scan`. The directories still limit what is reported.")]
+        no_scan: bool,
+    },
+
+    /// merge directory trees into --canon
+    #[command(long_about = "\
+Merge one or more directory trees into --canon. Every file found under the \
+source directories (any directory that is not --canon) is moved into the \
+matching subdirectory path under --canon. If a file with identical content \
+already exists in --canon it is treated as a true duplicate and deleted from \
+the source instead of being moved. Files with the same name but different \
+content are renamed with a numeric suffix to avoid collisions. The database \
+is updated to reflect every move and deletion. The directories are always \
+scanned first.")]
+    Merge {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// directory the other trees are merged into
+        #[arg(long)]
+        canon: PathBuf,
+
+        /// confirm that files may be deleted without prompting (required)
+        #[arg(long)]
+        delete: bool,
+
+        /// keep the newer file on conflicts instead of asking
+        #[arg(long, long_help = "\
+When the same path exists in both trees with different content, keep \
+whichever copy is newer instead of asking.")]
+        no_confirmation: bool,
+    },
+
+    /// sort photos into a date-based folder hierarchy under --canon
+    #[command(long_about = "\
+Sort media files into a date-based folder hierarchy under --canon. Each file \
+is placed into YYYY/YYYY-MM/YYYY-MM-DD/ subdirectories derived first from its \
+EXIF DateTimeOriginal tag (falling back to DateTimeDigitized, then DateTime), \
+and finally from the file's modification time if no valid EXIF date is found. \
+Files that are already in the correct destination are skipped. True duplicates \
+(identical content already present at the destination) are deleted. Name \
+collisions between files with different content are resolved by appending a \
+numeric suffix. Requires --delete and --no-confirmation because it moves and \
+deletes files without prompting. The directories are always scanned first.")]
+    SortPhotos {
+        #[command(flatten)]
+        scan: ScanArgs,
+
+        /// root for the date-based directories
+        #[arg(long)]
+        canon: PathBuf,
+
+        /// confirm that files may be deleted (required)
+        #[arg(long)]
+        delete: bool,
+
+        /// confirm that files may be moved without prompting (required)
+        #[arg(long)]
+        no_confirmation: bool,
+    },
+
+    /// report duplicates already in the database without touching the filesystem
+    #[command(long_about = "\
+Report duplicate files and duplicate directories from an existing database. \
+Nothing on disk is read, hashed or changed, so this is instant and safe to \
+run as often as you like after a scan. Groups can be narrowed down with \
+--min-count and --min-wasted, and directory groups to members under the \
+directories given. With --unique, the files that have no copy anywhere in the \
+database are listed instead.")]
+    Report {
+        /// only report duplicate directories (with --unique, files) under these paths
+        directories: Vec<PathBuf>,
+
+        /// list the files whose content exists nowhere else instead of duplicates
+        #[arg(long, conflicts_with_all = ["min_count", "min_wasted"], long_help = "\
+List the files whose content appears exactly once across everything in the \
+database, i.e. the files that would be lost if their drive were wiped. Useful \
+when consolidating old drives: scan them all, then run `report --unique \
+/old/drive`. Hardlinks to the same data count as one copy. Supports --format \
+text and json.")]
+        unique: bool,
+
+        /// only report groups with at least this many copies
+        #[arg(long, default_value_t = 2, value_name = "N")]
+        min_count: usize,
+
+        /// only report groups that waste at least this many bytes
+        #[arg(long, default_value_t = 0, value_name = "BYTES",
+            value_parser = clap::value_parser!(i64).range(0..), long_help = "\
+Only report groups that waste at least this many bytes, i.e. whose copies \
+beyond the first add up to at least BYTES.")]
+        min_wasted: i64,
+
+        /// output format
+        #[arg(long, value_enum, default_value_t = report::Format::Text, long_help = "\
+Output format. text (the default) is meant for reading; json prints a single \
+document with every group, its hash, member paths and sizes, and the bytes \
+that removing the extra copies would free, for jq and other tools; html is a \
+standalone page with sortable tables, duplicate bytes per directory, and \
+file:// links to every path (use with --output).")]
+        format: report::Format,
+
+        /// write the json or html report to this file instead of stdout
+        #[arg(long, short, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// also list groups of empty files and of directories holding no data
+        #[arg(long, conflicts_with = "unique", long_help = "\
+Also report the group of empty files and duplicate directories whose files \
+are all empty. Every zero-byte file shares the same hash and none of them \
+wastes space, so they are left out by default. Empty files are also left out \
+of directory hashes unless this is given, so a stray one doesn't keep two \
+copies of a tree apart; switching rehashes every directory from the \
+database.")]
+        include_empty: bool,
+
+        /// only show the top-most duplicates, not the files inside duplicate directories
+        #[arg(long, conflicts_with = "unique", long_help = "\
+Only show the top-most duplicated units. When two directories are copies of \
+each other, every file in them is a duplicate too and would otherwise be \
+listed as a group of its own, burying the directory group that matters. With \
+--top-level, a file group is left out when each of its copies sits inside a \
+different copy of the same duplicate directory; groups with a copy \
+elsewhere, or two copies inside one directory, are still shown. The summary \
+and the reclaimable bytes still count every group.")]
+        top_level: bool,
+    },
+
+    /// rehash unchanged files and report any whose content silently changed
+    #[command(long_about = "\
+Rehash the files in the database whose size and modification time are still \
+what the last scan recorded, and report every one whose content hash no \
+longer matches: content that changed without its metadata changing is \
+corruption (bitrot, a failing disk, a bad copy). Files that were edited, \
+deleted or only have a provisional --prefilter hash are counted but not \
+checked. The database is not updated. Exits with status 1 if any file is \
+corrupt, and 2 if any couldn't be read. With no PATHS, every file in the \
+database is checked.")]
+    Verify {
+        /// only check files under these paths
+        paths: Vec<PathBuf>,
+
+        /// number of threads used to hash files (default: one per CPU)
+        #[arg(long, default_value_t = 0, value_name = "N")]
+        threads: usize,
+    },
+
+    /// check that every file in SRC has a copy, by content, somewhere in DST
+    #[command(long_about = "\
+Compare two scanned trees by content hash, e.g. to verify a backup. Lists the \
+files in SRC whose content is missing from DST and the files in DST whose \
+content is not in SRC, and counts the matched files (noting the ones that \
+only exist under a different path in DST). Both trees must already be in the \
+database; run `deduplifier scan SRC DST` first. Only the database is read.")]
+    Compare {
+        /// the original tree
+        src: PathBuf,
+
+        /// the tree that should contain a copy of SRC, e.g. the backup
+        dst: PathBuf,
+
+        /// also list every matched file and where its copy is
+        #[arg(long)]
+        show_matched: bool,
+    },
+
+    /// export every file and its duplicate group to CSV, or a checksum manifest
+    #[command(long_about = "\
+Write the files in the database to CSV, one row per file with its duplicate \
+group number (empty when the file has no duplicate), hash, size and \
+modification time (Unix seconds), for auditing in a spreadsheet or pandas \
+before any cleanup. Hardlinks carry the group number of the file they link \
+to, named in the hardlink_of column. Duplicates come first, ordered by group. \
+With --format sha256sum, b3sum or xxh128sum, write a `<hash>  <path>` \
+checksum manifest instead, which that tool's -c option can check; it must \
+match the database's hash algorithm. Files only given a provisional hash, and \
+files merged in from other databases, are left out of manifests. Only the \
+database is read.")]
+    Export {
+        /// write to this file instead of stdout
+        #[arg(long, short, value_name = "FILE")]
+        output: Option<PathBuf>,
+
+        /// leave out files that have no duplicate (CSV only)
+        #[arg(long)]
+        duplicates_only: bool,
+
+        /// what to write
+        #[arg(long, value_enum, default_value_t, value_name = "FORMAT")]
+        format: report::ExportFormat,
+    },
+
+    /// record the hashes of a sha256sum/b3sum manifest without reading the files
+    #[command(long_about = "\
+Seed the database from an existing checksum manifest, as written by \
+sha256sum, b3sum or xxh128sum (`<hash>  <name>` lines), instead of hashing \
+the files again. Names are taken relative to --root, the current directory \
+by default. Each listed file must still exist: its size and modification time \
+are read from it, and files modified after the manifest was written are \
+skipped, since their hash may be out of date. Files the database already has \
+an up-to-date hash for are left alone. The next scan reuses the imported \
+hashes and computes the directory hashes.")]
+    Import {
+        /// the manifest file, e.g. SHA256SUMS
+        manifest: PathBuf,
+
+        /// the directory the manifest's names are relative to
+        #[arg(long, value_name = "DIR")]
+        root: Option<PathBuf>,
+
+        /// the algorithm the manifest's hashes were made with (default: the
+        /// database's); needed for a new database
+        #[arg(long, value_enum, value_name = "ALGO")]
+        hash: Option<hashing::HashAlgorithm>,
+    },
+
+    /// show which extensions and directories hold the duplicate bytes
+    #[command(long_about = "\
+Break the duplicate files in the database down by file extension and by \
+top-level directory (the first directory below each scanned root), with each \
+one's share of the duplicate bytes, to show where cleanup pays off most. \
+Every copy in a duplicate group counts. Only the database is read.")]
+    Stats {
+        /// show at most N rows per breakdown (0 for all)
+        #[arg(long, default_value_t = 10, value_name = "N")]
+        top: usize,
+    },
+
+    /// list the files and directories the latest scans had to skip
+    #[command(long_about = "\
+List every path the latest scan of each directory could not read or hash, \
+with the time of that scan, the kind of error (permission-denied, not-found, \
+io or hash) and the message. Rescanning a directory replaces what was \
+recorded for it, so fixed paths drop off the list. Only the database is \
+read.")]
+    Errors,
+
+    /// list the recorded scans
+    #[command(long_about = "\
+List every scan run against the database with its id, start time, the \
+directories it was given, and how many files it found added (+), changed (~) \
+and removed (-) since the scan before. Use the ids with `diff`. Only the \
+database is read.")]
+    Scans,
+
+    /// check the database for corruption and rows that no longer add up
+    #[command(long_about = "\
+Look the database over: run SQLite's integrity check, list directory rows \
+that record data but have no files left under them (so their hash can no \
+longer be accounted for), and list hashes that can't have come from the \
+database's hash algorithm, which never match anything else. Then print the \
+size of the database, how much of it deleted rows left free, and the row \
+count of each table. Nothing is changed unless --vacuum is given. Exits with \
+status 1 if a problem is found.")]
+    Doctor {
+        /// rewrite the database afterwards to give back the free space
+        #[arg(long, long_help = "\
+Rewrite the database once it has been checked, giving back the space deleted \
+rows left behind (SQLite's VACUUM), and fold the write-ahead log back into \
+it. Needs about as much free disk space as the database takes up.")]
+        vacuum: bool,
+    },
+
+    /// show what changed between two scans
+    #[command(long_about = "\
+Compare the files as one scan left them with the files as a later scan left \
+them: duplicate groups that are new or were resolved, and the files that \
+appeared, were removed or changed content. Directories only one of the scans \
+covered count as unchanged. Files with a provisional --prefilter hash only \
+count as changed when their size did. Only the database is read.")]
+    Diff {
+        /// the earlier scan (defaults to the one before --to)
+        #[arg(long, value_name = "SCAN")]
+        from: Option<i64>,
+
+        /// the later scan (defaults to the latest)
+        #[arg(long, value_name = "SCAN")]
+        to: Option<i64>,
+    },
+
+    /// copy other databases into this one to find duplicates across machines
+    #[command(long_about = "\
+Copy the files and directories of each SOURCE database into the --database \
+one, so reports find duplicates across the machines they were scanned on. \
+Each source's rows are stored under its file name: /home/me/a.jpg from \
+laptop.db becomes laptop:/home/me/a.jpg, so the same path on two machines \
+never collides. Merging a source again replaces what it merged before. Rows \
+a source had merged itself keep their original source. Merged rows describe \
+another machine's disk: dedupe, verify and clean leave them alone. All the \
+databases must use the same hash algorithm. The sources are only read.")]
+    MergeDb {
+        /// the databases to copy in, e.g. laptop.db nas.db
+        #[arg(required = true)]
+        sources: Vec<PathBuf>,
+    },
+
+    /// drop database rows for files and directories that no longer exist
+    #[command(long_about = "\
+Check every path stored in the database and remove the rows for files and \
+directories that no longer exist on disk. The hashes of the directories that \
+contained them are recomputed, so deleted content stops showing up in \
+duplicate reports. Unlike the stale-entry prompt shown after each scan, this \
+covers the whole database and does not ask for confirmation. Nothing on disk \
+is changed.")]
+    Clean,
+
+    /// reverse recent deletions, link replacements and moves
+    #[command(long_about = "\
+Reverse the changes recorded in the database's actions table by dedupe, \
+merge, sort-photos and dup-dirs --delete, newest first. Moved files are moved \
+back and hardlinks or symlinks are replaced with independent copies. A \
+trashed file is restored from the trash where the platform supports it; \
+otherwise it, like a deleted file or directory, is recreated from a surviving \
+copy with the same content. Nothing is overwritten: an action whose path \
+exists again is reported and left pending. Reflinks need no undoing.")]
+    #[command(group(clap::ArgGroup::new("which").required(true).args(["last", "since"])))]
+    Undo {
+        /// undo the N most recent actions
+        #[arg(long, value_name = "N")]
+        last: Option<usize>,
+
+        /// undo every action since this Unix timestamp
+        #[arg(long, value_name = "TIMESTAMP")]
+        since: Option<i64>,
+    },
+
+    /// keep paths out of reach of dedupe and dup-dirs --delete
+    #[command(long_about = "\
+Manage the protect list stored in the database. A protected path, and \
+everything under it, is never deleted or replaced: dedupe always keeps \
+protected copies, whatever the --auto rules or the copies chosen at the \
+prompt, even when every copy in a group is protected, and dup-dirs --delete \
+skips any directory that is or holds a protected path. Give paths the way \
+the directories were scanned, since they are matched against stored paths.")]
+    Protect {
+        #[command(subcommand)]
+        action: ProtectAction,
+    },
+
+    /// keep known duplicates out of reports and remediation for good
+    #[command(long_about = "\
+Manage the ignore rules stored in the database, for duplicates that are \
+meant to be there, such as a deliberately mirrored config tree. A rule is a \
+path, a glob or a hash. Files and directories at or under an ignored path, \
+or matching an ignored glob, are left out of every duplicate group; a glob \
+without a / is matched against the file name alone. An ignored hash (or the \
+start of one, as reports show it) drops the whole group. Reports, stats, \
+export, dedupe, dup-files and dup-dirs all leave ignored entries alone. The \
+kind of each rule is guessed from its look unless --kind is given.")]
+    Ignore {
+        #[command(subcommand)]
+        action: IgnoreAction,
+    },
+}
+
+impl Command {
+    /// The directories and scan options of a command that scans.
+    fn scan_args_mut(&mut self) -> Option<&mut ScanArgs> {
+        match self {
+            Command::Scan { scan }
+            | Command::Watch { scan, .. }
+            | Command::DupDirs { scan, .. }
+            | Command::DupFiles { scan, .. }
+            | Command::Dedupe { scan, .. }
+            | Command::Tui { scan, .. }
+            | Command::Similar { scan, .. }
+            | Command::Overlap { scan, .. }
+            | Command::Contained { scan, .. }
+            | Command::SimilarImages { scan, .. }
+            | Command::SimilarAudio { scan, .. }
+            | Command::SimilarText { scan, .. }
+            | Command::SharedChunks { scan, .. }
+            | Command::SameName { scan, .. }
+            | Command::SimilarVideos { scan, .. }
+            | Command::DupPhotos { scan, .. }
+            | Command::DupCompressed { scan, .. }
+            | Command::Merge { scan, .. }
+            | Command::SortPhotos { scan, .. } => Some(scan),
+            _ => None,
+        }
+    }
+}
+
+/// What `ignore` does with the ignore rules.
+#[derive(Subcommand, Debug)]
+enum IgnoreAction {
+    /// ignore these paths, globs or hashes from now on
+    Add {
+        #[arg(required = true, value_name = "GLOB|HASH|PATH")]
+        rules: Vec<String>,
+
+        /// what the rules are, instead of guessing
+        #[arg(long, value_enum)]
+        kind: Option<ignore_rules::RuleKind>,
+    },
+    /// stop ignoring these paths, globs or hashes
+    Remove {
+        #[arg(required = true, value_name = "GLOB|HASH|PATH")]
+        rules: Vec<String>,
+
+        /// only remove rules of this kind
+        #[arg(long, value_enum)]
+        kind: Option<ignore_rules::RuleKind>,
+    },
+    /// list the ignore rules
+    List,
+}
+
+/// What `protect` does with the protect list.
+#[derive(Subcommand, Debug)]
+enum ProtectAction {
+    /// protect these paths and everything under them
+    Add {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// stop protecting these paths
+    Remove {
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+    },
+    /// list the protected paths
+    List,
+}
+
+/// Options shared by every subcommand that scans: the directories and how to
+/// hash them.
+#[derive(Args, Debug)]
+struct ScanArgs {
+    /// directories to scan (default: the profile's roots)
+    #[arg(long_help = "\
+Directories to scan. All provided directories are walked recursively, \
+every file is hashed (BLAKE3 by default, see --hash), and the results are stored in the database. \
+On subsequent runs only files whose modification time has changed are re-hashed, \
+so rescans of large trees are fast. You may list as many directories as you like; \
+they are scanned in the order given (with --canon, if provided, always scanned first). \
+When none are given, the roots of the configuration file's profile (see --profile) are scanned. \
+A directory on another machine is given as ssh://[user@]host[:port]/path (see --remote-helper), \
+and a bucket, or a prefix of one, as s3://bucket/prefix (see --s3-download).")]
+    directories: Vec<PathBuf>,
+
+    /// number of threads used to hash files (default: one per CPU)
+    #[arg(long, default_value_t = 0, value_name = "N", long_help = "\
+Number of worker threads used to hash new or changed files during the scan. \
+Hashing is usually the slowest part of a scan, so spreading it across cores \
+makes large photo and video libraries much faster to index. Results are fed \
+back to a single database writer, so the database is never accessed from more \
+than one thread. Defaults to 0, which uses one thread per logical CPU; use 1 \
+to hash sequentially (e.g. on a spinning disk where parallel reads thrash).")]
+    threads: usize,
+
+    /// read buffer size used when hashing files, in KiB
+    #[arg(long, default_value_t = 1024, value_name = "KIB", long_help = "\
+Size of the read buffer used when hashing files, in KiB (default 1024, i.e. \
+1 MiB). Files are streamed through this buffer rather than loaded into memory, \
+so peak memory use is roughly this size times --threads regardless of how large \
+the files are. Larger buffers can help on fast storage; smaller ones reduce \
+memory use on constrained machines.")]
+    hash_buffer: usize,
+
+    /// only fully hash files whose size and partial hash collide
+    #[arg(long, long_help = "\
+Skip reading files that cannot have a duplicate. Files are grouped by size \
+first; a file whose size is unique is never read. Files that share a size have \
+only their first and last 64 KiB hashed, and only those whose partial hashes \
+still match are hashed in full. Files proven unique this way are stored with a \
+provisional \"unhashed:\" hash, and are hashed properly as soon as a later scan \
+finds a file that could match them. On large trees this cuts scan time \
+dramatically, at the cost of the database no longer holding a content hash \
+for every file.")]
+    prefilter: bool,
+
+    /// hash algorithm for a new database (default: blake3)
+    #[arg(long = "hash", value_enum, value_name = "ALGO", long_help = "\
+Hash algorithm used for file and directory hashes. The choice is recorded in \
+the database the first time it is used, and every later run reuses it; asking \
+for a different algorithm than the database was built with is an error, since \
+hashes from different algorithms can never match. New databases default to \
+blake3, which is several times faster than sha256 and still cryptographic. \
+Databases created before this option existed hold sha256 hashes. xxh3 is the \
+fastest, but is not collision resistant against deliberately crafted files.")]
+    hash: Option<hashing::HashAlgorithm>,
+
+    /// memory-map files at least this many MiB when hashing (0 = never)
+    #[arg(long, default_value_t = 256, value_name = "MIB", long_help = "\
+Files at least this large (in MiB, default 256) are memory-mapped and hashed \
+directly from the page cache instead of being copied through the read buffer, \
+which noticeably speeds up hashing of large media files. If a file cannot be \
+mapped, it is streamed as usual. Set to 0 to always stream.")]
+    mmap_threshold: u64,
+
+    /// read at most this many MiB per second while hashing (0 = no limit)
+    #[arg(long, default_value_t = 0, value_name = "MIB", long_help = "\
+Cap the rate at which the scan reads file contents, in MiB per second across \
+all hashing threads together, so a scan running in the background leaves the \
+disk to everything else. Short bursts of up to a tenth of a second are let \
+through. Defaults to 0, which reads as fast as the disk allows.")]
+    throttle: u64,
+
+    /// name the volume being scanned, e.g. nas
+    #[arg(long, value_name = "NAME", long_help = "\
+Record NAME as the volume the scanned files are on, next to this machine's \
+host name. Reports name the host or volume of every copy once the database \
+spans more than one, and a label stays the same wherever the volume is \
+mounted: label a NAS `nas` and its files are reported as on nas whether it \
+was scanned at /mnt/nas or /Volumes/nas. A rescan without --label keeps the \
+label given before.")]
+    label: Option<String>,
+
+    /// scan at a lower CPU priority
+    #[arg(long, long_help = "\
+Run the scan at a lower CPU priority, like nice -n 10 on Unix or below-normal \
+priority on Windows, so hashing yields to interactive programs.")]
+    nice: bool,
+
+    /// only read from disk when nothing else is
+    #[arg(long, long_help = "\
+Give the scan's disk reads the lowest priority: the idle I/O class on Linux \
+(like ionice -c 3, honoured by the BFQ and CFQ schedulers), background \
+priority on macOS and Windows, which also lowers CPU priority there. On other \
+platforms a warning is printed and the scan runs as usual.")]
+    idle_io: bool,
+
+    /// continue an interrupted scan, skipping directories it already finished
+    #[arg(long, long_help = "\
+Continue a scan that was interrupted (for example with Ctrl-C). Pressing \
+Ctrl-C during a scan stops hashing, keeps every file hashed so far, and \
+records which of the listed directories were already scanned to the end. \
+Running again with --resume skips those directories; the rest are rescanned, \
+which is quick because files hashed before the interruption are not read \
+again. Without --resume, every listed directory is scanned. Press Ctrl-C \
+twice to quit immediately.")]
+    resume: bool,
+
+    /// cache each file's hash in a user.deduplifier extended attribute
+    #[arg(long, long_help = "\
+Store the hash of every file scanned, with its size and modification time, in \
+a user.deduplifier extended attribute on the file itself, and use it instead \
+of reading the file whenever the database has no up-to-date hash: after the \
+database is deleted, with a separate database, or when the same files are \
+scanned from another machine over NFS or SMB. An attribute is only trusted \
+while the file's size and modification time (to the nanosecond) still match \
+and it was written with the same --hash algorithm. Files that can't carry \
+attributes, such as those on read-only or FAT filesystems, are hashed as \
+usual. Supported on Linux and macOS.")]
+    xattr_cache: bool,
+
+    /// also hash the files inside zip and tar archives
+    #[arg(long, long_help = "\
+Look inside zip, tar and gzipped tar (.tar.gz, .tgz) archives as well: every \
+file in one is hashed as it is decompressed and stored under the archive's \
+path, as in /backup/2019.zip!/photos/a.jpg, so files that also exist inside \
+an old backup archive show up as duplicates of it. An archive is only read \
+again once it changes. Files inside archives are reported like any other, but \
+dedupe never offers them for removal, verify and checksum manifests skip \
+them, and directory hashes leave them out, so dup-dirs is unaffected. Archives inside \
+archives are hashed as files, not opened.")]
+    scan_archives: bool,
+
+    /// don't descend into directories on other filesystems
+    #[arg(short = 'x', long, long_help = "\
+Stay on the filesystem of each directory given, like du -x or rsync -x: \
+directories that are mount points for another filesystem (network shares, \
+bind mounts, /proc when scanning /) are not descended into. The mount point \
+itself is recorded as an empty directory.")]
+    one_file_system: bool,
+
+    /// skip files and directories matching this glob (repeatable)
+    #[arg(long, value_name = "GLOB", long_help = "\
+Leave out every file and directory matching GLOB; an excluded directory is not \
+even descended into. A pattern without a / matches names at any depth \
+(node_modules, .git, *.tmp); one with a / matches the path relative to the \
+directory being scanned (photos/*/thumbs, /build), where * stays within one \
+level and ** spans any number. A trailing / only matches directories \
+(target/). May be given several times. Excluded files don't count towards \
+directory hashes, so directories that only differ in excluded files are \
+reported as duplicates.")]
+    exclude: Vec<String>,
+
+    /// skip a built-in set of system or development directories (comma-separated, repeatable)
+    #[arg(long = "preset", value_name = "PRESET", value_enum, value_delimiter = ',', long_help = "\
+Exclude a built-in set of directories, as if each was given with --exclude, \
+so that a scan of / or a home directory works without a dozen exclude flags. \
+system leaves out virtual filesystems and temporary files (/proc, /sys, /dev, \
+/run, /tmp on Linux; /System and /private/var on macOS; \\Windows, pagefile.sys \
+and System Volume Information on Windows), the trash and application and \
+browser caches. dev leaves out dependency and build directories \
+(node_modules, target, .venv, __pycache__, .gradle and the like) and package \
+manager caches. The system paths only match when the root of the drive is \
+scanned. Comma-separated or given several times.")]
+    presets: Vec<walk::Preset>,
+
+    /// only scan files matching this glob (repeatable)
+    #[arg(long, value_name = "GLOB", long_help = "\
+Only scan files matching GLOB, written as for --exclude (*.jpg, raw/**). \
+Directories are still walked unless excluded. May be given several times; a \
+file matching any of the patterns is scanned.")]
+    include: Vec<String>,
+
+    /// skip paths matching this regular expression (repeatable)
+    #[arg(long, value_name = "REGEX", long_help = "\
+Leave out every file and directory whose path relative to the directory being \
+scanned, written with / separators on every platform, matches REGEX \
+(unanchored, Rust regex syntax), e.g. '(^|/)\\.cache(/|$)'. Excluded \
+directories are not descended into. May be given several times.")]
+    exclude_regex: Vec<String>,
+
+    /// also skip what .gitignore files ignore
+    #[arg(long, long_help = "\
+Honour .gitignore files the way .dedupignore files always are: every file and \
+directory they ignore is left out of the scan, no matter whether the \
+directory is a git repository. Handy for keeping build output and \
+dependencies out of a scan of a home directory.")]
+    gitignore: bool,
+
+    /// only descend this many levels below each directory
+    #[arg(long, value_name = "N", long_help = "\
+Only scan files at most N levels below each directory given (1: the files \
+directly inside it). Subdirectories deeper than that are left out altogether \
+rather than recorded as empty, but the directories above them are hashed \
+without them, so compare directory duplicates with care.")]
+    max_depth: Option<usize>,
+
+    /// skip hidden files and directories
+    #[arg(long, long_help = "\
+Leave out files and directories whose name starts with a dot, and on Windows \
+those with the hidden attribute too, without descending into hidden \
+directories. The directories given are scanned even if hidden themselves.")]
+    skip_hidden: bool,
+
+    /// descend into symlinked directories
+    #[arg(short = 'L', long, long_help = "\
+Descend into symbolic links to directories, which are otherwise skipped \
+(links to files are always scanned). Each directory is still scanned at most \
+once: a link pointing into a directory being scanned is skipped, since its \
+target is scanned anyway, and so is a directory already reached through \
+another link, which also keeps link cycles from looping. Files reached \
+through a link are marked as such in the database and in reports.")]
+    follow_symlinks: bool,
+
+    /// only scan files of these kinds (comma-separated, repeatable)
+    #[arg(long = "type", value_name = "KIND", value_enum, value_delimiter = ',', long_help = "\
+Only scan files of these kinds, recognised by extension (or by content with \
+--sniff): image, video, audio, document (PDFs, office documents, e-books, \
+text) or archive. Comma-separated or given several times; together with --ext, \
+a file matching either is scanned. Directories are always walked.")]
+    types: Vec<walk::FileKind>,
+
+    /// only scan files with these extensions (comma-separated, repeatable)
+    #[arg(long, value_name = "EXT", value_delimiter = ',', long_help = "\
+Only scan files with one of these extensions, compared case-insensitively \
+with or without the dot (--ext jpg,png,.heic). Comma-separated or given \
+several times.")]
+    ext: Vec<String>,
+
+    /// skip files smaller than this many bytes
+    #[arg(long, value_name = "BYTES", long_help = "\
+Leave out files smaller than BYTES, such as thumbnails and icons, which \
+are seldom worth deduplicating. Like excluded files, they don't count \
+towards directory hashes.")]
+    min_file_size: Option<u64>,
+
+    /// recognise --type by file content
+    #[arg(long, requires = "types", long_help = "\
+Decide --type by each file's first bytes (its magic number) rather than its \
+extension, so misnamed and extensionless files are sorted correctly. Files \
+whose content isn't recognised, such as plain text, go by extension. Every \
+file whose extension doesn't match --ext is opened to check.")]
+    sniff: bool,
+
+    /// how to start deduplifier on the machines of ssh:// roots
+    #[arg(long, value_name = "CMD", default_value = "deduplifier", long_help = "\
+The command that starts deduplifier on the machine an ssh://[user@]host[:port]/path \
+root is on, as the shell there reads it, e.g. ~/bin/deduplifier when it isn't \
+on the PATH ssh gives. It lists and hashes the files where they are, walking \
+with this scan's --exclude, --ext and other options, so only the listing and \
+the hashes cross the network.")]
+    remote_helper: String,
+
+    /// download s3:// objects whose ETag can't tell if they're here
+    #[arg(long, long_help = "\
+Objects under an s3://bucket/prefix root are listed with the aws command line \
+tool, which uses its usual credentials (and AWS_PROFILE). An object uploaded in \
+one part has the MD5 of its content as its ETag, which is checked against the \
+local files of the same size, so scan local roots first. One uploaded in parts \
+(as large files are) only matches another object with the same ETag; with this \
+flag it is downloaded and hashed instead, when some local file has its size.")]
+    s3_download: bool,
+
+    /// the endpoint of an S3-compatible service for s3:// roots
+    #[arg(long, value_name = "URL")]
+    s3_endpoint: Option<String>,
+}
+
+impl ScanArgs {
+    /// Fill in what the command line left out from the configuration file:
+    /// its roots when no directories were given, its patterns ahead of the
+    /// command line's, and its other settings where no option was given.
+    fn apply(&mut self, settings: &profile::Settings) {
+        if self.directories.is_empty() {
+            self.directories = settings.roots.clone();
+        }
+        let ahead = |from_file: &[String], given: &mut Vec<String>| {
+            *given = [from_file, given.as_slice()].concat();
+        };
+        ahead(&settings.exclude, &mut self.exclude);
+        ahead(&settings.include, &mut self.include);
+        ahead(&settings.exclude_regex, &mut self.exclude_regex);
+        ahead(&settings.ext, &mut self.ext);
+        self.min_file_size = self.min_file_size.or(settings.min_file_size);
+        self.hash = self.hash.or(settings.hash);
+        if self.threads == 0 {
+            self.threads = settings.threads.unwrap_or(0);
+        }
+        self.skip_hidden |= settings.skip_hidden;
+        self.gitignore |= settings.gitignore;
+    }
+
+    /// The options that make `remote-helper` walk and hash a remote root the
+    /// way this scan walks and hashes local ones.
+    fn helper_args(&self, algorithm: hashing::HashAlgorithm) -> Vec<String> {
+        fn name(value: &impl clap::ValueEnum) -> String {
+            value.to_possible_value().map_or_else(String::new, |v| v.get_name().to_string())
+        }
+        let mut args = vec![
+            format!("--hash={}", algorithm.name()),
+            format!("--threads={}", self.threads),
+            format!("--hash-buffer={}", self.hash_buffer),
+            format!("--mmap-threshold={}", self.mmap_threshold),
+            format!("--throttle={}", self.throttle),
+        ];
+        args.extend(self.exclude.iter().map(|p| format!("--exclude={p}")));
+        args.extend(self.include.iter().map(|p| format!("--include={p}")));
+        args.extend(self.exclude_regex.iter().map(|p| format!("--exclude-regex={p}")));
+        args.extend(self.ext.iter().map(|e| format!("--ext={e}")));
+        for preset in &self.presets {
+            args.push(format!("--preset={}", name(preset)));
+        }
+        for kind in &self.types {
+            args.push(format!("--type={}", name(kind)));
+        }
+        if let Some(depth) = self.max_depth {
+            args.push(format!("--max-depth={depth}"));
+        }
+        if let Some(size) = self.min_file_size {
+            args.push(format!("--min-file-size={size}"));
+        }
+        let flags = [
+            ("--nice", self.nice),
+            ("--idle-io", self.idle_io),
+            ("--one-file-system", self.one_file_system),
+            ("--gitignore", self.gitignore),
+            ("--skip-hidden", self.skip_hidden),
+            ("--follow-symlinks", self.follow_symlinks),
+            ("--sniff", self.sniff),
+        ];
+        args.extend(flags.iter().filter(|(_, on)| *on).map(|(flag, _)| flag.to_string()));
+        args
+    }
+
+    /// The options this scan was given, as words `scan_options_from_args`
+    /// reads back: the helper's, and those only a local scan uses.
+    fn option_args(&self, algorithm: hashing::HashAlgorithm) -> Vec<String> {
+        let mut args = self.helper_args(algorithm);
+        args.push(format!("--remote-helper={}", self.remote_helper));
+        if let Some(endpoint) = &self.s3_endpoint {
+            args.push(format!("--s3-endpoint={endpoint}"));
+        }
+        let flags = [
+            ("--prefilter", self.prefilter),
+            ("--xattr-cache", self.xattr_cache),
+            ("--scan-archives", self.scan_archives),
+            ("--s3-download", self.s3_download),
+        ];
+        args.extend(flags.iter().filter(|(_, on)| *on).map(|(flag, _)| flag.to_string()));
+        args
+    }
+
+    /// The directories to scan, in order (see `build_scan_list`).
+    fn scan_list<'a>(&'a self, canon: Option<&'a PathBuf>) -> Vec<&'a Path> {
+        build_scan_list(&self.directories, canon)
+            .into_iter()
+            .map(|p| p.as_path())
+            .collect()
+    }
+
+    fn scan_options(&self, algorithm: hashing::HashAlgorithm) -> Result<scan::ScanOptions> {
+        let mut exclude = self.exclude.clone();
+        for preset in &self.presets {
+            exclude.extend(preset.excludes().into_iter().map(String::from));
+        }
+        Ok(scan::ScanOptions {
+            threads: self.threads,
+            hash: hashing::HashOptions {
+                buffer_size: self.hash_buffer * 1024,
+                algorithm,
+                mmap_threshold: self.mmap_threshold * 1024 * 1024,
+                throttle: self.throttle * 1024 * 1024,
+            },
+            prefilter: self.prefilter,
+            xattr_cache: self.xattr_cache,
+            scan_archives: self.scan_archives,
+            remote: remote::RemoteOptions {
+                helper: self.remote_helper.clone(),
+                args: self.helper_args(algorithm),
+            },
+            s3: s3::S3Options {
+                download: self.s3_download,
+                endpoint: self.s3_endpoint.clone(),
+            },
+            walk: walk::WalkOptions {
+                one_file_system: self.one_file_system,
+                filter: walk::PathFilter::new(&exclude, &self.include, &self.exclude_regex)?,
+                gitignore: self.gitignore,
+                max_depth: self.max_depth,
+                skip_hidden: self.skip_hidden,
+                follow_symlinks: self.follow_symlinks,
+                types: walk::TypeFilter::new(&self.ext, &self.types, self.sniff),
+                min_file_size: self.min_file_size.unwrap_or(0),
+            },
+            on_hashed: ui::on_hashed(),
+            cancel: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+/// Build the ordered list of directories to scan: canon first (if provided and not already
+/// present), then the rest. Canon is first so its hashes are in the DB before we scan others.
+pub fn build_scan_list<'a>(
+    directories: &'a [PathBuf],
+    canon: Option<&'a PathBuf>,
+) -> Vec<&'a PathBuf> {
+    let mut list: Vec<&PathBuf> = Vec::new();
+    if let Some(c) = canon {
+        list.push(c);
+    }
+    for dir in directories {
+        if !list.contains(&dir) {
+            list.push(dir);
+        }
+    }
+    list
+}
+
+/// Exit status when duplicates were found.
+const EXIT_DUPLICATES: u8 = 1;
+/// Exit status when files could not be scanned or the command failed.
+const EXIT_ERROR: u8 = 2;
+/// Exit status when Ctrl-C stopped the run.
+const EXIT_INTERRUPTED: u8 = 130;
+
+/// What a run found, for the exit status.
+#[derive(Default)]
+struct Outcome {
+    /// Duplicates, or what else the command looks for (corrupt files for
+    /// verify, problems for doctor), were found
+    duplicates_found: bool,
+    scan_errors: usize,
+}
+
+impl Outcome {
+    fn exit_code(&self) -> ExitCode {
+        if self.scan_errors > 0 {
+            ExitCode::from(EXIT_ERROR)
+        } else if self.duplicates_found {
+            ExitCode::from(EXIT_DUPLICATES)
+        } else {
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// Resolve the hash algorithm and, unless `no_scan`, scan `directories`,
+/// counting files that could not be hashed in `outcome`. Returns the options
+/// the scan used, which later steps hash with too.
+fn scan_if_needed(
+    conn: &rusqlite::Connection,
+    args: &ScanArgs,
+    directories: &[&Path],
+    no_scan: bool,
+    outcome: &mut Outcome,
+) -> Result<scan::ScanOptions> {
+    let algorithm = hashing::resolve_algorithm(conn, args.hash)?;
+    let opts = args.scan_options(algorithm)?;
+    if no_scan {
+        return Ok(opts);
+    }
+    if args.nice && !throttle::lower_cpu_priority()? {
+        ui::show_unsupported("--nice");
+    }
+    if args.idle_io && !throttle::idle_io_priority()? {
+        ui::show_unsupported("--idle-io");
+    }
+    if args.xattr_cache && !xattr::SUPPORTED {
+        ui::show_unsupported("--xattr-cache");
+    }
+    let _interruptible = cancel_on_ctrl_c(opts.cancel.clone())?;
+    outcome.scan_errors +=
+        ui::run_scan(conn, directories, &opts, args.resume, args.label.as_deref())?;
+    let options = args.option_args(algorithm);
+    for directory in directories {
+        db::set_root_options(conn, &utils::path_to_db(directory), &options)?;
+    }
+    Ok(opts)
+}
+
+/// The scan options `args` stand for, as `ScanArgs::option_args` recorded
+/// them, so a root can be scanned again the way it was last (see
+/// `db::root_options`).
+pub fn scan_options_from_args(args: &[String]) -> Result<scan::ScanOptions> {
+    let argv = ["deduplifier", "scan"].into_iter().map(String::from);
+    let cli = Cli::try_parse_from(argv.chain(args.iter().cloned()))?;
+    let Command::Scan { scan } = cli.command else {
+        unreachable!("parsed as a scan");
+    };
+    let mut opts = scan.scan_options(scan.hash.unwrap_or_default())?;
+    // Nothing to tell of what is hashed, away from the command line
+    opts.on_hashed = None;
+    Ok(opts)
+}
+
+/// List and hash the directory a scan of an `ssh://` root asked for, talking
+/// to it over standard input and output (see `remote::serve`).
+fn run_remote_helper(args: &ScanArgs) -> Result<Outcome> {
+    let [root] = args.directories.as_slice() else {
+        bail!("remote-helper takes one directory");
+    };
+    let opts = args.scan_options(args.hash.unwrap_or_default())?;
+    if args.nice {
+        throttle::lower_cpu_priority()?;
+    }
+    if args.idle_io {
+        throttle::idle_io_priority()?;
+    }
+    let output = std::io::BufWriter::new(std::io::stdout().lock());
+    remote::serve(root, &opts, std::io::stdin().lock(), output)?;
+    Ok(Outcome::default())
+}
+
+/// The flag Ctrl-C sets while something that stops cleanly on it is running.
+static INTERRUPTIBLE: Mutex<Option<Arc<AtomicBool>>> = Mutex::new(None);
+
+/// Set `cancel` on the first Ctrl-C for as long as the returned guard lives,
+/// so whatever checks it can stop cleanly; a second one quits now. Without a
+/// guard, Ctrl-C quits at once as it does by default, so prompts after a scan
+/// aren't left ignoring it.
+fn cancel_on_ctrl_c(cancel: Arc<AtomicBool>) -> Result<Interruptible> {
+    static HANDLER: Once = Once::new();
+    let mut installed = Ok(());
+    HANDLER.call_once(|| {
+        installed = ctrlc::set_handler(|| {
+            let cancel = INTERRUPTIBLE.lock().ok().and_then(|c| c.clone());
+            match cancel {
+                Some(cancel) if !cancel.swap(true, Ordering::SeqCst) => {}
+                _ => std::process::exit(EXIT_INTERRUPTED.into()),
+            }
+        });
+    });
+    installed?;
+    *INTERRUPTIBLE.lock().unwrap() = Some(cancel);
+    Ok(Interruptible)
+}
+
+/// While it lives, Ctrl-C cancels instead of quitting (see `cancel_on_ctrl_c`).
+struct Interruptible;
+
+impl Drop for Interruptible {
+    fn drop(&mut self) {
+        if let Ok(mut cancel) = INTERRUPTIBLE.lock() {
+            *cancel = None;
+        }
+    }
+}
+
+/// `deduplifier daemon`: check or load the configuration, then scan on
+/// schedule until stopped, in the background with --detach.
+fn run_daemon(cli: &Cli, config: &Path, detach: bool, check: bool) -> Result<Outcome> {
+    let config = daemon::DaemonConfig::load(config)?;
+    if check {
+        ui::show_daemon_schedule(&config)?;
+        return Ok(Outcome::default());
+    }
+    if cli.dry_run {
+        bail!("--dry-run is not supported by daemon; use --check to see what it would scan");
+    }
+    let database = config.database.clone().unwrap_or_else(|| cli.database().to_path_buf());
+    if cli.encrypted {
+        db::set_key(database_key(&database)?)?;
+    }
+    if detach {
+        if config.log.is_none() {
+            bail!("--detach needs `log` set in the configuration");
+        }
+        // Before any threads are started, which a fork wouldn't carry over
+        if let Some(pid) = daemon::detach()? {
+            let log = config.log.as_ref().unwrap();
+            println!("Daemon started (pid {pid}), logging to {}", log.display());
+            return Ok(Outcome::default());
+        }
+    }
+    let mut log = daemon::Log::open(config.log.as_deref(), config.log_max_size, config.log_keep)?;
+    let store = HashStore::open(&database)?;
+    let cancel = Arc::new(AtomicBool::new(false));
+    let _interruptible = cancel_on_ctrl_c(cancel.clone())?;
+    daemon::run(&store, &config, &mut log, &cancel)?;
+    Ok(Outcome::default())
+}
+
+/// The key `--encrypted` opens `database` with: `DEDUPLIFIER_KEY`, or else
+/// asked for, confirmed when the database is about to be created.
+#[cfg(feature = "sqlcipher")]
+fn database_key(database: &Path) -> Result<String> {
+    match std::env::var("DEDUPLIFIER_KEY") {
+        Ok(key) if !key.is_empty() => Ok(key),
+        _ => ui::prompt_database_key(!database.exists()),
+    }
+}
+
+#[cfg(not(feature = "sqlcipher"))]
+fn database_key(_database: &Path) -> Result<String> {
+    bail!("--encrypted needs a build with SQLCipher: cargo build --features sqlcipher")
+}
+
+/// How this run was invoked, for others to see while it holds the run lock.
+fn command_line() -> String {
+    let args = std::env::args_os().skip(1).map(|a| a.to_string_lossy().into_owned());
+    std::iter::once("deduplifier".to_string())
+        .chain(args)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The `deduplifier` command: parse the command line, run it, and give the
+/// exit code for its outcome.
+pub fn main() -> ExitCode {
+    match run(Cli::parse()) {
+        Ok(outcome) => outcome.exit_code(),
+        // What was interrupted has said so already
+        Err(e) if e.is::<scan::Interrupted>() => ExitCode::from(EXIT_INTERRUPTED),
+        Err(e) => {
+            ui::emit(|| events::fatal(&e));
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+fn run(mut cli: Cli) -> Result<Outcome> {
+    ui::set_quiet(cli.quiet);
+    logging::init(cli.verbose, cli.log_file.as_deref())?;
+    if let Command::RemoteHelper { scan } = &cli.command {
+        // The other end has the database, and the settings
+        return run_remote_helper(scan);
+    }
+    if let Some(events::EventFormat::Jsonl) = cli.events {
+        ui::set_events(match &cli.events_file {
+            Some(path) => events::EventStream::to_file(path)?,
+            None => events::EventStream::to_stdout()?,
+        });
+    }
+    let settings = profile::load_settings(cli.profile.as_deref())?;
+    if cli.database.is_none() {
+        cli.database = settings.database.clone();
+    }
+    ui::set_hooks(hooks::Hooks {
+        on_duplicate_group: cli.on_duplicate_group.clone().or(settings.on_duplicate_group.clone()),
+        post_scan: cli.post_scan.clone().or(settings.post_scan.clone()),
+    });
+    if let Some(scan) = cli.command.scan_args_mut() {
+        scan.apply(&settings);
+        if scan.directories.is_empty() {
+            bail!("no directories to scan; name some, or pick a --profile with roots");
+        }
+    }
+    if let Command::Daemon { config, detach, check } = &cli.command {
+        // The configuration names the database, so it is opened there
+        return run_daemon(&cli, config, *detach, *check);
+    }
+    let reads_only = matches!(
+        cli.command,
+        Command::Report { .. }
+            | Command::Compare { .. }
+            | Command::Verify { .. }
+            | Command::Export { .. }
+            | Command::Stats { .. }
+            | Command::Errors
+            | Command::Scans
+            | Command::Diff { .. }
+            | Command::Doctor { .. }
+            | Command::Serve { .. }
+    );
+    let database = cli.database();
+    if reads_only && !database.exists() {
+        // Opening would create an empty database and report nothing
+        bail!("database {} does not exist; run `deduplifier scan` first", database.display());
+    }
+    file_system::set_dry_run(cli.dry_run);
+    if cli.encrypted {
+        db::set_key(database_key(database)?)?;
+    }
+    let store = if cli.dry_run {
+        HashStore::open_scratch_copy(database)?
+    } else {
+        HashStore::open(database)?
+    };
+    let conn = store.connection();
+
+    // Commands that change the database run one at a time; the others only
+    // warn that what they read may be half done
+    let changes = !reads_only || matches!(cli.command, Command::Doctor { vacuum: true });
+    let _lock = if changes && !cli.dry_run {
+        Some(ui::acquire_run_lock(conn, &command_line(), cli.wait)?)
+    } else {
+        if let Some(holder) = db::lock_holder(conn)?.filter(|h| !h.is_abandoned()) {
+            ui::show_run_in_progress(&holder);
+        }
+        None
+    };
+
+    let mut outcome = Outcome::default();
+    match &cli.command {
+        Command::Scan { scan } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, false, &mut outcome)?;
+        }
+        Command::Watch {
+            scan,
+            no_scan,
+            duplicates,
+            settle,
+        } => {
+            let directories = scan.scan_list(None);
+            let opts = scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            let _interruptible = cancel_on_ctrl_c(opts.cancel.clone())?;
+            let settle = std::time::Duration::from_secs(*settle);
+            outcome.scan_errors += ui::run_watch(conn, &directories, &opts, settle, *duplicates)?;
+        }
+        Command::Daemon { .. } => unreachable!("daemon runs before the database is opened"),
+        Command::RemoteHelper { .. } => unreachable!("remote-helper never opens the database"),
+        Command::Serve { listen, allow_scan } => {
+            let options = serve::ServeOptions {
+                allow_scan: *allow_scan,
+                ..serve::ServeOptions::default()
+            };
+            let _interruptible = cancel_on_ctrl_c(options.cancel.clone())?;
+            ui::run_serve(&store, *listen, &options)?;
+        }
+        Command::DupDirs {
+            scan,
+            no_scan,
+            canon,
+            delete,
+            no_confirmation,
+            include_empty,
+        } => {
+            let directories = scan.scan_list(canon.as_ref());
+            scan::count_empty_files(conn, *include_empty)?;
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            ui::show_section("Finding duplicate directories");
+            outcome.duplicates_found = ui::run_dup_dirs(
+                conn,
+                *delete,
+                canon.as_deref(),
+                *no_confirmation,
+                &directories,
+                *include_empty,
+            )?;
+        }
+        Command::DupFiles {
+            scan,
+            no_scan,
+            format,
+            size,
+            include_empty,
+        } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            if *format == report::DupFilesFormat::Text {
+                ui::show_section("Finding duplicate files");
+            }
+            outcome.duplicates_found = ui::run_dup_files(conn, *format, *size, *include_empty)?;
+        }
+        Command::Dedupe {
+            scan,
+            no_scan,
+            auto,
+            rules,
+            keep_n,
+            action,
+            permanent,
+            quarantine,
+            no_verify,
+            emit_script,
+            script_shell,
+            ..
+        } => {
+            if *permanent && *action != dedupe::Action::Delete {
+                bail!("--permanent only applies to --action delete");
+            }
+            if quarantine.is_some() && *action != dedupe::Action::Delete {
+                bail!("--quarantine only applies to --action delete");
+            }
+            let apply = dedupe::ApplyOptions {
+                action: *action,
+                permanent: *permanent,
+                quarantine: quarantine.clone(),
+                verify: !no_verify,
+            };
+            let directories = scan.scan_list(None);
+            let opts = scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            let algorithm = opts.hash.algorithm;
+            let plans = if *auto {
+                let policy = dedupe::KeepPolicy {
+                    rules: rules.clone(),
+                    keep_n: *keep_n,
+                };
+                ui::plan_dedupe_auto(conn, &directories, &policy, &apply)?
+            } else {
+                ui::plan_dedupe_interactive(conn, &directories)?
+            };
+            outcome.duplicates_found = plans.is_some();
+            match (plans, emit_script) {
+                (None, _) => {}
+                (Some(plans), Some(path)) => {
+                    ui::write_dedupe_script(&plans, &apply, *script_shell, path)?
+                }
+                (Some(plans), None) => ui::apply_dedupe(conn, &plans, &apply, !auto, algorithm)?,
+            }
+        }
+        Command::Tui {
+            scan,
+            no_scan,
+            link,
+            permanent,
+            quarantine,
+            no_verify,
+        } => {
+            if *link == dedupe::Action::Delete {
+                bail!("--link takes hardlink, symlink, relative-symlink or reflink");
+            }
+            let directories = scan.scan_list(None);
+            let opts = scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            let delete = dedupe::ApplyOptions {
+                action: dedupe::Action::Delete,
+                permanent: *permanent,
+                quarantine: quarantine.clone(),
+                verify: !no_verify,
+            };
+            let tui = tui::TuiOptions {
+                link: dedupe::ApplyOptions {
+                    action: *link,
+                    ..delete.clone()
+                },
+                delete,
+                algorithm: opts.hash.algorithm,
+            };
+            outcome.duplicates_found = tui::run(conn, &directories, &tui)?;
+        }
+        Command::Similar {
+            scan,
+            no_scan,
+            threshold,
+        } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            ui::show_similarity_section(*threshold);
+            ui::run_similar(conn, *threshold, &directories, true)?;
+        }
+        Command::Overlap {
+            scan,
+            no_scan,
+            threshold,
+        } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            outcome.duplicates_found = ui::run_overlap(conn, *threshold, &directories)?;
+        }
+        Command::Contained {
+            scan,
+            no_scan,
+            within,
+        } => {
+            let directories = scan.scan_list(None);
+            let within: Vec<&Path> = within.iter().map(|p| p.as_path()).collect();
+            let mut scanned = directories.clone();
+            scanned.extend(within.iter().filter(|p| !directories.contains(p)));
+            scan_if_needed(conn, scan, &scanned, *no_scan, &mut outcome)?;
+            outcome.duplicates_found = ui::run_contained(conn, &directories, &within)?;
+        }
+        Command::SimilarImages {
+            scan,
+            no_scan,
+            threshold,
+        } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            outcome.duplicates_found = ui::run_similar_images(conn, *threshold, &directories)?;
+        }
+        Command::SimilarAudio {
+            scan,
+            no_scan,
+            threshold,
+        } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            outcome.duplicates_found = ui::run_similar_audio(conn, *threshold, &directories)?;
+        }
+        Command::SimilarText {
+            scan,
+            no_scan,
+            threshold,
+        } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            outcome.duplicates_found = ui::run_similar_text(conn, *threshold, &directories)?;
+        }
+        Command::SharedChunks {
+            scan,
+            no_scan,
+            min_size,
+            threshold,
+        } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            let min_size = (*min_size * 1024 * 1024) as i64;
+            outcome.duplicates_found =
+                ui::run_shared_chunks(conn, min_size, *threshold, &directories)?;
+        }
+        Command::SameName {
+            scan,
+            no_scan,
+            stem,
+        } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            outcome.duplicates_found = ui::run_same_name(conn, &directories, *stem)?;
+        }
+        Command::SimilarVideos {
+            scan,
+            no_scan,
+            threshold,
+        } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            outcome.duplicates_found = ui::run_similar_videos(conn, *threshold, &directories)?;
+        }
+        Command::DupPhotos { scan, no_scan } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            outcome.duplicates_found = ui::run_dup_photos(conn, &directories)?;
+        }
+        Command::DupCompressed { scan, no_scan } => {
+            let directories = scan.scan_list(None);
+            scan_if_needed(conn, scan, &directories, *no_scan, &mut outcome)?;
+            outcome.duplicates_found = ui::run_dup_compressed(conn, &directories)?;
+        }
+        Command::Merge {
+            scan,
+            canon,
+            delete,
+            no_confirmation,
+        } => {
+            if !delete {
+                bail!("merge requires --delete, because it will delete files without prompting.");
+            }
+            let directories = scan.scan_list(Some(canon));
+            let opts = scan_if_needed(conn, scan, &directories, false, &mut outcome)?;
+            let sources: Vec<&Path> = directories
+                .iter()
+                .copied()
+                .filter(|&p| p != canon)
+                .collect();
+            ui::show_section("Merging directories into canon");
+            ui::run_merge(conn, canon, &sources, *no_confirmation, &opts.hash)?;
+        }
+        Command::SortPhotos {
+            scan,
+            canon,
+            delete,
+            no_confirmation,
+        } => {
+            if !delete || !no_confirmation {
+                bail!(
+                    "sort-photos requires both --delete and --no-confirmation, \
+                     because it will move and delete files without prompting."
+                );
+            }
+            let directories = scan.scan_list(Some(canon));
+            let opts = scan_if_needed(conn, scan, &directories, false, &mut outcome)?;
+            ui::show_section("Sorting photos into date-based directories");
+            ui::run_sort_photos(conn, &directories, canon, &opts.hash)?;
+        }
+        Command::Report {
+            directories,
+            min_count,
+            min_wasted,
+            unique,
+            format,
+            output,
+            include_empty,
+            top_level,
+        } => {
+            if output.is_some() && *format == report::Format::Text {
+                bail!("--output needs --format json or --format html");
+            }
+            let scope: Vec<&Path> = directories.iter().map(|p| p.as_path()).collect();
+            if *unique {
+                if *format == report::Format::Html {
+                    bail!("--unique supports --format text and json");
+                }
+                ui::run_unique_report(conn, &scope, *format, output.as_deref())?;
+                return Ok(outcome);
+            }
+            let filter = duplicates::ReportFilter {
+                min_count: *min_count,
+                min_wasted: *min_wasted,
+                include_empty: *include_empty,
+                top_level: *top_level,
+            };
+            scan::count_empty_files(conn, *include_empty)?;
+            outcome.duplicates_found =
+                ui::run_report(conn, &scope, filter, *format, output.as_deref())?;
+        }
+        Command::Verify { paths, threads } => {
+            let algorithm = hashing::resolve_algorithm(conn, None)?;
+            let opts = hashing::HashOptions {
+                algorithm,
+                ..Default::default()
+            };
+            let paths: Vec<&Path> = paths.iter().map(|p| p.as_path()).collect();
+            let result = ui::run_verify(conn, &paths, &opts, *threads)?;
+            outcome.duplicates_found = !result.corrupt.is_empty();
+            outcome.scan_errors += result.errors.len();
+        }
+        Command::Compare {
+            src,
+            dst,
+            show_matched,
+        } => {
+            let src: PathBuf = src.components().collect();
+            let dst: PathBuf = dst.components().collect();
+            ui::run_compare(conn, &src, &dst, *show_matched)?;
+        }
+        Command::Export {
+            output,
+            duplicates_only,
+            format,
+        } => {
+            ui::run_export(conn, output.as_deref(), *duplicates_only, *format)?;
+        }
+        Command::Import {
+            manifest,
+            root,
+            hash,
+        } => {
+            let root = match root {
+                Some(root) => root.clone(),
+                None => std::env::current_dir()?,
+            };
+            ui::run_import(conn, manifest, &root, *hash)?;
+        }
+        Command::Stats { top } => {
+            ui::run_stats(conn, *top)?;
+        }
+        Command::Errors => {
+            ui::run_errors(conn)?;
+        }
+        Command::Scans => {
+            ui::run_scans(conn)?;
+        }
+        Command::Doctor { vacuum } => {
+            outcome.duplicates_found = ui::run_doctor(conn, *vacuum)?;
+        }
+        Command::Diff { from, to } => {
+            ui::run_diff(conn, *from, *to)?;
+        }
+        Command::MergeDb { sources } => {
+            ui::run_merge_db(conn, sources)?;
+        }
+        Command::Clean => {
+            let algorithm = hashing::resolve_algorithm(conn, None)?;
+            ui::show_section("Pruning missing entries");
+            ui::run_prune(conn, algorithm)?;
+        }
+        Command::Undo { last, since } => {
+            let algorithm = hashing::resolve_algorithm(conn, None)?;
+            ui::run_undo(conn, *last, *since, algorithm)?;
+        }
+        Command::Protect { action } => match action {
+            ProtectAction::Add { paths } => {
+                let paths: Vec<PathBuf> = paths.iter().map(|p| p.components().collect()).collect();
+                ui::run_protect_add(conn, &paths)?;
+            }
+            ProtectAction::Remove { paths } => {
+                let paths: Vec<PathBuf> = paths.iter().map(|p| p.components().collect()).collect();
+                ui::run_protect_remove(conn, &paths)?;
+            }
+            ProtectAction::List => ui::run_protect_list(conn)?,
+        },
+        Command::Ignore { action } => match action {
+            IgnoreAction::Add { rules, kind } => ui::run_ignore_add(conn, rules, *kind)?,
+            IgnoreAction::Remove { rules, kind } => ui::run_ignore_remove(conn, rules, *kind)?,
+            IgnoreAction::List => ui::run_ignore_list(conn)?,
+        },
+    }
+
+    Ok(outcome)
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p(s: &str) -> PathBuf {
+        PathBuf::from(s)
+    }
+
+    #[test]
+    fn test_build_scan_list_empty_dirs_no_canon() {
+        let dirs: Vec<PathBuf> = vec![];
+        let list = build_scan_list(&dirs, None);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn test_build_scan_list_no_canon() {
+        let dirs = vec![p("/a"), p("/b")];
+        let list = build_scan_list(&dirs, None);
+        assert_eq!(list, vec![&p("/a"), &p("/b")]);
+    }
+
+    #[test]
+    fn test_build_scan_list_canon_prepended() {
+        // Canon not in directories list — should appear first
+        let dirs = vec![p("/other")];
+        let canon = p("/canon");
+        let list = build_scan_list(&dirs, Some(&canon));
+        assert_eq!(list, vec![&p("/canon"), &p("/other")]);
+    }
+
+    #[test]
+    fn test_build_scan_list_canon_already_in_dirs() {
+        // Canon already listed in directories — should not be duplicated
+        let dirs = vec![p("/canon"), p("/other")];
+        let canon = p("/canon");
+        let list = build_scan_list(&dirs, Some(&canon));
+        assert_eq!(list, vec![&p("/canon"), &p("/other")]);
+    }
+
+    #[test]
+    fn test_build_scan_list_canon_is_first() {
+        // Even if canon appears last in directories, it should be first in the scan list
+        let dirs = vec![p("/other"), p("/canon")];
+        let canon = p("/canon");
+        let list = build_scan_list(&dirs, Some(&canon));
+        assert_eq!(list[0], &p("/canon"));
+    }
+
+    // -----------------------------------------------------------------------
+    // Command-line parsing
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_cli_definition_is_valid() {
+        use clap::CommandFactory;
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn test_cli_database_accepted_after_subcommand() {
+        let cli = Cli::try_parse_from(["deduplifier", "scan", "/a", "--database", "x.db"]).unwrap();
+        assert_eq!(cli.database(), p("x.db"));
+        assert!(matches!(cli.command, Command::Scan { .. }));
+    }
+
+    #[test]
+    fn test_cli_encrypted_accepted_after_subcommand() {
+        let cli = Cli::try_parse_from(["deduplifier", "stats", "--encrypted"]).unwrap();
+        assert!(cli.encrypted);
+        let cli = Cli::try_parse_from(["deduplifier", "stats"]).unwrap();
+        assert!(!cli.encrypted);
+    }
+
+    #[cfg(not(feature = "sqlcipher"))]
+    #[test]
+    fn test_encrypted_needs_a_sqlcipher_build() {
+        assert!(database_key(Path::new("x.db")).is_err());
+    }
+
+    #[test]
+    fn test_cli_dup_dirs_no_scan_keeps_directories_for_scope() {
+        let cli =
+            Cli::try_parse_from(["deduplifier", "dup-dirs", "--no-scan", "--canon", "/c", "/a"])
+                .unwrap();
+        let Command::DupDirs {
+            scan,
+            no_scan,
+            canon,
+            ..
+        } = &cli.command
+        else {
+            panic!("expected dup-dirs");
+        };
+        assert!(no_scan);
+        assert_eq!(scan.scan_list(canon.as_ref()), vec![Path::new("/c"), Path::new("/a")]);
+    }
+
+    #[test]
+    fn test_cli_merge_requires_canon() {
+        assert!(Cli::try_parse_from(["deduplifier", "merge", "--delete", "/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_report_needs_no_directories() {
+        let cli = Cli::try_parse_from(["deduplifier", "report", "--min-wasted", "4096"]).unwrap();
+        let Command::Report {
+            directories,
+            min_count,
+            min_wasted,
+            ..
+        } = &cli.command
+        else {
+            panic!("expected report");
+        };
+        assert!(directories.is_empty());
+        assert_eq!((*min_count, *min_wasted), (2, 4096));
+        // Past i64::MAX it would wrap round and match every group
+        let parse =
+            |bytes: &str| Cli::try_parse_from(["deduplifier", "report", "--min-wasted", bytes]);
+        assert!(parse("9223372036854775808").is_err());
+        assert!(parse("-1").is_err());
+    }
+
+    #[test]
+    fn test_cli_stats_top_defaults_to_ten() {
+        let cli = Cli::try_parse_from(["deduplifier", "stats"]).unwrap();
+        assert!(matches!(cli.command, Command::Stats { top: 10 }));
+    }
+
+    #[test]
+    fn test_cli_diff_scans_are_optional() {
+        let cli = Cli::try_parse_from(["deduplifier", "diff", "--from", "3"]).unwrap();
+        let Command::Diff { from, to } = cli.command else {
+            panic!("expected diff");
+        };
+        assert_eq!((from, to), (Some(3), None));
+        assert!(Cli::try_parse_from(["deduplifier", "diff", "--to", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_merge_db_needs_a_source() {
+        let cli = Cli::try_parse_from(["deduplifier", "merge-db", "a.db", "b.db"]).unwrap();
+        let Command::MergeDb { sources } = cli.command else {
+            panic!("expected merge-db");
+        };
+        assert_eq!(sources, vec![PathBuf::from("a.db"), PathBuf::from("b.db")]);
+        assert!(Cli::try_parse_from(["deduplifier", "merge-db"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_compare_takes_src_and_dst() {
+        let cli = Cli::try_parse_from(["deduplifier", "compare", "/a", "/b"]).unwrap();
+        let Command::Compare { src, dst, .. } = cli.command else {
+            panic!("expected compare");
+        };
+        assert_eq!((src, dst), (PathBuf::from("/a"), PathBuf::from("/b")));
+        assert!(Cli::try_parse_from(["deduplifier", "compare", "/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_contained_takes_repeated_in_paths() {
+        let cli =
+            Cli::try_parse_from(["deduplifier", "contained", "/old", "--in", "/a", "--in", "/b"])
+                .unwrap();
+        let Command::Contained { scan, within, .. } = cli.command else {
+            panic!("expected contained");
+        };
+        assert_eq!(scan.directories, vec![PathBuf::from("/old")]);
+        assert_eq!(within, vec![PathBuf::from("/a"), PathBuf::from("/b")]);
+    }
+
+    #[test]
+    fn test_scan_args_take_what_the_command_line_leaves_out_from_a_profile() {
+        let settings = profile::Settings {
+            roots: vec![p("/photos")],
+            exclude: vec!["@eaDir".into()],
+            min_file_size: Some(1024),
+            hash: Some(hashing::HashAlgorithm::Xxh3),
+            ..Default::default()
+        };
+        let mut cli = Cli::try_parse_from(["deduplifier", "--profile", "photos", "dup-files"])
+            .unwrap();
+        assert_eq!(cli.profile.as_deref(), Some("photos"));
+        let scan = cli.command.scan_args_mut().unwrap();
+        scan.apply(&settings);
+        assert_eq!(scan.directories, [p("/photos")]);
+        assert_eq!(scan.min_file_size, Some(1024));
+
+        let mut cli = Cli::try_parse_from([
+            "deduplifier",
+            "scan",
+            "/a",
+            "--exclude",
+            "*.tmp",
+            "--min-file-size",
+            "0",
+            "--hash",
+            "sha256",
+        ])
+        .unwrap();
+        let scan = cli.command.scan_args_mut().unwrap();
+        scan.apply(&settings);
+        assert_eq!(scan.directories, [p("/a")]);
+        assert_eq!(scan.exclude, ["@eaDir", "*.tmp"]);
+        assert_eq!(scan.min_file_size, Some(0));
+        assert_eq!(scan.hash, Some(hashing::HashAlgorithm::Sha256));
+        assert!(Cli::try_parse_from(["deduplifier", "report"])
+            .unwrap()
+            .command
+            .scan_args_mut()
+            .is_none());
+    }
+
+    #[test]
+    fn test_cli_verify_paths_are_optional() {
+        let cli = Cli::try_parse_from(["deduplifier", "verify"]).unwrap();
+        assert!(matches!(cli.command, Command::Verify { ref paths, .. } if paths.is_empty()));
+    }
+
+    #[test]
+    fn test_cli_report_unique_conflicts_with_filters() {
+        assert!(Cli::try_parse_from(["deduplifier", "report", "--unique", "/old"]).is_ok());
+        let result = Cli::try_parse_from(["deduplifier", "report", "--unique", "--min-count", "3"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_cli_dry_run_is_global() {
+        let args = ["deduplifier", "merge", "--dry-run", "--delete", "--canon", "/c", "/a"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert!(cli.dry_run);
+        assert!(!Cli::try_parse_from(["deduplifier", "scan", "/a"]).unwrap().dry_run);
+    }
+
+    #[test]
+    fn test_cli_exclude_is_repeatable() {
+        let args = ["deduplifier", "scan", "--exclude", ".git", "--exclude", "*.tmp", "/a"];
+        let Command::Scan { scan } = Cli::try_parse_from(args).unwrap().command else {
+            panic!("expected scan");
+        };
+        assert_eq!(scan.exclude, [".git", "*.tmp"]);
+        assert!(scan.scan_options(hashing::HashAlgorithm::default()).is_ok());
+
+        let args = ["deduplifier", "scan", "--exclude-regex", "(", "/a"];
+        let Command::Scan { scan } = Cli::try_parse_from(args).unwrap().command else {
+            panic!("expected scan");
+        };
+        assert!(scan.scan_options(hashing::HashAlgorithm::default()).is_err());
+    }
+
+    #[test]
+    fn test_cli_type_and_ext_split_on_commas() {
+        let args = ["deduplifier", "scan", "--type", "image,video", "--ext", "pdf", "/a"];
+        let Command::Scan { scan } = Cli::try_parse_from(args).unwrap().command else {
+            panic!("expected scan");
+        };
+        assert_eq!(scan.types, [walk::FileKind::Image, walk::FileKind::Video]);
+        assert_eq!(scan.ext, ["pdf"]);
+        // --sniff only makes sense with --type
+        assert!(Cli::try_parse_from(["deduplifier", "scan", "--sniff", "/a"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_presets_add_to_the_excludes() {
+        let args = ["deduplifier", "scan", "--preset", "dev", "--exclude", "*.tmp", "/a"];
+        let Command::Scan { scan } = Cli::try_parse_from(args).unwrap().command else {
+            panic!("expected scan");
+        };
+        let opts = scan.scan_options(hashing::HashAlgorithm::default()).unwrap();
+        assert!(opts.walk.filter.skips(Path::new("app/node_modules"), true));
+        assert!(opts.walk.filter.skips(Path::new("x.tmp"), false));
+        assert!(!opts.walk.filter.skips(Path::new("app/src"), true));
+    }
+
+    #[test]
+    fn test_cli_label_is_a_scan_option() {
+        let args = ["deduplifier", "dup-files", "--label", "nas", "/mnt/nas"];
+        let Command::DupFiles { scan, .. } = Cli::try_parse_from(args).unwrap().command else {
+            panic!("expected dup-files");
+        };
+        assert_eq!(scan.label.as_deref(), Some("nas"));
+    }
+
+    #[test]
+    fn test_cli_quiet_is_global() {
+        let cli = Cli::try_parse_from(["deduplifier", "dup-files", "-q", "/a"]).unwrap();
+        assert!(cli.quiet);
+    }
+
+    #[test]
+    fn test_cli_events_are_global() {
+        let cli = Cli::try_parse_from(["deduplifier", "scan", "/a", "--events", "jsonl"]).unwrap();
+        assert_eq!(cli.events, Some(events::EventFormat::Jsonl));
+        assert_eq!(cli.events_file, None);
+        let cli = Cli::try_parse_from([
+            "deduplifier",
+            "--events=jsonl",
+            "--events-file",
+            "run.jsonl",
+            "report",
+        ])
+        .unwrap();
+        assert_eq!(cli.events_file, Some(PathBuf::from("run.jsonl")));
+        // The file is only for the stream
+        assert!(Cli::try_parse_from(["deduplifier", "report", "--events-file", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_verbose_counts_and_log_file() {
+        let cli = Cli::try_parse_from(["deduplifier", "-vv", "scan", "/a"]).unwrap();
+        assert_eq!(cli.verbose, 2);
+        assert_eq!(cli.log_file, None);
+        let cli =
+            Cli::try_parse_from(["deduplifier", "scan", "/a", "-v", "--log-file", "run.log"])
+                .unwrap();
+        assert_eq!(cli.verbose, 1);
+        assert_eq!(cli.log_file, Some(PathBuf::from("run.log")));
+    }
+
+    #[test]
+    fn test_cli_hooks_are_global() {
+        let cli = Cli::try_parse_from([
+            "deduplifier",
+            "--post-scan",
+            "notify-send done",
+            "dup-files",
+            "/a",
+            "--on-duplicate-group",
+            "jq .group.hash",
+        ])
+        .unwrap();
+        assert_eq!(cli.post_scan.as_deref(), Some("notify-send done"));
+        assert_eq!(cli.on_duplicate_group.as_deref(), Some("jq .group.hash"));
+    }
+
+    #[test]
+    fn test_helper_args_give_the_remote_helper_the_same_walk() {
+        let mut cli = Cli::try_parse_from([
+            "deduplifier",
+            "scan",
+            "ssh://nas/photos",
+            "--exclude=-draft*",
+            "--preset",
+            "dev",
+            "--type",
+            "image",
+            "--skip-hidden",
+            "--min-file-size",
+            "1024",
+            "--remote-helper",
+            "~/bin/deduplifier",
+        ])
+        .unwrap();
+        let scan = cli.command.scan_args_mut().unwrap();
+        assert_eq!(scan.remote_helper, "~/bin/deduplifier");
+        let mut argv = vec!["deduplifier".to_string(), "remote-helper".to_string()];
+        argv.extend(scan.helper_args(hashing::HashAlgorithm::Xxh3));
+        argv.extend(["--".to_string(), "/photos".to_string()]);
+
+        let helper = Cli::try_parse_from(argv).unwrap();
+        let Command::RemoteHelper { scan: helper } = helper.command else {
+            panic!("not remote-helper");
+        };
+        assert_eq!(helper.directories, [p("/photos")]);
+        assert_eq!(helper.hash, Some(hashing::HashAlgorithm::Xxh3));
+        assert_eq!(helper.exclude, ["-draft*"]);
+        assert_eq!(helper.presets, [walk::Preset::Dev]);
+        assert_eq!(helper.types, [walk::FileKind::Image]);
+        assert!(helper.skip_hidden && !helper.gitignore);
+        assert_eq!(helper.min_file_size, Some(1024));
+    }
+
+    #[test]
+    fn test_option_args_read_back_as_the_same_scan_options() {
+        let mut cli = Cli::try_parse_from([
+            "deduplifier",
+            "scan",
+            "/photos",
+            "--exclude=-draft*",
+            "--prefilter",
+            "--scan-archives",
+            "--max-depth",
+            "3",
+            "--s3-endpoint",
+            "http://minio:9000",
+        ])
+        .unwrap();
+        let scan = cli.command.scan_args_mut().unwrap();
+        let args = scan.option_args(hashing::HashAlgorithm::Sha256);
+        let opts = scan_options_from_args(&args).unwrap();
+        let expected = scan.scan_options(hashing::HashAlgorithm::Sha256).unwrap();
+        assert_eq!(opts.hash.algorithm, hashing::HashAlgorithm::Sha256);
+        assert!(opts.prefilter && opts.scan_archives && !opts.xattr_cache);
+        assert_eq!(opts.walk.max_depth, Some(3));
+        assert!(opts.walk.filter.skips(Path::new("-draft1.jpg"), false));
+        assert_eq!(opts.s3.endpoint, expected.s3.endpoint);
+        assert_eq!(opts.remote.args, expected.remote.args);
+    }
+
+    #[test]
+    fn test_outcome_exit_code_priority() {
+        let mut outcome = Outcome::default();
+        assert_eq!(outcome.exit_code(), ExitCode::SUCCESS);
+        outcome.duplicates_found = true;
+        assert_eq!(outcome.exit_code(), ExitCode::from(EXIT_DUPLICATES));
+        outcome.scan_errors = 1;
+        assert_eq!(outcome.exit_code(), ExitCode::from(EXIT_ERROR));
+    }
+
+    #[test]
+    fn test_cli_dedupe_requires_a_mode() {
+        assert!(Cli::try_parse_from(["deduplifier", "dedupe", "/a"]).is_err());
+        let cli = Cli::try_parse_from(["deduplifier", "dedupe", "--interactive", "/a"]).unwrap();
+        assert!(matches!(cli.command, Command::Dedupe { interactive: true, .. }));
+        let both = ["deduplifier", "dedupe", "--interactive", "--auto", "--delete", "/a"];
+        assert!(Cli::try_parse_from(both).is_err());
+    }
+
+    #[test]
+    fn test_cli_dedupe_auto_rules() {
+        assert!(Cli::try_parse_from(["deduplifier", "dedupe", "--auto", "/a"]).is_err());
+        let cli = Cli::try_parse_from([
+            "deduplifier",
+            "dedupe",
+            "--auto",
+            "--delete",
+            "--rule",
+            "prefer-path=/photos",
+            "--rule",
+            "keep-newest",
+            "--keep-n",
+            "2",
+            "/a",
+        ])
+        .unwrap();
+        let Command::Dedupe { rules, keep_n, .. } = cli.command else {
+            panic!("expected dedupe");
+        };
+        assert_eq!(
+            rules,
+            vec![dedupe::KeepRule::PreferPath("/photos".into()), dedupe::KeepRule::Newest]
+        );
+        assert_eq!(keep_n, 2);
+        let bad = ["deduplifier", "dedupe", "--auto", "--delete", "--rule", "biggest", "/a"];
+        assert!(Cli::try_parse_from(bad).is_err());
+    }
+
+    #[test]
+    fn test_cli_dedupe_emit_script() {
+        // A script changes nothing, so --auto needs no --delete for it
+        let args = ["deduplifier", "dedupe", "--auto", "--emit-script", "out.sh", "/a"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        let Command::Dedupe { emit_script, script_shell, .. } = cli.command else {
+            panic!("expected dedupe");
+        };
+        assert_eq!(emit_script, Some(PathBuf::from("out.sh")));
+        assert_eq!(script_shell, script::Shell::Sh);
+        let shell_alone = ["deduplifier", "dedupe", "--interactive", "--script-shell", "sh", "/a"];
+        assert!(Cli::try_parse_from(shell_alone).is_err());
+    }
+
+    #[test]
+    fn test_cli_protect() {
+        let cli = Cli::try_parse_from(["deduplifier", "protect", "add", "/a", "/b"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Protect { action: ProtectAction::Add { ref paths } } if paths.len() == 2
+        ));
+        assert!(Cli::try_parse_from(["deduplifier", "protect", "add"]).is_err());
+        assert!(Cli::try_parse_from(["deduplifier", "protect", "list"]).is_ok());
+    }
+
+    #[test]
+    fn test_cli_ignore() {
+        let cli =
+            Cli::try_parse_from(["deduplifier", "ignore", "add", "--kind", "hash", "abcd1234"])
+                .unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Ignore {
+                action: IgnoreAction::Add { ref rules, kind: Some(ignore_rules::RuleKind::Hash) }
+            } if rules == &["abcd1234"]
+        ));
+        assert!(Cli::try_parse_from(["deduplifier", "ignore", "remove"]).is_err());
+        assert!(Cli::try_parse_from(["deduplifier", "ignore", "list"]).is_ok());
+    }
+
+    #[test]
+    fn test_cli_undo_needs_a_limit() {
+        assert!(Cli::try_parse_from(["deduplifier", "undo"]).is_err());
+        let cli = Cli::try_parse_from(["deduplifier", "undo", "--last", "3"]).unwrap();
+        assert!(matches!(cli.command, Command::Undo { last: Some(3), since: None }));
+        let both = ["deduplifier", "undo", "--last", "3", "--since", "1700000000"];
+        assert!(Cli::try_parse_from(both).is_err());
+    }
+
+    #[test]
+    fn test_cli_dedupe_action() {
+        let cli = Cli::try_parse_from(["deduplifier", "dedupe", "--interactive", "/a"]).unwrap();
+        assert!(matches!(cli.command, Command::Dedupe { action: dedupe::Action::Delete, .. }));
+        let args = ["deduplifier", "dedupe", "--interactive", "--action", "hardlink", "/a"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert!(matches!(cli.command, Command::Dedupe { action: dedupe::Action::Hardlink, .. }));
+        let cli = Cli::try_parse_from(["deduplifier", "dedupe", "--interactive", "/a"]).unwrap();
+        assert!(matches!(cli.command, Command::Dedupe { permanent: false, .. }));
+    }
+
+    #[test]
+    fn test_cli_clean_takes_no_directories() {
+        let cli = Cli::try_parse_from(["deduplifier", "clean"]).unwrap();
+        assert!(matches!(cli.command, Command::Clean));
+    }
+
+    #[test]
+    fn test_cli_tui_links_with_hardlinks_by_default() {
+        let cli = Cli::try_parse_from(["deduplifier", "tui", "/a"]).unwrap();
+        assert!(matches!(cli.command, Command::Tui { link: dedupe::Action::Hardlink, .. }));
+        let args = ["deduplifier", "tui", "--link", "symlink", "--permanent", "/a"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Tui { link: dedupe::Action::Symlink, permanent: true, .. }
+        ));
+    }
+
+    #[test]
+    fn test_cli_serve_listens_on_localhost_by_default() {
+        let cli = Cli::try_parse_from(["deduplifier", "serve"]).unwrap();
+        let Command::Serve { listen, allow_scan } = cli.command else {
+            panic!("expected serve");
+        };
+        assert_eq!(listen.to_string(), "127.0.0.1:8080");
+        assert!(!allow_scan);
+        assert!(Cli::try_parse_from(["deduplifier", "serve", "--listen", "nas:80"]).is_err());
+    }
+
+    #[test]
+    fn test_cli_daemon_needs_a_config() {
+        assert!(Cli::try_parse_from(["deduplifier", "daemon"]).is_err());
+        let cli = Cli::try_parse_from(["deduplifier", "daemon", "--config", "d.toml", "--detach"])
+            .unwrap();
+        assert!(matches!(
+            cli.command,
+            Command::Daemon { ref config, detach: true, check: false } if config == &p("d.toml")
+        ));
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use rusqlite::Connection;
+
+use crate::{db, hashing, utils};
+
+/// A file in the source tree and the destination file with the same content.
+/// The destination copy at the same relative path is preferred when there is
+/// one, so `renamed` is only set when the content lives elsewhere.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub src: String,
+    pub dst: String,
+    pub renamed: bool,
+}
+
+/// The result of comparing two trees by content.
+pub struct Comparison {
+    /// Source files with a copy somewhere in the destination
+    pub matched: Vec<Match>,
+    /// Source files whose content appears nowhere in the destination
+    pub missing: Vec<db::FileRecord>,
+    /// Destination files whose content appears nowhere in the source
+    pub extra: Vec<db::FileRecord>,
+}
+
+/// Compare the files stored under `src` and `dst` by content hash. Both trees
+/// must already be scanned; only the database is read. Files `--prefilter`
+/// left with a provisional hash had no same-sized file anywhere when they were
+/// scanned, so they never match.
+pub fn compare_trees(conn: &Connection, src: &Path, dst: &Path) -> Result<Comparison> {
+    for root in [src, dst] {
+        if db::get_directory(conn, root)?.is_none() {
+            bail!(
+                "{} is not in the database; run `deduplifier scan {}` first",
+                root.display(),
+                root.display()
+            );
+        }
+    }
+    let src_files = db::files_under(conn, src)?;
+    let dst_files = db::files_under(conn, dst)?;
+
+    let mut dst_by_hash: HashMap<&str, Vec<&db::FileRecord>> = HashMap::new();
+    for file in &dst_files {
+        if !hashing::is_provisional(&file.hash) {
+            dst_by_hash.entry(&file.hash).or_default().push(file);
+        }
+    }
+
+    let mut matched = Vec::new();
+    let mut missing = Vec::new();
+    let mut src_hashes = HashSet::new();
+    for file in src_files {
+        src_hashes.insert(file.hash.clone());
+        let Some(copies) = dst_by_hash.get(file.hash.as_str()) else {
+            missing.push(file);
+            continue;
+        };
+        let path = utils::path_from_db(&file.path);
+        let relative = path.strip_prefix(src).ok();
+        let same_place = copies
+            .iter()
+            .find(|c| utils::path_from_db(&c.path).strip_prefix(dst).ok() == relative);
+        let (dst_file, renamed) = match same_place {
+            Some(c) => (c, false),
+            None => (&copies[0], true),
+        };
+        matched.push(Match {
+            src: file.path,
+            dst: dst_file.path.clone(),
+            renamed,
+        });
+    }
+
+    let extra = dst_files
+        .into_iter()
+        .filter(|f| !src_hashes.contains(&f.hash) || hashing::is_provisional(&f.hash))
+        .collect();
+    Ok(Comparison {
+        matched,
+        missing,
+        extra,
+    })
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn p(s: &str) -> String {
+        s.replace('/', std::path::MAIN_SEPARATOR_STR)
+    }
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    fn seeded_db() -> Connection {
+        let conn = open_test_db();
+        for dir in ["/src", "/dst"] {
+            db::upsert_directory(&conn, Path::new(&p(dir)), "d", 0).unwrap();
+        }
+        let files = [
+            ("/src/same.txt", "h_same"),
+            ("/src/moved.txt", "h_moved"),
+            ("/src/lost.txt", "h_lost"),
+            ("/dst/same.txt", "h_same"),
+            ("/dst/sub/moved.txt", "h_moved"),
+            ("/dst/new.txt", "h_new"),
+        ];
+        for (path, hash) in files {
+            db::upsert_file(&conn, Path::new(&p(path)), hash, 1, 0).unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn test_compare_trees_sorts_files_into_three_sets() {
+        let conn = seeded_db();
+        let cmp = compare_trees(&conn, Path::new(&p("/src")), Path::new(&p("/dst"))).unwrap();
+        let missing: Vec<&str> = cmp.missing.iter().map(|f| f.path.as_str()).collect();
+        let extra: Vec<&str> = cmp.extra.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(missing, vec![p("/src/lost.txt")]);
+        assert_eq!(extra, vec![p("/dst/new.txt")]);
+        assert_eq!(
+            cmp.matched,
+            vec![
+                Match {
+                    src: p("/src/moved.txt"),
+                    dst: p("/dst/sub/moved.txt"),
+                    renamed: true,
+                },
+                Match {
+                    src: p("/src/same.txt"),
+                    dst: p("/dst/same.txt"),
+                    renamed: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compare_trees_prefers_copy_at_same_relative_path() {
+        let conn = seeded_db();
+        db::upsert_file(&conn, Path::new(&p("/dst/a/same.txt")), "h_same", 1, 0).unwrap();
+        let cmp = compare_trees(&conn, Path::new(&p("/src")), Path::new(&p("/dst"))).unwrap();
+        let same = cmp
+            .matched
+            .iter()
+            .find(|m| m.src == p("/src/same.txt"))
+            .unwrap();
+        assert_eq!(same.dst, p("/dst/same.txt"));
+        assert!(!same.renamed);
+    }
+
+    #[test]
+    fn test_compare_trees_provisional_hashes_never_match() {
+        let conn = open_test_db();
+        for dir in ["/src", "/dst"] {
+            db::upsert_directory(&conn, Path::new(&p(dir)), "d", 0).unwrap();
+        }
+        let hash = hashing::provisional_hash("x");
+        db::upsert_file(&conn, Path::new(&p("/src/a")), &hash, 1, 0).unwrap();
+        db::upsert_file(&conn, Path::new(&p("/dst/a")), &hash, 1, 0).unwrap();
+        let cmp = compare_trees(&conn, Path::new(&p("/src")), Path::new(&p("/dst"))).unwrap();
+        assert!(cmp.matched.is_empty());
+        assert_eq!(cmp.missing.len(), 1);
+        assert_eq!(cmp.extra.len(), 1);
+    }
+
+    #[test]
+    fn test_compare_trees_requires_scanned_roots() {
+        let conn = seeded_db();
+        let err = compare_trees(&conn, Path::new(&p("/src")), Path::new(&p("/nope")))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("not in the database"));
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+use rayon::prelude::*;
+use rusqlite::Connection;
+
+use crate::hashing::{self, HashAlgorithm, HashOptions};
+use crate::ignore_rules::IgnoreRules;
+use crate::{db, utils};
+
+/// Rows written per transaction while storing decompressed hashes.
+const TRANSACTION_BATCH_SIZE: usize = 1000;
+
+/// The single-file compression formats `dup-compressed` looks through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl Format {
+    /// The format of the file at `path`, going by its extension. Compressed
+    /// tarballs (`.tgz`, `.tbz2`, `.txz`, `.tzst`) count too: their content is
+    /// the tar file.
+    pub fn of(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_string_lossy().to_lowercase();
+        match extension.as_str() {
+            "gz" | "tgz" => Some(Self::Gzip),
+            "bz2" | "tbz" | "tbz2" => Some(Self::Bzip2),
+            "xz" | "txz" => Some(Self::Xz),
+            "zst" | "tzst" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Bzip2 => "bzip2",
+            Self::Xz => "xz",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+/// Hash what the file at `path` decompresses to, as if it were a file of its
+/// own: the same file gzipped with different header timestamps, or
+/// compressed with xz instead, gives the same hash as the original.
+/// Concatenated streams (as `cat a.gz b.gz` makes) are decompressed in full.
+/// Nothing is kept in memory but the decoder's window.
+pub fn decompressed_hash(path: &Path, format: Format, opts: &HashOptions) -> Result<String> {
+    let file = BufReader::new(File::open(path)?);
+    let mut decoder: Box<dyn Read> = match format {
+        Format::Gzip => Box::new(flate2::read::MultiGzDecoder::new(file)),
+        Format::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(file)),
+        Format::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(file)),
+        Format::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(file)?),
+    };
+    hashing::compute_reader_hash(&mut decoder, opts)
+}
+
+/// A file in a `ContentGroup`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wrapped {
+    pub path: String,
+    /// Its content hash: files with the same one are plain duplicates
+    pub hash: String,
+    pub size: i64,
+    /// How it is compressed; `None` for the uncompressed file itself
+    pub format: Option<Format>,
+}
+
+/// Files that hold the same content once decompressed, but differ as files.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContentGroup {
+    /// The hash of the decompressed content
+    pub content: String,
+    /// Sorted by path
+    pub files: Vec<Wrapped>,
+    /// How many different file contents there are among `files`, at least 2
+    pub variants: usize,
+}
+
+/// What `find_cross_format_duplicates` found.
+pub struct CrossFormat {
+    /// Most variants first
+    pub groups: Vec<ContentGroup>,
+    /// Compressed files that couldn't be decompressed, and so aren't in any
+    /// group
+    pub unreadable: usize,
+}
+
+/// Group the files in the database (under `scope`, when it isn't empty)
+/// that hold the same content once gzip, bzip2, xz and zstd compression is
+/// taken off: a file and its compressed copies, and copies compressed with
+/// another tool, level or timestamp. Compressed files are read only when the
+/// database holds no decompressed hash for their current content; those are
+/// stored for next time. Groups of exact copies alone are `dup-files`'
+/// business and aren't reported. Files merged in from another database or
+/// inside archives, files the ignore rules match, and files `--prefilter`
+/// left with a provisional hash are left out.
+pub fn find_cross_format_duplicates(
+    conn: &Connection,
+    scope: &[&Path],
+    on_progress: impl Fn(usize, usize) + Sync,
+) -> Result<CrossFormat> {
+    let opts = HashOptions {
+        algorithm: hashing::recorded_algorithm(conn)?.unwrap_or(HashAlgorithm::RECOMMENDED),
+        ..Default::default()
+    };
+    let off_disk = db::files_off_disk(conn)?;
+    let ignored = IgnoreRules::load(conn)?;
+    let files: Vec<(db::FileRecord, Option<Format>)> = db::all_files(conn)?
+        .into_iter()
+        .filter(|f| !off_disk.contains(&f.path) && !ignored.ignores_path(&f.path))
+        .filter(|f| !hashing::is_provisional(&f.hash))
+        .filter_map(|f| {
+            let path = utils::path_from_db(&f.path);
+            let in_scope = scope.is_empty() || scope.iter().any(|r| path.starts_with(r));
+            in_scope.then(|| (f, Format::of(&path)))
+        })
+        .collect();
+
+    let mut stored = db::decompressed_hashes(conn)?;
+    let stale: Vec<(&db::FileRecord, Format)> = files
+        .iter()
+        .filter_map(|(f, format)| Some((f, (*format)?)))
+        .filter(|(f, _)| stored.get(&f.path).is_none_or(|s| s.hash != f.hash))
+        .collect();
+    let done = AtomicUsize::new(0);
+    let fresh: Vec<(&db::FileRecord, Option<String>)> = stale
+        .par_iter()
+        .map(|&(file, format)| {
+            let path = utils::path_from_db(&file.path);
+            let content = decompressed_hash(&path, format, &opts).ok();
+            on_progress(done.fetch_add(1, Ordering::Relaxed) + 1, stale.len());
+            (file, content)
+        })
+        .collect();
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    for (file, content) in fresh {
+        let record = db::DecompressedHashRecord {
+            hash: file.hash.clone(),
+            content,
+        };
+        db::set_decompressed_hash(conn, &file.path, &record)?;
+        stored.insert(file.path.clone(), record);
+        batch.tick()?;
+    }
+    db::prune_decompressed_hashes(conn)?;
+    batch.commit()?;
+
+    // Compressed files first, so an uncompressed file only joins a group
+    // some compressed file has started
+    let mut unreadable = 0;
+    let mut by_content: HashMap<&str, Vec<Wrapped>> = HashMap::new();
+    for (file, format) in &files {
+        let Some(format) = format else { continue };
+        let Some(content) = stored.get(&file.path).and_then(|s| s.content.as_deref()) else {
+            unreadable += 1;
+            continue;
+        };
+        by_content.entry(content).or_default().push(Wrapped {
+            path: file.path.clone(),
+            hash: file.hash.clone(),
+            size: file.size,
+            format: Some(*format),
+        });
+    }
+    for (file, _) in files.iter().filter(|(_, format)| format.is_none()) {
+        if let Some(group) = by_content.get_mut(file.hash.as_str()) {
+            group.push(Wrapped {
+                path: file.path.clone(),
+                hash: file.hash.clone(),
+                size: file.size,
+                format: None,
+            });
+        }
+    }
+    let mut groups: Vec<ContentGroup> = by_content
+        .into_iter()
+        .filter_map(|(content, files)| {
+            let mut hashes: Vec<&str> = files.iter().map(|f| f.hash.as_str()).collect();
+            hashes.sort_unstable();
+            hashes.dedup();
+            let variants = hashes.len();
+            (variants > 1).then(|| ContentGroup {
+                content: content.to_string(),
+                files,
+                variants,
+            })
+        })
+        .collect();
+    for group in &mut groups {
+        group.files.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+    groups.sort_by(|a, b| {
+        b.variants
+            .cmp(&a.variants)
+            .then_with(|| a.files[0].path.cmp(&b.files[0].path))
+    });
+    Ok(CrossFormat { groups, unreadable })
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan;
+    use std::io::Write;
+
+    const TEXT: &[u8] = b"the same text, compressed several ways\n";
+
+    fn gzip(data: &[u8], mtime: u32) -> Vec<u8> {
+        let mut gz = flate2::GzBuilder::new()
+            .mtime(mtime)
+            .write(Vec::new(), flate2::Compression::default());
+        gz.write_all(data).unwrap();
+        gz.finish().unwrap()
+    }
+
+    fn compress(data: &[u8], format: Format) -> Vec<u8> {
+        match format {
+            Format::Gzip => gzip(data, 0),
+            Format::Bzip2 => {
+                let mut bz =
+                    bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+                bz.write_all(data).unwrap();
+                bz.finish().unwrap()
+            }
+            Format::Xz => {
+                let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+                xz.write_all(data).unwrap();
+                xz.finish().unwrap()
+            }
+            Format::Zstd => zstd::encode_all(data, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_format_goes_by_extension() {
+        assert_eq!(Format::of(Path::new("/a/b.TXT.GZ")), Some(Format::Gzip));
+        assert_eq!(Format::of(Path::new("/a/b.tgz")), Some(Format::Gzip));
+        assert_eq!(Format::of(Path::new("/a/b.tbz2")), Some(Format::Bzip2));
+        assert_eq!(Format::of(Path::new("/a/b.txz")), Some(Format::Xz));
+        assert_eq!(Format::of(Path::new("/a/b.zst")), Some(Format::Zstd));
+        assert_eq!(Format::of(Path::new("/a/b.zip")), None);
+        assert_eq!(Format::of(Path::new("/a/gz")), None);
+    }
+
+    #[test]
+    fn test_decompressed_hash_matches_the_original_in_every_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = HashOptions::default();
+        let original = dir.path().join("a.txt");
+        std::fs::write(&original, TEXT).unwrap();
+        let expected = hashing::compute_file_hash(&original, &opts).unwrap();
+        for format in [Format::Gzip, Format::Bzip2, Format::Xz, Format::Zstd] {
+            let path = dir.path().join(format!("a.{}", format.name()));
+            std::fs::write(&path, compress(TEXT, format)).unwrap();
+            assert_eq!(
+                decompressed_hash(&path, format, &opts).unwrap(),
+                expected,
+                "{}",
+                format.name()
+            );
+        }
+
+        // Two streams one after the other decompress to both
+        let joined = dir.path().join("joined.gz");
+        let mut data = gzip(&TEXT[..10], 0);
+        data.extend(gzip(&TEXT[10..], 0));
+        std::fs::write(&joined, data).unwrap();
+        assert_eq!(
+            decompressed_hash(&joined, Format::Gzip, &opts).unwrap(),
+            expected
+        );
+
+        let broken = dir.path().join("broken.xz");
+        std::fs::write(&broken, "not xz").unwrap();
+        assert!(decompressed_hash(&broken, Format::Xz, &opts).is_err());
+    }
+
+    #[test]
+    fn test_find_cross_format_duplicates_groups_files_by_decompressed_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::write(root.join("a.txt"), TEXT).unwrap();
+        // Gzipped on different days, so the headers differ
+        std::fs::write(root.join("a.txt.gz"), gzip(TEXT, 1)).unwrap();
+        std::fs::write(root.join("b.txt.gz"), gzip(TEXT, 2)).unwrap();
+        std::fs::write(root.join("a.txt.zst"), compress(TEXT, Format::Zstd)).unwrap();
+        // An exact copy and a compressed file with nothing to match
+        std::fs::write(root.join("copy.gz"), gzip(TEXT, 1)).unwrap();
+        std::fs::write(root.join("other.xz"), compress(b"other", Format::Xz)).unwrap();
+        std::fs::write(root.join("broken.bz2"), "not bzip2").unwrap();
+        // Only exact copies once decompressed, so dup-files' business
+        std::fs::write(root.join("c.gz"), gzip(b"c", 0)).unwrap();
+        std::fs::write(root.join("d.gz"), gzip(b"c", 0)).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        let opts = scan::ScanOptions::default();
+        scan::scan_directory(&conn, root, 1, &opts, |_, _, _| ()).unwrap();
+
+        let found = find_cross_format_duplicates(&conn, &[], |_, _| ()).unwrap();
+        assert_eq!(found.unreadable, 1);
+        assert_eq!(found.groups.len(), 1);
+        let group = &found.groups[0];
+        assert_eq!(group.variants, 4);
+        let names: Vec<_> = group
+            .files
+            .iter()
+            .map(|f| (utils::path_from_db(&f.path), f.format))
+            .map(|(p, format)| {
+                (
+                    p.file_name().unwrap().to_string_lossy().into_owned(),
+                    format,
+                )
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("a.txt".to_string(), None),
+                ("a.txt.gz".to_string(), Some(Format::Gzip)),
+                ("a.txt.zst".to_string(), Some(Format::Zstd)),
+                ("b.txt.gz".to_string(), Some(Format::Gzip)),
+                ("copy.gz".to_string(), Some(Format::Gzip)),
+            ]
+        );
+        assert_eq!(db::decompressed_hashes(&conn).unwrap().len(), 8);
+
+        // A second run uses the stored hashes instead of reading the files
+        std::fs::write(root.join("b.txt.gz"), "no longer gzip").unwrap();
+        let again = find_cross_format_duplicates(&conn, &[], |_, _| ()).unwrap();
+        assert_eq!(again.groups, found.groups);
+    }
+}
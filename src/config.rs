@@ -0,0 +1,142 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::de::{DeserializeOwned, Error};
+use serde::{Deserialize, Deserializer};
+
+use crate::hashing::HashAlgorithm;
+
+/// Read the TOML configuration file at `path` into `T`, whose fields say
+/// which settings there are (see `parse`).
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    parse(&text).with_context(|| format!("could not read {}", path.display()))
+}
+
+/// Parse a configuration file into `T`. Mistakes, whether in the TOML or in
+/// a setting `T` doesn't have or can't hold, are reported with the line and
+/// column they are at.
+pub fn parse<T: DeserializeOwned>(text: &str) -> Result<T> {
+    Ok(toml::from_str(text)?)
+}
+
+/// Read a hash algorithm by the name `--hash` takes, for
+/// `#[serde(deserialize_with)]`.
+pub fn hash_algorithm<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<HashAlgorithm>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    match HashAlgorithm::from_name(&name) {
+        Some(algorithm) => Ok(Some(algorithm)),
+        None => Err(D::Error::custom(format!(
+            "`{name}` is not a hash algorithm"
+        ))),
+    }
+}
+
+/// Read a string naming a file or directory, in which a leading `~/` stands
+/// for the home directory, for `#[serde(deserialize_with)]`.
+pub fn path<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<PathBuf>, D::Error> {
+    Ok(Some(expand_home(&String::deserialize(deserializer)?)))
+}
+
+/// An array of what `path` reads.
+pub fn paths<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<PathBuf>, D::Error> {
+    let paths = Vec::<String>::deserialize(deserializer)?;
+    Ok(paths.iter().map(|path| expand_home(path)).collect())
+}
+
+/// `path` with a leading `~/` replaced by the home directory, when it is
+/// known.
+pub fn expand_home(path: &str) -> PathBuf {
+    if let Some(rest) = path.strip_prefix("~/") {
+        if let Some(home) = home_dir() {
+            return home.join(rest);
+        }
+    }
+    PathBuf::from(path)
+}
+
+/// The user's home directory, from `HOME` (or `USERPROFILE` on Windows).
+pub fn home_dir() -> Option<PathBuf> {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    std::env::var_os(var)
+        .filter(|h| !h.is_empty())
+        .map(PathBuf::from)
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Sample {
+        #[serde(default, deserialize_with = "path")]
+        database: Option<PathBuf>,
+        #[serde(default, deserialize_with = "paths")]
+        roots: Vec<PathBuf>,
+        #[serde(default, deserialize_with = "hash_algorithm")]
+        hash: Option<HashAlgorithm>,
+    }
+
+    #[test]
+    fn test_parse_reads_paths_and_hash_names() {
+        let sample: Sample = parse(
+            r#"
+database = "/volume1/dedup/hashes.db"   # trailing comment
+roots = [
+    "/volume1/photos",    # the camera's
+    "/volume1/\"docs\"\u00e9",
+]
+hash = "xxh3"
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            sample.database,
+            Some(PathBuf::from("/volume1/dedup/hashes.db"))
+        );
+        assert_eq!(
+            sample.roots,
+            [
+                PathBuf::from("/volume1/photos"),
+                PathBuf::from("/volume1/\"docs\"é")
+            ]
+        );
+        assert_eq!(sample.hash, Some(HashAlgorithm::Xxh3));
+
+        let empty: Sample = parse("").unwrap();
+        assert_eq!(empty.database, None);
+        assert!(empty.roots.is_empty());
+        if let Some(home) = home_dir() {
+            let sample: Sample = parse("database = \"~/hashes.db\"").unwrap();
+            assert_eq!(sample.database, Some(home.join("hashes.db")));
+        }
+    }
+
+    #[test]
+    fn test_parse_explains_mistakes_with_their_line() {
+        let error = |text: &str| format!("{:#}", parse::<Sample>(text).unwrap_err());
+        assert!(error("hash = \"md5\"").contains("`md5` is not a hash algorithm"));
+        assert!(error("database = \"a\"\ndatabase = \"b\"").contains("line 2"));
+        let unknown = error("\n\ncolour = 'red'");
+        assert!(unknown.contains("line 3"), "{unknown}");
+        assert!(unknown.contains("unknown field `colour`"), "{unknown}");
+        assert!(error("roots = \"/photos\"").contains("expected a sequence"));
+        assert!(error("database = nope").contains("line 1"));
+    }
+}
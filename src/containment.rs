@@ -0,0 +1,299 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::{db, duplicates, utils};
+
+/// A directory every file of which has a copy, by content, outside it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contained {
+    pub path: String,
+    /// Files anywhere under it
+    pub files: usize,
+    pub bytes: i64,
+    /// The deepest directory outside `path` that holds a copy of all of its
+    /// files, if one does; otherwise the copies are spread over several
+    pub container: Option<String>,
+}
+
+/// Find the top-most directories under `scope` whose every file, at any
+/// depth, has a copy outside the directory: what would still be there,
+/// extra files around it or not, if the directory were wiped. With `within`,
+/// only copies under those paths count. Copies are duplicates as
+/// `duplicates::find_duplicate_files` finds them, so a hardlink is not a copy
+/// (though one of a copy is) and a file the ignore rules match has none;
+/// copies merged in from other databases or inside archives count.
+/// Subdirectories of a reported directory are left out, and so are
+/// directories with no files. Ordered by path. Only the database is read.
+pub fn find_contained_directories(
+    conn: &Connection,
+    scope: &[&Path],
+    within: &[&Path],
+) -> Result<Vec<Contained>> {
+    let roots: Vec<String> = scope
+        .iter()
+        .map(|r| utils::path_to_db(r).into_owned())
+        .collect();
+    let within: Vec<String> = within
+        .iter()
+        .map(|r| utils::path_to_db(r).into_owned())
+        .collect();
+    let counts =
+        |path: &str| within.is_empty() || within.iter().any(|w| Path::new(path).starts_with(w));
+
+    let groups = duplicates::find_duplicate_files(conn)?;
+    let copies = Copies::new(&groups);
+
+    // Every directory under the roots, with the files it holds at any depth
+    // and whether one of them has no copy outside it
+    let mut dirs: HashMap<String, (usize, i64, bool)> = HashMap::new();
+    for file in db::all_files(conn)? {
+        let Some(root) = roots.iter().find(|r| Path::new(&file.path).starts_with(r)) else {
+            continue;
+        };
+        let copies: Vec<&str> = copies.of(&file.path).filter(|p| counts(p)).collect();
+        // The file has a copy outside every directory but those that hold
+        // all its copies too, i.e. the ancestors of the copies' deepest
+        // common directory
+        let common = common_ancestor(&copies);
+        for dir in Path::new(&file.path).ancestors().skip(1) {
+            let name = dir.to_string_lossy();
+            let entry = dirs.entry(name.into_owned()).or_insert((0, 0, false));
+            entry.0 += 1;
+            entry.1 += file.size;
+            let has_copy = match common {
+                Some(common) => !Path::new(common).starts_with(dir),
+                None => false,
+            };
+            if !has_copy {
+                entry.2 = true;
+            }
+            if dir == Path::new(root) {
+                break;
+            }
+        }
+    }
+
+    let mut found: Vec<Contained> = Vec::new();
+    for (path, &(files, bytes, missing)) in &dirs {
+        if missing {
+            continue;
+        }
+        let parent_contained = roots.iter().all(|r| r != path)
+            && Path::new(path)
+                .parent()
+                .and_then(|p| dirs.get(p.to_string_lossy().as_ref()))
+                .is_some_and(|&(_, _, missing)| !missing);
+        if parent_contained {
+            continue;
+        }
+        found.push(Contained {
+            path: path.clone(),
+            files,
+            bytes,
+            container: None,
+        });
+    }
+    found.sort_by(|a, b| a.path.cmp(&b.path));
+
+    if !found.is_empty() {
+        let files = db::all_files(conn)?;
+        for contained in &mut found {
+            let inside: Vec<&str> = files
+                .iter()
+                .map(|f| f.path.as_str())
+                .filter(|p| Path::new(p).starts_with(&contained.path))
+                .collect();
+            contained.container = container(&contained.path, &inside, &copies, &counts);
+        }
+    }
+    Ok(found)
+}
+
+/// Where each duplicate file's copies are, by path.
+struct Copies<'a> {
+    groups: &'a [duplicates::DuplicateFileGroup],
+    /// Every path in a group, hardlinks too, to its group and entry
+    entries: HashMap<&'a str, (usize, usize)>,
+}
+
+impl<'a> Copies<'a> {
+    fn new(groups: &'a [duplicates::DuplicateFileGroup]) -> Self {
+        let mut entries = HashMap::new();
+        for (g, group) in groups.iter().enumerate() {
+            for (e, entry) in group.files.iter().enumerate() {
+                for path in std::iter::once(&entry.path).chain(&entry.hardlinks) {
+                    entries.insert(path.as_str(), (g, e));
+                }
+            }
+        }
+        Self { groups, entries }
+    }
+
+    /// The paths of the copies of the file at `path`, leaving out its own
+    /// hardlinks.
+    fn of(&self, path: &str) -> impl Iterator<Item = &'a str> + '_ {
+        let found = self.entries.get(path).copied();
+        found
+            .into_iter()
+            .flat_map(|(g, own)| {
+                self.groups[g]
+                    .files
+                    .iter()
+                    .enumerate()
+                    .filter(move |&(e, _)| e != own)
+            })
+            .flat_map(|(_, entry)| std::iter::once(&entry.path).chain(&entry.hardlinks))
+            .map(|p| p.as_str())
+    }
+}
+
+/// The deepest directory that holds every one of `paths`, if they have one.
+fn common_ancestor<'a>(paths: &[&'a str]) -> Option<&'a str> {
+    let (first, rest) = paths.split_first()?;
+    let mut common = Path::new(*first);
+    for path in rest {
+        while !Path::new(path).starts_with(common) {
+            common = common.parent()?;
+        }
+    }
+    common.to_str()
+}
+
+/// The deepest directory outside `dir` that holds a copy of each of the
+/// files at `inside`, trying the directories above the first file's copies.
+fn container(
+    dir: &str,
+    inside: &[&str],
+    copies: &Copies,
+    counts: &impl Fn(&str) -> bool,
+) -> Option<String> {
+    let copies_of = |path: &str| -> Vec<&str> {
+        copies
+            .of(path)
+            .filter(|&p| !Path::new(p).starts_with(dir) && counts(p))
+            .collect()
+    };
+    let mut candidates: Vec<&Path> = copies_of(inside.first()?)
+        .into_iter()
+        .flat_map(|copy| Path::new(copy).ancestors().skip(1))
+        .filter(|c| !c.as_os_str().is_empty() && !Path::new(dir).starts_with(c))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    // Deepest first
+    candidates.sort_by_key(|c| std::cmp::Reverse((c.components().count(), c.to_path_buf())));
+    let copies: Vec<Vec<&str>> = inside.iter().map(|p| copies_of(p)).collect();
+    candidates
+        .into_iter()
+        .find(|c| {
+            copies
+                .iter()
+                .all(|copies| copies.iter().any(|p| Path::new(p).starts_with(c)))
+        })
+        .map(|c| c.to_string_lossy().into_owned())
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    fn insert_file(conn: &Connection, path: &str, hash: &str) {
+        db::upsert_file(conn, Path::new(path), hash, 10, 0).unwrap();
+    }
+
+    #[test]
+    fn test_common_ancestor_is_the_deepest_shared_directory() {
+        assert_eq!(common_ancestor(&[]), None);
+        assert_eq!(common_ancestor(&["/a/b/c"]), Some("/a/b/c"));
+        assert_eq!(common_ancestor(&["/a/b/c", "/a/b/d/e"]), Some("/a/b"));
+        assert_eq!(common_ancestor(&["/a/x", "/b/y"]), Some("/"));
+    }
+
+    #[test]
+    fn test_find_contained_directories_reports_top_most_with_their_container() {
+        let conn = open_test_db();
+        // Everything in Docs is in Documents, which has more besides
+        insert_file(&conn, "/old/Docs/a.txt", "a");
+        insert_file(&conn, "/old/Docs/sub/b.txt", "b");
+        insert_file(&conn, "/home/Documents/2019/a.txt", "a");
+        insert_file(&conn, "/home/Documents/b.txt", "b");
+        insert_file(&conn, "/home/Documents/c.txt", "c");
+        // Music is copied, but to two places with nothing in common but the root
+        insert_file(&conn, "/old/Music/x.mp3", "x");
+        insert_file(&conn, "/old/Music/y.mp3", "y");
+        insert_file(&conn, "/home/Music/x.mp3", "x");
+        insert_file(&conn, "/backup/y.mp3", "y");
+        // One file with no copy keeps /old itself from being reported, but
+        // not the directory next to it whose files are copied within /old
+        insert_file(&conn, "/old/unique.txt", "unique");
+        insert_file(&conn, "/old/Dupes/d.txt", "d");
+        insert_file(&conn, "/old/Other/d.txt", "d");
+
+        let found = find_contained_directories(&conn, &[Path::new("/old")], &[]).unwrap();
+        let summary: Vec<(&str, usize, Option<&str>)> = found
+            .iter()
+            .map(|c| (c.path.as_str(), c.files, c.container.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("/old/Docs", 2, Some("/home/Documents")),
+                ("/old/Dupes", 1, Some("/old/Other")),
+                ("/old/Music", 2, None),
+                ("/old/Other", 1, Some("/old/Dupes")),
+            ]
+        );
+
+        // Only copies under /home count
+        let found =
+            find_contained_directories(&conn, &[Path::new("/old")], &[Path::new("/home")]).unwrap();
+        let paths: Vec<&str> = found.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(paths, vec!["/old/Docs"]);
+    }
+
+    #[test]
+    fn test_find_contained_directories_reports_a_root_that_is_all_copied() {
+        let conn = open_test_db();
+        insert_file(&conn, "/old/a.txt", "a");
+        insert_file(&conn, "/old/sub/b.txt", "b");
+        insert_file(&conn, "/new/a.txt", "a");
+        insert_file(&conn, "/new/b.txt", "b");
+        // A hardlink is the same data, not a copy
+        insert_file(&conn, "/old/c.txt", "c");
+        insert_file(&conn, "/new/c.txt", "c");
+        db::update_file_identity(&conn, Path::new("/old/c.txt"), 1, 5).unwrap();
+        db::update_file_identity(&conn, Path::new("/new/c.txt"), 1, 5).unwrap();
+        let found = find_contained_directories(&conn, &[Path::new("/old")], &[]).unwrap();
+        let paths: Vec<&str> = found.iter().map(|c| c.path.as_str()).collect();
+        assert_eq!(paths, vec!["/old/sub"]);
+
+        db::remove_file(&conn, Path::new("/old/c.txt")).unwrap();
+        let found = find_contained_directories(&conn, &[Path::new("/old")], &[]).unwrap();
+        assert_eq!(
+            found,
+            vec![Contained {
+                path: "/old".to_string(),
+                files: 2,
+                bytes: 20,
+                container: Some("/new".to_string()),
+            }]
+        );
+    }
+}
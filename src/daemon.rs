@@ -0,0 +1,487 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+
+use crate::config;
+use crate::schedule::Schedule;
+use crate::{db, hashing, scan, utils, walk, HashStore, Scanner};
+
+/// How often the daemon checks whether a scan is due, or it was stopped.
+const TICK: Duration = Duration::from_secs(1);
+
+/// Skipped paths named in the log after each scan.
+const ERRORS_LOGGED: usize = 20;
+
+/// A `daemon --config` file.
+#[derive(Debug, Clone)]
+pub struct DaemonConfig {
+    /// Where the hashes go; `--database` when not set.
+    pub database: Option<PathBuf>,
+    /// The log file; standard error when not set.
+    pub log: Option<PathBuf>,
+    /// A log file this large is rotated before the next line is written.
+    pub log_max_size: u64,
+    /// How many rotated logs (`daemon.log.1` being the newest) are kept.
+    pub log_keep: usize,
+    /// The algorithm a new database hashes with (see `Scanner::algorithm`).
+    pub hash: Option<hashing::HashAlgorithm>,
+    /// Hashing threads per scan; 0 for one per CPU.
+    pub threads: usize,
+    pub roots: Vec<RootConfig>,
+}
+
+/// One `[[root]]` of a `DaemonConfig`.
+#[derive(Debug, Clone)]
+pub struct RootConfig {
+    pub path: PathBuf,
+    /// The cron expression, as written
+    pub schedule_text: String,
+    pub schedule: Schedule,
+    /// Globs skipped as `--exclude` skips them
+    pub exclude: Vec<String>,
+    /// Keep the records of files that are gone instead of removing them.
+    pub keep_missing: bool,
+}
+
+impl DaemonConfig {
+    /// Read and check the configuration at `path`:
+    ///
+    /// ```toml
+    /// database = "/volume1/dedup/hashes.db"
+    /// log = "/volume1/dedup/daemon.log"
+    ///
+    /// [[root]]
+    /// path = "/volume1/photos"
+    /// schedule = "0 3 * * *"
+    /// exclude = ["@eaDir"]
+    /// ```
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = config::load(path)?;
+        Self::from_file(file)
+            .with_context(|| format!("invalid daemon configuration {}", path.display()))
+    }
+
+    fn from_file(file: DaemonFile) -> Result<Self> {
+        let mut roots = Vec::new();
+        for root in file.root {
+            let path = config::expand_home(&root.path);
+            let schedule_text = root
+                .schedule
+                .ok_or_else(|| anyhow!("[[root]] {} needs a `schedule`", path.display()))?;
+            let schedule = Schedule::parse(&schedule_text)
+                .with_context(|| format!("bad schedule for {}", path.display()))?;
+            roots.push(RootConfig {
+                path,
+                schedule_text,
+                schedule,
+                exclude: root.exclude,
+                keep_missing: root.keep_missing,
+            });
+        }
+        if roots.is_empty() {
+            bail!("no [[root]] to scan");
+        }
+        Ok(Self {
+            database: file.database,
+            log: file.log,
+            log_max_size: file.log_max_mib * 1024 * 1024,
+            log_keep: file.log_keep,
+            hash: file.hash,
+            threads: file.threads,
+            roots,
+        })
+    }
+}
+
+/// A `daemon --config` file as written.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DaemonFile {
+    #[serde(default, deserialize_with = "config::path")]
+    database: Option<PathBuf>,
+    #[serde(default, deserialize_with = "config::path")]
+    log: Option<PathBuf>,
+    #[serde(default = "DaemonFile::default_log_max_mib")]
+    log_max_mib: u64,
+    #[serde(default = "DaemonFile::default_log_keep")]
+    log_keep: usize,
+    #[serde(default, deserialize_with = "config::hash_algorithm")]
+    hash: Option<hashing::HashAlgorithm>,
+    #[serde(default)]
+    threads: usize,
+    #[serde(default)]
+    root: Vec<RootFile>,
+}
+
+impl DaemonFile {
+    fn default_log_max_mib() -> u64 {
+        10
+    }
+
+    fn default_log_keep() -> usize {
+        5
+    }
+}
+
+/// One `[[root]]` as written.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RootFile {
+    path: String,
+    schedule: Option<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    keep_missing: bool,
+}
+
+/// The daemon's log: timestamped lines in a file that is rotated once it
+/// reaches its size limit, or on standard error.
+pub struct Log {
+    path: Option<PathBuf>,
+    max_size: u64,
+    keep: usize,
+    file: Option<File>,
+}
+
+impl Log {
+    pub fn open(path: Option<&Path>, max_size: u64, keep: usize) -> Result<Self> {
+        let mut log = Self {
+            path: path.map(Path::to_path_buf),
+            max_size,
+            keep,
+            file: None,
+        };
+        log.reopen()?;
+        Ok(log)
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        if let Some(path) = &self.path {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("could not open the log {}", path.display()))?;
+            self.file = Some(file);
+        }
+        Ok(())
+    }
+
+    /// Append `message` with the current time (UTC).
+    pub fn write(&mut self, message: &str) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs() as i64;
+        let line = format!("{}:{:02}  {message}\n", utils::fmt_mtime(now), now % 60);
+        let Some(file) = &mut self.file else {
+            eprint!("{line}");
+            return Ok(());
+        };
+        if file.metadata()?.len() + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        if let Some(file) = &mut self.file {
+            file.write_all(line.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Shift `log.1` to `log.2` and so on, dropping the oldest beyond `keep`,
+    /// move the log to `log.1` and start a new one.
+    fn rotate(&mut self) -> Result<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let rotated = |n: usize| {
+            let mut name = path.clone().into_os_string();
+            name.push(format!(".{n}"));
+            PathBuf::from(name)
+        };
+        self.file = None;
+        if self.keep == 0 {
+            fs::remove_file(&path)?;
+        } else {
+            let _ = fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            fs::rename(&path, rotated(1))?;
+        }
+        self.reopen()
+    }
+}
+
+/// Run each root's scan whenever its schedule says, until `cancel` is set.
+/// A scan that comes due while another run holds the database's run lock is
+/// skipped until its next time; runs missed during a long scan are not made
+/// up. Each scan, and what it found, is logged; failures are logged too and
+/// don't stop the daemon.
+pub fn run(
+    store: &HashStore,
+    config: &DaemonConfig,
+    log: &mut Log,
+    cancel: &Arc<AtomicBool>,
+) -> Result<()> {
+    log.write(&format!(
+        "started (pid {}), scanning {} root(s)",
+        std::process::id(),
+        config.roots.len()
+    ))?;
+    let now = unix_now()?;
+    let mut next: Vec<Option<i64>> = Vec::new();
+    for root in &config.roots {
+        let at = root.schedule.next_after(now);
+        match at {
+            Some(at) => log.write(&format!(
+                "{}: next scan {}",
+                root.path.display(),
+                utils::fmt_mtime(at)
+            ))?,
+            None => log.write(&format!(
+                "{}: schedule `{}` never comes round",
+                root.path.display(),
+                root.schedule_text
+            ))?,
+        }
+        next.push(at);
+    }
+
+    while !cancel.load(Ordering::Relaxed) {
+        let now = unix_now()?;
+        for (root, next) in config.roots.iter().zip(next.iter_mut()) {
+            if next.is_none_or(|at| at > now) || cancel.load(Ordering::Relaxed) {
+                continue;
+            }
+            if let Err(e) = scan_root(store, config, root, log, cancel) {
+                log.write(&format!("{}: scan failed: {e:#}", root.path.display()))?;
+            }
+            *next = root.schedule.next_after(unix_now()?);
+        }
+        std::thread::sleep(TICK);
+    }
+    log.write("stopped")?;
+    Ok(())
+}
+
+/// Scan `root` into `store`, holding the run lock while it does.
+fn scan_root(
+    store: &HashStore,
+    config: &DaemonConfig,
+    root: &RootConfig,
+    log: &mut Log,
+    cancel: &Arc<AtomicBool>,
+) -> Result<()> {
+    let name = root.path.display();
+    let command = format!("deduplifier daemon (scanning {name})");
+    let _lock = match db::try_lock(store.connection(), &command)? {
+        Ok(lock) => lock,
+        Err(holder) => {
+            log.write(&format!(
+                "{name}: skipped, the database is in use by `{}` (pid {})",
+                holder.command, holder.pid
+            ))?;
+            return Ok(());
+        }
+    };
+    log.write(&format!("{name}: scanning"))?;
+    let started = Instant::now();
+    let scanner = Scanner {
+        options: scan::ScanOptions {
+            threads: config.threads,
+            walk: walk::WalkOptions {
+                filter: walk::PathFilter::new(&root.exclude, &[], &[])?,
+                ..walk::WalkOptions::default()
+            },
+            cancel: cancel.clone(),
+            ..scan::ScanOptions::default()
+        },
+        algorithm: config.hash,
+        remove_stale: !root.keep_missing,
+    };
+    let results = scanner.scan(store, &[root.path.as_path()], |_, _, _| {})?;
+    let Some(result) = results.first() else {
+        return Ok(());
+    };
+    if result.interrupted {
+        log.write(&format!("{name}: scan stopped part-way"))?;
+        return Ok(());
+    }
+    let (files, bytes) = store.totals()?;
+    log.write(&format!(
+        "{name}: scanned in {:.1}s; {} moved, {} gone ({}), {} could not be scanned; \
+         {files} file(s), {} in the database",
+        started.elapsed().as_secs_f64(),
+        result.moved,
+        result.stale_count,
+        if root.keep_missing { "kept" } else { "removed" },
+        result.errors.len(),
+        utils::fmt_size(bytes)
+    ))?;
+    for error in result.errors.iter().take(ERRORS_LOGGED) {
+        log.write(&format!("  {}: {}", error.path, error.message))?;
+    }
+    if result.errors.len() > ERRORS_LOGGED {
+        log.write(&format!(
+            "  ... and {} more",
+            result.errors.len() - ERRORS_LOGGED
+        ))?;
+    }
+    Ok(())
+}
+
+fn unix_now() -> Result<i64> {
+    Ok(SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as i64)
+}
+
+/// Carry on in the background: fork, and in the child start a new session
+/// with standard input and output on `/dev/null`. Returns the child's pid in
+/// the parent, which should exit, and `None` in the child. Must be called
+/// before any threads are started.
+#[cfg(unix)]
+pub fn detach() -> Result<Option<u32>> {
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()).context("could not fork"),
+        0 => {
+            unsafe { libc::setsid() };
+            let null = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open("/dev/null")?;
+            use std::os::unix::io::AsRawFd;
+            for fd in 0..3 {
+                unsafe { libc::dup2(null.as_raw_fd(), fd) };
+            }
+            Ok(None)
+        }
+        pid => Ok(Some(pid as u32)),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn detach() -> Result<Option<u32>> {
+    bail!("--detach is only supported on Unix; run the daemon as a service instead")
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(text: &str) -> Result<DaemonConfig> {
+        DaemonConfig::from_file(config::parse(text)?)
+    }
+
+    #[test]
+    fn test_config_reads_roots_and_defaults() {
+        let config = load(
+            r#"
+database = "/nas/hashes.db"
+log_max_mib = 1
+hash = "xxh3"
+
+[[root]]
+path = "/nas/photos"
+schedule = "0 3 * * *"
+exclude = ["@eaDir"]
+
+[[root]]
+path = "/nas/docs"
+schedule = "@hourly"
+keep_missing = true
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.database, Some(PathBuf::from("/nas/hashes.db")));
+        assert_eq!(config.log, None);
+        assert_eq!(config.log_max_size, 1024 * 1024);
+        assert_eq!(config.log_keep, 5);
+        assert_eq!(config.hash, Some(hashing::HashAlgorithm::Xxh3));
+        assert_eq!(config.roots.len(), 2);
+        assert_eq!(config.roots[0].exclude, vec!["@eaDir"]);
+        assert!(!config.roots[0].keep_missing);
+        assert_eq!(
+            config.roots[1].schedule,
+            Schedule::parse("0 * * * *").unwrap()
+        );
+        assert!(config.roots[1].keep_missing);
+
+        let error = |text: &str| format!("{:#}", load(text).unwrap_err());
+        assert_eq!(error(""), "no [[root]] to scan");
+        assert_eq!(
+            error("[[root]]\npath = \"/a\""),
+            "[[root]] /a needs a `schedule`"
+        );
+        assert_eq!(
+            error("[[root]]\npath = \"/a\"\nschedule = \"0 25 * * *\""),
+            "bad schedule for /a: hour: `25` is not between 0 and 23"
+        );
+        let misspelt = error("[[root]]\npath = \"/a\"\nschedle = \"@daily\"");
+        assert!(misspelt.contains("line 3"), "{misspelt}");
+        assert!(misspelt.contains("unknown field `schedle`"), "{misspelt}");
+        assert!(error("log_keep = -1").contains("integer `-1`"));
+    }
+
+    #[test]
+    fn test_log_rotates_and_keeps_the_newest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("daemon.log");
+        let mut log = Log::open(Some(&path), 100, 2).unwrap();
+        for i in 0..12 {
+            log.write(&format!("line {i}")).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(tmp.path().join(name)).unwrap();
+        // Every line is 28 or 29 bytes, so each file holds three
+        assert!(read("daemon.log").ends_with("line 11\n"));
+        assert_eq!(read("daemon.log").lines().count(), 3);
+        assert!(read("daemon.log.1").ends_with("line 8\n"));
+        assert!(read("daemon.log.2").ends_with("line 5\n"));
+        assert!(!tmp.path().join("daemon.log.3").exists());
+    }
+
+    #[test]
+    fn test_scan_root_scans_unless_the_database_is_locked() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("a.txt"), "a").unwrap();
+        let store = HashStore::open_in_memory().unwrap();
+        let text = format!(
+            "[[root]]\npath = '{}'\nschedule = '@daily'",
+            tmp.path().display()
+        );
+        let config = load(&text).unwrap();
+        let logs = tempfile::tempdir().unwrap();
+        let log_path = logs.path().join("log");
+        let mut log = Log::open(Some(&log_path), 1 << 20, 1).unwrap();
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        let lock = db::try_lock(store.connection(), "someone else")
+            .unwrap()
+            .unwrap();
+        scan_root(&store, &config, &config.roots[0], &mut log, &cancel).unwrap();
+        assert_eq!(store.totals().unwrap().0, 0);
+        drop(lock);
+        scan_root(&store, &config, &config.roots[0], &mut log, &cancel).unwrap();
+        assert_eq!(store.totals().unwrap().0, 1);
+        // Released again
+        assert!(db::lock_holder(store.connection()).unwrap().is_none());
+
+        let logged = fs::read_to_string(&log_path).unwrap();
+        assert!(logged.contains("skipped, the database is in use by `someone else`"));
+        assert!(logged.contains("1 file(s), 1 B in the database"));
+    }
+}
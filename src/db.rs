@@ -1,13 +1,12 @@
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
-use std::time::SystemTime;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use rusqlite::{params, Connection};
 
-use crate::utils;
-
-/// SHA-256 of an empty byte sequence — the hash assigned to empty directories.
-pub const EMPTY_DIR_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+use crate::{hashing, utils};
 
 // ---------------------------------------------------------------------------
 // Structs
@@ -20,6 +19,17 @@ pub struct FileRecord {
     pub hash: String,
     pub size: i64,
     pub modified: i64,
+    /// Hash of the first and last `hashing::PARTIAL_CHUNK_SIZE` bytes; set
+    /// whenever the scan reads the file.
+    pub partial_hash: Option<String>,
+    /// Device and inode numbers (Unix only). Paths sharing both are
+    /// hardlinks to the same data.
+    pub device: Option<i64>,
+    pub inode: Option<i64>,
+    /// The scan reached this path through a symlink (`--follow-symlinks`).
+    pub via_link: bool,
+    /// Link count, owner and permissions (Unix only).
+    pub stat: Option<utils::FileStat>,
 }
 
 /// A summary row from a duplicate-group query.
@@ -31,6 +41,85 @@ pub struct DuplicateGroupHash {
     pub size: i64,
 }
 
+/// A row from the `actions` table: one change made to the filesystem, with
+/// what `undo` needs to reverse it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActionRecord {
+    pub id: i64,
+    /// Unix timestamp of the change
+    pub time: i64,
+    /// `delete`, `trash`, `quarantine`, `hardlink`, `symlink`, `reflink`,
+    /// `move` or `delete-dir`
+    pub action: String,
+    /// The path that was deleted, replaced with a link, or moved away
+    pub source: String,
+    /// Where the data still is: the kept copy or link target, or where a file
+    /// was moved or quarantined to; `None` when unknown
+    pub destination: Option<String>,
+    /// Content hash of `source` at the time, when known
+    pub hash: Option<String>,
+}
+
+/// A row from the `dedupe_log` table: one decision `dedupe` made about one path.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DedupeLogEntry {
+    /// Unix timestamp of the decision
+    pub time: i64,
+    /// Hash of the duplicate group
+    pub hash: String,
+    pub path: String,
+    /// `keep`, `delete`, or why a planned action was not taken
+    pub action: String,
+    /// The copy kept in place of `path`, for removals
+    pub keeper: Option<String>,
+    /// What made the decision: `interactive`, or the `--auto` rules
+    pub reason: String,
+}
+
+/// A row from the `errors` table: one path a scan had to skip.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorRecord {
+    /// Unix timestamp of the scan that hit the error
+    pub time: i64,
+    /// The directory being scanned
+    pub root: String,
+    pub path: String,
+    /// `permission-denied`, `not-found`, `io` or `hash`
+    pub kind: String,
+    pub message: String,
+}
+
+/// A row from the `scans` table, with how many files it recorded as added,
+/// changed and removed in `file_history`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanRecord {
+    pub id: i64,
+    /// Unix timestamp of when the scan started
+    pub time: i64,
+    /// The directories it was asked to scan
+    pub roots: Vec<String>,
+    pub added: i64,
+    pub changed: i64,
+    pub removed: i64,
+}
+
+/// A file as `file_history` has it after some scan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryFile {
+    pub path: String,
+    pub hash: String,
+    pub size: i64,
+}
+
+/// Where a file or directory is: the machine that scanned it and the
+/// `--label` the scan was given, which names the volume wherever it is
+/// mounted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Location {
+    pub host: Option<String>,
+    pub volume: Option<String>,
+}
+
 /// A row from the `directories` table.
 #[derive(Debug, Clone, PartialEq)]
 pub struct DirRecord {
@@ -43,7 +132,71 @@ pub struct DirRecord {
 // Schema / connection
 // ---------------------------------------------------------------------------
 
+/// The schema changes in order: a database at version N has had the first N
+/// applied. Never change one that has been released; append a new one.
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] = &[
+    create_baseline,
+    add_scan_history,
+    add_merge_sources,
+    add_locations,
+    add_file_stats,
+    add_run_lock,
+    add_ignore_rules,
+    add_image_hashes,
+    add_audio_fingerprints,
+    add_photo_payloads,
+    add_archive_members,
+    add_decompressed_hashes,
+    add_text_signatures,
+    add_file_chunks,
+    add_video_signatures,
+    add_remote_rows,
+    add_root_options,
+];
+
+/// The schema version this build creates and understands.
+pub const SCHEMA_VERSION: i64 = MIGRATIONS.len() as i64;
+
+/// Bring the database up to `SCHEMA_VERSION`, applying each missing
+/// migration in its own transaction. Refuses a database written by a newer
+/// version, whose schema this build can't know how to use.
 pub fn setup_schema(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)",
+        [],
+    )?;
+    let version = schema_version(conn)?;
+    if version > SCHEMA_VERSION {
+        bail!(
+            "the database has schema version {version}, but this deduplifier only supports up to \
+             {SCHEMA_VERSION}; upgrade deduplifier to open it"
+        );
+    }
+    for (applied, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.unchecked_transaction()?;
+        migration(&tx)?;
+        tx.execute("DELETE FROM schema_version", [])?;
+        tx.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            params![applied as i64 + 1],
+        )?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// The version recorded in `schema_version`; 0 for a new database or one
+/// from before versioning.
+pub fn schema_version(conn: &Connection) -> Result<i64> {
+    let version: Option<i64> =
+        conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| row.get(0))?;
+    Ok(version.unwrap_or(0))
+}
+
+/// Version 1: everything from before schema versioning. Databases created
+/// back then may have any part of it, so every step is skipped if it is
+/// already there.
+fn create_baseline(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS files (
             path TEXT PRIMARY KEY,
@@ -73,283 +226,666 @@ pub fn setup_schema(conn: &Connection) -> Result<()> {
         [],
     )?;
 
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS meta (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS scan_state (
+            root TEXT PRIMARY KEY,
+            completed INTEGER NOT NULL DEFAULT 0
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS dedupe_log (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            time INTEGER NOT NULL,
+            hash TEXT NOT NULL,
+            path TEXT NOT NULL,
+            action TEXT NOT NULL,
+            keeper TEXT,
+            reason TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS actions (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            time INTEGER NOT NULL,
+            action TEXT NOT NULL,
+            source TEXT NOT NULL,
+            destination TEXT,
+            hash TEXT,
+            undone INTEGER
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS protected (
+            path TEXT PRIMARY KEY
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS errors (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            time INTEGER NOT NULL,
+            root TEXT NOT NULL,
+            path TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            message TEXT NOT NULL
+        )",
+        [],
+    )?;
+
+    add_column_if_missing(conn, "files", "partial_hash", "TEXT")?;
+    add_column_if_missing(conn, "files", "device", "INTEGER")?;
+    add_column_if_missing(conn, "files", "inode", "INTEGER")?;
+    add_column_if_missing(conn, "files", "via_link", "INTEGER NOT NULL DEFAULT 0")?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_file_size ON files(size)",
+        [],
+    )?;
+
     Ok(())
 }
 
-pub fn init_database(path: &Path) -> Result<Connection> {
-    let conn = Connection::open(path)?;
-    setup_schema(&conn)?;
-    Ok(conn)
-}
+/// Version 2: scan sessions and per-file history for `scans` and `diff`.
+/// Unversioned databases written just after it was added have it already.
+fn add_scan_history(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "last_scan", "INTEGER")?;
 
-// ---------------------------------------------------------------------------
-// File records
-// ---------------------------------------------------------------------------
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS scans (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            time INTEGER NOT NULL,
+            roots TEXT NOT NULL
+        )",
+        [],
+    )?;
 
-/// Returns `true` if the file at `path` is not in the DB, or its stored
-/// modified timestamp differs from `modified`.
-pub fn should_update_file(conn: &Connection, path: &Path, modified: SystemTime) -> Result<bool> {
-    let actual = modified.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
-    match get_file(conn, path)? {
-        None => Ok(true),
-        Some(rec) => Ok(actual != rec.modified),
-    }
-}
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS file_history (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            scan_id INTEGER NOT NULL,
+            path TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            event TEXT NOT NULL
+        )",
+        [],
+    )?;
 
-/// Fetch a single file record by path; returns `None` if not found.
-pub fn get_file(conn: &Connection, path: &Path) -> Result<Option<FileRecord>> {
-    let path_str = utils::path_to_str(path)?;
-    let result = conn
-        .prepare("SELECT path, hash, size, modified FROM files WHERE path = ?1")?
-        .query_row(params![path_str], |row| {
-            Ok(FileRecord {
-                path: row.get(0)?,
-                hash: row.get(1)?,
-                size: row.get(2)?,
-                modified: row.get(3)?,
-            })
-        });
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_history_path ON file_history(path, id)",
+        [],
+    )?;
 
-    match result {
-        Ok(file) => Ok(Some(file)),
-        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
-        Err(e) => Err(e.into()),
-    }
+    Ok(())
 }
 
-/// Return all file records, ordered by path.
-pub fn all_files(conn: &Connection) -> Result<Vec<FileRecord>> {
-    let mut stmt = conn.prepare("SELECT path, hash, size, modified FROM files ORDER BY path")?;
-    let rows = stmt
-        .query_map([], |row| {
-            Ok(FileRecord {
-                path: row.get(0)?,
-                hash: row.get(1)?,
-                size: row.get(2)?,
-                modified: row.get(3)?,
-            })
-        })?
-        .collect::<rusqlite::Result<_>>()?;
-    Ok(rows)
+/// Version 3: which merged database a file or directory row came from.
+fn add_merge_sources(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "source", "TEXT")?;
+    add_column_if_missing(conn, "directories", "source", "TEXT")?;
+    Ok(())
 }
 
-/// Return all file records with the given hash, ordered by path.
-pub fn files_with_hash(conn: &Connection, hash: &str) -> Result<Vec<FileRecord>> {
-    let mut stmt =
-        conn.prepare("SELECT path, hash, size, modified FROM files WHERE hash = ?1 ORDER BY path")?;
-    let rows = stmt
-        .query_map(params![hash], |row| {
-            Ok(FileRecord {
-                path: row.get(0)?,
-                hash: row.get(1)?,
-                size: row.get(2)?,
-                modified: row.get(3)?,
-            })
-        })?
-        .collect::<rusqlite::Result<Vec<_>>>()?;
-    Ok(rows)
+/// Version 4: the host and volume label each file and directory was
+/// scanned on.
+fn add_locations(conn: &Connection) -> Result<()> {
+    for table in ["files", "directories"] {
+        add_column_if_missing(conn, table, "host", "TEXT")?;
+        add_column_if_missing(conn, table, "volume", "TEXT")?;
+    }
+    Ok(())
 }
 
-/// Return groups of files that share the same hash (i.e. duplicates).
-/// Each item is `(hash, count, total_size_bytes)`, sorted by total_size descending.
-pub fn duplicate_file_groups(conn: &Connection) -> Result<Vec<DuplicateGroupHash>> {
-    let mut stmt = conn.prepare(
-        "SELECT hash, COUNT(*) AS cnt, SUM(size) AS total_size
-            FROM files
-            GROUP BY hash
-            HAVING cnt > 1
-            ORDER BY total_size DESC",
-    )?;
-    let rows = stmt
-        .query_map([], |row| {
-            Ok(DuplicateGroupHash {
-                hash: row.get(0)?,
-                count: row.get(1)?,
-                size: row.get(2)?,
-            })
-        })?
-        .collect::<rusqlite::Result<Vec<_>>>()?;
-    Ok(rows)
+/// Version 5: the link count, owner and permissions of each file.
+fn add_file_stats(conn: &Connection) -> Result<()> {
+    for column in ["nlink", "uid", "gid", "mode"] {
+        add_column_if_missing(conn, "files", column, "INTEGER")?;
+    }
+    Ok(())
 }
 
-/// Insert or replace a file record.
-pub fn upsert_file(
-    conn: &Connection,
-    path: &Path,
-    hash: &str,
-    size: i64,
-    modified: i64,
-) -> Result<()> {
-    let path_str = utils::path_to_str(path)?;
+/// Version 6: the lock a command that changes the database holds while it runs.
+fn add_run_lock(conn: &Connection) -> Result<()> {
     conn.execute(
-        "INSERT OR REPLACE INTO files (path, hash, size, modified) VALUES (?1, ?2, ?3, ?4)",
-        params![path_str, hash, size, modified],
+        "CREATE TABLE IF NOT EXISTS run_lock (
+            id INTEGER PRIMARY KEY CHECK (id = 1),
+            command TEXT NOT NULL,
+            pid INTEGER NOT NULL,
+            host TEXT,
+            started INTEGER NOT NULL
+        )",
+        [],
     )?;
     Ok(())
 }
 
-/// Rename a file record from `old_path` to `new_path`.
-pub fn move_file(conn: &Connection, old_path: &Path, new_path: &Path) -> Result<()> {
-    let old = utils::path_to_str(old_path)?;
-    let new = utils::path_to_str(new_path)?;
-
-    // By the time this is called, the filesystem move has already succeeded, so
-    // resolve_dest already confirmed `new_path` was free on disk. Any existing DB
-    // entry at this path is therefore a ghost (e.g. a sync client renamed the file
-    // mid-run). Remove it so the UPDATE below doesn't hit a PK conflict.
-    let removed = conn.execute("DELETE FROM files WHERE path = ?1", params![new])?;
-    if removed > 0 {
-        eprintln!(
-            "Warning: removed ghost DB entry for {} (file was renamed by another process mid-run)",
-            new_path.display()
-        );
-    }
-
+/// Version 7: the rules `ignore add` keeps files and groups out of reports with.
+fn add_ignore_rules(conn: &Connection) -> Result<()> {
     conn.execute(
-        "UPDATE files SET path = ?1 WHERE path = ?2",
-        params![new, old],
+        "CREATE TABLE IF NOT EXISTS ignore_rules (
+            kind TEXT NOT NULL,
+            pattern TEXT NOT NULL,
+            PRIMARY KEY (kind, pattern)
+        )",
+        [],
     )?;
     Ok(())
 }
 
-/// Delete a single file record.
-pub fn remove_file(conn: &Connection, path: &Path) -> Result<()> {
-    let path_str = utils::path_to_str(path)?;
-    conn.execute("DELETE FROM files WHERE path = ?1", params![path_str])?;
+/// Version 8: the perceptual hashes `similar-images` compares pictures by.
+fn add_image_hashes(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS image_hashes (
+            path TEXT PRIMARY KEY,
+            hash TEXT NOT NULL,
+            dhash INTEGER,
+            width INTEGER NOT NULL,
+            height INTEGER NOT NULL
+        )",
+        [],
+    )?;
     Ok(())
 }
 
-/// Update only the hash of an existing file record.
-pub fn update_file_hash(conn: &Connection, path: &Path, hash: &str) -> Result<()> {
-    let path_str = utils::path_to_str(path)?;
+/// Version 9: the acoustic fingerprints `similar-audio` compares tracks by.
+fn add_audio_fingerprints(conn: &Connection) -> Result<()> {
     conn.execute(
-        "UPDATE files SET hash = ?1 WHERE path = ?2",
-        params![hash, path_str],
+        "CREATE TABLE IF NOT EXISTS audio_fingerprints (
+            path TEXT PRIMARY KEY,
+            hash TEXT NOT NULL,
+            fingerprint BLOB,
+            duration REAL NOT NULL
+        )",
+        [],
     )?;
     Ok(())
 }
 
-// ---------------------------------------------------------------------------
-// Stale-file tracking  (requires the `visited_files` temp table)
-// ---------------------------------------------------------------------------
+/// Version 10: the payload hashes `dup-photos` groups photos by.
+fn add_photo_payloads(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS photo_payloads (
+            path TEXT PRIMARY KEY,
+            hash TEXT NOT NULL,
+            payload TEXT
+        )",
+        [],
+    )?;
+    Ok(())
+}
 
-/// Create (if absent) and clear the `visited_files` temp table.
-/// Call once at the start of each scan.
-pub fn init_visited_files(conn: &Connection) -> Result<()> {
-    conn.execute_batch(
-        "CREATE TEMP TABLE IF NOT EXISTS visited_files (path TEXT PRIMARY KEY);
-         DELETE FROM visited_files;",
+/// Version 11: which archive a file row was read out of, for
+/// `--scan-archives`.
+fn add_archive_members(conn: &Connection) -> Result<()> {
+    add_column_if_missing(conn, "files", "archive", "TEXT")?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_files_archive ON files(archive)",
+        [],
     )?;
     Ok(())
 }
 
-/// Record that `path` was seen during the current scan.
-pub fn mark_visited(conn: &Connection, path: &str) -> Result<()> {
+/// Version 12: the hashes of what compressed files decompress to, which
+/// `dup-compressed` groups files by.
+fn add_decompressed_hashes(conn: &Connection) -> Result<()> {
     conn.execute(
-        "INSERT OR IGNORE INTO visited_files (path) VALUES (?1)",
-        params![path],
+        "CREATE TABLE IF NOT EXISTS decompressed_hashes (
+            path TEXT PRIMARY KEY,
+            hash TEXT NOT NULL,
+            content TEXT
+        )",
+        [],
     )?;
     Ok(())
 }
 
-/// Count files in the DB under `root_prefix` that were not seen in this scan.
-/// `root_prefix` should be the root path string (no trailing slash needed).
-pub fn stale_file_count(conn: &Connection, root_prefix: &str) -> Result<i64> {
-    let sep = std::path::MAIN_SEPARATOR;
-    let pattern = format!("{}{}%", root_prefix.trim_end_matches(sep), sep);
-    let count = conn.query_row(
-        "SELECT COUNT(*) FROM files
-            LEFT JOIN visited_files ON files.path = visited_files.path
-            WHERE files.path LIKE ?1 AND visited_files.path IS NULL",
-        params![pattern],
-        |row| row.get(0),
+/// Version 13: the MinHash signatures `similar-text` compares documents by.
+fn add_text_signatures(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS text_signatures (
+            path TEXT PRIMARY KEY,
+            hash TEXT NOT NULL,
+            signature BLOB
+        )",
+        [],
     )?;
-    Ok(count)
+    Ok(())
 }
 
-/// Delete all files under `root_prefix` that were not seen in this scan.
-pub fn delete_stale_files(conn: &Connection, root_prefix: &str) -> Result<()> {
-    let sep = std::path::MAIN_SEPARATOR;
-    let pattern = format!("{}{}%", root_prefix.trim_end_matches(sep), sep);
+/// Version 14: the content-defined chunks `shared-chunks` compares large
+/// files by.
+fn add_file_chunks(conn: &Connection) -> Result<()> {
     conn.execute(
-        "DELETE FROM files WHERE path LIKE ?1 AND path IN (
-            SELECT files.path FROM files
-            LEFT JOIN visited_files ON files.path = visited_files.path
-            WHERE files.path LIKE ?1 AND visited_files.path IS NULL
+        "CREATE TABLE IF NOT EXISTS file_chunks (
+            path TEXT PRIMARY KEY,
+            hash TEXT NOT NULL,
+            chunks BLOB
         )",
-        params![pattern],
+        [],
     )?;
     Ok(())
 }
 
-// ---------------------------------------------------------------------------
-// Directory records
+/// Version 15: the sampled-frame signatures `similar-videos` compares
+/// videos by.
+fn add_video_signatures(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS video_signatures (
+            path TEXT PRIMARY KEY,
+            hash TEXT NOT NULL,
+            frames BLOB,
+            duration REAL NOT NULL,
+            width INTEGER NOT NULL,
+            height INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 16: the machine or bucket a file or directory row was listed on,
+/// for `ssh://` and `s3://` roots.
+fn add_remote_rows(conn: &Connection) -> Result<()> {
+    for table in ["files", "directories"] {
+        add_column_if_missing(conn, table, "remote", "TEXT")?;
+    }
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_files_remote ON files(remote)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Version 17: the options each root was last scanned with, which
+/// `POST /api/scan` scans it again with.
+fn add_root_options(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS root_options (
+            root TEXT PRIMARY KEY,
+            options TEXT NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Add `column` to `table` unless it is already there, so databases created
+/// by older versions pick up new columns the first time they are opened.
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<()> {
+    let exists = conn
+        .prepare(&format!("PRAGMA table_info({table})"))?
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?
+        .iter()
+        .any(|name| name == column);
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"), [])?;
+    }
+    Ok(())
+}
+
+/// How long a statement waits for another process's write to the database
+/// to finish before failing with "database is locked".
+const BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Whether this build can encrypt databases (the `sqlcipher` feature).
+pub const ENCRYPTION_SUPPORTED: bool = cfg!(feature = "sqlcipher");
+
+/// The SQLCipher key every database this run opens is encrypted with, once
+/// `set_key` has been called.
+static KEY: OnceLock<String> = OnceLock::new();
+
+/// Open every database from here on encrypted with `key`, the ones
+/// `merge-db` reads from included. Fails in a build without SQLCipher.
+pub fn set_key(key: String) -> Result<()> {
+    if !ENCRYPTION_SUPPORTED {
+        bail!(
+            "this build can't encrypt databases; \
+             rebuild with `cargo build --features sqlcipher`"
+        );
+    }
+    KEY.set(key).map_err(|_| anyhow!("the database key was already set"))
+}
+
+/// Unlock `conn` with `key`, before anything else reads it. SQLCipher only
+/// finds out whether the key is right on the first read, so one is made
+/// here rather than failing with "file is not a database" later on.
+fn apply_key(conn: &Connection, key: Option<&str>) -> Result<()> {
+    if let Some(key) = key {
+        conn.pragma_update(None, "key", key)?;
+    }
+    let read = conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()));
+    match (read, key) {
+        (Ok(()), _) => Ok(()),
+        (Err(_), Some(_)) => {
+            bail!("cannot read the database: the key is wrong, or it isn't encrypted")
+        }
+        (Err(e), None) if e.sqlite_error_code() == Some(rusqlite::ErrorCode::NotADatabase) => {
+            bail!("cannot read the database: it isn't one, or it is encrypted (give --encrypted)")
+        }
+        (Err(e), None) => Err(e.into()),
+    }
+}
+
+pub fn init_database(path: &Path) -> Result<Connection> {
+    open_database(path, KEY.get().map(String::as_str))
+}
+
+fn open_database(path: &Path, key: Option<&str>) -> Result<Connection> {
+    let conn = Connection::open(path)?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    apply_key(&conn, key)?;
+    // WAL lets each commit append to a log instead of rewriting pages, and
+    // NORMAL sync is still crash-safe in WAL mode; together they keep the
+    // database from being the bottleneck during a scan.
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.pragma_update(None, "synchronous", "NORMAL")?;
+    setup_schema(&conn)?;
+    Ok(conn)
+}
+
+/// An in-memory copy of the database at `path` (empty if there is none yet)
+/// for `--dry-run`: commands read and write it as usual, but nothing they do
+/// reaches the file.
+pub fn open_scratch_copy(path: &Path) -> Result<Connection> {
+    scratch_copy(path, KEY.get().map(String::as_str))
+}
+
+fn scratch_copy(path: &Path, key: Option<&str>) -> Result<Connection> {
+    let mut conn = Connection::open_in_memory()?;
+    // SQLCipher only copies pages between databases with the same key
+    apply_key(&conn, key)?;
+    if path.exists() {
+        let disk = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        disk.busy_timeout(BUSY_TIMEOUT)?;
+        apply_key(&disk, key)?;
+        rusqlite::backup::Backup::new(&disk, &mut conn)?.run_to_completion(
+            1024,
+            Duration::ZERO,
+            None,
+        )?;
+    }
+    setup_schema(&conn)?;
+    Ok(conn)
+}
+
+/// Groups writes into explicit transactions of up to `batch_size` steps, so
+/// a scan pays for one commit per batch rather than one per statement.
+/// Whatever is pending is committed by `commit`, or on drop — work already
+/// done is kept even if the scan bails out with an error.
+pub struct WriteBatch<'a> {
+    conn: &'a Connection,
+    batch_size: usize,
+    pending: usize,
+    open: bool,
+}
+
+impl<'a> WriteBatch<'a> {
+    pub fn begin(conn: &'a Connection, batch_size: usize) -> Result<Self> {
+        conn.execute_batch("BEGIN")?;
+        Ok(Self {
+            conn,
+            batch_size: batch_size.max(1),
+            pending: 0,
+            open: true,
+        })
+    }
+
+    /// Count one unit of work, committing and starting a new transaction
+    /// once `batch_size` have accumulated.
+    pub fn tick(&mut self) -> Result<()> {
+        self.pending += 1;
+        if self.pending >= self.batch_size {
+            self.conn.execute_batch("COMMIT; BEGIN")?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        self.open = false;
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+}
+
+impl Drop for WriteBatch<'_> {
+    fn drop(&mut self) {
+        if self.open {
+            let _ = self.conn.execute_batch("COMMIT");
+        }
+    }
+}
+
+/// Returns `true` if the database holds no file or directory records yet.
+pub fn is_empty(conn: &Connection) -> Result<bool> {
+    let has_rows: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM files) OR EXISTS (SELECT 1 FROM directories)",
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(!has_rows)
+}
+
+// ---------------------------------------------------------------------------
+// Meta (database-wide settings)
 // ---------------------------------------------------------------------------
 
-/// Return all directory paths, ordered by path.
-/// Used by similar.rs to load the full directory set in one query.
-pub fn all_directory_paths(conn: &Connection) -> Result<Vec<String>> {
-    let mut stmt = conn.prepare("SELECT path FROM directories ORDER BY path")?;
+/// Fetch a value from the `meta` table; returns `None` if the key is unset.
+pub fn get_meta(conn: &Connection, key: &str) -> Result<Option<String>> {
+    let result = conn.query_row(
+        "SELECT value FROM meta WHERE key = ?1",
+        params![key],
+        |row| row.get(0),
+    );
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Insert or replace a value in the `meta` table.
+pub fn set_meta(conn: &Connection, key: &str, value: &str) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO meta (key, value) VALUES (?1, ?2)",
+        params![key, value],
+    )?;
+    Ok(())
+}
+
+/// Remove a key from the `meta` table, if it is set.
+pub fn delete_meta(conn: &Connection, key: &str) -> Result<()> {
+    conn.execute("DELETE FROM meta WHERE key = ?1", params![key])?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// File records
+// ---------------------------------------------------------------------------
+
+/// Returns `true` if the file at `path` is not in the DB, or its stored
+/// modified timestamp differs from `modified`.
+pub fn should_update_file(conn: &Connection, path: &Path, modified: SystemTime) -> Result<bool> {
+    let actual = modified.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+    match get_file(conn, path)? {
+        None => Ok(true),
+        Some(rec) => Ok(actual != rec.modified),
+    }
+}
+
+/// Map a `SELECT path, hash, size, modified, partial_hash, device, inode,
+/// via_link, nlink, uid, gid, mode` row.
+fn file_record(row: &rusqlite::Row) -> rusqlite::Result<FileRecord> {
+    let stat = match (row.get(8)?, row.get(9)?, row.get(10)?, row.get(11)?) {
+        (Some(nlink), Some(uid), Some(gid), Some(mode)) => {
+            Some(utils::FileStat { nlink, uid, gid, mode })
+        }
+        _ => None,
+    };
+    Ok(FileRecord {
+        path: row.get(0)?,
+        hash: row.get(1)?,
+        size: row.get(2)?,
+        modified: row.get(3)?,
+        partial_hash: row.get(4)?,
+        device: row.get(5)?,
+        inode: row.get(6)?,
+        via_link: row.get(7)?,
+        stat,
+    })
+}
+
+/// Fetch a single file record by path; returns `None` if not found.
+pub fn get_file(conn: &Connection, path: &Path) -> Result<Option<FileRecord>> {
+    let path_str = utils::path_to_db(path);
+    let result = conn
+        .prepare_cached(
+            "SELECT path, hash, size, modified, partial_hash, device, inode, via_link,
+                nlink, uid, gid, mode FROM files WHERE path = ?1",
+        )?
+        .query_row(params![path_str], file_record);
+
+    match result {
+        Ok(file) => Ok(Some(file)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Return all file records, ordered by path.
+pub fn all_files(conn: &Connection) -> Result<Vec<FileRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT path, hash, size, modified, partial_hash, device, inode, via_link,
+            nlink, uid, gid, mode FROM files ORDER BY path",
+    )?;
     let rows = stmt
-        .query_map([], |row| row.get::<_, String>(0))?
+        .query_map([], file_record)?
         .collect::<rusqlite::Result<_>>()?;
     Ok(rows)
 }
 
-/// Return the immediate child directories of `dir_path` stored in the DB.
-/// "Immediate" means exactly one level deeper — no grandchildren.
-pub fn child_directories(conn: &Connection, dir_path: &Path) -> Result<Vec<DirRecord>> {
-    let path_str = utils::path_to_str(dir_path)?;
+/// Number of file rows and the sum of their sizes.
+pub fn file_totals(conn: &Connection) -> Result<(i64, i64)> {
+    let totals = conn.query_row(
+        "SELECT COUNT(*), COALESCE(SUM(size), 0) FROM files",
+        [],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    Ok(totals)
+}
+
+/// Return all file records with the given hash, ordered by path.
+pub fn files_with_hash(conn: &Connection, hash: &str) -> Result<Vec<FileRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT path, hash, size, modified, partial_hash, device, inode, via_link,
+            nlink, uid, gid, mode FROM files WHERE hash = ?1 ORDER BY path",
+    )?;
+    let rows = stmt
+        .query_map(params![hash], file_record)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// Return all file records with the given size, ordered by path.
+pub fn files_with_size(conn: &Connection, size: i64) -> Result<Vec<FileRecord>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT path, hash, size, modified, partial_hash, device, inode, via_link,
+            nlink, uid, gid, mode FROM files WHERE size = ?1 ORDER BY path",
+    )?;
+    let rows = stmt
+        .query_map(params![size], file_record)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// Return the file records directly inside `dir_path` (no deeper descendants).
+pub fn files_in_directory(conn: &Connection, dir_path: &Path) -> Result<Vec<FileRecord>> {
+    let path_str = utils::path_to_db(dir_path);
     let sep = std::path::MAIN_SEPARATOR;
     let bare_path = path_str.trim_end_matches(sep);
     let child_pattern = format!("{bare_path}{sep}%");
     let grandchild_pattern = format!("{bare_path}{sep}%{sep}%");
     let mut stmt = conn.prepare(
-        "SELECT path, hash, size FROM directories
-            WHERE path LIKE ?1
-            AND path NOT LIKE ?2",
+        "SELECT path, hash, size, modified, partial_hash, device, inode, via_link,
+            nlink, uid, gid, mode
+            FROM files WHERE path LIKE ?1
+            AND path NOT LIKE ?2
+            ORDER BY path",
     )?;
     let rows = stmt
-        .query_map(params![child_pattern, grandchild_pattern], |row| {
-            Ok(DirRecord {
-                path: row.get(0)?,
-                hash: row.get(1)?,
-                size: row.get(2)?,
-            })
-        })?
+        .query_map(params![child_pattern, grandchild_pattern], file_record)?
         .collect::<rusqlite::Result<Vec<_>>>()?;
     Ok(rows)
 }
 
-/// Return all directory records with the given hash, ordered by path.
-pub fn directories_with_hash(conn: &Connection, hash: &str) -> Result<Vec<DirRecord>> {
-    let mut stmt =
-        conn.prepare("SELECT path, hash, size FROM directories WHERE hash = ?1 ORDER BY path")?;
+/// Return the file records anywhere below `root`, ordered by path.
+pub fn files_under(conn: &Connection, root: &Path) -> Result<Vec<FileRecord>> {
+    let path_str = utils::path_to_db(root);
+    let sep = std::path::MAIN_SEPARATOR;
+    let subtree_pattern = format!("{}{sep}%", path_str.trim_end_matches(sep));
+    let mut stmt = conn.prepare(
+        "SELECT path, hash, size, modified, partial_hash, device, inode, via_link,
+            nlink, uid, gid, mode
+            FROM files WHERE path LIKE ?1
+            ORDER BY path",
+    )?;
     let rows = stmt
-        .query_map(params![hash], |row| {
-            Ok(DirRecord {
-                path: row.get(0)?,
-                hash: row.get(1)?,
-                size: row.get(2)?,
-            })
-        })?
+        .query_map(params![subtree_pattern], file_record)?
         .collect::<rusqlite::Result<Vec<_>>>()?;
     Ok(rows)
 }
 
-/// Return groups of directories that share the same non-empty hash (i.e. duplicates).
-/// Each item is `(hash, count, max_size_bytes)`, sorted by max_size descending.
-pub fn duplicate_directory_groups(conn: &Connection) -> Result<Vec<DuplicateGroupHash>> {
+/// Up to `limit` file records, ordered by path, whose path contains
+/// `path_part` and whose hash starts with `hash_prefix` (either may be left
+/// out), e.g. the 16 digits a report shows.
+pub fn search_files(
+    conn: &Connection,
+    path_part: Option<&str>,
+    hash_prefix: Option<&str>,
+    limit: usize,
+) -> Result<Vec<FileRecord>> {
     let mut stmt = conn.prepare(
-        "SELECT hash, COUNT(*) AS cnt, MAX(size) AS max_size
-            FROM directories
-            WHERE hash != ?1
+        "SELECT path, hash, size, modified, partial_hash, device, inode, via_link,
+            nlink, uid, gid, mode
+            FROM files
+            WHERE (?1 IS NULL OR instr(path, ?1) > 0)
+            AND (?2 IS NULL OR substr(hash, 1, length(?2)) = lower(?2))
+            ORDER BY path LIMIT ?3",
+    )?;
+    let rows = stmt
+        .query_map(params![path_part, hash_prefix, limit as i64], file_record)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// Return groups of files that share the same hash (i.e. duplicates).
+/// Each item is `(hash, count, total_size_bytes)`, sorted by total_size descending.
+pub fn duplicate_file_groups(conn: &Connection) -> Result<Vec<DuplicateGroupHash>> {
+    let mut stmt = conn.prepare(
+        "SELECT hash, COUNT(*) AS cnt, SUM(size) AS total_size
+            FROM files
             GROUP BY hash
             HAVING cnt > 1
-            ORDER BY max_size DESC",
+            ORDER BY total_size DESC",
     )?;
     let rows = stmt
-        .query_map(params![EMPTY_DIR_HASH], |row| {
+        .query_map([], |row| {
             Ok(DuplicateGroupHash {
                 hash: row.get(0)?,
                 count: row.get(1)?,
@@ -360,81 +896,1945 @@ pub fn duplicate_directory_groups(conn: &Connection) -> Result<Vec<DuplicateGrou
     Ok(rows)
 }
 
-/// Insert or replace a directory record.
-pub fn upsert_directory(conn: &Connection, path: &Path, hash: &str, size: i64) -> Result<()> {
-    let path_str = utils::path_to_str(path)?;
+/// Insert or replace a file record.
+pub fn upsert_file(
+    conn: &Connection,
+    path: &Path,
+    hash: &str,
+    size: i64,
+    modified: i64,
+) -> Result<()> {
+    let path_str = utils::path_to_db(path);
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO files (path, hash, size, modified) VALUES (?1, ?2, ?3, ?4)",
+    )?
+    .execute(params![path_str, hash, size, modified])?;
+    Ok(())
+}
+
+/// Rename a file record from `old_path` to `new_path`.
+pub fn move_file(conn: &Connection, old_path: &Path, new_path: &Path) -> Result<()> {
+    let old = utils::path_to_db(old_path);
+    let new = utils::path_to_db(new_path);
+
+    // By the time this is called, the filesystem move has already succeeded, so
+    // resolve_dest already confirmed `new_path` was free on disk. Any existing DB
+    // entry at this path is therefore a ghost (e.g. a sync client renamed the file
+    // mid-run). Remove it so the UPDATE below doesn't hit a PK conflict.
+    let removed = conn.execute("DELETE FROM files WHERE path = ?1", params![new])?;
+    if removed > 0 {
+        tracing::warn!(
+            "removed ghost DB entry for {} (file was renamed by another process mid-run)",
+            new_path.display()
+        );
+    }
+
     conn.execute(
-        "INSERT OR REPLACE INTO directories (path, hash, size) VALUES (?1, ?2, ?3)",
-        params![path_str, hash, size],
+        "UPDATE files SET path = ?1 WHERE path = ?2",
+        params![new, old],
+    )?;
+    Ok(())
+}
+
+/// Repoint the file and directory records at and below `old_path` to the
+/// same places below `new_path`, as after the directory was renamed. Records
+/// already below `new_path` are ghosts, as in `move_file`, and are dropped.
+pub fn move_tree(conn: &Connection, old_path: &Path, new_path: &Path) -> Result<()> {
+    let sep = std::path::MAIN_SEPARATOR;
+    let old = utils::path_to_db(old_path);
+    let old = old.trim_end_matches(sep);
+    let new = utils::path_to_db(new_path);
+    let new = new.trim_end_matches(sep);
+    let subtree_pattern = format!("{old}{sep}%");
+
+    remove_tree(conn, new_path)?;
+    // Archive members name their archive by path too
+    for (table, column) in [("files", "path"), ("files", "archive"), ("directories", "path")] {
+        conn.execute(
+            &format!(
+                "UPDATE {table} SET {column} = ?2 || substr({column}, length(?1) + 1)
+                    WHERE {column} = ?1 OR {column} LIKE ?3"
+            ),
+            params![old, new, subtree_pattern],
+        )?;
+    }
+    Ok(())
+}
+
+/// Delete a single file record.
+pub fn remove_file(conn: &Connection, path: &Path) -> Result<()> {
+    let path_str = utils::path_to_db(path);
+    conn.execute("DELETE FROM files WHERE path = ?1", params![path_str])?;
+    Ok(())
+}
+
+/// Make the record for `to` a copy of the one for `from` under the new path,
+/// as after `to` became a hardlink to `from`. Does nothing if `from` has no
+/// record.
+pub fn copy_file_record(conn: &Connection, from: &Path, to: &Path) -> Result<()> {
+    let from_str = utils::path_to_db(from);
+    let to_str = utils::path_to_db(to);
+    conn.execute(
+        "INSERT OR REPLACE INTO files
+            (path, hash, size, modified, partial_hash, device, inode, nlink, uid, gid, mode)
+            SELECT ?2, hash, size, modified, partial_hash, device, inode, nlink, uid, gid, mode
+            FROM files WHERE path = ?1",
+        params![from_str, to_str],
     )?;
     Ok(())
 }
 
-/// Delete all file records and all directory records whose path starts with
-/// `path` (inclusive). Use this for bulk removal of an entire directory tree.
-pub fn remove_tree(conn: &Connection, path: &Path) -> Result<()> {
-    let path_str = utils::path_to_str(path)?;
-    let sep = std::path::MAIN_SEPARATOR;
-    let bare_path = path_str.trim_end_matches(sep);
-    // Match the root itself OR anything directly under it, but not siblings
-    // (e.g. /photos/12 must not match /photos/123).
-    let subtree_pattern = format!("{}{sep}%", bare_path);
-    conn.execute(
-        "DELETE FROM files WHERE path = ?1 OR path LIKE ?2",
-        params![bare_path, subtree_pattern],
-    )?;
-    conn.execute(
-        "DELETE FROM directories WHERE path = ?1 OR path LIKE ?2",
-        params![bare_path, subtree_pattern],
-    )?;
-    Ok(())
-}
+/// Update only the hash of an existing file record.
+pub fn update_file_hash(conn: &Connection, path: &Path, hash: &str) -> Result<()> {
+    let path_str = utils::path_to_db(path);
+    conn.prepare_cached("UPDATE files SET hash = ?1 WHERE path = ?2")?
+        .execute(params![hash, path_str])?;
+    Ok(())
+}
+
+/// Record the partial hash of an existing file record.
+pub fn update_partial_hash(conn: &Connection, path: &Path, partial_hash: &str) -> Result<()> {
+    let path_str = utils::path_to_db(path);
+    conn.prepare_cached("UPDATE files SET partial_hash = ?1 WHERE path = ?2")?
+        .execute(params![partial_hash, path_str])?;
+    Ok(())
+}
+
+/// Record the device and inode numbers of an existing file record.
+pub fn update_file_identity(conn: &Connection, path: &Path, device: i64, inode: i64) -> Result<()> {
+    let path_str = utils::path_to_db(path);
+    conn.prepare_cached("UPDATE files SET device = ?1, inode = ?2 WHERE path = ?3")?
+        .execute(params![device, inode, path_str])?;
+    Ok(())
+}
+
+/// Record the link count, owner and permissions of an existing file record.
+pub fn update_file_stat(conn: &Connection, path: &Path, stat: &utils::FileStat) -> Result<()> {
+    let path_str = utils::path_to_db(path);
+    conn.prepare_cached(
+        "UPDATE files SET nlink = ?1, uid = ?2, gid = ?3, mode = ?4 WHERE path = ?5",
+    )?
+    .execute(params![stat.nlink, stat.uid, stat.gid, stat.mode, path_str])?;
+    Ok(())
+}
+
+/// Record whether the scan reached an existing file record through a symlink.
+pub fn update_file_via_link(conn: &Connection, path: &Path, via_link: bool) -> Result<()> {
+    let path_str = utils::path_to_db(path);
+    conn.prepare_cached("UPDATE files SET via_link = ?1 WHERE path = ?2")?
+        .execute(params![via_link, path_str])?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Stale-file tracking  (requires the `visited_files` temp table)
+// ---------------------------------------------------------------------------
+
+/// Create (if absent) and clear the `visited_files` temp table.
+/// Call once at the start of each scan.
+pub fn init_visited_files(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TEMP TABLE IF NOT EXISTS visited_files (path TEXT PRIMARY KEY);
+         DELETE FROM visited_files;",
+    )?;
+    Ok(())
+}
+
+/// Record that `path` was seen during the current scan.
+pub fn mark_visited(conn: &Connection, path: &str) -> Result<()> {
+    conn.prepare_cached("INSERT OR IGNORE INTO visited_files (path) VALUES (?1)")?
+        .execute(params![path])?;
+    Ok(())
+}
+
+/// The files in the DB under `root_prefix` that were not seen in this scan,
+/// ordered by path. `root_prefix` should be the root path string (no trailing
+/// slash needed).
+pub fn stale_file_paths(conn: &Connection, root_prefix: &str) -> Result<Vec<String>> {
+    let sep = std::path::MAIN_SEPARATOR;
+    let pattern = format!("{}{}%", root_prefix.trim_end_matches(sep), sep);
+    let mut stmt = conn.prepare(
+        "SELECT files.path FROM files
+            LEFT JOIN visited_files ON files.path = visited_files.path
+            WHERE files.path LIKE ?1 AND visited_files.path IS NULL
+            ORDER BY files.path",
+    )?;
+    let rows = stmt
+        .query_map(params![pattern], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Delete all files under `root_prefix` that were not seen in this scan.
+pub fn delete_stale_files(conn: &Connection, root_prefix: &str) -> Result<()> {
+    let sep = std::path::MAIN_SEPARATOR;
+    let pattern = format!("{}{}%", root_prefix.trim_end_matches(sep), sep);
+    conn.execute(
+        "DELETE FROM files WHERE path LIKE ?1 AND path IN (
+            SELECT files.path FROM files
+            LEFT JOIN visited_files ON files.path = visited_files.path
+            WHERE files.path LIKE ?1 AND visited_files.path IS NULL
+        )",
+        params![pattern],
+    )?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Archive members  (rows of the `files` table with an `archive`)
+// ---------------------------------------------------------------------------
+
+/// Store a file read out of the archive stored as `archive` under `path`
+/// (see `archive::member_path`). Members get no partial hash, so they are
+/// never taken for a file that moved.
+pub fn insert_archive_member(
+    conn: &Connection,
+    path: &str,
+    archive: &str,
+    hash: &str,
+    size: i64,
+    modified: i64,
+) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO files (path, hash, size, modified, archive)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?
+    .execute(params![path, hash, size, modified, archive])?;
+    Ok(())
+}
+
+/// Drop the members of the archive stored as `archive`, once it has changed.
+/// Returns how many.
+pub fn delete_archive_members(conn: &Connection, archive: &str) -> Result<usize> {
+    let removed = conn
+        .prepare_cached("DELETE FROM files WHERE archive = ?1")?
+        .execute(params![archive])?;
+    Ok(removed)
+}
+
+/// Mark the members of the archive stored as `archive` seen in the current
+/// scan, when the archive itself is unchanged. Returns how many it has.
+pub fn mark_members_visited(conn: &Connection, archive: &str) -> Result<usize> {
+    let count: i64 = conn
+        .prepare_cached("SELECT COUNT(*) FROM files WHERE archive = ?1")?
+        .query_row(params![archive], |row| row.get(0))?;
+    if count > 0 {
+        conn.prepare_cached(
+            "INSERT OR IGNORE INTO visited_files (path)
+             SELECT path FROM files WHERE archive = ?1",
+        )?
+        .execute(params![archive])?;
+    }
+    Ok(count as usize)
+}
+
+/// The paths of every archive member, with the archive each one is in.
+pub fn archive_members(conn: &Connection) -> Result<HashMap<String, String>> {
+    let mut stmt = conn.prepare("SELECT path, archive FROM files WHERE archive IS NOT NULL")?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// The paths of the file rows that aren't files on this machine's disk:
+/// rows merged in from another database, the members of archives, and files
+/// listed on another machine or in a bucket. Commands that read, change or
+/// check files leave them alone.
+pub fn files_off_disk(conn: &Connection) -> Result<HashSet<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT path FROM files
+            WHERE source IS NOT NULL OR archive IS NOT NULL OR remote IS NOT NULL",
+    )?;
+    let rows = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+// ---------------------------------------------------------------------------
+// Remote rows  (the `remote` columns of `files` and `directories`)
+// ---------------------------------------------------------------------------
+
+// Rows listed on another machine (`ssh://nas`) or in a bucket (`s3://photos`)
+// record which in `remote`. Their paths aren't paths on this disk, so `clean`
+// keeps them and nothing reads, links or deletes them.
+
+/// Store the file at `path`, listed on `remote`. Like `upsert_file`, it
+/// replaces the row, and drops any partial hash.
+pub fn upsert_remote_file(
+    conn: &Connection,
+    path: &Path,
+    remote: &str,
+    hash: &str,
+    size: i64,
+    modified: i64,
+) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO files (path, hash, size, modified, remote)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?
+    .execute(params![utils::path_to_db(path), hash, size, modified, remote])?;
+    Ok(())
+}
+
+/// Record `remote` for the root `root` and every file and directory row
+/// below it, once a scan of it has stored their hashes.
+pub fn tag_remote(conn: &Connection, root: &str, remote: &str) -> Result<()> {
+    let root = root.trim_end_matches('/');
+    let pattern = format!("{root}/%");
+    conn.execute(
+        "UPDATE files SET remote = ?1 WHERE path LIKE ?2",
+        params![remote, pattern],
+    )?;
+    conn.execute(
+        "UPDATE directories SET remote = ?1 WHERE path = ?2 OR path LIKE ?3",
+        params![remote, root, pattern],
+    )?;
+    Ok(())
+}
+
+/// The paths of every remote file and directory row.
+pub fn remote_paths(conn: &Connection) -> Result<HashSet<String>> {
+    let mut stmt = conn.prepare(
+        "SELECT path FROM files WHERE remote IS NOT NULL
+            UNION ALL
+            SELECT path FROM directories WHERE remote IS NOT NULL",
+    )?;
+    let rows = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+// ---------------------------------------------------------------------------
+// Scan session checkpoints  (the `scan_state` table)
+// ---------------------------------------------------------------------------
+
+/// Forget any previous (possibly interrupted) scan session.
+pub fn clear_scan_state(conn: &Connection) -> Result<()> {
+    conn.execute("DELETE FROM scan_state", [])?;
+    Ok(())
+}
+
+/// Add `roots` to the current session; roots already recorded keep their status.
+pub fn add_scan_roots(conn: &Connection, roots: &[&str]) -> Result<()> {
+    for root in roots {
+        conn.execute(
+            "INSERT OR IGNORE INTO scan_state (root) VALUES (?1)",
+            params![root],
+        )?;
+    }
+    Ok(())
+}
+
+/// Record that `root` was scanned to the end in the current session.
+pub fn mark_scan_root_complete(conn: &Connection, root: &str) -> Result<()> {
+    conn.execute(
+        "UPDATE scan_state SET completed = 1 WHERE root = ?1",
+        params![root],
+    )?;
+    Ok(())
+}
+
+/// Return the roots the current session has already finished, ordered by path.
+pub fn completed_scan_roots(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT root FROM scan_state WHERE completed = 1 ORDER BY root")?;
+    let rows = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+// ---------------------------------------------------------------------------
+// Directory records
+// ---------------------------------------------------------------------------
+
+/// Return every directory record, ordered by path.
+pub fn all_directories(conn: &Connection) -> Result<Vec<DirRecord>> {
+    let mut stmt = conn.prepare("SELECT path, hash, size FROM directories ORDER BY path")?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(DirRecord {
+                path: row.get(0)?,
+                hash: row.get(1)?,
+                size: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Return all directory paths, ordered by path.
+/// Used by similar.rs to load the full directory set in one query.
+pub fn all_directory_paths(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT path FROM directories ORDER BY path")?;
+    let rows = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Return the immediate child directories of `dir_path` stored in the DB.
+/// "Immediate" means exactly one level deeper — no grandchildren.
+pub fn child_directories(conn: &Connection, dir_path: &Path) -> Result<Vec<DirRecord>> {
+    let path_str = utils::path_to_db(dir_path);
+    let sep = std::path::MAIN_SEPARATOR;
+    let bare_path = path_str.trim_end_matches(sep);
+    let child_pattern = format!("{bare_path}{sep}%");
+    let grandchild_pattern = format!("{bare_path}{sep}%{sep}%");
+    let mut stmt = conn.prepare(
+        "SELECT path, hash, size FROM directories
+            WHERE path LIKE ?1
+            AND path NOT LIKE ?2",
+    )?;
+    let rows = stmt
+        .query_map(params![child_pattern, grandchild_pattern], |row| {
+            Ok(DirRecord {
+                path: row.get(0)?,
+                hash: row.get(1)?,
+                size: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// Fetch a single directory record by path; returns `None` if not found.
+pub fn get_directory(conn: &Connection, path: &Path) -> Result<Option<DirRecord>> {
+    let path_str = utils::path_to_db(path);
+    let result = conn
+        .prepare_cached("SELECT path, hash, size FROM directories WHERE path = ?1")?
+        .query_row(params![path_str], |row| {
+            Ok(DirRecord {
+                path: row.get(0)?,
+                hash: row.get(1)?,
+                size: row.get(2)?,
+            })
+        });
+
+    match result {
+        Ok(dir) => Ok(Some(dir)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Return the records of `root` and every directory below it, ordered by path.
+pub fn directories_under(conn: &Connection, root: &Path) -> Result<Vec<DirRecord>> {
+    let path_str = utils::path_to_db(root);
+    let sep = std::path::MAIN_SEPARATOR;
+    let bare_path = path_str.trim_end_matches(sep);
+    let subtree_pattern = format!("{bare_path}{sep}%");
+    let mut stmt = conn.prepare(
+        "SELECT path, hash, size FROM directories
+            WHERE path = ?1 OR path LIKE ?2
+            ORDER BY path",
+    )?;
+    let rows = stmt
+        .query_map(params![bare_path, subtree_pattern], |row| {
+            Ok(DirRecord {
+                path: row.get(0)?,
+                hash: row.get(1)?,
+                size: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// Return all directory records with the given hash, ordered by path.
+pub fn directories_with_hash(conn: &Connection, hash: &str) -> Result<Vec<DirRecord>> {
+    let mut stmt =
+        conn.prepare("SELECT path, hash, size FROM directories WHERE hash = ?1 ORDER BY path")?;
+    let rows = stmt
+        .query_map(params![hash], |row| {
+            Ok(DirRecord {
+                path: row.get(0)?,
+                hash: row.get(1)?,
+                size: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// Return groups of directories that share the same non-empty hash (i.e. duplicates).
+/// Each item is `(hash, count, max_size_bytes)`, sorted by max_size descending.
+pub fn duplicate_directory_groups(conn: &Connection) -> Result<Vec<DuplicateGroupHash>> {
+    // Every algorithm's empty hash, so the filter holds whichever one the DB uses
+    let empty_hashes: Vec<String> = <hashing::HashAlgorithm as clap::ValueEnum>::value_variants()
+        .iter()
+        .map(|a| a.empty_hash())
+        .collect();
+    let placeholders = vec!["?"; empty_hashes.len()].join(", ");
+    let mut stmt = conn.prepare(&format!(
+        "SELECT hash, COUNT(*) AS cnt, MAX(size) AS max_size
+            FROM directories
+            WHERE hash NOT IN ({placeholders})
+            GROUP BY hash
+            HAVING cnt > 1
+            ORDER BY max_size DESC"
+    ))?;
+    let rows = stmt
+        .query_map(rusqlite::params_from_iter(&empty_hashes), |row| {
+            Ok(DuplicateGroupHash {
+                hash: row.get(0)?,
+                count: row.get(1)?,
+                size: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// Insert or replace a directory record.
+pub fn upsert_directory(conn: &Connection, path: &Path, hash: &str, size: i64) -> Result<()> {
+    let path_str = utils::path_to_db(path);
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO directories (path, hash, size) VALUES (?1, ?2, ?3)",
+    )?
+    .execute(params![path_str, hash, size])?;
+    Ok(())
+}
+
+/// Delete a single directory record (its contents are left alone).
+pub fn remove_directory(conn: &Connection, path: &Path) -> Result<()> {
+    let path_str = utils::path_to_db(path);
+    conn.execute("DELETE FROM directories WHERE path = ?1", params![path_str])?;
+    Ok(())
+}
+
+/// Delete all file records and all directory records whose path starts with
+/// `path` (inclusive). Use this for bulk removal of an entire directory tree.
+pub fn remove_tree(conn: &Connection, path: &Path) -> Result<()> {
+    let path_str = utils::path_to_db(path);
+    let sep = std::path::MAIN_SEPARATOR;
+    let bare_path = path_str.trim_end_matches(sep);
+    // Match the root itself OR anything directly under it, but not siblings
+    // (e.g. /photos/12 must not match /photos/123).
+    let subtree_pattern = format!("{}{sep}%", bare_path);
+    conn.execute(
+        "DELETE FROM files WHERE path = ?1 OR path LIKE ?2",
+        params![bare_path, subtree_pattern],
+    )?;
+    conn.execute(
+        "DELETE FROM directories WHERE path = ?1 OR path LIKE ?2",
+        params![bare_path, subtree_pattern],
+    )?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Dedupe log  (the `dedupe_log` table)
+// ---------------------------------------------------------------------------
+
+/// Append a decision to the dedupe log.
+pub fn log_dedupe(conn: &Connection, entry: &DedupeLogEntry) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO dedupe_log (time, hash, path, action, keeper, reason)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?
+    .execute(params![
+        entry.time,
+        entry.hash,
+        entry.path,
+        entry.action,
+        entry.keeper,
+        entry.reason
+    ])?;
+    Ok(())
+}
+
+/// Return the whole dedupe log, oldest first.
+#[cfg(test)]
+pub fn dedupe_log(conn: &Connection) -> Result<Vec<DedupeLogEntry>> {
+    let mut stmt = conn.prepare(
+        "SELECT time, hash, path, action, keeper, reason FROM dedupe_log ORDER BY id",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(DedupeLogEntry {
+                time: row.get(0)?,
+                hash: row.get(1)?,
+                path: row.get(2)?,
+                action: row.get(3)?,
+                keeper: row.get(4)?,
+                reason: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+// ---------------------------------------------------------------------------
+// Actions  (the `actions` table)
+// ---------------------------------------------------------------------------
+
+/// Record that `action` was done to `source`, now.
+pub fn log_action(
+    conn: &Connection,
+    action: &str,
+    source: &Path,
+    destination: Option<&Path>,
+    hash: Option<&str>,
+) -> Result<()> {
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+    let destination = destination.map(utils::path_to_db);
+    conn.prepare_cached(
+        "INSERT INTO actions (time, action, source, destination, hash)
+            VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?
+    .execute(params![time, action, utils::path_to_db(source), destination, hash])?;
+    Ok(())
+}
+
+/// The actions not yet undone, newest first: the latest `last` of them, or
+/// those since Unix time `since`, or both limits together.
+pub fn pending_actions(
+    conn: &Connection,
+    last: Option<usize>,
+    since: Option<i64>,
+) -> Result<Vec<ActionRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, time, action, source, destination, hash FROM actions
+            WHERE undone IS NULL AND time >= ?1
+            ORDER BY id DESC LIMIT ?2",
+    )?;
+    let limit = last.map_or(-1, |n| n as i64);
+    let rows = stmt
+        .query_map(params![since.unwrap_or(i64::MIN), limit], |row| {
+            Ok(ActionRecord {
+                id: row.get(0)?,
+                time: row.get(1)?,
+                action: row.get(2)?,
+                source: row.get(3)?,
+                destination: row.get(4)?,
+                hash: row.get(5)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+/// Mark an action as undone, so `pending_actions` no longer returns it.
+pub fn mark_undone(conn: &Connection, id: i64) -> Result<()> {
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+    conn.execute("UPDATE actions SET undone = ?1 WHERE id = ?2", params![time, id])?;
+    Ok(())
+}
+
+/// Any recorded file with content `hash` that is still on disk, to restore a
+/// deleted copy from.
+pub fn existing_file_with_hash(conn: &Connection, hash: &str) -> Result<Option<String>> {
+    let mut stmt = conn.prepare_cached("SELECT path FROM files WHERE hash = ?1 ORDER BY path")?;
+    let paths = stmt
+        .query_map(params![hash], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(paths.into_iter().find(|p| utils::path_from_db(p).is_file()))
+}
+
+// ---------------------------------------------------------------------------
+// Scan errors  (the `errors` table)
+// ---------------------------------------------------------------------------
+
+/// Forget the errors earlier scans of `root` recorded, as a new scan of it
+/// starts; those still failing are recorded again.
+pub fn clear_scan_errors(conn: &Connection, root: &str) -> Result<()> {
+    conn.execute("DELETE FROM errors WHERE root = ?1", params![root])?;
+    Ok(())
+}
+
+/// Record that the scan of `root` begun at `time` skipped `path`.
+pub fn log_scan_error(
+    conn: &Connection,
+    time: i64,
+    root: &str,
+    path: &str,
+    kind: &str,
+    message: &str,
+) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT INTO errors (time, root, path, kind, message) VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?
+    .execute(params![time, root, path, kind, message])?;
+    Ok(())
+}
+
+/// Every recorded scan error, latest scan first, then by path.
+pub fn scan_errors(conn: &Connection) -> Result<Vec<ErrorRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT time, root, path, kind, message FROM errors ORDER BY time DESC, path",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(ErrorRecord {
+                time: row.get(0)?,
+                root: row.get(1)?,
+                path: row.get(2)?,
+                kind: row.get(3)?,
+                message: row.get(4)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+// ---------------------------------------------------------------------------
+// Scan history  (the `scans` and `file_history` tables)
+// ---------------------------------------------------------------------------
+
+// `file_history` only holds changes: a file's row for the latest scan up to
+// some point says what it looked like then, or that it was gone ('removed').
+
+/// The latest history row of each path under ?2, as `latest`.
+const LATEST_UNDER_ROOT: &str = "WITH latest AS (
+        SELECT h.path, h.hash, h.size, h.event FROM file_history h
+        WHERE h.path LIKE ?2
+        AND h.id = (SELECT MAX(id) FROM file_history WHERE path = h.path)
+    )";
+
+/// Start a scan session of `roots` at `time`, returning its id.
+pub fn begin_scan(conn: &Connection, time: i64, roots: &[&str]) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO scans (time, roots) VALUES (?1, ?2)",
+        params![time, serde_json::to_string(roots)?],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Bring `file_history` up to date with the files under `root_prefix` after
+/// scan `scan_id` walked it to the end: visited files that are new or whose
+/// content changed, and files last seen under it that weren't visited, are
+/// recorded against the scan, and every visited row is tagged with it.
+/// Requires the `visited_files` temp table.
+pub fn record_scan_history(conn: &Connection, scan_id: i64, root_prefix: &str) -> Result<()> {
+    let sep = std::path::MAIN_SEPARATOR;
+    let pattern = format!("{}{}%", root_prefix.trim_end_matches(sep), sep);
+    conn.execute(
+        &format!(
+            "{LATEST_UNDER_ROOT}
+            INSERT INTO file_history (scan_id, path, hash, size, event)
+            SELECT ?1, f.path, f.hash, f.size,
+                CASE WHEN l.path IS NULL OR l.event = 'removed' THEN 'added' ELSE 'changed' END
+            FROM files f
+            JOIN visited_files v ON v.path = f.path
+            LEFT JOIN latest l ON l.path = f.path
+            WHERE f.path LIKE ?2
+            AND (l.path IS NULL OR l.event = 'removed' OR l.hash != f.hash OR l.size != f.size)"
+        ),
+        params![scan_id, pattern],
+    )?;
+    conn.execute(
+        &format!(
+            "{LATEST_UNDER_ROOT}
+            INSERT INTO file_history (scan_id, path, hash, size, event)
+            SELECT ?1, path, hash, size, 'removed' FROM latest
+            WHERE event != 'removed' AND path NOT IN (SELECT path FROM visited_files)"
+        ),
+        params![scan_id, pattern],
+    )?;
+    conn.execute(
+        "UPDATE files SET last_scan = ?1
+            WHERE path LIKE ?2 AND path IN (SELECT path FROM visited_files)",
+        params![scan_id, pattern],
+    )?;
+    Ok(())
+}
+
+/// Every recorded scan, oldest first.
+pub fn scans(conn: &Connection) -> Result<Vec<ScanRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT s.id, s.time, s.roots,
+            COUNT(CASE WHEN h.event = 'added' THEN 1 END),
+            COUNT(CASE WHEN h.event = 'changed' THEN 1 END),
+            COUNT(CASE WHEN h.event = 'removed' THEN 1 END)
+        FROM scans s
+        LEFT JOIN file_history h ON h.scan_id = s.id
+        GROUP BY s.id
+        ORDER BY s.id",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, i64>(4)?,
+                row.get::<_, i64>(5)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    rows.into_iter()
+        .map(|(id, time, roots, added, changed, removed)| {
+            Ok(ScanRecord {
+                id,
+                time,
+                roots: serde_json::from_str(&roots)?,
+                added,
+                changed,
+                removed,
+            })
+        })
+        .collect()
+}
+
+/// Record that `root` was scanned with `options`, the scan's command-line
+/// options as words (`--exclude=*.tmp`), replacing what it had before.
+pub fn set_root_options(conn: &Connection, root: &str, options: &[String]) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO root_options (root, options) VALUES (?1, ?2)",
+        params![root, serde_json::to_string(options)?],
+    )?;
+    Ok(())
+}
+
+/// The options recorded for the closest root at or above `path`, if a scan
+/// of one recorded any.
+pub fn root_options(conn: &Connection, path: &Path) -> Result<Option<Vec<String>>> {
+    let mut stmt = conn.prepare("SELECT root, options FROM root_options")?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let closest = rows
+        .into_iter()
+        .filter(|(root, _)| path.starts_with(utils::path_from_db(root)))
+        .max_by_key(|(root, _)| root.len());
+    match closest {
+        Some((_, options)) => Ok(Some(serde_json::from_str(&options)?)),
+        None => Ok(None),
+    }
+}
+
+/// The files as they were after scan `scan_id`, ordered by path: every path
+/// whose latest history row up to that scan doesn't say it was removed.
+pub fn files_at_scan(conn: &Connection, scan_id: i64) -> Result<Vec<HistoryFile>> {
+    let mut stmt = conn.prepare(
+        "SELECT h.path, h.hash, h.size FROM file_history h
+            WHERE h.id = (
+                SELECT MAX(id) FROM file_history WHERE path = h.path AND scan_id <= ?1
+            )
+            AND h.event != 'removed'
+            ORDER BY h.path",
+    )?;
+    let rows = stmt
+        .query_map(params![scan_id], |row| {
+            Ok(HistoryFile {
+                path: row.get(0)?,
+                hash: row.get(1)?,
+                size: row.get(2)?,
+            })
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rows)
+}
+
+// ---------------------------------------------------------------------------
+// Merged databases  (the `source` columns of `files` and `directories`)
+// ---------------------------------------------------------------------------
+
+// Rows scanned into this database have no source. Rows copied in by
+// `merge-db` name the database they came from; their paths belong to another
+// machine, so commands that touch the disk leave them alone.
+
+/// The paths of every merged file and directory, with the source each one
+/// came from.
+pub fn merged_sources(conn: &Connection) -> Result<HashMap<String, String>> {
+    let mut stmt = conn.prepare(
+        "SELECT path, source FROM files WHERE source IS NOT NULL
+            UNION ALL
+            SELECT path, source FROM directories WHERE source IS NOT NULL",
+    )?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Drop every row merged in from `source`, before it is merged again.
+pub fn remove_merged(conn: &Connection, source: &str) -> Result<()> {
+    conn.execute("DELETE FROM files WHERE source = ?1", params![source])?;
+    conn.execute("DELETE FROM directories WHERE source = ?1", params![source])?;
+    Ok(())
+}
+
+/// Store `file` under `path` as merged in from `source`. Device and inode
+/// numbers are dropped, since they only identify hardlinks on their own
+/// machine, and so are link counts and owners with them.
+pub fn insert_merged_file(
+    conn: &Connection,
+    path: &str,
+    file: &FileRecord,
+    source: &str,
+) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO files (path, hash, size, modified, partial_hash, via_link, source)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?
+    .execute(params![
+        path,
+        file.hash,
+        file.size,
+        file.modified,
+        file.partial_hash,
+        file.via_link,
+        source
+    ])?;
+    Ok(())
+}
+
+/// Store `dir` under `path` as merged in from `source`.
+pub fn insert_merged_directory(
+    conn: &Connection,
+    path: &str,
+    dir: &DirRecord,
+    source: &str,
+) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO directories (path, hash, size, source) VALUES (?1, ?2, ?3, ?4)",
+    )?
+    .execute(params![path, dir.hash, dir.size, source])?;
+    Ok(())
+}
+
+// ---------------------------------------------------------------------------
+// Locations  (the `host` and `volume` columns of `files` and `directories`)
+// ---------------------------------------------------------------------------
+
+/// Record `location` for the files this scan visited under `root_prefix`, and
+/// for the root and the directories below it. A scan without a label keeps
+/// the volume recorded before. Requires the `visited_files` temp table.
+pub fn tag_location(conn: &Connection, root_prefix: &str, location: &Location) -> Result<()> {
+    let sep = std::path::MAIN_SEPARATOR;
+    let root = root_prefix.trim_end_matches(sep);
+    let pattern = format!("{root}{sep}%");
+    conn.execute(
+        "UPDATE files SET host = ?1, volume = COALESCE(?2, volume)
+            WHERE path LIKE ?3 AND path IN (SELECT path FROM visited_files)",
+        params![location.host, location.volume, pattern],
+    )?;
+    conn.execute(
+        "UPDATE directories SET host = ?1, volume = COALESCE(?2, volume)
+            WHERE path = ?3 OR path LIKE ?4",
+        params![location.host, location.volume, root, pattern],
+    )?;
+    Ok(())
+}
+
+/// Set the location of the file or directory row at `path`.
+pub fn set_location(conn: &Connection, path: &str, location: &Location) -> Result<()> {
+    for table in ["files", "directories"] {
+        conn.prepare_cached(&format!(
+            "UPDATE {table} SET host = ?1, volume = ?2 WHERE path = ?3"
+        ))?
+        .execute(params![location.host, location.volume, path])?;
+    }
+    Ok(())
+}
+
+/// The recorded location of every file and directory that has one.
+pub fn locations(conn: &Connection) -> Result<HashMap<String, Location>> {
+    let mut stmt = conn.prepare(
+        "SELECT path, host, volume FROM files WHERE host IS NOT NULL OR volume IS NOT NULL
+            UNION ALL
+            SELECT path, host, volume FROM directories
+            WHERE host IS NOT NULL OR volume IS NOT NULL",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                Location {
+                    host: row.get(1)?,
+                    volume: row.get(2)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// The name reports give where each file and directory is: its volume label,
+/// else the host that scanned it, else the database it was merged from.
+pub fn location_names(conn: &Connection) -> Result<HashMap<String, String>> {
+    let mut stmt = conn.prepare(
+        "SELECT path, COALESCE(volume, host, source) AS name FROM files WHERE name IS NOT NULL
+            UNION ALL
+            SELECT path, COALESCE(volume, host, source) AS name FROM directories
+            WHERE name IS NOT NULL",
+    )?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+// ---------------------------------------------------------------------------
+// Protected paths  (the `protected` table)
+// ---------------------------------------------------------------------------
+
+/// Protect `path` and everything under it. Returns `false` if it already was.
+pub fn add_protected(conn: &Connection, path: &Path) -> Result<bool> {
+    let path_str = utils::path_to_db(path);
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO protected (path) VALUES (?1)",
+        params![path_str],
+    )?;
+    Ok(added > 0)
+}
+
+/// Stop protecting `path`. Returns `false` if it wasn't protected; a path
+/// protected through a parent stays protected.
+pub fn remove_protected(conn: &Connection, path: &Path) -> Result<bool> {
+    let path_str = utils::path_to_db(path);
+    let removed = conn.execute("DELETE FROM protected WHERE path = ?1", params![path_str])?;
+    Ok(removed > 0)
+}
+
+/// Every protected path, sorted.
+pub fn protected_paths(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("SELECT path FROM protected ORDER BY path")?;
+    let paths = stmt
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(paths)
+}
+
+// ---------------------------------------------------------------------------
+// Ignore rules  (the `ignore_rules` table)
+// ---------------------------------------------------------------------------
+
+/// Store an ignore rule of `kind` (see `ignore_rules::RuleKind`). Returns
+/// `false` if it already was.
+pub fn add_ignore_rule(conn: &Connection, kind: &str, pattern: &str) -> Result<bool> {
+    let added = conn.execute(
+        "INSERT OR IGNORE INTO ignore_rules (kind, pattern) VALUES (?1, ?2)",
+        params![kind, pattern],
+    )?;
+    Ok(added > 0)
+}
+
+/// Drop the rules for `pattern`, of `kind` or of any kind. Returns how many
+/// there were.
+pub fn remove_ignore_rule(conn: &Connection, kind: Option<&str>, pattern: &str) -> Result<usize> {
+    let removed = conn.execute(
+        "DELETE FROM ignore_rules WHERE pattern = ?1 AND (?2 IS NULL OR kind = ?2)",
+        params![pattern, kind],
+    )?;
+    Ok(removed)
+}
+
+/// Every ignore rule as `(kind, pattern)`, sorted.
+pub fn ignore_rules(conn: &Connection) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare("SELECT kind, pattern FROM ignore_rules ORDER BY kind, pattern")?;
+    let rules = stmt
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(rules)
+}
+
+// ---------------------------------------------------------------------------
+// Image hashes  (the `image_hashes` table)
+// ---------------------------------------------------------------------------
+
+/// The perceptual hash of an image file, as it was when the file had the
+/// content hash `hash`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageHashRecord {
+    pub hash: String,
+    /// `None` when the file could not be decoded
+    pub dhash: Option<i64>,
+    pub width: i64,
+    pub height: i64,
+}
+
+/// Every stored image hash, by path.
+pub fn image_hashes(conn: &Connection) -> Result<HashMap<String, ImageHashRecord>> {
+    let mut stmt = conn.prepare("SELECT path, hash, dhash, width, height FROM image_hashes")?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                ImageHashRecord {
+                    hash: row.get(1)?,
+                    dhash: row.get(2)?,
+                    width: row.get(3)?,
+                    height: row.get(4)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Store the image hash of the file stored as `path`, replacing any before.
+pub fn set_image_hash(conn: &Connection, path: &str, record: &ImageHashRecord) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO image_hashes (path, hash, dhash, width, height)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+    )?
+    .execute(params![path, record.hash, record.dhash, record.width, record.height])?;
+    Ok(())
+}
+
+/// Drop the image hashes of files no longer in the database. Returns how many.
+pub fn prune_image_hashes(conn: &Connection) -> Result<usize> {
+    let removed = conn.execute(
+        "DELETE FROM image_hashes WHERE path NOT IN (SELECT path FROM files)",
+        [],
+    )?;
+    Ok(removed)
+}
+
+// ---------------------------------------------------------------------------
+// Audio fingerprints  (the `audio_fingerprints` table)
+// ---------------------------------------------------------------------------
+
+/// The acoustic fingerprint of an audio file, as it was when the file had
+/// the content hash `hash`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioFingerprintRecord {
+    pub hash: String,
+    /// `None` when the file could not be decoded
+    pub fingerprint: Option<Vec<u32>>,
+    /// In seconds
+    pub duration: f64,
+}
+
+/// Every stored audio fingerprint, by path.
+pub fn audio_fingerprints(conn: &Connection) -> Result<HashMap<String, AudioFingerprintRecord>> {
+    let mut stmt =
+        conn.prepare("SELECT path, hash, fingerprint, duration FROM audio_fingerprints")?;
+    let rows = stmt
+        .query_map([], |row| {
+            let blob: Option<Vec<u8>> = row.get(2)?;
+            Ok((
+                row.get(0)?,
+                AudioFingerprintRecord {
+                    hash: row.get(1)?,
+                    fingerprint: blob.map(|b| {
+                        b.chunks_exact(4)
+                            .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                            .collect()
+                    }),
+                    duration: row.get(3)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Store the fingerprint of the file stored as `path`, replacing any before.
+pub fn set_audio_fingerprint(
+    conn: &Connection,
+    path: &str,
+    record: &AudioFingerprintRecord,
+) -> Result<()> {
+    let blob: Option<Vec<u8>> = record
+        .fingerprint
+        .as_ref()
+        .map(|items| items.iter().flat_map(|i| i.to_le_bytes()).collect());
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO audio_fingerprints (path, hash, fingerprint, duration)
+         VALUES (?1, ?2, ?3, ?4)",
+    )?
+    .execute(params![path, record.hash, blob, record.duration])?;
+    Ok(())
+}
+
+/// Drop the fingerprints of files no longer in the database. Returns how
+/// many.
+pub fn prune_audio_fingerprints(conn: &Connection) -> Result<usize> {
+    let removed = conn.execute(
+        "DELETE FROM audio_fingerprints WHERE path NOT IN (SELECT path FROM files)",
+        [],
+    )?;
+    Ok(removed)
+}
+
+// ---------------------------------------------------------------------------
+// Photo payloads  (the `photo_payloads` table)
+// ---------------------------------------------------------------------------
+
+/// The hash of a photo's image data without its metadata, as it was when
+/// the file had the content hash `hash`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhotoPayloadRecord {
+    pub hash: String,
+    /// `None` when the file's payload could not be found
+    pub payload: Option<String>,
+}
+
+/// Every stored payload hash, by path.
+pub fn photo_payloads(conn: &Connection) -> Result<HashMap<String, PhotoPayloadRecord>> {
+    let mut stmt = conn.prepare("SELECT path, hash, payload FROM photo_payloads")?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                PhotoPayloadRecord {
+                    hash: row.get(1)?,
+                    payload: row.get(2)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Store the payload hash of the file stored as `path`, replacing any before.
+pub fn set_photo_payload(conn: &Connection, path: &str, record: &PhotoPayloadRecord) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO photo_payloads (path, hash, payload) VALUES (?1, ?2, ?3)",
+    )?
+    .execute(params![path, record.hash, record.payload])?;
+    Ok(())
+}
+
+/// Drop the payload hashes of files no longer in the database. Returns how
+/// many.
+pub fn prune_photo_payloads(conn: &Connection) -> Result<usize> {
+    let removed = conn.execute(
+        "DELETE FROM photo_payloads WHERE path NOT IN (SELECT path FROM files)",
+        [],
+    )?;
+    Ok(removed)
+}
+
+// ---------------------------------------------------------------------------
+// Decompressed hashes  (the `decompressed_hashes` table)
+// ---------------------------------------------------------------------------
+
+/// The hash of what a compressed file decompresses to, as it was when the
+/// file had the content hash `hash`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecompressedHashRecord {
+    pub hash: String,
+    /// `None` when the file could not be decompressed
+    pub content: Option<String>,
+}
+
+/// Every stored decompressed hash, by path.
+pub fn decompressed_hashes(conn: &Connection) -> Result<HashMap<String, DecompressedHashRecord>> {
+    let mut stmt = conn.prepare("SELECT path, hash, content FROM decompressed_hashes")?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                DecompressedHashRecord {
+                    hash: row.get(1)?,
+                    content: row.get(2)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Store the decompressed hash of the file stored as `path`, replacing any
+/// before.
+pub fn set_decompressed_hash(
+    conn: &Connection,
+    path: &str,
+    record: &DecompressedHashRecord,
+) -> Result<()> {
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO decompressed_hashes (path, hash, content) VALUES (?1, ?2, ?3)",
+    )?
+    .execute(params![path, record.hash, record.content])?;
+    Ok(())
+}
+
+/// Drop the decompressed hashes of files no longer in the database. Returns
+/// how many.
+pub fn prune_decompressed_hashes(conn: &Connection) -> Result<usize> {
+    let removed = conn.execute(
+        "DELETE FROM decompressed_hashes WHERE path NOT IN (SELECT path FROM files)",
+        [],
+    )?;
+    Ok(removed)
+}
+
+// ---------------------------------------------------------------------------
+// Text signatures  (the `text_signatures` table)
+// ---------------------------------------------------------------------------
+
+/// The MinHash signature of a text file, as it was when the file had the
+/// content hash `hash`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextSignatureRecord {
+    pub hash: String,
+    /// `None` when the file could not be read as text; empty when it holds
+    /// no words
+    pub signature: Option<Vec<u64>>,
+}
+
+/// Every stored text signature, by path.
+pub fn text_signatures(conn: &Connection) -> Result<HashMap<String, TextSignatureRecord>> {
+    let mut stmt = conn.prepare("SELECT path, hash, signature FROM text_signatures")?;
+    let rows = stmt
+        .query_map([], |row| {
+            let blob: Option<Vec<u8>> = row.get(2)?;
+            Ok((
+                row.get(0)?,
+                TextSignatureRecord {
+                    hash: row.get(1)?,
+                    signature: blob.map(|b| {
+                        b.chunks_exact(8)
+                            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+                            .collect()
+                    }),
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Store the signature of the file stored as `path`, replacing any before.
+pub fn set_text_signature(
+    conn: &Connection,
+    path: &str,
+    record: &TextSignatureRecord,
+) -> Result<()> {
+    let blob: Option<Vec<u8>> = record
+        .signature
+        .as_ref()
+        .map(|slots| slots.iter().flat_map(|s| s.to_le_bytes()).collect());
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO text_signatures (path, hash, signature) VALUES (?1, ?2, ?3)",
+    )?
+    .execute(params![path, record.hash, blob])?;
+    Ok(())
+}
+
+/// Drop the signatures of files no longer in the database. Returns how many.
+pub fn prune_text_signatures(conn: &Connection) -> Result<usize> {
+    let removed = conn.execute(
+        "DELETE FROM text_signatures WHERE path NOT IN (SELECT path FROM files)",
+        [],
+    )?;
+    Ok(removed)
+}
+
+// ---------------------------------------------------------------------------
+// File chunks  (the `file_chunks` table)
+// ---------------------------------------------------------------------------
+
+/// The content-defined chunks of a file, as they were when the file had the
+/// content hash `hash`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileChunksRecord {
+    pub hash: String,
+    /// Each chunk's hash and length, in order; `None` when the file could
+    /// not be read
+    pub chunks: Option<Vec<(u64, u32)>>,
+}
+
+/// Bytes each chunk takes in the `chunks` blob: its hash, then its length.
+const CHUNK_ENTRY_SIZE: usize = 12;
+
+/// Every stored chunk list, by path.
+pub fn file_chunks(conn: &Connection) -> Result<HashMap<String, FileChunksRecord>> {
+    let mut stmt = conn.prepare("SELECT path, hash, chunks FROM file_chunks")?;
+    let rows = stmt
+        .query_map([], |row| {
+            let blob: Option<Vec<u8>> = row.get(2)?;
+            Ok((
+                row.get(0)?,
+                FileChunksRecord {
+                    hash: row.get(1)?,
+                    chunks: blob.map(|b| {
+                        b.chunks_exact(CHUNK_ENTRY_SIZE)
+                            .map(|c| {
+                                let (hash, length) = c.split_at(8);
+                                (
+                                    u64::from_le_bytes(hash.try_into().unwrap()),
+                                    u32::from_le_bytes(length.try_into().unwrap()),
+                                )
+                            })
+                            .collect()
+                    }),
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Store the chunk list of the file stored as `path`, replacing any before.
+pub fn set_file_chunks(conn: &Connection, path: &str, record: &FileChunksRecord) -> Result<()> {
+    let blob: Option<Vec<u8>> = record.chunks.as_ref().map(|chunks| {
+        chunks
+            .iter()
+            .flat_map(|(hash, length)| {
+                hash.to_le_bytes().into_iter().chain(length.to_le_bytes())
+            })
+            .collect()
+    });
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO file_chunks (path, hash, chunks) VALUES (?1, ?2, ?3)",
+    )?
+    .execute(params![path, record.hash, blob])?;
+    Ok(())
+}
+
+/// Drop the chunk lists of files no longer in the database. Returns how
+/// many.
+pub fn prune_file_chunks(conn: &Connection) -> Result<usize> {
+    let removed = conn.execute(
+        "DELETE FROM file_chunks WHERE path NOT IN (SELECT path FROM files)",
+        [],
+    )?;
+    Ok(removed)
+}
+
+// ---------------------------------------------------------------------------
+// Video signatures  (the `video_signatures` table)
+// ---------------------------------------------------------------------------
+
+/// The sampled-frame signature of a video, as it was when the file had the
+/// content hash `hash`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VideoSignatureRecord {
+    pub hash: String,
+    /// The dHash of each sampled frame; `None` when the file could not be
+    /// sampled
+    pub frames: Option<Vec<u64>>,
+    /// In seconds
+    pub duration: f64,
+    pub width: i64,
+    pub height: i64,
+}
+
+/// Every stored video signature, by path.
+pub fn video_signatures(conn: &Connection) -> Result<HashMap<String, VideoSignatureRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT path, hash, frames, duration, width, height FROM video_signatures",
+    )?;
+    let rows = stmt
+        .query_map([], |row| {
+            let blob: Option<Vec<u8>> = row.get(2)?;
+            Ok((
+                row.get(0)?,
+                VideoSignatureRecord {
+                    hash: row.get(1)?,
+                    frames: blob.map(|b| {
+                        b.chunks_exact(8)
+                            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+                            .collect()
+                    }),
+                    duration: row.get(3)?,
+                    width: row.get(4)?,
+                    height: row.get(5)?,
+                },
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(rows)
+}
+
+/// Store the signature of the video stored as `path`, replacing any before.
+pub fn set_video_signature(
+    conn: &Connection,
+    path: &str,
+    record: &VideoSignatureRecord,
+) -> Result<()> {
+    let blob: Option<Vec<u8>> = record
+        .frames
+        .as_ref()
+        .map(|frames| frames.iter().flat_map(|f| f.to_le_bytes()).collect());
+    conn.prepare_cached(
+        "INSERT OR REPLACE INTO video_signatures (path, hash, frames, duration, width, height)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+    )?
+    .execute(params![path, record.hash, blob, record.duration, record.width, record.height])?;
+    Ok(())
+}
+
+/// Drop the signatures of videos no longer in the database. Returns how
+/// many.
+pub fn prune_video_signatures(conn: &Connection) -> Result<usize> {
+    let removed = conn.execute(
+        "DELETE FROM video_signatures WHERE path NOT IN (SELECT path FROM files)",
+        [],
+    )?;
+    Ok(removed)
+}
+
+// ---------------------------------------------------------------------------
+// Run lock  (the `run_lock` table)
+// ---------------------------------------------------------------------------
+//
+// SQLite keeps two processes from corrupting the file, but not a scan from
+// interleaving with a dedupe that deletes the rows it is hashing. Every
+// command that changes the database holds this lock for its whole run, so a
+// second one is turned away (or waits) instead.
+
+/// The run that holds the lock.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LockHolder {
+    /// Its command line
+    pub command: String,
+    pub pid: u32,
+    pub host: Option<String>,
+    /// When it took the lock, in Unix seconds
+    pub started: i64,
+}
+
+impl LockHolder {
+    /// Whether the holder ran on this host and is no longer running, so it
+    /// will never release the lock itself.
+    pub fn is_abandoned(&self) -> bool {
+        self.host == utils::hostname() && !utils::process_alive(self.pid)
+    }
+}
+
+/// The run lock, held by this process until dropped.
+pub struct RunLock<'a> {
+    conn: &'a Connection,
+    pid: u32,
+    host: Option<String>,
+}
+
+impl Drop for RunLock<'_> {
+    fn drop(&mut self) {
+        // A lock that is never released (the process was killed, or exited
+        // early) is taken over by the next run on this host instead
+        let _ = self.conn.execute(
+            "DELETE FROM run_lock WHERE pid = ?1 AND host IS ?2",
+            params![self.pid, self.host],
+        );
+    }
+}
+
+/// Take the run lock for `command` (for others to see), or say who holds it.
+/// A lock taken on this host by a process that is no longer running is
+/// taken over; one from another host is only ever released by its holder.
+pub fn try_lock<'a>(
+    conn: &'a Connection,
+    command: &str,
+) -> Result<Result<RunLock<'a>, LockHolder>> {
+    let pid = std::process::id();
+    let host = utils::hostname();
+    loop {
+        let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+        let taken = conn.execute(
+            "INSERT OR IGNORE INTO run_lock (id, command, pid, host, started)
+             VALUES (1, ?1, ?2, ?3, ?4)",
+            params![command, pid, host, started],
+        )?;
+        if taken == 1 {
+            return Ok(Ok(RunLock { conn, pid, host }));
+        }
+        // Released in between: try again
+        let Some(holder) = lock_holder(conn)? else {
+            continue;
+        };
+        if !holder.is_abandoned() {
+            return Ok(Err(holder));
+        }
+        conn.execute(
+            "DELETE FROM run_lock WHERE pid = ?1 AND host IS ?2 AND started = ?3",
+            params![holder.pid, holder.host, holder.started],
+        )?;
+    }
+}
+
+/// Who holds the run lock, if anyone.
+pub fn lock_holder(conn: &Connection) -> Result<Option<LockHolder>> {
+    let result = conn.query_row("SELECT command, pid, host, started FROM run_lock", [], |row| {
+        Ok(LockHolder {
+            command: row.get(0)?,
+            pid: row.get(1)?,
+            host: row.get(2)?,
+            started: row.get(3)?,
+        })
+    });
+    match result {
+        Ok(holder) => Ok(Some(holder)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Maintenance
+// ---------------------------------------------------------------------------
+
+/// How much room the database takes up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Storage {
+    /// Size of the database's pages, not counting a pending WAL log.
+    pub bytes: i64,
+    /// Bytes of pages left free by deleted rows, which `VACUUM` gives back.
+    pub free_bytes: i64,
+    /// Row count of each table, by name.
+    pub tables: Vec<(String, i64)>,
+}
+
+/// What `PRAGMA integrity_check` reports wrong; empty when nothing is.
+pub fn integrity_check(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("PRAGMA integrity_check")?;
+    let messages = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(messages.into_iter().filter(|m| m != "ok").collect())
+}
+
+pub fn storage(conn: &Connection) -> Result<Storage> {
+    let pragma = |name: &str| conn.query_row(&format!("PRAGMA {name}"), [], |row| row.get(0));
+    let page_size: i64 = pragma("page_size")?;
+    let names = conn
+        .prepare(
+            "SELECT name FROM sqlite_master
+                WHERE type = 'table' AND name NOT LIKE 'sqlite_%' ORDER BY name",
+        )?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut tables = Vec::new();
+    for name in names {
+        let count = conn.query_row(&format!("SELECT COUNT(*) FROM \"{name}\""), [], |row| {
+            row.get(0)
+        })?;
+        tables.push((name, count));
+    }
+    Ok(Storage {
+        bytes: pragma("page_count")? * page_size,
+        free_bytes: pragma("freelist_count")? * page_size,
+        tables,
+    })
+}
+
+/// Rewrite the database without its free pages, and fold the WAL log back
+/// into it so the file on disk shrinks too.
+pub fn vacuum(conn: &Connection) -> Result<()> {
+    conn.execute_batch("VACUUM")?;
+    conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+    Ok(())
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        setup_schema(&conn).unwrap();
+        conn
+    }
+
+    // -----------------------------------------------------------------------
+    // Helpers
+    // -----------------------------------------------------------------------
+
+    /// Convert a Unix-style test path to the OS path separator.
+    /// Needed so LIKE patterns (which use MAIN_SEPARATOR) match stored paths.
+    fn p(s: &str) -> String {
+        s.replace('/', std::path::MAIN_SEPARATOR_STR)
+    }
+
+    fn insert_file_raw(conn: &Connection, path: &str, hash: &str, size: i64, modified: i64) {
+        conn.execute(
+            "INSERT INTO files (path, hash, size, modified) VALUES (?1, ?2, ?3, ?4)",
+            params![path, hash, size, modified],
+        )
+        .unwrap();
+    }
+
+    fn insert_dir_raw(conn: &Connection, path: &str, hash: &str, size: i64) {
+        conn.execute(
+            "INSERT INTO directories (path, hash, size) VALUES (?1, ?2, ?3)",
+            params![path, hash, size],
+        )
+        .unwrap();
+    }
+
+    // -----------------------------------------------------------------------
+    // init_database / WriteBatch
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_open_scratch_copy_leaves_file_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("test.db");
+        let disk = init_database(&path).unwrap();
+        upsert_file(&disk, Path::new("/a.txt"), "h1", 1, 0).unwrap();
+
+        let scratch = open_scratch_copy(&path).unwrap();
+        assert!(get_file(&scratch, Path::new("/a.txt")).unwrap().is_some());
+        remove_file(&scratch, Path::new("/a.txt")).unwrap();
+        upsert_file(&scratch, Path::new("/b.txt"), "h2", 1, 0).unwrap();
+
+        assert!(get_file(&disk, Path::new("/a.txt")).unwrap().is_some());
+        assert!(get_file(&disk, Path::new("/b.txt")).unwrap().is_none());
+        assert!(open_scratch_copy(&dir.path().join("none.db")).is_ok());
+        assert!(!dir.path().join("none.db").exists());
+    }
+
+    #[test]
+    fn test_init_database_enables_wal() {
+        let dir = tempfile::tempdir().unwrap();
+        let conn = init_database(&dir.path().join("test.db")).unwrap();
+        let mode: String = conn
+            .query_row("PRAGMA journal_mode", [], |r| r.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+    }
+
+    #[cfg(feature = "sqlcipher")]
+    #[test]
+    fn test_encrypted_database_needs_its_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret.db");
+        {
+            let conn = open_database(&path, Some("hunter2")).unwrap();
+            upsert_file(&conn, Path::new("/private/a.txt"), "h1", 1, 0).unwrap();
+        }
+        assert!(open_database(&path, None).is_err());
+        assert!(open_database(&path, Some("wrong")).is_err());
+        let conn = open_database(&path, Some("hunter2")).unwrap();
+        assert!(get_file(&conn, Path::new("/private/a.txt")).unwrap().is_some());
+        let scratch = scratch_copy(&path, Some("hunter2")).unwrap();
+        assert!(get_file(&scratch, Path::new("/private/a.txt")).unwrap().is_some());
+        // Nothing of the file listing is readable on disk
+        let raw = std::fs::read(&path).unwrap();
+        assert!(!raw.windows(7).any(|w| w == b"private"));
+    }
+
+    #[test]
+    fn test_a_key_cannot_be_set_without_sqlcipher() {
+        if !ENCRYPTION_SUPPORTED {
+            assert!(set_key("k".to_string()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_write_batch_commits_every_batch_and_at_end() {
+        let conn = open_test_db();
+        let mut batch = WriteBatch::begin(&conn, 2).unwrap();
+        for i in 0..3 {
+            upsert_file(&conn, Path::new(&format!("/f{i}.txt")), "h", 1, 0).unwrap();
+            batch.tick().unwrap();
+        }
+        // Two batches of two: the first was committed, the second is open
+        assert!(!conn.is_autocommit());
+        batch.commit().unwrap();
+        assert!(conn.is_autocommit());
+        assert_eq!(all_files(&conn).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_write_batch_commits_on_drop() {
+        let conn = open_test_db();
+        {
+            let _batch = WriteBatch::begin(&conn, 100).unwrap();
+            upsert_file(&conn, Path::new("/f.txt"), "h", 1, 0).unwrap();
+        }
+        assert!(conn.is_autocommit());
+        assert!(get_file(&conn, Path::new("/f.txt")).unwrap().is_some());
+    }
+
+    // -----------------------------------------------------------------------
+    // Scan history
+    // -----------------------------------------------------------------------
+
+    fn history_paths(conn: &Connection, scan_id: i64) -> Vec<String> {
+        files_at_scan(conn, scan_id).unwrap().into_iter().map(|f| f.path).collect()
+    }
+
+    #[test]
+    fn test_scan_history_records_only_changes() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, &p("/root/a.txt"), "h1", 10, 1);
+        insert_file_raw(&conn, &p("/root/b.txt"), "h2", 10, 1);
+        init_visited_files(&conn).unwrap();
+        mark_visited(&conn, &p("/root/a.txt")).unwrap();
+        mark_visited(&conn, &p("/root/b.txt")).unwrap();
+        let first = begin_scan(&conn, 100, &[&p("/root")]).unwrap();
+        record_scan_history(&conn, first, &p("/root")).unwrap();
+
+        // b.txt changes, a.txt is deleted, c.txt appears
+        conn.execute("DELETE FROM files WHERE path = ?1", params![p("/root/a.txt")]).unwrap();
+        conn.execute("UPDATE files SET hash = 'h3' WHERE path = ?1", params![p("/root/b.txt")])
+            .unwrap();
+        insert_file_raw(&conn, &p("/root/c.txt"), "h4", 10, 1);
+        init_visited_files(&conn).unwrap();
+        mark_visited(&conn, &p("/root/b.txt")).unwrap();
+        mark_visited(&conn, &p("/root/c.txt")).unwrap();
+        let second = begin_scan(&conn, 200, &[&p("/root")]).unwrap();
+        record_scan_history(&conn, second, &p("/root")).unwrap();
+        // Nothing changes: the third scan records nothing
+        let third = begin_scan(&conn, 300, &[&p("/root")]).unwrap();
+        record_scan_history(&conn, third, &p("/root")).unwrap();
+
+        let counts: Vec<_> = scans(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.id, s.time, s.added, s.changed, s.removed))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![(first, 100, 2, 0, 0), (second, 200, 1, 1, 1), (third, 300, 0, 0, 0)]
+        );
+        assert_eq!(scans(&conn).unwrap()[0].roots, vec![p("/root")]);
+        assert_eq!(history_paths(&conn, first), vec![p("/root/a.txt"), p("/root/b.txt")]);
+        assert_eq!(history_paths(&conn, third), vec![p("/root/b.txt"), p("/root/c.txt")]);
+        assert_eq!(files_at_scan(&conn, first).unwrap()[1].hash, "h2");
+        assert_eq!(files_at_scan(&conn, third).unwrap()[0].hash, "h3");
+    }
+
+    #[test]
+    fn test_scan_history_tags_visited_files_and_leaves_other_roots_alone() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, &p("/root/a.txt"), "h1", 10, 1);
+        insert_file_raw(&conn, &p("/other/b.txt"), "h2", 10, 1);
+        init_visited_files(&conn).unwrap();
+        mark_visited(&conn, &p("/other/b.txt")).unwrap();
+        let other = begin_scan(&conn, 100, &[&p("/other")]).unwrap();
+        record_scan_history(&conn, other, &p("/other")).unwrap();
 
-// ------------------------------------------------------------------
-//
-//
-// TESTS
-//
-//
-// ------------------------------------------------------------------
+        init_visited_files(&conn).unwrap();
+        mark_visited(&conn, &p("/root/a.txt")).unwrap();
+        let root = begin_scan(&conn, 200, &[&p("/root")]).unwrap();
+        record_scan_history(&conn, root, &p("/root")).unwrap();
+
+        // /other wasn't scanned again, so its file is still there
+        assert_eq!(history_paths(&conn, root), vec![p("/other/b.txt"), p("/root/a.txt")]);
+        let last_scan = |path: &str| -> Option<i64> {
+            conn.query_row("SELECT last_scan FROM files WHERE path = ?1", params![path], |r| {
+                r.get(0)
+            })
+            .unwrap()
+        };
+        assert_eq!(last_scan(&p("/other/b.txt")), Some(other));
+        assert_eq!(last_scan(&p("/root/a.txt")), Some(root));
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rusqlite::Connection;
-    use std::path::Path;
-    use std::time::{Duration, SystemTime};
+    #[test]
+    fn test_root_options_come_from_the_closest_root() {
+        let conn = open_test_db();
+        let words = |w: &[&str]| -> Vec<String> { w.iter().map(|s| s.to_string()).collect() };
+        set_root_options(&conn, &p("/root"), &words(&["--exclude=*.tmp"])).unwrap();
+        set_root_options(&conn, &p("/root/sub"), &words(&["--prefilter"])).unwrap();
+        set_root_options(&conn, &p("/root"), &words(&["--skip-hidden"])).unwrap();
+
+        let options = |path: &str| root_options(&conn, Path::new(&p(path))).unwrap();
+        assert_eq!(options("/root/a"), Some(words(&["--skip-hidden"])));
+        assert_eq!(options("/root/sub/b"), Some(words(&["--prefilter"])));
+        assert_eq!(options("/root/subway"), Some(words(&["--skip-hidden"])));
+        assert_eq!(options("/other"), None);
+    }
 
-    fn open_test_db() -> Connection {
-        let conn = Connection::open_in_memory().unwrap();
-        setup_schema(&conn).unwrap();
-        conn
+    // -----------------------------------------------------------------------
+    // Locations
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_tag_location_covers_the_scanned_root_only() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, &p("/nas/a.txt"), "h1", 10, 1);
+        insert_file_raw(&conn, &p("/other/b.txt"), "h2", 10, 1);
+        upsert_directory(&conn, Path::new(&p("/nas")), "d1", 10).unwrap();
+        upsert_directory(&conn, Path::new(&p("/nas/sub")), "d2", 0).unwrap();
+        init_visited_files(&conn).unwrap();
+        mark_visited(&conn, &p("/nas/a.txt")).unwrap();
+        let labelled = Location {
+            host: Some("laptop".to_string()),
+            volume: Some("nas".to_string()),
+        };
+        tag_location(&conn, &p("/nas"), &labelled).unwrap();
+        // Rescanning without a label keeps the volume
+        let unlabelled = Location {
+            host: Some("desktop".to_string()),
+            volume: None,
+        };
+        tag_location(&conn, &p("/nas"), &unlabelled).unwrap();
+
+        let found = locations(&conn).unwrap();
+        assert_eq!(found.len(), 3);
+        let expected = Location {
+            host: Some("desktop".to_string()),
+            volume: Some("nas".to_string()),
+        };
+        assert_eq!(found[&p("/nas/a.txt")], expected);
+        assert_eq!(found[&p("/nas/sub")], expected);
+        assert!(!found.contains_key(&p("/other/b.txt")));
+    }
+
+    #[test]
+    fn test_location_names_prefer_volume_then_host_then_source() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, "/a", "h", 1, 0);
+        insert_file_raw(&conn, "/b", "h", 1, 0);
+        insert_file_raw(&conn, "/c", "h", 1, 0);
+        conn.execute("UPDATE files SET host = 'laptop', volume = 'nas' WHERE path = '/a'", [])
+            .unwrap();
+        conn.execute("UPDATE files SET host = 'laptop', source = 'l' WHERE path = '/b'", [])
+            .unwrap();
+        conn.execute("UPDATE files SET source = 'desktop' WHERE path = '/c'", []).unwrap();
+        insert_file_raw(&conn, "/d", "h", 1, 0);
+
+        let names = location_names(&conn).unwrap();
+        assert_eq!(names.len(), 3);
+        assert_eq!(names["/a"], "nas");
+        assert_eq!(names["/b"], "laptop");
+        assert_eq!(names["/c"], "desktop");
     }
 
     // -----------------------------------------------------------------------
-    // Helpers
+    // Scan session checkpoints
     // -----------------------------------------------------------------------
 
-    /// Convert a Unix-style test path to the OS path separator.
-    /// Needed so LIKE patterns (which use MAIN_SEPARATOR) match stored paths.
-    fn p(s: &str) -> String {
-        s.replace('/', std::path::MAIN_SEPARATOR_STR)
+    #[test]
+    fn test_scan_state_tracks_completed_roots() {
+        let conn = open_test_db();
+        add_scan_roots(&conn, &["/a", "/b"]).unwrap();
+        assert!(completed_scan_roots(&conn).unwrap().is_empty());
+
+        mark_scan_root_complete(&conn, "/a").unwrap();
+        // Re-adding a root on resume must not reset its status
+        add_scan_roots(&conn, &["/a", "/c"]).unwrap();
+        assert_eq!(completed_scan_roots(&conn).unwrap(), vec!["/a"]);
+
+        clear_scan_state(&conn).unwrap();
+        assert!(completed_scan_roots(&conn).unwrap().is_empty());
     }
 
-    fn insert_file_raw(conn: &Connection, path: &str, hash: &str, size: i64, modified: i64) {
-        conn.execute(
-            "INSERT INTO files (path, hash, size, modified) VALUES (?1, ?2, ?3, ?4)",
-            params![path, hash, size, modified],
-        )
-        .unwrap();
+    // -----------------------------------------------------------------------
+    // meta / is_empty
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_meta_get_set() {
+        let conn = open_test_db();
+        assert_eq!(get_meta(&conn, "k").unwrap(), None);
+        set_meta(&conn, "k", "v1").unwrap();
+        set_meta(&conn, "k", "v2").unwrap();
+        assert_eq!(get_meta(&conn, "k").unwrap().as_deref(), Some("v2"));
+        delete_meta(&conn, "k").unwrap();
+        assert_eq!(get_meta(&conn, "k").unwrap(), None);
     }
 
-    fn insert_dir_raw(conn: &Connection, path: &str, hash: &str, size: i64) {
-        conn.execute(
-            "INSERT INTO directories (path, hash, size) VALUES (?1, ?2, ?3)",
-            params![path, hash, size],
-        )
-        .unwrap();
+    #[test]
+    fn test_is_empty() {
+        let conn = open_test_db();
+        assert!(is_empty(&conn).unwrap());
+        insert_dir_raw(&conn, "/a", "h1", 0);
+        assert!(!is_empty(&conn).unwrap());
     }
 
     // -----------------------------------------------------------------------
@@ -507,6 +2907,69 @@ mod tests {
         assert!(results.iter().all(|r| r.hash == "shared"));
     }
 
+    #[test]
+    fn test_file_totals() {
+        let conn = open_test_db();
+        assert_eq!(file_totals(&conn).unwrap(), (0, 0));
+        insert_file_raw(&conn, "/a.txt", "h1", 10, 1);
+        insert_file_raw(&conn, "/b.txt", "h2", 15, 2);
+        assert_eq!(file_totals(&conn).unwrap(), (2, 25));
+    }
+
+    // -----------------------------------------------------------------------
+    // files_with_size / files_in_directory
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_files_with_size_returns_matches() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, "/a.txt", "h1", 10, 1);
+        insert_file_raw(&conn, "/b.txt", "h2", 10, 2);
+        insert_file_raw(&conn, "/c.txt", "h3", 11, 3);
+        let results = files_with_size(&conn, 10).unwrap();
+        let paths: Vec<&str> = results.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["/a.txt", "/b.txt"]);
+    }
+
+    #[test]
+    fn test_files_under_is_recursive_and_skips_siblings() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, &p("/root/a.txt"), "h1", 1, 1);
+        insert_file_raw(&conn, &p("/root/sub/b.txt"), "h2", 1, 1);
+        insert_file_raw(&conn, &p("/rootx/c.txt"), "h3", 1, 1);
+        let results = files_under(&conn, Path::new(&p("/root"))).unwrap();
+        let paths: Vec<&str> = results.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec![p("/root/a.txt"), p("/root/sub/b.txt")]);
+    }
+
+    #[test]
+    fn test_search_files_by_path_part_and_hash_prefix() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, "/photos/cat.jpg", "abc123", 1, 1);
+        insert_file_raw(&conn, "/backup/cat.jpg", "abc123", 1, 1);
+        insert_file_raw(&conn, "/photos/dog.jpg", "def456", 1, 1);
+        let paths = |path: Option<&str>, hash: Option<&str>, limit| -> Vec<String> {
+            let files = search_files(&conn, path, hash, limit).unwrap();
+            files.into_iter().map(|f| f.path).collect()
+        };
+        assert_eq!(paths(Some("cat"), None, 10), ["/backup/cat.jpg", "/photos/cat.jpg"]);
+        assert_eq!(paths(None, Some("ABC"), 10), ["/backup/cat.jpg", "/photos/cat.jpg"]);
+        assert_eq!(paths(Some("photos"), Some("abc"), 10), ["/photos/cat.jpg"]);
+        assert_eq!(paths(None, None, 1), ["/backup/cat.jpg"]);
+        assert!(paths(Some("%"), None, 10).is_empty());
+    }
+
+    #[test]
+    fn test_files_in_directory_returns_immediate_only() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, &p("/root/a.txt"), "h1", 1, 1);
+        insert_file_raw(&conn, &p("/root/sub/b.txt"), "h2", 1, 1);
+        insert_file_raw(&conn, &p("/rootx/c.txt"), "h3", 1, 1);
+        let results = files_in_directory(&conn, Path::new(&p("/root"))).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].path, p("/root/a.txt"));
+    }
+
     // -----------------------------------------------------------------------
     // duplicate_file_groups
     // -----------------------------------------------------------------------
@@ -584,6 +3047,34 @@ mod tests {
         assert!(get_file(&conn, Path::new("/new.txt")).unwrap().is_some());
     }
 
+    #[test]
+    fn test_move_tree_repoints_everything_below() {
+        let conn = open_test_db();
+        insert_dir_raw(&conn, &p("/a"), "dh1", 20);
+        insert_dir_raw(&conn, &p("/a/sub"), "dh2", 10);
+        insert_file_raw(&conn, &p("/a/sub/x.txt"), "fh1", 10, 1);
+        insert_archive_member(&conn, &p("/a/t.zip!/y"), &p("/a/t.zip"), "fh2", 5, 1).unwrap();
+        insert_file_raw(&conn, &p("/ab/z.txt"), "fh3", 10, 1);
+        insert_file_raw(&conn, &p("/b/ghost.txt"), "fh4", 10, 1);
+
+        move_tree(&conn, Path::new(&p("/a")), Path::new(&p("/b"))).unwrap();
+        let dirs: Vec<String> = directories_under(&conn, Path::new(&p("/b")))
+            .unwrap()
+            .into_iter()
+            .map(|d| d.path)
+            .collect();
+        assert_eq!(dirs, [p("/b"), p("/b/sub")]);
+        let files: Vec<String> = files_under(&conn, Path::new(&p("/b")))
+            .unwrap()
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        assert_eq!(files, [p("/b/sub/x.txt"), p("/b/t.zip!/y")]);
+        assert_eq!(delete_archive_members(&conn, &p("/b/t.zip")).unwrap(), 1);
+        // A sibling sharing the prefix stays put
+        assert!(get_file(&conn, Path::new(&p("/ab/z.txt"))).unwrap().is_some());
+    }
+
     // -----------------------------------------------------------------------
     // remove_file
     // -----------------------------------------------------------------------
@@ -619,41 +3110,181 @@ mod tests {
         assert_eq!(rec.modified, 1);
     }
 
+    // -----------------------------------------------------------------------
+    // update_partial_hash
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_update_partial_hash() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, "/f.txt", "h1", 10, 1);
+        assert_eq!(get_file(&conn, Path::new("/f.txt")).unwrap().unwrap().partial_hash, None);
+        update_partial_hash(&conn, Path::new("/f.txt"), "p1").unwrap();
+        let rec = get_file(&conn, Path::new("/f.txt")).unwrap().unwrap();
+        assert_eq!(rec.partial_hash.as_deref(), Some("p1"));
+        assert_eq!(rec.hash, "h1");
+    }
+
+    #[test]
+    fn test_copy_file_record() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, "/keep.txt", "h1", 10, 5);
+        update_file_identity(&conn, Path::new("/keep.txt"), 3, 42).unwrap();
+        insert_file_raw(&conn, "/copy.txt", "h1", 10, 9);
+        copy_file_record(&conn, Path::new("/keep.txt"), Path::new("/copy.txt")).unwrap();
+        let rec = get_file(&conn, Path::new("/copy.txt")).unwrap().unwrap();
+        assert_eq!((rec.modified, rec.device, rec.inode), (5, Some(3), Some(42)));
+        assert!(get_file(&conn, Path::new("/keep.txt")).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_update_file_identity() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, "/f.txt", "h1", 10, 1);
+        assert_eq!(get_file(&conn, Path::new("/f.txt")).unwrap().unwrap().inode, None);
+        update_file_identity(&conn, Path::new("/f.txt"), 3, 42).unwrap();
+        let rec = get_file(&conn, Path::new("/f.txt")).unwrap().unwrap();
+        assert_eq!((rec.device, rec.inode), (Some(3), Some(42)));
+    }
+
+    #[test]
+    fn test_update_file_stat_and_copy_keeps_it() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, "/f.txt", "h1", 10, 1);
+        assert_eq!(get_file(&conn, Path::new("/f.txt")).unwrap().unwrap().stat, None);
+        let stat = utils::FileStat {
+            nlink: 2,
+            uid: 1000,
+            gid: 100,
+            mode: 0o640,
+        };
+        update_file_stat(&conn, Path::new("/f.txt"), &stat).unwrap();
+        copy_file_record(&conn, Path::new("/f.txt"), Path::new("/g.txt")).unwrap();
+        for path in ["/f.txt", "/g.txt"] {
+            let rec = get_file(&conn, Path::new(path)).unwrap().unwrap();
+            assert_eq!(rec.stat, Some(stat));
+        }
+    }
+
+    #[test]
+    fn test_update_file_via_link_and_upsert_resets_it() {
+        let conn = open_test_db();
+        let path = Path::new("/linked/f.txt");
+        upsert_file(&conn, path, "h1", 10, 1).unwrap();
+        assert!(!get_file(&conn, path).unwrap().unwrap().via_link);
+        update_file_via_link(&conn, path, true).unwrap();
+        assert!(get_file(&conn, path).unwrap().unwrap().via_link);
+        upsert_file(&conn, path, "h2", 10, 2).unwrap();
+        assert!(!get_file(&conn, path).unwrap().unwrap().via_link);
+    }
+
+    // -----------------------------------------------------------------------
+    // setup_schema on an older database
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_setup_schema_adds_partial_hash_to_old_files_table() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE files (
+                path TEXT PRIMARY KEY,
+                hash TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                modified INTEGER NOT NULL
+            );
+            INSERT INTO files VALUES ('/old.txt', 'h1', 10, 1);",
+        )
+        .unwrap();
+
+        setup_schema(&conn).unwrap();
+        // Running it again must not try to add the column twice
+        setup_schema(&conn).unwrap();
+
+        let rec = get_file(&conn, Path::new("/old.txt")).unwrap().unwrap();
+        assert_eq!(rec.hash, "h1");
+        assert_eq!(rec.partial_hash, None);
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_setup_schema_records_the_version_once() {
+        let conn = open_test_db();
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+        setup_schema(&conn).unwrap();
+        let rows: i64 = conn
+            .query_row("SELECT COUNT(*) FROM schema_version", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 1);
+    }
+
+    #[test]
+    fn test_setup_schema_applies_only_the_missing_migrations() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute("CREATE TABLE schema_version (version INTEGER NOT NULL)", []).unwrap();
+        conn.execute("INSERT INTO schema_version VALUES (1)", []).unwrap();
+        create_baseline(&conn).unwrap();
+        assert!(conn.prepare("SELECT * FROM scans").is_err());
+
+        setup_schema(&conn).unwrap();
+        assert!(scans(&conn).unwrap().is_empty());
+        assert_eq!(schema_version(&conn).unwrap(), SCHEMA_VERSION);
+    }
+
+    #[test]
+    fn test_setup_schema_refuses_a_newer_database() {
+        let conn = open_test_db();
+        conn.execute("UPDATE schema_version SET version = ?1", params![SCHEMA_VERSION + 1])
+            .unwrap();
+        let err = setup_schema(&conn).unwrap_err().to_string();
+        assert!(err.contains("upgrade deduplifier"), "{err}");
+    }
+
     // -----------------------------------------------------------------------
     // Stale file tracking
     // -----------------------------------------------------------------------
 
     #[test]
-    fn test_stale_file_count_all_visited() {
+    fn test_stale_file_paths_all_visited() {
         let conn = open_test_db();
         insert_file_raw(&conn, "/root/a.txt", "h1", 10, 1);
         insert_file_raw(&conn, "/root/b.txt", "h2", 10, 2);
         init_visited_files(&conn).unwrap();
         mark_visited(&conn, "/root/a.txt").unwrap();
         mark_visited(&conn, "/root/b.txt").unwrap();
-        assert_eq!(stale_file_count(&conn, "/root").unwrap(), 0);
+        assert_eq!(stale_file_paths(&conn, "/root").unwrap().len(), 0);
     }
 
     #[test]
-    fn test_stale_file_count_one_missing() {
+    fn test_stale_file_paths_one_missing() {
         let conn = open_test_db();
         insert_file_raw(&conn, &p("/root/a.txt"), "h1", 10, 1);
         insert_file_raw(&conn, &p("/root/b.txt"), "h2", 10, 2);
         init_visited_files(&conn).unwrap();
         mark_visited(&conn, &p("/root/a.txt")).unwrap();
         // b.txt not visited → stale
-        assert_eq!(stale_file_count(&conn, &p("/root")).unwrap(), 1);
+        assert_eq!(stale_file_paths(&conn, &p("/root")).unwrap(), vec![p("/root/b.txt")]);
+    }
+
+    #[test]
+    fn test_stale_file_paths_lists_unvisited_files_under_root() {
+        let conn = open_test_db();
+        insert_file_raw(&conn, &p("/root/a.txt"), "h1", 10, 1);
+        insert_file_raw(&conn, &p("/root/sub/b.txt"), "h2", 10, 2);
+        insert_file_raw(&conn, &p("/other/c.txt"), "h3", 10, 3);
+        init_visited_files(&conn).unwrap();
+        mark_visited(&conn, &p("/root/a.txt")).unwrap();
+        assert_eq!(stale_file_paths(&conn, &p("/root")).unwrap(), vec![p("/root/sub/b.txt")]);
     }
 
     #[test]
-    fn test_stale_file_count_ignores_other_roots() {
+    fn test_stale_file_paths_ignores_other_roots() {
         let conn = open_test_db();
         insert_file_raw(&conn, "/root/a.txt", "h1", 10, 1);
         insert_file_raw(&conn, "/other/b.txt", "h2", 10, 2);
         init_visited_files(&conn).unwrap();
         mark_visited(&conn, "/root/a.txt").unwrap();
         // /other/b.txt is under a different root — not counted as stale for /root
-        assert_eq!(stale_file_count(&conn, "/root").unwrap(), 0);
+        assert_eq!(stale_file_paths(&conn, "/root").unwrap().len(), 0);
     }
 
     #[test]
@@ -677,6 +3308,17 @@ mod tests {
     // child_directories
     // -----------------------------------------------------------------------
 
+    #[test]
+    fn test_directories_under_includes_root_but_not_siblings() {
+        let conn = open_test_db();
+        for path in ["/root", "/root/a", "/root/a/b", "/root2", "/other"] {
+            insert_dir_raw(&conn, &p(path), "h", 1);
+        }
+        let dirs = directories_under(&conn, Path::new(&p("/root"))).unwrap();
+        let paths: Vec<&str> = dirs.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec![p("/root"), p("/root/a"), p("/root/a/b")]);
+    }
+
     #[test]
     fn test_child_directories_none() {
         let conn = open_test_db();
@@ -701,6 +3343,34 @@ mod tests {
         assert!(!paths.contains(&p("/unrelated").as_str()));
     }
 
+    // -----------------------------------------------------------------------
+    // get_directory
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_get_directory_found_and_missing() {
+        let conn = open_test_db();
+        insert_dir_raw(&conn, "/a", "h1", 10);
+        assert_eq!(get_directory(&conn, Path::new("/a")).unwrap().unwrap().hash, "h1");
+        assert_eq!(get_directory(&conn, Path::new("/b")).unwrap(), None);
+    }
+
+    // -----------------------------------------------------------------------
+    // remove_directory
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_remove_directory_leaves_children() {
+        let conn = open_test_db();
+        insert_dir_raw(&conn, &p("/a"), "h1", 10);
+        insert_dir_raw(&conn, &p("/a/sub"), "h2", 10);
+        remove_directory(&conn, Path::new(&p("/a"))).unwrap();
+        assert_eq!(get_directory(&conn, Path::new(&p("/a"))).unwrap(), None);
+        assert!(get_directory(&conn, Path::new(&p("/a/sub")))
+            .unwrap()
+            .is_some());
+    }
+
     // -----------------------------------------------------------------------
     // directories_with_hash
     // -----------------------------------------------------------------------
@@ -735,8 +3405,15 @@ mod tests {
     #[test]
     fn test_duplicate_directory_groups_excludes_empty_hash() {
         let conn = open_test_db();
-        insert_dir_raw(&conn, "/a", EMPTY_DIR_HASH, 0);
-        insert_dir_raw(&conn, "/b", EMPTY_DIR_HASH, 0);
+        for algorithm in [
+            hashing::HashAlgorithm::Sha256,
+            hashing::HashAlgorithm::Blake3,
+            hashing::HashAlgorithm::Xxh3,
+        ] {
+            let empty = algorithm.empty_hash();
+            insert_dir_raw(&conn, &format!("/a/{}", algorithm.name()), &empty, 0);
+            insert_dir_raw(&conn, &format!("/b/{}", algorithm.name()), &empty, 0);
+        }
         // Empty dirs should not appear as duplicates
         assert!(duplicate_directory_groups(&conn).unwrap().is_empty());
     }
@@ -862,4 +3539,171 @@ mod tests {
             .is_some());
         assert!(directories_with_hash(&conn, "dh2").unwrap().len() == 1);
     }
+
+    // -----------------------------------------------------------------------
+    // dedupe_log
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_dedupe_log_round_trip_in_order() {
+        let conn = open_test_db();
+        let keep = DedupeLogEntry {
+            time: 1,
+            hash: "h".into(),
+            path: "/a".into(),
+            action: "keep".into(),
+            keeper: None,
+            reason: "interactive".into(),
+        };
+        let delete = DedupeLogEntry {
+            path: "/b".into(),
+            action: "delete".into(),
+            keeper: Some("/a".into()),
+            ..keep.clone()
+        };
+        log_dedupe(&conn, &keep).unwrap();
+        log_dedupe(&conn, &delete).unwrap();
+        assert_eq!(dedupe_log(&conn).unwrap(), vec![keep, delete]);
+    }
+
+    // -----------------------------------------------------------------------
+    // actions
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_pending_actions_newest_first_and_limited() {
+        let conn = open_test_db();
+        for source in ["/a", "/b", "/c"] {
+            log_action(&conn, "delete", Path::new(source), Some(Path::new("/k")), Some("h"))
+                .unwrap();
+        }
+        let sources = |actions: Vec<ActionRecord>| -> Vec<String> {
+            actions.into_iter().map(|a| a.source).collect()
+        };
+        assert_eq!(sources(pending_actions(&conn, None, None).unwrap()), ["/c", "/b", "/a"]);
+        assert_eq!(sources(pending_actions(&conn, Some(2), None).unwrap()), ["/c", "/b"]);
+        assert!(pending_actions(&conn, None, Some(i64::MAX)).unwrap().is_empty());
+
+        let newest = &pending_actions(&conn, Some(1), None).unwrap()[0];
+        assert_eq!(newest.destination.as_deref(), Some("/k"));
+        mark_undone(&conn, newest.id).unwrap();
+        assert_eq!(sources(pending_actions(&conn, Some(1), None).unwrap()), ["/b"]);
+    }
+
+    #[test]
+    fn test_existing_file_with_hash_skips_missing_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let present = dir.path().join("present.txt");
+        std::fs::write(&present, b"x").unwrap();
+        let conn = open_test_db();
+        upsert_file(&conn, &dir.path().join("gone.txt"), "h", 1, 0).unwrap();
+        upsert_file(&conn, &present, "h", 1, 0).unwrap();
+        assert_eq!(
+            existing_file_with_hash(&conn, "h").unwrap().as_deref(),
+            present.to_str()
+        );
+        assert_eq!(existing_file_with_hash(&conn, "other").unwrap(), None);
+    }
+
+    // -----------------------------------------------------------------------
+    // protected
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_vacuum_gives_back_the_room_deleted_rows_left() {
+        let conn = open_test_db();
+        for i in 0..500 {
+            upsert_file(&conn, Path::new(&format!("/f{i}")), &"h".repeat(64), 1, 0).unwrap();
+        }
+        conn.execute("DELETE FROM files", []).unwrap();
+        let before = storage(&conn).unwrap();
+        assert!(before.free_bytes > 0);
+        assert!(before.tables.contains(&("files".to_string(), 0)));
+        vacuum(&conn).unwrap();
+        let after = storage(&conn).unwrap();
+        assert_eq!(after.free_bytes, 0);
+        assert!(after.bytes < before.bytes);
+        assert!(integrity_check(&conn).unwrap().is_empty());
+    }
+
+    // -----------------------------------------------------------------------
+    // run lock
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_run_lock_turns_a_second_run_away_until_released() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("shared.db");
+        let first = init_database(&path).unwrap();
+        let second = init_database(&path).unwrap();
+
+        let lock = try_lock(&first, "deduplifier scan /a").unwrap().unwrap();
+        let Err(holder) = try_lock(&second, "deduplifier dedupe").unwrap() else {
+            panic!("the lock was taken twice");
+        };
+        assert_eq!(holder.command, "deduplifier scan /a");
+        assert_eq!((holder.pid, holder.host), (std::process::id(), utils::hostname()));
+        assert!(lock_holder(&second).unwrap().is_some());
+
+        drop(lock);
+        assert_eq!(lock_holder(&second).unwrap(), None);
+        assert!(try_lock(&second, "deduplifier dedupe").unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_run_lock_of_a_dead_process_is_taken_over_on_its_host_only() {
+        let conn = open_test_db();
+        let leave_lock = |pid: u32, host: Option<String>| {
+            conn.execute("DELETE FROM run_lock", []).unwrap();
+            conn.execute(
+                "INSERT INTO run_lock (id, command, pid, host, started)
+                 VALUES (1, 'old', ?1, ?2, 0)",
+                params![pid, host],
+            )
+            .unwrap();
+        };
+        // Above the largest pid Linux or macOS hands out
+        let dead = i32::MAX as u32;
+        leave_lock(dead, Some("elsewhere".to_string()));
+        assert!(try_lock(&conn, "new").unwrap().is_err());
+        leave_lock(std::process::id(), utils::hostname());
+        assert!(try_lock(&conn, "new").unwrap().is_err());
+        if cfg!(unix) {
+            leave_lock(dead, utils::hostname());
+            let _lock = try_lock(&conn, "new").unwrap().unwrap();
+            assert_eq!(lock_holder(&conn).unwrap().unwrap().command, "new");
+        }
+    }
+
+    #[test]
+    fn test_protected_paths_add_and_remove() {
+        let conn = open_test_db();
+        assert!(add_protected(&conn, Path::new("/photos/originals")).unwrap());
+        assert!(!add_protected(&conn, Path::new("/photos/originals")).unwrap());
+        assert!(add_protected(&conn, Path::new("/docs")).unwrap());
+        assert_eq!(protected_paths(&conn).unwrap(), ["/docs", "/photos/originals"]);
+        assert!(remove_protected(&conn, Path::new("/docs")).unwrap());
+        assert!(!remove_protected(&conn, Path::new("/docs")).unwrap());
+        assert_eq!(protected_paths(&conn).unwrap(), ["/photos/originals"]);
+    }
+
+    // -----------------------------------------------------------------------
+    // errors
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_scan_errors_latest_first_and_cleared_per_root() {
+        let conn = open_test_db();
+        log_scan_error(&conn, 10, "/a", "/a/x", "io", "Input/output error").unwrap();
+        log_scan_error(&conn, 20, "/b", "/b/y", "permission-denied", "denied").unwrap();
+        let errors = scan_errors(&conn).unwrap();
+        assert_eq!(errors.len(), 2);
+        assert_eq!((errors[0].path.as_str(), errors[0].time), ("/b/y", 20));
+        assert_eq!(errors[1].kind, "io");
+
+        clear_scan_errors(&conn, "/a").unwrap();
+        let errors = scan_errors(&conn).unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].root, "/b");
+    }
 }
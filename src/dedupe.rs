@@ -0,0 +1,1124 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::{db, duplicates, file_system, hashing, scan, utils};
+
+/// One rule of an `--auto` keep policy. Rules are applied in order, each
+/// only breaking the ties left by the ones before it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeepRule {
+    /// Prefer the most recently modified copy
+    Newest,
+    /// Prefer the least recently modified copy
+    Oldest,
+    /// Prefer the copy with the shortest path
+    ShortestPath,
+    /// Prefer copies under this directory
+    PreferPath(PathBuf),
+}
+
+impl FromStr for KeepRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep-newest" => Ok(Self::Newest),
+            "keep-oldest" => Ok(Self::Oldest),
+            "keep-shortest-path" => Ok(Self::ShortestPath),
+            _ => match s.strip_prefix("prefer-path=") {
+                Some(prefix) if !prefix.is_empty() => Ok(Self::PreferPath(prefix.into())),
+                _ => Err(format!(
+                    "unknown rule '{s}'; expected keep-newest, keep-oldest, \
+                     keep-shortest-path or prefer-path=PREFIX"
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for KeepRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Newest => write!(f, "keep-newest"),
+            Self::Oldest => write!(f, "keep-oldest"),
+            Self::ShortestPath => write!(f, "keep-shortest-path"),
+            Self::PreferPath(prefix) => write!(f, "prefer-path={}", prefix.display()),
+        }
+    }
+}
+
+impl KeepRule {
+    /// `Less` when `a` is the better copy to keep.
+    fn compare(&self, a: &duplicates::FileEntry, b: &duplicates::FileEntry) -> Ordering {
+        match self {
+            Self::Newest => b.modified.cmp(&a.modified),
+            Self::Oldest => a.modified.cmp(&b.modified),
+            Self::ShortestPath => a.path.chars().count().cmp(&b.path.chars().count()),
+            Self::PreferPath(prefix) => {
+                let under = |f: &duplicates::FileEntry| {
+                    utils::path_from_db(&f.path).starts_with(prefix)
+                };
+                under(b).cmp(&under(a))
+            }
+        }
+    }
+}
+
+/// The paths `deduplifier protect add` put out of reach: a protected copy, or
+/// one under a protected directory, is always kept and never deleted or
+/// replaced, whatever the keep policy or the answer at the prompt.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProtectedPaths(Vec<PathBuf>);
+
+impl ProtectedPaths {
+    #[cfg(test)]
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        Self(paths)
+    }
+
+    /// The protect list stored in the database.
+    pub fn load(conn: &Connection) -> Result<Self> {
+        Ok(Self(db::protected_paths(conn)?.into_iter().map(PathBuf::from).collect()))
+    }
+
+    /// Whether `path` is protected.
+    pub fn covers(&self, path: &str) -> bool {
+        self.0.iter().any(|p| Path::new(path).starts_with(p))
+    }
+
+    /// Whether `file` or any of its hardlinks is protected. Its data has to
+    /// stay either way, so the whole entry counts as protected.
+    pub fn covers_file(&self, file: &duplicates::FileEntry) -> bool {
+        self.covers(&file.path) || file.hardlinks.iter().any(|l| self.covers(l))
+    }
+
+    /// Whether deleting the directory `dir` would take a protected path with
+    /// it: it is protected itself or holds something that is.
+    pub fn within_tree(&self, dir: &Path) -> bool {
+        self.0.iter().any(|p| dir.starts_with(p) || p.starts_with(dir))
+    }
+}
+
+/// How `--auto` picks the survivors of each group: the first `keep_n` copies
+/// once sorted by `rules`, with the path as the final tie-break so the same
+/// database always gives the same answer. Protected copies come before
+/// everything else, so they count towards `keep_n` and are all kept even when
+/// there are more of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeepPolicy {
+    pub rules: Vec<KeepRule>,
+    pub keep_n: usize,
+}
+
+impl KeepPolicy {
+    /// The 0-based indices of the copies to keep, in ascending order. At least
+    /// one copy is always kept, even with a `keep_n` of 0.
+    pub fn keepers(
+        &self,
+        group: &duplicates::DuplicateFileGroup,
+        protected: &ProtectedPaths,
+    ) -> Vec<usize> {
+        let is_protected: Vec<bool> =
+            group.files.iter().map(|f| protected.covers_file(f)).collect();
+        let mut order: Vec<usize> = (0..group.files.len()).collect();
+        order.sort_by(|&i, &j| {
+            let (a, b) = (&group.files[i], &group.files[j]);
+            is_protected[j].cmp(&is_protected[i]).then_with(|| {
+                self.rules
+                    .iter()
+                    .map(|rule| rule.compare(a, b))
+                    .find(|o| o.is_ne())
+                    .unwrap_or_else(|| a.path.cmp(&b.path))
+            })
+        });
+        let protected_count = is_protected.iter().filter(|&&p| p).count();
+        order.truncate(self.keep_n.max(1).max(protected_count));
+        order.sort_unstable();
+        order
+    }
+
+    /// The reason logged for this policy's decisions.
+    pub fn describe(&self) -> String {
+        let mut parts: Vec<String> = self.rules.iter().map(|r| r.to_string()).collect();
+        parts.push(format!("keep-n={}", self.keep_n));
+        format!("auto: {}", parts.join(", "))
+    }
+}
+
+/// What to do with one duplicate file group, as chosen at the prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeepChoice {
+    /// Keep these copies (0-based indices, at least one) and remove the rest
+    Keep(Vec<usize>),
+    /// Keep every copy
+    KeepAll,
+    /// Leave the group alone for now
+    Skip,
+    /// Stop going through groups; removals chosen so far still go ahead
+    /// once confirmed
+    Quit,
+}
+
+impl KeepChoice {
+    /// Parse a prompt answer for a group of `count` copies: copy numbers
+    /// (1-based, separated by spaces or commas), `f` (keep the first), `a`
+    /// (keep all), `s` (skip) or `q` (quit). Returns `None` for anything else,
+    /// including numbers out of range.
+    pub fn parse(input: &str, count: usize) -> Option<Self> {
+        let input = input.trim();
+        match input.to_ascii_lowercase().as_str() {
+            "f" => return Some(Self::Keep(vec![0])),
+            "a" => return Some(Self::KeepAll),
+            "s" => return Some(Self::Skip),
+            "q" => return Some(Self::Quit),
+            _ => {}
+        }
+        let mut keep = Vec::new();
+        for part in input.split(|c: char| c == ',' || c.is_whitespace()) {
+            if part.is_empty() {
+                continue;
+            }
+            match part.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= count => {
+                    if !keep.contains(&(n - 1)) {
+                        keep.push(n - 1);
+                    }
+                }
+                _ => return None,
+            }
+        }
+        if keep.is_empty() {
+            return None;
+        }
+        keep.sort_unstable();
+        if keep.len() == count {
+            return Some(Self::KeepAll);
+        }
+        Some(Self::Keep(keep))
+    }
+}
+
+/// What to do with one duplicate group: the copies kept and the paths removed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupPlan {
+    pub hash: String,
+    pub keep: Vec<String>,
+    pub removals: Vec<Removal>,
+    /// Recorded in the dedupe log with every decision
+    pub reason: String,
+}
+
+/// A path to remove, and the surviving copy that makes removing it safe.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Removal {
+    pub path: String,
+    /// Bytes freed by removing this path: the file's size, or 0 for a
+    /// hardlink whose data goes with another path in the same removal set
+    pub size: i64,
+    pub keeper: String,
+}
+
+/// What `apply_plans` does with each redundant copy.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Action {
+    /// Delete the copy
+    #[default]
+    Delete,
+    /// Replace the copy with a hardlink to the kept file, so its path still
+    /// works (same filesystem only)
+    Hardlink,
+    /// Replace the copy with a symlink holding the kept file's absolute path
+    Symlink,
+    /// Replace the copy with a symlink holding the path to the kept file
+    /// relative to the copy's directory
+    RelativeSymlink,
+    /// Leave the copy in place but have it share the kept file's storage
+    /// (btrfs, XFS and other copy-on-write filesystems)
+    Reflink,
+}
+
+/// How `apply_plans` treats the copies it doesn't keep.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyOptions {
+    pub action: Action,
+    /// Unlink deleted copies instead of moving them to the system trash
+    pub permanent: bool,
+    /// Move deleted copies into this directory, mirroring their paths,
+    /// instead of trashing or unlinking them
+    pub quarantine: Option<PathBuf>,
+    /// Compare each copy with the kept file byte by byte before removing or
+    /// replacing it, rather than trusting equal hashes alone
+    pub verify: bool,
+}
+
+impl ApplyOptions {
+    /// The name shown in plans and recorded in the dedupe log.
+    pub fn verb(&self) -> &'static str {
+        match self.action {
+            Action::Delete if self.quarantine.is_some() => "quarantine",
+            Action::Delete if !self.permanent => "trash",
+            action => action.verb(),
+        }
+    }
+}
+
+impl Action {
+    fn verb(self) -> &'static str {
+        match self {
+            Self::Delete => "delete",
+            Self::Hardlink => "hardlink",
+            Self::Symlink | Self::RelativeSymlink => "symlink",
+            Self::Reflink => "reflink",
+        }
+    }
+}
+
+/// What `apply_plans` did.
+#[derive(Debug, Default, PartialEq)]
+pub struct DedupeStats {
+    /// Copies deleted or replaced with links
+    pub removed: usize,
+    pub freed: i64,
+    /// Removals not made because the kept copy had disappeared
+    pub keeper_missing: usize,
+    /// Paths that were already gone from disk; their rows were dropped
+    pub already_gone: usize,
+    /// Removals not made because the copy or its keeper isn't a file on this
+    /// machine's disk: merged in, inside an archive, or remote
+    pub off_disk: usize,
+    /// Copies not linked because they're on another filesystem than the kept
+    /// copy
+    pub cross_device: usize,
+    /// Copies not reflinked because their filesystem can't share extents
+    pub unsupported: usize,
+    /// Copies not reflinked because the kernel found they no longer match
+    pub differs: usize,
+    /// Planned removals refused because the path is protected
+    pub protected: usize,
+    /// Hashes of the groups left alone because a copy's bytes differ from the
+    /// kept file's despite the equal hash
+    pub mismatched_groups: Vec<String>,
+}
+
+/// The duplicate file groups with at least one copy under `scope` (every
+/// group when empty), largest first. Empty files are never offered: removing
+/// them frees nothing, and many are markers (`__init__.py`, `.gitkeep`).
+/// Copies merged in from another database are on another machine, and copies
+/// inside archives can't be removed on their own, so both are left out of the
+/// groups, which then need two local copies.
+pub fn groups_in_scope(
+    conn: &Connection,
+    scope: &[&Path],
+) -> Result<Vec<duplicates::DuplicateFileGroup>> {
+    let off_disk = db::files_off_disk(conn)?;
+    let groups = duplicates::find_duplicate_files(conn)?
+        .into_iter()
+        .filter_map(|mut g| {
+            if !off_disk.is_empty() {
+                g.files.retain(|f| !off_disk.contains(&f.path));
+                g.count = g.files.len() as i64;
+                g.total_size = g.files.iter().map(|f| f.size).sum();
+            }
+            (g.files.len() > 1).then_some(g)
+        })
+        .filter(|g| g.total_size > 0)
+        .filter(|g| {
+            scope.is_empty()
+                || g.files
+                    .iter()
+                    .any(|f| scope.iter().any(|s| utils::path_from_db(&f.path).starts_with(s)))
+        })
+        .collect();
+    Ok(groups)
+}
+
+/// The plan that keeps the copies at `keep` (0-based), and every protected
+/// copy, and drops every other copy in `group`. A dropped copy's hardlinks go
+/// with it, since the space is only freed once every name for the data is
+/// gone. With nothing to keep, nothing is removed.
+pub fn plan_group(
+    group: &duplicates::DuplicateFileGroup,
+    keep: &[usize],
+    protected: &ProtectedPaths,
+    reason: &str,
+) -> GroupPlan {
+    let mut plan = GroupPlan {
+        hash: group.hash.clone(),
+        keep: Vec::new(),
+        removals: Vec::new(),
+        reason: reason.to_string(),
+    };
+    let Some(&first_kept) = keep.first() else {
+        return plan;
+    };
+    let keeper = &group.files[first_kept].path;
+    let removals = &mut plan.removals;
+    for (i, file) in group.files.iter().enumerate() {
+        if keep.contains(&i) || protected.covers_file(file) {
+            plan.keep.push(file.path.clone());
+            plan.keep.extend(file.hardlinks.iter().cloned());
+            continue;
+        }
+        removals.push(Removal {
+            path: file.path.clone(),
+            size: file.size,
+            keeper: keeper.clone(),
+        });
+        for link in &file.hardlinks {
+            removals.push(Removal {
+                path: link.clone(),
+                size: 0,
+                keeper: keeper.clone(),
+            });
+        }
+    }
+    plan
+}
+
+/// Carry out `plans` as `opts` says. Deleting a copy, into the trash unless
+/// `opts.permanent`, drops its database row
+/// and rehashes the directories that contained it; replacing it with a link
+/// keeps the row, now pointing at the keeper's inode, and leaves directory
+/// hashes alone since no content changed. The dedupe log's `keeper` column
+/// records each link's target. A reflink changes nothing the database
+/// records, and `freed` counts the bytes the kernel reports deduplicating.
+/// Once a filesystem turns out not to support reflinks, its other copies are
+/// skipped without trying. A removal whose keeper no
+/// longer exists is skipped, so the last copy of anything is never lost, and
+/// so is one of a protected path, whatever the plan says, and one of a row
+/// that isn't on this disk (see `db::files_off_disk`). With `opts.verify`
+/// every copy about to go is first compared with its keeper in full, and if
+/// any differs the whole group is left alone: equal hashes then mean a
+/// collision or a file changed since the scan, and neither is safe to act on.
+/// Reflinks need no such check, since the kernel compares the data itself.
+/// Every decision and its outcome goes into the dedupe log.
+pub fn apply_plans(
+    conn: &Connection,
+    plans: &[GroupPlan],
+    opts: &ApplyOptions,
+    algorithm: hashing::HashAlgorithm,
+) -> Result<DedupeStats> {
+    let _dedupe = tracing::info_span!("dedupe", groups = plans.len()).entered();
+    let action = opts.action;
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let log = |path: &str, action: &str, keeper: Option<&str>, plan: &GroupPlan| {
+        db::log_dedupe(
+            conn,
+            &db::DedupeLogEntry {
+                time,
+                hash: plan.hash.clone(),
+                path: path.to_string(),
+                action: action.to_string(),
+                keeper: keeper.map(str::to_string),
+                reason: plan.reason.clone(),
+            },
+        )
+    };
+
+    let mut stats = DedupeStats::default();
+    let mut touched: Vec<PathBuf> = Vec::new();
+    let mut no_reflink: HashSet<i64> = HashSet::new();
+    let protected = ProtectedPaths::load(conn)?;
+    let off_disk = db::files_off_disk(conn)?;
+    // Whatever was changed before an error gets its directories rehashed
+    let mut apply = || -> Result<()> {
+        for plan in plans {
+            for path in &plan.keep {
+                log(path, "keep", None, plan)?;
+            }
+            if opts.verify && action != Action::Reflink && !contents_match(plan, &protected)? {
+                for removal in &plan.removals {
+                    let keeper = Some(removal.keeper.as_str());
+                    let skipped = "skipped: contents differ despite equal hashes";
+                    log(&removal.path, skipped, keeper, plan)?;
+                }
+                stats.mismatched_groups.push(plan.hash.clone());
+                continue;
+            }
+            for removal in &plan.removals {
+                let keeper = Some(removal.keeper.as_str());
+                if protected.covers(&removal.path) {
+                    stats.protected += 1;
+                    log(&removal.path, "skipped: protected", keeper, plan)?;
+                    continue;
+                }
+                if off_disk.contains(&removal.path) || off_disk.contains(&removal.keeper) {
+                    stats.off_disk += 1;
+                    log(&removal.path, "skipped: not on this disk", keeper, plan)?;
+                    continue;
+                }
+                if !utils::path_from_db(&removal.keeper).is_file() {
+                    stats.keeper_missing += 1;
+                    log(&removal.path, "skipped: kept copy missing", keeper, plan)?;
+                    continue;
+                }
+                let path = &utils::path_from_db(&removal.path);
+                let keeper_path = &utils::path_from_db(&removal.keeper);
+                if !path.is_file() {
+                    stats.already_gone += 1;
+                    log(&removal.path, "skipped: already gone", keeper, plan)?;
+                    db::remove_file(conn, path)?;
+                    touched.push(path.to_path_buf());
+                    continue;
+                }
+                let same_fs_only = matches!(action, Action::Hardlink | Action::Reflink);
+                if same_fs_only && !file_system::same_filesystem(path, keeper_path)? {
+                    stats.cross_device += 1;
+                    log(&removal.path, "skipped: different filesystem", keeper, plan)?;
+                    continue;
+                }
+                let mut freed = removal.size;
+                // Where the data is afterwards, for the actions table
+                let mut destination = keeper_path.to_path_buf();
+                match action {
+                    Action::Delete => {
+                        if let Some(dir) = &opts.quarantine {
+                            destination = file_system::quarantine_file(path, dir)?;
+                        } else if opts.permanent {
+                            file_system::delete_file(path)?;
+                        } else {
+                            file_system::trash_file(path)?;
+                        }
+                        db::remove_file(conn, path)?;
+                        touched.push(path.to_path_buf());
+                    }
+                    Action::Hardlink => {
+                        file_system::replace_with_hardlink(path, keeper_path)?;
+                        db::copy_file_record(conn, keeper_path, path)?;
+                    }
+                    Action::Symlink | Action::RelativeSymlink => {
+                        let relative = action == Action::RelativeSymlink;
+                        file_system::replace_with_symlink(path, keeper_path, relative)?;
+                        // A scan follows the link and sees the keeper's metadata
+                        db::copy_file_record(conn, keeper_path, path)?;
+                    }
+                    Action::Reflink => {
+                        let device = device_of(keeper_path)?;
+                        if device.is_some_and(|d| no_reflink.contains(&d)) {
+                            stats.unsupported += 1;
+                            log(&removal.path, "skipped: reflink unsupported", keeper, plan)?;
+                            continue;
+                        }
+                        match file_system::share_extents(keeper_path, path)? {
+                            file_system::ExtentSharing::Shared(bytes) => freed = bytes as i64,
+                            file_system::ExtentSharing::Differs => {
+                                stats.differs += 1;
+                                log(&removal.path, "skipped: contents differ", keeper, plan)?;
+                                continue;
+                            }
+                            file_system::ExtentSharing::Unsupported => {
+                                no_reflink.extend(device);
+                                stats.unsupported += 1;
+                                log(&removal.path, "skipped: reflink unsupported", keeper, plan)?;
+                                continue;
+                            }
+                        }
+                    }
+                }
+                stats.removed += 1;
+                stats.freed += freed;
+                log(&removal.path, opts.verb(), keeper, plan)?;
+                db::log_action(conn, opts.verb(), path, Some(&destination), Some(&plan.hash))?;
+            }
+        }
+        Ok(())
+    };
+    let applied = apply();
+    let rehashed = scan::rehash_ancestors(conn, &touched, algorithm);
+    applied?;
+    rehashed?;
+    Ok(stats)
+}
+
+/// Whether every copy `plan` would remove still has exactly its keeper's
+/// bytes. Copies that will be skipped anyway, because they or their keeper
+/// are gone or they are protected, aren't read.
+fn contents_match(plan: &GroupPlan, protected: &ProtectedPaths) -> Result<bool> {
+    for removal in &plan.removals {
+        let (path, keeper) = (
+            &utils::path_from_db(&removal.path),
+            &utils::path_from_db(&removal.keeper),
+        );
+        if protected.covers(&removal.path) || !path.is_file() || !keeper.is_file() {
+            continue;
+        }
+        if !file_system::same_contents(keeper, path)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn device_of(path: &Path) -> Result<Option<i64>> {
+    let metadata = std::fs::metadata(path)?;
+    Ok(utils::file_identity(&metadata).map(|(device, _)| device))
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    /// Tests unlink rather than fill the trash of whoever runs them
+    fn apply_opts(action: Action) -> ApplyOptions {
+        ApplyOptions {
+            action,
+            permanent: true,
+            verify: true,
+            ..Default::default()
+        }
+    }
+
+    fn scan_tmp(conn: &Connection, root: &Path) {
+        scan::scan_directory(conn, root, 0, &scan::ScanOptions::default(), |_, _, _| {}).unwrap();
+    }
+
+    #[test]
+    fn test_keep_choice_parse() {
+        assert_eq!(KeepChoice::parse("f", 3), Some(KeepChoice::Keep(vec![0])));
+        assert_eq!(KeepChoice::parse(" A ", 3), Some(KeepChoice::KeepAll));
+        assert_eq!(KeepChoice::parse("s", 3), Some(KeepChoice::Skip));
+        assert_eq!(KeepChoice::parse("q", 3), Some(KeepChoice::Quit));
+        assert_eq!(
+            KeepChoice::parse("3, 1", 3),
+            Some(KeepChoice::Keep(vec![0, 2]))
+        );
+        assert_eq!(KeepChoice::parse("1 2 3", 3), Some(KeepChoice::KeepAll));
+        assert_eq!(KeepChoice::parse("4", 3), None);
+        assert_eq!(KeepChoice::parse("", 3), None);
+        assert_eq!(KeepChoice::parse("x", 3), None);
+    }
+
+    #[test]
+    fn test_removals_for_takes_hardlinks_along() {
+        let group = duplicates::DuplicateFileGroup {
+            hash: "h".into(),
+            count: 2,
+            total_size: 20,
+            files: vec![
+                duplicates::FileEntry {
+                    path: "/a".into(),
+                    size: 10,
+                    modified: 0,
+                    hardlinks: vec![],
+                    via_link: false,
+                },
+                duplicates::FileEntry {
+                    path: "/b".into(),
+                    size: 10,
+                    modified: 0,
+                    hardlinks: vec!["/b2".into()],
+                    via_link: false,
+                },
+            ],
+        };
+        let plan = plan_group(&group, &[0], &ProtectedPaths::default(), "interactive");
+        let paths: Vec<(&str, i64, &str)> = plan
+            .removals
+            .iter()
+            .map(|r| (r.path.as_str(), r.size, r.keeper.as_str()))
+            .collect();
+        assert_eq!(paths, vec![("/b", 10, "/a"), ("/b2", 0, "/a")]);
+        assert_eq!(plan.keep, vec!["/a"]);
+        let nothing_kept = plan_group(&group, &[], &ProtectedPaths::default(), "interactive");
+        assert!(nothing_kept.removals.is_empty());
+    }
+
+    #[test]
+    fn test_apply_removals_deletes_and_rehashes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir(root.join("x")).unwrap();
+        fs::write(root.join("a.txt"), b"same").unwrap();
+        fs::write(root.join("x/b.txt"), b"same").unwrap();
+        let conn = open_test_db();
+        scan_tmp(&conn, root);
+
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        assert_eq!(groups.len(), 1);
+        let plan = plan_group(&groups[0], &[0], &ProtectedPaths::default(), "interactive");
+        let stats = apply_plans(
+            &conn,
+            &[plan],
+            &apply_opts(Action::Delete),
+            hashing::HashAlgorithm::default(),
+        )
+        .unwrap();
+        assert_eq!(stats.removed, 1);
+        assert_eq!(stats.freed, 4);
+        assert!(root.join("a.txt").exists());
+        assert!(!root.join("x/b.txt").exists());
+        assert!(groups_in_scope(&conn, &[]).unwrap().is_empty());
+
+        // The emptied directory now hashes like a fresh scan of it would
+        let stored = db::get_directory(&conn, &root.join("x")).unwrap().unwrap();
+        let fresh = open_test_db();
+        scan_tmp(&fresh, root);
+        let rescanned = db::get_directory(&fresh, &root.join("x")).unwrap().unwrap();
+        assert_eq!(stored.hash, rescanned.hash);
+    }
+
+    #[test]
+    fn test_apply_plans_rehashes_what_it_changed_before_an_error() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir(root.join("x")).unwrap();
+        fs::write(root.join("a.txt"), b"same").unwrap();
+        fs::write(root.join("x/b.txt"), b"same").unwrap();
+        let conn = open_test_db();
+        scan_tmp(&conn, root);
+
+        // Recording the deletion fails once the file is gone
+        conn.execute_batch("DROP TABLE actions").unwrap();
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        let plan = plan_group(&groups[0], &[0], &ProtectedPaths::default(), "interactive");
+        let opts = apply_opts(Action::Delete);
+        assert!(apply_plans(&conn, &[plan], &opts, hashing::HashAlgorithm::default()).is_err());
+        assert!(!root.join("x/b.txt").exists());
+
+        let stored = db::get_directory(&conn, &root.join("x")).unwrap().unwrap();
+        let fresh = open_test_db();
+        scan_tmp(&fresh, root);
+        let rescanned = db::get_directory(&fresh, &root.join("x")).unwrap().unwrap();
+        assert_eq!(stored.hash, rescanned.hash);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_plans_hardlink_keeps_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), b"same").unwrap();
+        fs::write(root.join("b.txt"), b"same").unwrap();
+        let conn = open_test_db();
+        scan_tmp(&conn, root);
+        let dir_hash = db::get_directory(&conn, root).unwrap().unwrap().hash;
+
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        let plan = plan_group(&groups[0], &[0], &ProtectedPaths::default(), "interactive");
+        let algorithm = hashing::HashAlgorithm::default();
+        let stats = apply_plans(&conn, &[plan], &apply_opts(Action::Hardlink), algorithm).unwrap();
+        assert_eq!((stats.removed, stats.freed), (1, 4));
+        assert_eq!(fs::read(root.join("b.txt")).unwrap(), b"same");
+
+        // Both rows remain, now as one file with a hardlink
+        let groups = duplicates::find_duplicate_files(&conn).unwrap();
+        assert!(groups.is_empty());
+        let a = db::get_file(&conn, &root.join("a.txt")).unwrap().unwrap();
+        let b = db::get_file(&conn, &root.join("b.txt")).unwrap().unwrap();
+        assert_eq!((a.device, a.inode), (b.device, b.inode));
+        assert_eq!(
+            db::get_directory(&conn, root).unwrap().unwrap().hash,
+            dir_hash
+        );
+        let log = db::dedupe_log(&conn).unwrap();
+        assert_eq!(log[1].action, "hardlink");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_apply_plans_symlink_crosses_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir(root.join("x")).unwrap();
+        fs::write(root.join("a.txt"), b"same").unwrap();
+        fs::write(root.join("x/b.txt"), b"same").unwrap();
+        let conn = open_test_db();
+        scan_tmp(&conn, root);
+
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        let plan = plan_group(&groups[0], &[0], &ProtectedPaths::default(), "interactive");
+        let algorithm = hashing::HashAlgorithm::default();
+        let action = Action::RelativeSymlink;
+        let stats = apply_plans(&conn, &[plan], &apply_opts(action), algorithm).unwrap();
+        assert_eq!(stats.removed, 1);
+        let link = root.join("x/b.txt");
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("../a.txt"));
+        assert!(duplicates::find_duplicate_files(&conn).unwrap().is_empty());
+
+        // A rescan sees the link exactly as recorded
+        let rescanned = open_test_db();
+        scan_tmp(&rescanned, root);
+        let stored = db::get_file(&conn, &link).unwrap().unwrap();
+        let fresh = db::get_file(&rescanned, &link).unwrap().unwrap();
+        assert_eq!(
+            (stored.hash, stored.modified, stored.inode),
+            (fresh.hash, fresh.modified, fresh.inode)
+        );
+        let log = db::dedupe_log(&conn).unwrap();
+        assert_eq!(log[1].action, "symlink");
+        assert_eq!(log[1].keeper.as_deref(), root.join("a.txt").to_str());
+    }
+
+    #[test]
+    fn test_apply_plans_reflink_leaves_paths_and_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(root.join(name), b"same").unwrap();
+        }
+        let conn = open_test_db();
+        scan_tmp(&conn, root);
+
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        let plan = plan_group(&groups[0], &[0], &ProtectedPaths::default(), "interactive");
+        let algorithm = hashing::HashAlgorithm::default();
+        let stats = apply_plans(&conn, &[plan], &apply_opts(Action::Reflink), algorithm).unwrap();
+        // Which of the two happens depends on the filesystem under /tmp
+        assert_eq!(stats.removed + stats.unsupported, 2);
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            assert_eq!(fs::read(root.join(name)).unwrap(), b"same");
+        }
+        assert_eq!(groups_in_scope(&conn, &[]).unwrap()[0].count, 3);
+    }
+
+    #[test]
+    fn test_apply_removals_never_deletes_last_copy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), b"same").unwrap();
+        fs::write(root.join("b.txt"), b"same").unwrap();
+        let conn = open_test_db();
+        scan_tmp(&conn, root);
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        let plan = plan_group(&groups[0], &[0], &ProtectedPaths::default(), "interactive");
+        fs::remove_file(&plan.removals[0].keeper).unwrap();
+
+        let algorithm = hashing::HashAlgorithm::default();
+        let stats = apply_plans(
+            &conn,
+            std::slice::from_ref(&plan),
+            &apply_opts(Action::Delete),
+            algorithm,
+        )
+        .unwrap();
+        assert_eq!(stats.keeper_missing, 1);
+        assert!(Path::new(&plan.removals[0].path).exists());
+    }
+
+    #[test]
+    fn test_apply_plans_leaves_rows_off_this_disk_alone() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), b"same").unwrap();
+        fs::write(root.join("b.txt"), b"same").unwrap();
+        let conn = open_test_db();
+        scan_tmp(&conn, root);
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        let plan = plan_group(&groups[0], &[0], &ProtectedPaths::default(), "imported");
+        // As if the row had been listed on another machine since the plan
+        // was written; its path then names nothing here
+        conn.execute(
+            "UPDATE files SET remote = 'ssh://nas' WHERE path = ?1",
+            [&plan.removals[0].path],
+        )
+        .unwrap();
+
+        let algorithm = hashing::HashAlgorithm::default();
+        let stats = apply_plans(
+            &conn,
+            std::slice::from_ref(&plan),
+            &apply_opts(Action::Delete),
+            algorithm,
+        )
+        .unwrap();
+        assert_eq!((stats.off_disk, stats.removed), (1, 0));
+        assert!(Path::new(&plan.removals[0].path).exists());
+        assert!(db::get_file(&conn, Path::new(&plan.removals[0].path)).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_apply_plans_logs_every_decision() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), b"same").unwrap();
+        fs::write(root.join("b.txt"), b"same").unwrap();
+        let conn = open_test_db();
+        scan_tmp(&conn, root);
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        let plan = plan_group(&groups[0], &[0], &ProtectedPaths::default(), "auto: keep-n=1");
+        apply_plans(
+            &conn,
+            &[plan],
+            &apply_opts(Action::Delete),
+            hashing::HashAlgorithm::default(),
+        )
+        .unwrap();
+
+        let log = db::dedupe_log(&conn).unwrap();
+        let actions: Vec<(&str, &str)> = log
+            .iter()
+            .map(|e| (e.action.as_str(), e.reason.as_str()))
+            .collect();
+        assert_eq!(
+            actions,
+            vec![("keep", "auto: keep-n=1"), ("delete", "auto: keep-n=1")]
+        );
+        assert_eq!(log[1].keeper.as_deref(), Some(log[0].path.as_str()));
+    }
+
+    fn entry(path: &str, modified: i64) -> duplicates::FileEntry {
+        duplicates::FileEntry {
+            path: path.into(),
+            size: 1,
+            modified,
+            hardlinks: vec![],
+            via_link: false,
+        }
+    }
+
+    fn group_of(files: Vec<duplicates::FileEntry>) -> duplicates::DuplicateFileGroup {
+        duplicates::DuplicateFileGroup {
+            hash: "h".into(),
+            count: files.len() as i64,
+            total_size: files.len() as i64,
+            files,
+        }
+    }
+
+    #[test]
+    fn test_apply_options_verb() {
+        assert_eq!(ApplyOptions::default().verb(), "trash");
+        assert_eq!(apply_opts(Action::Delete).verb(), "delete");
+        let link = ApplyOptions {
+            action: Action::RelativeSymlink,
+            ..Default::default()
+        };
+        assert_eq!(link.verb(), "symlink");
+    }
+
+    #[test]
+    fn test_keep_rule_parse_and_display() {
+        for s in [
+            "keep-newest",
+            "keep-oldest",
+            "keep-shortest-path",
+            "prefer-path=/a",
+        ] {
+            assert_eq!(s.parse::<KeepRule>().unwrap().to_string(), s);
+        }
+        assert!("keep-biggest".parse::<KeepRule>().is_err());
+        assert!("prefer-path=".parse::<KeepRule>().is_err());
+    }
+
+    #[test]
+    fn test_keep_policy_rules_break_ties_in_order() {
+        let group = group_of(vec![
+            entry("/backup/long/name.jpg", 300),
+            entry("/photos/a.jpg", 100),
+            entry("/photos/deeper/a.jpg", 300),
+        ]);
+        let policy = |rules: Vec<KeepRule>| KeepPolicy { rules, keep_n: 1 };
+        let none = ProtectedPaths::default();
+        assert_eq!(policy(vec![KeepRule::Oldest]).keepers(&group, &none), vec![1]);
+        assert_eq!(policy(vec![KeepRule::ShortestPath]).keepers(&group, &none), vec![1]);
+        // Two copies tie on newest; the path decides between them
+        assert_eq!(policy(vec![KeepRule::Newest]).keepers(&group, &none), vec![0]);
+        let photos_newest = vec![KeepRule::PreferPath("/photos".into()), KeepRule::Newest];
+        assert_eq!(policy(photos_newest).keepers(&group, &none), vec![2]);
+    }
+
+    #[test]
+    fn test_keep_policy_keep_n() {
+        let group = group_of(vec![entry("/c", 1), entry("/a", 3), entry("/b", 2)]);
+        let policy = KeepPolicy {
+            rules: vec![KeepRule::Newest],
+            keep_n: 2,
+        };
+        let none = ProtectedPaths::default();
+        assert_eq!(policy.keepers(&group, &none), vec![1, 2]);
+        assert_eq!(policy.describe(), "auto: keep-newest, keep-n=2");
+        let all = KeepPolicy {
+            rules: vec![],
+            keep_n: 5,
+        };
+        assert_eq!(all.keepers(&group, &none), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_protected_copies_always_survive() {
+        let group = group_of(vec![
+            entry("/originals/a.jpg", 1),
+            entry("/originals/b.jpg", 2),
+            entry("/tmp/c.jpg", 3),
+        ]);
+        let protected = ProtectedPaths::new(vec!["/originals".into()]);
+        let newest = KeepPolicy {
+            rules: vec![KeepRule::Newest],
+            keep_n: 1,
+        };
+        // Both protected copies beat the newest one, and keep-n=1 can't drop either
+        assert_eq!(newest.keepers(&group, &protected), vec![0, 1]);
+
+        // Choosing only the unprotected copy at the prompt still keeps the others
+        let plan = plan_group(&group, &[2], &protected, "interactive");
+        assert_eq!(plan.keep, vec!["/originals/a.jpg", "/originals/b.jpg", "/tmp/c.jpg"]);
+        assert!(plan.removals.is_empty());
+
+        let mut linked = entry("/tmp/d.jpg", 0);
+        linked.hardlinks = vec!["/originals/d.jpg".into()];
+        assert!(protected.covers_file(&linked));
+        assert!(protected.within_tree(Path::new("/")));
+        assert!(!protected.within_tree(Path::new("/tmp")));
+    }
+
+    #[test]
+    fn test_apply_plans_verify_leaves_mismatched_group_alone() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            fs::write(root.join(name), b"same").unwrap();
+        }
+        let conn = open_test_db();
+        scan_tmp(&conn, root);
+        // Same size and stored hash, different bytes
+        fs::write(root.join("c.txt"), b"sane").unwrap();
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        let plan = plan_group(&groups[0], &[0], &ProtectedPaths::default(), "interactive");
+
+        let algorithm = hashing::HashAlgorithm::default();
+        let opts = apply_opts(Action::Delete);
+        let stats = apply_plans(&conn, std::slice::from_ref(&plan), &opts, algorithm).unwrap();
+        assert_eq!(stats.mismatched_groups, vec![plan.hash.clone()]);
+        assert_eq!(stats.removed, 0);
+        // b.txt matches, but the whole group is left alone
+        assert!(root.join("b.txt").exists() && root.join("c.txt").exists());
+
+        let trusting = ApplyOptions {
+            verify: false,
+            ..opts
+        };
+        let stats = apply_plans(&conn, &[plan], &trusting, algorithm).unwrap();
+        assert_eq!(stats.removed, 2);
+    }
+
+    #[test]
+    fn test_apply_plans_quarantine_mirrors_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("tree");
+        fs::create_dir_all(root.join("x")).unwrap();
+        fs::write(root.join("a.txt"), b"same").unwrap();
+        fs::write(root.join("x/b.txt"), b"same").unwrap();
+        let conn = open_test_db();
+        scan_tmp(&conn, &root);
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        let plan = plan_group(&groups[0], &[0], &ProtectedPaths::default(), "interactive");
+
+        let quarantine = tmp.path().join("quarantine");
+        let opts = ApplyOptions {
+            quarantine: Some(quarantine.clone()),
+            ..Default::default()
+        };
+        assert_eq!(opts.verb(), "quarantine");
+        let stats = apply_plans(&conn, &[plan], &opts, hashing::HashAlgorithm::default()).unwrap();
+        assert_eq!(stats.removed, 1);
+        let parked = file_system::mirrored_path(&quarantine, &root.join("x/b.txt")).unwrap();
+        assert_eq!(fs::read(&parked).unwrap(), b"same");
+        assert!(!root.join("x/b.txt").exists());
+        assert!(groups_in_scope(&conn, &[]).unwrap().is_empty());
+
+        let logged = &db::pending_actions(&conn, None, None).unwrap()[0];
+        assert_eq!(logged.action, "quarantine");
+        assert_eq!(logged.destination.as_deref(), parked.to_str());
+    }
+
+    #[test]
+    fn test_apply_plans_refuses_protected_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::write(root.join("a.txt"), b"same").unwrap();
+        fs::write(root.join("b.txt"), b"same").unwrap();
+        let conn = open_test_db();
+        scan_tmp(&conn, root);
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        // Planned before the path was protected
+        let plan = plan_group(&groups[0], &[0], &ProtectedPaths::default(), "interactive");
+        db::add_protected(&conn, &root.join("b.txt")).unwrap();
+
+        let stats = apply_plans(
+            &conn,
+            &[plan],
+            &apply_opts(Action::Delete),
+            hashing::HashAlgorithm::default(),
+        )
+        .unwrap();
+        assert_eq!(stats.protected, 1);
+        assert_eq!(stats.removed, 0);
+        assert!(root.join("b.txt").exists());
+    }
+
+    #[test]
+    fn test_groups_in_scope() {
+        let conn = open_test_db();
+        for path in ["/a/1", "/b/1", "/c/2", "/d/2"] {
+            let hash = if path.ends_with('1') { "h1" } else { "h2" };
+            db::upsert_file(&conn, Path::new(path), hash, 1, 0).unwrap();
+        }
+        let groups = groups_in_scope(&conn, &[Path::new("/c")]).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].hash, "h2");
+    }
+
+    #[test]
+    fn test_groups_in_scope_skips_empty_files() {
+        let conn = open_test_db();
+        for path in ["/a/__init__.py", "/b/__init__.py"] {
+            db::upsert_file(&conn, Path::new(path), "h_empty", 0, 0).unwrap();
+        }
+        assert!(groups_in_scope(&conn, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_groups_in_scope_leaves_out_merged_copies() {
+        let conn = open_test_db();
+        db::upsert_file(&conn, Path::new("/a/x"), "h", 10, 0).unwrap();
+        db::upsert_file(&conn, Path::new("/b/x"), "h", 10, 0).unwrap();
+        db::upsert_file(&conn, Path::new("/c/y"), "h2", 10, 0).unwrap();
+        let remote = db::FileRecord {
+            path: String::new(),
+            hash: String::new(),
+            size: 10,
+            modified: 0,
+            partial_hash: None,
+            device: None,
+            inode: None,
+            via_link: false,
+            stat: None,
+        };
+        for (path, hash) in [("nas:/a/x", "h"), ("nas:/c/y", "h2")] {
+            let file = db::FileRecord {
+                hash: hash.to_string(),
+                ..remote.clone()
+            };
+            db::insert_merged_file(&conn, path, &file, "nas").unwrap();
+        }
+        // The local pair stays a group without its remote copy; the local
+        // file whose only copy is remote isn't offered at all
+        let groups = groups_in_scope(&conn, &[]).unwrap();
+        assert_eq!(groups.len(), 1);
+        let paths: Vec<&str> = groups[0].files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec!["/a/x", "/b/x"]);
+        assert_eq!(groups[0].count, 2);
+    }
+}
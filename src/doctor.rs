@@ -0,0 +1,192 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use rusqlite::Connection;
+
+use crate::db;
+use crate::hashing::{self, HashAlgorithm};
+
+/// A row whose hash can't have been made with the database's algorithm.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForeignHash {
+    pub path: String,
+    pub hash: String,
+    /// The algorithm the hash looks like it came from, when that can be told
+    pub looks_like: Option<HashAlgorithm>,
+}
+
+/// What `check` found.
+pub struct Health {
+    /// What SQLite's integrity check reports wrong
+    pub integrity_errors: Vec<String>,
+    /// Directories recorded as holding data with no file rows under them,
+    /// whose hash nothing in the database can account for any more
+    pub orphaned_directories: Vec<String>,
+    /// The algorithm the database records; `None` before anything is hashed
+    pub algorithm: Option<HashAlgorithm>,
+    pub foreign_hashes: Vec<ForeignHash>,
+    pub storage: db::Storage,
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        self.integrity_errors.is_empty()
+            && self.orphaned_directories.is_empty()
+            && self.foreign_hashes.is_empty()
+    }
+}
+
+/// Look the database over for corruption and for rows that no longer add
+/// up. Nothing is changed.
+pub fn check(conn: &Connection) -> Result<Health> {
+    let integrity_errors = db::integrity_check(conn)?;
+    let algorithm = hashing::recorded_algorithm(conn)?;
+    let files = db::all_files(conn)?;
+    let directories = db::all_directories(conn)?;
+
+    let file_paths: Vec<&str> = files.iter().map(|f| f.path.as_str()).collect();
+    let sep = std::path::MAIN_SEPARATOR;
+    let orphaned_directories = directories
+        .iter()
+        .filter(|d| d.size > 0)
+        .filter(|d| {
+            let prefix = format!("{}{sep}", d.path.trim_end_matches(sep));
+            let first = file_paths.partition_point(|p| *p < prefix.as_str());
+            !file_paths
+                .get(first)
+                .is_some_and(|p| p.starts_with(&prefix))
+        })
+        .map(|d| d.path.clone())
+        .collect();
+
+    let rows = files
+        .iter()
+        .map(|f| (&f.path, &f.hash))
+        .chain(directories.iter().map(|d| (&d.path, &d.hash)));
+    let foreign_hashes = match algorithm {
+        Some(algorithm) => rows
+            .filter_map(|(path, hash)| {
+                let looks_like = foreign(algorithm, hash)?;
+                Some(ForeignHash {
+                    path: path.clone(),
+                    hash: hash.clone(),
+                    looks_like,
+                })
+            })
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Ok(Health {
+        integrity_errors,
+        orphaned_directories,
+        algorithm,
+        foreign_hashes,
+        storage: db::storage(conn)?,
+    })
+}
+
+/// `Some` if `hash` can't be an `algorithm` hash, with the algorithm it
+/// looks like if only one fits. A hash of the wrong length is foreign, and
+/// so is another algorithm's hash of nothing, which every empty file and
+/// directory has.
+fn foreign(algorithm: HashAlgorithm, hash: &str) -> Option<Option<HashAlgorithm>> {
+    if hashing::is_provisional(hash) {
+        return None;
+    }
+    let others = HashAlgorithm::value_variants()
+        .iter()
+        .copied()
+        .filter(|&a| a != algorithm);
+    if let Some(other) = others.clone().find(|a| a.empty_hash() == hash) {
+        return Some(Some(other));
+    }
+    let expected = algorithm.empty_hash().len();
+    if hash.len() == expected && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut same_length = others.filter(|a| a.empty_hash().len() == hash.len());
+    match (same_length.next(), same_length.next()) {
+        (Some(only), None) => Some(Some(only)),
+        _ => Some(None),
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn setup() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        hashing::resolve_algorithm(&conn, Some(HashAlgorithm::Blake3)).unwrap();
+        conn
+    }
+
+    /// A hash the shape of a BLAKE3 one.
+    fn hash(n: u8) -> String {
+        format!("{n:064x}")
+    }
+
+    #[test]
+    fn test_check_passes_a_consistent_database() {
+        let conn = setup();
+        db::upsert_file(&conn, Path::new("/a/x"), &hash(1), 1, 0).unwrap();
+        db::upsert_directory(&conn, Path::new("/a"), &hash(2), 1).unwrap();
+        let empty = HashAlgorithm::Blake3.empty_hash();
+        db::upsert_directory(&conn, Path::new("/empty"), &empty, 0).unwrap();
+
+        let health = check(&conn).unwrap();
+        assert!(health.is_healthy());
+        assert_eq!(health.algorithm, Some(HashAlgorithm::Blake3));
+        assert!(health.storage.bytes > 0);
+        let files = health.storage.tables.iter().find(|(t, _)| t == "files");
+        assert_eq!(files, Some(&("files".to_string(), 1)));
+    }
+
+    #[test]
+    fn test_check_finds_directories_whose_files_are_gone() {
+        let conn = setup();
+        db::upsert_file(&conn, Path::new("/a/x"), &hash(1), 1, 0).unwrap();
+        for dir in ["/a", "/ab", "/b"] {
+            db::upsert_directory(&conn, Path::new(dir), &hash(2), 1).unwrap();
+        }
+        let health = check(&conn).unwrap();
+        // /a/x isn't under /ab
+        assert_eq!(health.orphaned_directories, vec!["/ab", "/b"]);
+        assert!(!health.is_healthy());
+    }
+
+    #[test]
+    fn test_check_finds_hashes_from_another_algorithm() {
+        let conn = setup();
+        let sha_empty = HashAlgorithm::Sha256.empty_hash();
+        db::upsert_file(&conn, Path::new("/empty"), &sha_empty, 0, 0).unwrap();
+        db::upsert_file(&conn, Path::new("/short"), &"a".repeat(32), 5, 0).unwrap();
+        db::upsert_file(&conn, Path::new("/ok"), &hash(3), 2, 0).unwrap();
+        let provisional = format!("{}x", hashing::PROVISIONAL_PREFIX);
+        db::upsert_file(&conn, Path::new("/unhashed"), &provisional, 2, 0).unwrap();
+
+        let health = check(&conn).unwrap();
+        let found: Vec<(&str, Option<HashAlgorithm>)> = health
+            .foreign_hashes
+            .iter()
+            .map(|f| (f.path.as_str(), f.looks_like))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("/empty", Some(HashAlgorithm::Sha256)),
+                ("/short", Some(HashAlgorithm::Xxh3)),
+            ]
+        );
+    }
+}
@@ -1,10 +1,11 @@
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
 
 use anyhow::Result;
 use rusqlite::Connection;
 
-use crate::db;
+use crate::ignore_rules::IgnoreRules;
+use crate::{db, utils};
 
 /// A single directory instance that is a member of a duplicate group.
 pub struct DirEntry {
@@ -16,6 +17,13 @@ pub struct DirEntry {
 pub struct FileEntry {
     pub path: String,
     pub size: i64,
+    /// Unix timestamp of last modification
+    pub modified: i64,
+    /// Other paths that are hardlinks to this same file. They share its data,
+    /// so they are not duplicates of it and deleting them frees nothing.
+    pub hardlinks: Vec<String>,
+    /// The scan reached `path` through a symlink (`--follow-symlinks`).
+    pub via_link: bool,
 }
 
 /// A set of directories that all share the same hash, along with aggregate metadata.
@@ -26,6 +34,7 @@ pub struct DuplicateDirGroup {
 }
 
 /// A group of files that share the same hash (i.e. exact duplicates).
+/// `count` and `total_size` cover distinct copies; hardlinks are not counted.
 pub struct DuplicateFileGroup {
     pub hash: String,
     pub count: i64,
@@ -33,27 +42,158 @@ pub struct DuplicateFileGroup {
     pub files: Vec<FileEntry>,
 }
 
+impl DuplicateDirGroup {
+    /// Bytes that deleting every copy but one would free.
+    pub fn wasted(&self) -> i64 {
+        self.max_size * (self.members.len() as i64 - 1)
+    }
+}
+
+impl DuplicateFileGroup {
+    /// Bytes that deleting every copy but one would free.
+    pub fn wasted(&self) -> i64 {
+        self.files.first().map_or(0, |f| self.total_size - f.size)
+    }
+}
+
+/// Which duplicate groups a report shows.
+#[derive(Debug, Clone, Copy)]
+pub struct ReportFilter {
+    /// Minimum number of copies in a group.
+    pub min_count: usize,
+    /// Minimum bytes the group wastes (see `wasted`).
+    pub min_wasted: i64,
+    /// Keep groups of empty files, and of directories holding no data. Every
+    /// empty file shares one hash, so they are left out unless asked for.
+    /// Whether directory hashes count empty files is kept with the database
+    /// (see `scan::count_empty_files`).
+    pub include_empty: bool,
+    /// Only show the top-most duplicates: leave out file groups that a
+    /// reported directory group already accounts for (see
+    /// `split_covered_file_groups`).
+    pub top_level: bool,
+}
+
+impl Default for ReportFilter {
+    fn default() -> Self {
+        ReportFilter {
+            min_count: 2,
+            min_wasted: 0,
+            include_empty: false,
+            top_level: false,
+        }
+    }
+}
+
+impl ReportFilter {
+    pub fn keeps_files(&self, group: &DuplicateFileGroup) -> bool {
+        group.files.len() >= self.min_count
+            && group.wasted() >= self.min_wasted
+            && (self.include_empty || group.total_size > 0)
+    }
+
+    pub fn keeps_dirs(&self, group: &DuplicateDirGroup) -> bool {
+        group.members.len() >= self.min_count
+            && group.wasted() >= self.min_wasted
+            && (self.include_empty || group.max_size > 0)
+    }
+}
+
+/// Groups of files with the same hash, largest first. Paths that are hardlinks
+/// to one another are folded into a single entry, and a group left with only
+/// one real copy is not reported. Files and hashes the ignore rules match are
+/// left out.
 pub fn find_duplicate_files(conn: &Connection) -> Result<Vec<DuplicateFileGroup>> {
     let groups = db::duplicate_file_groups(conn)?;
+    let ignored = IgnoreRules::load(conn)?;
     let mut result = Vec::new();
     for group in groups {
-        let files = db::files_with_hash(conn, &group.hash)?
-            .into_iter()
-            .map(|r| FileEntry { path: r.path, size: r.size })
-            .collect();
+        if ignored.ignores_hash(&group.hash) {
+            continue;
+        }
+        let mut records = db::files_with_hash(conn, &group.hash)?;
+        records.retain(|r| !ignored.ignores_path(&r.path));
+        let files = collapse_hardlinks(records);
+        if files.len() < 2 {
+            continue;
+        }
         result.push(DuplicateFileGroup {
             hash: group.hash,
-            count: group.count,
-            total_size: group.size,
+            count: files.len() as i64,
+            total_size: files.iter().map(|f| f.size).sum(),
             files,
         });
     }
+    // Folding hardlinks can shrink a group, so restore the largest-first order
+    result.sort_by_key(|g| std::cmp::Reverse(g.total_size));
     Ok(result)
 }
 
+/// Split `file_groups` into the ones to show and the ones `dir_groups`
+/// already account for: every copy sits inside a different member of the
+/// same directory group, so keeping one copy of the directory keeps exactly
+/// one copy of the file. A group with copies outside the directories, under
+/// two directory groups, or twice inside one member still has duplicates
+/// of its own and is shown. Returns `(shown, covered)`, each in the order
+/// given.
+pub fn split_covered_file_groups(
+    file_groups: Vec<DuplicateFileGroup>,
+    dir_groups: &[DuplicateDirGroup],
+) -> (Vec<DuplicateFileGroup>, Vec<DuplicateFileGroup>) {
+    let member_of: HashMap<&str, usize> = dir_groups
+        .iter()
+        .enumerate()
+        .flat_map(|(i, g)| g.members.iter().map(move |m| (m.path.as_str(), i)))
+        .collect();
+    // The innermost directory member a file is in, and that member's group
+    let enclosing = |path: &str| -> Option<(String, usize)> {
+        Path::new(path).ancestors().skip(1).find_map(|dir| {
+            let dir = dir.to_string_lossy();
+            member_of.get(dir.as_ref()).map(|&i| (dir.into_owned(), i))
+        })
+    };
+    file_groups.into_iter().partition(|group| {
+        let mut members = HashSet::new();
+        let mut groups = HashSet::new();
+        let covered = group.files.iter().all(|f| match enclosing(&f.path) {
+            Some((member, i)) => {
+                groups.insert(i);
+                members.insert(member)
+            }
+            None => false,
+        });
+        !(covered && groups.len() == 1)
+    })
+}
+
+/// Fold records sharing a device and inode into one entry (the first path,
+/// in the order given). Records without an identity are always kept apart.
+fn collapse_hardlinks(records: Vec<db::FileRecord>) -> Vec<FileEntry> {
+    let mut files: Vec<FileEntry> = Vec::new();
+    let mut by_identity: HashMap<(i64, i64), usize> = HashMap::new();
+    for record in records {
+        if let (Some(device), Some(inode)) = (record.device, record.inode) {
+            if let Some(&index) = by_identity.get(&(device, inode)) {
+                files[index].hardlinks.push(record.path);
+                continue;
+            }
+            by_identity.insert((device, inode), files.len());
+        }
+        files.push(FileEntry {
+            path: record.path,
+            size: record.size,
+            modified: record.modified,
+            hardlinks: Vec::new(),
+            via_link: record.via_link,
+        });
+    }
+    files
+}
+
 /// From a list of duplicate directory groups, fetch paths for each group,
-/// filter to only members under `scanned_dirs` (if any), drop groups with
-/// fewer than 2 remaining members, and then partition into top-level groups
+/// filter to only members under `scanned_dirs` (if any are given) that no
+/// ignore rule matches, drop groups with fewer than 2 remaining members, and
+/// then partition into top-level groups
 /// (those not entirely contained within another duplicate group) vs. covered
 /// sub-groups (which will be skipped to avoid double-deletion).
 /// Returns `(top_level, covered_count)`.
@@ -63,8 +203,12 @@ pub fn build_top_level_groups(
     scanned_dirs: &[&Path],
 ) -> Result<(Vec<DuplicateDirGroup>, usize)> {
     let mut groups: Vec<DuplicateDirGroup> = Vec::new();
+    let ignored = IgnoreRules::load(conn)?;
 
     for group in duplicate_group_hashes {
+        if ignored.ignores_hash(&group.hash) {
+            continue;
+        }
         // find dirs with matching hash
         let all_dirs_with_hash: Vec<DirEntry> = db::directories_with_hash(conn, &group.hash)?
             .into_iter()
@@ -78,9 +222,11 @@ pub fn build_top_level_groups(
         let members: Vec<DirEntry> = all_dirs_with_hash
             .into_iter()
             .filter(|e| {
-                let candidate = Path::new(&e.path);
-                scanned_dirs.iter().any(|root| candidate.starts_with(root))
+                let candidate = &utils::path_from_db(&e.path);
+                scanned_dirs.is_empty()
+                    || scanned_dirs.iter().any(|root| candidate.starts_with(root))
             })
+            .filter(|e| !ignored.ignores_path(&e.path))
             .collect();
 
         // drop groups with fewer than 2 members after filtering
@@ -198,10 +344,47 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_find_duplicate_files_leaves_out_what_ignore_rules_match() {
+        let conn = open_test_db();
+        insert_file(&conn, "/etc/a.conf", "conf", 10);
+        insert_file(&conn, "/srv/mirror/etc/a.conf", "conf", 10);
+        insert_file(&conn, "/backup/a.conf", "conf", 10);
+        insert_file(&conn, "/x/lib.dll", "dll", 20);
+        insert_file(&conn, "/y/lib.dll", "dll", 20);
+        insert_file(&conn, "/p/one.jpg", "0123456789abcdef", 30);
+        insert_file(&conn, "/q/one.jpg", "0123456789abcdef", 30);
+        for (kind, pattern) in [("path", "/srv/mirror"), ("glob", "*.dll"), ("hash", "01234567")] {
+            db::add_ignore_rule(&conn, kind, pattern).unwrap();
+        }
+
+        let groups = find_duplicate_files(&conn).unwrap();
+        assert_eq!(groups.len(), 1);
+        let paths: Vec<&str> = groups[0].files.iter().map(|f| f.path.as_str()).collect();
+        // The mirrored copy is left out, the other two are still duplicates
+        assert_eq!(paths, vec!["/backup/a.conf", "/etc/a.conf"]);
+        assert_eq!(groups[0].total_size, 20);
+    }
+
     // -----------------------------------------------------------------------
     // build_top_level_groups — tests the filtering and covered-group logic
     // -----------------------------------------------------------------------
 
+    #[test]
+    fn test_build_top_level_groups_leaves_out_ignored_directories() {
+        let conn = open_test_db();
+        insert_dir(&conn, "/etc", "etc", 1024);
+        insert_dir(&conn, "/srv/mirror/etc", "etc", 1024);
+        insert_dir(&conn, "/a/photos", "photos", 1024);
+        insert_dir(&conn, "/b/photos", "photos", 1024);
+        db::add_ignore_rule(&conn, "path", "/srv/mirror").unwrap();
+
+        let groups = db::duplicate_directory_groups(&conn).unwrap();
+        let (top_level, _) = build_top_level_groups(&conn, &groups, &[]).unwrap();
+        let hashes: Vec<&str> = top_level.iter().map(|g| g.hash.as_str()).collect();
+        assert_eq!(hashes, vec!["photos"]);
+    }
+
     #[test]
     fn test_build_top_level_groups_basic() {
         let conn = open_test_db();
@@ -293,4 +476,192 @@ mod tests {
         assert_eq!(groups[0].count, 2);
         assert_eq!(groups[0].files.len(), 2);
     }
+
+    #[test]
+    fn test_build_top_level_groups_no_scope_keeps_everything() {
+        let conn = open_test_db();
+        insert_dir(&conn, "/scanned/photos", "hash1", 1024);
+        insert_dir(&conn, "/other/photos", "hash1", 1024);
+
+        let groups = db::duplicate_directory_groups(&conn).unwrap();
+        let (top_level, _) = build_top_level_groups(&conn, &groups, &[]).unwrap();
+        assert_eq!(top_level.len(), 1);
+    }
+
+    // -----------------------------------------------------------------------
+    // ReportFilter
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_file_group_wasted_counts_all_but_one_copy() {
+        let conn = open_test_db();
+        for path in ["/a/f", "/b/f", "/c/f"] {
+            insert_file(&conn, path, "hash_dup", 100);
+        }
+        let groups = find_duplicate_files(&conn).unwrap();
+        assert_eq!(groups[0].wasted(), 200);
+    }
+
+    #[test]
+    fn test_report_filter_min_count_and_min_wasted() {
+        let conn = open_test_db();
+        insert_file(&conn, "/a/small", "hash_small", 10);
+        insert_file(&conn, "/b/small", "hash_small", 10);
+        for path in ["/a/big", "/b/big", "/c/big"] {
+            insert_file(&conn, path, "hash_big", 1000);
+        }
+        let groups = find_duplicate_files(&conn).unwrap();
+
+        let by_count = ReportFilter {
+            min_count: 3,
+            ..ReportFilter::default()
+        };
+        let by_wasted = ReportFilter {
+            min_wasted: 11,
+            ..ReportFilter::default()
+        };
+        for filter in [by_count, by_wasted] {
+            let kept: Vec<&str> = groups
+                .iter()
+                .filter(|g| filter.keeps_files(g))
+                .map(|g| g.hash.as_str())
+                .collect();
+            assert_eq!(kept, vec!["hash_big"]);
+        }
+        assert!(groups.iter().all(|g| ReportFilter::default().keeps_files(g)));
+    }
+
+    #[test]
+    fn test_report_filter_dir_group_wasted() {
+        let group = DuplicateDirGroup {
+            hash: "h".to_string(),
+            max_size: 500,
+            members: vec![
+                DirEntry { path: "/a".to_string(), size: 500 },
+                DirEntry { path: "/b".to_string(), size: 500 },
+            ],
+        };
+        assert_eq!(group.wasted(), 500);
+        let filter = ReportFilter {
+            min_wasted: 501,
+            ..ReportFilter::default()
+        };
+        assert!(!filter.keeps_dirs(&group));
+    }
+
+    #[test]
+    fn test_report_filter_leaves_out_empty_groups_unless_asked() {
+        let conn = open_test_db();
+        for path in ["/a/empty", "/b/empty"] {
+            insert_file(&conn, path, "hash_empty", 0);
+        }
+        for path in ["/a/data", "/b/data"] {
+            insert_file(&conn, path, "hash_data", 10);
+        }
+        let groups = find_duplicate_files(&conn).unwrap();
+        let include = ReportFilter {
+            include_empty: true,
+            ..ReportFilter::default()
+        };
+        let kept = |filter: &ReportFilter| -> Vec<&str> {
+            groups
+                .iter()
+                .filter(|g| filter.keeps_files(g))
+                .map(|g| g.hash.as_str())
+                .collect()
+        };
+        assert_eq!(kept(&ReportFilter::default()), vec!["hash_data"]);
+        assert_eq!(kept(&include), vec!["hash_data", "hash_empty"]);
+
+        let hollow = DuplicateDirGroup {
+            hash: "h".to_string(),
+            max_size: 0,
+            members: vec![
+                DirEntry { path: "/a".to_string(), size: 0 },
+                DirEntry { path: "/b".to_string(), size: 0 },
+            ],
+        };
+        assert!(!ReportFilter::default().keeps_dirs(&hollow));
+        assert!(include.keeps_dirs(&hollow));
+    }
+
+    // -----------------------------------------------------------------------
+    // Hardlinks
+    // -----------------------------------------------------------------------
+
+    fn insert_linked_file(conn: &Connection, path: &str, hash: &str, inode: i64) {
+        insert_file(conn, path, hash, 100);
+        db::update_file_identity(conn, Path::new(path), 1, inode).unwrap();
+    }
+
+    #[test]
+    fn test_find_duplicate_files_hardlinks_alone_are_not_duplicates() {
+        let conn = open_test_db();
+        insert_linked_file(&conn, "/a/file.txt", "hash_dup", 7);
+        insert_linked_file(&conn, "/b/file.txt", "hash_dup", 7);
+        assert!(find_duplicate_files(&conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_find_duplicate_files_folds_hardlinks_into_one_entry() {
+        let conn = open_test_db();
+        insert_linked_file(&conn, "/a/file.txt", "hash_dup", 7);
+        insert_linked_file(&conn, "/b/file.txt", "hash_dup", 7);
+        insert_linked_file(&conn, "/c/file.txt", "hash_dup", 8);
+        let groups = find_duplicate_files(&conn).unwrap();
+
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].count, 2);
+        assert_eq!(groups[0].total_size, 200);
+        assert_eq!(groups[0].files[0].path, "/a/file.txt");
+        assert_eq!(groups[0].files[0].hardlinks, vec!["/b/file.txt"]);
+        assert!(groups[0].files[1].hardlinks.is_empty());
+    }
+
+    #[test]
+    fn test_find_duplicate_files_same_inode_on_other_device_is_a_duplicate() {
+        let conn = open_test_db();
+        insert_linked_file(&conn, "/a/file.txt", "hash_dup", 7);
+        insert_file(&conn, "/b/file.txt", "hash_dup", 100);
+        db::update_file_identity(&conn, Path::new("/b/file.txt"), 2, 7).unwrap();
+        assert_eq!(find_duplicate_files(&conn).unwrap()[0].count, 2);
+    }
+
+    // -----------------------------------------------------------------------
+    // split_covered_file_groups
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_split_covered_file_groups_sets_aside_files_inside_duplicate_dirs() {
+        let conn = open_test_db();
+        for dir in ["/a/photos", "/b/photos", "/c/docs", "/d/docs"] {
+            insert_dir(&conn, dir, &format!("hash_{}", &dir[3..]), 10);
+        }
+        // Accounted for by the photos directories
+        insert_file(&conn, "/a/photos/1.jpg", "hash_1", 10);
+        insert_file(&conn, "/b/photos/1.jpg", "hash_1", 10);
+        // One more copy elsewhere
+        for path in ["/a/photos/2.jpg", "/b/photos/2.jpg", "/e/2.jpg"] {
+            insert_file(&conn, path, "hash_2", 10);
+        }
+        // Twice in each photos directory
+        for path in ["/a/photos/3.jpg", "/a/photos/3 copy.jpg", "/b/photos/3.jpg"] {
+            insert_file(&conn, path, "hash_3", 10);
+        }
+        // Under two different directory groups
+        insert_file(&conn, "/a/photos/4.jpg", "hash_4", 10);
+        insert_file(&conn, "/c/docs/4.jpg", "hash_4", 10);
+
+        let hashes = db::duplicate_directory_groups(&conn).unwrap();
+        let (dir_groups, _) = build_top_level_groups(&conn, &hashes, &[]).unwrap();
+        let file_groups = find_duplicate_files(&conn).unwrap();
+        let (shown, covered) = split_covered_file_groups(file_groups, &dir_groups);
+        let hashes = |groups: &[DuplicateFileGroup]| -> Vec<String> {
+            let mut hashes: Vec<String> = groups.iter().map(|g| g.hash.clone()).collect();
+            hashes.sort();
+            hashes
+        };
+        assert_eq!(hashes(&covered), vec!["hash_1"]);
+        assert_eq!(hashes(&shown), vec!["hash_2", "hash_3", "hash_4"]);
+    }
 }
@@ -0,0 +1,316 @@
+use std::path::Path;
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use rusqlite::Connection;
+
+use crate::duplicates::{DuplicateDirGroup, DuplicateFileGroup, ReportFilter};
+use crate::{db, hashing, report, scan, utils};
+
+/// The SQLite database of file and directory hashes: what a `Scanner` fills
+/// in and a `DuplicateReport` is built from.
+pub struct HashStore {
+    conn: Connection,
+}
+
+impl HashStore {
+    /// Open the database at `path`, creating it if there is none, and bring
+    /// its schema up to date.
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            conn: db::init_database(path)?,
+        })
+    }
+
+    /// An empty database that lives in memory and goes away when dropped.
+    pub fn open_in_memory() -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    /// An in-memory copy of the database at `path` (empty if there is none
+    /// yet): it is read and written as usual, but nothing reaches the file.
+    pub fn open_scratch_copy(path: &Path) -> Result<Self> {
+        Ok(Self {
+            conn: db::open_scratch_copy(path)?,
+        })
+    }
+
+    /// Take over a connection opened elsewhere, bringing its schema up to date.
+    pub fn from_connection(conn: Connection) -> Result<Self> {
+        db::setup_schema(&conn)?;
+        Ok(Self { conn })
+    }
+
+    /// The connection underneath, for queries of your own against the tables
+    /// the scans fill in (`files`, `directories`, `scans` and the rest).
+    pub fn connection(&self) -> &Connection {
+        &self.conn
+    }
+
+    /// Give up the store for the connection underneath.
+    pub fn into_connection(self) -> Connection {
+        self.conn
+    }
+
+    /// The stored record of the file at `path`, if a scan has seen it.
+    pub fn file(&self, path: &Path) -> Result<Option<db::FileRecord>> {
+        db::get_file(&self.conn, path)
+    }
+
+    /// Every file in the store, ordered by path.
+    pub fn files(&self) -> Result<Vec<db::FileRecord>> {
+        db::all_files(&self.conn)
+    }
+
+    /// How many files the store holds, and their combined size in bytes.
+    pub fn totals(&self) -> Result<(i64, i64)> {
+        db::file_totals(&self.conn)
+    }
+
+    /// The algorithm the store's hashes were made with, if anything has been
+    /// scanned into it yet.
+    pub fn algorithm(&self) -> Result<Option<hashing::HashAlgorithm>> {
+        hashing::recorded_algorithm(&self.conn)
+    }
+}
+
+/// Walks directory trees and records the hash of every file and directory
+/// in them in a `HashStore`, rehashing only what changed since the last scan.
+#[derive(Debug, Clone, Default)]
+pub struct Scanner {
+    /// How trees are walked and files hashed. `options.hash.algorithm` is
+    /// overridden by `algorithm`.
+    pub options: scan::ScanOptions,
+    /// The algorithm to hash with. A store keeps the one it was first
+    /// scanned with, and scanning it with another is an error; `None` uses
+    /// that one, or BLAKE3 for a new store.
+    pub algorithm: Option<hashing::HashAlgorithm>,
+    /// Delete the records of files that are gone from disk; otherwise they
+    /// are kept, and counted in each root's `stale_count`.
+    pub remove_stale: bool,
+}
+
+impl Scanner {
+    pub fn new(options: scan::ScanOptions) -> Self {
+        Self {
+            options,
+            ..Self::default()
+        }
+    }
+
+    /// Scan each of `roots` into `store` in order, recording the run as one
+    /// entry in its scan history, and return what each root's scan found.
+    /// Progress is reported via `on_progress(processed, total, filename)`,
+    /// counting from zero for every root. Scanning stops after a root whose
+    /// scan was cancelled (see `scan::ScanOptions::cancel`); its result, the
+    /// last, says so. Nothing is printed and nothing is asked.
+    pub fn scan(
+        &self,
+        store: &HashStore,
+        roots: &[&Path],
+        on_progress: impl Fn(usize, usize, &str),
+    ) -> Result<Vec<scan::ScanResult>> {
+        let conn = store.connection();
+        for root in roots {
+            if !root.is_dir() {
+                bail!("{} is not a directory", root.display());
+            }
+        }
+        let mut opts = self.options.clone();
+        opts.hash.algorithm = hashing::resolve_algorithm(conn, self.algorithm)?;
+
+        let stored: Vec<_> = roots.iter().map(|r| utils::path_to_db(r)).collect();
+        let stored: Vec<&str> = stored.iter().map(|r| r.as_ref()).collect();
+        let started = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_secs() as i64;
+        let scan_id = db::begin_scan(conn, started, &stored)?;
+        let location = db::Location {
+            host: utils::hostname(),
+            volume: None,
+        };
+
+        let mut results = Vec::new();
+        for root in roots {
+            let total_files = hashing::count_files(root, &opts.walk)?;
+            let result = scan::scan_directory(conn, root, total_files, &opts, &on_progress)?;
+            if result.interrupted {
+                results.push(result);
+                break;
+            }
+            if result.stale_count > 0 && self.remove_stale {
+                db::delete_stale_files(conn, &result.root_str)?;
+            }
+            db::record_scan_history(conn, scan_id, &result.root_str)?;
+            db::tag_location(conn, &result.root_str, &location)?;
+            results.push(result);
+        }
+        Ok(results)
+    }
+}
+
+/// The duplicate files and directories in a `HashStore`, gathered in one go
+/// as the `report` command shows them.
+pub struct DuplicateReport {
+    report: report::Report,
+}
+
+impl DuplicateReport {
+    /// Every duplicate group in `store`, leaving out empty files and
+    /// directories with no data in them.
+    pub fn build(store: &HashStore) -> Result<Self> {
+        Self::build_filtered(store, &[], ReportFilter::default())
+    }
+
+    /// The duplicate groups in `store` that pass `filter`, with the directory
+    /// groups limited to members under `scope` (all of them when empty).
+    pub fn build_filtered(
+        store: &HashStore,
+        scope: &[&Path],
+        filter: ReportFilter,
+    ) -> Result<Self> {
+        Ok(Self {
+            report: report::build(store.connection(), scope, filter)?,
+        })
+    }
+
+    /// Groups of files with identical content, the most space wasted first.
+    /// Hardlinks to one file are a single entry in its group.
+    pub fn file_groups(&self) -> &[DuplicateFileGroup] {
+        &self.report.file_groups
+    }
+
+    /// Groups of directory trees with identical content, largest first. A
+    /// subdirectory of a duplicate directory is only listed here when it has
+    /// copies of its own elsewhere.
+    pub fn dir_groups(&self) -> &[DuplicateDirGroup] {
+        &self.report.dir_groups
+    }
+
+    /// No duplicate files and no duplicate directories.
+    pub fn is_empty(&self) -> bool {
+        self.report.file_groups.is_empty() && self.report.dir_groups.is_empty()
+    }
+
+    /// Bytes freed by keeping one copy of every duplicate file.
+    pub fn wasted(&self) -> i64 {
+        self.report.wasted()
+    }
+
+    /// Copies beyond the first in every duplicate file group.
+    pub fn redundant_copies(&self) -> i64 {
+        self.report.redundant_copies()
+    }
+
+    /// Files in the store, duplicate or not, and their combined size.
+    pub fn totals(&self) -> (i64, i64) {
+        (self.report.total_files, self.report.total_bytes)
+    }
+
+    /// The report as `report --format json` prints it.
+    pub fn to_json(&self) -> Result<String> {
+        report::to_json(&self.report)
+    }
+
+    /// The report as `report --format html` writes it: a standalone page.
+    pub fn to_html(&self) -> String {
+        report::to_html(&self.report)
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn write(path: &Path, contents: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_scan_then_report_finds_duplicate_files_and_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        write(&root.join("a/one.txt"), "one");
+        write(&root.join("a/two.txt"), "two");
+        write(&root.join("b/one.txt"), "one");
+        write(&root.join("b/two.txt"), "two");
+        write(&root.join("c/one copy.txt"), "one");
+        write(&root.join("c/unique.txt"), "unique");
+
+        let store = HashStore::open_in_memory().unwrap();
+        let results = Scanner::default()
+            .scan(&store, &[root], |_, _, _| {})
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].errors.is_empty());
+        assert_eq!(store.totals().unwrap(), (6, 21));
+        assert_eq!(
+            store.algorithm().unwrap(),
+            Some(hashing::HashAlgorithm::RECOMMENDED)
+        );
+
+        let report = DuplicateReport::build(&store).unwrap();
+        assert!(!report.is_empty());
+        let counts: Vec<i64> = report.file_groups().iter().map(|g| g.count).collect();
+        assert_eq!(counts, vec![3, 2]);
+        assert_eq!(report.wasted(), 9);
+        assert_eq!(report.redundant_copies(), 3);
+        assert_eq!(report.dir_groups().len(), 1);
+        assert_eq!(report.dir_groups()[0].members.len(), 2);
+        assert!(report.to_json().unwrap().contains("one copy.txt"));
+    }
+
+    #[test]
+    fn test_scan_keeps_or_removes_stale_records() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        write(&root.join("kept.txt"), "kept");
+        write(&root.join("gone.txt"), "gone");
+        let store = HashStore::open_in_memory().unwrap();
+        let mut scanner = Scanner::default();
+        scanner.scan(&store, &[root], |_, _, _| {}).unwrap();
+
+        fs::remove_file(root.join("gone.txt")).unwrap();
+        let results = scanner.scan(&store, &[root], |_, _, _| {}).unwrap();
+        assert_eq!(results[0].stale_count, 1);
+        assert!(store.file(&root.join("gone.txt")).unwrap().is_some());
+
+        scanner.remove_stale = true;
+        scanner.scan(&store, &[root], |_, _, _| {}).unwrap();
+        assert!(store.file(&root.join("gone.txt")).unwrap().is_none());
+        assert_eq!(store.files().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_scan_refuses_a_second_algorithm_or_a_missing_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        write(&tmp.path().join("a.txt"), "a");
+        let store = HashStore::open_in_memory().unwrap();
+        let mut scanner = Scanner {
+            algorithm: Some(hashing::HashAlgorithm::Sha256),
+            ..Scanner::default()
+        };
+        scanner.scan(&store, &[tmp.path()], |_, _, _| {}).unwrap();
+        scanner.algorithm = Some(hashing::HashAlgorithm::Xxh3);
+        assert!(scanner.scan(&store, &[tmp.path()], |_, _, _| {}).is_err());
+        scanner.algorithm = None;
+        scanner.scan(&store, &[tmp.path()], |_, _, _| {}).unwrap();
+        assert_eq!(
+            store.algorithm().unwrap(),
+            Some(hashing::HashAlgorithm::Sha256)
+        );
+
+        let missing = tmp.path().join("missing");
+        assert!(scanner.scan(&store, &[&missing], |_, _, _| {}).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use serde_json::{json, Value};
+
+use crate::scan::{ScanError, ScanResult};
+use crate::utils;
+
+/// The formats `--events` can stream in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum EventFormat {
+    /// One JSON object per line
+    Jsonl,
+}
+
+/// A machine-readable account of a run, for programs that drive
+/// deduplifier: one JSON object per line, each with its name under `event`,
+/// written as soon as it happens.
+pub struct EventStream {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl EventStream {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Mutex::new(Box::new(out)),
+        }
+    }
+
+    /// Stream to the file at `path`, replacing what it held.
+    pub fn to_file(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("could not create the events file {}", path.display()))?;
+        Ok(Self::new(file))
+    }
+
+    /// Stream to standard output, which is then the events' alone: on Unix,
+    /// everything else the run prints goes to standard error instead.
+    pub fn to_stdout() -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::io::FromRawFd;
+            let fd = unsafe { libc::dup(1) };
+            if fd == -1 {
+                return Err(std::io::Error::last_os_error())
+                    .context("could not take over standard output");
+            }
+            if unsafe { libc::dup2(2, 1) } == -1 {
+                return Err(std::io::Error::last_os_error())
+                    .context("could not send the output to standard error");
+            }
+            Ok(Self::new(unsafe { File::from_raw_fd(fd) }))
+        }
+        #[cfg(not(unix))]
+        Ok(Self::new(std::io::stdout()))
+    }
+
+    /// Write `event` as one line. A reader that went away is no reason to
+    /// stop the run, so failures are ignored.
+    pub fn emit(&self, event: &Value) {
+        let mut line = event.to_string();
+        line.push('\n');
+        if let Ok(mut out) = self.out.lock() {
+            let _ = out.write_all(line.as_bytes()).and_then(|_| out.flush());
+        }
+    }
+}
+
+/// A scan of `root` has begun; `files` and `bytes` are what it will hash at
+/// most, when they were counted beforehand (they aren't for remote roots).
+pub fn scan_started(scan_id: i64, root: &Path, files: Option<usize>, bytes: Option<u64>) -> Value {
+    json!({
+        "event": "scan_started",
+        "scan_id": scan_id,
+        "root": utils::path_to_db(root),
+        "files": files,
+        "bytes": bytes,
+    })
+}
+
+/// A file was hashed (or given the hash of its copy) and stored.
+pub fn file_hashed(path: &Path, size: u64, hash: &str) -> Value {
+    json!({
+        "event": "file_hashed",
+        "path": utils::path_to_db(path),
+        "size": size,
+        "hash": hash,
+    })
+}
+
+/// Something a scan couldn't list, read or hash, with the same `kind` the
+/// scan_errors table has, or a root that isn't there (`not_found`).
+pub fn error(path: &str, kind: &str, message: &str) -> Value {
+    json!({
+        "event": "error",
+        "path": path,
+        "kind": kind,
+        "message": message,
+    })
+}
+
+pub fn scan_error(error: &ScanError) -> Value {
+    self::error(&error.path, error.kind, &error.message)
+}
+
+/// The run failed with `error`, and will exit.
+pub fn fatal(error: &anyhow::Error) -> Value {
+    json!({
+        "event": "error",
+        "path": null,
+        "kind": "fatal",
+        "message": format!("{error:#}"),
+    })
+}
+
+/// The scan of one root has finished, or was interrupted.
+pub fn scan_finished(scan_id: i64, result: &ScanResult, elapsed: Duration) -> Value {
+    json!({
+        "event": "scan_finished",
+        "scan_id": scan_id,
+        "root": result.root_str,
+        "errors": result.errors.len(),
+        "stale": result.stale_count,
+        "moved": result.moved,
+        "interrupted": result.interrupted,
+        "elapsed_secs": elapsed.as_secs_f64(),
+    })
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_events_are_written_one_per_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.jsonl");
+        let stream = EventStream::to_file(&path).unwrap();
+        stream.emit(&scan_started(1, Path::new("/photos"), Some(2), Some(10)));
+        stream.emit(&file_hashed(Path::new("/photos/a.jpg"), 5, "abc"));
+        stream.emit(&error("/photos/b.jpg", "permission", "Permission denied"));
+
+        let text = fs::read_to_string(&path).unwrap();
+        let events: Vec<Value> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["event"], "scan_started");
+        assert_eq!(events[0]["files"], 2);
+        assert_eq!(events[1]["event"], "file_hashed");
+        assert_eq!(events[1]["path"], "/photos/a.jpg");
+        assert_eq!(events[1]["hash"], "abc");
+        assert_eq!(events[2]["kind"], "permission");
+    }
+
+    #[test]
+    fn test_scan_finished_and_fatal_events() {
+        let result = ScanResult {
+            errors: vec![ScanError {
+                path: "/photos/b.jpg".to_string(),
+                kind: "io",
+                message: "gone".to_string(),
+            }],
+            stale_count: 3,
+            root_str: "/photos".to_string(),
+            interrupted: false,
+            moved: 1,
+        };
+        let event = scan_finished(7, &result, Duration::from_millis(250));
+        assert_eq!(event["event"], "scan_finished");
+        assert_eq!(event["root"], "/photos");
+        assert_eq!(event["errors"], 1);
+        assert_eq!(event["stale"], 3);
+        assert_eq!(event["elapsed_secs"], 0.25);
+        assert_eq!(scan_error(&result.errors[0])["message"], "gone");
+
+        let event = fatal(&anyhow::anyhow!("disk full").context("could not scan"));
+        assert_eq!(event["kind"], "fatal");
+        assert_eq!(event["message"], "could not scan: disk full");
+        assert_eq!(event["path"], Value::Null);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_paths_that_are_not_utf8_are_encoded_as_stored() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let root = Path::new(OsStr::from_bytes(b"/caf\xe9"));
+        let path = root.join("a.jpg");
+        let started = scan_started(1, root, None, None);
+        let hashed = file_hashed(&path, 5, "abc");
+        // The same encoding the database and the JSON report use
+        assert_eq!(started["root"], utils::path_to_db(root).as_ref());
+        let stored = hashed["path"].as_str().unwrap();
+        assert_eq!(stored, utils::path_to_db(&path));
+        assert_eq!(utils::path_from_db(stored), path);
+    }
+}
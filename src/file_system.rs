@@ -1,9 +1,65 @@
+use std::collections::HashSet;
 use std::fs;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use walkdir::WalkDir;
 
+use crate::{utils, walk};
+
+// ---------------------------------------------------------------------------
+// Dry run
+// ---------------------------------------------------------------------------
+
+#[cfg(not(test))]
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+// Tests run side by side in one process, so there a dry run only holds for
+// the thread that asked for it
+#[cfg(test)]
+thread_local! {
+    static DRY_RUN: AtomicBool = const { AtomicBool::new(false) };
+}
+
+/// From now on, have every function here that would change the filesystem
+/// print what it would do, with the bytes involved, and succeed without
+/// touching anything. Commands change files through this module; the few
+/// writes made elsewhere check `dry_run` themselves (`xattr::store_hash`
+/// writes no attribute, and hooks are printed instead of run) or never
+/// happen in a dry run (`daemon` refuses one, and scans of `ssh://` and
+/// `s3://` roots only read, through `ssh` and `aws`).
+pub fn set_dry_run(dry_run: bool) {
+    #[cfg(not(test))]
+    DRY_RUN.store(dry_run, Ordering::Relaxed);
+    #[cfg(test)]
+    DRY_RUN.with(|d| d.store(dry_run, Ordering::Relaxed));
+}
+
+pub fn dry_run() -> bool {
+    #[cfg(not(test))]
+    return DRY_RUN.load(Ordering::Relaxed);
+    #[cfg(test)]
+    DRY_RUN.with(|d| d.load(Ordering::Relaxed))
+}
+
+/// Print one line of the dry-run plan: `[dry run] delete /a/b.txt (4.0 KiB)`.
+fn show_dry_run(what: &str, path: &Path, bytes: Option<u64>) {
+    match bytes {
+        Some(bytes) => println!(
+            "[dry run] {} {} ({})",
+            what,
+            path.display(),
+            utils::fmt_size(bytes as i64)
+        ),
+        None => println!("[dry run] {} {}", what, path.display()),
+    }
+}
+
+fn file_size(path: &Path) -> Option<u64> {
+    fs::metadata(path).ok().map(|m| m.len())
+}
+
 // ---------------------------------------------------------------------------
 // File operations
 // ---------------------------------------------------------------------------
@@ -17,6 +73,11 @@ use walkdir::WalkDir;
 /// we only move files into destinations that don't yet exist, but callers
 /// should be aware if that assumption ever changes.
 pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if dry_run() {
+        let what = format!("move to {}:", to.display());
+        show_dry_run(&what, from, file_size(from));
+        return Ok(());
+    }
     ensure_parent_exists(to)?;
     match fs::rename(from, to) {
         Ok(()) => Ok(()),
@@ -39,6 +100,11 @@ pub fn move_file(from: &Path, to: &Path) -> Result<()> {
 /// Copy a file from `src` to `dst`, creating parent directories as needed.
 /// The original is left in place.
 pub fn copy_file(src: &Path, dst: &Path) -> Result<()> {
+    if dry_run() {
+        let what = format!("copy to {}:", dst.display());
+        show_dry_run(&what, src, file_size(src));
+        return Ok(());
+    }
     ensure_parent_exists(dst)?;
     fs::copy(src, dst)
         .with_context(|| format!("copying {} -> {}", src.display(), dst.display()))?;
@@ -47,18 +113,388 @@ pub fn copy_file(src: &Path, dst: &Path) -> Result<()> {
 
 /// Delete a single file.
 pub fn delete_file(path: &Path) -> Result<()> {
+    if dry_run() {
+        show_dry_run("delete", path, file_size(path));
+        return Ok(());
+    }
     fs::remove_file(path).with_context(|| format!("deleting file {}", path.display()))
 }
 
+/// Move a file to the system trash (the freedesktop.org trash on Linux, the
+/// Recycle Bin on Windows, the Trash on macOS), from where it can be restored.
+pub fn trash_file(path: &Path) -> Result<()> {
+    if dry_run() {
+        show_dry_run("move to trash", path, file_size(path));
+        return Ok(());
+    }
+    trash::delete(path).with_context(|| {
+        format!(
+            "moving {} to the trash (--permanent deletes instead)",
+            path.display()
+        )
+    })
+}
+
+/// Move `path` into the quarantine directory `dir`, at its absolute path
+/// mirrored below `dir` (`/photos/a.jpg` goes to `DIR/photos/a.jpg`, `C:\a`
+/// to `DIR\C\a`), so copies from different places never collide. A name
+/// already taken there, by an earlier quarantine of the same path, gets a
+/// `.1`, `.2`, ... suffix. Returns where the file went.
+pub fn quarantine_file(path: &Path, dir: &Path) -> Result<std::path::PathBuf> {
+    let mirrored = mirrored_path(dir, path)?;
+    let mut target = mirrored.clone();
+    let mut n = 1;
+    while fs::symlink_metadata(&target).is_ok() {
+        let mut name = mirrored.as_os_str().to_os_string();
+        name.push(format!(".{}", n));
+        target = name.into();
+        n += 1;
+    }
+    move_file(path, &target)?;
+    Ok(target)
+}
+
+/// Put back the most recently trashed file that was at `path`. Returns
+/// `false` when the trash holds nothing from there, or on platforms whose
+/// trash can't be searched (macOS).
+pub fn restore_from_trash(path: &Path) -> Result<bool> {
+    #[cfg(any(
+        windows,
+        all(
+            unix,
+            not(target_os = "macos"),
+            not(target_os = "ios"),
+            not(target_os = "android")
+        )
+    ))]
+    {
+        // The trash records absolute paths; the database may hold relative ones
+        let original = std::path::absolute(path)?;
+        let mut items: Vec<trash::TrashItem> = trash::os_limited::list()
+            .context("listing the trash")?
+            .into_iter()
+            .filter(|item| item.original_path() == original)
+            .collect();
+        items.sort_by_key(|item| item.time_deleted);
+        let Some(item) = items.pop() else {
+            return Ok(false);
+        };
+        if dry_run() {
+            show_dry_run("restore from trash", path, None);
+            return Ok(true);
+        }
+        trash::os_limited::restore_all([item])
+            .with_context(|| format!("restoring {} from the trash", path.display()))?;
+        Ok(true)
+    }
+    #[cfg(not(any(
+        windows,
+        all(
+            unix,
+            not(target_os = "macos"),
+            not(target_os = "ios"),
+            not(target_os = "android")
+        )
+    )))]
+    {
+        let _ = path;
+        Ok(false)
+    }
+}
+
+/// Replace `path` (typically a link) with an independent copy of `source`,
+/// staged and renamed into place like `replace_with_hardlink`.
+pub fn replace_with_copy(path: &Path, source: &Path) -> Result<()> {
+    if dry_run() {
+        let what = format!("replace with a copy of {}:", source.display());
+        show_dry_run(&what, path, file_size(source));
+        return Ok(());
+    }
+    let tmp = temp_sibling(path);
+    fs::copy(source, &tmp)
+        .with_context(|| format!("copying {} -> {}", source.display(), tmp.display()))?;
+    fs::rename(&tmp, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        anyhow::Error::new(e).context(format!("replacing {} with a copy", path.display()))
+    })
+}
+
+/// Replace `path` with a hardlink to `target`, keeping the name but sharing
+/// `target`'s data. The link is made under a temporary name next to `path`
+/// and renamed over it, so `path` is never missing if something fails. Both
+/// must be on the same filesystem; on Windows `fs::hard_link` uses
+/// `CreateHardLinkW`, which needs NTFS.
+pub fn replace_with_hardlink(path: &Path, target: &Path) -> Result<()> {
+    if !same_filesystem(path, target)? {
+        bail!(
+            "{} and {} are on different filesystems",
+            path.display(),
+            target.display()
+        );
+    }
+    if dry_run() {
+        let what = format!("hardlink to {}:", target.display());
+        show_dry_run(&what, path, file_size(path));
+        return Ok(());
+    }
+    let tmp = temp_sibling(path);
+    fs::hard_link(target, &tmp)
+        .with_context(|| format!("linking {} -> {}", tmp.display(), target.display()))?;
+    fs::rename(&tmp, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        anyhow::Error::new(e).context(format!("replacing {} with a link", path.display()))
+    })
+}
+
+/// Replace `path` with a symlink to `target`, staged and renamed into place
+/// like `replace_with_hardlink`. With `relative` the link holds the path
+/// from `path`'s directory to `target` (e.g. `../keep/a.jpg`), which keeps
+/// working if the whole tree moves; otherwise `target` as given. Works across
+/// filesystems. On Windows, creating symlinks needs Developer Mode or admin
+/// rights.
+pub fn replace_with_symlink(path: &Path, target: &Path, relative: bool) -> Result<()> {
+    if !target.is_file() {
+        bail!("{} is not a file", target.display());
+    }
+    let contents = match (relative, path.parent()) {
+        (true, Some(dir)) => relative_path(dir, target),
+        _ => target.to_path_buf(),
+    };
+    if dry_run() {
+        let what = format!("symlink to {}:", contents.display());
+        show_dry_run(&what, path, file_size(path));
+        return Ok(());
+    }
+    let tmp = temp_sibling(path);
+    symlink_file(&contents, &tmp)
+        .with_context(|| format!("linking {} -> {}", tmp.display(), contents.display()))?;
+    fs::rename(&tmp, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        anyhow::Error::new(e).context(format!("replacing {} with a link", path.display()))
+    })
+}
+
+#[cfg(unix)]
+fn symlink_file(contents: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(contents, link)
+}
+
+#[cfg(windows)]
+fn symlink_file(contents: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(contents, link)
+}
+
+/// What `share_extents` managed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtentSharing {
+    /// The kernel compared the files, found them identical and now stores
+    /// this many of `dst`'s bytes in `src`'s extents
+    Shared(u64),
+    /// The contents differ, so nothing was shared
+    Differs,
+    /// The filesystem (or platform) can't share extents between these files
+    Unsupported,
+}
+
+/// Make `dst` share `src`'s storage on a copy-on-write filesystem (btrfs,
+/// XFS) with the `FIDEDUPERANGE` ioctl, leaving both paths, their metadata
+/// and their contents untouched. The kernel locks both ranges and compares
+/// them byte by byte before sharing, so this is safe even if a file changed
+/// since it was hashed. Other filesystems, cross-device pairs and other
+/// platforms report `Unsupported`.
+#[cfg(target_os = "linux")]
+pub fn share_extents(src: &Path, dst: &Path) -> Result<ExtentSharing> {
+    use std::os::fd::AsRawFd;
+
+    // struct file_dedupe_range with a single file_dedupe_range_info
+    #[repr(C)]
+    struct DedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+    // _IOWR(0x94, 54, struct file_dedupe_range)
+    const FIDEDUPERANGE: u64 = 0xC018_9436;
+    const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+    // btrfs handles at most 16 MiB per call; larger files take several
+    const CHUNK: u64 = 16 << 20;
+
+    let unsupported = |errno: i32| {
+        matches!(
+            errno,
+            libc::EOPNOTSUPP | libc::ENOTTY | libc::EINVAL | libc::EXDEV | libc::ENOSYS
+        )
+    };
+    let src_file = fs::File::open(src).with_context(|| format!("opening {}", src.display()))?;
+    // Writable if we can: older kernels refuse a read-only destination
+    let dst_file = fs::File::options()
+        .read(true)
+        .write(true)
+        .open(dst)
+        .or_else(|_| fs::File::open(dst))
+        .with_context(|| format!("opening {}", dst.display()))?;
+    let len = src_file.metadata()?.len();
+    if dst_file.metadata()?.len() != len {
+        return Ok(ExtentSharing::Differs);
+    }
+    if dry_run() {
+        // Whether the filesystem can do it is only found out by trying
+        let what = format!("reflink to {}:", src.display());
+        show_dry_run(&what, dst, Some(len));
+        return Ok(ExtentSharing::Shared(len));
+    }
+
+    let mut offset = 0;
+    while offset < len {
+        let mut range = DedupeRange {
+            src_offset: offset,
+            src_length: CHUNK.min(len - offset),
+            dest_count: 1,
+            reserved1: 0,
+            reserved2: 0,
+            dest_fd: dst_file.as_raw_fd() as i64,
+            dest_offset: offset,
+            bytes_deduped: 0,
+            status: 0,
+            reserved: 0,
+        };
+        // SAFETY: `range` is a valid file_dedupe_range followed by one info
+        // record, and both descriptors stay open for the call
+        let rc = unsafe { libc::ioctl(src_file.as_raw_fd(), FIDEDUPERANGE as _, &mut range) };
+        if rc < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error().is_some_and(unsupported) {
+                return Ok(ExtentSharing::Unsupported);
+            }
+            return Err(err).with_context(|| {
+                format!("sharing extents {} -> {}", src.display(), dst.display())
+            });
+        }
+        match range.status {
+            FILE_DEDUPE_RANGE_DIFFERS => return Ok(ExtentSharing::Differs),
+            s if s < 0 && unsupported(-s) => return Ok(ExtentSharing::Unsupported),
+            s if s < 0 => {
+                return Err(std::io::Error::from_raw_os_error(-s)).with_context(|| {
+                    format!("sharing extents {} -> {}", src.display(), dst.display())
+                })
+            }
+            _ => {}
+        }
+        if range.bytes_deduped == 0 {
+            break;
+        }
+        offset += range.bytes_deduped;
+    }
+    Ok(ExtentSharing::Shared(offset))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn share_extents(_src: &Path, _dst: &Path) -> Result<ExtentSharing> {
+    Ok(ExtentSharing::Unsupported)
+}
+
+/// Whether `a` and `b` live on the same filesystem, by device id. Where
+/// device ids aren't available the answer is `true`, and creating the link
+/// reports the problem instead.
+pub fn same_filesystem(a: &Path, b: &Path) -> Result<bool> {
+    let device = |p: &Path| -> Result<Option<i64>> {
+        let metadata = fs::metadata(p).with_context(|| format!("reading {}", p.display()))?;
+        Ok(utils::file_identity(&metadata).map(|(dev, _)| dev))
+    };
+    Ok(match (device(a)?, device(b)?) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    })
+}
+
+/// Whether `a` and `b` hold exactly the same bytes, compared in full rather
+/// than trusting that equal hashes mean equal content. Two names for one
+/// inode are equal without reading anything.
+pub fn same_contents(a: &Path, b: &Path) -> Result<bool> {
+    let open = |p: &Path| -> Result<(fs::File, fs::Metadata)> {
+        let file = fs::File::open(p).with_context(|| format!("opening {}", p.display()))?;
+        let metadata = file.metadata()?;
+        Ok((file, metadata))
+    };
+    let (mut file_a, meta_a) = open(a)?;
+    let (mut file_b, meta_b) = open(b)?;
+    if meta_a.len() != meta_b.len() {
+        return Ok(false);
+    }
+    if let (Some(x), Some(y)) = (utils::file_identity(&meta_a), utils::file_identity(&meta_b)) {
+        if x == y {
+            return Ok(true);
+        }
+    }
+    let mut buf_a = vec![0u8; 64 * 1024];
+    let mut buf_b = vec![0u8; 64 * 1024];
+    loop {
+        let n = read_full(&mut file_a, &mut buf_a)
+            .with_context(|| format!("reading {}", a.display()))?;
+        let m = read_full(&mut file_b, &mut buf_b)
+            .with_context(|| format!("reading {}", b.display()))?;
+        if buf_a[..n] != buf_b[..m] {
+            return Ok(false);
+        }
+        if n == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+/// Fill `buf` from `reader` as far as the data goes; short only at the end.
+fn read_full(reader: &mut impl std::io::Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
 // ---------------------------------------------------------------------------
 // Directory operations
 // ---------------------------------------------------------------------------
 
 /// Recursively delete `path` and everything inside it.
 pub fn delete_dir_all(path: &Path) -> Result<()> {
+    if dry_run() {
+        let bytes = WalkDir::new(path)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.metadata().ok())
+            .filter(|m| m.is_file())
+            .map(|m| m.len())
+            .sum();
+        show_dry_run("delete directory tree", path, Some(bytes));
+        return Ok(());
+    }
     fs::remove_dir_all(path).with_context(|| format!("removing directory tree {}", path.display()))
 }
 
+/// Copy every file under `src` to the same place under `dst`.
+pub fn copy_tree(src: &Path, dst: &Path) -> Result<()> {
+    for entry in WalkDir::new(src).into_iter() {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            let rel = entry.path().strip_prefix(src)?;
+            copy_file(entry.path(), &dst.join(rel))?;
+        }
+    }
+    Ok(())
+}
+
 /// Walk `root` bottom-up (deepest subdirectory first) and remove any empty
 /// subdirectory. The root itself is never removed.
 pub fn delete_empty_subdirs(root: &Path) -> Result<()> {
@@ -71,26 +507,278 @@ pub fn delete_empty_subdirs(root: &Path) -> Result<()> {
         .collect();
 
     // Deepest first so we remove children before parents
-    dirs.sort_by(|a, b| b.components().count().cmp(&a.components().count()));
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
 
     for dir in &dirs {
         let Ok(mut entries) = fs::read_dir(dir) else {
             continue;
         };
         if entries.next().is_none() {
+            if dry_run() {
+                show_dry_run("remove empty dir", dir, None);
+                continue;
+            }
             match fs::remove_dir(dir) {
                 Ok(()) => println!("  Removed empty dir: {}", dir.display()),
-                Err(e) => eprintln!("  Warning: could not remove {}: {}", dir.display(), e),
+                Err(e) => tracing::warn!("could not remove {}: {}", dir.display(), e),
             }
         }
     }
     Ok(())
 }
 
+// ---------------------------------------------------------------------------
+// Traversal
+// ---------------------------------------------------------------------------
+
+/// One entry listed by `walk_tree`.
+pub struct WalkEntry {
+    pub entry: walkdir::DirEntry,
+    /// Reached through a symlink: the entry is one, or lies below a
+    /// symlinked directory that was followed.
+    pub via_link: bool,
+}
+
+impl WalkEntry {
+    pub fn path(&self) -> &Path {
+        self.entry.path()
+    }
+}
+
+/// The walk used for scanning. With `one_file_system` it doesn't descend
+/// into directories on another device than `root` (mount points themselves
+/// are still listed, empty, like `rsync -x`). Entries the filter or an ignore
+/// file skips are left out, and so is everything below a skipped directory,
+/// as are hidden entries with `skip_hidden`. With `max_depth`, files up to
+/// that many levels below `root` are listed but directories only above it,
+/// so no directory whose contents went unread is recorded as empty. The root
+/// itself is always listed.
+///
+/// Symlinked directories are listed but not descended into unless
+/// `follow_symlinks` is set. Then each directory is walked once however many
+/// links lead to it: a link into the tree below `root` is left out since its
+/// target is walked anyway, and so is a directory whose device and inode
+/// were already listed, which also breaks cycles. Symlinked files are always
+/// listed; like hardlinks, they share their target's device and inode.
+pub fn walk_tree<'a>(root: &'a Path, opts: &'a walk::WalkOptions) -> TreeWalk<'a> {
+    walk_within(root, opts, &[])
+}
+
+/// `walk_tree`, listing only the entries at or below `paths` (everything when
+/// it is empty), and the directories above them on the way down. Nothing
+/// else is read, but the entries are left out by the same rules, so an
+/// entry is listed here exactly when `walk_tree` would list it.
+pub fn walk_within<'a>(
+    root: &'a Path,
+    opts: &'a walk::WalkOptions,
+    paths: &'a [std::path::PathBuf],
+) -> TreeWalk<'a> {
+    TreeWalk {
+        root,
+        opts,
+        within: paths,
+        inner: walker(root, opts).into_iter(),
+        ignores: walk::IgnoreFiles::new(opts),
+        links: Vec::new(),
+        seen: HashSet::new(),
+        canonical_root: opts
+            .follow_symlinks
+            .then(|| fs::canonicalize(root).ok())
+            .flatten(),
+    }
+}
+
+fn walker(root: &Path, opts: &walk::WalkOptions) -> WalkDir {
+    let walker = WalkDir::new(root)
+        .follow_links(opts.follow_symlinks)
+        .same_file_system(opts.one_file_system);
+    match opts.max_depth {
+        Some(depth) => walker.max_depth(depth),
+        None => walker,
+    }
+}
+
+/// The iterator behind `walk_tree`.
+pub struct TreeWalk<'a> {
+    root: &'a Path,
+    opts: &'a walk::WalkOptions,
+    /// See `walk_within`
+    within: &'a [std::path::PathBuf],
+    inner: walkdir::IntoIter,
+    ignores: walk::IgnoreFiles,
+    /// Depths of the followed directory links above the current entry
+    links: Vec<usize>,
+    /// `(device, inode)` of the directories listed so far (only with
+    /// `follow_symlinks`)
+    seen: HashSet<(i64, i64)>,
+    canonical_root: Option<std::path::PathBuf>,
+}
+
+impl TreeWalk<'_> {
+    /// Whether `entry` is listed, and if so whether it was reached through
+    /// a link. Must see every entry the inner walk yields, in order.
+    fn admit(&mut self, entry: &walkdir::DirEntry) -> Option<bool> {
+        let depth = entry.depth();
+        let is_dir = entry.file_type().is_dir();
+        while self.links.last().is_some_and(|d| *d >= depth) {
+            self.links.pop();
+        }
+        let path = entry.path();
+        if depth > 0
+            && !self.within.is_empty()
+            && !self
+                .within
+                .iter()
+                .any(|p| path.starts_with(p) || p.starts_with(path))
+        {
+            return None;
+        }
+        let relative = entry.path().strip_prefix(self.root).unwrap_or(entry.path());
+        let skipped = self.opts.filter.skips(relative, is_dir)
+            || (self.opts.skip_hidden && is_hidden(entry))
+            || (!is_dir && !self.opts.types.admits(entry.path()))
+            || (!is_dir && self.is_too_small(entry))
+            || (is_dir && self.opts.max_depth.is_some_and(|max| depth >= max));
+        if depth > 0 && skipped {
+            return None;
+        }
+
+        let is_link = depth > 0 && entry.path_is_symlink();
+        let via_link = is_link || !self.links.is_empty();
+        if self.opts.follow_symlinks && is_dir {
+            if is_link && self.links_into_root(entry.path()) {
+                return None;
+            }
+            let identity = entry.metadata().ok().and_then(|m| utils::file_identity(&m));
+            if identity.is_some_and(|id| !self.seen.insert(id)) {
+                return None;
+            }
+        }
+        if self.ignores.skips(entry.path(), depth, is_dir) {
+            return None;
+        }
+        if is_link && is_dir {
+            self.links.push(depth);
+        }
+        Some(via_link)
+    }
+
+    /// Whether `entry`, a file, is below `min_file_size`. A link is measured
+    /// by its target.
+    fn is_too_small(&self, entry: &walkdir::DirEntry) -> bool {
+        let min = self.opts.min_file_size;
+        min > 0 && fs::metadata(entry.path()).is_ok_and(|m| m.len() < min)
+    }
+
+    /// Whether the directory link at `path` points somewhere below the root.
+    fn links_into_root(&self, path: &Path) -> bool {
+        let Some(root) = &self.canonical_root else {
+            return false;
+        };
+        fs::canonicalize(path).is_ok_and(|target| target.starts_with(root))
+    }
+}
+
+impl Iterator for TreeWalk<'_> {
+    type Item = walkdir::Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.inner.next()? {
+                Ok(entry) => entry,
+                // A link back to a directory above it, which is being walked
+                // already; or a followed link whose target is gone
+                Err(e) if e.loop_ancestor().is_some() => continue,
+                Err(e) if self.opts.follow_symlinks && is_dangling_link(&e) => continue,
+                Err(e) => return Some(Err(e)),
+            };
+            match self.admit(&entry) {
+                Some(via_link) => return Some(Ok(WalkEntry { entry, via_link })),
+                None if entry.file_type().is_dir() => self.inner.skip_current_dir(),
+                None => {}
+            }
+        }
+    }
+}
+
+fn is_dangling_link(error: &walkdir::Error) -> bool {
+    error
+        .path()
+        .is_some_and(|path| path.is_symlink() && !path.exists())
+}
+
+/// Dotfiles and dot-directories, and on Windows anything with the hidden
+/// attribute as well.
+fn is_hidden(entry: &walkdir::DirEntry) -> bool {
+    if entry.file_name().to_string_lossy().starts_with('.') {
+        return true;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        if let Ok(metadata) = entry.metadata() {
+            return metadata.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0;
+        }
+    }
+    false
+}
+
 // ---------------------------------------------------------------------------
 // Helpers
 // ---------------------------------------------------------------------------
 
+/// Where `path` lands below `dir` when its absolute path is mirrored there:
+/// the root is dropped, a Windows drive or share prefix becomes a plain
+/// directory name, and `..` can't climb out of `dir`.
+pub fn mirrored_path(dir: &Path, path: &Path) -> Result<std::path::PathBuf> {
+    use std::path::Component;
+
+    let absolute =
+        std::path::absolute(path).with_context(|| format!("resolving {}", path.display()))?;
+    let mut out = dir.to_path_buf();
+    for component in absolute.components() {
+        match component {
+            Component::Prefix(prefix) => {
+                // `C:` becomes `C`, `\\server\share` becomes `server_share`
+                let name = prefix.as_os_str().to_string_lossy().replace(':', "");
+                let parts: Vec<&str> = name
+                    .split('\\')
+                    .filter(|p| !p.is_empty() && *p != "?")
+                    .collect();
+                out.push(parts.join("_"));
+            }
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => out.push("_parent"),
+            Component::Normal(name) => out.push(name),
+        }
+    }
+    Ok(out)
+}
+
+/// The path that leads from directory `from` to `to`, both absolute, without
+/// touching the filesystem: `/a/b` to `/a/c/d` is `../c/d`.
+pub fn relative_path(from: &Path, to: &Path) -> std::path::PathBuf {
+    let from: Vec<_> = from.components().collect();
+    let to: Vec<_> = to.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut path = std::path::PathBuf::new();
+    for _ in common..from.len() {
+        path.push("..");
+    }
+    for part in &to[common..] {
+        path.push(part);
+    }
+    path
+}
+
+/// A name in `path`'s directory for staging its replacement.
+fn temp_sibling(path: &Path) -> std::path::PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".deduplifier-tmp");
+    path.with_file_name(name)
+}
+
 /// Ensure the parent directory of `path` exists, creating it if necessary.
 fn ensure_parent_exists(path: &Path) -> Result<()> {
     if let Some(parent) = path.parent() {
@@ -205,6 +893,165 @@ mod tests {
         assert!(delete_file(&path).is_err());
     }
 
+    // -----------------------------------------------------------------------
+    // replace_with_hardlink
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_replace_with_hardlink_shares_data() {
+        let dir = tempdir().unwrap();
+        let keep = dir.path().join("keep.txt");
+        let copy = dir.path().join("copy.txt");
+        fs::write(&keep, b"same").unwrap();
+        fs::write(&copy, b"same").unwrap();
+
+        replace_with_hardlink(&copy, &keep).unwrap();
+
+        assert_eq!(fs::read(&copy).unwrap(), b"same");
+        assert!(!dir.path().join("copy.txt.deduplifier-tmp").exists());
+        let identity = |p: &Path| utils::file_identity(&fs::metadata(p).unwrap());
+        if let Some(id) = identity(&keep) {
+            assert_eq!(identity(&copy), Some(id));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_replace_with_symlink_relative_and_absolute() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("keep")).unwrap();
+        fs::create_dir_all(dir.path().join("other/deeper")).unwrap();
+        let keep = dir.path().join("keep/a.txt");
+        let rel = dir.path().join("other/deeper/a.txt");
+        let abs = dir.path().join("other/a.txt");
+        for path in [&keep, &rel, &abs] {
+            fs::write(path, b"same").unwrap();
+        }
+
+        replace_with_symlink(&rel, &keep, true).unwrap();
+        replace_with_symlink(&abs, &keep, false).unwrap();
+
+        assert_eq!(fs::read_link(&rel).unwrap(), Path::new("../../keep/a.txt"));
+        assert_eq!(fs::read_link(&abs).unwrap(), keep);
+        assert_eq!(fs::read(&rel).unwrap(), b"same");
+        assert_eq!(fs::read(&abs).unwrap(), b"same");
+    }
+
+    #[test]
+    fn test_share_extents_leaves_files_intact() {
+        let dir = tempdir().unwrap();
+        let a = dir.path().join("a.bin");
+        let b = dir.path().join("b.bin");
+        let c = dir.path().join("c.bin");
+        fs::write(&a, vec![7u8; 64 * 1024]).unwrap();
+        fs::write(&b, vec![7u8; 64 * 1024]).unwrap();
+        fs::write(&c, b"short").unwrap();
+
+        // Whether extents can be shared depends on the filesystem under /tmp
+        let result = share_extents(&a, &b).unwrap();
+        assert!(matches!(
+            result,
+            ExtentSharing::Shared(65536) | ExtentSharing::Unsupported
+        ));
+        assert_eq!(fs::read(&b).unwrap(), vec![7u8; 64 * 1024]);
+        assert_eq!(share_extents(&a, &c).unwrap(), ExtentSharing::Differs);
+    }
+
+    #[test]
+    fn test_quarantine_file_mirrors_path_and_avoids_collisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let quarantine = dir.path().join("quarantine");
+        let file = dir.path().join("photos").join("a.jpg");
+        fs::create_dir_all(file.parent().unwrap()).unwrap();
+
+        fs::write(&file, b"first").unwrap();
+        let first = quarantine_file(&file, &quarantine).unwrap();
+        assert!(!file.exists());
+        assert_eq!(first, mirrored_path(&quarantine, &file).unwrap());
+        assert_eq!(fs::read(&first).unwrap(), b"first");
+
+        fs::write(&file, b"second").unwrap();
+        let second = quarantine_file(&file, &quarantine).unwrap();
+        assert_eq!(second.file_name().unwrap(), "a.jpg.1");
+        assert_eq!(fs::read(&second).unwrap(), b"second");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_mirrored_path_stays_inside() {
+        let mirrored = mirrored_path(Path::new("/q"), Path::new("/photos/../a.jpg")).unwrap();
+        assert_eq!(mirrored, Path::new("/q/photos/_parent/a.jpg"));
+    }
+
+    #[test]
+    fn test_same_contents_compares_every_byte() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        let c = dir.path().join("c");
+        let mut data = vec![7u8; 200 * 1024];
+        fs::write(&a, &data).unwrap();
+        fs::write(&b, &data).unwrap();
+        *data.last_mut().unwrap() = 8;
+        fs::write(&c, &data).unwrap();
+        assert!(same_contents(&a, &b).unwrap());
+        assert!(!same_contents(&a, &c).unwrap());
+        fs::write(&c, b"short").unwrap();
+        assert!(!same_contents(&a, &c).unwrap());
+        assert!(same_contents(&a, &a).unwrap());
+    }
+
+    #[test]
+    fn test_replace_with_copy_breaks_link() {
+        let dir = tempdir().unwrap();
+        let keep = dir.path().join("keep.txt");
+        let link = dir.path().join("link.txt");
+        fs::write(&keep, b"same").unwrap();
+        fs::hard_link(&keep, &link).unwrap();
+
+        replace_with_copy(&link, &keep).unwrap();
+
+        fs::write(&keep, b"edit").unwrap();
+        assert_eq!(fs::read(&link).unwrap(), b"same");
+    }
+
+    #[test]
+    fn test_copy_tree() {
+        let dir = tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("a.txt"), b"a").unwrap();
+        fs::write(src.join("sub/b.txt"), b"b").unwrap();
+
+        copy_tree(&src, &dir.path().join("dst")).unwrap();
+
+        assert_eq!(fs::read(dir.path().join("dst/a.txt")).unwrap(), b"a");
+        assert_eq!(fs::read(dir.path().join("dst/sub/b.txt")).unwrap(), b"b");
+        assert!(src.join("sub/b.txt").exists());
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            relative_path(Path::new("/a/b"), Path::new("/a/c/d")),
+            Path::new("../c/d")
+        );
+        assert_eq!(
+            relative_path(Path::new("/a"), Path::new("/a/x")),
+            Path::new("x")
+        );
+    }
+
+    #[test]
+    fn test_replace_with_hardlink_missing_target_leaves_path() {
+        let dir = tempdir().unwrap();
+        let copy = dir.path().join("copy.txt");
+        fs::write(&copy, b"data").unwrap();
+
+        assert!(replace_with_hardlink(&copy, &dir.path().join("gone.txt")).is_err());
+        assert_eq!(fs::read(&copy).unwrap(), b"data");
+    }
+
     // -----------------------------------------------------------------------
     // ensure_dir_exists
     // -----------------------------------------------------------------------
@@ -304,4 +1151,211 @@ mod tests {
         // A path with no parent (e.g. just a filename) should not error.
         ensure_parent_exists(Path::new("file.txt")).unwrap();
     }
+
+    // -----------------------------------------------------------------------
+    // walk_tree
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_walk_tree_one_file_system_still_walks_local_tree() {
+        let dir = tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        fs::write(sub.join("a.txt"), "x").unwrap();
+
+        let opts = walk::WalkOptions {
+            one_file_system: true,
+            ..Default::default()
+        };
+        let count = walk_tree(dir.path(), &opts).count();
+        assert_eq!(count, 3, "root, sub and sub/a.txt");
+    }
+
+    #[test]
+    fn test_walk_tree_prunes_excluded_dirs() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("app/node_modules/pkg")).unwrap();
+        fs::write(dir.path().join("app/node_modules/pkg/index.js"), "x").unwrap();
+        fs::write(dir.path().join("app/main.js"), "x").unwrap();
+        fs::write(dir.path().join("app/scratch.tmp"), "x").unwrap();
+
+        let filter = walk::PathFilter::new(&["node_modules".into(), "*.tmp".into()], &[], &[]);
+        let opts = walk::WalkOptions {
+            filter: filter.unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(walked(dir.path(), &opts), ["", "app", "app/main.js"]);
+    }
+
+    /// Everything `walk_tree` lists below `root`, relative to it and sorted.
+    fn walked(root: &Path, opts: &walk::WalkOptions) -> Vec<String> {
+        let mut found: Vec<String> = walk_tree(root, opts)
+            .map(|e| {
+                let entry = e.unwrap();
+                let relative = entry.path().strip_prefix(root).unwrap();
+                relative.to_string_lossy().replace('\\', "/")
+            })
+            .collect();
+        found.sort();
+        found
+    }
+
+    #[test]
+    fn test_walk_within_lists_only_the_paths_and_the_way_down() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+        fs::create_dir_all(dir.path().join("other")).unwrap();
+        fs::write(dir.path().join("top"), "x").unwrap();
+        fs::write(dir.path().join("a/one"), "x").unwrap();
+        fs::write(dir.path().join("a/b/two"), "x").unwrap();
+        fs::write(dir.path().join("a/b/skip.tmp"), "x").unwrap();
+        fs::write(dir.path().join("a/b/c/three"), "x").unwrap();
+
+        let filter = walk::PathFilter::new(&["*.tmp".into()], &[], &[]);
+        let opts = walk::WalkOptions {
+            filter: filter.unwrap(),
+            ..Default::default()
+        };
+        let paths = [dir.path().join("a/b"), dir.path().join("top")];
+        let mut found: Vec<String> = walk_within(dir.path(), &opts, &paths)
+            .map(|e| {
+                let entry = e.unwrap();
+                let relative = entry.path().strip_prefix(dir.path()).unwrap();
+                relative.to_string_lossy().replace('\\', "/")
+            })
+            .collect();
+        found.sort();
+        assert_eq!(
+            found,
+            ["", "a", "a/b", "a/b/c", "a/b/c/three", "a/b/two", "top"]
+        );
+    }
+
+    #[test]
+    fn test_walk_tree_max_depth_leaves_out_unread_dirs() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("a/b/c")).unwrap();
+        fs::write(dir.path().join("top"), "x").unwrap();
+        fs::write(dir.path().join("a/one"), "x").unwrap();
+        fs::write(dir.path().join("a/b/two"), "x").unwrap();
+
+        let opts = walk::WalkOptions {
+            max_depth: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(walked(dir.path(), &opts), ["", "a", "a/one", "top"]);
+    }
+
+    #[test]
+    fn test_walk_tree_leaves_out_files_below_min_file_size() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("thumbs")).unwrap();
+        fs::write(dir.path().join("thumbs/tiny.jpg"), "x").unwrap();
+        fs::write(dir.path().join("photo.jpg"), "0123456789").unwrap();
+        fs::write(dir.path().join("exact.jpg"), "01234").unwrap();
+
+        let opts = walk::WalkOptions {
+            min_file_size: 5,
+            ..Default::default()
+        };
+        assert_eq!(
+            walked(dir.path(), &opts),
+            ["", "exact.jpg", "photo.jpg", "thumbs"]
+        );
+    }
+
+    #[test]
+    fn test_walk_tree_skip_hidden() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(".cache/blobs")).unwrap();
+        fs::write(dir.path().join(".cache/blobs/x"), "x").unwrap();
+        fs::write(dir.path().join(".bashrc"), "x").unwrap();
+        fs::write(dir.path().join("notes.txt"), "x").unwrap();
+
+        let opts = walk::WalkOptions {
+            skip_hidden: true,
+            ..Default::default()
+        };
+        assert_eq!(walked(dir.path(), &opts), ["", "notes.txt"]);
+        // A hidden root is still walked
+        assert_eq!(
+            walked(&dir.path().join(".cache"), &opts),
+            ["", "blobs", "blobs/x"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_walk_tree_follow_symlinks_walks_each_directory_once() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempdir().unwrap();
+        let root = tmp.path().join("root");
+        let outside = tmp.path().join("outside");
+        fs::create_dir_all(root.join("real")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(root.join("real/a"), "x").unwrap();
+        fs::write(outside.join("b"), "x").unwrap();
+        symlink(&outside, root.join("out1")).unwrap();
+        symlink(&outside, root.join("out2")).unwrap();
+        symlink(root.join("real"), root.join("inside")).unwrap();
+        symlink(&root, root.join("real/loop")).unwrap();
+
+        // Without following, links are listed but not entered
+        let opts = walk::WalkOptions::default();
+        assert_eq!(
+            walked(&root, &opts),
+            ["", "inside", "out1", "out2", "real", "real/a", "real/loop"]
+        );
+
+        let opts = walk::WalkOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        let found = walked(&root, &opts);
+        // `outside` is walked through exactly one of its two links; the links
+        // into the root itself are left out
+        let through = |name: &str| found.contains(&format!("{name}/b"));
+        assert!(through("out1") != through("out2"), "{found:?}");
+        assert_eq!(found.len(), 5, "{found:?}");
+        assert!(found.contains(&"real/a".to_string()));
+        assert!(!found
+            .iter()
+            .any(|p| p.starts_with("inside") || p.contains("loop")));
+
+        let linked: Vec<String> = walk_tree(&root, &opts)
+            .map(|e| e.unwrap())
+            .filter(|e| e.via_link)
+            .map(|e| e.path().file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(linked.len(), 2, "{linked:?}");
+        assert!(linked.contains(&"b".to_string()));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_walk_tree_one_file_system_does_not_enter_proc() {
+        use std::os::unix::fs::MetadataExt;
+        let root_dev = fs::metadata("/").unwrap().dev();
+        if fs::metadata("/proc")
+            .map(|m| m.dev() == root_dev)
+            .unwrap_or(true)
+        {
+            return; // /proc isn't a separate mount here
+        }
+        let opts = walk::WalkOptions {
+            one_file_system: true,
+            ..Default::default()
+        };
+        let entries: Vec<_> = walker(Path::new("/"), &opts)
+            .max_depth(2)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .map(|e| e.into_path())
+            .collect();
+        assert!(entries.iter().any(|p| p == Path::new("/proc")));
+        assert!(!entries
+            .iter()
+            .any(|p| p.parent() == Some(Path::new("/proc"))));
+    }
 }
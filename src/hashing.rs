@@ -1,48 +1,314 @@
 use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use rusqlite::Connection;
 use sha2::{Digest, Sha256};
-use walkdir::WalkDir;
+use xxhash_rust::xxh3::Xxh3;
 
-use crate::{db, scan};
+use crate::{db, file_system, scan, throttle, utils, walk};
 
-pub fn count_files(root: &Path) -> Result<usize> {
-    let mut count = 0;
-    for entry in WalkDir::new(root).follow_links(false) {
-        let entry = entry?;
-        if entry.path().is_file() {
-            count += 1;
+pub fn count_files(root: &Path, opts: &walk::WalkOptions) -> Result<usize> {
+    Ok(measure_tree(root, opts)?.0)
+}
+
+/// How many files a scan of `root` will see, and their combined size in bytes.
+pub fn measure_tree(root: &Path, opts: &walk::WalkOptions) -> Result<(usize, u64)> {
+    let (mut count, mut bytes) = (0, 0);
+    // Unreadable entries are the scan's to report
+    for entry in file_system::walk_tree(root, opts).flatten() {
+        if let Ok(metadata) = fs::metadata(entry.path()) {
+            if metadata.is_file() {
+                count += 1;
+                bytes += metadata.len();
+            }
         }
     }
-    Ok(count)
+    Ok((count, bytes))
+}
+
+/// Bytes read by every hashing thread in the process so far, whether for a
+/// full or a partial hash; progress displays take throughput from it.
+static BYTES_HASHED: AtomicU64 = AtomicU64::new(0);
+
+pub fn bytes_hashed() -> u64 {
+    BYTES_HASHED.load(Ordering::Relaxed)
 }
 
-pub fn compute_file_hash(path: &Path) -> Result<String> {
-    use std::io::Read;
+pub(crate) fn count_hashed(bytes: usize) {
+    BYTES_HASHED.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+/// Default read buffer for file hashing. Large enough to keep the disk busy,
+/// small enough that one buffer per worker thread is negligible.
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Content hash used for files and directories. A database only ever holds
+/// hashes from one algorithm — see `resolve_algorithm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum HashAlgorithm {
+    /// What every database used before `--hash` existed.
+    #[default]
+    Sha256,
+    /// Much faster than SHA-256 and still cryptographic; the default for new databases.
+    Blake3,
+    /// 128-bit XXH3: fastest, but not collision resistant against crafted input.
+    Xxh3,
+}
+
+impl HashAlgorithm {
+    /// The algorithm new databases get when `--hash` is not given.
+    pub const RECOMMENDED: Self = Self::Blake3;
+
+    /// Name as stored in the database and accepted by `--hash`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+            Self::Blake3 => "blake3",
+            Self::Xxh3 => "xxh3",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(Self::Sha256),
+            "blake3" => Some(Self::Blake3),
+            "xxh3" => Some(Self::Xxh3),
+            _ => None,
+        }
+    }
+
+    /// Hash of `data`, for content put together in memory rather than read
+    /// from a file.
+    pub fn hash_bytes(self, data: &[u8]) -> String {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    /// Hash of zero bytes — what an empty directory hashes to.
+    pub fn empty_hash(self) -> String {
+        self.hasher().finish()
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+            Self::Blake3 => Hasher::Blake3(Box::default()),
+            Self::Xxh3 => Hasher::Xxh3(Box::default()),
+        }
+    }
+}
+
+/// Streaming state for whichever `HashAlgorithm` is in use.
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<Xxh3>),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(h) => h.update(data),
+            Self::Blake3(h) => {
+                h.update(data);
+            }
+            Self::Xxh3(h) => h.update(data),
+        }
+    }
+
+    /// Lower-case hex digest.
+    fn finish(self) -> String {
+        match self {
+            Self::Sha256(h) => format!("{:x}", h.finalize()),
+            Self::Blake3(h) => h.finalize().to_hex().to_string(),
+            Self::Xxh3(h) => format!("{:032x}", h.digest128()),
+        }
+    }
+}
+
+/// Meta key under which the database records its hash algorithm.
+const ALGORITHM_META_KEY: &str = "hash_algorithm";
+
+/// The algorithm the hashes in the database were made with, without
+/// recording anything; `None` for a database that holds no hashes yet.
+pub fn recorded_algorithm(conn: &Connection) -> Result<Option<HashAlgorithm>> {
+    Ok(match db::get_meta(conn, ALGORITHM_META_KEY)? {
+        Some(name) => Some(
+            HashAlgorithm::from_name(&name)
+                .ok_or_else(|| anyhow!("database uses unknown hash algorithm '{name}'"))?,
+        ),
+        None if db::is_empty(conn)? => None,
+        None => Some(HashAlgorithm::Sha256),
+    })
+}
+
+/// Decide which algorithm this run hashes with, and record it in the database.
+///
+/// A database keeps the algorithm it was built with: hashes from different
+/// algorithms never match, so asking for another one is an error rather than
+/// a silent mix. Databases from before `--hash` existed hold SHA-256 hashes;
+/// brand-new ones use `requested`, or BLAKE3 if nothing was asked for.
+pub fn resolve_algorithm(
+    conn: &Connection,
+    requested: Option<HashAlgorithm>,
+) -> Result<HashAlgorithm> {
+    let stored = recorded_algorithm(conn)?;
+    let algorithm = match (stored, requested) {
+        (Some(stored), Some(requested)) if stored != requested => bail!(
+            "this database holds {} hashes and cannot be mixed with --hash {}; \
+             omit --hash or point --database at a new file",
+            stored.name(),
+            requested.name()
+        ),
+        (Some(stored), _) => stored,
+        (None, requested) => requested.unwrap_or(HashAlgorithm::RECOMMENDED),
+    };
+
+    db::set_meta(conn, ALGORITHM_META_KEY, algorithm.name())?;
+    Ok(algorithm)
+}
+
+/// Files at least this large are hashed through a memory map by default.
+pub const DEFAULT_MMAP_THRESHOLD: u64 = 256 * 1024 * 1024;
+
+/// Knobs for `compute_file_hash`.
+#[derive(Debug, Clone, Copy)]
+pub struct HashOptions {
+    /// Bytes read per `read()` call. Memory use per hash is bounded by this,
+    /// no matter how large the file is.
+    pub buffer_size: usize,
+    pub algorithm: HashAlgorithm,
+    /// Files of at least this many bytes are memory-mapped and hashed straight
+    /// from the page cache; `0` always streams.
+    pub mmap_threshold: u64,
+    /// Most bytes per second read by all hashing threads together; `0` reads
+    /// as fast as the disk allows.
+    pub throttle: u64,
+}
+
+impl Default for HashOptions {
+    fn default() -> Self {
+        Self {
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            algorithm: HashAlgorithm::default(),
+            mmap_threshold: DEFAULT_MMAP_THRESHOLD,
+            throttle: 0,
+        }
+    }
+}
+
+/// Hash the contents of `path`, streaming it through a fixed-size buffer so
+/// multi-GB files never have to fit in memory. Files over
+/// `opts.mmap_threshold` are memory-mapped instead, which saves copying every
+/// byte into the buffer; if mapping fails we fall back to streaming. With
+/// `opts.throttle`, every buffer's worth waits its turn in the shared budget.
+pub fn compute_file_hash(path: &Path, opts: &HashOptions) -> Result<String> {
     let mut file = fs::File::open(path)?;
-    let mut hasher = Sha256::new();
-    let mut buffer = [0; 8192];
+    let mut hasher = opts.algorithm.hasher();
+
+    if opts.mmap_threshold > 0 && file.metadata()?.len() >= opts.mmap_threshold {
+        // SAFETY: the map is read-only and dropped before we return. If another
+        // process truncates the file meanwhile we may fault, the same risk every
+        // mmap reader takes; a concurrent write just gives a hash of mixed
+        // content, which the next scan corrects when it sees the new mtime.
+        if let Ok(map) = unsafe { memmap2::Mmap::map(&file) } {
+            count_hashed(map.len());
+            if opts.throttle == 0 {
+                hasher.update(&map);
+            } else {
+                for chunk in map.chunks(opts.buffer_size.max(1)) {
+                    throttle::consume(opts.throttle, chunk.len());
+                    hasher.update(chunk);
+                }
+            }
+            return Ok(hasher.finish());
+        }
+    }
+    compute_reader_hash(&mut file, opts)
+}
+
+/// Hash everything `reader` yields, through a buffer of `opts.buffer_size`
+/// bytes and within the `opts.throttle` budget: the streaming half of
+/// `compute_file_hash`, also used for files read out of archives.
+pub fn compute_reader_hash(reader: &mut impl std::io::Read, opts: &HashOptions) -> Result<String> {
+    let mut hasher = opts.algorithm.hasher();
+    let mut buffer = vec![0; opts.buffer_size.max(1)];
 
     loop {
-        let n = file.read(&mut buffer)?;
+        let n = reader.read(&mut buffer)?;
         if n == 0 {
             break;
         }
+        throttle::consume(opts.throttle, n);
+        count_hashed(n);
         hasher.update(&buffer[..n]);
     }
 
-    let result = hasher.finalize();
-    Ok(format!("{:x}", result))
+    Ok(hasher.finish())
+}
+
+/// Bytes hashed from each end of a file for its partial hash.
+pub const PARTIAL_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// Hash the first and last `PARTIAL_CHUNK_SIZE` bytes of `path` — a cheap
+/// fingerprint for ruling out same-size files before reading them in full.
+/// Files no larger than two chunks are read whole, so for them the partial
+/// hash *is* the full hash.
+pub fn compute_partial_hash(path: &Path, size: u64, opts: &HashOptions) -> Result<String> {
+    use std::io::{Read, Seek, SeekFrom};
+    if size <= 2 * PARTIAL_CHUNK_SIZE {
+        return compute_file_hash(path, opts);
+    }
+
+    let mut file = fs::File::open(path)?;
+    let mut hasher = opts.algorithm.hasher();
+    let mut buffer = vec![0; PARTIAL_CHUNK_SIZE as usize];
+
+    for offset in [0, size - PARTIAL_CHUNK_SIZE] {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer)?;
+        throttle::consume(opts.throttle, buffer.len());
+        count_hashed(buffer.len());
+        hasher.update(&buffer);
+    }
+
+    Ok(hasher.finish())
+}
+
+/// Prefix of the placeholder hash `--prefilter` stores for files whose size
+/// or partial hash already proves they have no duplicate.
+pub const PROVISIONAL_PREFIX: &str = "unhashed:";
+
+/// A placeholder hash for a file known to be unique. It embeds the path, so
+/// it can never equal another file's hash and never forms a duplicate group.
+pub fn provisional_hash(path_str: &str) -> String {
+    format!("{PROVISIONAL_PREFIX}{path_str}")
+}
+
+/// Returns `true` if `hash` is a `provisional_hash` placeholder rather than
+/// a hash of the file's contents.
+pub fn is_provisional(hash: &str) -> bool {
+    hash.starts_with(PROVISIONAL_PREFIX)
 }
 
+/// Hash `dir_path` from its immediate children and store the result.
+/// Child files come from `files_by_dir` and child directories from
+/// `dirs_by_parent`, both keyed by parent path, so no DB lookups are needed;
+/// the caller works bottom-up and adds each returned record to
+/// `dirs_by_parent` before hashing the parent. Empty files are left out
+/// unless `include_empty` (see `scan::directories_count_empty_files`).
 pub fn compute_directory_hash(
     conn: &Connection,
     dir_path: &Path,
     files_by_dir: &HashMap<PathBuf, Vec<scan::FileEntry>>,
-) -> Result<()> {
+    dirs_by_parent: &HashMap<PathBuf, Vec<db::DirRecord>>,
+    algorithm: HashAlgorithm,
+    include_empty: bool,
+) -> Result<db::DirRecord> {
     // child files and directories in dir_path, as (name, hash, size) tuples
     let mut children = Vec::new();
 
@@ -50,6 +316,11 @@ pub fn compute_directory_hash(
     // this ensures we hash the files just scanned, not stale data from a prior run.
     if let Some(files) = files_by_dir.get(dir_path) {
         for file in files {
+            // A stray empty file (a lock, a placeholder) holds nothing, and
+            // would otherwise keep two copies of a tree apart
+            if file.size == 0 && !include_empty {
+                continue;
+            }
             // Use just the filename, not the full path, so hash doesn't depend on directory tree
             if let Some(filename) = Path::new(&file.path).file_name() {
                 children.push((
@@ -61,16 +332,16 @@ pub fn compute_directory_hash(
         }
     }
 
-    // Get immediate child directories from the DB — the caller traverses bottom-up,
-    // so child directory hashes are already committed before we hash the parent.
-    for child in db::child_directories(conn, dir_path)? {
-        let child_path = PathBuf::from(&child.path);
-        if let Some(dirname) = child_path.file_name() {
-            children.push((
-                dirname.to_string_lossy().to_string(),
-                child.hash,
-                child.size as u64,
-            ));
+    // Immediate child directories hashed earlier in the same bottom-up pass.
+    if let Some(dirs) = dirs_by_parent.get(dir_path) {
+        for child in dirs {
+            if let Some(dirname) = Path::new(&child.path).file_name() {
+                children.push((
+                    dirname.to_string_lossy().to_string(),
+                    child.hash.clone(),
+                    child.size as u64,
+                ));
+            }
         }
     }
 
@@ -78,7 +349,7 @@ pub fn compute_directory_hash(
     children.sort_by(|a, b| a.0.cmp(&b.0));
 
     // Compute combined hash using only relative names and content hashes
-    let mut hasher = Sha256::new();
+    let mut hasher = algorithm.hasher();
     let mut total_size = 0u64;
     for (name, hash, size) in &children {
         hasher.update(name.as_bytes());
@@ -87,11 +358,15 @@ pub fn compute_directory_hash(
         hasher.update(b"\n");
         total_size += size;
     }
-    let dir_hash = format!("{:x}", hasher.finalize());
+    let dir_hash = hasher.finish();
 
     db::upsert_directory(conn, dir_path, &dir_hash, total_size as i64)?;
 
-    Ok(())
+    Ok(db::DirRecord {
+        path: utils::path_to_db(dir_path).into_owned(),
+        hash: dir_hash,
+        size: total_size as i64,
+    })
 }
 
 // ------------------------------------------------------------------
@@ -105,7 +380,7 @@ pub fn compute_directory_hash(
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::db::{directories_with_hash, setup_schema, upsert_directory, EMPTY_DIR_HASH};
+    use crate::db::{directories_with_hash, setup_schema};
     use std::fs;
     use tempfile::tempdir;
 
@@ -118,7 +393,10 @@ mod tests {
     #[test]
     fn test_count_files_empty_dir() {
         let dir = tempdir().unwrap();
-        assert_eq!(count_files(dir.path()).unwrap(), 0);
+        assert_eq!(
+            count_files(dir.path(), &walk::WalkOptions::default()).unwrap(),
+            0
+        );
     }
 
     #[test]
@@ -126,7 +404,10 @@ mod tests {
         let dir = tempdir().unwrap();
         fs::write(dir.path().join("a.txt"), "hello").unwrap();
         fs::write(dir.path().join("b.txt"), "world").unwrap();
-        assert_eq!(count_files(dir.path()).unwrap(), 2);
+        assert_eq!(
+            count_files(dir.path(), &walk::WalkOptions::default()).unwrap(),
+            2
+        );
     }
 
     #[test]
@@ -136,7 +417,33 @@ mod tests {
         fs::create_dir(&sub).unwrap();
         fs::write(dir.path().join("a.txt"), "hello").unwrap();
         fs::write(sub.join("b.txt"), "world").unwrap();
-        assert_eq!(count_files(dir.path()).unwrap(), 2);
+        assert_eq!(
+            count_files(dir.path(), &walk::WalkOptions::default()).unwrap(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_measure_tree_adds_up_file_sizes() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        fs::write(dir.path().join("sub/b.txt"), "hi").unwrap();
+        assert_eq!(
+            measure_tree(dir.path(), &walk::WalkOptions::default()).unwrap(),
+            (2, 7)
+        );
+    }
+
+    #[test]
+    fn test_bytes_hashed_counts_what_is_read() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("a.txt");
+        fs::write(&path, vec![7u8; 1000]).unwrap();
+        let before = bytes_hashed();
+        compute_file_hash(&path, &HashOptions::default()).unwrap();
+        // Other tests hash at the same time
+        assert!(bytes_hashed() - before >= 1000);
     }
 
     #[test]
@@ -146,7 +453,7 @@ mod tests {
         let file = dir.path().join("test.txt");
         fs::write(&file, "hello world").unwrap();
         assert_eq!(
-            compute_file_hash(&file).unwrap(),
+            compute_file_hash(&file, &HashOptions::default()).unwrap(),
             "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
         );
     }
@@ -156,8 +463,8 @@ mod tests {
         let dir = tempdir().unwrap();
         let file = dir.path().join("test.txt");
         fs::write(&file, "hello world").unwrap();
-        let hash1 = compute_file_hash(&file).unwrap();
-        let hash2 = compute_file_hash(&file).unwrap();
+        let hash1 = compute_file_hash(&file, &HashOptions::default()).unwrap();
+        let hash2 = compute_file_hash(&file, &HashOptions::default()).unwrap();
         assert_eq!(hash1, hash2);
     }
 
@@ -169,8 +476,8 @@ mod tests {
         fs::write(&f1, "hello").unwrap();
         fs::write(&f2, "world").unwrap();
         assert_ne!(
-            compute_file_hash(&f1).unwrap(),
-            compute_file_hash(&f2).unwrap()
+            compute_file_hash(&f1, &HashOptions::default()).unwrap(),
+            compute_file_hash(&f2, &HashOptions::default()).unwrap()
         );
     }
 
@@ -182,9 +489,259 @@ mod tests {
         fs::write(&f1, "same content").unwrap();
         fs::write(&f2, "same content").unwrap();
         assert_eq!(
-            compute_file_hash(&f1).unwrap(),
-            compute_file_hash(&f2).unwrap()
+            compute_file_hash(&f1, &HashOptions::default()).unwrap(),
+            compute_file_hash(&f2, &HashOptions::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compute_file_hash_buffer_size_does_not_change_hash() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("test.txt");
+        fs::write(&file, "hello world").unwrap();
+        for buffer_size in [1, 3, 4096] {
+            assert_eq!(
+                compute_file_hash(
+                    &file,
+                    &HashOptions {
+                        buffer_size,
+                        ..HashOptions::default()
+                    }
+                )
+                .unwrap(),
+                "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+            );
+        }
+    }
+
+    #[test]
+    fn test_compute_file_hash_sparse_file_streams_in_chunks() {
+        // 64 MiB of zeros, from a reader that refuses any read bigger than the
+        // 64 KiB buffer, so reading it all in at once would fail
+        struct ChunkReader {
+            left: usize,
+        }
+        impl std::io::Read for ChunkReader {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if buf.len() > 64 * 1024 {
+                    return Err(std::io::Error::other("read of more than one buffer"));
+                }
+                let n = buf.len().min(self.left);
+                buf[..n].fill(0);
+                self.left -= n;
+                Ok(n)
+            }
+        }
+        let opts = HashOptions {
+            buffer_size: 64 * 1024,
+            ..HashOptions::default()
+        };
+        let mut reader = ChunkReader {
+            left: 64 * 1024 * 1024,
+        };
+        let expected = "3b6a07d0d404fab4e23b6d34bc6696a6a312dd92821332385e5af7c01c421351";
+        assert_eq!(compute_reader_hash(&mut reader, &opts).unwrap(), expected);
+
+        // And the same from a sparse file of that size
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("sparse.bin");
+        fs::File::create(&file)
+            .unwrap()
+            .set_len(64 * 1024 * 1024)
+            .unwrap();
+        assert_eq!(compute_file_hash(&file, &opts).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_compute_file_hash_mmap_matches_streaming() {
+        // Same sparse file as above, once through a memory map and once streamed
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("sparse.bin");
+        fs::File::create(&file)
+            .unwrap()
+            .set_len(64 * 1024 * 1024)
+            .unwrap();
+        for mmap_threshold in [1, 0] {
+            let opts = HashOptions {
+                mmap_threshold,
+                ..HashOptions::default()
+            };
+            assert_eq!(
+                compute_file_hash(&file, &opts).unwrap(),
+                "3b6a07d0d404fab4e23b6d34bc6696a6a312dd92821332385e5af7c01c421351"
+            );
+        }
+    }
+
+    #[test]
+    fn test_compute_file_hash_throttled_is_paced_and_unchanged() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("f.bin");
+        fs::write(&file, vec![7u8; 300 * 1024]).unwrap();
+        let plain = compute_file_hash(&file, &HashOptions::default()).unwrap();
+        // 300 KiB at 1 MiB/s takes about 0.3s, less the 0.1s burst
+        for mmap_threshold in [1, 0] {
+            let opts = HashOptions {
+                buffer_size: 64 * 1024,
+                mmap_threshold,
+                throttle: 1024 * 1024,
+                ..HashOptions::default()
+            };
+            let start = std::time::Instant::now();
+            assert_eq!(compute_file_hash(&file, &opts).unwrap(), plain);
+            assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+        }
+    }
+
+    #[test]
+    fn test_compute_file_hash_each_algorithm_known_value() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("test.txt");
+        fs::write(&file, "hello world").unwrap();
+        for (algorithm, expected) in [
+            (
+                HashAlgorithm::Sha256,
+                "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+            ),
+            (
+                HashAlgorithm::Blake3,
+                "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
+            ),
+            (HashAlgorithm::Xxh3, "df8d09e93f874900a99b8775cc15b6c7"),
+        ] {
+            let opts = HashOptions {
+                algorithm,
+                ..HashOptions::default()
+            };
+            assert_eq!(compute_file_hash(&file, &opts).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_sha256_empty_hash_known_value() {
+        assert_eq!(
+            HashAlgorithm::Sha256.empty_hash(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn test_algorithm_name_round_trips() {
+        for algorithm in [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Blake3,
+            HashAlgorithm::Xxh3,
+        ] {
+            assert_eq!(HashAlgorithm::from_name(algorithm.name()), Some(algorithm));
+        }
+        assert_eq!(HashAlgorithm::from_name("md5"), None);
+    }
+
+    // -----------------------------------------------------------------------
+    // resolve_algorithm
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_resolve_algorithm_new_db_defaults_to_blake3_and_records_it() {
+        let conn = open_test_db();
+        assert_eq!(
+            resolve_algorithm(&conn, None).unwrap(),
+            HashAlgorithm::Blake3
+        );
+        assert_eq!(
+            db::get_meta(&conn, ALGORITHM_META_KEY).unwrap().as_deref(),
+            Some("blake3")
+        );
+    }
+
+    #[test]
+    fn test_resolve_algorithm_new_db_honours_request() {
+        let conn = open_test_db();
+        assert_eq!(
+            resolve_algorithm(&conn, Some(HashAlgorithm::Xxh3)).unwrap(),
+            HashAlgorithm::Xxh3
+        );
+        // Later runs without --hash keep using it
+        assert_eq!(resolve_algorithm(&conn, None).unwrap(), HashAlgorithm::Xxh3);
+    }
+
+    #[test]
+    fn test_resolve_algorithm_rejects_mismatch() {
+        let conn = open_test_db();
+        resolve_algorithm(&conn, Some(HashAlgorithm::Blake3)).unwrap();
+        assert!(resolve_algorithm(&conn, Some(HashAlgorithm::Sha256)).is_err());
+        assert!(resolve_algorithm(&conn, Some(HashAlgorithm::Blake3)).is_ok());
+    }
+
+    #[test]
+    fn test_resolve_algorithm_legacy_db_is_sha256() {
+        // A database with records but no recorded algorithm predates --hash
+        let conn = open_test_db();
+        db::upsert_file(&conn, Path::new("/a.txt"), "h", 1, 0).unwrap();
+        assert_eq!(
+            resolve_algorithm(&conn, None).unwrap(),
+            HashAlgorithm::Sha256
         );
+        assert!(resolve_algorithm(&conn, Some(HashAlgorithm::Blake3)).is_err());
+    }
+
+    // -----------------------------------------------------------------------
+    // compute_partial_hash / provisional hashes
+    // -----------------------------------------------------------------------
+
+    #[test]
+    fn test_compute_partial_hash_small_file_is_full_hash() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("test.txt");
+        fs::write(&file, "hello world").unwrap();
+        let opts = HashOptions::default();
+        assert_eq!(
+            compute_partial_hash(&file, 11, &opts).unwrap(),
+            compute_file_hash(&file, &opts).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_compute_partial_hash_ignores_middle_of_large_file() {
+        let dir = tempdir().unwrap();
+        let len = 4 * PARTIAL_CHUNK_SIZE as usize;
+        let mut a = vec![0u8; len];
+        let mut b = vec![0u8; len];
+        a[len / 2] = 1;
+        b[len / 2] = 2;
+        let (fa, fb) = (dir.path().join("a.bin"), dir.path().join("b.bin"));
+        fs::write(&fa, &a).unwrap();
+        fs::write(&fb, &b).unwrap();
+
+        let opts = HashOptions::default();
+        assert_eq!(
+            compute_partial_hash(&fa, len as u64, &opts).unwrap(),
+            compute_partial_hash(&fb, len as u64, &opts).unwrap()
+        );
+        assert_ne!(
+            compute_file_hash(&fa, &opts).unwrap(),
+            compute_file_hash(&fb, &opts).unwrap()
+        );
+
+        // A difference in the last chunk is caught
+        b[len / 2] = 1;
+        b[len - 1] = 9;
+        fs::write(&fb, &b).unwrap();
+        assert_ne!(
+            compute_partial_hash(&fa, len as u64, &opts).unwrap(),
+            compute_partial_hash(&fb, len as u64, &opts).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_provisional_hash_is_recognised_and_unique() {
+        let a = provisional_hash("/a.txt");
+        let b = provisional_hash("/b.txt");
+        assert!(is_provisional(&a));
+        assert_ne!(a, b);
+        assert!(!is_provisional(
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        ));
     }
 
     // -----------------------------------------------------------------------
@@ -197,9 +754,17 @@ mod tests {
         let dir = tempdir().unwrap();
         let files_by_dir = HashMap::new();
 
-        compute_directory_hash(&conn, dir.path(), &files_by_dir).unwrap();
-
-        let records = directories_with_hash(&conn, EMPTY_DIR_HASH).unwrap();
+        compute_directory_hash(
+            &conn,
+            dir.path(),
+            &files_by_dir,
+            &HashMap::new(),
+            HashAlgorithm::Sha256,
+            false,
+        )
+        .unwrap();
+
+        let records = directories_with_hash(&conn, &HashAlgorithm::Sha256.empty_hash()).unwrap();
         assert_eq!(records.len(), 1);
         assert_eq!(records[0].size, 0);
     }
@@ -226,13 +791,29 @@ mod tests {
         );
 
         let conn1 = open_test_db();
-        compute_directory_hash(&conn1, dir.path(), &files_by_dir).unwrap();
+        compute_directory_hash(
+            &conn1,
+            dir.path(),
+            &files_by_dir,
+            &HashMap::new(),
+            HashAlgorithm::Sha256,
+            false,
+        )
+        .unwrap();
         let hash1: String = conn1
             .query_row("SELECT hash FROM directories LIMIT 1", [], |r| r.get(0))
             .unwrap();
 
         let conn2 = open_test_db();
-        compute_directory_hash(&conn2, dir.path(), &files_by_dir).unwrap();
+        compute_directory_hash(
+            &conn2,
+            dir.path(),
+            &files_by_dir,
+            &HashMap::new(),
+            HashAlgorithm::Sha256,
+            false,
+        )
+        .unwrap();
         let hash2: String = conn2
             .query_row("SELECT hash FROM directories LIMIT 1", [], |r| r.get(0))
             .unwrap();
@@ -265,8 +846,24 @@ mod tests {
             }],
         );
 
-        compute_directory_hash(&conn1, dir.path(), &files_a).unwrap();
-        compute_directory_hash(&conn2, dir.path(), &files_b).unwrap();
+        compute_directory_hash(
+            &conn1,
+            dir.path(),
+            &files_a,
+            &HashMap::new(),
+            HashAlgorithm::Sha256,
+            false,
+        )
+        .unwrap();
+        compute_directory_hash(
+            &conn2,
+            dir.path(),
+            &files_b,
+            &HashMap::new(),
+            HashAlgorithm::Sha256,
+            false,
+        )
+        .unwrap();
 
         let hash1: String = conn1
             .query_row("SELECT hash FROM directories LIMIT 1", [], |r| r.get(0))
@@ -287,15 +884,43 @@ mod tests {
         let grandchild = child.join("grandchild");
 
         let files_by_dir = HashMap::new();
+        let child_record = |path: &Path, hash: &str, size: i64| db::DirRecord {
+            path: path.to_str().unwrap().to_string(),
+            hash: hash.to_string(),
+            size,
+        };
 
         // conn1: child dir only
-        upsert_directory(&conn1, &child, "child_hash", 50).unwrap();
-        compute_directory_hash(&conn1, dir.path(), &files_by_dir).unwrap();
+        let mut dirs1 = HashMap::new();
+        dirs1.insert(
+            dir.path().to_path_buf(),
+            vec![child_record(&child, "child_hash", 50)],
+        );
+        compute_directory_hash(
+            &conn1,
+            dir.path(),
+            &files_by_dir,
+            &dirs1,
+            HashAlgorithm::Sha256,
+            false,
+        )
+        .unwrap();
 
         // conn2: child dir + grandchild dir (grandchild should not change parent hash)
-        upsert_directory(&conn2, &child, "child_hash", 50).unwrap();
-        upsert_directory(&conn2, &grandchild, "grandchild_hash", 25).unwrap();
-        compute_directory_hash(&conn2, dir.path(), &files_by_dir).unwrap();
+        let mut dirs2 = dirs1.clone();
+        dirs2.insert(
+            child.clone(),
+            vec![child_record(&grandchild, "grandchild_hash", 25)],
+        );
+        compute_directory_hash(
+            &conn2,
+            dir.path(),
+            &files_by_dir,
+            &dirs2,
+            HashAlgorithm::Sha256,
+            false,
+        )
+        .unwrap();
 
         let hash1: String = conn1
             .query_row(
@@ -339,11 +964,48 @@ mod tests {
             ],
         );
 
-        compute_directory_hash(&conn, dir.path(), &files_by_dir).unwrap();
+        compute_directory_hash(
+            &conn,
+            dir.path(),
+            &files_by_dir,
+            &HashMap::new(),
+            HashAlgorithm::Sha256,
+            false,
+        )
+        .unwrap();
 
         let size: i64 = conn
             .query_row("SELECT size FROM directories LIMIT 1", [], |r| r.get(0))
             .unwrap();
         assert_eq!(size, 300);
     }
+
+    #[test]
+    fn test_compute_directory_hash_leaves_out_empty_files_unless_included() {
+        let dir = tempdir().unwrap();
+        let entry = |name: &str, hash: &str, size| scan::FileEntry {
+            path: dir.path().join(name).to_str().unwrap().to_string(),
+            hash: hash.to_string(),
+            size,
+        };
+        let plain = vec![entry("a.txt", "h1", 100)];
+        let mut with_empty = plain.clone();
+        with_empty.push(entry(".lock", &HashAlgorithm::Sha256.empty_hash(), 0));
+
+        let hash = |files: &Vec<scan::FileEntry>, include_empty| {
+            let files_by_dir = HashMap::from([(dir.path().to_path_buf(), files.clone())]);
+            compute_directory_hash(
+                &open_test_db(),
+                dir.path(),
+                &files_by_dir,
+                &HashMap::new(),
+                HashAlgorithm::Sha256,
+                include_empty,
+            )
+            .unwrap()
+            .hash
+        };
+        assert_eq!(hash(&plain, false), hash(&with_empty, false));
+        assert_ne!(hash(&plain, true), hash(&with_empty, true));
+    }
 }
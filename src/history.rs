@@ -0,0 +1,238 @@
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Result};
+use rusqlite::Connection;
+
+use crate::{db, hashing};
+
+/// A file whose content differs between the two scans.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Changed {
+    pub from: db::HistoryFile,
+    pub to: db::HistoryFile,
+}
+
+/// A content hash held by two or more files in one scan but not the other,
+/// with the paths that had it in the scan where it was duplicated.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DuplicateGroup {
+    pub hash: String,
+    pub size: i64,
+    pub paths: Vec<String>,
+}
+
+/// What changed from one scan to a later one.
+pub struct ScanDiff {
+    pub from: db::ScanRecord,
+    pub to: db::ScanRecord,
+    /// Files present after `to` but not after `from`
+    pub appeared: Vec<db::HistoryFile>,
+    /// Files present after `from` but gone after `to`
+    pub removed: Vec<db::HistoryFile>,
+    /// Files present after both whose content changed
+    pub changed: Vec<Changed>,
+    /// Content duplicated after `to` that wasn't after `from`
+    pub new_duplicates: Vec<DuplicateGroup>,
+    /// Content duplicated after `from` that no longer is after `to`
+    pub resolved_duplicates: Vec<DuplicateGroup>,
+}
+
+/// Compare the files as scan `from` left them with the files as scan `to`
+/// left them. Directories neither scan covered look the same in both. A file
+/// only counts as changed when its size differs or both scans have a real
+/// hash for it, since `--prefilter` replaces provisional hashes without the
+/// file changing.
+pub fn diff_scans(conn: &Connection, from: i64, to: i64) -> Result<ScanDiff> {
+    let scans = db::scans(conn)?;
+    let find = |id: i64| match scans.iter().find(|s| s.id == id) {
+        Some(scan) => Ok(scan.clone()),
+        None => bail!("no scan {id} in the database; `deduplifier scans` lists them"),
+    };
+    let (from_scan, to_scan) = (find(from)?, find(to)?);
+    if from >= to {
+        bail!("--from must be an earlier scan than --to ({from} is not before {to})");
+    }
+
+    let before = db::files_at_scan(conn, from)?;
+    let after = db::files_at_scan(conn, to)?;
+    let after_by_path: HashMap<&str, &db::HistoryFile> =
+        after.iter().map(|f| (f.path.as_str(), f)).collect();
+    let before_paths: HashSet<&str> = before.iter().map(|f| f.path.as_str()).collect();
+
+    let mut removed = Vec::new();
+    let mut changed = Vec::new();
+    for file in &before {
+        match after_by_path.get(file.path.as_str()) {
+            None => removed.push(file.clone()),
+            Some(&now) if content_changed(file, now) => changed.push(Changed {
+                from: file.clone(),
+                to: now.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    let appeared = after
+        .iter()
+        .filter(|f| !before_paths.contains(f.path.as_str()))
+        .cloned()
+        .collect();
+
+    let before_groups = duplicate_groups(&before);
+    let after_groups = duplicate_groups(&after);
+    let only_in = |groups: &[DuplicateGroup], other: &[DuplicateGroup]| {
+        let other: HashSet<&str> = other.iter().map(|g| g.hash.as_str()).collect();
+        groups
+            .iter()
+            .filter(|g| !other.contains(g.hash.as_str()))
+            .cloned()
+            .collect()
+    };
+
+    Ok(ScanDiff {
+        new_duplicates: only_in(&after_groups, &before_groups),
+        resolved_duplicates: only_in(&before_groups, &after_groups),
+        from: from_scan,
+        to: to_scan,
+        appeared,
+        removed,
+        changed,
+    })
+}
+
+fn content_changed(a: &db::HistoryFile, b: &db::HistoryFile) -> bool {
+    if a.size != b.size {
+        return true;
+    }
+    let provisional = hashing::is_provisional(&a.hash) || hashing::is_provisional(&b.hash);
+    !provisional && a.hash != b.hash
+}
+
+/// The hashes shared by two or more non-empty files, largest first. Empty
+/// files and provisional hashes are never duplicates here, as in reports.
+fn duplicate_groups(files: &[db::HistoryFile]) -> Vec<DuplicateGroup> {
+    let mut by_hash: HashMap<&str, Vec<&db::HistoryFile>> = HashMap::new();
+    for file in files {
+        if file.size > 0 && !hashing::is_provisional(&file.hash) {
+            by_hash.entry(&file.hash).or_default().push(file);
+        }
+    }
+    let mut groups: Vec<DuplicateGroup> = by_hash
+        .into_iter()
+        .filter(|(_, files)| files.len() > 1)
+        .map(|(hash, files)| DuplicateGroup {
+            hash: hash.to_string(),
+            size: files[0].size,
+            paths: files.iter().map(|f| f.path.clone()).collect(),
+        })
+        .collect();
+    groups.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.hash.cmp(&b.hash)));
+    groups
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    /// Record a scan of `/r` that saw exactly `files` (path, hash, size).
+    fn scan(conn: &Connection, files: &[(&str, &str, i64)]) -> i64 {
+        let id = db::begin_scan(conn, 0, &["/r"]).unwrap();
+        db::init_visited_files(conn).unwrap();
+        conn.execute("DELETE FROM files", []).unwrap();
+        for &(path, hash, size) in files {
+            conn.execute(
+                "INSERT INTO files (path, hash, size, modified) VALUES (?1, ?2, ?3, 0)",
+                rusqlite::params![path, hash, size],
+            )
+            .unwrap();
+            db::mark_visited(conn, path).unwrap();
+        }
+        db::record_scan_history(conn, id, "/r").unwrap();
+        id
+    }
+
+    fn paths(files: &[db::HistoryFile]) -> Vec<&str> {
+        files.iter().map(|f| f.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_diff_reports_files_and_duplicates_between_scans() {
+        let conn = setup();
+        let a = scan(
+            &conn,
+            &[
+                ("/r/keep", "k", 10),
+                ("/r/edit", "e1", 10),
+                ("/r/gone", "g", 10),
+                ("/r/dup1", "d", 10),
+                ("/r/dup2", "d", 10),
+            ],
+        );
+        let b = scan(
+            &conn,
+            &[
+                ("/r/keep", "k", 10),
+                ("/r/edit", "e2", 10),
+                ("/r/dup1", "d", 10),
+                ("/r/new1", "n", 10),
+                ("/r/new2", "n", 10),
+            ],
+        );
+        let diff = diff_scans(&conn, a, b).unwrap();
+        assert_eq!(paths(&diff.appeared), vec!["/r/new1", "/r/new2"]);
+        assert_eq!(paths(&diff.removed), vec!["/r/dup2", "/r/gone"]);
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].to.hash, "e2");
+        assert_eq!(diff.new_duplicates.len(), 1);
+        assert_eq!(diff.new_duplicates[0].hash, "n");
+        assert_eq!(diff.resolved_duplicates.len(), 1);
+        assert_eq!(diff.resolved_duplicates[0].hash, "d");
+    }
+
+    #[test]
+    fn test_diff_ignores_provisional_hashes_and_empty_files() {
+        let conn = setup();
+        let provisional = format!("{}x", hashing::PROVISIONAL_PREFIX);
+        let a = scan(
+            &conn,
+            &[
+                ("/r/big", &provisional, 10),
+                ("/r/e1", "z", 0),
+                ("/r/e2", "z", 0),
+            ],
+        );
+        // The prefilter later gave the file its real hash; nothing changed
+        let b = scan(
+            &conn,
+            &[("/r/big", "real", 10), ("/r/e1", "z", 0), ("/r/e2", "z", 0)],
+        );
+        let diff = diff_scans(&conn, a, b).unwrap();
+        assert!(diff.changed.is_empty());
+        assert!(diff.new_duplicates.is_empty());
+        assert!(diff.resolved_duplicates.is_empty());
+    }
+
+    #[test]
+    fn test_diff_needs_two_known_scans_in_order() {
+        let conn = setup();
+        let a = scan(&conn, &[]);
+        let b = scan(&conn, &[]);
+        assert!(diff_scans(&conn, a, b).is_ok());
+        assert!(diff_scans(&conn, b, a).is_err());
+        assert!(diff_scans(&conn, a, a).is_err());
+        assert!(diff_scans(&conn, a, 99).is_err());
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use rusqlite::Connection;
+use serde_json::{json, Value};
+
+use crate::duplicates::{DuplicateDirGroup, DuplicateFileGroup};
+use crate::{db, file_system, report, scan};
+
+/// External commands that are handed what a run found, so policies of one's
+/// own (notifying, tagging, uploading) can be plugged in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hooks {
+    /// Run once for every duplicate group reported
+    pub on_duplicate_group: Option<String>,
+    /// Run once a scan has finished
+    pub post_scan: Option<String>,
+}
+
+/// Run `command` through the shell (`sh -c`, or `cmd /C` on Windows) with
+/// `event` on its standard input as one line of JSON, and the event's name
+/// (its `event` field) in `DEDUPLIFIER_EVENT`. Its output goes where the
+/// run's does. Fails if it can't be started or doesn't exit successfully.
+/// In a dry run the command is only printed, since it may change anything.
+pub fn run(command: &str, event: &Value) -> Result<()> {
+    if file_system::dry_run() {
+        println!("[dry run] run `{command}`");
+        return Ok(());
+    }
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut child = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .env("DEDUPLIFIER_EVENT", event["event"].as_str().unwrap_or(""))
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("could not start `{command}`"))?;
+    let mut line = event.to_string();
+    line.push('\n');
+    if let Some(mut stdin) = child.stdin.take() {
+        match stdin.write_all(line.as_bytes()) {
+            // A command that doesn't read its input is fine
+            Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e.into()),
+            _ => {}
+        }
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("`{command}` failed ({status})");
+    }
+    Ok(())
+}
+
+/// The `duplicate_group` event for a group of identical files: the group as
+/// `report --format json` lists it, under `group`.
+pub fn file_group_event(group: &DuplicateFileGroup, locations: &HashMap<String, String>) -> Value {
+    json!({
+        "event": "duplicate_group",
+        "kind": "files",
+        "group": report::file_group_to_value(group, locations),
+    })
+}
+
+/// The `duplicate_group` event for a group of identical directories.
+pub fn dir_group_event(group: &DuplicateDirGroup, locations: &HashMap<String, String>) -> Value {
+    json!({
+        "event": "duplicate_group",
+        "kind": "directories",
+        "group": report::dir_group_to_value(group, locations),
+    })
+}
+
+/// The `post_scan` event for scan `scan_id`: its roots, what it found added,
+/// changed and removed, what it couldn't read, how long it took, and the
+/// files and bytes now in the database.
+pub fn post_scan_event(
+    conn: &Connection,
+    scan_id: i64,
+    errors: &[scan::ScanError],
+    elapsed: Duration,
+) -> Result<Value> {
+    let Some(record) = db::scans(conn)?.into_iter().find(|s| s.id == scan_id) else {
+        bail!("scan {scan_id} is not recorded");
+    };
+    let (total_files, total_bytes) = db::file_totals(conn)?;
+    let errors: Vec<Value> = errors
+        .iter()
+        .map(|e| json!({ "path": e.path, "kind": e.kind, "message": e.message }))
+        .collect();
+    Ok(json!({
+        "event": "post_scan",
+        "scan_id": record.id,
+        "started": record.time,
+        "elapsed_secs": elapsed.as_secs_f64(),
+        "roots": record.roots,
+        "added": record.added,
+        "changed": record.changed,
+        "removed": record.removed,
+        "errors": errors,
+        "total_files": total_files,
+        "total_bytes": total_bytes,
+    }))
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duplicates::FileEntry;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_file_group_event_holds_the_report_group() {
+        let group = DuplicateFileGroup {
+            hash: "abc".to_string(),
+            count: 2,
+            total_size: 10,
+            files: ["/a/x", "/b/x"]
+                .iter()
+                .map(|p| FileEntry {
+                    path: p.to_string(),
+                    size: 5,
+                    modified: 0,
+                    hardlinks: Vec::new(),
+                    via_link: false,
+                })
+                .collect(),
+        };
+        let event = file_group_event(&group, &HashMap::new());
+        assert_eq!(event["event"], "duplicate_group");
+        assert_eq!(event["kind"], "files");
+        assert_eq!(event["group"]["hash"], "abc");
+        assert_eq!(event["group"]["wasted"], 5);
+        assert_eq!(event["group"]["files"][1]["path"], "/b/x");
+    }
+
+    #[test]
+    fn test_post_scan_event_sums_up_the_scan() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let store = crate::HashStore::open_in_memory().unwrap();
+        crate::Scanner::default()
+            .scan(&store, &[dir.path()], |_, _, _| {})
+            .unwrap();
+        let conn = store.connection();
+        let scan_id = db::scans(conn).unwrap()[0].id;
+
+        let event = post_scan_event(conn, scan_id, &[], Duration::from_millis(1500)).unwrap();
+        assert_eq!(event["event"], "post_scan");
+        assert_eq!(event["added"], 1);
+        assert_eq!(event["total_bytes"], 5);
+        assert_eq!(event["elapsed_secs"], 1.5);
+        assert_eq!(event["errors"], json!([]));
+        let root = event["roots"][0].as_str().unwrap();
+        assert_eq!(Path::new(root), dir.path());
+        assert!(post_scan_event(conn, scan_id + 1, &[], Duration::ZERO).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_pipes_the_event_to_the_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let command = format!(
+            "cat > '{}'; echo $DEDUPLIFIER_EVENT >> '{}'",
+            out.display(),
+            out.display()
+        );
+        run(&command, &json!({ "event": "post_scan", "added": 1 })).unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "{\"added\":1,\"event\":\"post_scan\"}\npost_scan\n"
+        );
+
+        let error = run("exit 3", &json!({ "event": "post_scan" })).unwrap_err();
+        assert!(error.to_string().starts_with("`exit 3` failed"));
+        // Not reading the event is no failure
+        run("true", &json!({ "event": "post_scan" })).unwrap();
+
+        let touched = dir.path().join("touched");
+        file_system::set_dry_run(true);
+        let result = run(&format!("touch '{}'", touched.display()), &json!({}));
+        file_system::set_dry_run(false);
+        result.unwrap();
+        assert!(!touched.exists());
+    }
+}
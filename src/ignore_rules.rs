@@ -0,0 +1,222 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use rusqlite::Connection;
+
+use crate::db;
+
+/// Fewest hex digits an ignored hash may have: reports show hashes cut to
+/// 16, and anything much shorter would match unrelated groups.
+const MIN_HASH_DIGITS: usize = 8;
+
+/// What an ignore rule is matched against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RuleKind {
+    /// A glob matched against the end of each path; without a `/`, against
+    /// the file name
+    Glob,
+    /// A content hash, or the start of one
+    Hash,
+    /// A file or directory, and everything under it
+    Path,
+}
+
+impl RuleKind {
+    /// Name as stored in the database.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Glob => "glob",
+            Self::Hash => "hash",
+            Self::Path => "path",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "glob" => Some(Self::Glob),
+            "hash" => Some(Self::Hash),
+            "path" => Some(Self::Path),
+            _ => None,
+        }
+    }
+
+    /// What `pattern` most likely is: a glob if it has glob syntax in it, a
+    /// hash if it is all hex digits and no such path exists, a path
+    /// otherwise.
+    pub fn guess(pattern: &str) -> Self {
+        if pattern.contains(['*', '?', '[', '{']) {
+            Self::Glob
+        } else if is_hash(pattern) && !Path::new(pattern).exists() {
+            Self::Hash
+        } else {
+            Self::Path
+        }
+    }
+}
+
+fn is_hash(pattern: &str) -> bool {
+    pattern.len() >= MIN_HASH_DIGITS && pattern.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// `pattern` the way a `kind` rule stores it, or why it can't be one.
+pub fn normalize(kind: RuleKind, pattern: &str) -> Result<String> {
+    match kind {
+        RuleKind::Glob => {
+            Glob::new(pattern)?;
+            Ok(pattern.to_string())
+        }
+        RuleKind::Hash if is_hash(pattern) => Ok(pattern.to_ascii_lowercase()),
+        RuleKind::Hash => {
+            bail!("'{pattern}' is not a hash: give at least {MIN_HASH_DIGITS} hex digits")
+        }
+        // Matched against stored paths, so cleaned up the way they are
+        RuleKind::Path => Ok(Path::new(pattern)
+            .components()
+            .collect::<PathBuf>()
+            .to_string_lossy()
+            .into_owned()),
+    }
+}
+
+/// A compiled glob rule.
+#[derive(Debug, Clone)]
+struct Glob {
+    matcher: GlobMatcher,
+    /// Matched against the whole path rather than the file name
+    whole_path: bool,
+}
+
+impl Glob {
+    fn new(pattern: &str) -> Result<Self> {
+        // Stored paths are absolute: `sub/*.txt` is taken to mean any `sub`
+        let anchored = if pattern.starts_with(['/', '*']) {
+            pattern.to_string()
+        } else {
+            format!("**/{pattern}")
+        };
+        let matcher = GlobBuilder::new(&anchored)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("invalid glob '{}'", pattern))?
+            .compile_matcher();
+        Ok(Self {
+            matcher,
+            whole_path: pattern.contains('/'),
+        })
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        if self.whole_path {
+            self.matcher.is_match(path)
+        } else {
+            path.file_name().is_some_and(|n| self.matcher.is_match(n))
+        }
+    }
+}
+
+/// The rules `deduplifier ignore add` stored: files and directories they
+/// match are left out of every duplicate group, and groups whose hash they
+/// name aren't reported or acted on at all.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreRules {
+    globs: Vec<Glob>,
+    hashes: Vec<String>,
+    paths: Vec<PathBuf>,
+}
+
+impl IgnoreRules {
+    /// The ignore rules stored in the database.
+    pub fn load(conn: &Connection) -> Result<Self> {
+        let mut rules = Self::default();
+        for (kind, pattern) in db::ignore_rules(conn)? {
+            match RuleKind::from_name(&kind) {
+                Some(RuleKind::Glob) => rules.globs.push(Glob::new(&pattern)?),
+                Some(RuleKind::Hash) => rules.hashes.push(pattern),
+                Some(RuleKind::Path) => rules.paths.push(PathBuf::from(pattern)),
+                None => bail!("unknown kind of ignore rule '{kind}' in the database"),
+            }
+        }
+        Ok(rules)
+    }
+
+    /// Whether the group of everything with content `hash` is ignored.
+    pub fn ignores_hash(&self, hash: &str) -> bool {
+        self.hashes.iter().any(|h| hash.starts_with(h.as_str()))
+    }
+
+    /// Whether the file or directory stored as `path` is left out of groups.
+    pub fn ignores_path(&self, path: &str) -> bool {
+        let path = Path::new(path);
+        self.paths.iter().any(|p| path.starts_with(p)) || self.globs.iter().any(|g| g.matches(path))
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(stored: &[(RuleKind, &str)]) -> IgnoreRules {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        for &(kind, pattern) in stored {
+            let pattern = normalize(kind, pattern).unwrap();
+            db::add_ignore_rule(&conn, kind.name(), &pattern).unwrap();
+        }
+        IgnoreRules::load(&conn).unwrap()
+    }
+
+    #[test]
+    fn test_guess_tells_globs_hashes_and_paths_apart() {
+        assert_eq!(RuleKind::guess("*.dll"), RuleKind::Glob);
+        assert_eq!(RuleKind::guess("/etc/{a,b}"), RuleKind::Glob);
+        assert_eq!(RuleKind::guess("87428fc522803d31"), RuleKind::Hash);
+        assert_eq!(RuleKind::guess("cafe"), RuleKind::Path);
+        assert_eq!(RuleKind::guess("/srv/mirror"), RuleKind::Path);
+    }
+
+    #[test]
+    fn test_normalize_checks_each_kind() {
+        assert_eq!(
+            normalize(RuleKind::Hash, "ABCDEF0123").unwrap(),
+            "abcdef0123"
+        );
+        assert!(normalize(RuleKind::Hash, "abc").is_err());
+        assert!(normalize(RuleKind::Hash, "not-a-hash").is_err());
+        assert!(normalize(RuleKind::Glob, "a[").is_err());
+        assert_eq!(
+            normalize(RuleKind::Path, "/srv//mirror/").unwrap(),
+            "/srv/mirror"
+        );
+    }
+
+    #[test]
+    fn test_rules_match_paths_under_a_path_globs_and_hash_prefixes() {
+        let rules = rules(&[
+            (RuleKind::Path, "/srv/mirror"),
+            (RuleKind::Glob, "*.dll"),
+            (RuleKind::Glob, "/home/*/.cache/**"),
+            (RuleKind::Glob, "p/*.jpg"),
+            (RuleKind::Hash, "87428fc522803d31"),
+        ]);
+        assert!(rules.ignores_path("/srv/mirror"));
+        assert!(rules.ignores_path("/srv/mirror/etc/a.conf"));
+        assert!(!rules.ignores_path("/srv/mirrored/a.conf"));
+        assert!(rules.ignores_path("/win/system/x.dll"));
+        assert!(rules.ignores_path("/home/me/.cache/thumbs/a.png"));
+        assert!(rules.ignores_path("/x/y/p/1.jpg"));
+        assert!(!rules.ignores_path("/x/y/q/p.jpg"));
+        assert!(!rules.ignores_path("/home/me/photos/.cache"));
+        assert!(rules.ignores_hash("87428fc522803d31065e7bce3cf03fe4"));
+        assert!(!rules.ignores_hash("97428fc522803d31065e7bce3cf03fe4"));
+        assert!(!IgnoreRules::default().ignores_path("/a"));
+    }
+}
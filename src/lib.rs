@@ -0,0 +1,89 @@
+//! The engine behind the `deduplifier` command: scanning directory trees into
+//! a SQLite database of file and directory hashes, and finding what in it is
+//! duplicated.
+//!
+//! `Scanner`, `HashStore` and `DuplicateReport` cover the common case:
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use deduplifier::{DuplicateReport, HashStore, Scanner};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let store = HashStore::open(Path::new("hashes.db"))?;
+//! Scanner::default().scan(&store, &[Path::new("/home/me/Photos")], |_, _, _| {})?;
+//! let report = DuplicateReport::build(&store)?;
+//! for group in report.file_groups() {
+//!     println!("{} copies of {}", group.count, group.files[0].path);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Those, and the options and results they take and give, are all that is
+//! public: everything else the command does stays inside the crate, behind
+//! `main`, which is the command itself.
+
+mod archive;
+mod audio;
+mod chunks;
+mod clean;
+mod cli;
+mod compare;
+mod compressed;
+mod config;
+mod containment;
+mod daemon;
+mod db;
+mod dedupe;
+mod doctor;
+mod duplicates;
+mod engine;
+mod events;
+mod file_system;
+mod hashing;
+mod history;
+mod hooks;
+mod ignore_rules;
+mod logging;
+mod lookalike;
+mod manifest;
+mod merge;
+mod merge_db;
+mod names;
+mod overlap;
+mod payload;
+mod perceptual;
+mod photos;
+mod profile;
+mod remote;
+mod report;
+mod review;
+mod s3;
+mod scan;
+mod schedule;
+mod script;
+mod serve;
+mod similar;
+mod stats;
+mod text;
+mod throttle;
+mod tui;
+mod ui;
+mod undo;
+mod utils;
+mod verify;
+mod video;
+mod walk;
+mod watch;
+mod xattr;
+
+pub use cli::main;
+pub use db::FileRecord;
+pub use duplicates::{DirEntry, DuplicateDirGroup, DuplicateFileGroup, FileEntry, ReportFilter};
+pub use engine::{DuplicateReport, HashStore, Scanner};
+pub use hashing::{HashAlgorithm, HashOptions};
+pub use remote::RemoteOptions;
+pub use s3::S3Options;
+pub use scan::{HashedFn, Interrupted, OnHashed, ScanError, ScanOptions, ScanResult};
+pub use walk::{FileKind, PathFilter, TypeFilter, WalkOptions};
@@ -0,0 +1,356 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use serde_json::Value as Json;
+use tracing::field::{Field, Visit};
+use tracing::{span, Event, Level, Metadata, Subscriber};
+use tracing_subscriber::filter::{filter_fn, LevelFilter};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{self, Layer};
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::utils;
+
+/// The target of messages meant for the user rather than about how the run
+/// is going, such as where a report was written: always printed on standard
+/// error as they are, `--quiet` or not, and logged at info level.
+pub const NOTICE: &str = "deduplifier::notice";
+
+/// How the library's events and spans are printed on standard error once
+/// `init` has run, at the level `-v` flags ask for. Warnings are always
+/// printed, as `Warning: ...` the way the rest of the output has them; more
+/// detailed events carry the time since the run started, their level and the
+/// module that logged them. A span is a timed stretch of work, such as
+/// hashing the files under one root: it is printed at debug level when it
+/// starts and at info level, with how long it took, when it closes.
+struct Logger {
+    started: Instant,
+    stderr: LevelFilter,
+}
+
+/// The level standard error is shown at for `verbosity` `-v` flags.
+pub fn level_for(verbosity: u8) -> LevelFilter {
+    match verbosity {
+        0 => LevelFilter::WARN,
+        1 => LevelFilter::INFO,
+        2 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+/// Install the logger. Events at `level_for(verbosity)` are printed on
+/// standard error; with `log_file`, events at that level or at info,
+/// whichever is more detailed, are appended to it as JSON lines.
+pub fn init(verbosity: u8, log_file: Option<&Path>) -> Result<()> {
+    let stderr = level_for(verbosity);
+    let file = match log_file {
+        Some(path) => Some(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("could not open the log file {}", path.display()))?,
+        ),
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(Logger::new(stderr).filtered())
+        .with(file.map(|file| file_layer(stderr.max(LevelFilter::INFO), Mutex::new(file))))
+        .try_init()
+        .context("a logger is already installed")
+}
+
+/// The log file's layer: each event at `level` or above as one JSON object,
+/// its fields beside `timestamp`, `level`, `target` and the `span` and
+/// `spans` it happened in, and each span again when it closes, with the
+/// time it was busy and idle.
+fn file_layer<S, W>(level: LevelFilter, writer: W) -> impl Layer<S>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + Send + Sync + 'static,
+{
+    tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(false)
+        .with_writer(writer)
+        .with_filter(level)
+}
+
+impl Logger {
+    fn new(stderr: LevelFilter) -> Self {
+        Self {
+            started: Instant::now(),
+            stderr,
+        }
+    }
+
+    /// The logger behind a filter of its own, so that what standard error
+    /// leaves out still reaches the log file.
+    fn filtered<S>(self) -> impl Layer<S>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let stderr = self.stderr;
+        let filter = filter_fn(move |metadata: &Metadata<'_>| {
+            shown(stderr, *metadata.level(), metadata.target())
+        })
+        .with_max_level_hint(stderr.max(LevelFilter::INFO));
+        self.with_filter(filter)
+    }
+
+    fn write(&self, record: &Record) {
+        if shown(self.stderr, record.level, record.target) {
+            eprintln!("{}", self.stderr_line(record));
+        }
+    }
+
+    fn stderr_line(&self, record: &Record) -> String {
+        let mut line = match record.level {
+            _ if record.target == NOTICE => return record.message.clone(),
+            Level::ERROR => format!("Error: {}", record.message),
+            Level::WARN => format!("Warning: {}", record.message),
+            level => format!(
+                "[{:>8.3}s {:<5} {}] {}",
+                self.started.elapsed().as_secs_f64(),
+                level,
+                short_target(record.target),
+                record.message
+            ),
+        };
+        for (key, value) in &record.fields.0 {
+            // A span's own fields are in its message already
+            if record.span && (key == "phase" || key == "elapsed_ms" || key == "root") {
+                continue;
+            }
+            match value {
+                Json::String(s) => line.push_str(&format!(" {key}={s}")),
+                other => line.push_str(&format!(" {key}={other}")),
+            }
+        }
+        line
+    }
+}
+
+/// What a span's extensions hold: its fields, and when it started.
+struct SpanData {
+    fields: Fields,
+    started: Instant,
+}
+
+/// Whether standard error, showing `stderr` and above, prints a record at
+/// `level` from `target`; notices are printed at every level but `OFF`.
+fn shown(stderr: LevelFilter, level: Level, target: &str) -> bool {
+    level <= stderr || (target == NOTICE && level <= Level::INFO)
+}
+
+impl<S> Layer<S> for Logger
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: layer::Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let name = span.name();
+        fields.0.insert(0, ("phase".into(), Json::from(name)));
+        self.write(&Record {
+            level: Level::DEBUG,
+            target: attrs.metadata().target(),
+            message: format!("{name} started{}", subject(&fields)),
+            fields: &fields,
+            span: true,
+        });
+        span.extensions_mut().insert(SpanData {
+            fields,
+            started: Instant::now(),
+        });
+    }
+
+    fn on_close(&self, id: span::Id, ctx: layer::Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let extensions = span.extensions();
+        let Some(data) = extensions.get::<SpanData>() else {
+            return;
+        };
+        let elapsed = data.started.elapsed();
+        let mut fields = Fields(data.fields.0.clone());
+        fields
+            .0
+            .push(("elapsed_ms".into(), Json::from(elapsed.as_millis() as u64)));
+        self.write(&Record {
+            level: Level::INFO,
+            target: span.metadata().target(),
+            message: format!(
+                "{} finished{} in {}",
+                span.name(),
+                subject(&fields),
+                if elapsed.as_secs() >= 1 {
+                    utils::fmt_duration(elapsed.as_secs())
+                } else {
+                    format!("{}ms", elapsed.as_millis())
+                }
+            ),
+            fields: &fields,
+            span: true,
+        });
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: layer::Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        let message = match fields.0.iter().position(|(key, _)| key == "message") {
+            Some(i) => match fields.0.remove(i).1 {
+                Json::String(s) => s,
+                other => other.to_string(),
+            },
+            None => String::new(),
+        };
+        let metadata = event.metadata();
+        self.write(&Record {
+            level: *metadata.level(),
+            target: metadata.target(),
+            message,
+            fields: &fields,
+            span: false,
+        });
+    }
+}
+
+/// What a span's messages say it was done to, from its `root` field: a
+/// space and the path, or nothing.
+fn subject(fields: &Fields) -> String {
+    match fields.0.iter().find(|(key, _)| key == "root") {
+        Some((_, Json::String(root))) => format!(" {root}"),
+        _ => String::new(),
+    }
+}
+
+/// One line on standard error, from an event or a span starting or closing.
+struct Record<'a> {
+    level: Level,
+    target: &'a str,
+    message: String,
+    fields: &'a Fields,
+    /// Whether it is a span's own line
+    span: bool,
+}
+
+/// The module that logged an event, without the crate name.
+fn short_target(target: &str) -> &str {
+    target.strip_prefix("deduplifier::").unwrap_or(target)
+}
+
+/// An event's or span's fields, numbers and booleans kept as such.
+#[derive(Default)]
+struct Fields(Vec<(String, Json)>);
+
+impl Visit for Fields {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.push((field.name().to_string(), Json::from(value)));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.push((field.name().to_string(), Json::from(value)));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.push((field.name().to_string(), Json::from(value)));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.push((field.name().to_string(), Json::from(value)));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.push((field.name().to_string(), Json::from(value)));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .push((field.name().to_string(), Json::String(format!("{value:?}"))));
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_level_for_counts_v_flags() {
+        assert_eq!(level_for(0), LevelFilter::WARN);
+        assert_eq!(level_for(1), LevelFilter::INFO);
+        assert_eq!(level_for(2), LevelFilter::DEBUG);
+        assert_eq!(level_for(5), LevelFilter::TRACE);
+    }
+
+    /// A log file that is a buffer the test can read back
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(bytes)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_file_gets_spans_and_events_as_json_lines() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::registry()
+            .with(Logger::new(LevelFilter::OFF).filtered())
+            .with(file_layer(LevelFilter::INFO, move || writer.clone()));
+        tracing::subscriber::with_default(subscriber, || {
+            let _scan = tracing::info_span!("scan", root = "/photos").entered();
+            tracing::info!(errors = 2usize, done = true, "scanned /photos");
+            tracing::debug!("left out below info");
+        });
+        let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Json> = text
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2, "{text}");
+
+        let scan = serde_json::json!({"name": "scan", "root": "/photos"});
+        let event = &lines[0];
+        assert_eq!(event["level"], "INFO");
+        assert_eq!(event["target"], "deduplifier::logging::tests");
+        assert_eq!(event["message"], "scanned /photos");
+        assert_eq!(event["spans"], serde_json::json!([scan]));
+        assert_eq!(event["errors"], 2);
+        assert_eq!(event["done"], true);
+        assert!(event["timestamp"].as_str().unwrap().ends_with('Z'));
+
+        let finished = &lines[1];
+        assert_eq!(finished["message"], "close");
+        assert_eq!(finished["span"], scan);
+        assert!(finished["time.busy"].is_string());
+    }
+}
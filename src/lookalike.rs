@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::ignore_rules::IgnoreRules;
+use crate::{db, utils};
+
+/// The files in the database a similarity search looks at: those under
+/// `scope` (all of them when it is empty) that `wanted` picks, given each
+/// record and its path. Files merged in from another database or inside
+/// archives are left out, since there is nothing on disk to read, and so are
+/// the files the ignore rules match.
+pub fn candidates(
+    conn: &Connection,
+    scope: &[&Path],
+    wanted: impl Fn(&db::FileRecord, &Path) -> bool,
+) -> Result<Vec<db::FileRecord>> {
+    let off_disk = db::files_off_disk(conn)?;
+    let ignored = IgnoreRules::load(conn)?;
+    Ok(db::all_files(conn)?
+        .into_iter()
+        .filter(|f| !off_disk.contains(&f.path) && !ignored.ignores_path(&f.path))
+        .filter(|f| {
+            let path = utils::path_from_db(&f.path);
+            wanted(f, &path) && (scope.is_empty() || scope.iter().any(|r| path.starts_with(r)))
+        })
+        .collect())
+}
+
+/// `files` grouped by content hash, in the order each content first shows
+/// up. A similarity search takes exact copies as one item: matching them is
+/// `dup-files`' business, and every copy would otherwise turn up as similar
+/// to the rest.
+pub fn by_content(files: &[db::FileRecord]) -> Vec<Vec<&db::FileRecord>> {
+    let mut index: HashMap<&str, usize> = HashMap::new();
+    let mut groups: Vec<Vec<&db::FileRecord>> = Vec::new();
+    for file in files {
+        let at = *index.entry(&file.hash).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[at].push(file);
+    }
+    groups
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_candidates_leave_out_files_off_disk_and_ignored() {
+        let conn = open_test_db();
+        for path in [
+            "/a/one.jpg",
+            "/a/one.txt",
+            "/b/two.jpg",
+            "/a/cache/three.jpg",
+        ] {
+            db::upsert_file(&conn, Path::new(path), "h", 10, 0).unwrap();
+        }
+        let merged = db::get_file(&conn, Path::new("/a/one.jpg"))
+            .unwrap()
+            .unwrap();
+        db::insert_merged_file(&conn, "/a/four.jpg", &merged, "nas").unwrap();
+        db::add_ignore_rule(&conn, "path", "/a/cache").unwrap();
+
+        let jpg = |_: &db::FileRecord, path: &Path| path.extension().is_some_and(|e| e == "jpg");
+        let found = candidates(&conn, &[Path::new("/a")], jpg).unwrap();
+        let paths: Vec<&str> = found.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, ["/a/one.jpg"]);
+    }
+
+    #[test]
+    fn test_by_content_groups_exact_copies_in_first_seen_order() {
+        let file = |path: &str, hash: &str| db::FileRecord {
+            path: path.to_string(),
+            hash: hash.to_string(),
+            size: 10,
+            modified: 0,
+            partial_hash: None,
+            device: None,
+            inode: None,
+            via_link: false,
+            stat: None,
+        };
+        let files = [file("/b", "y"), file("/a", "x"), file("/c", "y")];
+        let groups: Vec<Vec<&str>> = by_content(&files)
+            .iter()
+            .map(|g| g.iter().map(|f| f.path.as_str()).collect())
+            .collect();
+        assert_eq!(groups, [vec!["/b", "/c"], vec!["/a"]]);
+    }
+}
@@ -0,0 +1,464 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, Context, Result};
+use rusqlite::Connection;
+
+use crate::hashing::{self, HashAlgorithm};
+use crate::{db, utils};
+
+/// Rows written per transaction while importing.
+const TRANSACTION_BATCH_SIZE: usize = 1000;
+
+/// Which checksum tool's manifest `write_manifest` writes. They share the
+/// `<hash>  <path>` layout and differ in the algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `sha256sum`, for databases hashed with SHA-256
+    Sha256sum,
+    /// `b3sum`, for databases hashed with BLAKE3
+    B3sum,
+    /// `xxh128sum`, for databases hashed with XXH3
+    Xxh128sum,
+}
+
+impl Format {
+    pub fn algorithm(self) -> HashAlgorithm {
+        match self {
+            Self::Sha256sum => HashAlgorithm::Sha256,
+            Self::B3sum => HashAlgorithm::Blake3,
+            Self::Xxh128sum => HashAlgorithm::Xxh3,
+        }
+    }
+
+    fn for_algorithm(algorithm: HashAlgorithm) -> Self {
+        match algorithm {
+            HashAlgorithm::Sha256 => Self::Sha256sum,
+            HashAlgorithm::Blake3 => Self::B3sum,
+            HashAlgorithm::Xxh3 => Self::Xxh128sum,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Sha256sum => "sha256sum",
+            Self::B3sum => "b3sum",
+            Self::Xxh128sum => "xxh128sum",
+        }
+    }
+}
+
+/// What `write_manifest` left out.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Written {
+    pub files: usize,
+    /// Files only given a provisional hash, so there is nothing to check them by
+    pub unhashed: usize,
+}
+
+/// Write every file in the database as a `format` manifest, one
+/// `<hash>  <path>` line each in path order, that `sha256sum -c` (or `b3sum
+/// -c`, `xxh128sum -c`) can check. Names holding a backslash or a line
+/// break are escaped the way those tools do it. Files merged in from another
+/// database are on another machine, and files inside archives have no path
+/// a checker could open, so both are left out.
+pub fn write_manifest(conn: &Connection, mut out: impl Write, format: Format) -> Result<Written> {
+    let algorithm = hashing::resolve_algorithm(conn, None)?;
+    if algorithm != format.algorithm() {
+        bail!(
+            "this database holds {} hashes, which {} can't check; use --format {}",
+            algorithm.name(),
+            format.name(),
+            Format::for_algorithm(algorithm).name()
+        );
+    }
+    let off_disk = db::files_off_disk(conn)?;
+    let mut written = Written::default();
+    for file in db::all_files(conn)? {
+        if off_disk.contains(&file.path) {
+            continue;
+        }
+        if hashing::is_provisional(&file.hash) {
+            written.unhashed += 1;
+            continue;
+        }
+        out.write_all(&manifest_line(&file.hash, &utils::path_from_db(&file.path)))?;
+        written.files += 1;
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+/// One manifest line, newline included.
+fn manifest_line(hash: &str, path: &Path) -> Vec<u8> {
+    let name = path_bytes(path);
+    let escaped = name.iter().any(|b| matches!(b, b'\\' | b'\n' | b'\r'));
+    let mut line = Vec::with_capacity(hash.len() + name.len() + 4);
+    if escaped {
+        line.push(b'\\');
+    }
+    line.extend_from_slice(hash.as_bytes());
+    line.extend_from_slice(b"  ");
+    for &b in name.iter() {
+        match b {
+            b'\\' if escaped => line.extend_from_slice(b"\\\\"),
+            b'\n' => line.extend_from_slice(b"\\n"),
+            b'\r' => line.extend_from_slice(b"\\r"),
+            _ => line.push(b),
+        }
+    }
+    line.push(b'\n');
+    line
+}
+
+/// An entry of a manifest: the hash and the name it was listed under.
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    hash: String,
+    name: PathBuf,
+}
+
+/// Read `line` (without its line break) as `<hash>  <name>` or, as written
+/// in binary mode, `<hash> *<name>`. `None` for anything else, a hash that
+/// isn't an `algorithm` one included.
+fn parse_line(line: &[u8], algorithm: HashAlgorithm) -> Option<Entry> {
+    let (escaped, line) = match line.strip_prefix(b"\\") {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let hash_len = algorithm.empty_hash().len();
+    let hash = line.get(..hash_len)?;
+    if !hash.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let name = match line.get(hash_len..hash_len + 2)? {
+        b"  " | b" *" => &line[hash_len + 2..],
+        _ => return None,
+    };
+    if name.is_empty() {
+        return None;
+    }
+    let name = if escaped {
+        unescape(name)?
+    } else {
+        name.to_vec()
+    };
+    Some(Entry {
+        hash: String::from_utf8(hash.to_ascii_lowercase()).ok()?,
+        name: path_from_bytes(name),
+    })
+}
+
+/// Undo `manifest_line`'s escaping; `None` for an escape it doesn't write.
+fn unescape(name: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(name.len());
+    let mut bytes = name.iter();
+    while let Some(&b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        out.push(match bytes.next()? {
+            b'\\' => b'\\',
+            b'n' => b'\n',
+            b'r' => b'\r',
+            _ => return None,
+        });
+    }
+    Some(out)
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// What `import_manifest` did with each line.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Imported {
+    /// Files recorded with the manifest's hash
+    pub files: usize,
+    /// Files the database already had an up-to-date hash for, left as they are
+    pub known: usize,
+    /// Listed files that aren't there (or aren't files)
+    pub missing: usize,
+    /// Files modified after the manifest was written, whose hash can't be trusted
+    pub changed: Vec<PathBuf>,
+    /// Numbers of the lines that aren't `<hash>  <name>` with an `algorithm` hash
+    pub malformed: Vec<usize>,
+}
+
+/// Record the files a checksum manifest lists, names taken relative to
+/// `root`, with the manifest's hashes instead of reading the files, so the
+/// next scan finds them already hashed. Each file must still be there, and
+/// not modified since the manifest itself was; its size and modification
+/// time come from the file. Directory hashes are left to the next scan.
+pub fn import_manifest(
+    conn: &Connection,
+    manifest: &Path,
+    root: &Path,
+    algorithm: Option<HashAlgorithm>,
+) -> Result<Imported> {
+    let algorithm = match (algorithm, hashing::recorded_algorithm(conn)?) {
+        (None, None) => bail!(
+            "say which algorithm made {} with --hash: this database has no hashes yet",
+            manifest.display()
+        ),
+        (requested, _) => hashing::resolve_algorithm(conn, requested)?,
+    };
+    let file =
+        fs::File::open(manifest).with_context(|| format!("opening {}", manifest.display()))?;
+    let written = file.metadata()?.modified()?;
+
+    let mut imported = Imported::default();
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    for (index, line) in std::io::BufReader::new(file).split(b'\n').enumerate() {
+        let line = line?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if line.is_empty() {
+            continue;
+        }
+        let Some(entry) = parse_line(line, algorithm) else {
+            imported.malformed.push(index + 1);
+            continue;
+        };
+        let path = root.join(&entry.name);
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => metadata,
+            _ => {
+                imported.missing += 1;
+                continue;
+            }
+        };
+        let modified = metadata.modified()?;
+        if modified > written {
+            imported.changed.push(path);
+            continue;
+        }
+        let modified_secs = modified.duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let size = metadata.len() as i64;
+        let known = db::get_file(conn, &path)?.is_some_and(|r| {
+            r.modified == modified_secs && r.size == size && !hashing::is_provisional(&r.hash)
+        });
+        if known {
+            imported.known += 1;
+            continue;
+        }
+        db::upsert_file(conn, &path, &entry.hash, size, modified_secs)?;
+        if let Some((device, inode)) = utils::file_identity(&metadata) {
+            db::update_file_identity(conn, &path, device, inode)?;
+        }
+        if let Some(stat) = utils::file_stat(&metadata) {
+            db::update_file_stat(conn, &path, &stat)?;
+        }
+        imported.files += 1;
+        batch.tick()?;
+    }
+    batch.commit()?;
+    Ok(imported)
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(algorithm: HashAlgorithm) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        hashing::resolve_algorithm(&conn, Some(algorithm)).unwrap();
+        conn
+    }
+
+    fn opts(algorithm: HashAlgorithm) -> hashing::HashOptions {
+        hashing::HashOptions {
+            algorithm,
+            ..hashing::HashOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_manifest_lines_escape_like_sha256sum() {
+        let line = manifest_line("ab", Path::new("/a b/c.txt"));
+        assert_eq!(line, b"ab  /a b/c.txt\n");
+        let line = manifest_line("ab", Path::new("/a\\b\nc"));
+        assert_eq!(line, b"\\ab  /a\\\\b\\nc\n");
+
+        let hash = HashAlgorithm::Xxh3.empty_hash();
+        for name in ["/a b/c.txt", "/a\\b\nc", "/tab\there", "* star"] {
+            let line = manifest_line(&hash, Path::new(name));
+            let entry = parse_line(&line[..line.len() - 1], HashAlgorithm::Xxh3).unwrap();
+            assert_eq!(
+                (entry.hash.as_str(), entry.name.as_path()),
+                (hash.as_str(), Path::new(name))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_line_takes_binary_mode_and_refuses_other_lines() {
+        let hash = "a".repeat(64);
+        let binary = format!("{}  *x", hash.to_uppercase()).into_bytes();
+        // Two spaces then "*x": the name really starts with a star
+        assert_eq!(
+            parse_line(&binary, HashAlgorithm::Sha256).unwrap().name,
+            Path::new("*x")
+        );
+        let binary = format!("{hash} *x").into_bytes();
+        let entry = parse_line(&binary, HashAlgorithm::Sha256).unwrap();
+        assert_eq!((entry.hash, entry.name), (hash.clone(), PathBuf::from("x")));
+
+        for bad in [
+            format!("{hash} x"),
+            format!("{hash}  "),
+            format!("{}  x", &hash[1..]),
+            format!("{}g  x", &hash[1..]),
+            format!("\\{hash}  bad\\escape"),
+            "SHA256 (x) = abc".to_string(),
+        ] {
+            assert_eq!(
+                parse_line(bad.as_bytes(), HashAlgorithm::Sha256),
+                None,
+                "{bad}"
+            );
+        }
+        // Right shape, wrong algorithm
+        assert_eq!(
+            parse_line(format!("{hash}  x").as_bytes(), HashAlgorithm::Xxh3),
+            None
+        );
+    }
+
+    #[test]
+    fn test_write_manifest_matches_the_database_algorithm() {
+        let conn = setup(HashAlgorithm::Blake3);
+        let hash = "b".repeat(64);
+        db::upsert_file(&conn, Path::new("/d/y"), &hash, 1, 0).unwrap();
+        db::upsert_file(&conn, Path::new("/d/x"), &hash, 1, 0).unwrap();
+        let provisional = format!("{}abc", hashing::PROVISIONAL_PREFIX);
+        db::upsert_file(&conn, Path::new("/d/big"), &provisional, 1, 0).unwrap();
+        db::insert_merged_file(
+            &conn,
+            "nas:/d/x",
+            &db::get_file(&conn, Path::new("/d/x")).unwrap().unwrap(),
+            "nas",
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        let written = write_manifest(&conn, &mut out, Format::B3sum).unwrap();
+        assert_eq!(
+            written,
+            Written {
+                files: 2,
+                unhashed: 1
+            }
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("{hash}  /d/x\n{hash}  /d/y\n")
+        );
+        assert!(write_manifest(&conn, Vec::new(), Format::Sha256sum).is_err());
+    }
+
+    #[test]
+    fn test_import_seeds_hashes_a_scan_then_trusts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("photos");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a.jpg"), "aaa").unwrap();
+        fs::write(root.join("sub/b.jpg"), "bbb").unwrap();
+        // A made-up hash shows the file isn't read
+        let seeded = "c".repeat(64);
+        let real_b =
+            hashing::compute_file_hash(&root.join("sub/b.jpg"), &opts(HashAlgorithm::Sha256))
+                .unwrap();
+        let manifest = tmp.path().join("SHA256SUMS");
+        fs::write(
+            &manifest,
+            format!("{seeded}  a.jpg\n{real_b} *sub/b.jpg\n{seeded}  gone.jpg\nnot a line\n\n"),
+        )
+        .unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        assert!(import_manifest(&conn, &manifest, &root, None).is_err());
+        let imported =
+            import_manifest(&conn, &manifest, &root, Some(HashAlgorithm::Sha256)).unwrap();
+        assert_eq!(
+            (imported.files, imported.known, imported.missing),
+            (2, 0, 1)
+        );
+        assert_eq!(imported.malformed, vec![4]);
+        let a = db::get_file(&conn, &root.join("a.jpg")).unwrap().unwrap();
+        assert_eq!((a.hash.as_str(), a.size), (seeded.as_str(), 3));
+        assert_eq!(
+            hashing::recorded_algorithm(&conn).unwrap(),
+            Some(HashAlgorithm::Sha256)
+        );
+
+        // Importing again changes nothing
+        let again = import_manifest(&conn, &manifest, &root, None).unwrap();
+        assert_eq!((again.files, again.known), (0, 2));
+
+        // A scan keeps the imported hash of the unchanged file
+        let scan_opts = crate::scan::ScanOptions {
+            hash: opts(HashAlgorithm::Sha256),
+            ..crate::scan::ScanOptions::default()
+        };
+        crate::scan::scan_directory(&conn, &root, 2, &scan_opts, |_, _, _| {}).unwrap();
+        let a = db::get_file(&conn, &root.join("a.jpg")).unwrap().unwrap();
+        assert_eq!(a.hash, seeded);
+    }
+
+    #[test]
+    fn test_import_skips_files_modified_after_the_manifest() {
+        let tmp = tempfile::tempdir().unwrap();
+        let manifest = tmp.path().join("B3SUMS");
+        let hash = "d".repeat(64);
+        fs::write(&manifest, format!("{hash}  later.txt\n")).unwrap();
+        let later = tmp.path().join("later.txt");
+        fs::write(&later, "new").unwrap();
+        let file = fs::File::options().write(true).open(&later).unwrap();
+        file.set_modified(
+            fs::metadata(&manifest).unwrap().modified().unwrap()
+                + std::time::Duration::from_secs(60),
+        )
+        .unwrap();
+
+        let conn = setup(HashAlgorithm::Blake3);
+        let imported = import_manifest(&conn, &manifest, tmp.path(), None).unwrap();
+        assert_eq!(imported.changed, vec![later.clone()]);
+        assert!(db::get_file(&conn, &later).unwrap().is_none());
+    }
+}
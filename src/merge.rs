@@ -28,8 +28,8 @@ pub fn similarity_score(
 ) -> Result<(f64, usize, usize)> {
     let both: &[&Path] = &[canon, source];
     let dir_index = similar::build_dir_index(conn, both)?;
-    let canon_str = canon.to_string_lossy().to_string();
-    let source_str = source.to_string_lossy().to_string();
+    let canon_str = utils::path_to_db(canon);
+    let source_str = utils::path_to_db(source);
     let canon_files = similar::files_for_dir(&dir_index, &canon_str);
     let source_files = similar::files_for_dir(&dir_index, &source_str);
     let canon_hashes: HashSet<&str> = canon_files.values().map(|(_, h, _)| h.as_str()).collect();
@@ -51,9 +51,10 @@ pub fn execute_merge(
     canon: &Path,
     source: &Path,
     no_confirmation: bool,
+    hash_opts: &hashing::HashOptions,
     on_conflict: impl Fn(&Path, &Path, i64, &Path, i64) -> Result<ConflictChoice>,
 ) -> Result<MergeStats> {
-    merge_one(conn, canon, source, no_confirmation, on_conflict)
+    merge_one(conn, canon, source, no_confirmation, hash_opts, on_conflict)
 }
 
 // ---------------------------------------------------------------------------
@@ -65,6 +66,7 @@ fn merge_one(
     canon: &Path,
     source: &Path,
     no_confirmation: bool,
+    hash_opts: &hashing::HashOptions,
     on_conflict: impl Fn(&Path, &Path, i64, &Path, i64) -> Result<ConflictChoice>,
 ) -> Result<MergeStats> {
     let source_files_on_disk: Vec<PathBuf> = WalkDir::new(source)
@@ -87,12 +89,13 @@ fn merge_one(
         let dest_abs = canon.join(rel);
 
         if dest_abs.exists() {
-            let src_hash = hash_file(src_abs)?;
-            let dest_hash = hash_file(&dest_abs)?;
+            let src_hash = hash_file(src_abs, hash_opts)?;
+            let dest_hash = hash_file(&dest_abs, hash_opts)?;
 
             if src_hash == dest_hash {
                 file_system::delete_file(src_abs)?;
                 db::remove_file(conn, src_abs)?;
+                db::log_action(conn, "delete", src_abs, Some(&dest_abs), Some(&src_hash))?;
                 deleted_dups += 1;
                 continue;
             }
@@ -118,14 +121,19 @@ fn merge_one(
                 file_system::delete_file(src_abs)?;
                 db::remove_file(conn, src_abs)?;
                 db::update_file_hash(conn, &dest_abs, &src_hash)?;
+                // The canonical version is overwritten; only the source can come back
+                db::log_action(conn, "delete", src_abs, Some(&dest_abs), Some(&src_hash))?;
             } else {
                 file_system::delete_file(src_abs)?;
                 db::remove_file(conn, src_abs)?;
+                db::log_action(conn, "delete", src_abs, None, Some(&src_hash))?;
             }
             moved += 1;
         } else {
+            let hash = db::get_file(conn, src_abs)?.map(|f| f.hash);
             file_system::move_file(src_abs, &dest_abs)?;
             db::move_file(conn, src_abs, &dest_abs)?;
+            db::log_action(conn, "move", src_abs, Some(&dest_abs), hash.as_deref())?;
             moved += 1;
         }
     }
@@ -153,8 +161,8 @@ pub enum ConflictChoice {
 // Helpers
 // ---------------------------------------------------------------------------
 
-fn hash_file(path: &Path) -> Result<String> {
-    hashing::compute_file_hash(path).with_context(|| format!("hashing {}", path.display()))
+fn hash_file(path: &Path, opts: &hashing::HashOptions) -> Result<String> {
+    hashing::compute_file_hash(path, opts).with_context(|| format!("hashing {}", path.display()))
 }
 
 // ------------------------------------------------------------------
@@ -199,7 +207,15 @@ mod tests {
         write_file(&src_file, b"unique photo");
         db::upsert_file(&conn, &src_file, "hash_unique", 12, 0).unwrap();
 
-        merge_one(&conn, &canon, &source, true, |_, _, _, _, _| unreachable!()).unwrap();
+        merge_one(
+            &conn,
+            &canon,
+            &source,
+            true,
+            &hashing::HashOptions::default(),
+            |_, _, _, _, _| unreachable!(),
+        )
+        .unwrap();
 
         assert!(canon.join("photo.jpg").exists(), "file should be in canon");
         assert!(!src_file.exists(), "file should be gone from source");
@@ -222,7 +238,15 @@ mod tests {
         write_file(&source.join("photo.jpg"), content);
         db::upsert_file(&conn, &source.join("photo.jpg"), "hash_same", 17, 0).unwrap();
 
-        merge_one(&conn, &canon, &source, true, |_, _, _, _, _| unreachable!()).unwrap();
+        merge_one(
+            &conn,
+            &canon,
+            &source,
+            true,
+            &hashing::HashOptions::default(),
+            |_, _, _, _, _| unreachable!(),
+        )
+        .unwrap();
 
         assert!(canon.join("photo.jpg").exists());
         assert!(!source.join("photo.jpg").exists());
@@ -244,7 +268,15 @@ mod tests {
         write_file(&src, b"source version");
         db::upsert_file(&conn, &src, "hash_source", 14, 0).unwrap();
 
-        merge_one(&conn, &canon, &source, true, |_, _, _, _, _| unreachable!()).unwrap();
+        merge_one(
+            &conn,
+            &canon,
+            &source,
+            true,
+            &hashing::HashOptions::default(),
+            |_, _, _, _, _| unreachable!(),
+        )
+        .unwrap();
 
         assert!(canon.join("photo.jpg").exists());
         assert!(!src.exists());
@@ -262,7 +294,15 @@ mod tests {
         write_file(&src_file, b"nested photo");
         db::upsert_file(&conn, &src_file, "hash_nested", 12, 0).unwrap();
 
-        merge_one(&conn, &canon, &source, true, |_, _, _, _, _| unreachable!()).unwrap();
+        merge_one(
+            &conn,
+            &canon,
+            &source,
+            true,
+            &hashing::HashOptions::default(),
+            |_, _, _, _, _| unreachable!(),
+        )
+        .unwrap();
 
         assert!(canon.join("2009").join("jan").join("img.jpg").exists());
         assert!(!src_file.exists());
@@ -281,7 +321,15 @@ mod tests {
         write_file(&src_file, b"photo");
         db::upsert_file(&conn, &src_file, "hash_x", 5, 0).unwrap();
 
-        merge_one(&conn, &canon, &source, true, |_, _, _, _, _| unreachable!()).unwrap();
+        merge_one(
+            &conn,
+            &canon,
+            &source,
+            true,
+            &hashing::HashOptions::default(),
+            |_, _, _, _, _| unreachable!(),
+        )
+        .unwrap();
 
         assert!(!album.exists(), "empty album subdir should be deleted");
     }
@@ -294,7 +342,15 @@ mod tests {
         fs::create_dir_all(&canon).unwrap();
         let conn = open_test_db();
         // Merging into itself: source has no files, so nothing happens
-        execute_merge(&conn, &canon, &canon, true, |_, _, _, _, _| unreachable!()).unwrap();
+        execute_merge(
+            &conn,
+            &canon,
+            &canon,
+            true,
+            &hashing::HashOptions::default(),
+            |_, _, _, _, _| unreachable!(),
+        )
+        .unwrap();
     }
 
     #[test]
@@ -309,7 +365,15 @@ mod tests {
         write_file(&src_file, b"data");
         db::upsert_file(&conn, &src_file, "hash_abc", 4, 0).unwrap();
 
-        merge_one(&conn, &canon, &source, true, |_, _, _, _, _| unreachable!()).unwrap();
+        merge_one(
+            &conn,
+            &canon,
+            &source,
+            true,
+            &hashing::HashOptions::default(),
+            |_, _, _, _, _| unreachable!(),
+        )
+        .unwrap();
 
         let dest = canon.join("img.jpg");
         let rec = db::get_file(&conn, &dest).unwrap();
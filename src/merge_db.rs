@@ -0,0 +1,231 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use rusqlite::Connection;
+
+use crate::{db, hashing};
+
+/// Rows written per transaction while copying a database in.
+const TRANSACTION_BATCH_SIZE: usize = 1000;
+
+/// What `merge_database` copied in from one source.
+pub struct MergeStats {
+    pub source: String,
+    pub files: usize,
+    pub directories: usize,
+}
+
+/// The name a source database's rows are filed under: its file name without
+/// the extension, so `laptop.db` becomes `laptop`.
+pub fn source_name(path: &Path) -> Result<String> {
+    match path.file_stem().and_then(|s| s.to_str()) {
+        Some(name) if !name.is_empty() => Ok(name.to_string()),
+        _ => bail!("cannot name the source database {}", path.display()),
+    }
+}
+
+/// Where a row from `source` is stored once merged: its path prefixed with
+/// the source name, e.g. `laptop:/home/me/a.jpg`. Paths from different
+/// machines can be the same, so this keeps them from replacing each other.
+pub fn merged_path(source: &str, path: &str) -> String {
+    format!("{source}:{path}")
+}
+
+/// Copy the files and directories of the database at `path` into `conn`,
+/// under `merged_path`, so their duplicates show up alongside this
+/// database's. Rows merged from the same source before are replaced, and
+/// rows the source itself merged from elsewhere keep their path and source.
+/// Every row keeps the host and volume it was scanned on.
+/// Both databases must hash with the same algorithm; the source is only read.
+pub fn merge_database(conn: &Connection, path: &Path) -> Result<MergeStats> {
+    let source = source_name(path)?;
+    if !path.exists() {
+        bail!("database {} does not exist", path.display());
+    }
+    // A scratch copy brings an older source up to this schema without
+    // writing to it, and refuses a newer one
+    let other = db::open_scratch_copy(path)?;
+    if !db::is_empty(&other)? {
+        let theirs = hashing::resolve_algorithm(&other, None)?;
+        if db::is_empty(conn)? {
+            hashing::resolve_algorithm(conn, Some(theirs))?;
+        } else {
+            let ours = hashing::resolve_algorithm(conn, None)?;
+            if ours != theirs {
+                bail!(
+                    "{} holds {} hashes but this database holds {}; they can never match",
+                    path.display(),
+                    theirs.name(),
+                    ours.name()
+                );
+            }
+        }
+    }
+
+    let nested = db::merged_sources(&other)?;
+    let locations = db::locations(&other)?;
+    let mut stats = MergeStats {
+        source: source.clone(),
+        files: 0,
+        directories: 0,
+    };
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    db::remove_merged(conn, &source)?;
+    for file in db::all_files(&other)? {
+        let path = match nested.get(&file.path) {
+            Some(from) => {
+                db::insert_merged_file(conn, &file.path, &file, from)?;
+                file.path.clone()
+            }
+            None => {
+                let path = merged_path(&source, &file.path);
+                db::insert_merged_file(conn, &path, &file, &source)?;
+                path
+            }
+        };
+        if let Some(location) = locations.get(&file.path) {
+            db::set_location(conn, &path, location)?;
+        }
+        stats.files += 1;
+        batch.tick()?;
+    }
+    for dir in db::all_directories(&other)? {
+        let path = match nested.get(&dir.path) {
+            Some(from) => {
+                db::insert_merged_directory(conn, &dir.path, &dir, from)?;
+                dir.path.clone()
+            }
+            None => {
+                let path = merged_path(&source, &dir.path);
+                db::insert_merged_directory(conn, &path, &dir, &source)?;
+                path
+            }
+        };
+        if let Some(location) = locations.get(&dir.path) {
+            db::set_location(conn, &path, location)?;
+        }
+        stats.directories += 1;
+        batch.tick()?;
+    }
+    batch.commit()?;
+    Ok(stats)
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duplicates;
+
+    /// A database file at `dir/name` holding `files` (path, hash).
+    fn source_db(dir: &Path, name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+        let path = dir.join(name);
+        let conn = db::init_database(&path).unwrap();
+        hashing::resolve_algorithm(&conn, None).unwrap();
+        for &(file, hash) in files {
+            db::upsert_file(&conn, Path::new(file), hash, 10, 0).unwrap();
+        }
+        db::upsert_directory(&conn, Path::new("/home"), "dh", 20).unwrap();
+        path
+    }
+
+    fn setup() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_merge_keeps_same_paths_from_different_machines_apart() {
+        let tmp = tempfile::tempdir().unwrap();
+        let laptop = source_db(tmp.path(), "laptop.db", &[("/home/a.jpg", "x")]);
+        let nas = source_db(tmp.path(), "nas.db", &[("/home/a.jpg", "x")]);
+        let conn = setup();
+
+        let stats = merge_database(&conn, &laptop).unwrap();
+        assert_eq!(
+            (stats.source.as_str(), stats.files, stats.directories),
+            ("laptop", 1, 1)
+        );
+        merge_database(&conn, &nas).unwrap();
+
+        let groups = duplicates::find_duplicate_files(&conn).unwrap();
+        assert_eq!(groups.len(), 1);
+        let paths: Vec<&str> = groups[0].files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, vec!["laptop:/home/a.jpg", "nas:/home/a.jpg"]);
+        let sources = db::merged_sources(&conn).unwrap();
+        assert_eq!(sources["nas:/home/a.jpg"], "nas");
+        assert_eq!(sources["laptop:/home"], "laptop");
+    }
+
+    #[test]
+    fn test_merging_a_source_again_replaces_its_rows() {
+        let tmp = tempfile::tempdir().unwrap();
+        let laptop = source_db(tmp.path(), "laptop.db", &[("/a", "x"), ("/b", "y")]);
+        let conn = setup();
+        merge_database(&conn, &laptop).unwrap();
+
+        std::fs::remove_file(&laptop).unwrap();
+        let laptop = source_db(tmp.path(), "laptop.db", &[("/a", "x")]);
+        merge_database(&conn, &laptop).unwrap();
+        let paths: Vec<String> = db::all_files(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        assert_eq!(paths, vec!["laptop:/a"]);
+    }
+
+    #[test]
+    fn test_merge_keeps_the_provenance_of_rows_merged_before() {
+        let tmp = tempfile::tempdir().unwrap();
+        let laptop = source_db(tmp.path(), "laptop.db", &[("/a", "x")]);
+        let desktop_path = tmp.path().join("desktop.db");
+        {
+            let desktop = db::init_database(&desktop_path).unwrap();
+            merge_database(&desktop, &laptop).unwrap();
+        }
+        let conn = setup();
+        merge_database(&conn, &desktop_path).unwrap();
+        let sources = db::merged_sources(&conn).unwrap();
+        assert_eq!(sources["laptop:/a"], "laptop");
+    }
+
+    #[test]
+    fn test_merge_keeps_where_each_row_was_scanned() {
+        let tmp = tempfile::tempdir().unwrap();
+        let nas = source_db(tmp.path(), "nas.db", &[("/a", "x")]);
+        {
+            let conn = db::init_database(&nas).unwrap();
+            let location = db::Location {
+                host: Some("laptop".to_string()),
+                volume: Some("photos".to_string()),
+            };
+            db::set_location(&conn, "/a", &location).unwrap();
+        }
+        let conn = setup();
+        merge_database(&conn, &nas).unwrap();
+        let names = db::location_names(&conn).unwrap();
+        assert_eq!(names["nas:/a"], "photos");
+        // Rows scanned before hosts were recorded are named after the source
+        assert_eq!(names["nas:/home"], "nas");
+    }
+
+    #[test]
+    fn test_merge_refuses_a_different_hash_algorithm() {
+        let tmp = tempfile::tempdir().unwrap();
+        let laptop = source_db(tmp.path(), "laptop.db", &[("/a", "x")]);
+        let conn = setup();
+        hashing::resolve_algorithm(&conn, Some(hashing::HashAlgorithm::Sha256)).unwrap();
+        db::upsert_file(&conn, Path::new("/local"), "h", 1, 0).unwrap();
+        assert!(merge_database(&conn, &laptop).is_err());
+        assert!(merge_database(&conn, &tmp.path().join("missing.db")).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::ignore_rules::IgnoreRules;
+use crate::{db, utils};
+
+/// A file sharing its name with others.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NamedFile {
+    pub path: String,
+    pub hash: String,
+    pub size: i64,
+    /// Unix timestamp of last modification
+    pub modified: i64,
+}
+
+/// Files with the same name whose contents aren't all the same.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameGroup {
+    /// The name (or stem) as the first file spells it
+    pub name: String,
+    /// Newest first, then by path
+    pub files: Vec<NamedFile>,
+    /// How many different contents there are among `files`, at least 2
+    pub versions: usize,
+}
+
+/// Group the files in the database (under `scope`, when it isn't empty) by
+/// file name, ignoring case, or with `by_stem` by the name without its
+/// extension, so `report_final.docx` and `report_final.pdf` go together,
+/// and keep the groups in which the content hashes differ: five
+/// `report_final.docx` files that are not all copies of one another. A file
+/// `--prefilter` gave a provisional hash differs from every other. Files the
+/// ignore rules match are left out. Most versions first, then by name.
+pub fn find_same_names(
+    conn: &Connection,
+    scope: &[&Path],
+    by_stem: bool,
+) -> Result<Vec<NameGroup>> {
+    let ignored = IgnoreRules::load(conn)?;
+    let mut by_name: HashMap<String, Vec<NamedFile>> = HashMap::new();
+    for file in db::all_files(conn)? {
+        let path = utils::path_from_db(&file.path);
+        if ignored.ignores_path(&file.path)
+            || !(scope.is_empty() || scope.iter().any(|r| path.starts_with(r)))
+        {
+            continue;
+        }
+        let name = if by_stem {
+            path.file_stem()
+        } else {
+            path.file_name()
+        };
+        let Some(name) = name else {
+            continue;
+        };
+        by_name
+            .entry(name.to_string_lossy().to_lowercase())
+            .or_default()
+            .push(NamedFile {
+                path: file.path,
+                hash: file.hash,
+                size: file.size,
+                modified: file.modified,
+            });
+    }
+
+    let mut groups: Vec<NameGroup> = by_name
+        .into_values()
+        .filter_map(|mut files| {
+            let versions = files.iter().map(|f| &f.hash).collect::<HashSet<_>>().len();
+            if versions < 2 {
+                return None;
+            }
+            files.sort_by(|a, b| {
+                b.modified
+                    .cmp(&a.modified)
+                    .then_with(|| a.path.cmp(&b.path))
+            });
+            let first = utils::path_from_db(&files[0].path);
+            let name = if by_stem {
+                first.file_stem()
+            } else {
+                first.file_name()
+            };
+            Some(NameGroup {
+                name: name.unwrap_or_default().to_string_lossy().into_owned(),
+                files,
+                versions,
+            })
+        })
+        .collect();
+    groups.sort_by(|a, b| {
+        b.versions
+            .cmp(&a.versions)
+            .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+    });
+    Ok(groups)
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    fn insert_file(conn: &Connection, path: &str, hash: &str, modified: i64) {
+        db::upsert_file(conn, Path::new(path), hash, 10, modified).unwrap();
+    }
+
+    fn summary(groups: &[NameGroup]) -> Vec<(&str, usize, Vec<&str>)> {
+        groups
+            .iter()
+            .map(|g| {
+                let paths = g.files.iter().map(|f| f.path.as_str()).collect();
+                (g.name.as_str(), g.versions, paths)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_find_same_names_keeps_names_whose_contents_differ() {
+        let conn = open_test_db();
+        insert_file(&conn, "/a/report_final.docx", "h1", 100);
+        insert_file(&conn, "/b/Report_Final.docx", "h2", 300);
+        insert_file(&conn, "/c/report_final.docx", "h1", 200);
+        insert_file(&conn, "/d/report_final.pdf", "h3", 50);
+        // Copies alone are dup-files' business
+        insert_file(&conn, "/a/notes.txt", "n", 1);
+        insert_file(&conn, "/b/notes.txt", "n", 1);
+        insert_file(&conn, "/a/unique.txt", "u", 1);
+
+        let groups = find_same_names(&conn, &[], false).unwrap();
+        assert_eq!(
+            summary(&groups),
+            vec![(
+                "Report_Final.docx",
+                2,
+                vec![
+                    "/b/Report_Final.docx",
+                    "/c/report_final.docx",
+                    "/a/report_final.docx"
+                ]
+            )]
+        );
+
+        let groups = find_same_names(&conn, &[], true).unwrap();
+        assert_eq!(summary(&groups)[0].1, 3);
+        assert_eq!(groups[0].files.len(), 4);
+
+        let groups = find_same_names(&conn, &[Path::new("/b"), Path::new("/c")], false).unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].files.len(), 2);
+        assert!(
+            find_same_names(&conn, &[Path::new("/a"), Path::new("/c")], false)
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::ignore_rules::IgnoreRules;
+use crate::{db, hashing, utils};
+
+/// Pairs whose smaller side holds fewer distinct files than this aren't
+/// reported: a single file in common is `dup-files`' business.
+const MIN_FILES: usize = 2;
+
+/// Most directories a file's content can be in and still pair them up by
+/// itself. Content found in more (a licence or `.gitignore` copied into
+/// hundreds of projects) still counts towards the share of pairs other files
+/// find, but would otherwise pair each of those directories with all the
+/// rest.
+const MAX_SHARERS: usize = 100;
+
+/// Two directories much of whose content is the same, wherever it sits in
+/// each.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlap {
+    /// The directory more of whose content is in the other
+    pub contained: String,
+    pub container: String,
+    /// Distinct file contents anywhere under each
+    pub contained_files: usize,
+    pub container_files: usize,
+    /// Distinct file contents under both
+    pub shared: usize,
+    /// Bytes of one copy of each shared content
+    pub shared_bytes: i64,
+}
+
+impl Overlap {
+    /// The fraction of `contained`'s content that is also in `container`.
+    pub fn contained_fraction(&self) -> f64 {
+        self.shared as f64 / self.contained_files as f64
+    }
+
+    /// The fraction of `container`'s content that is also in `contained`.
+    pub fn container_fraction(&self) -> f64 {
+        self.shared as f64 / self.container_files as f64
+    }
+}
+
+/// Find pairs of directories (under `scope`, when it isn't empty) where at
+/// least `threshold` of the distinct file contents of one are also anywhere
+/// under the other, e.g. a backup folder most of whose photos are in the
+/// photo library, renamed or sorted differently. Each pair is the top-most
+/// one: pairs of subdirectories of a reported pair are left out when it
+/// covers them, and so are directories and their own subdirectories, and
+/// identical trees, which `dup-dirs` reports. Empty files, files the ignore
+/// rules match and files `--prefilter` left with a provisional hash don't
+/// count. Most shared bytes first.
+pub fn find_overlapping_directories(
+    conn: &Connection,
+    threshold: f64,
+    scope: &[&Path],
+) -> Result<Vec<Overlap>> {
+    let ignored = IgnoreRules::load(conn)?;
+    let roots: Vec<String> = scope
+        .iter()
+        .map(|r| utils::path_to_db(r).into_owned())
+        .collect();
+
+    // Every directory's distinct contents, by id, counting files at any depth
+    let mut content_ids: HashMap<String, usize> = HashMap::new();
+    let mut content_sizes: Vec<i64> = Vec::new();
+    let mut dir_ids: HashMap<String, usize> = HashMap::new();
+    let mut dirs: Vec<(String, HashSet<usize>)> = Vec::new();
+    for file in db::all_files(conn)? {
+        if file.size == 0 || hashing::is_provisional(&file.hash) || ignored.ignores_path(&file.path)
+        {
+            continue;
+        }
+        let root = match roots.iter().find(|r| Path::new(&file.path).starts_with(r)) {
+            Some(root) => Some(Path::new(root)),
+            None if roots.is_empty() => None,
+            None => continue,
+        };
+        let next = content_ids.len();
+        let content = *content_ids.entry(file.hash).or_insert(next);
+        if content == content_sizes.len() {
+            content_sizes.push(file.size);
+        }
+        for dir in Path::new(&file.path).ancestors().skip(1) {
+            let name = dir.to_string_lossy();
+            if name.is_empty() {
+                break;
+            }
+            let id = match dir_ids.get(name.as_ref()) {
+                Some(&id) => id,
+                None => {
+                    dir_ids.insert(name.to_string(), dirs.len());
+                    dirs.push((name.to_string(), HashSet::new()));
+                    dirs.len() - 1
+                }
+            };
+            dirs[id].1.insert(content);
+            if Some(dir) == root {
+                break;
+            }
+        }
+    }
+
+    // Directories that share a content are candidate pairs
+    let mut sharers: Vec<Vec<usize>> = vec![Vec::new(); content_sizes.len()];
+    for (id, (_, contents)) in dirs.iter().enumerate() {
+        for &content in contents {
+            sharers[content].push(id);
+        }
+    }
+    let mut candidates: HashSet<(usize, usize)> = HashSet::new();
+    for list in sharers.iter().filter(|l| l.len() <= MAX_SHARERS) {
+        for (i, &a) in list.iter().enumerate() {
+            for &b in &list[i + 1..] {
+                candidates.insert((a.min(b), a.max(b)));
+            }
+        }
+    }
+
+    let dir_hashes: HashMap<String, String> = db::all_directories(conn)?
+        .into_iter()
+        .map(|d| (d.path, d.hash))
+        .collect();
+    // Identical trees are kept as `None`, to hide the pairs inside them too
+    let mut found: HashMap<(usize, usize), Option<Overlap>> = HashMap::new();
+    for (a, b) in candidates {
+        let ((path_a, set_a), (path_b, set_b)) = (&dirs[a], &dirs[b]);
+        if set_a.len().min(set_b.len()) < MIN_FILES
+            || Path::new(path_a).starts_with(path_b)
+            || Path::new(path_b).starts_with(path_a)
+        {
+            continue;
+        }
+        if let (Some(hash_a), Some(hash_b)) = (dir_hashes.get(path_a), dir_hashes.get(path_b)) {
+            if hash_a == hash_b {
+                found.insert((a, b), None);
+                continue;
+            }
+        }
+        let (small, large) = if set_a.len() <= set_b.len() {
+            (set_a, set_b)
+        } else {
+            (set_b, set_a)
+        };
+        let shared: Vec<usize> = small
+            .iter()
+            .filter(|c| large.contains(c))
+            .copied()
+            .collect();
+        if (shared.len() as f64) < threshold * small.len() as f64 {
+            continue;
+        }
+        let (contained, container) =
+            if set_a.len() < set_b.len() || (set_a.len() == set_b.len() && path_a <= path_b) {
+                ((path_a, set_a), (path_b, set_b))
+            } else {
+                ((path_b, set_b), (path_a, set_a))
+            };
+        found.insert(
+            (a, b),
+            Some(Overlap {
+                contained: contained.0.clone(),
+                container: container.0.clone(),
+                contained_files: contained.1.len(),
+                container_files: container.1.len(),
+                shared: shared.len(),
+                shared_bytes: shared.iter().map(|&c| content_sizes[c]).sum(),
+            }),
+        );
+    }
+
+    // A pair inside another pair, one side or both, is part of that one
+    let with_ancestors = |id: usize| -> Vec<usize> {
+        Path::new(&dirs[id].0)
+            .ancestors()
+            .map_while(|d| dir_ids.get(d.to_string_lossy().as_ref()).copied())
+            .collect()
+    };
+    let covered: HashSet<(usize, usize)> = found
+        .keys()
+        .filter(|&&(a, b)| {
+            let (above_a, above_b) = (with_ancestors(a), with_ancestors(b));
+            above_a.iter().any(|&x| {
+                above_b
+                    .iter()
+                    .any(|&y| (x, y) != (a, b) && found.contains_key(&(x.min(y), x.max(y))))
+            })
+        })
+        .copied()
+        .collect();
+    let mut overlaps: Vec<Overlap> = found
+        .into_iter()
+        .filter(|(key, _)| !covered.contains(key))
+        .filter_map(|(_, overlap)| overlap)
+        .collect();
+    overlaps.sort_by(|a, b| {
+        b.shared_bytes
+            .cmp(&a.shared_bytes)
+            .then_with(|| a.contained.cmp(&b.contained))
+            .then_with(|| a.container.cmp(&b.container))
+    });
+    Ok(overlaps)
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scan;
+    use std::fs;
+
+    fn write_files(dir: &Path, files: &[(&str, &str)]) {
+        for (name, content) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+    }
+
+    fn scanned(root: &Path) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        let opts = scan::ScanOptions::default();
+        scan::scan_directory(&conn, root, 1, &opts, |_, _, _| ()).unwrap();
+        conn
+    }
+
+    fn db_path(path: &Path) -> String {
+        utils::path_to_db(path).into_owned()
+    }
+
+    #[test]
+    fn test_find_overlapping_directories_matches_content_wherever_it_sits() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let photos: Vec<(String, String)> = (0..10)
+            .map(|i| {
+                (
+                    format!("Photos/{}/{i}.jpg", 2019 + i % 2),
+                    format!("photo {i}"),
+                )
+            })
+            .collect();
+        let photos: Vec<(&str, &str)> = photos
+            .iter()
+            .map(|(n, c)| (n.as_str(), c.as_str()))
+            .collect();
+        write_files(root, &photos);
+        // Nine of the ten photos under other names, and one more
+        let backup: Vec<(String, String)> = (0..9)
+            .map(|i| (format!("Backup/IMG_{i}.jpg"), format!("photo {i}")))
+            .chain([("Backup/notes.txt".to_string(), "notes".to_string())])
+            .collect();
+        let backup: Vec<(&str, &str)> = backup
+            .iter()
+            .map(|(n, c)| (n.as_str(), c.as_str()))
+            .collect();
+        write_files(root, &backup);
+        write_files(root, &[("Other/a.txt", "photo 0"), ("Other/b.txt", "b")]);
+
+        let conn = scanned(root);
+        let found = find_overlapping_directories(&conn, 0.85, &[root]).unwrap();
+        assert_eq!(
+            found,
+            vec![Overlap {
+                contained: db_path(&root.join("Backup")),
+                container: db_path(&root.join("Photos")),
+                contained_files: 10,
+                container_files: 10,
+                shared: 9,
+                shared_bytes: 63,
+            }]
+        );
+        assert_eq!(found[0].contained_fraction(), 0.9);
+
+        // Without the pair above, the half of the photos all in the backup
+        // is reported on its own
+        let found = find_overlapping_directories(&conn, 0.95, &[root]).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].contained, db_path(&root.join("Photos/2019")));
+        assert_eq!(found[0].container, db_path(&root.join("Backup")));
+    }
+
+    #[test]
+    fn test_find_overlapping_directories_reports_only_the_top_pair() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let tree = [
+            ("x/a.txt", "a"),
+            ("x/b.txt", "b"),
+            ("y/c.txt", "c"),
+            ("y/d.txt", "d"),
+        ];
+        write_files(&root.join("old"), &tree);
+        write_files(&root.join("new"), &tree);
+        write_files(root, &[("new/x/e.txt", "e"), ("new/y/f.txt", "f")]);
+        // Identical trees are dup-dirs' business
+        write_files(&root.join("copy"), &tree);
+        write_files(&root.join("copy2"), &tree);
+
+        let conn = scanned(root);
+        let scope = [root.join("new"), root.join("old")];
+        let scope: Vec<&Path> = scope.iter().map(|p| p.as_path()).collect();
+        let found = find_overlapping_directories(&conn, 0.6, &scope).unwrap();
+        let pairs: Vec<_> = found
+            .iter()
+            .map(|o| (o.contained.clone(), o.container.clone()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![(db_path(&root.join("old")), db_path(&root.join("new")))]
+        );
+
+        let scope = [root.join("copy"), root.join("copy2")];
+        let scope: Vec<&Path> = scope.iter().map(|p| p.as_path()).collect();
+        assert!(find_overlapping_directories(&conn, 0.6, &scope)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use rusqlite::Connection;
+
+use crate::hashing::{self, HashAlgorithm};
+use crate::ignore_rules::IgnoreRules;
+use crate::{db, utils};
+
+/// Rows written per transaction while storing payload hashes.
+const TRANSACTION_BATCH_SIZE: usize = 1000;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// PNG chunks that decide what the picture looks like; every other chunk
+/// (text, EXIF, timestamps, colour hints) is metadata.
+const PNG_IMAGE_CHUNKS: &[&[u8]] = &[b"IHDR", b"PLTE", b"tRNS", b"IDAT"];
+
+/// Whether the file at `path` is a photo whose payload `image_payload` can
+/// find, going by its extension.
+pub fn is_photo(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| matches!(e.as_str(), "jpg" | "jpeg" | "png"))
+}
+
+/// The part of a JPEG or PNG file that holds the picture, with the metadata
+/// that tools edit in place left out: for JPEG every APPn segment (EXIF,
+/// XMP, IPTC, ICC profiles and thumbnails) and comment, and anything after
+/// the end of the image; for PNG every chunk but the header, palette,
+/// transparency and image data. Adding tags, fixing a date or setting the
+/// EXIF orientation flag leaves the payload alone; re-encoding doesn't.
+pub fn image_payload(data: &[u8]) -> Result<Vec<u8>> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        jpeg_payload(data)
+    } else if data.starts_with(PNG_SIGNATURE) {
+        png_payload(data)
+    } else {
+        bail!("not a JPEG or PNG file")
+    }
+}
+
+fn jpeg_payload(data: &[u8]) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    let mut at = 2;
+    loop {
+        let Some(&[0xFF, marker]) = data.get(at..at + 2) else {
+            bail!("broken JPEG segment at byte {at}");
+        };
+        // Fill bytes before a marker
+        if marker == 0xFF {
+            at += 1;
+            continue;
+        }
+        let Some(length) = data.get(at + 2..at + 4) else {
+            bail!("JPEG ends inside a segment");
+        };
+        let end = at + 2 + usize::from(u16::from_be_bytes([length[0], length[1]]));
+        if end > data.len() {
+            bail!("JPEG ends inside a segment");
+        }
+        let metadata = (0xE0..=0xEF).contains(&marker) || marker == 0xFE;
+        if marker == 0xDA {
+            // Start of scan: from here on it's entropy-coded data and the
+            // tables between progressive scans, up to the end of the image
+            let rest = &data[at..];
+            let image_end = rest
+                .windows(2)
+                .rposition(|w| w == [0xFF, 0xD9])
+                .map_or(rest.len(), |i| i + 2);
+            payload.extend_from_slice(&rest[..image_end]);
+            return Ok(payload);
+        }
+        if !metadata {
+            payload.extend_from_slice(&data[at..end]);
+        }
+        at = end;
+    }
+}
+
+fn png_payload(data: &[u8]) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    let mut at = PNG_SIGNATURE.len();
+    while at < data.len() {
+        let Some(header) = data.get(at..at + 8) else {
+            bail!("PNG ends inside a chunk");
+        };
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = &header[4..8];
+        let Some(body) = data.get(at + 8..at + 8 + length) else {
+            bail!("PNG ends inside a chunk");
+        };
+        if kind == b"IEND" {
+            return Ok(payload);
+        }
+        if PNG_IMAGE_CHUNKS.contains(&kind) {
+            // Only IDAT's data counts, so splitting it differently doesn't
+            if kind != b"IDAT" {
+                payload.extend_from_slice(kind);
+            }
+            payload.extend_from_slice(body);
+        }
+        at += 8 + length + 4;
+    }
+    bail!("PNG has no end chunk")
+}
+
+/// Hash of the payload of the photo at `path`.
+pub fn payload_hash(path: &Path, algorithm: HashAlgorithm) -> Result<String> {
+    let data = std::fs::read(path)?;
+    Ok(algorithm.hash_bytes(&image_payload(&data)?))
+}
+
+/// A photo file, one of a group.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Photo {
+    pub path: String,
+    /// Its content hash: files with the same one are plain duplicates
+    pub hash: String,
+    pub size: i64,
+    pub modified: i64,
+}
+
+/// Photos with the same payload whose files differ.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PhotoGroup {
+    pub payload: String,
+    /// Sorted by path
+    pub photos: Vec<Photo>,
+    /// How many different file contents there are among `photos`, at least 2
+    pub variants: usize,
+}
+
+/// What `find_metadata_variants` found.
+pub struct MetadataVariants {
+    /// Most variants first
+    pub groups: Vec<PhotoGroup>,
+    /// Photos whose payload couldn't be found, and so aren't in any group
+    pub unreadable: usize,
+}
+
+/// Group the JPEG and PNG photos in the database (under `scope`, when it
+/// isn't empty) that hold the same picture but differ as files, only in
+/// their metadata. Files are read only when the database holds no payload
+/// hash for their current content; those are stored for next time. Groups
+/// of exact copies alone are `dup-files`' business and aren't reported.
+/// Files merged in from another database or inside archives, and files the
+/// ignore rules match, are left out.
+pub fn find_metadata_variants(
+    conn: &Connection,
+    scope: &[&Path],
+    on_progress: impl Fn(usize, usize) + Sync,
+) -> Result<MetadataVariants> {
+    let algorithm = hashing::recorded_algorithm(conn)?.unwrap_or(HashAlgorithm::RECOMMENDED);
+    let off_disk = db::files_off_disk(conn)?;
+    let ignored = IgnoreRules::load(conn)?;
+    let files: Vec<db::FileRecord> = db::all_files(conn)?
+        .into_iter()
+        .filter(|f| !off_disk.contains(&f.path) && !ignored.ignores_path(&f.path))
+        .filter(|f| !hashing::is_provisional(&f.hash))
+        .filter(|f| {
+            let path = utils::path_from_db(&f.path);
+            is_photo(&path) && (scope.is_empty() || scope.iter().any(|r| path.starts_with(r)))
+        })
+        .collect();
+
+    let mut stored = db::photo_payloads(conn)?;
+    let stale: Vec<&db::FileRecord> = files
+        .iter()
+        .filter(|f| stored.get(&f.path).is_none_or(|s| s.hash != f.hash))
+        .collect();
+    let done = AtomicUsize::new(0);
+    let fresh: Vec<(&db::FileRecord, Option<String>)> = stale
+        .par_iter()
+        .map(|&file| {
+            let payload = payload_hash(&utils::path_from_db(&file.path), algorithm).ok();
+            on_progress(done.fetch_add(1, Ordering::Relaxed) + 1, stale.len());
+            (file, payload)
+        })
+        .collect();
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    for (file, payload) in fresh {
+        let record = db::PhotoPayloadRecord {
+            hash: file.hash.clone(),
+            payload,
+        };
+        db::set_photo_payload(conn, &file.path, &record)?;
+        stored.insert(file.path.clone(), record);
+        batch.tick()?;
+    }
+    db::prune_photo_payloads(conn)?;
+    batch.commit()?;
+
+    let mut unreadable = 0;
+    let mut by_payload: HashMap<&str, Vec<Photo>> = HashMap::new();
+    for file in &files {
+        let Some(payload) = stored.get(&file.path).and_then(|s| s.payload.as_deref()) else {
+            unreadable += 1;
+            continue;
+        };
+        by_payload.entry(payload).or_default().push(Photo {
+            path: file.path.clone(),
+            hash: file.hash.clone(),
+            size: file.size,
+            modified: file.modified,
+        });
+    }
+    let mut groups: Vec<PhotoGroup> = by_payload
+        .into_iter()
+        .filter_map(|(payload, photos)| {
+            let mut contents: Vec<&str> = photos.iter().map(|p| p.hash.as_str()).collect();
+            contents.sort_unstable();
+            contents.dedup();
+            let variants = contents.len();
+            (variants > 1).then(|| PhotoGroup {
+                payload: payload.to_string(),
+                photos,
+                variants,
+            })
+        })
+        .collect();
+    for group in &mut groups {
+        group.photos.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+    groups.sort_by(|a, b| {
+        b.variants
+            .cmp(&a.variants)
+            .then_with(|| a.photos[0].path.cmp(&b.photos[0].path))
+    });
+    Ok(MetadataVariants { groups, unreadable })
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A tiny JPEG: SOI, the given APPn/COM segments, a quantization table,
+    /// a frame header, a scan with some data, EOI.
+    fn jpeg(metadata: &[(u8, &[u8])], scan: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8];
+        let mut segment = |marker: u8, body: &[u8]| {
+            data.extend_from_slice(&[0xFF, marker]);
+            data.extend_from_slice(&(body.len() as u16 + 2).to_be_bytes());
+            data.extend_from_slice(body);
+        };
+        for &(marker, body) in metadata {
+            segment(marker, body);
+        }
+        segment(0xDB, &[0; 65]);
+        segment(0xC0, &[8, 0, 16, 0, 16, 1, 1, 0x11, 0]);
+        segment(0xDA, &[1, 1, 0, 0, 63, 0]);
+        data.extend_from_slice(scan);
+        data.extend_from_slice(&[0xFF, 0xD9]);
+        data
+    }
+
+    fn png(chunks: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+        let mut data = PNG_SIGNATURE.to_vec();
+        for (kind, body) in chunks {
+            data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            data.extend_from_slice(*kind);
+            data.extend_from_slice(body);
+            // The CRC isn't checked
+            data.extend_from_slice(&[0; 4]);
+        }
+        data
+    }
+
+    #[test]
+    fn test_jpeg_payload_leaves_out_metadata_segments_and_trailing_bytes() {
+        let plain = jpeg(&[], b"\x12\x34\xFF\x00\x56");
+        let tagged = jpeg(
+            &[(0xE1, b"Exif\0\0orientation=6"), (0xFE, b"a comment")],
+            b"\x12\x34\xFF\x00\x56",
+        );
+        let mut trailing = plain.clone();
+        trailing.extend_from_slice(b"appended video");
+        let other = jpeg(&[], b"\x12\x35");
+
+        let payload = image_payload(&plain).unwrap();
+        assert_eq!(image_payload(&tagged).unwrap(), payload);
+        assert_eq!(image_payload(&trailing).unwrap(), payload);
+        assert_ne!(image_payload(&other).unwrap(), payload);
+        assert!(image_payload(&plain[..20]).is_err());
+        assert!(image_payload(b"GIF89a").is_err());
+    }
+
+    #[test]
+    fn test_png_payload_keeps_only_image_chunks() {
+        let header: &[u8] = &[0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0];
+        let plain = png(&[(b"IHDR", header), (b"IDAT", b"pixels"), (b"IEND", b"")]);
+        let tagged = png(&[
+            (b"IHDR", header),
+            (b"tEXt", b"Title\0Holiday"),
+            (b"IDAT", b"pix"),
+            (b"IDAT", b"els"),
+            (b"tIME", b"1234567"),
+            (b"IEND", b""),
+        ]);
+        let other = png(&[(b"IHDR", header), (b"IDAT", b"pixelz"), (b"IEND", b"")]);
+
+        let payload = image_payload(&plain).unwrap();
+        assert_eq!(image_payload(&tagged).unwrap(), payload);
+        assert_ne!(image_payload(&other).unwrap(), payload);
+        assert!(image_payload(&plain[..plain.len() - 12]).is_err());
+    }
+
+    #[test]
+    fn test_find_metadata_variants_groups_photos_differing_only_in_tags() {
+        let tmp = tempfile::tempdir().unwrap();
+        let scan = b"\x01\x02\x03";
+        std::fs::write(tmp.path().join("a.jpg"), jpeg(&[], scan)).unwrap();
+        std::fs::write(tmp.path().join("a copy.jpg"), jpeg(&[], scan)).unwrap();
+        let tagged = jpeg(&[(0xE1, b"Exif\0\0rating=5")], scan);
+        std::fs::write(tmp.path().join("a tagged.JPG"), tagged).unwrap();
+        std::fs::write(tmp.path().join("b.jpg"), jpeg(&[], b"\x09")).unwrap();
+        std::fs::write(tmp.path().join("b copy.jpg"), jpeg(&[], b"\x09")).unwrap();
+        std::fs::write(tmp.path().join("broken.png"), "not a png").unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        let opts = crate::scan::ScanOptions::default();
+        crate::scan::scan_directory(&conn, tmp.path(), 6, &opts, |_, _, _| {}).unwrap();
+
+        let found = find_metadata_variants(&conn, &[], |_, _| {}).unwrap();
+        assert_eq!(found.unreadable, 1);
+        // b.jpg and its copy are plain duplicates
+        assert_eq!(found.groups.len(), 1);
+        let group = &found.groups[0];
+        assert_eq!(group.variants, 2);
+        let names: Vec<String> = group
+            .photos
+            .iter()
+            .map(|p| {
+                let path = utils::path_from_db(&p.path);
+                path.file_name().unwrap().to_string_lossy().into_owned()
+            })
+            .collect();
+        assert_eq!(names, vec!["a copy.jpg", "a tagged.JPG", "a.jpg"]);
+        assert_eq!(db::photo_payloads(&conn).unwrap().len(), 6);
+
+        // Stored payload hashes are used rather than reading the files again
+        std::fs::remove_file(tmp.path().join("a tagged.JPG")).unwrap();
+        let again = find_metadata_variants(&conn, &[], |_, _| {}).unwrap();
+        assert_eq!(again.groups, found.groups);
+    }
+}
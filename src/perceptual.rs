@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+use rayon::prelude::*;
+use rusqlite::Connection;
+
+use crate::{db, lookalike, utils};
+
+/// Rows written per transaction while storing image hashes.
+const TRANSACTION_BATCH_SIZE: usize = 1000;
+
+/// Extensions of the image formats this build decodes (lowercase).
+const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "webp"];
+
+/// Greatest Hamming distance two 64-bit hashes can be apart.
+pub const MAX_DISTANCE: u32 = 64;
+
+/// Whether the file at `path` is an image `dhash` can read, going by its
+/// extension.
+pub fn is_image(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.as_str()))
+}
+
+/// An image's perceptual hash and its size in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHash {
+    pub dhash: u64,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The difference hash of the image at `path`: the image shrunk to 9×8 grey
+/// pixels, one bit per pair of horizontal neighbours, set when the left one
+/// is brighter. Re-encoding at another quality, rescaling and small colour
+/// changes leave most of the bits alone, so near-identical pictures end up a
+/// small Hamming distance apart.
+pub fn dhash(path: &Path) -> Result<ImageHash> {
+    let image = image::ImageReader::open(path)?
+        .with_guessed_format()?
+        .decode()?;
+    let grey = image.thumbnail_exact(9, 8).to_luma8();
+    Ok(ImageHash {
+        dhash: dhash_grey(grey.as_raw()),
+        width: image.width(),
+        height: image.height(),
+    })
+}
+
+/// The difference hash of a picture already shrunk to 9×8 grey pixels,
+/// given row by row, as `dhash` takes it.
+pub fn dhash_grey(pixels: &[u8]) -> u64 {
+    let mut dhash = 0u64;
+    for row in pixels.chunks_exact(9).take(8) {
+        for pair in row.windows(2) {
+            dhash = (dhash << 1) | u64::from(pair[0] > pair[1]);
+        }
+    }
+    dhash
+}
+
+/// How many of the 64 bits `a` and `b` differ in.
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// One distinct picture: every file with the same content counts once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    /// The files holding it, sorted
+    pub paths: Vec<String>,
+    pub size: i64,
+    pub hash: ImageHash,
+}
+
+/// Pictures whose perceptual hashes are within the threshold of one another,
+/// the one with the most pixels first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster {
+    pub images: Vec<Image>,
+}
+
+impl Cluster {
+    pub fn total_size(&self) -> i64 {
+        self.images
+            .iter()
+            .map(|i| i.size * i.paths.len() as i64)
+            .sum()
+    }
+}
+
+/// What `find_similar_images` found.
+pub struct SimilarImages {
+    /// Largest first
+    pub clusters: Vec<Cluster>,
+    /// Image files that couldn't be decoded, and so aren't in any cluster
+    pub unreadable: usize,
+}
+
+/// Cluster the images among `lookalike::candidates` whose perceptual hashes
+/// are at most `threshold` bits apart. A cluster links pictures through any
+/// chain of close pairs. Images are read only when the database holds no
+/// perceptual hash for their current content; those hashes are stored for
+/// next time.
+pub fn find_similar_images(
+    conn: &Connection,
+    threshold: u32,
+    scope: &[&Path],
+    on_progress: impl Fn(usize, usize) + Sync,
+) -> Result<SimilarImages> {
+    let files = lookalike::candidates(conn, scope, |_, path| is_image(path))?;
+
+    let mut stored = db::image_hashes(conn)?;
+    let stale: Vec<&db::FileRecord> = files
+        .iter()
+        .filter(|f| stored.get(&f.path).is_none_or(|s| s.hash != f.hash))
+        .collect();
+    let done = AtomicUsize::new(0);
+    let fresh: Vec<(&db::FileRecord, Option<ImageHash>)> = stale
+        .par_iter()
+        .map(|&file| {
+            let hash = dhash(&utils::path_from_db(&file.path)).ok();
+            on_progress(done.fetch_add(1, Ordering::Relaxed) + 1, stale.len());
+            (file, hash)
+        })
+        .collect();
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    for (file, hash) in fresh {
+        let record = db::ImageHashRecord {
+            hash: file.hash.clone(),
+            dhash: hash.map(|h| h.dhash as i64),
+            width: hash.map_or(0, |h| h.width.into()),
+            height: hash.map_or(0, |h| h.height.into()),
+        };
+        db::set_image_hash(conn, &file.path, &record)?;
+        stored.insert(file.path.clone(), record);
+        batch.tick()?;
+    }
+    db::prune_image_hashes(conn)?;
+    batch.commit()?;
+
+    let mut unreadable = 0;
+    let mut images = Vec::new();
+    for copies in lookalike::by_content(&files) {
+        let file = copies[0];
+        let Some(dhash) = stored.get(&file.path).and_then(|s| s.dhash.map(|d| (d, s))) else {
+            unreadable += copies.len();
+            continue;
+        };
+        let (dhash, record) = dhash;
+        images.push(Image {
+            paths: copies.iter().map(|f| f.path.clone()).collect(),
+            size: file.size,
+            hash: ImageHash {
+                dhash: dhash as u64,
+                width: record.width as u32,
+                height: record.height as u32,
+            },
+        });
+    }
+    Ok(SimilarImages {
+        clusters: cluster(images, threshold),
+        unreadable,
+    })
+}
+
+/// Group `images` into clusters of two or more whose hashes are linked by
+/// pairs at most `threshold` apart.
+fn cluster(images: Vec<Image>, threshold: u32) -> Vec<Cluster> {
+    let mut tree = BkTree::default();
+    for (index, image) in images.iter().enumerate() {
+        tree.insert(image.hash.dhash, index);
+    }
+    let mut sets = utils::DisjointSets::new(images.len());
+    let mut near = Vec::new();
+    for (index, image) in images.iter().enumerate() {
+        near.clear();
+        tree.within(image.hash.dhash, threshold, &mut near);
+        for &other in &near {
+            sets.union(index, other);
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<Image>> = HashMap::new();
+    for (index, mut image) in images.into_iter().enumerate() {
+        image.paths.sort();
+        members.entry(sets.find(index)).or_default().push(image);
+    }
+    let mut clusters: Vec<Cluster> = members
+        .into_values()
+        .filter(|images| images.len() > 1)
+        .map(|mut images| {
+            images.sort_by(|a, b| {
+                let pixels = |i: &Image| u64::from(i.hash.width) * u64::from(i.hash.height);
+                pixels(b)
+                    .cmp(&pixels(a))
+                    .then(b.size.cmp(&a.size))
+                    .then_with(|| a.paths.cmp(&b.paths))
+            });
+            Cluster { images }
+        })
+        .collect();
+    clusters.sort_by(|a, b| {
+        b.total_size()
+            .cmp(&a.total_size())
+            .then_with(|| a.images[0].paths.cmp(&b.images[0].paths))
+    });
+    clusters
+}
+
+/// A BK-tree over Hamming distance: finding every hash within a threshold
+/// of another only visits the branches that can hold one, rather than
+/// comparing it with every image in the library.
+#[derive(Default)]
+struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+struct BkNode {
+    hash: u64,
+    index: usize,
+    /// Each child with its distance from this node, all distinct
+    children: Vec<(u32, usize)>,
+}
+
+impl BkTree {
+    fn insert(&mut self, hash: u64, index: usize) {
+        let new = self.nodes.len();
+        self.nodes.push(BkNode {
+            hash,
+            index,
+            children: Vec::new(),
+        });
+        if new == 0 {
+            return;
+        }
+        let mut at = 0;
+        loop {
+            let d = distance(self.nodes[at].hash, hash);
+            match self.nodes[at].children.iter().find(|(cd, _)| *cd == d) {
+                Some(&(_, child)) => at = child,
+                None => {
+                    self.nodes[at].children.push((d, new));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Add the index of every hash at most `threshold` from `hash` to `out`.
+    fn within(&self, hash: u64, threshold: u32, out: &mut Vec<usize>) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut pending = vec![0];
+        while let Some(at) = pending.pop() {
+            let node = &self.nodes[at];
+            let d = distance(node.hash, hash);
+            if d <= threshold {
+                out.push(node.index);
+            }
+            // By the triangle inequality, only children this close can match
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(cd, _)| cd.abs_diff(d) <= threshold)
+                    .map(|&(_, child)| child),
+            );
+        }
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgb, RgbImage};
+
+    /// A picture of soft diagonal bands, shifted by `phase`.
+    fn bands(width: u32, height: u32, phase: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let x = x * 64 / width;
+            let y = y * 64 / height;
+            let v = (((x + 2 * y + phase) % 32) * 8) as u8;
+            Rgb([v, v / 2, 255 - v])
+        })
+    }
+
+    fn image(paths: &[&str], size: i64, dhash: u64, pixels: u32) -> Image {
+        Image {
+            paths: paths.iter().map(|p| p.to_string()).collect(),
+            size,
+            hash: ImageHash {
+                dhash,
+                width: pixels,
+                height: 1,
+            },
+        }
+    }
+
+    #[test]
+    fn test_dhash_barely_moves_when_an_image_is_resized_and_recompressed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let original = tmp.path().join("original.png");
+        let smaller = tmp.path().join("smaller.jpg");
+        let other = tmp.path().join("other.png");
+        bands(640, 480, 0).save(&original).unwrap();
+        image::imageops::resize(
+            &bands(640, 480, 0),
+            200,
+            150,
+            image::imageops::FilterType::Triangle,
+        )
+        .save_with_format(&smaller, ImageFormat::Jpeg)
+        .unwrap();
+        bands(640, 480, 16).save(&other).unwrap();
+
+        let a = dhash(&original).unwrap();
+        let b = dhash(&smaller).unwrap();
+        let c = dhash(&other).unwrap();
+        assert_eq!((a.width, a.height, b.width), (640, 480, 200));
+        assert!(
+            distance(a.dhash, b.dhash) <= 4,
+            "{}",
+            distance(a.dhash, b.dhash)
+        );
+        assert!(
+            distance(a.dhash, c.dhash) > 16,
+            "{}",
+            distance(a.dhash, c.dhash)
+        );
+        assert!(dhash(&tmp.path().join("missing.png")).is_err());
+    }
+
+    #[test]
+    fn test_cluster_links_chains_of_close_hashes_and_drops_loners() {
+        let images = vec![
+            image(&["/small.jpg"], 10, 0b0000, 100),
+            image(&["/big.png"], 50, 0b0011, 400),
+            // Three bits from /small.jpg, one from /big.png
+            image(&["/b/mid.jpg", "/a/mid.jpg"], 20, 0b0111, 200),
+            image(&["/alone.jpg"], 99, u64::MAX, 100),
+        ];
+        let clusters = cluster(images, 2);
+        assert_eq!(clusters.len(), 1);
+        let paths: Vec<&str> = clusters[0]
+            .images
+            .iter()
+            .map(|i| i.paths[0].as_str())
+            .collect();
+        // Most pixels first; copies of one picture keep their paths sorted
+        assert_eq!(paths, vec!["/big.png", "/a/mid.jpg", "/small.jpg"]);
+        assert_eq!(clusters[0].total_size(), 50 + 40 + 10);
+    }
+
+    #[test]
+    fn test_bk_tree_finds_exactly_the_hashes_within_the_threshold() {
+        let hashes: Vec<u64> = (0..500u64)
+            .map(|i| i.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+            .collect();
+        let mut tree = BkTree::default();
+        for (i, &h) in hashes.iter().enumerate() {
+            tree.insert(h, i);
+        }
+        for &probe in &hashes[..20] {
+            let mut found = Vec::new();
+            tree.within(probe, 24, &mut found);
+            found.sort();
+            let expected: Vec<usize> = (0..hashes.len())
+                .filter(|&i| distance(hashes[i], probe) <= 24)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn test_find_similar_images_stores_hashes_and_skips_what_it_cannot_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let a = tmp.path().join("a.png");
+        let b = tmp.path().join("b.jpg");
+        let copy = tmp.path().join("copy.png");
+        let broken = tmp.path().join("broken.jpg");
+        bands(320, 240, 0).save(&a).unwrap();
+        bands(160, 120, 0)
+            .save_with_format(&b, ImageFormat::Jpeg)
+            .unwrap();
+        std::fs::copy(&a, &copy).unwrap();
+        std::fs::write(&broken, "not a jpeg").unwrap();
+        std::fs::write(tmp.path().join("notes.txt"), "text").unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        let opts = crate::scan::ScanOptions::default();
+        crate::scan::scan_directory(&conn, tmp.path(), 5, &opts, |_, _, _| {}).unwrap();
+
+        let found = find_similar_images(&conn, 6, &[], |_, _| {}).unwrap();
+        assert_eq!(found.unreadable, 1);
+        assert_eq!(found.clusters.len(), 1);
+        let images = &found.clusters[0].images;
+        assert_eq!(images.len(), 2, "the exact copy counts once");
+        assert_eq!(images[0].paths.len(), 2);
+        assert_eq!(images[1].paths, vec![utils::path_to_db(&b).into_owned()]);
+        assert_eq!(db::image_hashes(&conn).unwrap().len(), 4);
+
+        // Stored hashes are used rather than reading the files again
+        std::fs::remove_file(&b).unwrap();
+        let again = find_similar_images(&conn, 6, &[], |_, _| {}).unwrap();
+        assert_eq!(again.clusters, found.clusters);
+        let none = find_similar_images(&conn, 6, &[Path::new("/elsewhere")], |_, _| {}).unwrap();
+        assert!(none.clusters.is_empty());
+    }
+}
@@ -43,6 +43,7 @@ pub fn sort_root(
     conn: &Connection,
     root: &Path,
     canon: &Path,
+    hash_opts: &hashing::HashOptions,
     on_event: &mut impl FnMut(SortEvent<'_>),
 ) -> Result<SortStats> {
     let dest_root = canon;
@@ -75,19 +76,23 @@ pub fn sort_root(
             }
         }
 
-        let dest_path = resolve_dest(src, &dest_dir, conn)?;
+        let dest_path = resolve_dest(src, &dest_dir, conn, hash_opts)?;
 
         match dest_path {
             DestResult::TrueDuplicate => {
                 on_event(SortEvent::Duplicate(src));
+                let hash = db::get_file(conn, src)?.map(|f| f.hash);
                 file_system::delete_file(src)?;
                 db::remove_file(conn, src)?;
+                db::log_action(conn, "delete", src, None, hash.as_deref())?;
                 deleted_dups += 1;
             }
             DestResult::Path(dest) => {
+                let hash = db::get_file(conn, src)?.map(|f| f.hash);
                 file_system::move_file(src, &dest)?;
                 on_event(SortEvent::Moved(src, &dest));
                 db::move_file(conn, src, &dest)?;
+                db::log_action(conn, "move", src, Some(&dest), hash.as_deref())?;
                 moved += 1;
             }
         }
@@ -113,7 +118,12 @@ enum DestResult {
 
 /// Return the destination path for `src` inside `dest_dir`, handling collisions.
 /// If an identical file (same hash) already exists there, return TrueDuplicate.
-fn resolve_dest(src: &Path, dest_dir: &Path, conn: &Connection) -> Result<DestResult> {
+fn resolve_dest(
+    src: &Path,
+    dest_dir: &Path,
+    conn: &Connection,
+    hash_opts: &hashing::HashOptions,
+) -> Result<DestResult> {
     let fname = src.file_name().and_then(|n| n.to_str()).unwrap_or("file");
 
     let stem = Path::new(fname)
@@ -136,9 +146,9 @@ fn resolve_dest(src: &Path, dest_dir: &Path, conn: &Connection) -> Result<DestRe
         }
 
         // File exists at destination — compare hashes
-        let src_hash = hashing::compute_file_hash(src)
+        let src_hash = hashing::compute_file_hash(src, hash_opts)
             .with_context(|| format!("hashing {}", src.display()))?;
-        let dest_hash = hashing::compute_file_hash(&candidate)
+        let dest_hash = hashing::compute_file_hash(&candidate, hash_opts)
             .with_context(|| format!("hashing {}", candidate.display()))?;
 
         if src_hash == dest_hash {
@@ -338,7 +348,14 @@ mod tests {
             .set_modified(mtime)
             .unwrap_or(()); // best-effort; fallback if unsupported
 
-        sort_root(&conn, root, root, &mut |_| ()).unwrap();
+        sort_root(
+            &conn,
+            root,
+            root,
+            &hashing::HashOptions::default(),
+            &mut |_| (),
+        )
+        .unwrap();
 
         // File should have moved somewhere under root/YYYY/...
         // We don't assert the exact date since set_modified may not work everywhere,
@@ -388,7 +405,14 @@ mod tests {
         fs::create_dir_all(&target_dir).unwrap();
         fs::rename(&tmp, &target).unwrap();
 
-        sort_root(&conn, root, root, &mut |_| ()).unwrap();
+        sort_root(
+            &conn,
+            root,
+            root,
+            &hashing::HashOptions::default(),
+            &mut |_| (),
+        )
+        .unwrap();
 
         // File should still be in place — already sorted
         assert!(target.exists(), "already-sorted file should not move");
@@ -425,7 +449,14 @@ mod tests {
         // Pre-place the identical file at the destination
         write_file(&dest, content);
 
-        sort_root(&conn, root, root, &mut |_| ()).unwrap();
+        sort_root(
+            &conn,
+            root,
+            root,
+            &hashing::HashOptions::default(),
+            &mut |_| (),
+        )
+        .unwrap();
 
         // Source should be deleted (true duplicate)
         assert!(!src.exists(), "true duplicate source should be removed");
@@ -461,7 +492,14 @@ mod tests {
         // Pre-place a DIFFERENT file with the same name
         write_file(&dest_dir.join("photo.jpg"), b"version B");
 
-        sort_root(&conn, root, root, &mut |_| ()).unwrap();
+        sort_root(
+            &conn,
+            root,
+            root,
+            &hashing::HashOptions::default(),
+            &mut |_| (),
+        )
+        .unwrap();
 
         // Source should be gone from original location
         assert!(!src.exists(), "source should have moved");
@@ -483,7 +521,14 @@ mod tests {
         let txt = root.join("notes.txt");
         write_file(&txt, b"some notes");
 
-        sort_root(&conn, root, root, &mut |_| ()).unwrap();
+        sort_root(
+            &conn,
+            root,
+            root,
+            &hashing::HashOptions::default(),
+            &mut |_| (),
+        )
+        .unwrap();
 
         assert!(txt.exists(), "non-media files should not be moved");
     }
@@ -499,7 +544,14 @@ mod tests {
         let src = album.join("photo.jpg");
         write_file(&src, b"vacation photo");
 
-        sort_root(&conn, root, root, &mut |_| ()).unwrap();
+        sort_root(
+            &conn,
+            root,
+            root,
+            &hashing::HashOptions::default(),
+            &mut |_| (),
+        )
+        .unwrap();
 
         // The photo moved out, so summer_vacation should be gone
         assert!(
@@ -520,7 +572,14 @@ mod tests {
         write_file(&photo, b"photo");
         write_file(&notes, b"notes");
 
-        sort_root(&conn, root, root, &mut |_| ()).unwrap();
+        sort_root(
+            &conn,
+            root,
+            root,
+            &hashing::HashOptions::default(),
+            &mut |_| (),
+        )
+        .unwrap();
 
         // Photo moved, but notes.txt keeps the dir non-empty
         assert!(
@@ -542,7 +601,14 @@ mod tests {
         let src = src_root.join("photo.jpg");
         write_file(&src, b"canon test photo");
 
-        sort_root(&conn, &src_root, &canon_root, &mut |_| ()).unwrap();
+        sort_root(
+            &conn,
+            &src_root,
+            &canon_root,
+            &hashing::HashOptions::default(),
+            &mut |_| (),
+        )
+        .unwrap();
 
         // File should have moved into canon_root, not src_root
         let moved: Vec<_> = WalkDir::new(&canon_root)
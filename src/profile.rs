@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::config;
+use crate::hashing::HashAlgorithm;
+
+/// Defaults for the command line, from the top of the configuration file or
+/// one of its profiles. Options given on the command line win over them.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    #[serde(deserialize_with = "config::path")]
+    pub database: Option<PathBuf>,
+    /// The directories scanned when a command is given none
+    #[serde(deserialize_with = "config::paths")]
+    pub roots: Vec<PathBuf>,
+    pub exclude: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude_regex: Vec<String>,
+    pub ext: Vec<String>,
+    /// Files smaller than this many bytes are left out of scans.
+    pub min_file_size: Option<u64>,
+    #[serde(deserialize_with = "config::hash_algorithm")]
+    pub hash: Option<HashAlgorithm>,
+    pub threads: Option<usize>,
+    pub skip_hidden: bool,
+    pub gitignore: bool,
+    /// Commands for `--on-duplicate-group` and `--post-scan`
+    pub on_duplicate_group: Option<String>,
+    pub post_scan: Option<String>,
+}
+
+/// The user's configuration file: defaults at the top, and named profiles
+/// below them, each a `[profile.NAME]` table for a recurring scan.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UserConfig {
+    pub defaults: Settings,
+    pub profiles: BTreeMap<String, Settings>,
+}
+
+/// The file as written: `[profile.NAME]` tables, and the defaults beside
+/// them, which are read once the profiles are taken out.
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    profile: BTreeMap<String, Settings>,
+    #[serde(flatten)]
+    defaults: toml::Table,
+}
+
+impl UserConfig {
+    /// Where the configuration file is looked for: `DEDUPLIFIER_CONFIG`
+    /// when it is set, otherwise `config.toml` in `$XDG_CONFIG_HOME/deduplifier`
+    /// or `~/.config/deduplifier`.
+    pub fn default_path() -> Option<PathBuf> {
+        let var = |name: &str| std::env::var_os(name).filter(|v| !v.is_empty());
+        if let Some(path) = var("DEDUPLIFIER_CONFIG") {
+            return Some(PathBuf::from(path));
+        }
+        let base = match var("XDG_CONFIG_HOME") {
+            Some(dir) => PathBuf::from(dir),
+            None => config::home_dir()?.join(".config"),
+        };
+        Some(base.join("deduplifier").join("config.toml"))
+    }
+
+    /// Read and check the configuration at `path`:
+    ///
+    /// ```toml
+    /// exclude = [".DS_Store", "@eaDir"]
+    /// min_file_size = 1024
+    ///
+    /// [profile.photos]
+    /// database = "~/photos.db"
+    /// roots = ["/volume1/photos", "~/Pictures"]
+    /// ext = ["jpg", "heic", "cr2"]
+    /// ```
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = config::load(path)?;
+        Self::from_file(file).with_context(|| format!("invalid configuration {}", path.display()))
+    }
+
+    fn from_file(file: ConfigFile) -> Result<Self> {
+        let defaults = toml::Value::Table(file.defaults)
+            .try_into()
+            .context("at the top of the file")?;
+        Ok(Self {
+            defaults,
+            profiles: file.profile,
+        })
+    }
+
+    /// The settings a run with `profile` (or none) gets. A profile's values
+    /// replace the defaults, except its lists of patterns, which are added to
+    /// them, and `skip_hidden` and `gitignore`, which either can turn on.
+    pub fn settings(&self, profile: Option<&str>) -> Result<Settings> {
+        let Some(name) = profile else {
+            return Ok(self.defaults.clone());
+        };
+        let Some(profile) = self.profiles.get(name) else {
+            let known: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            if known.is_empty() {
+                bail!("no profile `{name}`; the configuration has none");
+            }
+            bail!("no profile `{name}`; there are {}", known.join(", "));
+        };
+        let base = &self.defaults;
+        let joined = |a: &[String], b: &[String]| [a, b].concat();
+        Ok(Settings {
+            database: profile.database.clone().or_else(|| base.database.clone()),
+            roots: if profile.roots.is_empty() {
+                base.roots.clone()
+            } else {
+                profile.roots.clone()
+            },
+            exclude: joined(&base.exclude, &profile.exclude),
+            include: joined(&base.include, &profile.include),
+            exclude_regex: joined(&base.exclude_regex, &profile.exclude_regex),
+            ext: joined(&base.ext, &profile.ext),
+            min_file_size: profile.min_file_size.or(base.min_file_size),
+            hash: profile.hash.or(base.hash),
+            threads: profile.threads.or(base.threads),
+            skip_hidden: base.skip_hidden || profile.skip_hidden,
+            gitignore: base.gitignore || profile.gitignore,
+            on_duplicate_group: profile
+                .on_duplicate_group
+                .clone()
+                .or_else(|| base.on_duplicate_group.clone()),
+            post_scan: profile.post_scan.clone().or_else(|| base.post_scan.clone()),
+        })
+    }
+}
+
+/// The settings for this run: those of `profile`, or the defaults, from the
+/// file at `UserConfig::default_path`. Without a file there are no
+/// defaults, and no profiles to pick.
+pub fn load_settings(profile: Option<&str>) -> Result<Settings> {
+    let path = UserConfig::default_path().filter(|path| path.exists());
+    match (path, profile) {
+        (Some(path), profile) => UserConfig::load(&path)?.settings(profile),
+        (None, Some(name)) => match UserConfig::default_path() {
+            Some(path) => bail!("no profile `{name}`: {} does not exist", path.display()),
+            None => bail!("no profile `{name}`: there is no home directory to look in"),
+        },
+        (None, None) => Ok(Settings::default()),
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_config(text: &str) -> Result<UserConfig> {
+        UserConfig::from_file(config::parse(text)?)
+    }
+
+    const TEXT: &str = r#"
+database = "defaults.db"
+exclude = ["@eaDir"]
+min_file_size = 1024
+hash = "sha256"
+post_scan = "notify-send scanned"
+
+[profile.photos]
+database = "/data/photos.db"
+roots = ["/volume1/photos", "/volume1/camera"]
+exclude = ["thumbs/"]
+ext = ["jpg", "heic"]
+skip_hidden = true
+on_duplicate_group = "tag-group photos"
+
+[profile.music]
+roots = ["/volume1/music"]
+min_file_size = 0
+hash = "xxh3"
+"#;
+
+    #[test]
+    fn test_profiles_replace_defaults_and_add_to_their_patterns() {
+        let config = user_config(TEXT).unwrap();
+        assert_eq!(
+            config.profiles.keys().collect::<Vec<_>>(),
+            ["music", "photos"]
+        );
+
+        let defaults = config.settings(None).unwrap();
+        assert_eq!(defaults.database, Some(PathBuf::from("defaults.db")));
+        assert!(defaults.roots.is_empty());
+        assert_eq!(defaults.min_file_size, Some(1024));
+
+        let photos = config.settings(Some("photos")).unwrap();
+        assert_eq!(photos.database, Some(PathBuf::from("/data/photos.db")));
+        assert_eq!(
+            photos.roots,
+            [
+                PathBuf::from("/volume1/photos"),
+                PathBuf::from("/volume1/camera")
+            ]
+        );
+        assert_eq!(photos.exclude, ["@eaDir", "thumbs/"]);
+        assert_eq!(photos.ext, ["jpg", "heic"]);
+        assert_eq!(photos.min_file_size, Some(1024));
+        assert_eq!(photos.hash, Some(HashAlgorithm::Sha256));
+        assert!(photos.skip_hidden);
+        assert_eq!(
+            photos.on_duplicate_group.as_deref(),
+            Some("tag-group photos")
+        );
+        assert_eq!(photos.post_scan.as_deref(), Some("notify-send scanned"));
+
+        let music = config.settings(Some("music")).unwrap();
+        assert_eq!(music.database, Some(PathBuf::from("defaults.db")));
+        assert_eq!(music.min_file_size, Some(0));
+        assert_eq!(music.hash, Some(HashAlgorithm::Xxh3));
+        assert!(!music.skip_hidden);
+
+        assert_eq!(
+            config.settings(Some("videos")).unwrap_err().to_string(),
+            "no profile `videos`; there are music, photos"
+        );
+    }
+
+    #[test]
+    fn test_profiles_can_be_written_any_way_toml_allows() {
+        let config = user_config(
+            r#"
+profile.music = { roots = ["/volume1/music"], hash = "xxh3" }
+profile."a=b".exclude = ["*.tmp"]
+post_scan = """
+notify-send \
+    scanned"""
+
+[profile."photos = 2"]
+ext = ["jpg"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.profiles.keys().collect::<Vec<_>>(),
+            ["a=b", "music", "photos = 2"]
+        );
+        assert_eq!(
+            config.defaults.post_scan.as_deref(),
+            Some("notify-send scanned")
+        );
+        let music = config.settings(Some("music")).unwrap();
+        assert_eq!(music.roots, [PathBuf::from("/volume1/music")]);
+        assert_eq!(music.hash, Some(HashAlgorithm::Xxh3));
+        assert_eq!(config.settings(Some("a=b")).unwrap().exclude, ["*.tmp"]);
+    }
+
+    #[test]
+    fn test_bad_settings_are_refused() {
+        let error = |text: &str| format!("{:#}", user_config(text).unwrap_err());
+        let malformed = error("exclude = [\"@eaDir\"\n\n[profile.photos");
+        assert!(malformed.contains("line 3"), "{malformed}");
+        let twice = error("[profile.photos]\next = [\"jpg\"]\n[profile.photos]");
+        assert!(twice.contains("line 3"), "{twice}");
+        let unknown = error("min_size = 10");
+        assert!(unknown.starts_with("at the top of the file: "), "{unknown}");
+        assert!(unknown.contains("unknown field `min_size`"), "{unknown}");
+        let misspelt = error("[profile.photos]\nrots = [\"/p\"]");
+        assert!(misspelt.contains("line 2"), "{misspelt}");
+        assert!(misspelt.contains("unknown field `rots`"), "{misspelt}");
+        assert!(error("hash = \"md5\"").contains("`md5` is not a hash algorithm"));
+        assert!(error("[profile.photos]\nmin_file_size = -1").contains("integer `-1`"));
+        assert!(error("roots = \"/photos\"").contains("expected a sequence"));
+    }
+
+    #[test]
+    fn test_load_names_the_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("config.toml");
+        std::fs::write(&path, "threads = \"four\"").unwrap();
+        let error = format!("{:#}", UserConfig::load(&path).unwrap_err());
+        assert!(
+            error.starts_with(&format!("invalid configuration {}", path.display())),
+            "{error}"
+        );
+        std::fs::write(&path, "threads = [").unwrap();
+        let error = format!("{:#}", UserConfig::load(&path).unwrap_err());
+        assert!(
+            error.starts_with(&format!("could not read {}", path.display())),
+            "{error}"
+        );
+    }
+}
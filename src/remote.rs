@@ -0,0 +1,671 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use crate::scan::{self, FileEntry, ScanError, ScanOptions, ScanResult};
+use crate::{db, file_system, hashing, utils};
+
+/// Files written per transaction while a remote listing is recorded.
+const TRANSACTION_BATCH_SIZE: usize = 1000;
+
+/// A root on another machine, `ssh://[user@]host[:port]/path`. Its files are
+/// stored under the root as written, `ssh://nas/volume1/photos/a.jpg`, so
+/// they show up in reports next to local ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteRoot {
+    /// `[user@]host[:port]`, as written in the root
+    authority: String,
+    pub user: Option<String>,
+    pub host: String,
+    pub port: Option<u16>,
+    /// The directory on the remote machine, without a trailing `/`
+    pub path: String,
+}
+
+/// How `ssh://` roots are reached.
+#[derive(Debug, Clone, Default)]
+pub struct RemoteOptions {
+    /// The command that starts deduplifier on the remote machine, as its
+    /// shell reads it (`deduplifier`, `~/bin/deduplifier`)
+    pub helper: String,
+    /// Options handed to the helper, so it walks and hashes the way the local
+    /// scan would (see `ScanArgs::helper_args` in the command)
+    pub args: Vec<String>,
+}
+
+impl RemoteRoot {
+    /// The remote root `root` names, or `None` when it is a local path.
+    pub fn parse(root: &Path) -> Result<Option<Self>> {
+        let Some(rest) = root.to_str().and_then(|s| s.strip_prefix("ssh://")) else {
+            return Ok(None);
+        };
+        let Some(slash) = rest.find('/') else {
+            bail!(
+                "{} names no directory on the remote machine",
+                root.display()
+            );
+        };
+        let (authority, path) = rest.split_at(slash);
+        let (user, address) = match authority.rsplit_once('@') {
+            Some((user, address)) => (Some(user.to_string()), address),
+            None => (None, authority),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .with_context(|| format!("invalid port `{port}` in {}", root.display()))?;
+                (host, Some(port))
+            }
+            None => (address, None),
+        };
+        if host.is_empty() {
+            bail!("{} names no host", root.display());
+        }
+        let trimmed = path.trim_end_matches('/');
+        Ok(Some(Self {
+            authority: authority.to_string(),
+            user,
+            host: host.to_string(),
+            port,
+            path: if trimmed.is_empty() { "/" } else { trimmed }.to_string(),
+        }))
+    }
+
+    /// Where the remote file or directory at `remote` is stored in the database.
+    fn db_path(&self, remote: &str) -> PathBuf {
+        PathBuf::from(format!("ssh://{}{}", self.authority, remote))
+    }
+
+    /// The machine the root is on, `ssh://[user@]host[:port]`, as its rows
+    /// record it (see `db::tag_remote`).
+    fn machine(&self) -> String {
+        format!("ssh://{}", self.authority)
+    }
+
+    /// The `ssh` command that starts the helper on the remote machine.
+    fn command(&self, opts: &RemoteOptions) -> Command {
+        let mut remote = opts.helper.clone();
+        remote.push_str(" remote-helper");
+        let words = opts.args.iter().map(String::as_str);
+        for word in words.chain(["--", self.path.as_str()]) {
+            remote.push(' ');
+            remote.push_str(&shell_quote(word));
+        }
+        let mut command = Command::new("ssh");
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        let destination = match &self.user {
+            Some(user) => format!("{user}@{}", self.host),
+            None => self.host.clone(),
+        };
+        command.arg("--").arg(destination).arg(remote);
+        command
+    }
+}
+
+impl fmt::Display for RemoteRoot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ssh://{}{}", self.authority, self.path)
+    }
+}
+
+/// `arg` as one word for a POSIX shell.
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+/// One line of JSON between the helper and the scan that drives it. The
+/// helper lists every directory and file under the root, then `Listed`; the
+/// scan answers with the paths it needs hashed, one JSON string a line, and
+/// closes the helper's input; the helper then sends a `Hashed` (or an
+/// `Error`) for each.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    Dir {
+        path: String,
+    },
+    File {
+        path: String,
+        size: u64,
+        modified: i64,
+    },
+    Error {
+        path: String,
+        kind: String,
+        message: String,
+    },
+    Listed,
+    Hashed {
+        path: String,
+        hash: String,
+    },
+}
+
+impl Message {
+    fn error(error: ScanError) -> Self {
+        Self::Error {
+            path: error.path,
+            kind: error.kind.to_string(),
+            message: error.message,
+        }
+    }
+}
+
+fn send(output: &mut impl Write, message: &Message) -> Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    output.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// The `remote-helper` side: list `root` the way a local scan walks it,
+/// then hash the files asked for on `input`, on `opts.threads` workers.
+pub fn serve(
+    root: &Path,
+    opts: &ScanOptions,
+    input: impl BufRead,
+    mut output: impl Write,
+) -> Result<()> {
+    for entry in file_system::walk_tree(root, &opts.walk) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                send(&mut output, &Message::error(ScanError::from_walk(root, &e)))?;
+                continue;
+            }
+        };
+        let path = entry.path();
+        let message = if path.is_dir() {
+            Message::Dir {
+                path: utils::path_to_db(path).into_owned(),
+            }
+        } else if path.is_file() {
+            match fs::metadata(path).and_then(|m| m.modified().map(|t| (m, t))) {
+                Ok((metadata, modified)) => Message::File {
+                    path: utils::path_to_db(path).into_owned(),
+                    size: metadata.len(),
+                    modified: modified.duration_since(UNIX_EPOCH)?.as_secs() as i64,
+                },
+                Err(e) => Message::error(ScanError::io(path, &e)),
+            }
+        } else {
+            continue;
+        };
+        send(&mut output, &message)?;
+    }
+    send(&mut output, &Message::Listed)?;
+    output.flush()?;
+
+    let mut wanted = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        if !line.is_empty() {
+            let path: String = serde_json::from_str(&line)?;
+            wanted.push(path);
+        }
+    }
+    scan::hash_in_parallel(
+        wanted,
+        opts,
+        |path| hashing::compute_file_hash(&utils::path_from_db(path), &opts.hash),
+        |path, result| {
+            let message = match result {
+                Ok(hash) => Message::Hashed { path, hash },
+                Err(e) => Message::error(ScanError::hashing(&utils::path_from_db(&path), &e)),
+            };
+            send(&mut output, &message)
+        },
+    )?;
+    output.flush()?;
+    Ok(())
+}
+
+/// Scan `remote` by running the helper there over `ssh`, and record what
+/// it finds as `scan::scan_directory` would: new and changed files hashed
+/// where they are, unchanged ones kept, and every directory's hash worked
+/// out from them. Nothing is copied over but the listing and the hashes.
+///
+/// Moved files aren't looked for, and `--prefilter`, `--xattr-cache` and
+/// `--scan-archives` don't apply; every directory is rehashed each time.
+pub fn scan_remote(
+    conn: &Connection,
+    remote: &RemoteRoot,
+    opts: &ScanOptions,
+    on_progress: impl Fn(usize, usize, &str),
+) -> Result<ScanResult> {
+    let mut child = remote
+        .command(&opts.remote)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("could not run ssh")?;
+    let (Some(input), Some(output)) = (child.stdin.take(), child.stdout.take()) else {
+        bail!("could not talk to ssh");
+    };
+    let result = exchange(
+        conn,
+        remote,
+        opts,
+        BufReader::new(output),
+        input,
+        on_progress,
+    );
+    if result.as_ref().map_or(true, |r| r.interrupted) {
+        let _ = child.kill();
+    }
+    let status = child.wait()?;
+    let result = result.with_context(|| format!("scanning {remote}"))?;
+    if !result.interrupted && !status.success() {
+        bail!("the helper on {} failed ({status})", remote.host);
+    }
+    Ok(result)
+}
+
+/// A remote file whose hash has been asked for.
+struct Pending {
+    path: PathBuf,
+    path_str: String,
+    size: u64,
+    modified: i64,
+}
+
+/// Drive the helper at the other end of `from_helper` and `to_helper`.
+fn exchange(
+    conn: &Connection,
+    remote: &RemoteRoot,
+    opts: &ScanOptions,
+    from_helper: impl BufRead,
+    mut to_helper: impl Write,
+    on_progress: impl Fn(usize, usize, &str),
+) -> Result<ScanResult> {
+    let _scan = tracing::info_span!("scan", root = %remote).entered();
+    db::init_visited_files(conn)?;
+    let root = remote.db_path(&remote.path);
+    let root_str = utils::path_to_db(&root).into_owned();
+    let machine = remote.machine();
+    let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let db_error = |path: &str, kind: &str, message: String| ScanError {
+        path: utils::path_to_db(&remote.db_path(path)).into_owned(),
+        kind: error_kind(kind),
+        message,
+    };
+
+    let mut directories = Vec::new();
+    let mut errors = Vec::new();
+    let mut files_by_dir: HashMap<PathBuf, Vec<FileEntry>> = HashMap::new();
+    let mut pending: HashMap<String, Pending> = HashMap::new();
+    let mut unchanged = 0usize;
+    let mut lines = from_helper.lines();
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+
+    let walking = tracing::info_span!("walk", root = %remote).entered();
+    let mut listed = false;
+    for line in lines.by_ref() {
+        match serde_json::from_str(&line?)? {
+            Message::Dir { path } => directories.push(remote.db_path(&path)),
+            Message::File {
+                path,
+                size,
+                modified,
+            } => {
+                let local = remote.db_path(&path);
+                let path_str = utils::path_to_db(&local).into_owned();
+                db::mark_visited(conn, &path_str)?;
+                batch.tick()?;
+                let record = db::get_file(conn, &local)?.filter(|r| {
+                    r.modified == modified
+                        && r.size == size as i64
+                        && !hashing::is_provisional(&r.hash)
+                });
+                match (record, local.parent()) {
+                    (Some(record), Some(parent)) => {
+                        unchanged += 1;
+                        files_by_dir
+                            .entry(parent.to_path_buf())
+                            .or_default()
+                            .push(FileEntry {
+                                path: path_str,
+                                hash: record.hash,
+                                size,
+                            });
+                    }
+                    _ => {
+                        let job = Pending {
+                            path: local,
+                            path_str,
+                            size,
+                            modified,
+                        };
+                        pending.insert(path, job);
+                    }
+                }
+            }
+            Message::Error {
+                path,
+                kind,
+                message,
+            } => errors.push(db_error(&path, &kind, message)),
+            Message::Listed => {
+                listed = true;
+                break;
+            }
+            Message::Hashed { .. } => bail!("the helper sent a hash before listing the files"),
+        }
+        if opts.cancelled() {
+            break;
+        }
+    }
+    drop(walking);
+    if !listed && !opts.cancelled() {
+        bail!(
+            "the helper on {} stopped before listing {} (is `{}` installed there? see --remote-helper)",
+            remote.host,
+            remote.path,
+            opts.remote.helper
+        );
+    }
+    tracing::debug!(
+        unchanged = unchanged,
+        to_hash = pending.len(),
+        "walked {remote}"
+    );
+
+    let total = unchanged + pending.len();
+    let mut processed = unchanged;
+    on_progress(processed, total, "");
+    let hashing = tracing::info_span!("hash files", root = %remote).entered();
+    if listed {
+        for path in pending.keys() {
+            to_helper.write_all(serde_json::to_string(path)?.as_bytes())?;
+            to_helper.write_all(b"\n")?;
+        }
+        to_helper.flush()?;
+    }
+    // Closing its input starts the helper hashing
+    drop(to_helper);
+    for line in lines {
+        if !listed || opts.cancelled() {
+            break;
+        }
+        let (path, hash) = match serde_json::from_str(&line?)? {
+            Message::Hashed { path, hash } => (path, Ok(hash)),
+            Message::Error {
+                path,
+                kind,
+                message,
+            } => (path.clone(), Err(db_error(&path, &kind, message))),
+            _ => bail!("the helper sent a listing after the hashes"),
+        };
+        let Some(job) = pending.remove(&path) else {
+            bail!("the helper hashed {path}, which wasn't asked for");
+        };
+        processed += 1;
+        on_progress(processed, total, &scan::file_name(&job.path));
+        batch.tick()?;
+        let hash = match hash {
+            Ok(hash) => hash,
+            Err(error) => {
+                errors.push(error);
+                continue;
+            }
+        };
+        hashing::count_hashed(job.size as usize);
+        let (size, modified) = (job.size as i64, job.modified);
+        db::upsert_remote_file(conn, &job.path, &machine, &hash, size, modified)?;
+        opts.hashed(&job.path, job.size, &hash);
+        if let Some(parent) = job.path.parent() {
+            files_by_dir
+                .entry(parent.to_path_buf())
+                .or_default()
+                .push(FileEntry {
+                    path: job.path_str,
+                    hash,
+                    size: job.size,
+                });
+        }
+    }
+    drop(hashing);
+    batch.commit()?;
+
+    db::clear_scan_errors(conn, &root_str)?;
+    for error in &errors {
+        db::log_scan_error(
+            conn,
+            started,
+            &root_str,
+            &error.path,
+            error.kind,
+            &error.message,
+        )?;
+    }
+    if !listed || opts.cancelled() {
+        return Ok(ScanResult {
+            errors,
+            stale_count: 0,
+            root_str,
+            interrupted: true,
+            moved: 0,
+        });
+    }
+    if !pending.is_empty() {
+        bail!(
+            "the helper on {} stopped with {} file(s) still to hash",
+            remote.host,
+            pending.len()
+        );
+    }
+    let stale_count = db::stale_file_paths(conn, &root_str)?.len() as i64;
+
+    let unreadable: HashSet<&str> = errors.iter().map(|e| e.path.as_str()).collect();
+    let _directories = tracing::info_span!("hash directories", root = %remote).entered();
+    scan::compute_directory_hashes(
+        conn,
+        &root,
+        &directories,
+        &files_by_dir,
+        &unreadable,
+        None,
+        opts.hash.algorithm,
+    )?;
+    db::tag_remote(conn, &root_str, &machine)?;
+    tracing::info!(
+        errors = errors.len(),
+        stale = stale_count,
+        "scanned {remote}"
+    );
+    Ok(ScanResult {
+        errors,
+        stale_count,
+        root_str,
+        interrupted: false,
+        moved: 0,
+    })
+}
+
+/// The `ScanError` kind the helper named.
+fn error_kind(name: &str) -> &'static str {
+    match name {
+        "permission-denied" => "permission-denied",
+        "not-found" => "not-found",
+        "hash" => "hash",
+        _ => "io",
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clean, dedupe, verify};
+    use std::thread;
+
+    fn root(text: &str) -> Result<Option<RemoteRoot>> {
+        RemoteRoot::parse(Path::new(text))
+    }
+
+    #[test]
+    fn test_parse_splits_user_host_port_and_path() {
+        let remote = root("ssh://admin@nas:2222/volume1/photos/")
+            .unwrap()
+            .unwrap();
+        assert_eq!(remote.user.as_deref(), Some("admin"));
+        assert_eq!(remote.host, "nas");
+        assert_eq!(remote.port, Some(2222));
+        assert_eq!(remote.path, "/volume1/photos");
+        assert_eq!(remote.to_string(), "ssh://admin@nas:2222/volume1/photos");
+        assert_eq!(
+            remote.db_path("/volume1/photos/a.jpg"),
+            Path::new("ssh://admin@nas:2222/volume1/photos/a.jpg")
+        );
+
+        let remote = root("ssh://nas/").unwrap().unwrap();
+        assert_eq!((remote.user, remote.port), (None, None));
+        assert_eq!(remote.path, "/");
+        assert_eq!(root("/volume1/photos").unwrap(), None);
+
+        let error = |text: &str| root(text).unwrap_err().to_string();
+        assert_eq!(
+            error("ssh://nas"),
+            "ssh://nas names no directory on the remote machine"
+        );
+        assert_eq!(error("ssh://me@/photos"), "ssh://me@/photos names no host");
+        assert_eq!(
+            error("ssh://nas:ssh/p"),
+            "invalid port `ssh` in ssh://nas:ssh/p"
+        );
+    }
+
+    #[test]
+    fn test_command_quotes_what_the_remote_shell_reads() {
+        let remote = root("ssh://me@nas:2222/volume1/my photos")
+            .unwrap()
+            .unwrap();
+        let opts = RemoteOptions {
+            helper: "~/bin/deduplifier".to_string(),
+            args: vec!["--exclude".to_string(), "*.tmp".to_string()],
+        };
+        let command = remote.command(&opts);
+        assert_eq!(command.get_program(), "ssh");
+        let args: Vec<_> = command.get_args().map(|a| a.to_str().unwrap()).collect();
+        assert_eq!(
+            args,
+            [
+                "-p",
+                "2222",
+                "--",
+                "me@nas",
+                "~/bin/deduplifier remote-helper --exclude '*.tmp' -- '/volume1/my photos'"
+            ]
+        );
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(shell_quote(""), "''");
+    }
+
+    /// Scan `dir` as if it were on `nas`, with the helper in a thread.
+    fn scan_through_helper(conn: &Connection, dir: &Path) -> ScanResult {
+        let text = format!("ssh://nas{}", dir.display());
+        let remote = root(&text).unwrap().unwrap();
+        let opts = ScanOptions::default();
+        let (from_helper, helper_out) = std::io::pipe().unwrap();
+        let (helper_in, to_helper) = std::io::pipe().unwrap();
+        thread::scope(|s| {
+            let helper_opts = opts.clone();
+            s.spawn(move || serve(dir, &helper_opts, BufReader::new(helper_in), helper_out));
+            exchange(
+                conn,
+                &remote,
+                &opts,
+                BufReader::new(from_helper),
+                to_helper,
+                |_, _, _| {},
+            )
+            .unwrap()
+        })
+    }
+
+    #[test]
+    fn test_remote_scan_matches_a_local_one() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        fs::write(dir.path().join("sub/b.txt"), "world").unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+
+        let result = scan_through_helper(&conn, dir.path());
+        assert!(!result.interrupted && result.errors.is_empty());
+        let remote_file = format!("ssh://nas{}", dir.path().join("a.txt").display());
+        let record = db::get_file(&conn, Path::new(&remote_file))
+            .unwrap()
+            .unwrap();
+        let opts = hashing::HashOptions::default();
+        let expected = hashing::compute_file_hash(&dir.path().join("a.txt"), &opts).unwrap();
+        assert_eq!(record.hash, expected);
+
+        // The remote tree hashes as the same tree scanned locally does
+        scan::scan_directory(&conn, dir.path(), 2, &ScanOptions::default(), |_, _, _| {}).unwrap();
+        let hash = |path: &str| {
+            db::get_directory(&conn, Path::new(path))
+                .unwrap()
+                .unwrap()
+                .hash
+        };
+        let local_root = dir.path().display().to_string();
+        assert_eq!(hash(&format!("ssh://nas{local_root}")), hash(&local_root));
+
+        fs::remove_file(dir.path().join("sub/b.txt")).unwrap();
+        let result = scan_through_helper(&conn, dir.path());
+        assert_eq!(result.stale_count, 1);
+        assert_eq!(result.root_str, format!("ssh://nas{local_root}"));
+    }
+
+    #[test]
+    fn test_remote_rows_are_left_to_the_remote_machine() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        fs::write(dir.path().join("b.txt"), "hello").unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        scan_through_helper(&conn, dir.path());
+        let remote_root = format!("ssh://nas{}", dir.path().display());
+        let remote_file = format!("{remote_root}/a.txt");
+        assert!(db::remote_paths(&conn).unwrap().contains(&remote_root));
+
+        // Nothing at `ssh://nas/...` is on this disk, which `clean` mustn't
+        // take for the files having gone
+        let stats = clean::prune_missing(&conn, hashing::HashAlgorithm::Sha256).unwrap();
+        assert_eq!((stats.files_removed, stats.dirs_removed), (0, 0));
+        assert!(db::get_file(&conn, Path::new(&remote_file))
+            .unwrap()
+            .is_some());
+        assert!(db::get_directory(&conn, Path::new(&remote_root))
+            .unwrap()
+            .is_some());
+
+        // Nor are the remote copies offered for removal, or verified
+        assert!(dedupe::groups_in_scope(&conn, &[]).unwrap().is_empty());
+        assert!(verify::selected_files(&conn, &[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,928 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::Connection;
+use serde::Serialize;
+
+use crate::{db, duplicates, manifest, utils};
+
+/// Output format of the `report` command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    /// Human-readable text (the default)
+    #[default]
+    Text,
+    /// One pretty-printed JSON document
+    Json,
+    /// A standalone HTML page
+    Html,
+}
+
+/// Output format of the `dup-files` command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DupFilesFormat {
+    /// Human-readable text (the default)
+    #[default]
+    Text,
+    /// One path per line, groups separated by blank lines, like fdupes
+    Fdupes,
+}
+
+/// Output format of the `export` command.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ExportFormat {
+    /// One CSV row per file with its duplicate group (the default)
+    #[default]
+    Csv,
+    /// A `sha256sum` checksum manifest
+    Sha256sum,
+    /// A `b3sum` checksum manifest
+    B3sum,
+    /// An `xxh128sum` checksum manifest
+    Xxh128sum,
+}
+
+impl ExportFormat {
+    /// The checksum manifest this format writes, if it is one.
+    pub fn manifest(self) -> Option<manifest::Format> {
+        match self {
+            Self::Csv => None,
+            Self::Sha256sum => Some(manifest::Format::Sha256sum),
+            Self::B3sum => Some(manifest::Format::B3sum),
+            Self::Xxh128sum => Some(manifest::Format::Xxh128sum),
+        }
+    }
+}
+
+/// Everything a report shows, gathered from the database in one go so every
+/// output format renders exactly the same groups.
+pub struct Report {
+    pub file_groups: Vec<duplicates::DuplicateFileGroup>,
+    /// File groups left out by `ReportFilter::top_level`, because a directory
+    /// group accounts for them. They still count towards `wasted` and
+    /// `redundant_copies`.
+    pub covered_file_groups: Vec<duplicates::DuplicateFileGroup>,
+    pub dir_groups: Vec<duplicates::DuplicateDirGroup>,
+    /// Files in the database, duplicate or not
+    pub total_files: i64,
+    /// Combined size of every file in the database
+    pub total_bytes: i64,
+    /// Where each file and directory is (see `locations`)
+    pub locations: HashMap<String, String>,
+}
+
+impl Report {
+    /// Bytes freed by keeping one copy of every duplicate file. Files inside
+    /// duplicate directories are duplicate files too, so this already covers
+    /// the directory groups without counting anything twice.
+    pub fn wasted(&self) -> i64 {
+        self.all_file_groups().map(|g| g.wasted()).sum()
+    }
+
+    /// Copies beyond the first in every duplicate file group.
+    pub fn redundant_copies(&self) -> i64 {
+        self.all_file_groups().map(|g| g.count - 1).sum()
+    }
+
+    fn all_file_groups(&self) -> impl Iterator<Item = &duplicates::DuplicateFileGroup> {
+        self.file_groups.iter().chain(&self.covered_file_groups)
+    }
+}
+
+/// Collect the duplicate file and directory groups that pass `filter`.
+/// `scope` limits the directory groups to members under those paths (all of
+/// them when empty); with `filter.top_level`, file groups they account for
+/// are set aside in `covered_file_groups`. Only the database is read.
+pub fn build(
+    conn: &Connection,
+    scope: &[&Path],
+    filter: duplicates::ReportFilter,
+) -> Result<Report> {
+    let _report = tracing::info_span!("report").entered();
+    let file_groups: Vec<_> = duplicates::find_duplicate_files(conn)?
+        .into_iter()
+        .filter(|g| filter.keeps_files(g))
+        .collect();
+    let hashes = db::duplicate_directory_groups(conn)?;
+    let (dir_groups, _) = duplicates::build_top_level_groups(conn, &hashes, scope)?;
+    let dir_groups: Vec<_> = dir_groups
+        .into_iter()
+        .filter(|g| filter.keeps_dirs(g))
+        .collect();
+    let (file_groups, covered_file_groups) = if filter.top_level {
+        duplicates::split_covered_file_groups(file_groups, &dir_groups)
+    } else {
+        (file_groups, Vec::new())
+    };
+    let (total_files, total_bytes) = db::file_totals(conn)?;
+    Ok(Report {
+        file_groups,
+        covered_file_groups,
+        dir_groups,
+        total_files,
+        total_bytes,
+        locations: locations(conn)?,
+    })
+}
+
+/// The host or volume name of every file and directory (`db::location_names`),
+/// but only once the database spans more than one: naming the same machine
+/// on every line says nothing.
+pub fn locations(conn: &Connection) -> Result<HashMap<String, String>> {
+    let names = db::location_names(conn)?;
+    let distinct: HashSet<&str> = names.values().map(String::as_str).collect();
+    if distinct.len() < 2 {
+        return Ok(HashMap::new());
+    }
+    Ok(names)
+}
+
+// ---------------------------------------------------------------------------
+// JSON
+// ---------------------------------------------------------------------------
+
+// The JSON document is built from these views rather than by serializing the
+// duplicates types directly, so the field names stay stable (and include the
+// derived sizes) whatever happens to the internal structs.
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    file_groups: Vec<JsonFileGroup<'a>>,
+    directory_groups: Vec<JsonDirGroup<'a>>,
+    summary: JsonSummary,
+}
+
+#[derive(Serialize)]
+struct JsonFileGroup<'a> {
+    hash: &'a str,
+    count: i64,
+    /// Size of one copy
+    size: i64,
+    total_size: i64,
+    wasted: i64,
+    files: Vec<JsonFile<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonFile<'a> {
+    path: &'a str,
+    size: i64,
+    hardlinks: &'a [String],
+    /// Reached through a symlink by the scan
+    via_link: bool,
+    /// The host or volume the file is on, when the database spans several
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonDirGroup<'a> {
+    hash: &'a str,
+    count: usize,
+    size: i64,
+    wasted: i64,
+    directories: Vec<JsonDir<'a>>,
+}
+
+#[derive(Serialize)]
+struct JsonDir<'a> {
+    path: &'a str,
+    size: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    location: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonSummary {
+    total_files: i64,
+    total_bytes: i64,
+    file_groups: usize,
+    redundant_copies: i64,
+    /// Bytes freed by keeping one copy per file group
+    wasted: i64,
+    directory_groups: usize,
+    /// File groups `--top-level` left out, as the directory groups cover them
+    covered_file_groups: usize,
+}
+
+fn json_file_group<'a>(
+    g: &'a duplicates::DuplicateFileGroup,
+    locations: &'a HashMap<String, String>,
+) -> JsonFileGroup<'a> {
+    JsonFileGroup {
+        hash: &g.hash,
+        count: g.count,
+        size: g.files.first().map_or(0, |f| f.size),
+        total_size: g.total_size,
+        wasted: g.wasted(),
+        files: g
+            .files
+            .iter()
+            .map(|f| JsonFile {
+                path: &f.path,
+                size: f.size,
+                hardlinks: &f.hardlinks,
+                via_link: f.via_link,
+                location: locations.get(&f.path).map(String::as_str),
+            })
+            .collect(),
+    }
+}
+
+fn json_dir_group<'a>(
+    g: &'a duplicates::DuplicateDirGroup,
+    locations: &'a HashMap<String, String>,
+) -> JsonDirGroup<'a> {
+    JsonDirGroup {
+        hash: &g.hash,
+        count: g.members.len(),
+        size: g.max_size,
+        wasted: g.wasted(),
+        directories: g
+            .members
+            .iter()
+            .map(|d| JsonDir {
+                path: &d.path,
+                size: d.size,
+                location: locations.get(&d.path).map(String::as_str),
+            })
+            .collect(),
+    }
+}
+
+/// One file group as the JSON report has it, for handing on elsewhere.
+pub fn file_group_to_value(
+    group: &duplicates::DuplicateFileGroup,
+    locations: &HashMap<String, String>,
+) -> serde_json::Value {
+    serde_json::to_value(json_file_group(group, locations)).unwrap_or_default()
+}
+
+/// One directory group as the JSON report has it.
+pub fn dir_group_to_value(
+    group: &duplicates::DuplicateDirGroup,
+    locations: &HashMap<String, String>,
+) -> serde_json::Value {
+    serde_json::to_value(json_dir_group(group, locations)).unwrap_or_default()
+}
+
+/// Render `report` as a pretty-printed JSON document.
+pub fn to_json(report: &Report) -> Result<String> {
+    let doc = JsonReport {
+        file_groups: report
+            .file_groups
+            .iter()
+            .map(|g| json_file_group(g, &report.locations))
+            .collect(),
+        directory_groups: report
+            .dir_groups
+            .iter()
+            .map(|g| json_dir_group(g, &report.locations))
+            .collect(),
+        summary: JsonSummary {
+            total_files: report.total_files,
+            total_bytes: report.total_bytes,
+            file_groups: report.file_groups.len(),
+            redundant_copies: report.redundant_copies(),
+            wasted: report.wasted(),
+            directory_groups: report.dir_groups.len(),
+            covered_file_groups: report.covered_file_groups.len(),
+        },
+    };
+    Ok(serde_json::to_string_pretty(&doc)?)
+}
+
+// ---------------------------------------------------------------------------
+// Unique files
+// ---------------------------------------------------------------------------
+
+/// The files whose content exists nowhere else in the database, ordered by
+/// path: everything outside the duplicate groups. Hardlinks to a file with no
+/// other copy are unique too, each listed under its own path. `scope` limits
+/// the result to files under those paths (all of them when empty).
+pub fn unique_files(conn: &Connection, scope: &[&Path]) -> Result<Vec<db::FileRecord>> {
+    let groups = duplicates::find_duplicate_files(conn)?;
+    let duplicated: HashSet<&str> = groups
+        .iter()
+        .flat_map(|g| &g.files)
+        .flat_map(|f| std::iter::once(&f.path).chain(&f.hardlinks))
+        .map(|p| p.as_str())
+        .collect();
+    let files = db::all_files(conn)?
+        .into_iter()
+        .filter(|f| !duplicated.contains(f.path.as_str()))
+        .filter(|f| {
+            scope.is_empty()
+                || scope
+                    .iter()
+                    .any(|s| utils::path_from_db(&f.path).starts_with(s))
+        })
+        .collect();
+    Ok(files)
+}
+
+#[derive(Serialize)]
+struct JsonUnique<'a> {
+    files: Vec<JsonUniqueFile<'a>>,
+    summary: JsonUniqueSummary,
+}
+
+#[derive(Serialize)]
+struct JsonUniqueFile<'a> {
+    path: &'a str,
+    hash: &'a str,
+    size: i64,
+}
+
+#[derive(Serialize)]
+struct JsonUniqueSummary {
+    files: usize,
+    bytes: i64,
+}
+
+/// Render the `unique_files` result as a pretty-printed JSON document.
+pub fn unique_to_json(files: &[db::FileRecord]) -> Result<String> {
+    let doc = JsonUnique {
+        files: files
+            .iter()
+            .map(|f| JsonUniqueFile {
+                path: &f.path,
+                hash: &f.hash,
+                size: f.size,
+            })
+            .collect(),
+        summary: JsonUniqueSummary {
+            files: files.len(),
+            bytes: files.iter().map(|f| f.size).sum(),
+        },
+    };
+    Ok(serde_json::to_string_pretty(&doc)?)
+}
+
+// ---------------------------------------------------------------------------
+// HTML
+// ---------------------------------------------------------------------------
+
+/// Bytes held in duplicate files per directory — every copy counts, since
+/// each is what that directory would give up — largest first.
+pub fn duplicate_bytes_by_dir(report: &Report) -> Vec<(String, i64)> {
+    let mut totals: HashMap<String, i64> = HashMap::new();
+    for file in report.file_groups.iter().flat_map(|g| &g.files) {
+        let parent = Path::new(&file.path)
+            .parent()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        *totals.entry(parent).or_default() += file.size;
+    }
+    let mut totals: Vec<(String, i64)> = totals.into_iter().collect();
+    totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    totals
+}
+
+/// Inline styles and the column-sorting script, so the page has no
+/// dependencies and can be mailed around as a single file.
+const HTML_HEAD: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<title>Deduplifier report</title>
+<style>
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 2em; width: 100%; }
+th, td { border: 1px solid #ccc; padding: 4px 8px; text-align: left; vertical-align: top; }
+th { background: #eee; cursor: pointer; user-select: none; }
+th:hover { background: #ddd; }
+td.num { text-align: right; white-space: nowrap; }
+code { font-size: 90%; }
+ul { margin: 0; padding-left: 1.2em; }
+</style>
+<script>
+// Click a header to sort by that column; click again to reverse.
+function sortTable(th) {
+  const table = th.closest("table");
+  const body = table.tBodies[0];
+  const col = Array.from(th.parentNode.children).indexOf(th);
+  const asc = th.dataset.dir !== "asc";
+  th.dataset.dir = asc ? "asc" : "desc";
+  const key = row => {
+    const cell = row.children[col];
+    return cell.dataset.sort !== undefined ? Number(cell.dataset.sort) : cell.textContent;
+  };
+  Array.from(body.rows)
+    .sort((a, b) => {
+      const x = key(a), y = key(b);
+      const order = typeof x === "number" ? x - y : x.localeCompare(y);
+      return asc ? order : -order;
+    })
+    .forEach(row => body.appendChild(row));
+}
+</script>
+</head>
+<body>
+"#;
+
+/// Render `report` as a standalone HTML page with sortable tables and
+/// `file://` links to every path.
+pub fn to_html(report: &Report) -> String {
+    let mut html = String::from(HTML_HEAD);
+    html.push_str("<h1>Deduplifier report</h1>\n");
+    html.push_str(&format!(
+        "<p>{} file(s), {} in total. {} duplicate file group(s) with {} redundant \
+         cop(ies): removing them would free <strong>{}</strong>. \
+         {} duplicate directory group(s).</p>\n",
+        report.total_files,
+        utils::fmt_size(report.total_bytes),
+        report.file_groups.len() + report.covered_file_groups.len(),
+        report.redundant_copies(),
+        utils::fmt_size(report.wasted()),
+        report.dir_groups.len()
+    ));
+
+    html.push_str("<h2>Duplicate files</h2>\n");
+    if !report.covered_file_groups.is_empty() {
+        html.push_str(&format!(
+            "<p>{} group(s) of files inside the duplicate directories are not listed.</p>\n",
+            report.covered_file_groups.len()
+        ));
+    }
+    html.push_str(&table_head(&["Hash", "Copies", "Size", "Wasted", "Paths"]));
+    for group in &report.file_groups {
+        let size = group.files.first().map_or(0, |f| f.size);
+        let mut paths = String::new();
+        for file in &group.files {
+            paths.push_str(&format!("<li>{}", path_link(&file.path)));
+            for link in &file.hardlinks {
+                paths.push_str(&format!("<br>hardlink: {}", path_link(link)));
+            }
+            paths.push_str("</li>");
+        }
+        html.push_str(&format!(
+            "<tr><td><code>{}</code></td>{}{}{}<td><ul>{}</ul></td></tr>\n",
+            escape(short_hash(&group.hash)),
+            num_cell(group.count, group.count.to_string()),
+            num_cell(size, utils::fmt_size(size)),
+            num_cell(group.wasted(), utils::fmt_size(group.wasted())),
+            paths
+        ));
+    }
+    html.push_str("</tbody></table>\n");
+
+    html.push_str("<h2>Duplicate directories</h2>\n");
+    html.push_str(&table_head(&[
+        "Hash",
+        "Copies",
+        "Size",
+        "Wasted",
+        "Directories",
+    ]));
+    for group in &report.dir_groups {
+        let dirs: String = group
+            .members
+            .iter()
+            .map(|d| format!("<li>{}</li>", path_link(&d.path)))
+            .collect();
+        let count = group.members.len() as i64;
+        html.push_str(&format!(
+            "<tr><td><code>{}</code></td>{}{}{}<td><ul>{}</ul></td></tr>\n",
+            escape(short_hash(&group.hash)),
+            num_cell(count, count.to_string()),
+            num_cell(group.max_size, utils::fmt_size(group.max_size)),
+            num_cell(group.wasted(), utils::fmt_size(group.wasted())),
+            dirs
+        ));
+    }
+    html.push_str("</tbody></table>\n");
+
+    html.push_str("<h2>Duplicate bytes by directory</h2>\n");
+    html.push_str(
+        "<p>Total size of the files in each directory that have a copy somewhere else.</p>\n",
+    );
+    html.push_str(&table_head(&["Directory", "Duplicate bytes"]));
+    for (dir, bytes) in duplicate_bytes_by_dir(report) {
+        html.push_str(&format!(
+            "<tr><td>{}</td>{}</tr>\n",
+            path_link(&dir),
+            num_cell(bytes, utils::fmt_size(bytes))
+        ));
+    }
+    html.push_str("</tbody></table>\n</body>\n</html>\n");
+    html
+}
+
+fn table_head(columns: &[&str]) -> String {
+    let headers: String = columns
+        .iter()
+        .map(|c| format!("<th onclick=\"sortTable(this)\">{}</th>", c))
+        .collect();
+    format!("<table><thead><tr>{}</tr></thead><tbody>\n", headers)
+}
+
+/// A right-aligned cell that sorts by `value` while showing `label`.
+fn num_cell(value: i64, label: String) -> String {
+    format!(
+        "<td class=\"num\" data-sort=\"{}\">{}</td>",
+        value,
+        escape(&label)
+    )
+}
+
+fn short_hash(hash: &str) -> &str {
+    hash.get(..16).unwrap_or(hash)
+}
+
+fn path_link(path: &str) -> String {
+    format!(
+        "<a href=\"{}\">{}</a>",
+        escape(&file_url(path)),
+        escape(path)
+    )
+}
+
+/// `file://` URL for an absolute path, percent-encoding everything but
+/// unreserved characters and separators. Windows paths (`C:\x`) become
+/// `file:///C:/x`.
+fn file_url(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    let mut url = String::from("file://");
+    if !path.starts_with('/') {
+        url.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                url.push(byte as char)
+            }
+            _ => url.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    url
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// ---------------------------------------------------------------------------
+// fdupes
+// ---------------------------------------------------------------------------
+
+/// Render duplicate file groups the way `fdupes -r` (and jdupes) print them:
+/// one path per line, each group followed by a blank line. `show_size` adds
+/// the `N bytes each:` header that `fdupes -S` puts above every group.
+/// Hardlinks are left out, as fdupes does without `-H`.
+pub fn to_fdupes(groups: &[duplicates::DuplicateFileGroup], show_size: bool) -> String {
+    let mut out = String::new();
+    for group in groups {
+        if show_size {
+            let size = group.files.first().map_or(0, |f| f.size);
+            let unit = if size == 1 { "byte" } else { "bytes" };
+            out.push_str(&format!("{} {} each:\n", size, unit));
+        }
+        for file in &group.files {
+            out.push_str(&file.path);
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// ---------------------------------------------------------------------------
+// CSV export
+// ---------------------------------------------------------------------------
+
+/// One row of the CSV export. `group` numbers the duplicate file groups in
+/// report order (largest first) and is empty for files with no duplicate;
+/// `hardlink_of` names the path a hardlink was folded into.
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    group: Option<usize>,
+    path: &'a str,
+    hash: &'a str,
+    size: i64,
+    modified: i64,
+    hardlink_of: Option<&'a str>,
+}
+
+/// Write every file in the database as CSV, duplicates first (by group, then
+/// path), then the rest by path. With `duplicates_only`, files without a
+/// duplicate are left out.
+pub fn write_csv(conn: &Connection, out: impl Write, duplicates_only: bool) -> Result<()> {
+    let groups = duplicates::find_duplicate_files(conn)?;
+    let mut membership: HashMap<&str, (usize, Option<&str>)> = HashMap::new();
+    for (index, group) in groups.iter().enumerate() {
+        for file in &group.files {
+            membership.insert(&file.path, (index + 1, None));
+            for link in &file.hardlinks {
+                membership.insert(link, (index + 1, Some(&file.path)));
+            }
+        }
+    }
+
+    let files = db::all_files(conn)?;
+    let mut rows: Vec<CsvRow> = files
+        .iter()
+        .filter_map(|f| {
+            let member = membership.get(f.path.as_str());
+            if duplicates_only && member.is_none() {
+                return None;
+            }
+            Some(CsvRow {
+                group: member.map(|m| m.0),
+                path: &f.path,
+                hash: &f.hash,
+                size: f.size,
+                modified: f.modified,
+                hardlink_of: member.and_then(|m| m.1),
+            })
+        })
+        .collect();
+    // all_files is ordered by path, so a stable sort keeps each group in path order
+    rows.sort_by_key(|r| r.group.unwrap_or(usize::MAX));
+
+    let mut writer = csv::Writer::from_writer(out);
+    for row in &rows {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    fn seeded_db() -> Connection {
+        let conn = open_test_db();
+        for path in ["/a/photos/1.jpg", "/b/photos/1.jpg"] {
+            db::upsert_file(&conn, Path::new(path), "hash_jpg", 100, 0).unwrap();
+        }
+        for path in ["/a/photos", "/b/photos"] {
+            db::upsert_directory(&conn, Path::new(path), "hash_dir", 100).unwrap();
+        }
+        db::upsert_file(&conn, Path::new("/a/unique.txt"), "hash_unique", 5, 0).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_build_collects_file_and_dir_groups() {
+        let conn = seeded_db();
+        let report = build(&conn, &[], duplicates::ReportFilter::default()).unwrap();
+        assert_eq!(report.file_groups.len(), 1);
+        assert_eq!(report.dir_groups.len(), 1);
+        assert_eq!(report.wasted(), 100);
+        assert_eq!(report.redundant_copies(), 1);
+        assert_eq!((report.total_files, report.total_bytes), (3, 205));
+    }
+
+    #[test]
+    fn test_build_top_level_sets_aside_files_inside_duplicate_dirs() {
+        let conn = seeded_db();
+        let filter = duplicates::ReportFilter {
+            top_level: true,
+            ..duplicates::ReportFilter::default()
+        };
+        let report = build(&conn, &[], filter).unwrap();
+        assert!(report.file_groups.is_empty());
+        assert_eq!(report.covered_file_groups.len(), 1);
+        assert_eq!(report.dir_groups.len(), 1);
+        // The summary still counts what the directory group frees
+        assert_eq!(report.wasted(), 100);
+        assert_eq!(report.redundant_copies(), 1);
+        let doc: serde_json::Value = serde_json::from_str(&to_json(&report).unwrap()).unwrap();
+        assert_eq!(doc["summary"]["covered_file_groups"], 1);
+    }
+
+    #[test]
+    fn test_locations_are_only_named_across_several() {
+        let conn = seeded_db();
+        conn.execute("UPDATE files SET host = 'laptop'", [])
+            .unwrap();
+        assert!(locations(&conn).unwrap().is_empty());
+
+        conn.execute("UPDATE files SET host = 'nas' WHERE path LIKE '/b/%'", [])
+            .unwrap();
+        let report = build(&conn, &[], duplicates::ReportFilter::default()).unwrap();
+        assert_eq!(report.locations["/a/photos/1.jpg"], "laptop");
+        assert_eq!(report.locations["/b/photos/1.jpg"], "nas");
+        let doc: serde_json::Value = serde_json::from_str(&to_json(&report).unwrap()).unwrap();
+        assert_eq!(doc["file_groups"][0]["files"][1]["location"], "nas");
+        assert!(doc["directory_groups"][0]["directories"][0]
+            .get("location")
+            .is_none());
+    }
+
+    #[test]
+    fn test_build_applies_filter_to_both_kinds() {
+        let conn = seeded_db();
+        let filter = duplicates::ReportFilter {
+            min_wasted: 101,
+            ..duplicates::ReportFilter::default()
+        };
+        let report = build(&conn, &[], filter).unwrap();
+        assert!(report.file_groups.is_empty());
+        assert!(report.dir_groups.is_empty());
+    }
+
+    #[test]
+    fn test_to_json_structure() {
+        let conn = seeded_db();
+        let report = build(&conn, &[], duplicates::ReportFilter::default()).unwrap();
+        let doc: serde_json::Value = serde_json::from_str(&to_json(&report).unwrap()).unwrap();
+
+        let group = &doc["file_groups"][0];
+        assert_eq!(group["hash"], "hash_jpg");
+        assert_eq!(group["count"], 2);
+        assert_eq!(group["size"], 100);
+        assert_eq!(group["wasted"], 100);
+        assert_eq!(group["files"][1]["path"], "/b/photos/1.jpg");
+        assert_eq!(
+            doc["directory_groups"][0]["directories"][0]["path"],
+            "/a/photos"
+        );
+        assert_eq!(doc["summary"]["wasted"], 100);
+        assert_eq!(doc["summary"]["directory_groups"], 1);
+        assert_eq!(doc["summary"]["total_files"], 3);
+        assert_eq!(doc["summary"]["total_bytes"], 205);
+        assert_eq!(doc["summary"]["redundant_copies"], 1);
+    }
+
+    fn export(conn: &Connection, duplicates_only: bool) -> Vec<String> {
+        let mut out = Vec::new();
+        write_csv(conn, &mut out, duplicates_only).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn test_write_csv_lists_duplicates_first_with_group_ids() {
+        let lines = export(&seeded_db(), false);
+        assert_eq!(lines[0], "group,path,hash,size,modified,hardlink_of");
+        assert_eq!(lines[1], "1,/a/photos/1.jpg,hash_jpg,100,0,");
+        assert_eq!(lines[2], "1,/b/photos/1.jpg,hash_jpg,100,0,");
+        assert_eq!(lines[3], ",/a/unique.txt,hash_unique,5,0,");
+        assert_eq!(lines.len(), 4);
+    }
+
+    #[test]
+    fn test_write_csv_duplicates_only() {
+        let lines = export(&seeded_db(), true);
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|l| !l.contains("unique")));
+    }
+
+    #[test]
+    fn test_write_csv_marks_hardlinks_and_quotes_commas() {
+        let conn = seeded_db();
+        let linked = Path::new("/c/a, b.jpg");
+        db::upsert_file(&conn, linked, "hash_jpg", 100, 0).unwrap();
+        db::update_file_identity(&conn, Path::new("/a/photos/1.jpg"), 1, 9).unwrap();
+        db::update_file_identity(&conn, linked, 1, 9).unwrap();
+
+        let lines = export(&conn, true);
+        assert!(lines.contains(&"1,\"/c/a, b.jpg\",hash_jpg,100,0,/a/photos/1.jpg".to_string()));
+    }
+
+    #[test]
+    fn test_to_html_links_every_path() {
+        let conn = seeded_db();
+        let report = build(&conn, &[], duplicates::ReportFilter::default()).unwrap();
+        let html = to_html(&report);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains(r#"<a href="file:///a/photos/1.jpg">/a/photos/1.jpg</a>"#));
+        assert!(html.contains(r#"<a href="file:///b/photos">/b/photos</a>"#));
+        assert!(html.contains("sortTable"));
+    }
+
+    #[test]
+    fn test_to_html_escapes_paths() {
+        let conn = open_test_db();
+        for path in ["/a/<b> & c.txt", "/d/x.txt"] {
+            db::upsert_file(&conn, Path::new(path), "h", 1, 0).unwrap();
+        }
+        let report = build(&conn, &[], duplicates::ReportFilter::default()).unwrap();
+        let html = to_html(&report);
+        assert!(html.contains(">/a/&lt;b&gt; &amp; c.txt</a>"));
+        assert!(html.contains("file:///a/%3Cb%3E%20%26%20c.txt"));
+    }
+
+    #[test]
+    fn test_file_url_windows_path() {
+        assert_eq!(
+            file_url(r"C:\Photos\a b.jpg"),
+            "file:///C:/Photos/a%20b.jpg"
+        );
+    }
+
+    #[test]
+    fn test_duplicate_bytes_by_dir_counts_every_copy() {
+        let conn = seeded_db();
+        db::upsert_file(&conn, Path::new("/a/photos/2.jpg"), "hash_jpg", 100, 0).unwrap();
+        let report = build(&conn, &[], duplicates::ReportFilter::default()).unwrap();
+        assert_eq!(
+            duplicate_bytes_by_dir(&report),
+            vec![
+                ("/a/photos".to_string(), 200),
+                ("/b/photos".to_string(), 100)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_to_fdupes_groups_separated_by_blank_lines() {
+        let conn = seeded_db();
+        for path in ["/c/x.bin", "/d/x.bin"] {
+            db::upsert_file(&conn, Path::new(path), "hash_bin", 1, 0).unwrap();
+        }
+        let groups = duplicates::find_duplicate_files(&conn).unwrap();
+        assert_eq!(
+            to_fdupes(&groups, false),
+            "/a/photos/1.jpg\n/b/photos/1.jpg\n\n/c/x.bin\n/d/x.bin\n\n"
+        );
+        assert_eq!(
+            to_fdupes(&groups, true),
+            "100 bytes each:\n/a/photos/1.jpg\n/b/photos/1.jpg\n\n\
+             1 byte each:\n/c/x.bin\n/d/x.bin\n\n"
+        );
+    }
+
+    #[test]
+    fn test_to_fdupes_no_groups_is_empty() {
+        assert_eq!(to_fdupes(&[], true), "");
+    }
+
+    #[test]
+    fn test_unique_files_excludes_every_copy() {
+        let conn = seeded_db();
+        let paths: Vec<String> = unique_files(&conn, &[])
+            .unwrap()
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        assert_eq!(paths, vec!["/a/unique.txt"]);
+    }
+
+    #[test]
+    fn test_unique_files_scope_and_hardlinks() {
+        let conn = seeded_db();
+        for path in ["/b/link1", "/b/link2"] {
+            db::upsert_file(&conn, Path::new(path), "hash_link", 7, 0).unwrap();
+            db::update_file_identity(&conn, Path::new(path), 1, 42).unwrap();
+        }
+        let paths: Vec<String> = unique_files(&conn, &[Path::new("/b")])
+            .unwrap()
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        assert_eq!(paths, vec!["/b/link1", "/b/link2"]);
+    }
+
+    #[test]
+    fn test_unique_to_json_summary() {
+        let conn = seeded_db();
+        let files = unique_files(&conn, &[]).unwrap();
+        let doc: serde_json::Value =
+            serde_json::from_str(&unique_to_json(&files).unwrap()).unwrap();
+        assert_eq!(doc["files"][0]["path"], "/a/unique.txt");
+        assert_eq!(doc["summary"]["files"], 1);
+        assert_eq!(doc["summary"]["bytes"], 5);
+    }
+
+    #[test]
+    fn test_to_json_empty_report() {
+        let report = build(&open_test_db(), &[], duplicates::ReportFilter::default()).unwrap();
+        let doc: serde_json::Value = serde_json::from_str(&to_json(&report).unwrap()).unwrap();
+        assert_eq!(doc["file_groups"].as_array().unwrap().len(), 0);
+        assert_eq!(doc["summary"]["wasted"], 0);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::dedupe::{GroupPlan, ProtectedPaths, Removal};
+use crate::duplicates::DuplicateFileGroup;
+
+/// What to do with one copy in a group under review.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mark {
+    Keep,
+    Delete,
+    /// Replace with a link to the group's first kept copy
+    Link,
+}
+
+/// Why a mark was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// The copy is on the protect list
+    Protected,
+    /// Every other copy is already marked to go
+    LastCopy,
+}
+
+/// Duplicate file groups being gone through by hand: which copies to keep,
+/// delete or replace with links, for the `tui` command. Every copy starts
+/// out kept, and at least one copy of every group always is.
+pub struct Review {
+    groups: Vec<DuplicateFileGroup>,
+    marks: Vec<Vec<Mark>>,
+    protected: ProtectedPaths,
+}
+
+/// What a review's marks add up to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Tally {
+    pub deletes: usize,
+    pub links: usize,
+    /// Bytes freed once they are carried out
+    pub freed: i64,
+}
+
+impl Review {
+    /// Review `groups`, those that waste the most first.
+    pub fn new(mut groups: Vec<DuplicateFileGroup>, protected: ProtectedPaths) -> Self {
+        groups.sort_by(|a, b| {
+            b.wasted()
+                .cmp(&a.wasted())
+                .then_with(|| a.hash.cmp(&b.hash))
+        });
+        let marks = groups
+            .iter()
+            .map(|g| vec![Mark::Keep; g.files.len()])
+            .collect();
+        Self {
+            groups,
+            marks,
+            protected,
+        }
+    }
+
+    pub fn groups(&self) -> &[DuplicateFileGroup] {
+        &self.groups
+    }
+
+    pub fn marks(&self, group: usize) -> &[Mark] {
+        &self.marks[group]
+    }
+
+    pub fn is_protected(&self, group: usize, file: usize) -> bool {
+        self.protected.covers_file(&self.groups[group].files[file])
+    }
+
+    /// Mark copy `file` of `group`. Protected copies can only be kept, and
+    /// the last copy kept can't be marked to go.
+    pub fn set(&mut self, group: usize, file: usize, mark: Mark) -> Result<(), Refusal> {
+        if mark != Mark::Keep {
+            if self.is_protected(group, file) {
+                return Err(Refusal::Protected);
+            }
+            let others_kept = self.marks[group]
+                .iter()
+                .enumerate()
+                .any(|(i, &m)| i != file && m == Mark::Keep);
+            if !others_kept {
+                return Err(Refusal::LastCopy);
+            }
+        }
+        self.marks[group][file] = mark;
+        Ok(())
+    }
+
+    /// Keep copy `file` of `group` and mark every other one with `mark`,
+    /// except protected copies, which stay kept.
+    pub fn keep_only(&mut self, group: usize, file: usize, mark: Mark) {
+        for i in 0..self.marks[group].len() {
+            self.marks[group][i] = if i == file || self.is_protected(group, i) {
+                Mark::Keep
+            } else {
+                mark
+            };
+        }
+    }
+
+    /// Keep every copy of `group` again.
+    pub fn reset(&mut self, group: usize) {
+        self.marks[group].fill(Mark::Keep);
+    }
+
+    /// Whether any copy of `group` is marked to go.
+    pub fn is_marked(&self, group: usize) -> bool {
+        self.marks[group].iter().any(|&m| m != Mark::Keep)
+    }
+
+    /// What `group`'s marks add up to; a copy's hardlinks go with it.
+    pub fn group_tally(&self, group: usize) -> Tally {
+        let mut tally = Tally::default();
+        for (file, &mark) in self.groups[group].files.iter().zip(&self.marks[group]) {
+            match mark {
+                Mark::Keep => continue,
+                Mark::Delete => tally.deletes += 1 + file.hardlinks.len(),
+                Mark::Link => tally.links += 1 + file.hardlinks.len(),
+            }
+            tally.freed += file.size;
+        }
+        tally
+    }
+
+    pub fn tally(&self) -> Tally {
+        (0..self.groups.len()).fold(Tally::default(), |total, group| {
+            let tally = self.group_tally(group);
+            Tally {
+                deletes: total.deletes + tally.deletes,
+                links: total.links + tally.links,
+                freed: total.freed + tally.freed,
+            }
+        })
+    }
+
+    /// The plans that carry the marks out, for `dedupe::apply_plans`: one
+    /// list to apply with a deleting action and one with a linking one.
+    /// Every removal's keeper is the group's first kept copy, and a copy's
+    /// hardlinks are removed with it, as `dedupe::plan_group` does. Groups
+    /// with nothing marked are left out.
+    pub fn plans(&self) -> (Vec<GroupPlan>, Vec<GroupPlan>) {
+        let mut deletes = Vec::new();
+        let mut links = Vec::new();
+        for (group, marks) in self.groups.iter().zip(&self.marks) {
+            let Some(first_kept) = marks.iter().position(|&m| m == Mark::Keep) else {
+                continue;
+            };
+            let keeper = &group.files[first_kept].path;
+            let plan = |wanted: Mark, keep: bool| {
+                let mut plan = GroupPlan {
+                    hash: group.hash.clone(),
+                    keep: Vec::new(),
+                    removals: Vec::new(),
+                    reason: "tui".to_string(),
+                };
+                for (file, &mark) in group.files.iter().zip(marks) {
+                    if mark == Mark::Keep && keep {
+                        plan.keep.push(file.path.clone());
+                        plan.keep.extend(file.hardlinks.iter().cloned());
+                    } else if mark == wanted {
+                        plan.removals.push(Removal {
+                            path: file.path.clone(),
+                            size: file.size,
+                            keeper: keeper.clone(),
+                        });
+                        plan.removals
+                            .extend(file.hardlinks.iter().map(|link| Removal {
+                                path: link.clone(),
+                                size: 0,
+                                keeper: keeper.clone(),
+                            }));
+                    }
+                }
+                plan
+            };
+            // The kept copies are logged with the first plan only
+            let delete_plan = plan(Mark::Delete, true);
+            let has_deletes = !delete_plan.removals.is_empty();
+            if has_deletes {
+                deletes.push(delete_plan);
+            }
+            let link_plan = plan(Mark::Link, !has_deletes);
+            if !link_plan.removals.is_empty() {
+                links.push(link_plan);
+            }
+        }
+        (deletes, links)
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duplicates::FileEntry;
+    use std::path::PathBuf;
+
+    fn group(hash: &str, size: i64, paths: &[&str]) -> DuplicateFileGroup {
+        DuplicateFileGroup {
+            hash: hash.to_string(),
+            count: paths.len() as i64,
+            total_size: size * paths.len() as i64,
+            files: paths
+                .iter()
+                .map(|p| FileEntry {
+                    path: p.to_string(),
+                    size,
+                    modified: 0,
+                    hardlinks: Vec::new(),
+                    via_link: false,
+                })
+                .collect(),
+        }
+    }
+
+    fn review() -> Review {
+        let mut linked = group("big", 100, &["/a/big", "/b/big"]);
+        linked.files[1].hardlinks.push("/b/big-link".to_string());
+        Review::new(
+            vec![group("small", 10, &["/a/s", "/b/s", "/c/s"]), linked],
+            ProtectedPaths::new(vec![PathBuf::from("/c")]),
+        )
+    }
+
+    #[test]
+    fn test_groups_come_largest_waste_first_with_everything_kept() {
+        let review = review();
+        let hashes: Vec<&str> = review.groups().iter().map(|g| g.hash.as_str()).collect();
+        assert_eq!(hashes, ["big", "small"]);
+        assert!(review.marks(1).iter().all(|&m| m == Mark::Keep));
+        assert_eq!(review.tally(), Tally::default());
+        assert_eq!(review.plans(), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn test_set_refuses_protected_and_last_copies() {
+        let mut review = review();
+        assert_eq!(review.set(1, 2, Mark::Delete), Err(Refusal::Protected));
+        assert_eq!(review.set(0, 0, Mark::Delete), Ok(()));
+        assert_eq!(review.set(0, 1, Mark::Link), Err(Refusal::LastCopy));
+        assert_eq!(review.set(0, 0, Mark::Keep), Ok(()));
+        assert!(!review.is_marked(0));
+
+        review.keep_only(1, 0, Mark::Delete);
+        assert_eq!(review.marks(1), [Mark::Keep, Mark::Delete, Mark::Keep]);
+        review.reset(1);
+        assert!(!review.is_marked(1));
+    }
+
+    #[test]
+    fn test_plans_split_deletes_from_links_and_take_hardlinks_along() {
+        let mut review = review();
+        review.set(0, 1, Mark::Link).unwrap();
+        review.set(1, 0, Mark::Link).unwrap();
+        review.set(1, 1, Mark::Delete).unwrap();
+        assert_eq!(
+            review.tally(),
+            Tally {
+                deletes: 1,
+                links: 3,
+                freed: 120
+            }
+        );
+
+        let (deletes, links) = review.plans();
+        assert_eq!(deletes.len(), 1);
+        assert_eq!(deletes[0].hash, "small");
+        assert_eq!(deletes[0].keep, ["/c/s"]);
+        assert_eq!(deletes[0].removals[0].path, "/b/s");
+        assert_eq!(deletes[0].removals[0].keeper, "/c/s");
+
+        assert_eq!(links.len(), 2);
+        let removed: Vec<(&str, i64)> = links[0]
+            .removals
+            .iter()
+            .map(|r| (r.path.as_str(), r.size))
+            .collect();
+        assert_eq!(removed, [("/b/big", 100), ("/b/big-link", 0)]);
+        assert_eq!(links[0].keep, ["/a/big"]);
+        assert_eq!(links[0].reason, "tui");
+        // Already logged as kept with the group's deletions
+        assert!(links[1].keep.is_empty());
+        assert_eq!(links[1].removals[0].path, "/a/s");
+    }
+}
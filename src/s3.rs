@@ -0,0 +1,696 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Context, Result};
+use rusqlite::Connection;
+use serde::Deserialize;
+
+use crate::scan::{self, FileEntry, ScanError, ScanOptions, ScanResult};
+use crate::{db, hashing, utils, walk};
+
+/// Objects written per transaction while a listing is recorded.
+const TRANSACTION_BATCH_SIZE: usize = 1000;
+
+/// What an object's hash starts with while its content is only known by its
+/// ETag. Objects with the same ETag and size group with each other, but with
+/// no local file until the hash is confirmed (see `scan_bucket`).
+pub const ETAG_PREFIX: &str = "etag:";
+
+pub fn is_etag_hash(hash: &str) -> bool {
+    hash.starts_with(ETAG_PREFIX)
+}
+
+/// A bucket, or the objects under a prefix of one: `s3://bucket/prefix`.
+/// Objects are stored as `s3://bucket/key`, next to local files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct S3Root {
+    pub bucket: String,
+    /// Without slashes at either end; empty for the whole bucket
+    pub prefix: String,
+}
+
+/// How `s3://` roots are reached: through the `aws` command line tool, with
+/// its usual credentials and `AWS_PROFILE`.
+#[derive(Debug, Clone, Default)]
+pub struct S3Options {
+    /// Download and hash objects their ETag couldn't match with a local file
+    /// of the same size, such as those uploaded in parts
+    pub download: bool,
+    /// The endpoint of an S3-compatible service (MinIO, Backblaze B2, ...)
+    pub endpoint: Option<String>,
+}
+
+/// One object of a listing.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct Object {
+    #[serde(rename = "Key")]
+    pub key: String,
+    #[serde(rename = "Size")]
+    pub size: u64,
+    /// Without the quotes S3 puts around it
+    #[serde(rename = "ETag", deserialize_with = "unquoted")]
+    pub etag: String,
+    #[serde(rename = "LastModified", deserialize_with = "timestamp")]
+    pub modified: i64,
+}
+
+fn unquoted<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let etag = String::deserialize(deserializer)?;
+    Ok(etag.trim_matches('"').to_string())
+}
+
+fn timestamp<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<i64, D::Error> {
+    let text = String::deserialize(deserializer)?;
+    parse_time(&text).ok_or_else(|| serde::de::Error::custom(format!("bad time `{text}`")))
+}
+
+/// `2026-10-14T15:05:30.000Z` (or `+00:00`) as a Unix timestamp.
+fn parse_time(text: &str) -> Option<i64> {
+    let number = |range: std::ops::Range<usize>| text.get(range)?.parse::<u32>().ok();
+    let (y, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hh, mm, ss) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    let date = utils::ymd_to_secs(y as i32, month, day);
+    Some(date + (hh * 3600 + mm * 60 + ss) as i64)
+}
+
+impl S3Root {
+    /// The bucket root `root` names, or `None` when it is a local path.
+    pub fn parse(root: &Path) -> Result<Option<Self>> {
+        let Some(rest) = root.to_str().and_then(|s| s.strip_prefix("s3://")) else {
+            return Ok(None);
+        };
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            bail!("{} names no bucket", root.display());
+        }
+        Ok(Some(Self {
+            bucket: bucket.to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+        }))
+    }
+
+    /// Where the object `key` is stored in the database.
+    fn object_path(&self, key: &str) -> PathBuf {
+        PathBuf::from(format!("s3://{}/{}", self.bucket, key))
+    }
+
+    /// The root itself, as directories below it are stored.
+    fn root_path(&self) -> PathBuf {
+        PathBuf::from(self.to_string())
+    }
+
+    /// The bucket, `s3://bucket`, as its rows record it (see
+    /// `db::tag_remote`).
+    fn machine(&self) -> String {
+        format!("s3://{}", self.bucket)
+    }
+}
+
+impl fmt::Display for S3Root {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.prefix.is_empty() {
+            write!(f, "s3://{}", self.bucket)
+        } else {
+            write!(f, "s3://{}/{}", self.bucket, self.prefix)
+        }
+    }
+}
+
+fn aws(opts: &S3Options) -> Command {
+    let mut command = Command::new("aws");
+    if let Some(endpoint) = &opts.endpoint {
+        command.arg("--endpoint-url").arg(endpoint);
+    }
+    command
+}
+
+/// Every object under `root`, from `aws s3api list-objects-v2`.
+pub fn list_objects(root: &S3Root, opts: &S3Options) -> Result<Vec<Object>> {
+    let mut command = aws(opts);
+    command
+        .args(["s3api", "list-objects-v2", "--output", "json", "--bucket"])
+        .arg(&root.bucket);
+    if !root.prefix.is_empty() {
+        command.arg("--prefix").arg(format!("{}/", root.prefix));
+    }
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .context("couldn't run aws; is the AWS command line tool installed?")?;
+    ensure!(
+        output.status.success(),
+        "aws couldn't list {root}: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    parse_listing(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// The objects in `list-objects-v2` output; there is none at all when
+/// nothing matched.
+fn parse_listing(json: &str) -> Result<Vec<Object>> {
+    #[derive(Deserialize)]
+    struct Listing {
+        #[serde(rename = "Contents", default)]
+        contents: Vec<Object>,
+    }
+    if json.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let listing: Listing = serde_json::from_str(json).context("unexpected listing from aws")?;
+    Ok(listing.contents)
+}
+
+/// Download `key` with `aws s3 cp` and hash it as it arrives.
+fn download_hash(root: &S3Root, key: &str, opts: &ScanOptions) -> Result<String> {
+    let mut child = aws(&opts.s3)
+        .args(["s3", "cp", "--quiet"])
+        .arg(format!("s3://{}/{}", root.bucket, key))
+        .arg("-")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("couldn't run aws")?;
+    let Some(mut stdout) = child.stdout.take() else {
+        bail!("couldn't read from aws");
+    };
+    let hash = hashing::compute_reader_hash(&mut stdout, &opts.hash);
+    let output = child.wait_with_output()?;
+    ensure!(
+        output.status.success(),
+        "aws couldn't download it: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    hash
+}
+
+/// Scan the objects under `root`, storing each with a hash that tells
+/// whether its content is somewhere local:
+///
+/// - The ETag of an object uploaded in one part is the MD5 of its content, so
+///   it is checked against the MD5 of every local file of the same size. A
+///   match gives the object that file's hash, without downloading anything.
+/// - With `S3Options::download`, an object whose ETag matched nothing (one
+///   uploaded in parts, say) but that has a local file of its size is
+///   downloaded and hashed.
+/// - Any other object is stored under an `ETAG_PREFIX` hash, so it only
+///   groups with objects that have the same ETag and size.
+///
+/// The walk options apply to keys as they do to paths; "directories" are the
+/// prefixes between slashes. Unchanged objects keep their hash, unless it is
+/// still an ETag one, which is checked again in case the local file has
+/// turned up since. Local files are best scanned first.
+pub fn scan_bucket(
+    conn: &Connection,
+    root: &S3Root,
+    opts: &ScanOptions,
+    on_progress: impl Fn(usize, usize, &str),
+) -> Result<ScanResult> {
+    let objects = list_objects(root, &opts.s3)?;
+    record_objects(
+        conn,
+        root,
+        objects,
+        opts,
+        |object| download_hash(root, &object.key, opts),
+        on_progress,
+    )
+}
+
+/// Whether `opts` walk the way to `relative` (a key below the root), and
+/// keep the object there of `size` bytes.
+fn admits(opts: &walk::WalkOptions, relative: &Path, size: u64) -> bool {
+    let dirs: Vec<&Path> = relative.ancestors().skip(1).collect();
+    let depth = dirs.len() - 1;
+    if opts.max_depth.is_some_and(|max| depth > max) {
+        return false;
+    }
+    if opts.skip_hidden
+        && relative
+            .components()
+            .any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+    {
+        return false;
+    }
+    let below_root = dirs.iter().filter(|d| !d.as_os_str().is_empty());
+    if below_root
+        .into_iter()
+        .any(|dir| opts.filter.skips(dir, true))
+    {
+        return false;
+    }
+    size >= opts.min_file_size && !opts.filter.skips(relative, false) && opts.types.admits(relative)
+}
+
+/// Record a listing of `root`; `download` hashes an object's content.
+fn record_objects(
+    conn: &Connection,
+    root: &S3Root,
+    objects: Vec<Object>,
+    opts: &ScanOptions,
+    download: impl Fn(&Object) -> Result<String>,
+    on_progress: impl Fn(usize, usize, &str),
+) -> Result<ScanResult> {
+    let _scan = tracing::info_span!("scan", root = %root).entered();
+    db::init_visited_files(conn)?;
+    let root_path = root.root_path();
+    let root_str = utils::path_to_db(&root_path).into_owned();
+    let machine = root.machine();
+    let started = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let key_prefix = if root.prefix.is_empty() {
+        String::new()
+    } else {
+        format!("{}/", root.prefix)
+    };
+
+    let mut directories: HashSet<PathBuf> = HashSet::from([root_path.clone()]);
+    let mut files = Vec::new();
+    for object in objects {
+        let Some(relative) = object.key.strip_prefix(&key_prefix) else {
+            continue;
+        };
+        let path = root.object_path(&object.key);
+        // A key ending in `/` is an empty "folder" some tools create
+        let is_folder = object.key.ends_with('/');
+        if !is_folder && !admits(&opts.walk, Path::new(relative), object.size) {
+            continue;
+        }
+        let mut dir = if is_folder {
+            Some(root.object_path(object.key.trim_end_matches('/')))
+        } else {
+            path.parent().map(Path::to_path_buf)
+        };
+        while let Some(d) = dir.filter(|d| d.starts_with(&root_path)) {
+            dir = d.parent().map(Path::to_path_buf);
+            if !directories.insert(d) {
+                break;
+            }
+        }
+        if !is_folder {
+            files.push((path, object));
+        }
+    }
+
+    let mut errors = Vec::new();
+    let mut files_by_dir: HashMap<PathBuf, Vec<FileEntry>> = HashMap::new();
+    let mut resolver = Resolver {
+        conn,
+        opts,
+        download: &download,
+        off_disk: db::files_off_disk(conn)?,
+        md5s: HashMap::new(),
+        upgraded: Vec::new(),
+    };
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    let total = files.len();
+    for (processed, (path, object)) in files.into_iter().enumerate() {
+        if opts.cancelled() {
+            break;
+        }
+        let path_str = utils::path_to_db(&path).into_owned();
+        db::mark_visited(conn, &path_str)?;
+        batch.tick()?;
+        let stored = db::get_file(conn, &path)?.filter(|r| {
+            r.modified == object.modified && r.size == object.size as i64 && !is_etag_hash(&r.hash)
+        });
+        let hash = match stored {
+            Some(record) => record.hash,
+            None => match resolver.resolve(&path_str, &object) {
+                Ok(hash) => {
+                    let (size, modified) = (object.size as i64, object.modified);
+                    db::upsert_remote_file(conn, &path, &machine, &hash, size, modified)?;
+                    resolver.off_disk.insert(path_str.clone());
+                    opts.hashed(&path, object.size, &hash);
+                    hash
+                }
+                Err(e) => {
+                    errors.push(ScanError {
+                        path: path_str,
+                        kind: "io",
+                        message: format!("{e:#}"),
+                    });
+                    continue;
+                }
+            },
+        };
+        on_progress(processed + 1, total, &scan::file_name(&path));
+        if let Some(parent) = path.parent() {
+            files_by_dir
+                .entry(parent.to_path_buf())
+                .or_default()
+                .push(FileEntry {
+                    path: path_str,
+                    hash,
+                    size: object.size,
+                });
+        }
+    }
+    batch.commit()?;
+    scan::rehash_ancestors(conn, &resolver.upgraded, opts.hash.algorithm)?;
+
+    db::clear_scan_errors(conn, &root_str)?;
+    for error in &errors {
+        db::log_scan_error(
+            conn,
+            started,
+            &root_str,
+            &error.path,
+            error.kind,
+            &error.message,
+        )?;
+    }
+    if opts.cancelled() {
+        return Ok(ScanResult {
+            errors,
+            stale_count: 0,
+            root_str,
+            interrupted: true,
+            moved: 0,
+        });
+    }
+    let stale_count = db::stale_file_paths(conn, &root_str)?.len() as i64;
+    let unreadable: HashSet<&str> = errors.iter().map(|e| e.path.as_str()).collect();
+    let directories: Vec<PathBuf> = directories.into_iter().collect();
+    let _directories = tracing::info_span!("hash directories", root = %root).entered();
+    scan::compute_directory_hashes(
+        conn,
+        &root_path,
+        &directories,
+        &files_by_dir,
+        &unreadable,
+        None,
+        opts.hash.algorithm,
+    )?;
+    db::tag_remote(conn, &root_str, &machine)?;
+    tracing::info!(errors = errors.len(), stale = stale_count, "scanned {root}");
+    Ok(ScanResult {
+        errors,
+        stale_count,
+        root_str,
+        interrupted: false,
+        moved: 0,
+    })
+}
+
+/// Works out the hashes of objects (see `scan_bucket`).
+struct Resolver<'a, D> {
+    conn: &'a Connection,
+    opts: &'a ScanOptions,
+    download: &'a D,
+    /// The rows that aren't files on this machine, such as the objects
+    /// recorded so far
+    off_disk: HashSet<String>,
+    /// The MD5 of each local file read so far, `None` if it couldn't be read
+    md5s: HashMap<String, Option<String>>,
+    /// Local files whose provisional hash was replaced by a real one
+    upgraded: Vec<PathBuf>,
+}
+
+impl<D: Fn(&Object) -> Result<String>> Resolver<'_, D> {
+    fn resolve(&mut self, path_str: &str, object: &Object) -> Result<String> {
+        // Only files on this machine can be read for comparison
+        let candidates: Vec<db::FileRecord> = db::files_with_size(self.conn, object.size as i64)?
+            .into_iter()
+            .filter(|r| !self.off_disk.contains(&r.path) && r.path != path_str)
+            .filter(|r| utils::path_from_db(&r.path).is_file())
+            .collect();
+        let etag_md5 = Some(object.etag.as_str())
+            .filter(|e| e.len() == 32 && e.chars().all(|c| c.is_ascii_hexdigit()));
+        if let Some(etag) = etag_md5 {
+            for record in &candidates {
+                let local = utils::path_from_db(&record.path);
+                let md5 = self
+                    .md5s
+                    .entry(record.path.clone())
+                    .or_insert_with(|| md5_file(&local).ok());
+                if !md5
+                    .as_deref()
+                    .is_some_and(|md5| md5.eq_ignore_ascii_case(etag))
+                {
+                    continue;
+                }
+                if !hashing::is_provisional(&record.hash) {
+                    return Ok(record.hash.clone());
+                }
+                let hash = hashing::compute_file_hash(&local, &self.opts.hash)?;
+                db::update_file_hash(self.conn, &local, &hash)?;
+                self.upgraded.push(local);
+                return Ok(hash);
+            }
+        }
+        if self.opts.s3.download && !candidates.is_empty() {
+            return (self.download)(object);
+        }
+        Ok(format!("{ETAG_PREFIX}{}:{}", object.etag, object.size))
+    }
+}
+
+fn md5_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    md5_hex(&mut file)
+}
+
+/// The MD5 digest of everything `reader` yields, in hex: what S3 gives as the
+/// ETag of an object uploaded in one part, unencrypted or encrypted with S3's
+/// own keys. (No other hash here needs MD5, so it isn't a `HashAlgorithm`.)
+fn md5_hex(reader: &mut impl Read) -> Result<String> {
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut pending = Vec::with_capacity(128);
+    let mut length = 0u64;
+    loop {
+        let n = reader.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hashing::count_hashed(n);
+        length += n as u64;
+        let mut data = &buffer[..n];
+        if !pending.is_empty() {
+            let take = (64 - pending.len()).min(data.len());
+            pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if pending.len() == 64 {
+                md5_block(&mut state, &pending);
+                pending.clear();
+            }
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            md5_block(&mut state, block);
+        }
+        pending.extend_from_slice(blocks.remainder());
+    }
+    pending.push(0x80);
+    while pending.len() % 64 != 56 {
+        pending.push(0);
+    }
+    pending.extend_from_slice(&(length.wrapping_mul(8)).to_le_bytes());
+    for block in pending.chunks_exact(64) {
+        md5_block(&mut state, block);
+    }
+    Ok(state
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// RFC 1321's per-round shift amounts and sine-derived constants.
+const MD5_SHIFTS: [[u32; 4]; 4] = [
+    [7, 12, 17, 22],
+    [5, 9, 14, 20],
+    [4, 11, 16, 23],
+    [6, 10, 15, 21],
+];
+const MD5_K: [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+fn md5_block(state: &mut [u32; 4], block: &[u8]) {
+    let mut m = [0u32; 16];
+    for (word, bytes) in m.iter_mut().zip(block.chunks_exact(4)) {
+        *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    }
+    let [mut a, mut b, mut c, mut d] = *state;
+    for i in 0..64 {
+        let (f, g) = match i / 16 {
+            0 => ((b & c) | (!b & d), i),
+            1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+            2 => (b ^ c ^ d, (3 * i + 5) % 16),
+            _ => (c ^ (b | !d), (7 * i) % 16),
+        };
+        let f = f.wrapping_add(a).wrapping_add(MD5_K[i]).wrapping_add(m[g]);
+        a = d;
+        d = c;
+        c = b;
+        b = b.wrapping_add(f.rotate_left(MD5_SHIFTS[i / 16][i % 4]));
+    }
+    for (word, value) in state.iter_mut().zip([a, b, c, d]) {
+        *word = word.wrapping_add(value);
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clean, dedupe};
+
+    #[test]
+    fn test_parse_splits_bucket_and_prefix() {
+        let root = S3Root::parse(Path::new("s3://backup/photos/2024/"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(root.bucket, "backup");
+        assert_eq!(root.prefix, "photos/2024");
+        assert_eq!(root.to_string(), "s3://backup/photos/2024");
+        let root = S3Root::parse(Path::new("s3://backup")).unwrap().unwrap();
+        assert_eq!(
+            (root.prefix.as_str(), root.to_string()),
+            ("", "s3://backup".into())
+        );
+        assert_eq!(S3Root::parse(Path::new("/backup")).unwrap(), None);
+        assert!(S3Root::parse(Path::new("s3:///photos")).is_err());
+    }
+
+    #[test]
+    fn test_md5_matches_rfc_1321() {
+        let md5 = |data: &[u8]| md5_hex(&mut &data[..]).unwrap();
+        assert_eq!(md5(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(md5(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            md5(
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890"
+            ),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+        // Read in pieces that don't line up with blocks
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut reader = std::io::BufReader::with_capacity(1000, &data[..]);
+        assert_eq!(md5_hex(&mut reader).unwrap(), md5(&data));
+        assert_eq!(md5(&data), "415d6e662118c229c6ad3f950c24702a");
+    }
+
+    #[test]
+    fn test_parse_listing_unquotes_etags() {
+        let json = r#"{"Contents": [{"Key": "photos/a.jpg", "LastModified":
+            "2026-10-14T15:05:30.000Z", "ETag": "\"900150983cd24fb0d6963f7d28e17f72\"",
+            "Size": 3, "StorageClass": "STANDARD"}]}"#;
+        let objects = parse_listing(json).unwrap();
+        assert_eq!(
+            objects,
+            [Object {
+                key: "photos/a.jpg".to_string(),
+                size: 3,
+                etag: "900150983cd24fb0d6963f7d28e17f72".to_string(),
+                modified: 1_791_990_330,
+            }]
+        );
+        assert!(parse_listing("").unwrap().is_empty());
+        assert!(parse_listing("{}").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_record_objects_finds_what_is_already_uploaded() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("photos")).unwrap();
+        fs::write(dir.path().join("photos/a.jpg"), "abc").unwrap();
+        fs::write(dir.path().join("photos/b.jpg"), "big one").unwrap();
+        fs::write(dir.path().join("photos/c.jpg"), "not up").unwrap();
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        let opts = ScanOptions::default();
+        scan::scan_directory(&conn, &dir.path().join("photos"), 3, &opts, |_, _, _| {}).unwrap();
+
+        let object = |key: &str, size: u64, etag: &str| Object {
+            key: key.to_string(),
+            size,
+            etag: etag.to_string(),
+            modified: 1_791_990_330,
+        };
+        let objects = vec![
+            // Uploaded in one part: the ETag is the MD5
+            object("photos/a.jpg", 3, "900150983cd24fb0d6963f7d28e17f72"),
+            // Uploaded in parts, so only a download tells
+            object("photos/b.jpg", 7, "0123456789abcdef0123456789abcdef-2"),
+            object("photos/d.jpg", 5, "0123456789abcdef0123456789abcdef"),
+            object("photos/", 0, "d41d8cd98f00b204e9800998ecf8427e"),
+            object("other/x.jpg", 3, "900150983cd24fb0d6963f7d28e17f72"),
+        ];
+        let root = S3Root::parse(Path::new("s3://backup/photos"))
+            .unwrap()
+            .unwrap();
+        let hash_of = |name: &str| {
+            let local = dir.path().join("photos").join(name);
+            db::get_file(&conn, &local).unwrap().unwrap().hash
+        };
+        let stored = |key: &str| {
+            let path = PathBuf::from(format!("s3://backup/photos/{key}"));
+            db::get_file(&conn, &path).unwrap().map(|r| r.hash)
+        };
+        let downloads = std::cell::Cell::new(0);
+        let download = |object: &Object| {
+            downloads.set(downloads.get() + 1);
+            assert_eq!(object.key, "photos/b.jpg");
+            Ok(hash_of("b.jpg"))
+        };
+
+        let result =
+            record_objects(&conn, &root, objects.clone(), &opts, download, |_, _, _| {}).unwrap();
+        assert!(result.errors.is_empty());
+        assert_eq!(stored("a.jpg"), Some(hash_of("a.jpg")));
+        assert_eq!(
+            stored("b.jpg").unwrap(),
+            "etag:0123456789abcdef0123456789abcdef-2:7"
+        );
+        assert_eq!(
+            stored("d.jpg").unwrap(),
+            "etag:0123456789abcdef0123456789abcdef:5"
+        );
+        assert_eq!(downloads.get(), 0);
+
+        let opts = ScanOptions {
+            s3: S3Options {
+                download: true,
+                ..S3Options::default()
+            },
+            ..ScanOptions::default()
+        };
+        record_objects(&conn, &root, objects, &opts, download, |_, _, _| {}).unwrap();
+        assert_eq!(stored("b.jpg"), Some(hash_of("b.jpg")));
+        // No local file is 5 bytes, so there is nothing to download it for
+        assert_eq!(downloads.get(), 1);
+        let dir_hash = |path: &Path| db::get_directory(&conn, path).unwrap().map(|r| r.hash);
+        assert_ne!(dir_hash(Path::new("s3://backup/photos")), None);
+        assert_eq!(stored("../other/x.jpg"), None);
+
+        // An object matching a local file says it is backed up, nothing more:
+        // it is never offered for removal, and `clean` keeps it
+        let groups = dedupe::groups_in_scope(&conn, &[]).unwrap();
+        assert!(groups.is_empty());
+        let stats = clean::prune_missing(&conn, opts.hash.algorithm).unwrap();
+        assert_eq!((stats.files_removed, stats.dirs_removed), (0, 0));
+        assert_eq!(stored("a.jpg"), Some(hash_of("a.jpg")));
+        assert_ne!(dir_hash(Path::new("s3://backup/photos")), None);
+    }
+}
@@ -1,13 +1,31 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
 use std::time::SystemTime;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use rayon::prelude::*;
 use rusqlite::Connection;
-use walkdir::WalkDir;
 
-use crate::{db, hashing, utils};
+use crate::{archive, db, file_system, hashing, remote, s3, utils, walk, xattr};
+
+/// Bytes of the files every scan in the process has got through so far,
+/// whether hashed, found moved or unchanged; progress bars measure a scan
+/// against the total `hashing::measure_tree` finds with it.
+static BYTES_SCANNED: AtomicU64 = AtomicU64::new(0);
+
+pub fn bytes_scanned() -> u64 {
+    BYTES_SCANNED.load(Ordering::Relaxed)
+}
+
+fn count_scanned(bytes: u64) {
+    BYTES_SCANNED.fetch_add(bytes, Ordering::Relaxed);
+}
 
 #[derive(Debug, Clone)]
 pub struct FileEntry {
@@ -16,119 +34,1005 @@ pub struct FileEntry {
     pub size: u64,
 }
 
+/// Options that control how `scan_directory` walks and hashes a tree.
+#[derive(Debug, Clone, Default)]
+pub struct ScanOptions {
+    /// Number of hashing worker threads; `0` lets rayon pick (one per logical CPU).
+    pub threads: usize,
+    /// Passed through to `hashing::compute_file_hash` for every file.
+    pub hash: hashing::HashOptions,
+    /// Only read files in full when their size and partial hash collide with
+    /// another file; provably unique files get a provisional hash instead.
+    pub prefilter: bool,
+    /// Take hashes from, and write them to, each file's extended attribute
+    /// (see `xattr::hash_file`) as well as the database.
+    pub xattr_cache: bool,
+    /// Where the walk stops and what it skips.
+    pub walk: walk::WalkOptions,
+    /// Also hash the files inside zip and tar archives, each stored as a
+    /// member of its archive (see `archive::member_path`).
+    pub scan_archives: bool,
+    /// How `ssh://` roots are reached (see `remote::scan_remote`).
+    pub remote: remote::RemoteOptions,
+    /// How `s3://` roots are reached (see `s3::scan_bucket`).
+    pub s3: s3::S3Options,
+    /// Told of every file hashed, as it is stored.
+    pub on_hashed: Option<OnHashed>,
+    /// Set (e.g. by a Ctrl-C handler) to stop the scan early. Everything
+    /// hashed so far is kept; directory hashes for the unfinished root are not.
+    pub cancel: Arc<AtomicBool>,
+}
+
+impl ScanOptions {
+    pub(crate) fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn hashed(&self, path: &Path, size: u64, hash: &str) {
+        if let Some(on_hashed) = &self.on_hashed {
+            (on_hashed.0)(path, size, hash);
+        }
+    }
+}
+
+/// Called with the path, size and new hash of each file a scan hashes (or,
+/// for an `s3://` object, matches with a local file), on the thread that
+/// stores it.
+#[derive(Clone)]
+pub struct OnHashed(pub Arc<HashedFn>);
+
+pub type HashedFn = dyn Fn(&Path, u64, &str) + Send + Sync;
+
+impl fmt::Debug for OnHashed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OnHashed(..)")
+    }
+}
+
+/// Files (or directories) written per transaction during a scan.
+pub(crate) const TRANSACTION_BATCH_SIZE: usize = 1000;
+
+/// `meta` key prefix, followed by a root, marking that the directory hashes
+/// under the root may be out of date (see `scan_directory`).
+const PENDING_DIRECTORIES_PREFIX: &str = "directories_pending:";
+
+/// `meta` key set while directory hashes take empty files into account (see
+/// `count_empty_files`).
+const EMPTY_FILES_META_KEY: &str = "directories_include_empty";
+
+/// A file whose content hash still has to be worked out.
+struct HashJob {
+    path: PathBuf,
+    path_str: String,
+    size: u64,
+    modified_secs: i64,
+    /// First/last-chunk hash, once the prefilter has computed it.
+    partial: Option<String>,
+    /// The hash already in the DB, when this job is an existing row the
+    /// prefilter revisits rather than a new or changed file from the walk.
+    stored: Option<String>,
+    /// No row exists for this path yet, so the file may be one that moved.
+    untracked: bool,
+    /// `(device, inode)` from the walk, stored alongside the hash.
+    identity: Option<(i64, i64)>,
+    /// Link count, owner and permissions from the walk, stored likewise.
+    stat: Option<utils::FileStat>,
+    /// Reached through a symlink (see `file_system::WalkEntry`).
+    via_link: bool,
+}
+
+/// A path the scan had to skip, and why.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanError {
+    pub path: String,
+    /// `permission-denied`, `not-found`, `io` or `hash` (see `db::ErrorRecord`)
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl ScanError {
+    /// A file or directory whose metadata or contents couldn't be read.
+    pub(crate) fn io(path: &Path, error: &std::io::Error) -> Self {
+        let kind = match error.kind() {
+            std::io::ErrorKind::PermissionDenied => "permission-denied",
+            std::io::ErrorKind::NotFound => "not-found",
+            _ => "io",
+        };
+        ScanError {
+            path: utils::path_to_db(path).into_owned(),
+            kind,
+            message: error.to_string(),
+        }
+    }
+
+    /// An entry the walk couldn't read, such as a directory without
+    /// permission to list it.
+    pub(crate) fn from_walk(root: &Path, error: &walkdir::Error) -> Self {
+        let path = error.path().unwrap_or(root);
+        match error.io_error() {
+            Some(io) => ScanError::io(path, io),
+            None => ScanError {
+                path: utils::path_to_db(path).into_owned(),
+                kind: "io",
+                message: error.to_string(),
+            },
+        }
+    }
+
+    /// A file that couldn't be hashed.
+    pub(crate) fn hashing(path: &Path, error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<std::io::Error>() {
+            Some(io) => ScanError::io(path, io),
+            None => ScanError {
+                path: utils::path_to_db(path).into_owned(),
+                kind: "hash",
+                message: format!("{error:#}"),
+            },
+        }
+    }
+}
+
+/// What `scan_files` reports back to `scan_directory`.
+struct FilesPass {
+    /// Entries that could not be listed, read or hashed.
+    errors: Vec<ScanError>,
+    /// Files outside the scanned root whose provisional hash was replaced by
+    /// a real one, or whose row moved into the root; the directories above
+    /// their (old) paths need rehashing.
+    changed_elsewhere: Vec<PathBuf>,
+    /// Every directory the walk listed, root included.
+    directories: Vec<PathBuf>,
+    /// Files under the root that were hashed, upgraded or moved (old paths
+    /// too); the directories above them need rehashing.
+    changed: Vec<PathBuf>,
+    /// Files recognised as moved and repointed rather than rehashed.
+    moved: usize,
+    /// `opts.cancel` was set before every file was hashed.
+    interrupted: bool,
+}
+
 /// First pass: walk all files under `root`, hash any that are new or changed,
 /// load cached hashes for unchanged files, and populate `files_by_dir`.
-/// Returns the count of files skipped due to invalid UTF-8 paths.
+/// Hashing runs on a worker pool; results are fed back to this thread so the
+/// DB is only ever written through the single `conn`.
 fn scan_files(
     conn: &Connection,
     root: &Path,
     total_files: usize,
+    opts: &ScanOptions,
     files_by_dir: &mut HashMap<PathBuf, Vec<FileEntry>>,
     on_progress: impl Fn(usize, usize, &str),
-) -> Result<usize> {
+) -> Result<FilesPass> {
     let mut processed = 0;
-    let mut invalid_paths = 0usize;
-
-    for entry in WalkDir::new(root).follow_links(false) {
-        let entry = entry?;
+    let mut errors = Vec::new();
+    let mut directories = Vec::new();
+    let mut jobs: Vec<HashJob> = Vec::new();
+    // Archives whose members have to be read, with their mtimes
+    let mut archives: Vec<(PathBuf, String, i64)> = Vec::new();
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+
+    let walking = tracing::info_span!("walk", root = %root.display()).entered();
+    for entry in file_system::walk_tree(root, &opts.walk) {
+        if opts.cancelled() {
+            break;
+        }
+        // An unreadable entry is left out rather than ending the scan; the
+        // walk carries on with its siblings
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                errors.push(ScanError::from_walk(root, &e));
+                continue;
+            }
+        };
         let path = entry.path();
+        if path.is_dir() {
+            directories.push(path.to_path_buf());
+        }
 
         if path.is_file() {
-            processed += 1;
-            let file_name = path
-                .file_name()
-                .and_then(|n| n.to_str())
-                .unwrap_or("<unknown>");
-            on_progress(processed, total_files, file_name);
-
-            let metadata = fs::metadata(path)?;
-            let modified = metadata.modified()?;
+            let (metadata, modified) =
+                match fs::metadata(path).and_then(|m| m.modified().map(|t| (m, t))) {
+                    Ok(found) => found,
+                    Err(e) => {
+                        errors.push(ScanError::io(path, &e));
+                        continue;
+                    }
+                };
             let size = metadata.len();
+            let identity = utils::file_identity(&metadata);
+            let stat = utils::file_stat(&metadata);
 
-            let path_str = match utils::path_to_str(path) {
-                Ok(s) => s.to_string(),
-                Err(e) => {
-                    eprintln!("\nWarning: skipping file with invalid UTF-8 path: {}", e);
-                    invalid_paths += 1;
-                    continue;
-                }
-            };
+            let path_str = utils::path_to_db(path).into_owned();
 
             db::mark_visited(conn, &path_str)?;
+            batch.tick()?;
 
+            let is_archive = archive::is_archive(path);
             if db::should_update_file(conn, path, modified)? {
-                match hashing::compute_file_hash(path) {
-                    Ok(hash) => {
-                        let modified_secs =
-                            modified.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
-                        db::upsert_file(conn, path, &hash, size as i64, modified_secs)?;
-                        if let Some(parent) = path.parent() {
-                            files_by_dir
-                                .entry(parent.to_path_buf())
-                                .or_default()
-                                .push(FileEntry {
-                                    path: path_str,
-                                    hash,
-                                    size,
-                                });
-                        }
+                let modified_secs =
+                    modified.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+                // Members read from an earlier version of the archive are out
+                // of date, whether or not this scan reads them again
+                if is_archive {
+                    db::delete_archive_members(conn, &path_str)?;
+                    if opts.scan_archives {
+                        archives.push((path.to_path_buf(), path_str.clone(), modified_secs));
+                    }
+                }
+                let untracked = db::get_file(conn, path)?.is_none();
+                jobs.push(HashJob {
+                    path: path.to_path_buf(),
+                    path_str,
+                    size,
+                    modified_secs,
+                    partial: None,
+                    stored: None,
+                    untracked,
+                    identity,
+                    stat,
+                    via_link: entry.via_link,
+                });
+                continue;
+            }
+
+            processed += 1;
+            count_scanned(size);
+            on_progress(processed, total_files, &file_name(path));
+
+            // An unchanged archive keeps its members; one never read before
+            // is read now
+            if is_archive && db::mark_members_visited(conn, &path_str)? == 0 && opts.scan_archives {
+                let modified_secs =
+                    modified.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+                archives.push((path.to_path_buf(), path_str.clone(), modified_secs));
+            }
+
+            // File unchanged — load hash and size from the DB cache.
+            // We must use the cached hash here; re-hashing would give the same
+            // result but waste I/O, and more importantly, the hash already in the
+            // DB is what all other records (directory hashes, duplicates) refer to.
+            if let Some(record) = db::get_file(conn, path)? {
+                // Files hashed before --xattr-cache was given get the
+                // attribute too
+                if opts.xattr_cache && !hashing::is_provisional(&record.hash) {
+                    let _ = xattr::store_hash(path, &metadata, opts.hash.algorithm, &record.hash);
+                }
+                // Rows recorded before device/inode were tracked pick them up here
+                if let Some((device, inode)) = identity {
+                    if record.device != Some(device) || record.inode != Some(inode) {
+                        db::update_file_identity(conn, path, device, inode)?;
                     }
-                    Err(e) => eprintln!("Error hashing file {:?}: {}", path, e),
                 }
+                if let Some(stat) = stat.filter(|s| record.stat.as_ref() != Some(s)) {
+                    db::update_file_stat(conn, path, &stat)?;
+                }
+                if record.via_link != entry.via_link {
+                    db::update_file_via_link(conn, path, entry.via_link)?;
+                }
+                if let Some(parent) = path.parent() {
+                    files_by_dir
+                        .entry(parent.to_path_buf())
+                        .or_default()
+                        .push(FileEntry {
+                            path: path_str,
+                            hash: record.hash,
+                            size: record.size as u64,
+                        });
+                }
+            }
+        }
+    }
+    drop(walking);
+    tracing::debug!(unchanged = processed, to_hash = jobs.len(), "walked {}", root.display());
+
+    let mut changed_elsewhere = Vec::new();
+    let mut changed = Vec::new();
+    let mut moved = 0usize;
+    let jobs = if opts.cancelled() {
+        Vec::new()
+    } else {
+        detect_moves(conn, jobs, opts, |job, old| {
+            batch.tick()?;
+            processed += 1;
+            count_scanned(job.size);
+            on_progress(processed, total_files, &file_name(&job.path));
+            let old_path = utils::path_from_db(&old.path);
+            tracing::debug!("moved {} -> {}", old_path.display(), job.path.display());
+            db::move_file(conn, &old_path, &job.path)?;
+            if let Some((device, inode)) = job.identity {
+                db::update_file_identity(conn, &job.path, device, inode)?;
+            }
+            if let Some(stat) = &job.stat {
+                db::update_file_stat(conn, &job.path, stat)?;
+            }
+            db::update_file_via_link(conn, &job.path, job.via_link)?;
+            moved += 1;
+            changed.push(job.path.clone());
+            if old_path.starts_with(root) {
+                changed.push(old_path);
             } else {
-                // File unchanged — load hash and size from the DB cache.
-                // We must use the cached hash here; re-hashing would give the same
-                // result but waste I/O, and more importantly, the hash already in the
-                // DB is what all other records (directory hashes, duplicates) refer to.
-                if let Some(record) = db::get_file(conn, path)? {
-                    if let Some(parent) = path.parent() {
-                        files_by_dir
-                            .entry(parent.to_path_buf())
-                            .or_default()
-                            .push(FileEntry {
-                                path: path_str,
-                                hash: record.hash,
-                                size: record.size as u64,
-                            });
+                changed_elsewhere.push(old_path);
+            }
+            if let Some(parent) = job.path.parent() {
+                files_by_dir
+                    .entry(parent.to_path_buf())
+                    .or_default()
+                    .push(FileEntry {
+                        path: job.path_str,
+                        hash: old.hash,
+                        size: job.size,
+                    });
+            }
+            Ok(())
+        })?
+    };
+
+    let mut finish = |job: HashJob, result: Result<String>| -> Result<()> {
+        batch.tick()?;
+        let hash = match result {
+            Ok(hash) => hash,
+            Err(e) => {
+                tracing::debug!("could not hash {}: {:#}", job.path.display(), e);
+                errors.push(ScanError::hashing(&job.path, &e));
+                if job.stored.is_none() {
+                    processed += 1;
+                    count_scanned(job.size);
+                    on_progress(processed, total_files, &file_name(&job.path));
+                }
+                return Ok(());
+            }
+        };
+
+        if job.stored.is_some() {
+            // An existing row upgraded from a provisional hash. Rows under this
+            // root were already loaded into files_by_dir from the cache.
+            db::update_file_hash(conn, &job.path, &hash)?;
+            opts.hashed(&job.path, job.size, &hash);
+            if job.path.starts_with(root) {
+                changed.push(job.path.clone());
+                let siblings = job.path.parent().and_then(|p| files_by_dir.get_mut(p));
+                for file in siblings.into_iter().flatten() {
+                    if file.path == job.path_str {
+                        file.hash = hash.clone();
                     }
                 }
+            } else {
+                changed_elsewhere.push(job.path.clone());
+            }
+        } else {
+            processed += 1;
+            count_scanned(job.size);
+            on_progress(processed, total_files, &file_name(&job.path));
+            db::upsert_file(conn, &job.path, &hash, job.size as i64, job.modified_secs)?;
+            opts.hashed(&job.path, job.size, &hash);
+            changed.push(job.path.clone());
+            if let Some(parent) = job.path.parent() {
+                files_by_dir
+                    .entry(parent.to_path_buf())
+                    .or_default()
+                    .push(FileEntry {
+                        path: job.path_str.clone(),
+                        hash,
+                        size: job.size,
+                    });
+            }
+        }
+
+        if let Some(partial) = &job.partial {
+            db::update_partial_hash(conn, &job.path, partial)?;
+        }
+        if let Some((device, inode)) = job.identity {
+            db::update_file_identity(conn, &job.path, device, inode)?;
+        }
+        if let Some(stat) = &job.stat {
+            db::update_file_stat(conn, &job.path, stat)?;
+        }
+        if job.via_link {
+            db::update_file_via_link(conn, &job.path, true)?;
+        }
+        Ok(())
+    };
+
+    let hashing = tracing::info_span!("hash files", root = %root.display()).entered();
+    let jobs = if opts.cancelled() {
+        Vec::new()
+    } else if opts.prefilter {
+        prefilter(conn, jobs, opts, &mut finish)?
+    } else {
+        jobs
+    };
+    hash_in_parallel(
+        jobs,
+        opts,
+        |job| hash_file(&job.path, job.size, &mut job.partial, opts),
+        &mut finish,
+    )?;
+    drop(hashing);
+    if !opts.cancelled() {
+        read_archives(conn, archives, opts, &mut batch, &mut errors)?;
+    }
+    batch.commit()?;
+
+    Ok(FilesPass {
+        errors,
+        changed_elsewhere,
+        directories,
+        changed,
+        moved,
+        interrupted: opts.cancelled(),
+    })
+}
+
+/// The content hash of the file at `path`, through its extended attribute
+/// with `opts.xattr_cache`. `partial` is filled in too when it isn't known
+/// yet, so a later scan can recognise the file if it moves.
+pub(crate) fn hash_file(
+    path: &Path,
+    size: u64,
+    partial: &mut Option<String>,
+    opts: &ScanOptions,
+) -> Result<String> {
+    tracing::trace!(size, "hashing {}", path.display());
+    let hash = if opts.xattr_cache {
+        xattr::hash_file(path, &opts.hash)?
+    } else {
+        hashing::compute_file_hash(path, &opts.hash)?
+    };
+    if partial.is_none() {
+        *partial = if size <= 2 * hashing::PARTIAL_CHUNK_SIZE {
+            Some(hash.clone())
+        } else {
+            hashing::compute_partial_hash(path, size, &opts.hash).ok()
+        };
+    }
+    Ok(hash)
+}
+
+/// Hash the files inside each of `archives` on a pool of `opts.threads`
+/// workers and store them as members. Members don't count towards directory
+/// hashes: a directory holding an archive is the same directory whether or
+/// not the archive was looked into. An archive that can't be read is
+/// recorded in `errors` and keeps no members.
+pub(crate) fn read_archives(
+    conn: &Connection,
+    archives: Vec<(PathBuf, String, i64)>,
+    opts: &ScanOptions,
+    batch: &mut db::WriteBatch,
+    errors: &mut Vec<ScanError>,
+) -> Result<()> {
+    if archives.is_empty() {
+        return Ok(());
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.threads)
+        .build()?;
+    let read: Vec<_> = pool.install(|| {
+        archives
+            .into_par_iter()
+            .filter(|_| !opts.cancelled())
+            .map(|(path, path_str, modified)| {
+                let members = archive::hash_members(&path, &opts.hash)
+                    .context("couldn't read the files inside");
+                (path, path_str, modified, members)
+            })
+            .collect()
+    });
+    for (path, path_str, modified, members) in read {
+        let members = match members {
+            Ok(members) => members,
+            Err(e) => {
+                errors.push(ScanError::hashing(&path, &e));
+                continue;
             }
+        };
+        for member in members {
+            let member_path = archive::member_path(&path_str, &member.name);
+            db::insert_archive_member(
+                conn,
+                &member_path,
+                &path_str,
+                &member.hash,
+                member.size as i64,
+                modified,
+            )?;
+            db::mark_visited(conn, &member_path)?;
+            batch.tick()?;
+        }
+    }
+    Ok(())
+}
+
+/// Move detection: an untracked file whose size and mtime match a row whose
+/// file no longer exists is probably that file under a new name. The match is
+/// confirmed by partial hash, and `on_moved(job, old_row)` repoints the row
+/// instead of the file being hashed again.
+///
+/// Returns the jobs that still need hashing. Partial hashes computed here stay
+/// on the jobs, so nothing is read twice. Rows without a partial hash (files
+/// `--prefilter` never read) can't be confirmed and are never matched.
+fn detect_moves(
+    conn: &Connection,
+    jobs: Vec<HashJob>,
+    opts: &ScanOptions,
+    mut on_moved: impl FnMut(HashJob, db::FileRecord) -> Result<()>,
+) -> Result<Vec<HashJob>> {
+    let mut remaining = Vec::new();
+    let mut candidates: HashMap<String, Vec<db::FileRecord>> = HashMap::new();
+    let mut to_probe = Vec::new();
+
+    for job in jobs {
+        // Empty files are free to hash and all look alike
+        if !job.untracked || job.size == 0 {
+            remaining.push(job);
+            continue;
+        }
+        let rows: Vec<db::FileRecord> = db::files_with_size(conn, job.size as i64)?
+            .into_iter()
+            .filter(|r| r.modified == job.modified_secs && r.partial_hash.is_some())
+            .filter(|r| !utils::path_from_db(&r.path).exists())
+            .collect();
+        if rows.is_empty() {
+            remaining.push(job);
+        } else {
+            candidates.insert(job.path_str.clone(), rows);
+            to_probe.push(job);
         }
     }
-    Ok(invalid_paths)
+
+    // Each old row can only have moved to one place.
+    let mut claimed: HashSet<String> = HashSet::new();
+    hash_in_parallel(
+        to_probe,
+        opts,
+        |job| hashing::compute_partial_hash(&job.path, job.size, &opts.hash),
+        |mut job, result| {
+            let Ok(partial) = result else {
+                // The full hash will hit (and report) the same error
+                remaining.push(job);
+                return Ok(());
+            };
+            let matched = candidates
+                .remove(&job.path_str)
+                .unwrap_or_default()
+                .into_iter()
+                .find(|r| {
+                    r.partial_hash.as_deref() == Some(partial.as_str())
+                        && !claimed.contains(&r.path)
+                });
+            job.partial = Some(partial);
+            match matched {
+                Some(old) => {
+                    claimed.insert(old.path.clone());
+                    on_moved(job, old)
+                }
+                None => {
+                    remaining.push(job);
+                    Ok(())
+                }
+            }
+        },
+    )?;
+    Ok(remaining)
+}
+
+/// The `--prefilter` pipeline: settle as many jobs as possible without reading
+/// whole files, and return the ones that still need a full hash.
+///
+/// A file whose size matches nothing else (in the DB or this walk) is unique
+/// and gets a provisional hash straight away. Files that do share a size have
+/// their partial hash computed; those still colliding go on to a full hash,
+/// the rest are provisional too. DB rows a new file collides with are pulled
+/// in the same way, so a provisional row is upgraded as soon as a possible
+/// duplicate turns up.
+fn prefilter(
+    conn: &Connection,
+    jobs: Vec<HashJob>,
+    opts: &ScanOptions,
+    finish: &mut impl FnMut(HashJob, Result<String>) -> Result<()>,
+) -> Result<Vec<HashJob>> {
+    let job_paths: HashSet<String> = jobs.iter().map(|j| j.path_str.clone()).collect();
+    let mut by_size: HashMap<u64, Vec<HashJob>> = HashMap::new();
+    for job in jobs {
+        by_size.entry(job.size).or_default().push(job);
+    }
+
+    // Rows whose partial hash is already known and whose hash is real.
+    let mut partial_counts: HashMap<(u64, String), usize> = HashMap::new();
+    let mut candidates: Vec<HashJob> = Vec::new();
+
+    for (size, group) in by_size {
+        // Rows for the jobs themselves are about to be replaced, so skip them.
+        let rows: Vec<db::FileRecord> = db::files_with_size(conn, size as i64)?
+            .into_iter()
+            .filter(|r| !job_paths.contains(&r.path))
+            .collect();
+
+        if group.len() == 1 && rows.is_empty() {
+            for job in group {
+                let hash = hashing::provisional_hash(&job.path_str);
+                finish(job, Ok(hash))?;
+            }
+            continue;
+        }
+
+        for row in rows {
+            match row.partial_hash {
+                Some(partial) if !hashing::is_provisional(&row.hash) => {
+                    *partial_counts.entry((size, partial)).or_default() += 1;
+                }
+                partial => {
+                    candidates.extend(stored_job(row.path, row.hash, partial, size, row.modified))
+                }
+            }
+        }
+        candidates.extend(group);
+    }
+
+    let (mut ready, to_hash): (Vec<HashJob>, Vec<HashJob>) =
+        candidates.into_iter().partition(|j| j.partial.is_some());
+    hash_in_parallel(
+        to_hash,
+        opts,
+        |job| hashing::compute_partial_hash(&job.path, job.size, &opts.hash),
+        |mut job, result| match result {
+            Ok(partial) => {
+                job.partial = Some(partial);
+                ready.push(job);
+                Ok(())
+            }
+            Err(e) => finish(job, Err(e)),
+        },
+    )?;
+    if opts.cancelled() {
+        // Some partial hashes are missing, so the collision counts below
+        // would be wrong; leave the rest for the resumed scan.
+        return Ok(Vec::new());
+    }
+
+    for job in &ready {
+        if let Some(partial) = &job.partial {
+            *partial_counts
+                .entry((job.size, partial.clone()))
+                .or_default() += 1;
+        }
+    }
+
+    let mut full = Vec::new();
+    for job in ready {
+        let Some(partial) = job.partial.clone() else {
+            continue;
+        };
+        let colliding = partial_counts[&(job.size, partial.clone())] > 1;
+        let needs_hash = job.stored.as_deref().is_none_or(hashing::is_provisional);
+
+        if !needs_hash {
+            // A real hash we only lacked the partial for
+            db::update_partial_hash(conn, &job.path, &partial)?;
+        } else if !colliding {
+            match job.stored {
+                Some(_) => db::update_partial_hash(conn, &job.path, &partial)?,
+                None => {
+                    let hash = hashing::provisional_hash(&job.path_str);
+                    finish(job, Ok(hash))?;
+                }
+            }
+        } else if job.size <= 2 * hashing::PARTIAL_CHUNK_SIZE {
+            // The partial hash already covered the whole file
+            finish(job, Ok(partial))?;
+        } else {
+            full.push(job);
+        }
+    }
+    Ok(full)
+}
+
+/// Build a job for an existing DB row the prefilter needs to look at again,
+/// or `None` if the file is gone or has changed since it was recorded (the
+/// next scan of its own root will pick it up).
+fn stored_job(
+    path_str: String,
+    hash: String,
+    partial: Option<String>,
+    size: u64,
+    modified_secs: i64,
+) -> Option<HashJob> {
+    let path = utils::path_from_db(&path_str);
+    if !path.is_file() || utils::mtime(&path).ok()? != modified_secs {
+        return None;
+    }
+    Some(HashJob {
+        path,
+        path_str,
+        size,
+        modified_secs,
+        partial,
+        stored: Some(hash),
+        untracked: false,
+        identity: None,
+        stat: None,
+        // Only ever set, so the recorded value stands
+        via_link: false,
+    })
+}
+
+/// Run `hash_fn` over every job on a pool of `opts.threads` workers, handing
+/// each result to `on_result` on the calling thread as soon as it is ready. If
+/// `on_result` fails, the workers stop picking up new jobs and the error is
+/// returned.
+pub(crate) fn hash_in_parallel<J: Send>(
+    jobs: Vec<J>,
+    opts: &ScanOptions,
+    hash_fn: impl Fn(&mut J) -> Result<String> + Send + Sync,
+    mut on_result: impl FnMut(J, Result<String>) -> Result<()>,
+) -> Result<()> {
+    if jobs.is_empty() {
+        return Ok(());
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.threads)
+        .build()?;
+    // Bounded, so finished results never pile up ahead of the DB writer and a
+    // cancel stops the workers within a job or two.
+    let (tx, rx) = mpsc::sync_channel(pool.current_num_threads());
+
+    thread::scope(|s| {
+        s.spawn(move || {
+            pool.install(|| {
+                // A send error means the receiver bailed out; stop hashing.
+                let _ = jobs.into_par_iter().try_for_each_with(tx, |tx, mut job| {
+                    if opts.cancelled() {
+                        return Err(());
+                    }
+                    let result = hash_fn(&mut job);
+                    tx.send((job, result)).map_err(|_| ())
+                });
+            });
+        });
+        for (job, result) in rx {
+            on_result(job, result)?;
+        }
+        Ok(())
+    })
+}
+
+pub(crate) fn file_name(path: &Path) -> Cow<'_, str> {
+    path.file_name()
+        .map_or(Cow::Borrowed("<unknown>"), |n| n.to_string_lossy())
 }
 
 /// Second pass: compute and store directory hashes bottom-up (deepest first),
 /// so each child directory's hash is committed to the DB before its parent is hashed.
-fn compute_directory_hashes(
+/// Directories in `unreadable` are left out: with their contents unknown,
+/// they must not be recorded as empty.
+///
+/// With `changed`, only the directories that hold a changed path, directly
+/// or further down, are rehashed: the ones above those paths, new
+/// directories, and the parents of directories that are gone. Every other
+/// directory keeps its stored record. Without it, every directory is
+/// rehashed. Returns the number of directories rehashed.
+pub(crate) fn compute_directory_hashes(
     conn: &Connection,
     root: &Path,
+    directories: &[PathBuf],
     files_by_dir: &HashMap<PathBuf, Vec<FileEntry>>,
-) -> Result<()> {
-    let mut dir_entries: Vec<PathBuf> = WalkDir::new(root)
-        .follow_links(false)
+    unreadable: &HashSet<&str>,
+    changed: Option<&[PathBuf]>,
+    algorithm: hashing::HashAlgorithm,
+) -> Result<usize> {
+    let include_empty = directories_count_empty_files(conn)?;
+    let directories: Vec<&PathBuf> = directories
+        .iter()
+        .filter(|d| !unreadable.contains(utils::path_to_db(d).as_ref()))
+        .collect();
+    let mut stored: HashMap<PathBuf, db::DirRecord> = db::directories_under(conn, root)?
         .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.path().is_dir())
-        .map(|e| e.path().to_path_buf())
+        .map(|r| (utils::path_from_db(&r.path), r))
         .collect();
 
+    let mut dirty: HashSet<PathBuf> = HashSet::new();
+    match changed {
+        None => dirty.extend(directories.iter().map(|d| d.to_path_buf())),
+        Some(changed) => {
+            let listed: HashSet<&Path> = directories.iter().map(|d| d.as_path()).collect();
+            for path in changed {
+                mark_above(&mut dirty, root, path);
+            }
+            for dir in stored.keys().filter(|d| !listed.contains(d.as_path())) {
+                mark_above(&mut dirty, root, dir);
+            }
+            for dir in &directories {
+                if !stored.contains_key(dir.as_path()) {
+                    mark_above(&mut dirty, root, dir);
+                    dirty.insert(dir.to_path_buf());
+                }
+            }
+        }
+    }
+
+    // Each directory's record is kept here for its parent, so children never
+    // have to be looked up in the DB. Unchanged directories contribute the
+    // record already stored.
+    let mut dirs_by_parent: HashMap<PathBuf, Vec<db::DirRecord>> = HashMap::new();
+    let mut to_hash = Vec::new();
+    for dir in directories {
+        if dirty.contains(dir.as_path()) {
+            to_hash.push(dir);
+        } else if let (Some(record), Some(parent)) = (stored.remove(dir.as_path()), dir.parent()) {
+            dirs_by_parent
+                .entry(parent.to_path_buf())
+                .or_default()
+                .push(record);
+        }
+    }
+
     // Deepest first — children are committed before parents are hashed
-    dir_entries.sort_by(|a, b| b.components().count().cmp(&a.components().count()));
+    to_hash.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+
+    let rehashed = to_hash.len();
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    for dir_path in to_hash {
+        let record = hashing::compute_directory_hash(
+            conn,
+            dir_path,
+            files_by_dir,
+            &dirs_by_parent,
+            algorithm,
+            include_empty,
+        )?;
+        if let Some(parent) = dir_path.parent() {
+            dirs_by_parent
+                .entry(parent.to_path_buf())
+                .or_default()
+                .push(record);
+        }
+        batch.tick()?;
+    }
+    batch.commit()?;
+    Ok(rehashed)
+}
 
-    for dir_path in dir_entries {
-        hashing::compute_directory_hash(conn, &dir_path, files_by_dir)?;
+/// Add the directories above `path`, up to `root`, to `dirty`. Every marked
+/// directory's ancestors are marked too, so the climb stops at the first one
+/// already marked.
+fn mark_above(dirty: &mut HashSet<PathBuf>, root: &Path, path: &Path) {
+    for dir in path.ancestors().skip(1) {
+        if !dir.starts_with(root) || !dirty.insert(dir.to_path_buf()) {
+            break;
+        }
+    }
+}
+
+/// Recompute the hash of every recorded directory above `paths`, deepest
+/// first, from the DB alone. Used when the prefilter upgrades, or a move pulls
+/// in, files that live outside the root being scanned, and by `clean` after it drops rows.
+/// Returns the number of directories rehashed.
+pub fn rehash_ancestors(
+    conn: &Connection,
+    paths: &[PathBuf],
+    algorithm: hashing::HashAlgorithm,
+) -> Result<usize> {
+    let mut dirs: HashSet<PathBuf> = HashSet::new();
+    for path in paths {
+        dirs.extend(path.ancestors().skip(1).map(Path::to_path_buf));
+    }
+    // A missing ancestor doesn't stop the climb: the parent of a deleted
+    // directory is usually gone too, but its grandparent may still be recorded.
+    let mut recorded = Vec::new();
+    for dir in dirs {
+        if db::get_directory(conn, &dir)?.is_some() {
+            recorded.push(dir);
+        }
+    }
+    rehash_directories(conn, recorded, algorithm)
+}
+
+/// Recompute the hash of each of `dirs` from the DB alone, deepest first, so
+/// each child's new hash is stored before its parent is hashed. A directory
+/// without a record gets one. Returns the number of directories rehashed.
+pub(crate) fn rehash_directories(
+    conn: &Connection,
+    mut dirs: Vec<PathBuf>,
+    algorithm: hashing::HashAlgorithm,
+) -> Result<usize> {
+    dirs.sort_by(|a, b| {
+        let depth = |d: &PathBuf| d.components().count();
+        depth(b).cmp(&depth(a)).then_with(|| a.cmp(b))
+    });
+    dirs.dedup();
+    let include_empty = directories_count_empty_files(conn)?;
+
+    let mut files_by_dir: HashMap<PathBuf, Vec<FileEntry>> = HashMap::new();
+    for dir in &dirs {
+        let files = db::files_in_directory(conn, dir)?
+            .into_iter()
+            .map(|r| FileEntry {
+                path: r.path,
+                hash: r.hash,
+                size: r.size as u64,
+            })
+            .collect();
+        files_by_dir.insert(dir.clone(), files);
+    }
+    // Usually only a handful of directories, so their children are read back
+    // from the DB.
+    for dir in &dirs {
+        let mut dirs_by_parent = HashMap::new();
+        dirs_by_parent.insert(dir.clone(), db::child_directories(conn, dir)?);
+        hashing::compute_directory_hash(
+            conn,
+            dir,
+            &files_by_dir,
+            &dirs_by_parent,
+            algorithm,
+            include_empty,
+        )?;
+    }
+    Ok(dirs.len())
+}
+
+/// Whether the directory hashes in the database take empty files into
+/// account. They don't unless `--include-empty` asked for it: every empty
+/// file shares one hash, and a stray one would keep two otherwise identical
+/// directories from matching.
+pub(crate) fn directories_count_empty_files(conn: &Connection) -> Result<bool> {
+    Ok(db::get_meta(conn, EMPTY_FILES_META_KEY)?.is_some())
+}
+
+/// Have the directory hashes take empty files into account, or leave them
+/// out, as `include_empty` says. When that changes how the stored hashes were
+/// made, every recorded directory is rehashed from the DB, so that they all
+/// agree. Returns the number of directories rehashed.
+pub(crate) fn count_empty_files(conn: &Connection, include_empty: bool) -> Result<usize> {
+    if directories_count_empty_files(conn)? == include_empty {
+        return Ok(0);
+    }
+    // One transaction, so the setting and the hashes made by it change together
+    let batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    if include_empty {
+        db::set_meta(conn, EMPTY_FILES_META_KEY, "1")?;
+    } else {
+        db::delete_meta(conn, EMPTY_FILES_META_KEY)?;
+    }
+    let Some(algorithm) = hashing::recorded_algorithm(conn)? else {
+        return batch.commit().map(|()| 0);
+    };
+    let dirs = db::all_directory_paths(conn)?
+        .iter()
+        .map(|p| utils::path_from_db(p))
+        .collect();
+    let rehashed = rehash_directories(conn, dirs, algorithm)?;
+    batch.commit()?;
+    Ok(rehashed)
+}
+
+/// What a run fails with once a scan was interrupted (by Ctrl-C): what was
+/// hashed is kept, and `--resume` carries on from there.
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("the scan was interrupted")
     }
-    Ok(())
 }
 
+impl std::error::Error for Interrupted {}
+
 /// Result returned by `scan_directory`.
 /// Stale-entry handling (prompting + deletion) is left to the caller.
 #[derive(Debug)]
 pub struct ScanResult {
-    pub invalid_paths: usize,
+    /// Entries that could not be listed, read or hashed; they were left out
+    /// of the DB, and an unreadable directory out of its parent's hash.
+    pub errors: Vec<ScanError>,
     pub stale_count: i64,
     pub root_str: String,
+    /// The scan was cancelled part-way; `stale_count` is meaningless and
+    /// directory hashes under this root were not updated.
+    pub interrupted: bool,
+    /// Files found under a new path and updated in place instead of rehashed.
+    pub moved: usize,
 }
 
 /// Scan `root`: hash new/changed files, update the DB, compute directory hashes.
@@ -139,22 +1043,90 @@ pub fn scan_directory(
     conn: &Connection,
     root: &Path,
     total_files: usize,
+    opts: &ScanOptions,
     on_progress: impl Fn(usize, usize, &str),
 ) -> Result<ScanResult> {
+    let _scan = tracing::info_span!("scan", root = %root.display()).entered();
     db::init_visited_files(conn)?;
+    let root_str = utils::path_to_db(root).into_owned();
+    let started = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as i64;
+
+    // Set until the directory hashes under this root are known to be up to
+    // date, so a scan that was interrupted (or left stale rows behind, whose
+    // files may come back) is followed by a full directory pass.
+    let pending_key = format!("{PENDING_DIRECTORIES_PREFIX}{root_str}");
+    let full_pass = db::get_meta(conn, &pending_key)?.is_some();
+    db::set_meta(conn, &pending_key, "1")?;
 
     let mut files_by_dir: HashMap<PathBuf, Vec<FileEntry>> = HashMap::new();
-    let invalid_paths = scan_files(conn, root, total_files, &mut files_by_dir, on_progress)?;
-
-    let root_str = utils::path_to_str(root)?.to_string();
-    let stale_count = db::stale_file_count(conn, &root_str)?;
-
-    compute_directory_hashes(conn, root, &files_by_dir)?;
+    let pass = scan_files(
+        conn,
+        root,
+        total_files,
+        opts,
+        &mut files_by_dir,
+        on_progress,
+    )?;
+
+    db::clear_scan_errors(conn, &root_str)?;
+    for error in &pass.errors {
+        db::log_scan_error(
+            conn,
+            started,
+            &root_str,
+            &error.path,
+            error.kind,
+            &error.message,
+        )?;
+    }
+    if pass.interrupted {
+        return Ok(ScanResult {
+            errors: pass.errors,
+            stale_count: 0,
+            root_str,
+            interrupted: true,
+            moved: pass.moved,
+        });
+    }
+    let stale = db::stale_file_paths(conn, &root_str)?;
+    let stale_count = stale.len() as i64;
+
+    let unreadable: HashSet<&str> = pass.errors.iter().map(|e| e.path.as_str()).collect();
+    let mut changed = pass.changed;
+    changed.extend(stale.iter().map(|p| utils::path_from_db(p)));
+    changed.extend(pass.errors.iter().map(|e| utils::path_from_db(&e.path)));
+    let directories =
+        tracing::info_span!("hash directories", root = %root.display()).entered();
+    compute_directory_hashes(
+        conn,
+        root,
+        &pass.directories,
+        &files_by_dir,
+        &unreadable,
+        (!full_pass).then_some(changed.as_slice()),
+        opts.hash.algorithm,
+    )?;
+    rehash_ancestors(conn, &pass.changed_elsewhere, opts.hash.algorithm)?;
+    drop(directories);
+    if stale_count == 0 {
+        db::delete_meta(conn, &pending_key)?;
+    }
+    tracing::info!(
+        errors = pass.errors.len(),
+        moved = pass.moved,
+        stale = stale_count,
+        "scanned {}",
+        root.display()
+    );
 
     Ok(ScanResult {
-        invalid_paths,
+        errors: pass.errors,
         stale_count,
         root_str,
+        interrupted: false,
+        moved: pass.moved,
     })
 }
 
@@ -170,6 +1142,7 @@ pub fn scan_directory(
 mod tests {
     use super::*;
     use crate::db::{self, setup_schema};
+    use crate::xattr;
     use rusqlite::Connection;
     use std::fs;
     use tempfile::tempdir;
@@ -218,7 +1191,15 @@ mod tests {
         let conn = open_test_db();
         db::init_visited_files(&conn).unwrap();
         let mut files_by_dir = HashMap::new();
-        scan_files(&conn, dir.path(), 1, &mut files_by_dir, |_, _, _| ()).unwrap();
+        scan_files(
+            &conn,
+            dir.path(),
+            1,
+            &ScanOptions::default(),
+            &mut files_by_dir,
+            |_, _, _| (),
+        )
+        .unwrap();
 
         let files = files_by_dir
             .get(dir.path())
@@ -240,7 +1221,15 @@ mod tests {
         let conn = open_test_db();
         db::init_visited_files(&conn).unwrap();
         let mut files_by_dir = HashMap::new();
-        scan_files(&conn, dir.path(), 2, &mut files_by_dir, |_, _, _| ()).unwrap();
+        scan_files(
+            &conn,
+            dir.path(),
+            2,
+            &ScanOptions::default(),
+            &mut files_by_dir,
+            |_, _, _| (),
+        )
+        .unwrap();
 
         let visited_count: i64 = conn
             .query_row("SELECT COUNT(*) FROM visited_files", [], |r| r.get(0))
@@ -262,7 +1251,15 @@ mod tests {
         // First scan — stores real hash and modified time
         db::init_visited_files(&conn).unwrap();
         let mut files_by_dir = HashMap::new();
-        scan_files(&conn, dir.path(), 1, &mut files_by_dir, |_, _, _| ()).unwrap();
+        scan_files(
+            &conn,
+            dir.path(),
+            1,
+            &ScanOptions::default(),
+            &mut files_by_dir,
+            |_, _, _| (),
+        )
+        .unwrap();
 
         // Overwrite the hash with a sentinel, keeping modified time unchanged
         conn.execute(
@@ -274,7 +1271,15 @@ mod tests {
         // Second scan — modified time hasn't changed, so cache should be used
         db::init_visited_files(&conn).unwrap();
         let mut files_by_dir2 = HashMap::new();
-        scan_files(&conn, dir.path(), 1, &mut files_by_dir2, |_, _, _| ()).unwrap();
+        scan_files(
+            &conn,
+            dir.path(),
+            1,
+            &ScanOptions::default(),
+            &mut files_by_dir2,
+            |_, _, _| (),
+        )
+        .unwrap();
 
         let stored_hash = get_file_hash(&conn, &file);
         assert_eq!(
@@ -298,7 +1303,15 @@ mod tests {
         // First scan
         db::init_visited_files(&conn).unwrap();
         let mut files_by_dir = HashMap::new();
-        scan_files(&conn, dir.path(), 1, &mut files_by_dir, |_, _, _| ()).unwrap();
+        scan_files(
+            &conn,
+            dir.path(),
+            1,
+            &ScanOptions::default(),
+            &mut files_by_dir,
+            |_, _, _| (),
+        )
+        .unwrap();
 
         // Store a wrong hash and wind back the modified time in the DB so
         // should_update_file sees a mismatch on the next scan
@@ -311,7 +1324,15 @@ mod tests {
         // Second scan — modified time mismatch triggers re-hash
         db::init_visited_files(&conn).unwrap();
         let mut files_by_dir2 = HashMap::new();
-        scan_files(&conn, dir.path(), 1, &mut files_by_dir2, |_, _, _| ()).unwrap();
+        scan_files(
+            &conn,
+            dir.path(),
+            1,
+            &ScanOptions::default(),
+            &mut files_by_dir2,
+            |_, _, _| (),
+        )
+        .unwrap();
 
         let stored_hash = get_file_hash(&conn, &file);
         assert_ne!(
@@ -320,10 +1341,455 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_scan_files_multiple_threads_hashes_every_file() {
+        let dir = tempdir().unwrap();
+        for i in 0..50 {
+            fs::write(dir.path().join(format!("f{i}.txt")), format!("content {i}")).unwrap();
+        }
+
+        let conn = open_test_db();
+        db::init_visited_files(&conn).unwrap();
+        let mut files_by_dir = HashMap::new();
+        let opts = ScanOptions {
+            threads: 4,
+            ..ScanOptions::default()
+        };
+        scan_files(
+            &conn,
+            dir.path(),
+            50,
+            &opts,
+            &mut files_by_dir,
+            |_, _, _| (),
+        )
+        .unwrap();
+
+        assert_eq!(files_by_dir.get(dir.path()).unwrap().len(), 50);
+        for i in 0..50 {
+            let path = dir.path().join(format!("f{i}.txt"));
+            assert_eq!(
+                get_file_hash(&conn, &path),
+                hashing::compute_file_hash(&path, &hashing::HashOptions::default()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_scan_cancelled_up_front_hashes_nothing() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+
+        let conn = open_test_db();
+        let opts = ScanOptions::default();
+        opts.cancel.store(true, Ordering::Relaxed);
+        let result = scan_directory(&conn, dir.path(), 1, &opts, |_, _, _| ()).unwrap();
+
+        assert!(result.interrupted);
+        assert!(db::get_file(&conn, &dir.path().join("a.txt"))
+            .unwrap()
+            .is_none());
+        assert!(db::get_directory(&conn, dir.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_scan_cancelled_mid_hash_keeps_finished_files() {
+        // Cancel from the progress callback after the first hashed file; files
+        // already written stay in the DB and the resumed scan finishes the rest.
+        let dir = tempdir().unwrap();
+        for i in 0..20 {
+            fs::write(dir.path().join(format!("f{i}.txt")), format!("content {i}")).unwrap();
+        }
+
+        let conn = open_test_db();
+        let opts = ScanOptions {
+            threads: 1,
+            ..ScanOptions::default()
+        };
+        let cancel = opts.cancel.clone();
+        let result = scan_directory(&conn, dir.path(), 20, &opts, |_, _, _| {
+            cancel.store(true, Ordering::Relaxed)
+        })
+        .unwrap();
+        assert!(result.interrupted);
+        let kept = db::all_files(&conn).unwrap().len();
+        assert!((1..20).contains(&kept), "kept {kept} of 20 files");
+
+        let result =
+            scan_directory(&conn, dir.path(), 20, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert!(!result.interrupted);
+        assert_eq!(db::all_files(&conn).unwrap().len(), 20);
+        assert!(db::get_directory(&conn, dir.path()).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_on_hashed_is_told_of_each_file_hashed() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        fs::write(dir.path().join("b.txt"), "world!").unwrap();
+
+        let conn = open_test_db();
+        let seen = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = seen.clone();
+        let opts = ScanOptions {
+            on_hashed: Some(OnHashed(Arc::new(move |path, size, hash| {
+                let name = file_name(path).into_owned();
+                sink.lock().unwrap().push((name, size, hash.to_string()));
+            }))),
+            ..ScanOptions::default()
+        };
+        scan_directory(&conn, dir.path(), 2, &opts, |_, _, _| ()).unwrap();
+        let mut hashed = seen.lock().unwrap().clone();
+        hashed.sort();
+        assert_eq!(hashed.len(), 2);
+        assert_eq!((hashed[0].0.as_str(), hashed[0].1), ("a.txt", 5));
+        assert_eq!(hashed[0].2, get_file_hash(&conn, &dir.path().join("a.txt")));
+
+        // Unchanged files aren't hashed again
+        seen.lock().unwrap().clear();
+        scan_directory(&conn, dir.path(), 2, &opts, |_, _, _| ()).unwrap();
+        assert!(seen.lock().unwrap().is_empty());
+    }
+
+    // -----------------------------------------------------------------------
+    // prefilter
+    // -----------------------------------------------------------------------
+
+    fn prefilter_opts() -> ScanOptions {
+        ScanOptions {
+            prefilter: true,
+            ..ScanOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_prefilter_unique_size_gets_provisional_hash() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("a.txt");
+        fs::write(&file, "hello").unwrap();
+        fs::write(dir.path().join("b.txt"), "longer content").unwrap();
+
+        let conn = open_test_db();
+        scan_directory(&conn, dir.path(), 2, &prefilter_opts(), |_, _, _| ()).unwrap();
+
+        let record = db::get_file(&conn, &file).unwrap().unwrap();
+        assert!(hashing::is_provisional(&record.hash));
+        assert_eq!(
+            record.partial_hash, None,
+            "unique size should never be read"
+        );
+    }
+
+    #[test]
+    fn test_prefilter_duplicates_get_real_hash() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join("a.txt"), "same").unwrap();
+        fs::write(dir.path().join("b.txt"), "same").unwrap();
+        fs::write(dir.path().join("c.txt"), "diff").unwrap();
+
+        let conn = open_test_db();
+        scan_directory(&conn, dir.path(), 3, &prefilter_opts(), |_, _, _| ()).unwrap();
+
+        let hash_a = get_file_hash(&conn, &dir.path().join("a.txt"));
+        assert_eq!(hash_a, get_file_hash(&conn, &dir.path().join("b.txt")));
+        assert!(!hashing::is_provisional(&hash_a));
+        // Same size, different partial hash: ruled out without a full hash
+        assert!(hashing::is_provisional(&get_file_hash(
+            &conn,
+            &dir.path().join("c.txt")
+        )));
+    }
+
+    #[test]
+    fn test_prefilter_partial_collision_falls_through_to_full_hash() {
+        let dir = tempdir().unwrap();
+        let len = 4 * hashing::PARTIAL_CHUNK_SIZE as usize;
+        let mut a = vec![0u8; len];
+        let mut b = vec![0u8; len];
+        a[len / 2] = 1;
+        b[len / 2] = 2;
+        fs::write(dir.path().join("a.bin"), &a).unwrap();
+        fs::write(dir.path().join("b.bin"), &b).unwrap();
+
+        let conn = open_test_db();
+        scan_directory(&conn, dir.path(), 2, &prefilter_opts(), |_, _, _| ()).unwrap();
+
+        let opts = hashing::HashOptions::default();
+        for name in ["a.bin", "b.bin"] {
+            let path = dir.path().join(name);
+            assert_eq!(
+                get_file_hash(&conn, &path),
+                hashing::compute_file_hash(&path, &opts).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_prefilter_upgrades_provisional_row_from_earlier_scan() {
+        // The first scan sees only one copy, so it is stored provisionally. The
+        // second scan finds the other copy; both must end up with a real hash,
+        // and the first tree's directory hash must be refreshed to match.
+        let root = tempdir().unwrap();
+        let dir_a = root.path().join("a");
+        let dir_b = root.path().join("b");
+        fs::create_dir(&dir_a).unwrap();
+        fs::create_dir(&dir_b).unwrap();
+        fs::write(dir_a.join("file.txt"), "same content").unwrap();
+
+        let conn = open_test_db();
+        scan_directory(&conn, &dir_a, 1, &prefilter_opts(), |_, _, _| ()).unwrap();
+        assert!(hashing::is_provisional(&get_file_hash(
+            &conn,
+            &dir_a.join("file.txt")
+        )));
+
+        fs::write(dir_b.join("file.txt"), "same content").unwrap();
+        scan_directory(&conn, &dir_b, 1, &prefilter_opts(), |_, _, _| ()).unwrap();
+
+        let hash_a = get_file_hash(&conn, &dir_a.join("file.txt"));
+        assert!(!hashing::is_provisional(&hash_a));
+        assert_eq!(hash_a, get_file_hash(&conn, &dir_b.join("file.txt")));
+        assert_eq!(get_dir_hash(&conn, &dir_a), get_dir_hash(&conn, &dir_b));
+    }
+
+    #[test]
+    fn test_prefilter_identical_dirs_get_same_hash() {
+        let root = tempdir().unwrap();
+        let dir_a = root.path().join("a");
+        let dir_b = root.path().join("b");
+        fs::create_dir(&dir_a).unwrap();
+        fs::create_dir(&dir_b).unwrap();
+        fs::write(dir_a.join("file.txt"), "same content").unwrap();
+        fs::write(dir_b.join("file.txt"), "same content").unwrap();
+        fs::write(dir_a.join("only_a.txt"), "x").unwrap();
+
+        let conn = open_test_db();
+        scan_directory(&conn, root.path(), 3, &prefilter_opts(), |_, _, _| ()).unwrap();
+
+        assert_ne!(get_dir_hash(&conn, &dir_a), get_dir_hash(&conn, &dir_b));
+
+        fs::remove_file(dir_a.join("only_a.txt")).unwrap();
+        let conn = open_test_db();
+        scan_directory(&conn, root.path(), 2, &prefilter_opts(), |_, _, _| ()).unwrap();
+        assert_eq!(get_dir_hash(&conn, &dir_a), get_dir_hash(&conn, &dir_b));
+    }
+
     // -----------------------------------------------------------------------
     // compute_directory_hashes
     // -----------------------------------------------------------------------
 
+    #[test]
+    fn test_scan_detects_moved_file_without_rehashing() {
+        let dir = tempdir().unwrap();
+        let old = dir.path().join("a.txt");
+        fs::write(&old, "photo bytes").unwrap();
+        let conn = open_test_db();
+        scan_directory(&conn, dir.path(), 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        // A rehash would overwrite this sentinel
+        db::update_file_hash(&conn, &old, "sentinel").unwrap();
+
+        let new = dir.path().join("album").join("a.txt");
+        fs::create_dir(dir.path().join("album")).unwrap();
+        fs::rename(&old, &new).unwrap();
+        let result =
+            scan_directory(&conn, dir.path(), 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+
+        assert_eq!(result.moved, 1);
+        assert_eq!(get_file_hash(&conn, &new), "sentinel");
+        assert!(db::get_file(&conn, &old).unwrap().is_none());
+        assert_eq!(result.stale_count, 0);
+    }
+
+    #[test]
+    fn test_scan_same_size_and_mtime_with_different_content_is_not_a_move() {
+        let dir = tempdir().unwrap();
+        let old = dir.path().join("a.txt");
+        fs::write(&old, "aaaa").unwrap();
+        let conn = open_test_db();
+        scan_directory(&conn, dir.path(), 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+
+        let mtime = fs::metadata(&old).unwrap().modified().unwrap();
+        fs::remove_file(&old).unwrap();
+        let new = dir.path().join("b.txt");
+        fs::write(&new, "bbbb").unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&new)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        let result =
+            scan_directory(&conn, dir.path(), 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+
+        assert_eq!(result.moved, 0);
+        let expected = hashing::compute_file_hash(&new, &hashing::HashOptions::default()).unwrap();
+        assert_eq!(get_file_hash(&conn, &new), expected);
+        assert_eq!(result.stale_count, 1, "old row is left for stale cleanup");
+    }
+
+    #[test]
+    fn test_scan_move_from_another_root_rehashes_old_directory() {
+        let dir = tempdir().unwrap();
+        let first = dir.path().join("first");
+        let second = dir.path().join("second");
+        fs::create_dir_all(&first).unwrap();
+        fs::create_dir_all(&second).unwrap();
+        fs::write(first.join("a.txt"), "moving house").unwrap();
+        let conn = open_test_db();
+        scan_directory(&conn, &first, 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+
+        fs::rename(first.join("a.txt"), second.join("a.txt")).unwrap();
+        let result =
+            scan_directory(&conn, &second, 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+
+        assert_eq!(result.moved, 1);
+        assert_eq!(
+            get_dir_hash(&conn, &first),
+            hashing::HashAlgorithm::Sha256.empty_hash()
+        );
+    }
+
+    #[test]
+    fn test_scan_with_xattr_cache_trusts_and_writes_attributes() {
+        let dir = tempdir().unwrap();
+        let (forged, plain) = (dir.path().join("forged.txt"), dir.path().join("plain.txt"));
+        fs::write(&forged, "forged").unwrap();
+        fs::write(&plain, "plain").unwrap();
+        let opts = ScanOptions {
+            xattr_cache: true,
+            ..Default::default()
+        };
+        let algorithm = opts.hash.algorithm;
+        let metadata = fs::metadata(&forged).unwrap();
+        if xattr::store_hash(&forged, &metadata, algorithm, "from-xattr").is_err() {
+            // No user attributes on this filesystem (or platform)
+            return;
+        }
+
+        // A database that has never seen the files takes the attribute's word
+        let conn = open_test_db();
+        scan_directory(&conn, dir.path(), 1, &opts, |_, _, _| ()).unwrap();
+        assert_eq!(get_file_hash(&conn, &forged), "from-xattr");
+        let without = open_test_db();
+        scan_directory(
+            &without,
+            dir.path(),
+            1,
+            &ScanOptions::default(),
+            |_, _, _| (),
+        )
+        .unwrap();
+        assert_ne!(get_file_hash(&without, &forged), "from-xattr");
+
+        // The file the scan hashed itself now carries its hash, so another
+        // fresh database agrees without reading it
+        let expected = get_file_hash(&conn, &plain);
+        let metadata = fs::metadata(&plain).unwrap();
+        xattr::store_hash(&plain, &metadata, algorithm, "overwritten").unwrap();
+        let fresh = open_test_db();
+        scan_directory(&fresh, dir.path(), 1, &opts, |_, _, _| ()).unwrap();
+        assert_eq!(get_file_hash(&fresh, &plain), "overwritten");
+
+        // A rescan against a database already holding the hash rewrites it
+        scan_directory(&conn, dir.path(), 1, &opts, |_, _, _| ()).unwrap();
+        let again = open_test_db();
+        scan_directory(&again, dir.path(), 1, &opts, |_, _, _| ()).unwrap();
+        assert_eq!(get_file_hash(&again, &plain), expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_records_same_identity_for_hardlinks() {
+        let dir = tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        let b = dir.path().join("b.txt");
+        fs::write(&a, "linked").unwrap();
+        fs::hard_link(&a, &b).unwrap();
+        fs::write(dir.path().join("c.txt"), "linked").unwrap();
+
+        let conn = open_test_db();
+        scan_directory(&conn, dir.path(), 3, &ScanOptions::default(), |_, _, _| ()).unwrap();
+
+        let rec_a = db::get_file(&conn, &a).unwrap().unwrap();
+        let rec_b = db::get_file(&conn, &b).unwrap().unwrap();
+        let rec_c = db::get_file(&conn, &dir.path().join("c.txt"))
+            .unwrap()
+            .unwrap();
+        assert!(rec_a.inode.is_some());
+        assert_eq!((rec_a.device, rec_a.inode), (rec_b.device, rec_b.inode));
+        assert_ne!(rec_a.inode, rec_c.inode);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_records_link_count_owner_and_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let a = dir.path().join("a.txt");
+        fs::write(&a, "linked").unwrap();
+        fs::hard_link(&a, dir.path().join("b.txt")).unwrap();
+        fs::set_permissions(&a, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let conn = open_test_db();
+        let opts = ScanOptions::default();
+        scan_directory(&conn, dir.path(), 2, &opts, |_, _, _| ()).unwrap();
+        let stat = db::get_file(&conn, &a).unwrap().unwrap().stat.unwrap();
+        assert_eq!((stat.nlink, stat.mode), (2, 0o640));
+        assert_eq!(Some(stat), utils::file_stat(&fs::metadata(&a).unwrap()));
+
+        // A change of permissions alone leaves the hash be but is picked up
+        fs::set_permissions(&a, fs::Permissions::from_mode(0o600)).unwrap();
+        scan_directory(&conn, dir.path(), 2, &opts, |_, _, _| ()).unwrap();
+        let stat = db::get_file(&conn, &a).unwrap().unwrap().stat.unwrap();
+        assert_eq!(stat.mode, 0o600);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_records_files_reached_through_links() {
+        let tmp = tempdir().unwrap();
+        let root = tmp.path().join("root");
+        let outside = tmp.path().join("outside");
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(root.join("a.txt"), "same").unwrap();
+        fs::write(outside.join("b.txt"), "same").unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("linked")).unwrap();
+
+        let conn = open_test_db();
+        scan_directory(&conn, &root, 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert!(db::get_file(&conn, &root.join("linked/b.txt"))
+            .unwrap()
+            .is_none());
+
+        let opts = ScanOptions {
+            walk: walk::WalkOptions {
+                follow_symlinks: true,
+                ..walk::WalkOptions::default()
+            },
+            ..ScanOptions::default()
+        };
+        scan_directory(&conn, &root, 2, &opts, |_, _, _| ()).unwrap();
+        let linked = db::get_file(&conn, &root.join("linked/b.txt"))
+            .unwrap()
+            .unwrap();
+        assert!(linked.via_link);
+        assert!(
+            !db::get_file(&conn, &root.join("a.txt"))
+                .unwrap()
+                .unwrap()
+                .via_link
+        );
+        // The linked directory is hashed like the directory it points to
+        let direct = open_test_db();
+        scan_directory(&direct, &outside, 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert_eq!(
+            get_dir_hash(&conn, &root.join("linked")),
+            get_dir_hash(&direct, &outside)
+        );
+    }
+
     #[test]
     fn test_compute_directory_hashes_stores_all_dirs() {
         let dir = tempdir().unwrap();
@@ -334,8 +1800,25 @@ mod tests {
         let conn = open_test_db();
         db::init_visited_files(&conn).unwrap();
         let mut files_by_dir = HashMap::new();
-        scan_files(&conn, dir.path(), 1, &mut files_by_dir, |_, _, _| ()).unwrap();
-        compute_directory_hashes(&conn, dir.path(), &files_by_dir).unwrap();
+        let pass = scan_files(
+            &conn,
+            dir.path(),
+            1,
+            &ScanOptions::default(),
+            &mut files_by_dir,
+            |_, _, _| (),
+        )
+        .unwrap();
+        compute_directory_hashes(
+            &conn,
+            dir.path(),
+            &pass.directories,
+            &files_by_dir,
+            &HashSet::new(),
+            None,
+            hashing::HashAlgorithm::default(),
+        )
+        .unwrap();
 
         let dir_count: i64 = conn
             .query_row("SELECT COUNT(*) FROM directories", [], |r| r.get(0))
@@ -358,8 +1841,25 @@ mod tests {
             let conn = open_test_db();
             db::init_visited_files(&conn).unwrap();
             let mut fbd = HashMap::new();
-            scan_files(&conn, root.path(), 1, &mut fbd, |_, _, _| ()).unwrap();
-            compute_directory_hashes(&conn, root.path(), &fbd).unwrap();
+            let pass = scan_files(
+                &conn,
+                root.path(),
+                1,
+                &ScanOptions::default(),
+                &mut fbd,
+                |_, _, _| (),
+            )
+            .unwrap();
+            compute_directory_hashes(
+                &conn,
+                root.path(),
+                &pass.directories,
+                &fbd,
+                &HashSet::new(),
+                None,
+                hashing::HashAlgorithm::default(),
+            )
+            .unwrap();
             get_dir_hash(&conn, root.path())
         };
 
@@ -371,6 +1871,138 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_compute_directory_hashes_ignores_db_rows_for_deleted_subdirs() {
+        // A directory row left over from an earlier scan must not leak into
+        // the parent's hash; only subdirectories seen in this pass count.
+        let root = tempdir().unwrap();
+        fs::write(root.path().join("a.txt"), "hello").unwrap();
+
+        let conn = open_test_db();
+        scan_directory(&conn, root.path(), 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        let before = get_dir_hash(&conn, root.path());
+
+        db::upsert_directory(&conn, &root.path().join("gone"), "ghost", 1).unwrap();
+        scan_directory(&conn, root.path(), 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert_eq!(get_dir_hash(&conn, root.path()), before);
+    }
+
+    #[test]
+    fn test_count_empty_files_rehashes_directories_that_differ_by_one() {
+        let root = tempdir().unwrap();
+        let root = root.path();
+        for dir in ["a", "b"] {
+            fs::create_dir(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("x.txt"), "same").unwrap();
+        }
+        fs::write(root.join("b/.lock"), "").unwrap();
+        let conn = open_test_db();
+        scan_directory(&conn, root, 3, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        let same = |conn: &Connection| {
+            get_dir_hash(conn, &root.join("a")) == get_dir_hash(conn, &root.join("b"))
+        };
+        assert!(same(&conn));
+
+        // Both directories and the root above them
+        assert_eq!(count_empty_files(&conn, true).unwrap(), 3);
+        assert!(!same(&conn));
+        assert_eq!(count_empty_files(&conn, true).unwrap(), 0);
+        // Later passes keep to the setting
+        fs::write(root.join("a/.lock"), "").unwrap();
+        scan_directory(&conn, root, 4, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert!(same(&conn));
+        fs::remove_file(root.join("a/.lock")).unwrap();
+        scan_directory(&conn, root, 3, &ScanOptions::default(), |_, _, _| ()).unwrap();
+
+        count_empty_files(&conn, false).unwrap();
+        assert!(same(&conn));
+    }
+
+    /// Scan `root` (holding `a/x.txt` and `b/y.txt`), then overwrite `b`'s
+    /// stored hash, so a later scan that rehashes `b` is easy to spot.
+    fn scan_and_mark_b(root: &Path) -> Connection {
+        for (dir, name) in [("a", "x.txt"), ("b", "y.txt")] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join(name), dir).unwrap();
+        }
+        let conn = open_test_db();
+        scan_directory(&conn, root, 2, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        db::upsert_directory(&conn, &root.join("b"), "untouched", 1).unwrap();
+        conn
+    }
+
+    #[test]
+    fn test_rescan_only_rehashes_directories_above_changes() {
+        let root = tempdir().unwrap();
+        let root = root.path();
+        let conn = scan_and_mark_b(root);
+        let a_before = get_dir_hash(&conn, &root.join("a"));
+        let root_before = get_dir_hash(&conn, root);
+
+        scan_directory(&conn, root, 2, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert_eq!(get_dir_hash(&conn, &root.join("b")), "untouched");
+        assert_eq!(get_dir_hash(&conn, root), root_before);
+
+        // A new file in a: a and the root are rehashed, b is left alone
+        fs::write(root.join("a/z.txt"), "new").unwrap();
+        scan_directory(&conn, root, 3, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert_ne!(get_dir_hash(&conn, &root.join("a")), a_before);
+        assert_ne!(get_dir_hash(&conn, root), root_before);
+        assert_eq!(get_dir_hash(&conn, &root.join("b")), "untouched");
+
+        // So is a new subdirectory, whose parent is rehashed to include it
+        fs::create_dir(root.join("a/sub")).unwrap();
+        let a_with_file = get_dir_hash(&conn, &root.join("a"));
+        scan_directory(&conn, root, 3, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert_ne!(get_dir_hash(&conn, &root.join("a")), a_with_file);
+        assert_eq!(get_dir_hash(&conn, &root.join("b")), "untouched");
+    }
+
+    #[test]
+    fn test_interrupted_scan_is_followed_by_a_full_directory_pass() {
+        let root = tempdir().unwrap();
+        let root = root.path();
+        let conn = scan_and_mark_b(root);
+        let cancelled = ScanOptions::default();
+        cancelled.cancel.store(true, Ordering::SeqCst);
+        let result = scan_directory(&conn, root, 2, &cancelled, |_, _, _| ()).unwrap();
+        assert!(result.interrupted);
+
+        scan_directory(&conn, root, 2, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        let fresh = open_test_db();
+        scan_directory(&fresh, root, 2, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert_eq!(
+            get_dir_hash(&conn, &root.join("b")),
+            get_dir_hash(&fresh, &root.join("b"))
+        );
+    }
+
+    #[test]
+    fn test_stale_rows_keep_directory_passes_full_until_gone() {
+        let root = tempdir().unwrap();
+        let root = root.path();
+        let conn = scan_and_mark_b(root);
+        // Excluding y.txt leaves its row stale, and rehashes b without it
+        let skip_y = ScanOptions {
+            walk: walk::WalkOptions {
+                filter: walk::PathFilter::new(&["y.txt".into()], &[], &[]).unwrap(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let result = scan_directory(&conn, root, 1, &skip_y, |_, _, _| ()).unwrap();
+        assert_eq!(result.stale_count, 1);
+        let without_y = get_dir_hash(&conn, &root.join("b"));
+        assert_ne!(without_y, "untouched");
+
+        // y.txt is back but unchanged; the directory pass must still see it
+        scan_directory(&conn, root, 2, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert_ne!(get_dir_hash(&conn, &root.join("b")), without_y);
+        let fresh = open_test_db();
+        scan_directory(&fresh, root, 2, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert_eq!(get_dir_hash(&conn, root), get_dir_hash(&fresh, root));
+    }
+
     // -----------------------------------------------------------------------
     // scan_directory (integration)
     // -----------------------------------------------------------------------
@@ -381,7 +2013,7 @@ mod tests {
         fs::write(dir.path().join("a.txt"), "hello").unwrap();
 
         let conn = open_test_db();
-        scan_directory(&conn, dir.path(), 1, |_, _, _| ()).unwrap();
+        scan_directory(&conn, dir.path(), 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
 
         let record = db::get_file(&conn, &dir.path().join("a.txt"))
             .unwrap()
@@ -393,6 +2025,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_scan_prunes_excluded_subtrees() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("app/node_modules/pkg")).unwrap();
+        fs::write(dir.path().join("app/node_modules/pkg/index.js"), "x").unwrap();
+        fs::write(dir.path().join("app/main.js"), "y").unwrap();
+        fs::write(dir.path().join("app/scratch.tmp"), "z").unwrap();
+
+        let patterns = ["node_modules".to_string(), "*.tmp".to_string()];
+        let opts = ScanOptions {
+            walk: walk::WalkOptions {
+                filter: walk::PathFilter::new(&patterns, &[], &[]).unwrap(),
+                ..walk::WalkOptions::default()
+            },
+            ..ScanOptions::default()
+        };
+        let conn = open_test_db();
+        scan_directory(&conn, dir.path(), 1, &opts, |_, _, _| ()).unwrap();
+
+        assert!(db::get_file(&conn, &dir.path().join("app/main.js"))
+            .unwrap()
+            .is_some());
+        assert!(db::get_file(&conn, &dir.path().join("app/scratch.tmp"))
+            .unwrap()
+            .is_none());
+        let index = dir.path().join("app/node_modules/pkg/index.js");
+        assert!(db::get_file(&conn, &index).unwrap().is_none());
+        let excluded = dir.path().join("app/node_modules");
+        assert!(db::get_directory(&conn, &excluded).unwrap().is_none());
+    }
+
     #[test]
     fn test_scan_identical_dirs_get_same_hash() {
         let root = tempdir().unwrap();
@@ -404,7 +2067,7 @@ mod tests {
         fs::write(dir_b.join("file.txt"), "same content").unwrap();
 
         let conn = open_test_db();
-        scan_directory(&conn, root.path(), 2, |_, _, _| ()).unwrap();
+        scan_directory(&conn, root.path(), 2, &ScanOptions::default(), |_, _, _| ()).unwrap();
 
         let hash_a = get_dir_hash(&conn, &dir_a);
         let hash_b = get_dir_hash(&conn, &dir_b);
@@ -422,7 +2085,7 @@ mod tests {
         fs::write(dir_b.join("file.txt"), "content B").unwrap();
 
         let conn = open_test_db();
-        scan_directory(&conn, root.path(), 2, |_, _, _| ()).unwrap();
+        scan_directory(&conn, root.path(), 2, &ScanOptions::default(), |_, _, _| ()).unwrap();
 
         let hash_a = get_dir_hash(&conn, &dir_a);
         let hash_b = get_dir_hash(&conn, &dir_b);
@@ -435,14 +2098,14 @@ mod tests {
         fs::write(dir.path().join("a.txt"), "hello").unwrap();
 
         let conn = open_test_db();
-        scan_directory(&conn, dir.path(), 1, |_, _, _| ()).unwrap();
+        scan_directory(&conn, dir.path(), 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
 
         // Insert a ghost record for a file that doesn't exist on disk
         let ghost = dir.path().join("ghost.txt");
         insert_ghost_file(&conn, &ghost);
 
         // Rescan — ghost record should survive (caller decides what to do with stale entries)
-        scan_directory(&conn, dir.path(), 1, |_, _, _| ()).unwrap();
+        scan_directory(&conn, dir.path(), 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
 
         let count: i64 = conn
             .query_row(
@@ -457,13 +2120,139 @@ mod tests {
         );
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_skips_unreadable_directory_and_carries_on() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let locked = dir.path().join("locked");
+        fs::create_dir(&locked).unwrap();
+        fs::write(locked.join("secret.txt"), "hidden").unwrap();
+        fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o000)).unwrap();
+        if fs::read_dir(&locked).is_ok() {
+            // Running as root: permissions don't stop anything
+            fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+            return;
+        }
+
+        let conn = open_test_db();
+        let result = scan_directory(&conn, dir.path(), 1, &ScanOptions::default(), |_, _, _| ());
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o755)).unwrap();
+        let result = result.unwrap();
+
+        assert_eq!(result.errors.len(), 1);
+        assert_eq!(result.errors[0].path, locked.display().to_string());
+        let recorded = db::scan_errors(&conn).unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].kind, "permission-denied");
+        assert!(db::get_file(&conn, &dir.path().join("a.txt"))
+            .unwrap()
+            .is_some());
+        // Not recorded as an empty directory, which would match every other one
+        assert!(db::get_directory(&conn, &locked).unwrap().is_none());
+        assert!(db::get_directory(&conn, dir.path()).unwrap().is_some());
+    }
+
     #[test]
-    fn test_scan_returns_zero_invalid_paths_for_clean_dir() {
+    fn test_scan_forgets_earlier_errors_under_its_root() {
         let dir = tempdir().unwrap();
         fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        let conn = open_test_db();
+        let root = dir.path().to_str().unwrap();
+        db::log_scan_error(&conn, 1, root, "/old", "io", "stale").unwrap();
+        db::log_scan_error(&conn, 1, "/elsewhere", "/other", "io", "kept").unwrap();
+
+        scan_directory(&conn, dir.path(), 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        let recorded = db::scan_errors(&conn).unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].root, "/elsewhere");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_records_names_that_are_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = tempdir().unwrap();
+        // Two Latin-1 names that would both read as "caf\u{FFFD}" if decoded lossily
+        let names = [OsStr::from_bytes(b"caf\xe9"), OsStr::from_bytes(b"caf\xe8")];
+        for name in names {
+            fs::write(dir.path().join(name), "hello").unwrap();
+        }
+
+        let conn = open_test_db();
+        let opts = ScanOptions::default();
+        scan_directory(&conn, dir.path(), 1, &opts, |_, _, _| ()).unwrap();
+        let files = db::all_files(&conn).unwrap();
+        assert_eq!(files.len(), 2);
+        for file in &files {
+            assert!(utils::path_from_db(&file.path).is_file());
+        }
+
+        // A rescan finds the same files again rather than treating them as gone
+        let result = scan_directory(&conn, dir.path(), 1, &opts, |_, _, _| ()).unwrap();
+        assert_eq!(result.stale_count, 0);
+        assert_eq!(db::all_files(&conn).unwrap().len(), 2);
+    }
+
+    fn write_zip(path: &Path, files: &[(&str, &str)]) {
+        use std::io::Write;
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        for (name, data) in files {
+            zip.start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            zip.write_all(data.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn test_scan_archives_stores_members_as_duplicates_of_loose_files() {
+        let dir = tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        fs::write(sub.join("a.txt"), "alpha").unwrap();
+        let zip = dir.path().join("backup.zip");
+        write_zip(&zip, &[("sub/a.txt", "alpha"), ("b.txt", "beta")]);
+        let zip_str = zip.to_str().unwrap();
+        let member = archive::member_path(zip_str, "sub/a.txt");
 
         let conn = open_test_db();
-        let result = scan_directory(&conn, dir.path(), 1, |_, _, _| ()).unwrap();
-        assert_eq!(result.invalid_paths, 0);
+        let opts = ScanOptions {
+            scan_archives: true,
+            ..Default::default()
+        };
+        scan_directory(&conn, dir.path(), 1, &opts, |_, _, _| ()).unwrap();
+        let dir_hash = get_dir_hash(&conn, dir.path());
+        assert_eq!(db::archive_members(&conn).unwrap().len(), 2);
+        let groups = crate::duplicates::find_duplicate_files(&conn).unwrap();
+        assert_eq!(groups.len(), 1);
+        let paths: Vec<_> = groups[0].files.iter().map(|f| f.path.clone()).collect();
+        assert!(paths.contains(&member));
+        assert!(paths.contains(&sub.join("a.txt").to_str().unwrap().to_string()));
+
+        // Members don't count towards directory hashes
+        let plain = open_test_db();
+        scan_directory(&plain, dir.path(), 1, &ScanOptions::default(), |_, _, _| ()).unwrap();
+        assert_eq!(get_dir_hash(&plain, dir.path()), dir_hash);
+
+        // An unchanged archive keeps its members without being read again
+        let result = scan_directory(&conn, dir.path(), 1, &opts, |_, _, _| ()).unwrap();
+        assert_eq!(result.stale_count, 0);
+        assert_eq!(db::archive_members(&conn).unwrap().len(), 2);
+
+        // A changed one has its members replaced
+        write_zip(&zip, &[("c.txt", "gamma")]);
+        conn.execute(
+            "UPDATE files SET modified = 0 WHERE path = ?1",
+            rusqlite::params![zip_str],
+        )
+        .unwrap();
+        scan_directory(&conn, dir.path(), 1, &opts, |_, _, _| ()).unwrap();
+        let members: Vec<_> = db::archive_members(&conn).unwrap().into_keys().collect();
+        assert_eq!(members, vec![archive::member_path(zip_str, "c.txt")]);
     }
 }
@@ -0,0 +1,194 @@
+use anyhow::{anyhow, bail, Result};
+
+/// When something runs, as a cron expression: `minute hour day-of-month
+/// month day-of-week`, each field `*`, a number, a range `a-b`, a step `*/n`
+/// or `a-b/n`, or a comma-separated list of those. Days of the week run from
+/// 0 (Sunday) to 6, with 7 for Sunday too. Like cron, a day matches when
+/// either day field does if both are restricted. `@hourly`, `@daily`,
+/// `@weekly` and `@monthly` stand for the usual expressions. Times are UTC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: Vec<bool>,
+    hours: Vec<bool>,
+    days: Vec<bool>,
+    months: Vec<bool>,
+    weekdays: Vec<bool>,
+    /// Whether each day field was given as `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+/// How far ahead `next_after` looks before deciding a schedule never fires,
+/// e.g. on the 31st of February.
+const HORIZON_DAYS: i64 = 5 * 366;
+
+impl Schedule {
+    pub fn parse(expression: &str) -> Result<Self> {
+        let expression = match expression.trim() {
+            "@hourly" => "0 * * * *",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@weekly" => "0 0 * * 0",
+            "@monthly" => "0 0 1 * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!(
+                "`{expression}` is not a schedule: expected five fields, \
+                 minute hour day month weekday"
+            );
+        };
+        let mut weekdays = parse_field(weekday, 0, 7).map_err(|e| anyhow!("day of week: {e}"))?;
+        if weekdays[7] {
+            weekdays[0] = true;
+        }
+        weekdays.truncate(7);
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59).map_err(|e| anyhow!("minute: {e}"))?,
+            hours: parse_field(hour, 0, 23).map_err(|e| anyhow!("hour: {e}"))?,
+            days: parse_field(day, 1, 31).map_err(|e| anyhow!("day of month: {e}"))?,
+            months: parse_field(month, 1, 12).map_err(|e| anyhow!("month: {e}"))?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+
+    /// The first time the schedule fires strictly after `after`, both in Unix
+    /// seconds; `None` if it never does.
+    pub fn next_after(&self, after: i64) -> Option<i64> {
+        // From the start of the next minute
+        let start = (after.div_euclid(60) + 1) * 60;
+        let first_day = start.div_euclid(86400);
+        for day in first_day..first_day + HORIZON_DAYS {
+            if !self.fires_on(day) {
+                continue;
+            }
+            let from = if day == first_day {
+                start.rem_euclid(86400) / 60
+            } else {
+                0
+            };
+            for minute_of_day in from..24 * 60 {
+                let (hour, minute) = (minute_of_day / 60, minute_of_day % 60);
+                if self.hours[hour as usize] && self.minutes[minute as usize] {
+                    return Some(day * 86400 + minute_of_day * 60);
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether the schedule fires at any time on `day`, counted in days
+    /// since the Unix epoch.
+    fn fires_on(&self, day: i64) -> bool {
+        let (_, month, day_of_month) = crate::utils::secs_to_ymd(day * 86400);
+        // 1 January 1970 was a Thursday
+        let weekday = (day + 4).rem_euclid(7) as usize;
+        let day_matches = match (self.any_day, self.any_weekday) {
+            (false, false) => self.days[day_of_month as usize] || self.weekdays[weekday],
+            _ => self.days[day_of_month as usize] && self.weekdays[weekday],
+        };
+        self.months[month as usize] && day_matches
+    }
+}
+
+/// Which of `0..=max` one cron field allows (below `min` never is).
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Vec<bool>> {
+    let mut allowed = vec![false; max as usize + 1];
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .ok()
+                    .filter(|&s| s > 0)
+                    .ok_or_else(|| anyhow!("`{step}` is not a step"))?;
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let number = |text: &str| -> Result<u32> {
+            text.parse()
+                .ok()
+                .filter(|n| (min..=max).contains(n))
+                .ok_or_else(|| anyhow!("`{text}` is not between {min} and {max}"))
+        };
+        let (from, to) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((from, to)) => (number(from)?, number(to)?),
+            // `5/15` runs from 5 to the end, like `5-59/15`
+            None if step > 1 => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if from > to {
+            bail!("`{range}` is backwards");
+        }
+        for n in (from..=to).step_by(step as usize) {
+            allowed[n as usize] = true;
+        }
+    }
+    Ok(allowed)
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2026-10-14 15:05:30 UTC, a Wednesday
+    const NOW: i64 = 1_791_990_330;
+
+    fn next(expression: &str) -> String {
+        let schedule = Schedule::parse(expression).unwrap();
+        let next = schedule.next_after(NOW).unwrap();
+        assert_eq!(next % 60, 0);
+        crate::utils::fmt_mtime(next)
+    }
+
+    #[test]
+    fn test_next_after_finds_the_next_matching_minute() {
+        assert_eq!(crate::utils::fmt_mtime(NOW), "2026-10-14 15:05");
+        assert_eq!(next("* * * * *"), "2026-10-14 15:06");
+        assert_eq!(next("*/30 * * * *"), "2026-10-14 15:30");
+        assert_eq!(next("0 3 * * *"), "2026-10-15 03:00");
+        assert_eq!(next("@daily"), "2026-10-15 00:00");
+        assert_eq!(next("5 15 * * *"), "2026-10-15 15:05");
+        assert_eq!(next("0 9-17/4,20 * * *"), "2026-10-14 17:00");
+        // Sunday, as 0 and as 7
+        assert_eq!(next("0 2 * * 0"), "2026-10-18 02:00");
+        assert_eq!(next("0 2 * * 7"), "2026-10-18 02:00");
+        assert_eq!(next("0 0 1 * *"), "2026-11-01 00:00");
+        assert_eq!(next("0 0 29 2 *"), "2028-02-29 00:00");
+        // Either day field matches when both are given
+        assert_eq!(next("0 0 1 * 5"), "2026-10-16 00:00");
+        assert_eq!(Schedule::parse("0 0 31 2 *").unwrap().next_after(NOW), None);
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_schedules() {
+        let error = |e: &str| Schedule::parse(e).unwrap_err().to_string();
+        assert_eq!(
+            error("0 3 * *"),
+            "`0 3 * *` is not a schedule: expected five fields, minute hour day month weekday"
+        );
+        assert_eq!(error("60 * * * *"), "minute: `60` is not between 0 and 59");
+        assert_eq!(
+            error("* * 0 * *"),
+            "day of month: `0` is not between 1 and 31"
+        );
+        assert_eq!(error("*/0 * * * *"), "minute: `0` is not a step");
+        assert_eq!(error("* 5-2 * * *"), "hour: `5-2` is backwards");
+        assert_eq!(
+            error("* * * * mon"),
+            "day of week: `mon` is not between 0 and 7"
+        );
+    }
+}
@@ -0,0 +1,473 @@
+use std::path::Path;
+
+use crate::dedupe::{Action, ApplyOptions, GroupPlan};
+use crate::{file_system, utils};
+
+/// Which shell `render` writes for.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Shell {
+    /// A POSIX `sh` script (the default)
+    #[default]
+    Sh,
+    /// A PowerShell script for Windows
+    Powershell,
+}
+
+/// A script that carries out `plans` the way `dedupe::apply_plans` would
+/// with `opts`, one command per path, each guarded so it only runs while the
+/// kept copy still exists. Nothing here touches the database: rescan after
+/// running it. Paths are quoted to survive spaces, quotes, leading dashes
+/// and newlines, and `sh` scripts spell out names that aren't UTF-8 byte by
+/// byte; PowerShell can't name those, so their commands are left out.
+pub fn render(plans: &[GroupPlan], opts: &ApplyOptions, shell: Shell) -> String {
+    let mut out = String::new();
+    match shell {
+        Shell::Sh => {
+            out.push_str("#!/bin/sh\n");
+            out.push_str(HEADER);
+            out.push_str("set -u\n");
+            if uses_trash(opts) {
+                out.push_str(SH_TRASH);
+            }
+        }
+        Shell::Powershell => {
+            out.push_str(HEADER);
+            out.push_str("$ErrorActionPreference = 'Continue'\n");
+            if uses_trash(opts) {
+                out.push_str(PS_TRASH);
+            }
+        }
+    }
+    for plan in plans {
+        if plan.removals.is_empty() {
+            continue;
+        }
+        out.push_str(&format!(
+            "\n# {} ({})\n",
+            &plan.hash[..plan.hash.len().min(16)],
+            comment_text(&plan.reason)
+        ));
+        for path in &plan.keep {
+            out.push_str(&format!("# keep {}\n", comment_path(path)));
+        }
+        for removal in &plan.removals {
+            let path = &utils::path_from_db(&removal.path);
+            let keeper = &utils::path_from_db(&removal.keeper);
+            let line = match shell {
+                Shell::Sh => sh_command(path, keeper, opts),
+                Shell::Powershell => ps_command(path, keeper, opts),
+            };
+            out.push_str(&line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+const HEADER: &str = "\
+# Generated by `deduplifier dedupe --emit-script`. Review and edit it, then
+# run it; each command is skipped if the copy it relies on is gone.
+# Rescan afterwards so the database matches the disk again.
+";
+
+const SH_TRASH: &str = r#"
+trash() {
+    if command -v gio >/dev/null 2>&1; then gio trash -- "$1"
+    elif command -v trash-put >/dev/null 2>&1; then trash-put -- "$1"
+    else echo "no trash command found; not removing $1" >&2; return 1
+    fi
+}
+"#;
+
+const PS_TRASH: &str = r#"
+Add-Type -AssemblyName Microsoft.VisualBasic
+function Move-ToRecycleBin($Path) {
+    [Microsoft.VisualBasic.FileIO.FileSystem]::DeleteFile(
+        $Path, 'OnlyErrorDialogs', 'SendToRecycleBin')
+}
+"#;
+
+fn uses_trash(opts: &ApplyOptions) -> bool {
+    opts.action == Action::Delete && !opts.permanent && opts.quarantine.is_none()
+}
+
+/// Where `path` goes in the quarantine directory `dir`. Unlike
+/// `file_system::quarantine_file` the script doesn't pick a free name; it
+/// skips the move when the spot is taken.
+fn quarantine_target(dir: &Path, path: &Path) -> std::path::PathBuf {
+    file_system::mirrored_path(dir, path).unwrap_or_else(|_| dir.join(path))
+}
+
+fn link_contents(path: &Path, keeper: &Path, action: Action) -> std::path::PathBuf {
+    match (action, path.parent()) {
+        (Action::RelativeSymlink, Some(dir)) => file_system::relative_path(dir, keeper),
+        _ => keeper.to_path_buf(),
+    }
+}
+
+fn sh_command(path: &Path, keeper: &Path, opts: &ApplyOptions) -> String {
+    let mut names = ShNames::default();
+    let p = names.quote(path);
+    let k = names.quote(keeper);
+    let quarantine = opts
+        .quarantine
+        .as_deref()
+        .map(|dir| quarantine_target(dir, path));
+    let command = match (opts.action, quarantine) {
+        (Action::Delete, Some(target)) => {
+            let parent = target.parent().unwrap_or(&target);
+            let q = names.quote(&target);
+            let dir = names.quote(parent);
+            format!("[ ! -e {q} ] && mkdir -p -- {dir} && mv -- {p} {q}")
+        }
+        (Action::Delete, None) if opts.permanent => format!("rm -f -- {p}"),
+        (Action::Delete, None) => format!("trash {p}"),
+        (Action::Hardlink, _) => format!("ln -f -- {k} {p}"),
+        (Action::Symlink | Action::RelativeSymlink, _) => {
+            let target = names.quote(&link_contents(path, keeper, opts.action));
+            format!("ln -sf -- {target} {p}")
+        }
+        // GNU cp; unlike FIDEDUPERANGE this rewrites the copy as a new file
+        (Action::Reflink, _) => format!("cp --reflink=always --preserve=all -- {k} {p}"),
+    };
+    format!("{}[ -f {k} ] && {command}", names.bindings)
+}
+
+fn ps_command(path: &Path, keeper: &Path, opts: &ApplyOptions) -> String {
+    let quarantine = opts
+        .quarantine
+        .as_deref()
+        .map(|dir| quarantine_target(dir, path));
+    let link = link_contents(path, keeper, opts.action);
+    let named = [Some(path), Some(keeper), quarantine.as_deref(), Some(&link)];
+    if named.iter().flatten().any(|p| p.to_str().is_none()) {
+        return format!(
+            "# PowerShell can't name it; skipped {}",
+            comment_path(&utils::path_to_db(path))
+        );
+    }
+    let p = ps_quote(&path.to_string_lossy());
+    let k = ps_quote(&keeper.to_string_lossy());
+    // Every cmdlet that takes wildcards in -Path gets -LiteralPath, so names
+    // with `[` and `]` mean themselves. New-Item has no -LiteralPath: its
+    // -Path is never expanded.
+    let command = match (opts.action, quarantine) {
+        (Action::Delete, Some(target)) => {
+            let parent = target.parent().unwrap_or(&target);
+            let q = ps_quote(&target.to_string_lossy());
+            let dir = ps_quote(&parent.to_string_lossy());
+            format!(
+                "if (-not (Test-Path -LiteralPath {q})) {{ New-Item -ItemType Directory -Force -Path {dir} | Out-Null; Move-Item -LiteralPath {p} -Destination {q} }}"
+            )
+        }
+        (Action::Delete, None) if opts.permanent => format!("Remove-Item -LiteralPath {p}"),
+        (Action::Delete, None) => format!("Move-ToRecycleBin {p}"),
+        (Action::Hardlink, _) => format!(
+            "Remove-Item -LiteralPath {p}; New-Item -ItemType HardLink -Path {p} -Target {k} | Out-Null"
+        ),
+        (Action::Symlink | Action::RelativeSymlink, _) => {
+            let target = ps_quote(&link.to_string_lossy());
+            format!(
+                "Remove-Item -LiteralPath {p}; New-Item -ItemType SymbolicLink -Path {p} -Target {target} | Out-Null"
+            )
+        }
+        (Action::Reflink, _) => {
+            let path = comment_path(&utils::path_to_db(path));
+            return format!("# no reflink in PowerShell; skipped {path}");
+        }
+    };
+    format!("if (Test-Path -LiteralPath {k} -PathType Leaf) {{ {command} }}")
+}
+
+/// Quote `s` for `sh`: single quotes keep everything literal, and a single
+/// quote itself is written as `'\''`.
+fn sh_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Quotes the paths of one `sh` command line. A name that isn't UTF-8 can't
+/// be typed in as it is, so `printf` writes it out from octal escapes into a
+/// variable, set at the start of the line. It prints an `x` after the name,
+/// stripped off again, so the command substitution can't drop newlines the
+/// name ends in.
+#[derive(Default)]
+struct ShNames {
+    bindings: String,
+    count: usize,
+}
+
+impl ShNames {
+    fn quote(&mut self, path: &Path) -> String {
+        if let Some(s) = path.to_str() {
+            return sh_quote(s);
+        }
+        let mut format = String::new();
+        for &byte in path.as_os_str().as_encoded_bytes() {
+            match byte {
+                b'\'' => format.push_str(r"'\''"),
+                b'\\' => format.push_str(r"\\"),
+                b'%' => format.push_str("%%"),
+                b' '..=b'~' => format.push(byte as char),
+                _ => format.push_str(&format!("\\{byte:03o}")),
+            }
+        }
+        self.count += 1;
+        let name = format!("name{}", self.count);
+        self.bindings
+            .push_str(&format!("{name}=$(printf '{format}x'); {name}=${{{name}%x}}; "));
+        format!("\"${name}\"")
+    }
+}
+
+/// `text` as it can be put on a comment line: control characters, a
+/// newline above all, would end the comment and start a command, so they
+/// are written as escapes.
+fn comment_text(text: &str) -> String {
+    text.chars()
+        .map(|c| {
+            if c.is_control() {
+                c.escape_default().to_string()
+            } else {
+                c.to_string()
+            }
+        })
+        .collect()
+}
+
+/// The stored `path` as it can be shown on a comment line.
+fn comment_path(path: &str) -> String {
+    comment_text(&utils::display_db_path(path))
+}
+
+/// Quote `s` for PowerShell: in single quotes only `'` is special, and it is
+/// doubled. The typographic single quotes count as quotes too.
+fn ps_quote(s: &str) -> String {
+    let mut out = String::from("'");
+    for c in s.chars() {
+        if matches!(c, '\'' | '\u{2018}' | '\u{2019}' | '\u{201A}' | '\u{201B}') {
+            out.push(c);
+        }
+        out.push(c);
+    }
+    out.push('\'');
+    out
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dedupe::Removal;
+
+    fn plan() -> GroupPlan {
+        GroupPlan {
+            hash: "0123456789abcdef0123".into(),
+            keep: vec!["/keep/a.txt".into()],
+            removals: vec![Removal {
+                path: "/dup/it's -a.txt".into(),
+                size: 4,
+                keeper: "/keep/a.txt".into(),
+            }],
+            reason: "auto: keep-n=1".into(),
+        }
+    }
+
+    fn opts(action: Action, permanent: bool) -> ApplyOptions {
+        ApplyOptions {
+            action,
+            permanent,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_sh_quote() {
+        assert_eq!(sh_quote("plain"), "'plain'");
+        assert_eq!(sh_quote("it's"), r"'it'\''s'");
+        assert_eq!(sh_quote("a\nb $x"), "'a\nb $x'");
+    }
+
+    #[test]
+    fn test_ps_quote() {
+        assert_eq!(ps_quote("it's $x"), "'it''s $x'");
+        assert_eq!(ps_quote("a\u{2019}b"), "'a\u{2019}\u{2019}b'");
+    }
+
+    #[test]
+    fn test_render_sh_commands() {
+        let script = render(&[plan()], &opts(Action::Delete, true), Shell::Sh);
+        assert!(script.starts_with("#!/bin/sh\n"));
+        assert!(script.contains("# keep /keep/a.txt\n"));
+        assert!(script.contains(r"[ -f '/keep/a.txt' ] && rm -f -- '/dup/it'\''s -a.txt'"));
+        assert!(!script.contains("trash()"));
+
+        let trash = render(&[plan()], &opts(Action::Delete, false), Shell::Sh);
+        assert!(trash.contains("trash() {"));
+        assert!(trash.contains(r"&& trash '/dup/it'\''s -a.txt'"));
+
+        let quarantine = ApplyOptions {
+            quarantine: Some("/q".into()),
+            ..Default::default()
+        };
+        let parked = render(&[plan()], &quarantine, Shell::Sh);
+        assert!(!parked.contains("trash()"));
+        assert!(parked.contains(
+            r"[ ! -e '/q/dup/it'\''s -a.txt' ] && mkdir -p -- '/q/dup' && mv -- '/dup/it'\''s -a.txt' '/q/dup/it'\''s -a.txt'"
+        ));
+
+        let link = render(&[plan()], &opts(Action::RelativeSymlink, false), Shell::Sh);
+        assert!(link.contains(r"ln -sf -- '../keep/a.txt' '/dup/it'\''s -a.txt'"));
+    }
+
+    #[test]
+    fn test_render_powershell_commands() {
+        let script = render(&[plan()], &opts(Action::Hardlink, false), Shell::Powershell);
+        assert!(script.contains(
+            "if (Test-Path -LiteralPath '/keep/a.txt' -PathType Leaf) { Remove-Item -LiteralPath \
+             '/dup/it''s -a.txt'; New-Item -ItemType HardLink -Path '/dup/it''s -a.txt' \
+             -Target '/keep/a.txt' | Out-Null }"
+        ));
+    }
+
+    #[test]
+    fn test_render_powershell_names_with_brackets_literally() {
+        let mut plan = plan();
+        plan.removals[0].path = "/dup/[2020] a.txt".into();
+        let quarantine = ApplyOptions {
+            quarantine: Some("/q[1]".into()),
+            ..Default::default()
+        };
+        let parked = render(&[plan.clone()], &quarantine, Shell::Powershell);
+        assert!(parked.contains(
+            "if (-not (Test-Path -LiteralPath '/q[1]/dup/[2020] a.txt')) { New-Item -ItemType \
+             Directory -Force -Path '/q[1]/dup' | Out-Null; Move-Item -LiteralPath \
+             '/dup/[2020] a.txt' -Destination '/q[1]/dup/[2020] a.txt' }"
+        ));
+        let link = render(&[plan], &opts(Action::Symlink, false), Shell::Powershell);
+        assert!(link.contains(
+            "Remove-Item -LiteralPath '/dup/[2020] a.txt'; New-Item -ItemType SymbolicLink \
+             -Path '/dup/[2020] a.txt' -Target '/keep/a.txt' | Out-Null"
+        ));
+    }
+
+    #[test]
+    fn test_comment_text_escapes_control_characters() {
+        assert_eq!(comment_text("a\nrm -rf ~\r\u{1b}"), "a\\nrm -rf ~\\r\\u{1b}");
+        assert_eq!(comment_text("D\u{e9}j\u{e0} vu"), "D\u{e9}j\u{e0} vu");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rendered_sh_script_leaves_reasons_commented_out() {
+        let tmp = tempfile::tempdir().unwrap();
+        let marker = tmp.path().join("ran");
+        let mut plan = plan();
+        // A --prefer-path with a newline in it, say
+        plan.reason = format!("auto: prefer-path /photos\ntouch '{}'", marker.display());
+        let script = render(&[plan], &opts(Action::Delete, true), Shell::Sh);
+        assert!(script.contains("# 0123456789abcdef (auto: prefer-path /photos\\ntouch"));
+        // The kept copy isn't there, so nothing else runs either
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&script)
+            .status()
+            .unwrap();
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_render_skips_plans_without_removals() {
+        let mut kept = plan();
+        kept.removals.clear();
+        let script = render(&[kept], &opts(Action::Delete, true), Shell::Sh);
+        assert!(!script.contains("# keep"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rendered_sh_script_runs() {
+        let tmp = tempfile::tempdir().unwrap();
+        let keep = tmp.path().join("keep");
+        let dup = tmp.path().join("it's\na -dup");
+        std::fs::write(&keep, b"same").unwrap();
+        std::fs::write(&dup, b"same").unwrap();
+        let plan = GroupPlan {
+            hash: "h".into(),
+            keep: vec![keep.to_string_lossy().into_owned()],
+            removals: vec![Removal {
+                path: dup.to_string_lossy().into_owned(),
+                size: 4,
+                keeper: keep.to_string_lossy().into_owned(),
+            }],
+            reason: "interactive".into(),
+        };
+        let script = render(&[plan], &opts(Action::Symlink, false), Shell::Sh);
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&script)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(std::fs::read_link(&dup).unwrap(), keep);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_rendered_sh_script_removes_names_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let keep = tmp.path().join("keep");
+        let dup = tmp
+            .path()
+            .join(std::ffi::OsStr::from_bytes(b"it's 100%\\\xe9"));
+        std::fs::write(&keep, b"same").unwrap();
+        std::fs::write(&dup, b"same").unwrap();
+        let plan = GroupPlan {
+            hash: "h".into(),
+            keep: vec![utils::path_to_db(&keep).into_owned()],
+            removals: vec![Removal {
+                path: utils::path_to_db(&dup).into_owned(),
+                size: 4,
+                keeper: utils::path_to_db(&keep).into_owned(),
+            }],
+            reason: "interactive".into(),
+        };
+        let plans = [plan];
+        let script = render(&plans, &opts(Action::Delete, true), Shell::Sh);
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&script)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(!dup.exists() && keep.exists());
+
+        // And one that ends in a newline, which `$(...)` on its own would drop
+        let dup = tmp.path().join(std::ffi::OsStr::from_bytes(b"dup\xe9\n"));
+        let dropped = tmp.path().join(std::ffi::OsStr::from_bytes(b"dup\xe9"));
+        std::fs::write(&dup, b"same").unwrap();
+        std::fs::write(&dropped, b"other").unwrap();
+        let mut plan = plans[0].clone();
+        plan.removals[0].path = utils::path_to_db(&dup).into_owned();
+        let script = render(&[plan], &opts(Action::Delete, true), Shell::Sh);
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(&script)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(!dup.exists() && dropped.exists());
+
+        let ps = render(&plans, &opts(Action::Delete, true), Shell::Powershell);
+        assert!(ps.contains("# PowerShell can't name it; skipped"));
+        assert!(!ps.contains("Remove-Item"));
+    }
+}
@@ -0,0 +1,908 @@
+use std::cell::RefCell;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use serde::Serialize;
+
+use crate::duplicates::ReportFilter;
+use crate::{cli, db, report, scan, stats, utils, HashStore, Scanner};
+
+/// How long the server waits between checks for a new connection, or for
+/// being stopped.
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+
+/// Connections read and answered at once; more wait to be accepted.
+const MAX_CONNECTIONS: usize = 16;
+
+/// How long a client has to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A request line and headers longer than this are turned away.
+const MAX_REQUEST_BYTES: u64 = 16 * 1024;
+
+/// Files `/api/files` returns when the request doesn't say, and at most.
+const DEFAULT_FILE_LIMIT: usize = 100;
+const MAX_FILE_LIMIT: usize = 10_000;
+
+/// What `GET /api` lists.
+const ENDPOINTS: [&str; 5] = [
+    "/api/groups",
+    "/api/stats",
+    "/api/scans",
+    "/api/files",
+    "/api/scan",
+];
+
+/// How `serve` answers.
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+    /// Accept `POST /api/scan`, which rescans a directory already in the
+    /// database.
+    pub allow_scan: bool,
+    /// Stops the server, and a scan it is running, once set.
+    pub cancel: Arc<AtomicBool>,
+}
+
+/// Where the scan `POST /api/scan` last started is, as `GET /api/scan`
+/// tells it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+enum ScanStatus {
+    /// None was started
+    #[default]
+    Idle,
+    Running {
+        root: String,
+    },
+    Finished(JsonScanResult),
+    Failed {
+        root: String,
+        error: String,
+    },
+}
+
+/// Answers requests from a database: the reads from it, and the scans of it
+/// `POST /api/scan` starts, each of which runs on a thread of its own with a
+/// connection of its own.
+pub struct Server<'a> {
+    store: &'a HashStore,
+    options: ServeOptions,
+    status: Arc<Mutex<ScanStatus>>,
+    scanning: RefCell<Option<JoinHandle<()>>>,
+}
+
+/// A request a connection's thread read, for the server to answer.
+struct Question {
+    request: Request,
+    /// The answer already, to a request that couldn't be read
+    response: Option<Response>,
+    reply: mpsc::Sender<Response>,
+}
+
+/// A request as `serve` understood it: the method, the path without its
+/// query string, and the decoded query parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Split a request target like `/api/files?path=a%20b` into its path
+    /// and decoded parameters.
+    pub fn new(method: &str, target: &str) -> Self {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (decode_component(key), decode_component(value))
+            })
+            .collect();
+        Self {
+            method: method.to_string(),
+            path: path.to_string(),
+            query,
+        }
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn number<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, Response> {
+        self.param(name)
+            .map(|v| {
+                v.parse().map_err(|_| {
+                    Response::error(400, &format!("`{name}` must be a number, not `{v}`"))
+                })
+            })
+            .transpose()
+    }
+
+    fn flag(&self, name: &str) -> Result<bool, Response> {
+        match self.param(name) {
+            None | Some("false") | Some("0") => Ok(false),
+            Some("") | Some("true") | Some("1") => Ok(true),
+            Some(v) => Err(Response::error(
+                400,
+                &format!("`{name}` must be true or false, not `{v}`"),
+            )),
+        }
+    }
+}
+
+/// A status code and the JSON document sent with it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+impl Response {
+    fn json(value: &impl Serialize) -> Self {
+        match serde_json::to_string_pretty(value) {
+            Ok(body) => Self { status: 200, body },
+            Err(e) => Self::error(500, &e.to_string()),
+        }
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Self {
+            status,
+            body: serde_json::json!({ "error": message }).to_string(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            202 => "Accepted",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+impl<'a> Server<'a> {
+    pub fn new(store: &'a HashStore, options: ServeOptions) -> Self {
+        Self {
+            store,
+            options,
+            status: Arc::default(),
+            scanning: RefCell::new(None),
+        }
+    }
+
+    /// Answer HTTP requests on `listener` until `options.cancel` is set,
+    /// then wait for a scan that is running to stop. Each connection is read
+    /// and written on a thread of its own, up to `MAX_CONNECTIONS` at once,
+    /// and its request is answered here in turn; `on_request` is called with
+    /// each response as it is sent. A connection that can't be read or
+    /// answered is dropped; only a failing listener ends the server with an
+    /// error.
+    pub fn serve(
+        &self,
+        listener: &TcpListener,
+        mut on_request: impl FnMut(&Request, &Response),
+    ) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        let (asked, questions) = mpsc::channel::<Question>();
+        let open = Arc::new(AtomicUsize::new(0));
+        let served = loop {
+            if self.options.cancel.load(Ordering::Relaxed) {
+                break Ok(());
+            }
+            if open.load(Ordering::Relaxed) < MAX_CONNECTIONS {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let (asked, open) = (asked.clone(), open.clone());
+                        open.fetch_add(1, Ordering::Relaxed);
+                        thread::spawn(move || {
+                            let _ = read_and_answer(stream, &asked);
+                            open.fetch_sub(1, Ordering::Relaxed);
+                        });
+                        continue;
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(e) => break Err(e.into()),
+                }
+            }
+            if let Ok(question) = questions.recv_timeout(ACCEPT_POLL) {
+                let response = match question.response {
+                    Some(response) => response,
+                    None => self.respond(&question.request),
+                };
+                on_request(&question.request, &response);
+                let _ = question.reply.send(response);
+            }
+        };
+        if let Some(scanning) = self.scanning.take() {
+            let _ = scanning.join();
+        }
+        served
+    }
+
+    /// The response to `request`:
+    ///
+    /// - `GET /api/groups`: the duplicate groups, as `report --format json`
+    ///   has them; `min_wasted`, `include_empty` and `top_level` filter them
+    ///   as the `report` options of those names do
+    /// - `GET /api/stats`: file totals and where the duplicate bytes are
+    /// - `GET /api/scans`: the scan history, oldest first
+    /// - `GET /api/files?path=...&hash=...`: files whose path contains
+    ///   `path` and whose hash starts with `hash`, up to `limit` (default
+    ///   100)
+    /// - `POST /api/scan?path=...`: start rescanning a directory at or under
+    ///   one scanned before, with the options that root was last scanned
+    ///   with, if `options.allow_scan`; answered at once with 202, while the
+    ///   scan runs in the background, one at a time
+    /// - `GET /api/scan`: how that scan is doing: `idle`, `running`,
+    ///   `finished` with what it found, or `failed` with why
+    pub fn respond(&self, request: &Request) -> Response {
+        let store = self.store;
+        let method = request.method.as_str();
+        let result = match (method, request.path.trim_end_matches('/')) {
+            ("GET", "" | "/api") => Ok(Response::json(
+                &serde_json::json!({ "endpoints": ENDPOINTS }),
+            )),
+            ("GET", "/api/groups") => groups(store, request),
+            ("GET", "/api/stats") => stats(store),
+            ("GET", "/api/scans") => scans(store),
+            ("GET", "/api/files") => files(store, request),
+            ("GET", "/api/scan") => Ok(Response::json(&*self.status.lock().unwrap())),
+            ("POST", "/api/scan") => self.scan(request),
+            (_, path) if path.is_empty() || path == "/api" || ENDPOINTS.contains(&path) => Ok(
+                Response::error(405, &format!("{method} is not allowed on {path}")),
+            ),
+            (_, path) => Ok(Response::error(404, &format!("no endpoint {path}"))),
+        };
+        match result {
+            Ok(response) => response,
+            Err(e) => Response::error(500, &format!("{e:#}")),
+        }
+    }
+
+    fn scan(&self, request: &Request) -> Result<Response> {
+        if !self.options.allow_scan {
+            return Ok(Response::error(
+                403,
+                "scans are not allowed; start serve with --allow-scan",
+            ));
+        }
+        let Some(path) = request.param("path").map(Path::new) else {
+            return Ok(Response::error(400, "give the `path` to scan"));
+        };
+        // Only what is already in the database, so a client can't have the
+        // server read anywhere it likes. Paths are compared resolved (relative
+        // ones from where serve runs), but the scan goes under the root as it
+        // was recorded, so that it finds its rows again.
+        let conn = self.store.connection();
+        let not_scanned = || {
+            Response::error(
+                403,
+                &format!("{} is not under a directory scanned before", path.display()),
+            )
+        };
+        if path.components().any(|c| c == Component::ParentDir) {
+            return Ok(not_scanned());
+        }
+        let Ok(resolved) = fs::canonicalize(path) else {
+            return Ok(Response::error(
+                404,
+                &format!("{} does not exist", path.display()),
+            ));
+        };
+        let scanned = db::scans(conn)?;
+        let recorded = scanned.iter().flat_map(|s| &s.roots).find_map(|root| {
+            let root = utils::path_from_db(root);
+            let below = resolved.strip_prefix(fs::canonicalize(&root).ok()?).ok()?;
+            Some(if below.as_os_str().is_empty() {
+                root
+            } else {
+                root.join(below)
+            })
+        });
+        let Some(path) = recorded else {
+            return Ok(not_scanned());
+        };
+        let path = path.as_path();
+        // The scan writes through a connection of its own, while this one
+        // goes on answering
+        let Some(database) = conn.path().filter(|p| !p.is_empty()) else {
+            return Ok(Response::error(
+                409,
+                "scans need the database on disk, not a copy in memory (--dry-run)",
+            ));
+        };
+        let mut status = self.status.lock().unwrap();
+        if let ScanStatus::Running { root } = &*status {
+            return Ok(Response::error(
+                409,
+                &format!("a scan of {root} is running already"),
+            ));
+        }
+        if let Some(holder) = db::lock_holder(conn)?.filter(|h| !h.is_abandoned()) {
+            return Ok(Response::error(
+                409,
+                &format!(
+                    "the database is in use by `{}` (pid {})",
+                    holder.command, holder.pid
+                ),
+            ));
+        }
+        let mut options = match db::root_options(conn, path)? {
+            Some(args) => cli::scan_options_from_args(&args)?,
+            None => scan::ScanOptions::default(),
+        };
+        options.cancel = self.options.cancel.clone();
+        let root = path.display().to_string();
+        *status = ScanStatus::Running { root: root.clone() };
+        drop(status);
+
+        if let Some(finished) = self.scanning.take() {
+            let _ = finished.join();
+        }
+        let (database, path) = (PathBuf::from(database), path.to_path_buf());
+        let status = self.status.clone();
+        *self.scanning.borrow_mut() = Some(thread::spawn(move || {
+            let result = scan_in_background(&database, &path, options);
+            *status.lock().unwrap() = match result {
+                Ok(result) => ScanStatus::Finished(result),
+                Err(e) => ScanStatus::Failed {
+                    root,
+                    error: format!("{e:#}"),
+                },
+            };
+        }));
+        let mut response = Response::json(&*self.status.lock().unwrap());
+        response.status = 202;
+        Ok(response)
+    }
+}
+
+/// Read one request from `stream`, have the server answer it through
+/// `asked` and send the response back; nothing if the client went away
+/// before asking anything.
+fn read_and_answer(mut stream: TcpStream, asked: &mpsc::Sender<Question>) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new((&stream).take(MAX_REQUEST_BYTES));
+    let mut request_line = String::new();
+    if reader.read_line(&mut request_line)? == 0 {
+        return Ok(());
+    }
+    // The headers say nothing the endpoints need, but must be read past
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+    let mut parts = request_line.split_whitespace();
+    let (request, response) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (Request::new(method, target), None),
+        _ => (
+            Request::new("", ""),
+            Some(Response::error(400, "malformed request")),
+        ),
+    };
+    let (reply, replied) = mpsc::channel();
+    asked.send(Question {
+        request,
+        response,
+        reply,
+    })?;
+    let response = replied.recv()?;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        response.status,
+        response.reason(),
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+fn groups(store: &HashStore, request: &Request) -> Result<Response> {
+    let filter = ReportFilter {
+        min_wasted: match request.number("min_wasted") {
+            Ok(value) => value.unwrap_or(0),
+            Err(response) => return Ok(response),
+        },
+        include_empty: match request.flag("include_empty") {
+            Ok(value) => value,
+            Err(response) => return Ok(response),
+        },
+        top_level: match request.flag("top_level") {
+            Ok(value) => value,
+            Err(response) => return Ok(response),
+        },
+        ..ReportFilter::default()
+    };
+    let report = report::build(store.connection(), &[], filter)?;
+    Ok(Response {
+        status: 200,
+        body: report::to_json(&report)?,
+    })
+}
+
+#[derive(Serialize)]
+struct JsonStats {
+    total_files: i64,
+    total_bytes: i64,
+    duplicate_files: usize,
+    duplicate_bytes: i64,
+    by_extension: Vec<JsonBucket>,
+    by_directory: Vec<JsonBucket>,
+}
+
+#[derive(Serialize)]
+struct JsonBucket {
+    key: String,
+    files: usize,
+    bytes: i64,
+}
+
+fn stats(store: &HashStore) -> Result<Response> {
+    let (total_files, total_bytes) = store.totals()?;
+    let stats = stats::collect(store.connection())?;
+    let buckets = |buckets: Vec<stats::Bucket>| {
+        buckets
+            .into_iter()
+            .map(|b| JsonBucket {
+                key: b.key,
+                files: b.files,
+                bytes: b.bytes,
+            })
+            .collect()
+    };
+    Ok(Response::json(&JsonStats {
+        total_files,
+        total_bytes,
+        duplicate_files: stats.duplicate_files,
+        duplicate_bytes: stats.duplicate_bytes,
+        by_extension: buckets(stats.by_extension),
+        by_directory: buckets(stats.by_directory),
+    }))
+}
+
+#[derive(Serialize)]
+struct JsonScan {
+    id: i64,
+    /// Unix timestamp of when the scan started
+    time: i64,
+    roots: Vec<String>,
+    added: i64,
+    changed: i64,
+    removed: i64,
+}
+
+fn scans(store: &HashStore) -> Result<Response> {
+    let scans: Vec<JsonScan> = db::scans(store.connection())?
+        .into_iter()
+        .map(|s| JsonScan {
+            id: s.id,
+            time: s.time,
+            roots: s.roots,
+            added: s.added,
+            changed: s.changed,
+            removed: s.removed,
+        })
+        .collect();
+    Ok(Response::json(&scans))
+}
+
+#[derive(Serialize)]
+struct JsonFile {
+    path: String,
+    hash: String,
+    size: i64,
+    /// Unix timestamp of the file's last modification
+    modified: i64,
+}
+
+fn files(store: &HashStore, request: &Request) -> Result<Response> {
+    let path = request.param("path").filter(|p| !p.is_empty());
+    let hash = request.param("hash").filter(|h| !h.is_empty());
+    if path.is_none() && hash.is_none() {
+        return Ok(Response::error(400, "give `path`, `hash` or both"));
+    }
+    let limit = match request.number("limit") {
+        Ok(limit) => limit.unwrap_or(DEFAULT_FILE_LIMIT).min(MAX_FILE_LIMIT),
+        Err(response) => return Ok(response),
+    };
+    let files: Vec<JsonFile> = db::search_files(store.connection(), path, hash, limit)?
+        .into_iter()
+        .map(|f| JsonFile {
+            path: f.path,
+            hash: f.hash,
+            size: f.size,
+            modified: f.modified,
+        })
+        .collect();
+    Ok(Response::json(&serde_json::json!({ "files": files })))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct JsonScanResult {
+    root: String,
+    /// Files found under a new path
+    moved: usize,
+    /// Records of files that were gone, and were removed
+    removed: i64,
+    /// Paths that could not be scanned
+    errors: Vec<String>,
+    interrupted: bool,
+}
+
+/// Rescan `path` into the database at `database`, through a connection and
+/// under a run lock of its own.
+fn scan_in_background(
+    database: &Path,
+    path: &Path,
+    options: scan::ScanOptions,
+) -> Result<JsonScanResult> {
+    let store = HashStore::open(database)?;
+    let command = format!("deduplifier serve (scanning {})", path.display());
+    let _lock = match db::try_lock(store.connection(), &command)? {
+        Ok(lock) => lock,
+        Err(holder) => bail!(
+            "the database is in use by `{}` (pid {})",
+            holder.command,
+            holder.pid
+        ),
+    };
+    let scanner = Scanner {
+        options,
+        remove_stale: true,
+        ..Scanner::default()
+    };
+    let result = scanner
+        .scan(&store, &[path], |_, _, _| {})?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("the scan returned nothing"))?;
+    Ok(JsonScanResult {
+        root: result.root_str,
+        moved: result.moved,
+        removed: if result.interrupted {
+            0
+        } else {
+            result.stale_count
+        },
+        errors: result.errors.into_iter().map(|e| e.path).collect(),
+        interrupted: result.interrupted,
+    })
+}
+
+/// Decode one `%XX`-escaped query string component, with `+` for a space.
+/// Invalid escapes are kept as they are.
+fn decode_component(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .filter(|_| bytes[i] == b'%')
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (_, Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', None) => decoded.push(b' '),
+            (byte, None) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn scanned_store(root: &Path) -> HashStore {
+        fs::create_dir_all(root.join("a")).unwrap();
+        fs::write(root.join("a/one.txt"), "one").unwrap();
+        fs::write(root.join("a/one copy.txt"), "one").unwrap();
+        fs::write(root.join("two.txt"), "two").unwrap();
+        let store = HashStore::open_in_memory().unwrap();
+        Scanner::default()
+            .scan(&store, &[root], |_, _, _| {})
+            .unwrap();
+        store
+    }
+
+    fn get(store: &HashStore, target: &str) -> (u16, serde_json::Value) {
+        let response =
+            Server::new(store, ServeOptions::default()).respond(&Request::new("GET", target));
+        (
+            response.status,
+            serde_json::from_str(&response.body).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_request_decodes_the_query_string() {
+        let request = Request::new("GET", "/api/files?path=a%20b+c&hash=&x=%zz&flag");
+        assert_eq!(request.path, "/api/files");
+        assert_eq!(request.param("path"), Some("a b c"));
+        assert_eq!(request.param("hash"), Some(""));
+        assert_eq!(request.param("x"), Some("%zz"));
+        assert_eq!(request.param("flag"), Some(""));
+        assert_eq!(request.param("missing"), None);
+        assert_eq!(
+            Request::new("GET", "/caf%C3%A9?q=%C3%A9").param("q"),
+            Some("é")
+        );
+    }
+
+    #[test]
+    fn test_read_endpoints_answer_from_the_database() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = scanned_store(tmp.path());
+
+        let (status, groups) = get(&store, "/api/groups");
+        assert_eq!(status, 200);
+        assert_eq!(groups["summary"]["file_groups"], 1);
+        assert_eq!(groups["file_groups"][0]["count"], 2);
+        assert_eq!(
+            get(&store, "/api/groups?min_wasted=4").1["summary"]["file_groups"],
+            0
+        );
+        assert_eq!(get(&store, "/api/groups?min_wasted=x").0, 400);
+
+        let (status, stats) = get(&store, "/api/stats");
+        assert_eq!(status, 200);
+        assert_eq!(stats["total_files"], 3);
+        assert_eq!(stats["duplicate_bytes"], 6);
+        assert_eq!(stats["by_extension"][0]["key"], "txt");
+
+        let (status, scans) = get(&store, "/api/scans");
+        assert_eq!(status, 200);
+        assert_eq!(scans.as_array().unwrap().len(), 1);
+        assert_eq!(scans[0]["added"], 3);
+
+        let (status, files) = get(&store, "/api/files?path=one%20copy");
+        assert_eq!(status, 200);
+        assert_eq!(files["files"].as_array().unwrap().len(), 1);
+        let hash = files["files"][0]["hash"].as_str().unwrap();
+        let (_, copies) = get(&store, &format!("/api/files?hash={}", &hash[..16]));
+        assert_eq!(copies["files"].as_array().unwrap().len(), 2);
+        assert_eq!(
+            get(&store, "/api/files?path=one&limit=1").1["files"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(get(&store, "/api/files").0, 400);
+    }
+
+    #[test]
+    fn test_unknown_endpoints_and_methods_are_refused() {
+        let store = HashStore::open_in_memory().unwrap();
+        assert_eq!(
+            get(&store, "/api").1["endpoints"].as_array().unwrap().len(),
+            5
+        );
+        let (status, body) = get(&store, "/api/nothing");
+        assert_eq!(status, 404);
+        assert_eq!(body["error"], "no endpoint /api/nothing");
+        let server = Server::new(&store, ServeOptions::default());
+        let post = server.respond(&Request::new("POST", "/api/stats"));
+        assert_eq!(post.status, 405);
+        assert_eq!(get(&store, "/api/scan").1["state"], "idle");
+        let delete = server.respond(&Request::new("DELETE", "/api/scan"));
+        assert_eq!(delete.status, 405);
+    }
+
+    /// A store on disk in `dir`, for scans to open again
+    fn stored(dir: &Path, root: &Path, options: scan::ScanOptions) -> HashStore {
+        let store = HashStore::open(&dir.join("hashes.db")).unwrap();
+        Scanner {
+            options,
+            ..Scanner::default()
+        }
+        .scan(&store, &[root], |_, _, _| {})
+        .unwrap();
+        store
+    }
+
+    /// Poll `GET /api/scan` until the scan is no longer running
+    fn wait_for_scan(server: &Server) -> serde_json::Value {
+        loop {
+            let response = server.respond(&Request::new("GET", "/api/scan"));
+            let status: serde_json::Value = serde_json::from_str(&response.body).unwrap();
+            if status["state"] != "running" {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_scan_needs_permission_and_a_scanned_directory() {
+        let tmp = tempfile::tempdir().unwrap();
+        let memory = scanned_store(tmp.path());
+        let root = tmp.path().display().to_string();
+        let post = |store, target: &str, allow_scan| {
+            let options = ServeOptions {
+                allow_scan,
+                ..ServeOptions::default()
+            };
+            Server::new(store, options).respond(&Request::new("POST", target))
+        };
+        assert_eq!(
+            post(&memory, &format!("/api/scan?path={root}"), false).status,
+            403
+        );
+        assert_eq!(post(&memory, "/api/scan?path=/etc", true).status, 403);
+        assert_eq!(
+            post(&memory, &format!("/api/scan?path={root}/../x"), true).status,
+            403
+        );
+        assert_eq!(post(&memory, "/api/scan", true).status, 400);
+        // A copy in memory has nothing for the scan to open
+        assert_eq!(
+            post(&memory, &format!("/api/scan?path={root}"), true).status,
+            409
+        );
+
+        let database = tempfile::tempdir().unwrap();
+        let store = stored(database.path(), tmp.path(), scan::ScanOptions::default());
+        let server = Server::new(
+            &store,
+            ServeOptions {
+                allow_scan: true,
+                ..ServeOptions::default()
+            },
+        );
+        fs::remove_file(tmp.path().join("two.txt")).unwrap();
+        fs::write(tmp.path().join("a/three.txt"), "three").unwrap();
+        let target = format!("/api/scan?path={root}");
+        let response = server.respond(&Request::new("POST", &target));
+        assert_eq!(response.status, 202, "{}", response.body);
+        let status = wait_for_scan(&server);
+        assert_eq!(status["state"], "finished", "{status}");
+        assert_eq!(status["removed"], 1);
+        assert_eq!(status["interrupted"], false);
+        assert_eq!(store.totals().unwrap().0, 3);
+
+        let _held = db::try_lock(store.connection(), "deduplifier scan")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            server
+                .respond(&Request::new("POST", &format!("{target}/a")))
+                .status,
+            409
+        );
+    }
+
+    #[test]
+    fn test_scan_uses_the_options_the_root_was_scanned_with() {
+        let tmp = tempfile::tempdir().unwrap();
+        fs::write(tmp.path().join("kept.txt"), "kept").unwrap();
+        let args = vec!["--exclude=*.log".to_string()];
+        let database = tempfile::tempdir().unwrap();
+        let store = stored(
+            database.path(),
+            tmp.path(),
+            cli::scan_options_from_args(&args).unwrap(),
+        );
+        let root = tmp.path().display().to_string();
+        db::set_root_options(store.connection(), &root, &args).unwrap();
+
+        fs::write(tmp.path().join("new.log"), "new").unwrap();
+        fs::write(tmp.path().join("new.txt"), "new").unwrap();
+        let server = Server::new(
+            &store,
+            ServeOptions {
+                allow_scan: true,
+                ..ServeOptions::default()
+            },
+        );
+        let target = format!("/api/scan?path={root}");
+        assert_eq!(server.respond(&Request::new("POST", &target)).status, 202);
+        assert_eq!(wait_for_scan(&server)["state"], "finished");
+        let found = |name| db::search_files(store.connection(), Some(name), None, 10).unwrap();
+        assert_eq!(found("new.txt").len(), 1);
+        assert!(found("new.log").is_empty());
+    }
+
+    #[test]
+    fn test_scan_reaches_a_root_scanned_by_a_relative_path() {
+        // Relative to the working directory, as `deduplifier scan a` records it
+        let tmp = tempfile::tempdir_in(".").unwrap();
+        let root = Path::new(tmp.path().file_name().unwrap());
+        let absolute = fs::canonicalize(tmp.path()).unwrap();
+        let database = tempfile::tempdir().unwrap();
+        let store = stored(database.path(), root, scan::ScanOptions::default());
+        let server = Server::new(
+            &store,
+            ServeOptions {
+                allow_scan: true,
+                ..ServeOptions::default()
+            },
+        );
+        for (given, name) in [(root, "one.txt"), (absolute.as_path(), "two.txt")] {
+            fs::write(tmp.path().join(name), name).unwrap();
+            let target = format!("/api/scan?path={}", given.display());
+            assert_eq!(server.respond(&Request::new("POST", &target)).status, 202);
+            assert_eq!(wait_for_scan(&server)["state"], "finished");
+            // Under the root as recorded, not a second copy under the other form
+            let found = db::search_files(store.connection(), Some(name), None, 10).unwrap();
+            let paths: Vec<_> = found.iter().map(|f| f.path.as_str()).collect();
+            assert_eq!(paths, [root.join(name).to_str().unwrap()]);
+        }
+    }
+
+    #[test]
+    fn test_serve_answers_over_http_until_cancelled() {
+        let tmp = tempfile::tempdir().unwrap();
+        let store = scanned_store(tmp.path());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let options = ServeOptions::default();
+        let cancel = options.cancel.clone();
+        let client = std::thread::spawn(move || {
+            // A client that never asks must not hold up the one that does
+            let _idle = TcpStream::connect(address).unwrap();
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(b"GET /api/stats HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            cancel.store(true, Ordering::Relaxed);
+            response
+        });
+        let mut seen = Vec::new();
+        Server::new(&store, options)
+            .serve(&listener, |request, response| {
+                seen.push((request.path.clone(), response.status))
+            })
+            .unwrap();
+        let response = client.join().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{response}");
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        let stats: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(stats["total_files"], 3);
+        assert_eq!(seen, vec![("/api/stats".to_string(), 200)]);
+    }
+}
@@ -50,7 +50,7 @@ pub fn build_dir_index(conn: &Connection, scanned_dirs: &[&Path]) -> Result<DirI
     for row in rows {
         // Filter to scanned roots if any are specified
         if !scanned_dirs.is_empty() {
-            let p = Path::new(&row.path);
+            let p = utils::path_from_db(&row.path);
             if !scanned_dirs.iter().any(|root| p.starts_with(root)) {
                 continue;
             }
@@ -86,7 +86,7 @@ fn find_leaf_directories(conn: &Connection, scanned_dirs: &[&Path]) -> Result<Ve
             }
             scanned_dirs
                 .iter()
-                .any(|root| Path::new(p).starts_with(root))
+                .any(|root| utils::path_from_db(p).starts_with(root))
         })
         .collect();
 
@@ -135,11 +135,7 @@ pub fn files_for_dir<'a>(
     result
 }
 
-fn compare_dirs_mem<'a>(
-    dir_index: &'a DirIndex,
-    path_a: &str,
-    path_b: &str,
-) -> Option<SimilarPair> {
+fn compare_dirs_mem(dir_index: &DirIndex, path_a: &str, path_b: &str) -> Option<SimilarPair> {
     let files_a = files_for_dir(dir_index, path_a);
     let files_b = files_for_dir(dir_index, path_b);
 
@@ -221,15 +217,11 @@ fn walk_up_similar_mem(
     threshold: f64,
     scanned_dirs: &[&Path],
 ) -> (String, String) {
-    loop {
-        let parent_a = match Path::new(&path_a).parent() {
-            Some(p) => p.to_string_lossy().to_string(),
-            None => break,
-        };
-        let parent_b = match Path::new(&path_b).parent() {
-            Some(p) => p.to_string_lossy().to_string(),
-            None => break,
-        };
+    while let (Some(parent_a), Some(parent_b)) =
+        (Path::new(&path_a).parent(), Path::new(&path_b).parent())
+    {
+        let parent_a = parent_a.to_string_lossy().to_string();
+        let parent_b = parent_b.to_string_lossy().to_string();
 
         // Stop if either current path is already a scanned root
         if !scanned_dirs.is_empty() {
@@ -282,6 +274,7 @@ pub enum MergeEvent<'a> {
 
 /// Per-file conflict choice; returning `KeepAllOld`/`KeepAllNew` locks in that
 /// choice for the remaining conflicts in this pair.
+#[allow(clippy::enum_variant_names)]
 pub enum FileConflictChoice {
     KeepOld,
     KeepNew,
@@ -303,14 +296,20 @@ pub fn merge_into(
 
     for (rel, src_abs) in &pair.only_in_a {
         let dst_abs = format!("{}/{}", pair.b.path, rel);
-        file_system::copy_file(Path::new(src_abs), Path::new(&dst_abs))?;
+        file_system::copy_file(
+            &utils::path_from_db(src_abs),
+            &utils::path_from_db(&dst_abs),
+        )?;
         on_event(MergeEvent::CopiedOnlyInA(rel));
         copied += 1;
     }
 
     for (rel, src_abs) in &pair.only_in_b {
         let dst_abs = format!("{}/{}", pair.a.path, rel);
-        file_system::copy_file(Path::new(src_abs), Path::new(&dst_abs))?;
+        file_system::copy_file(
+            &utils::path_from_db(src_abs),
+            &utils::path_from_db(&dst_abs),
+        )?;
         on_event(MergeEvent::CopiedOnlyInB(rel));
         copied += 1;
     }
@@ -360,10 +359,16 @@ pub fn merge_into(
         };
 
         if keep_newer {
-            file_system::copy_file(Path::new(newer_path), Path::new(older_path))?;
+            file_system::copy_file(
+                &utils::path_from_db(newer_path),
+                &utils::path_from_db(older_path),
+            )?;
             on_event(MergeEvent::KeptNewer(rel));
         } else {
-            file_system::copy_file(Path::new(older_path), Path::new(newer_path))?;
+            file_system::copy_file(
+                &utils::path_from_db(older_path),
+                &utils::path_from_db(newer_path),
+            )?;
             on_event(MergeEvent::KeptOlder(rel));
         }
         copied += 1;
@@ -410,7 +415,7 @@ pub fn compute_similar_pairs(
     }
 
     let mut candidate_pairs: Vec<(String, String)> = Vec::new();
-    for (_name, paths) in &by_name {
+    for paths in by_name.values() {
         if paths.len() < 2 {
             continue;
         }
@@ -535,16 +540,16 @@ mod tests {
     /// specific scenario they are testing.
     fn setup_two_photo_dirs(conn: &Connection, shared_count: usize) {
         let mut batch = String::from(
-            "INSERT INTO directories VALUES ('/a/photos', 'hashA', 5000);
-             INSERT INTO directories VALUES ('/b/photos', 'hashB', 5000);",
+            "INSERT INTO directories (path, hash, size) VALUES ('/a/photos', 'hashA', 5000);
+             INSERT INTO directories (path, hash, size) VALUES ('/b/photos', 'hashB', 5000);",
         );
         for i in 1..=shared_count {
             batch.push_str(&format!(
-                "INSERT INTO files VALUES ('/a/photos/img{}.jpg', 'fh{}', 500, 1000);",
+                "INSERT INTO files (path, hash, size, modified) VALUES ('/a/photos/img{}.jpg', 'fh{}', 500, 1000);",
                 i, i
             ));
             batch.push_str(&format!(
-                "INSERT INTO files VALUES ('/b/photos/img{}.jpg', 'fh{}', 500, 1000);",
+                "INSERT INTO files (path, hash, size, modified) VALUES ('/b/photos/img{}.jpg', 'fh{}', 500, 1000);",
                 i, i
             ));
         }
@@ -578,7 +583,7 @@ mod tests {
         let conn = open_test_db();
         setup_two_photo_dirs(&conn, 9);
         conn.execute_batch(
-            "INSERT INTO files VALUES ('/a/photos/Thumbs.db', 'thumbhash', 10, 1000);",
+            "INSERT INTO files (path, hash, size, modified) VALUES ('/a/photos/Thumbs.db', 'thumbhash', 10, 1000);",
         )
         .unwrap();
         let pairs = compute_similar_pairs(&conn, 0.85, &[], |_| ()).unwrap();
@@ -593,9 +598,9 @@ mod tests {
     fn test_build_dir_index_groups_by_parent() {
         let conn = open_test_db();
         conn.execute_batch(
-            "INSERT INTO directories VALUES ('/a/photos', 'dh1', 5000);
-             INSERT INTO files VALUES ('/a/photos/img1.jpg', 'fh1', 100, 1000);
-             INSERT INTO files VALUES ('/a/photos/img2.jpg', 'fh2', 200, 2000);",
+            "INSERT INTO directories (path, hash, size) VALUES ('/a/photos', 'dh1', 5000);
+             INSERT INTO files (path, hash, size, modified) VALUES ('/a/photos/img1.jpg', 'fh1', 100, 1000);
+             INSERT INTO files (path, hash, size, modified) VALUES ('/a/photos/img2.jpg', 'fh2', 200, 2000);",
         )
         .unwrap();
         let index = build_dir_index(&conn, &[]).unwrap();
@@ -611,10 +616,10 @@ mod tests {
     fn test_build_dir_index_filters_to_scanned_roots() {
         let conn = open_test_db();
         conn.execute_batch(
-            "INSERT INTO directories VALUES ('/a/photos', 'dh1', 5000);
-             INSERT INTO directories VALUES ('/b/photos', 'dh2', 5000);
-             INSERT INTO files VALUES ('/a/photos/img1.jpg', 'fh1', 100, 1000);
-             INSERT INTO files VALUES ('/b/photos/img1.jpg', 'fh2', 100, 1000);",
+            "INSERT INTO directories (path, hash, size) VALUES ('/a/photos', 'dh1', 5000);
+             INSERT INTO directories (path, hash, size) VALUES ('/b/photos', 'dh2', 5000);
+             INSERT INTO files (path, hash, size, modified) VALUES ('/a/photos/img1.jpg', 'fh1', 100, 1000);
+             INSERT INTO files (path, hash, size, modified) VALUES ('/b/photos/img1.jpg', 'fh2', 100, 1000);",
         )
         .unwrap();
         let root = std::path::Path::new("/a");
@@ -633,10 +638,10 @@ mod tests {
     fn test_files_for_dir_includes_subdirs_recursively() {
         let conn = open_test_db();
         conn.execute_batch(
-            "INSERT INTO directories VALUES ('/a', 'dh0', 5000);
-             INSERT INTO directories VALUES ('/a/sub', 'dh1', 5000);
-             INSERT INTO files VALUES ('/a/root.txt', 'fh0', 50, 1000);
-             INSERT INTO files VALUES ('/a/sub/child.txt', 'fh1', 50, 1000);",
+            "INSERT INTO directories (path, hash, size) VALUES ('/a', 'dh0', 5000);
+             INSERT INTO directories (path, hash, size) VALUES ('/a/sub', 'dh1', 5000);
+             INSERT INTO files (path, hash, size, modified) VALUES ('/a/root.txt', 'fh0', 50, 1000);
+             INSERT INTO files (path, hash, size, modified) VALUES ('/a/sub/child.txt', 'fh1', 50, 1000);",
         )
         .unwrap();
         let index = build_dir_index(&conn, &[]).unwrap();
@@ -659,8 +664,8 @@ mod tests {
         let conn = open_test_db();
         setup_two_photo_dirs(&conn, 9);
         conn.execute_batch(
-            "INSERT INTO files VALUES ('/a/photos/conflict.jpg', 'hashA_conflict', 500, 1000);
-             INSERT INTO files VALUES ('/b/photos/conflict.jpg', 'hashB_conflict', 500, 2000);",
+            "INSERT INTO files (path, hash, size, modified) VALUES ('/a/photos/conflict.jpg', 'hashA_conflict', 500, 1000);
+             INSERT INTO files (path, hash, size, modified) VALUES ('/b/photos/conflict.jpg', 'hashB_conflict', 500, 2000);",
         )
         .unwrap();
         let pairs = compute_similar_pairs(&conn, 0.85, &[], |_| ()).unwrap();
@@ -683,11 +688,11 @@ mod tests {
         let mut batch = String::new();
         for i in 1..=9 {
             batch.push_str(&format!(
-                "INSERT INTO files VALUES ('/a/photos/only_a{}.jpg', 'fhA{}', 500, 1000);",
+                "INSERT INTO files (path, hash, size, modified) VALUES ('/a/photos/only_a{}.jpg', 'fhA{}', 500, 1000);",
                 i, i
             ));
             batch.push_str(&format!(
-                "INSERT INTO files VALUES ('/b/photos/only_b{}.jpg', 'fhB{}', 500, 1000);",
+                "INSERT INTO files (path, hash, size, modified) VALUES ('/b/photos/only_b{}.jpg', 'fhB{}', 500, 1000);",
                 i, i
             ));
         }
@@ -0,0 +1,218 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::Result;
+use rusqlite::Connection;
+
+use crate::{db, duplicates};
+
+/// Duplicate bytes attributed to one extension or directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bucket {
+    pub key: String,
+    pub files: usize,
+    pub bytes: i64,
+}
+
+/// Where the duplicate bytes in the database live. A file counts as
+/// duplicate when it belongs to a duplicate group, and every copy counts, so
+/// each bucket is what could shrink by deleting copies that live there.
+/// Hardlinks add nothing: they share their file's bytes.
+pub struct Stats {
+    pub duplicate_files: usize,
+    pub duplicate_bytes: i64,
+    /// Lowercased extension (`(none)` for files without one), largest first
+    pub by_extension: Vec<Bucket>,
+    /// First directory below each scanned root, largest first
+    pub by_directory: Vec<Bucket>,
+}
+
+impl Bucket {
+    /// Share of `total`, as a percentage.
+    pub fn percent_of(&self, total: i64) -> f64 {
+        if total == 0 {
+            0.0
+        } else {
+            self.bytes as f64 * 100.0 / total as f64
+        }
+    }
+}
+
+/// Break the duplicate files down by extension and by top-level directory.
+/// Only the database is read.
+pub fn collect(conn: &Connection) -> Result<Stats> {
+    let groups = duplicates::find_duplicate_files(conn)?;
+    let roots = scan_roots(&db::all_directory_paths(conn)?);
+
+    let mut by_extension: HashMap<String, Bucket> = HashMap::new();
+    let mut by_directory: HashMap<String, Bucket> = HashMap::new();
+    let mut duplicate_files = 0usize;
+    let mut duplicate_bytes = 0i64;
+    for file in groups.iter().flat_map(|g| &g.files) {
+        duplicate_files += 1;
+        duplicate_bytes += file.size;
+        let path = Path::new(&file.path);
+        add(&mut by_extension, extension_key(path), file.size);
+        add(&mut by_directory, top_level_dir(path, &roots), file.size);
+    }
+
+    Ok(Stats {
+        duplicate_files,
+        duplicate_bytes,
+        by_extension: sorted(by_extension),
+        by_directory: sorted(by_directory),
+    })
+}
+
+fn add(buckets: &mut HashMap<String, Bucket>, key: String, size: i64) {
+    let bucket = buckets.entry(key.clone()).or_insert(Bucket {
+        key,
+        files: 0,
+        bytes: 0,
+    });
+    bucket.files += 1;
+    bucket.bytes += size;
+}
+
+fn sorted(buckets: HashMap<String, Bucket>) -> Vec<Bucket> {
+    let mut buckets: Vec<Bucket> = buckets.into_values().collect();
+    buckets.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.key.cmp(&b.key)));
+    buckets
+}
+
+fn extension_key(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_else(|| "(none)".to_string())
+}
+
+/// The directories that were scanned as roots: those whose parent is not in
+/// the database.
+fn scan_roots(dirs: &[String]) -> Vec<String> {
+    let known: HashSet<&str> = dirs.iter().map(|d| d.as_str()).collect();
+    dirs.iter()
+        .filter(|d| {
+            Path::new(d)
+                .parent()
+                .and_then(|p| p.to_str())
+                .is_none_or(|p| !known.contains(p))
+        })
+        .cloned()
+        .collect()
+}
+
+/// The directory directly below the scan root containing `path`, or the
+/// root itself for files directly inside it. Files under no known root fall
+/// back to their parent directory.
+fn top_level_dir(path: &Path, roots: &[String]) -> String {
+    let root = roots
+        .iter()
+        .map(Path::new)
+        .filter(|r| path.starts_with(r))
+        .max_by_key(|r| r.components().count());
+    let Some(root) = root else {
+        return path
+            .parent()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default();
+    };
+    let rest = path.strip_prefix(root).unwrap_or(path);
+    let mut components = rest.components();
+    let first = components.next();
+    match (first, components.next()) {
+        (Some(dir), Some(_)) => root.join(dir).to_string_lossy().into_owned(),
+        _ => root.to_string_lossy().into_owned(),
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    fn seeded_db() -> Connection {
+        let conn = open_test_db();
+        for dir in [
+            "/home",
+            "/home/Downloads",
+            "/home/Photos",
+            "/home/Photos/2020",
+        ] {
+            db::upsert_directory(&conn, Path::new(dir), "d", 0).unwrap();
+        }
+        let files = [
+            ("/home/Downloads/a.JPG", "h1", 300),
+            ("/home/Photos/2020/a.jpg", "h1", 300),
+            ("/home/Downloads/notes", "h2", 50),
+            ("/home/notes", "h2", 50),
+            ("/home/Photos/unique.png", "h3", 999),
+        ];
+        for (path, hash, size) in files {
+            db::upsert_file(&conn, Path::new(path), hash, size, 0).unwrap();
+        }
+        conn
+    }
+
+    #[test]
+    fn test_collect_totals_every_copy() {
+        let stats = collect(&seeded_db()).unwrap();
+        assert_eq!(stats.duplicate_files, 4);
+        assert_eq!(stats.duplicate_bytes, 700);
+    }
+
+    #[test]
+    fn test_collect_by_extension() {
+        let stats = collect(&seeded_db()).unwrap();
+        let keys: Vec<(&str, usize, i64)> = stats
+            .by_extension
+            .iter()
+            .map(|b| (b.key.as_str(), b.files, b.bytes))
+            .collect();
+        assert_eq!(keys, vec![("jpg", 2, 600), ("(none)", 2, 100)]);
+    }
+
+    #[test]
+    fn test_collect_by_top_level_directory() {
+        let stats = collect(&seeded_db()).unwrap();
+        let keys: Vec<(&str, i64)> = stats
+            .by_directory
+            .iter()
+            .map(|b| (b.key.as_str(), b.bytes))
+            .collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("/home/Downloads", 350),
+                ("/home/Photos", 300),
+                ("/home", 50)
+            ]
+        );
+        assert!((stats.by_directory[0].percent_of(700) - 50.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_top_level_dir_without_known_root() {
+        assert_eq!(top_level_dir(Path::new("/x/y/z.txt"), &[]), "/x/y");
+    }
+
+    #[test]
+    fn test_collect_empty_database() {
+        let stats = collect(&open_test_db()).unwrap();
+        assert_eq!(stats.duplicate_bytes, 0);
+        assert!(stats.by_extension.is_empty());
+        assert!(stats.by_directory.is_empty());
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{ensure, Result};
+use rayon::prelude::*;
+use rusqlite::Connection;
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{db, lookalike, utils};
+
+/// Rows written per transaction while storing signatures.
+const TRANSACTION_BATCH_SIZE: usize = 1000;
+
+/// Extensions of the plain-text documents and source files compared
+/// (lowercase).
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "text", "md", "markdown", "rst", "adoc", "org", "tex", "csv", "tsv", "log", "srt",
+    "html", "htm", "xml", "json", "yaml", "yml", "toml", "ini", "cfg", "conf", "rtf", "rs", "py",
+    "c", "h", "cc", "cpp", "hpp", "cs", "java", "kt", "scala", "go", "js", "jsx", "ts", "tsx",
+    "rb", "php", "pl", "lua", "swift", "sh", "sql", "css",
+];
+
+/// Files larger than this are left out: they are rarely documents, and
+/// reading them whole would take a while.
+const MAX_TEXT_SIZE: i64 = 16 * 1024 * 1024;
+
+/// Words per shingle: enough that a shingle says something about word
+/// order, few enough that one edited word changes only a handful.
+const SHINGLE_WORDS: usize = 5;
+
+/// MinHash values per signature.
+pub const SIGNATURE_SLOTS: usize = 128;
+
+/// Slices a signature is cut into for locality-sensitive hashing: documents
+/// are only compared when one slice of theirs is identical. 32 slices of 4
+/// find nearly every pair at 80% alike, and most at 50%.
+const BANDS: usize = 32;
+
+/// Whether the file at `path` is text `signature` should be given, going by
+/// its extension.
+pub fn is_text(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| TEXT_EXTENSIONS.contains(&e.as_str()))
+}
+
+/// The text of the file at `path`, with any invalid UTF-8 replaced. Fails
+/// for a file holding NUL bytes, which is no text whatever its name.
+pub fn read_text(path: &Path) -> Result<String> {
+    let bytes = std::fs::read(path)?;
+    ensure!(!bytes.contains(&0), "not a text file");
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// The MinHash signature of `text`: for each of `SIGNATURE_SLOTS` hash
+/// functions, the least hash of any of its shingles (runs of
+/// `SHINGLE_WORDS` words, lowercased, punctuation and spacing ignored).
+/// Two signatures agree in about the fraction of slots that the two texts'
+/// sets of shingles overlap by. Empty for text with no words; text with
+/// fewer than `SHINGLE_WORDS` is one shingle.
+pub fn signature(text: &str) -> Vec<u64> {
+    let words: Vec<String> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    if words.is_empty() {
+        return Vec::new();
+    }
+    let seeds: Vec<u64> = (0..SIGNATURE_SLOTS as u64).map(|i| mix(i + 1)).collect();
+    let mut slots = vec![u64::MAX; SIGNATURE_SLOTS];
+    for shingle in words.windows(SHINGLE_WORDS.min(words.len())) {
+        let mut hasher = Xxh3::new();
+        for word in shingle {
+            hasher.update(word.as_bytes());
+            hasher.update(b" ");
+        }
+        let hash = hasher.digest();
+        for (slot, seed) in slots.iter_mut().zip(&seeds) {
+            *slot = (*slot).min(mix(hash ^ seed));
+        }
+    }
+    slots
+}
+
+/// SplitMix64's finaliser: turns a shingle's hash into the one the slot
+/// with that seed ranks it by.
+fn mix(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// The fraction of slots in which two signatures agree: an estimate of how
+/// much of their shingles two texts share. 0 for signatures of different
+/// lengths.
+pub fn similarity(a: &[u64], b: &[u64]) -> f64 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+    let same = a.iter().zip(b).filter(|(x, y)| x == y).count();
+    same as f64 / a.len() as f64
+}
+
+/// One distinct text: every file with the same content counts once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    /// The files holding it, sorted
+    pub paths: Vec<String>,
+    pub size: i64,
+    pub signature: Vec<u64>,
+}
+
+/// Texts at least the threshold alike, the largest first: an edited copy
+/// has usually grown.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster {
+    pub documents: Vec<Document>,
+}
+
+impl Cluster {
+    pub fn total_size(&self) -> i64 {
+        self.documents
+            .iter()
+            .map(|d| d.size * d.paths.len() as i64)
+            .sum()
+    }
+}
+
+/// What `find_similar_text` found.
+pub struct SimilarText {
+    /// Largest first
+    pub clusters: Vec<Cluster>,
+    /// Files with a text extension that turned out not to be text or
+    /// couldn't be read, and so aren't in any cluster
+    pub unreadable: usize,
+}
+
+/// Cluster the text files among `lookalike::candidates` whose signatures
+/// have a `similarity` of at least `threshold`. A cluster links documents
+/// through any chain of such pairs. Files are only read when the database
+/// holds no signature for their current content; those signatures are stored
+/// for next time. Files with no words and files over `MAX_TEXT_SIZE` are
+/// left out.
+pub fn find_similar_text(
+    conn: &Connection,
+    threshold: f64,
+    scope: &[&Path],
+    on_progress: impl Fn(usize, usize) + Sync,
+) -> Result<SimilarText> {
+    let files = lookalike::candidates(conn, scope, |f, path| {
+        f.size > 0 && f.size <= MAX_TEXT_SIZE && is_text(path)
+    })?;
+
+    let mut stored = db::text_signatures(conn)?;
+    let stale: Vec<&db::FileRecord> = files
+        .iter()
+        .filter(|f| stored.get(&f.path).is_none_or(|s| s.hash != f.hash))
+        .collect();
+    let done = AtomicUsize::new(0);
+    let fresh: Vec<(&db::FileRecord, Option<Vec<u64>>)> = stale
+        .par_iter()
+        .map(|&file| {
+            let signature = read_text(&utils::path_from_db(&file.path))
+                .ok()
+                .map(|text| signature(&text));
+            on_progress(done.fetch_add(1, Ordering::Relaxed) + 1, stale.len());
+            (file, signature)
+        })
+        .collect();
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    for (file, signature) in fresh {
+        let record = db::TextSignatureRecord {
+            hash: file.hash.clone(),
+            signature,
+        };
+        db::set_text_signature(conn, &file.path, &record)?;
+        stored.insert(file.path.clone(), record);
+        batch.tick()?;
+    }
+    db::prune_text_signatures(conn)?;
+    batch.commit()?;
+
+    let mut unreadable = 0;
+    let mut documents = Vec::new();
+    for copies in lookalike::by_content(&files) {
+        let file = copies[0];
+        let Some(signature) = stored.get(&file.path).and_then(|s| s.signature.as_ref()) else {
+            unreadable += copies.len();
+            continue;
+        };
+        if signature.len() != SIGNATURE_SLOTS {
+            continue;
+        }
+        documents.push(Document {
+            paths: copies.iter().map(|f| f.path.clone()).collect(),
+            size: file.size,
+            signature: signature.clone(),
+        });
+    }
+    Ok(SimilarText {
+        clusters: cluster(documents, threshold),
+        unreadable,
+    })
+}
+
+/// Group `documents` into clusters of two or more linked by pairs at least
+/// `threshold` alike. Only documents that share a whole band of their
+/// signatures are compared, rather than every pair in the library.
+fn cluster(documents: Vec<Document>, threshold: f64) -> Vec<Cluster> {
+    let rows = SIGNATURE_SLOTS / BANDS;
+    let mut sets = utils::DisjointSets::new(documents.len());
+    for band in 0..BANDS {
+        let mut buckets: HashMap<&[u64], Vec<usize>> = HashMap::new();
+        for (index, document) in documents.iter().enumerate() {
+            let slice = &document.signature[band * rows..(band + 1) * rows];
+            buckets.entry(slice).or_default().push(index);
+        }
+        for bucket in buckets.values().filter(|b| b.len() > 1) {
+            for (k, &i) in bucket.iter().enumerate() {
+                for &j in &bucket[k + 1..] {
+                    if sets.find(i) != sets.find(j)
+                        && similarity(&documents[i].signature, &documents[j].signature) >= threshold
+                    {
+                        sets.union(i, j);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<Document>> = HashMap::new();
+    for (index, mut document) in documents.into_iter().enumerate() {
+        document.paths.sort();
+        members.entry(sets.find(index)).or_default().push(document);
+    }
+    let mut clusters: Vec<Cluster> = members
+        .into_values()
+        .filter(|documents| documents.len() > 1)
+        .map(|mut documents| {
+            documents.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.paths.cmp(&b.paths)));
+            Cluster { documents }
+        })
+        .collect();
+    clusters.sort_by(|a, b| {
+        b.total_size()
+            .cmp(&a.total_size())
+            .then_with(|| a.documents[0].paths.cmp(&b.documents[0].paths))
+    });
+    clusters
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A few hundred words of made-up prose, different for every seed.
+    fn prose(seed: u32, words: usize) -> String {
+        let vocabulary = [
+            "the", "report", "shows", "that", "our", "budget", "for", "next", "year", "will",
+            "grow", "slowly", "while", "costs", "fall", "and", "staff", "remain", "busy", "with",
+            "projects", "across", "every", "region", "this", "spring",
+        ];
+        let mut state = seed;
+        let mut text = Vec::new();
+        for _ in 0..words {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            text.push(vocabulary[(state >> 16) as usize % vocabulary.len()]);
+        }
+        text.join(" ")
+    }
+
+    fn document(path: &str, size: i64, text: &str) -> Document {
+        Document {
+            paths: vec![path.to_string()],
+            size,
+            signature: signature(text),
+        }
+    }
+
+    #[test]
+    fn test_signature_ignores_case_and_punctuation() {
+        let a = signature("Hello, world! This is a test of the signature.");
+        let b = signature("hello world -- this is A TEST of the\n\nsignature");
+        assert_eq!(a.len(), SIGNATURE_SLOTS);
+        assert_eq!(similarity(&a, &b), 1.0);
+        assert!(signature("  ... \n").is_empty());
+        assert_eq!(signature("two words").len(), SIGNATURE_SLOTS);
+    }
+
+    #[test]
+    fn test_similarity_estimates_how_much_text_is_shared() {
+        let text = prose(1, 400);
+        let mut words: Vec<&str> = text.split(' ').collect();
+        words[200] = "edited";
+        let edited = words.join(" ");
+        let other = prose(2, 400);
+
+        let (a, b, c) = (signature(&text), signature(&edited), signature(&other));
+        assert!(similarity(&a, &b) > 0.9, "{}", similarity(&a, &b));
+        assert!(similarity(&a, &c) < 0.3, "{}", similarity(&a, &c));
+        // Half the text, half the shingles
+        let half = signature(&words[..200].join(" "));
+        let estimate = similarity(&a, &half);
+        assert!((0.35..0.65).contains(&estimate), "{estimate}");
+        assert_eq!(similarity(&a, &[]), 0.0);
+    }
+
+    #[test]
+    fn test_cluster_links_edited_copies_and_leaves_the_rest() {
+        let text = prose(1, 300);
+        let edited = format!("{text} and one more sentence at the end");
+        let clusters = cluster(
+            vec![
+                document("/notes.txt", 10, &text),
+                document("/notes (edited).txt", 20, &edited),
+                document("/other.txt", 30, &prose(3, 300)),
+            ],
+            0.8,
+        );
+        assert_eq!(clusters.len(), 1);
+        let paths: Vec<&str> = clusters[0]
+            .documents
+            .iter()
+            .map(|d| d.paths[0].as_str())
+            .collect();
+        assert_eq!(paths, vec!["/notes (edited).txt", "/notes.txt"]);
+    }
+
+    #[test]
+    fn test_find_similar_text_stores_signatures_and_skips_what_it_cannot_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let text = prose(5, 300);
+        std::fs::write(tmp.path().join("a.md"), &text).unwrap();
+        std::fs::write(tmp.path().join("b.txt"), format!("# Title\n\n{text}\n")).unwrap();
+        std::fs::write(tmp.path().join("copy.md"), &text).unwrap();
+        std::fs::write(tmp.path().join("c.txt"), prose(6, 300)).unwrap();
+        std::fs::write(tmp.path().join("binary.txt"), b"\x00\x01\x02").unwrap();
+        std::fs::write(tmp.path().join("blank.txt"), "\n\n").unwrap();
+        std::fs::write(tmp.path().join("photo.jpg"), &text).unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        let opts = crate::scan::ScanOptions::default();
+        crate::scan::scan_directory(&conn, tmp.path(), 4, &opts, |_, _, _| {}).unwrap();
+
+        let found = find_similar_text(&conn, 0.8, &[], |_, _| {}).unwrap();
+        assert_eq!(found.unreadable, 1);
+        assert_eq!(found.clusters.len(), 1);
+        let documents = &found.clusters[0].documents;
+        assert_eq!(documents.len(), 2);
+        // The exact copy is listed with its document
+        assert_eq!(documents[1].paths.len(), 2);
+        assert_eq!(db::text_signatures(&conn).unwrap().len(), 6);
+
+        // Stored signatures are used rather than reading the files again
+        std::fs::remove_file(tmp.path().join("b.txt")).unwrap();
+        let again = find_similar_text(&conn, 0.8, &[], |_, _| {}).unwrap();
+        assert_eq!(again.clusters, found.clusters);
+    }
+}
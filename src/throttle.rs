@@ -0,0 +1,168 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+/// How far ahead of its rate a reader may get before it has to wait, so a
+/// burst of small files isn't slowed down one read at a time.
+const BURST: Duration = Duration::from_millis(100);
+
+/// The read budget shared by every hashing thread in the process, so
+/// `--throttle` caps the scan as a whole rather than each worker.
+static BUDGET: Mutex<Budget> = Mutex::new(Budget { next: None });
+
+/// A rate limiter that tracks the moment the bytes read so far will have
+/// been paid for; a reader waits until that is at most `BURST` away.
+struct Budget {
+    next: Option<Instant>,
+}
+
+impl Budget {
+    /// Book `bytes` read at `now` against `rate` bytes per second, returning
+    /// how long the reader should wait before carrying on.
+    fn reserve(&mut self, now: Instant, rate: u64, bytes: u64) -> Duration {
+        let start = self.next.map_or(now, |next| next.max(now));
+        let next = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+        self.next = Some(next);
+        next.saturating_duration_since(now + BURST)
+    }
+}
+
+/// Wait as long as it takes for `bytes` more to stay within `rate` bytes per
+/// second across every thread. A rate of 0 never waits.
+pub fn consume(rate: u64, bytes: usize) {
+    if rate == 0 || bytes == 0 {
+        return;
+    }
+    let wait = {
+        let mut budget = BUDGET.lock().unwrap_or_else(|e| e.into_inner());
+        budget.reserve(Instant::now(), rate, bytes as u64)
+    };
+    if !wait.is_zero() {
+        thread::sleep(wait);
+    }
+}
+
+/// Lower this process's CPU priority, like `nice -n 10`. Returns `false`
+/// where that isn't supported.
+#[cfg(unix)]
+pub fn lower_cpu_priority() -> Result<bool> {
+    // nice() returns the new niceness, which can't be -1 after raising it
+    if unsafe { libc::nice(10) } == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(true)
+}
+
+#[cfg(windows)]
+pub fn lower_cpu_priority() -> Result<bool> {
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x4000;
+    windows::set_priority_class(BELOW_NORMAL_PRIORITY_CLASS)?;
+    Ok(true)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn lower_cpu_priority() -> Result<bool> {
+    Ok(false)
+}
+
+/// Only read from disk when nothing else wants to: the idle I/O class on
+/// Linux (`ionice -c 3`), background priority on macOS and Windows, which
+/// also throttles the disk. Returns `false` where there is no such thing.
+#[cfg(target_os = "linux")]
+pub fn idle_io_priority() -> Result<bool> {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_long = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+    let priority = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+    let rc = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, priority) };
+    if rc == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(true)
+}
+
+#[cfg(target_os = "macos")]
+pub fn idle_io_priority() -> Result<bool> {
+    let rc = unsafe { libc::setpriority(libc::PRIO_DARWIN_PROCESS, 0, libc::PRIO_DARWIN_BG) };
+    if rc == -1 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(true)
+}
+
+#[cfg(windows)]
+pub fn idle_io_priority() -> Result<bool> {
+    const PROCESS_MODE_BACKGROUND_BEGIN: u32 = 0x0010_0000;
+    windows::set_priority_class(PROCESS_MODE_BACKGROUND_BEGIN)?;
+    Ok(true)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn idle_io_priority() -> Result<bool> {
+    Ok(false)
+}
+
+#[cfg(windows)]
+mod windows {
+    use anyhow::Result;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentProcess() -> isize;
+        fn SetPriorityClass(process: isize, class: u32) -> i32;
+    }
+
+    pub fn set_priority_class(class: u32) -> Result<()> {
+        if unsafe { SetPriorityClass(GetCurrentProcess(), class) } == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget_lets_a_burst_through_then_paces_reads() {
+        let mut budget = Budget { next: None };
+        let now = Instant::now();
+        // 1000 bytes/s: 100 bytes fit in the burst, 500 more must wait 0.5s
+        assert_eq!(budget.reserve(now, 1000, 100), Duration::ZERO);
+        assert_eq!(budget.reserve(now, 1000, 500), Duration::from_millis(500));
+        // Having waited, the reader is back within its budget
+        let later = now + Duration::from_secs(1);
+        assert_eq!(budget.reserve(later, 1000, 100), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_budget_does_not_bank_idle_time() {
+        let mut budget = Budget { next: None };
+        let now = Instant::now();
+        budget.reserve(now, 1000, 100);
+        // An idle minute doesn't buy a minute's worth of unthrottled reading
+        let later = now + Duration::from_secs(60);
+        assert_eq!(
+            budget.reserve(later, 1000, 1000),
+            Duration::from_millis(900)
+        );
+    }
+
+    #[test]
+    fn test_consume_without_a_rate_never_waits() {
+        let start = Instant::now();
+        consume(0, usize::MAX);
+        assert!(start.elapsed() < BURST);
+    }
+}
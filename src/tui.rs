@@ -0,0 +1,599 @@
+use std::io::{self, IsTerminal};
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::Paragraph;
+use ratatui::Frame;
+use rusqlite::Connection;
+
+use crate::review::{Mark, Refusal, Review};
+use crate::{dedupe, hashing, ui, utils};
+
+// ---------------------------------------------------------------------------
+// The `tui` command: a full-screen list of the duplicate file groups, drawn
+// with ratatui on a crossterm terminal.
+// ---------------------------------------------------------------------------
+
+/// Title, separator, status and help lines around the two lists.
+const CHROME_ROWS: usize = 4;
+
+/// How the review's marks are carried out once applied.
+pub struct TuiOptions {
+    /// For the copies marked delete
+    pub delete: dedupe::ApplyOptions,
+    /// For the copies marked link; its action is a linking one
+    pub link: dedupe::ApplyOptions,
+    pub algorithm: hashing::HashAlgorithm,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Left,
+    Right,
+    Tab,
+    Enter,
+    Escape,
+    Char(char),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Focus {
+    Groups,
+    Files,
+}
+
+/// A question on the status line, answered with y.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Pending {
+    Apply,
+    Quit,
+}
+
+struct App {
+    review: Review,
+    focus: Focus,
+    group: usize,
+    file: usize,
+    /// The first group and file shown
+    group_top: usize,
+    file_top: usize,
+    /// The terminal's height, as of the last resize
+    rows: usize,
+    status: String,
+    pending: Option<Pending>,
+}
+
+/// The terminal in raw mode on the alternate screen, restored on drop.
+struct Screen(ratatui::DefaultTerminal);
+
+impl Screen {
+    fn enter() -> Result<Self> {
+        Ok(Self(ratatui::try_init()?))
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        ratatui::restore();
+    }
+}
+
+/// Go through the duplicate file groups under `scope` on the terminal and
+/// carry out the marks once applied. Returns whether there were duplicates.
+pub fn run(conn: &Connection, scope: &[&Path], opts: &TuiOptions) -> Result<bool> {
+    if !io::stdin().is_terminal() || !io::stdout().is_terminal() {
+        bail!("tui needs a terminal; use `dedupe --interactive` to answer prompts instead");
+    }
+    let groups = dedupe::groups_in_scope(conn, scope)?;
+    if groups.is_empty() {
+        ui::show_no_duplicate_files();
+        return Ok(false);
+    }
+    let (_, rows) = crossterm::terminal::size().unwrap_or((80, 24));
+    let mut app = App::new(
+        Review::new(groups, dedupe::ProtectedPaths::load(conn)?),
+        rows.into(),
+    );
+    let apply = {
+        let mut screen = Screen::enter()?;
+        loop {
+            screen.0.draw(|frame| app.draw(frame))?;
+            let key = match event::read()? {
+                Event::Key(key) => key_for(key),
+                Event::Resize(_, rows) => {
+                    app.resize(rows.into());
+                    None
+                }
+                _ => None,
+            };
+            let Some(key) = key else {
+                continue;
+            };
+            match app.handle(key) {
+                Some(Pending::Apply) => break true,
+                Some(Pending::Quit) => break false,
+                None => {}
+            }
+        }
+    };
+    if apply {
+        let (deletes, links) = app.review.plans();
+        for (plans, apply) in [(&deletes, &opts.delete), (&links, &opts.link)] {
+            if !plans.is_empty() {
+                let stats = dedupe::apply_plans(conn, plans, apply, opts.algorithm)?;
+                ui::show_dedupe_stats(&stats, apply);
+            }
+        }
+    } else if app.review.tally() != Default::default() {
+        println!("Nothing changed.");
+    }
+    Ok(true)
+}
+
+/// The key a key event is, if it is one the lists use: presses only, with
+/// Ctrl-C as the `\u{3}` a terminal in raw mode would send.
+fn key_for(event: KeyEvent) -> Option<Key> {
+    if event.kind != KeyEventKind::Press {
+        return None;
+    }
+    let key = match event.code {
+        KeyCode::Char('c') if event.modifiers.contains(KeyModifiers::CONTROL) => Key::Char('\u{3}'),
+        KeyCode::Up => Key::Up,
+        KeyCode::Down => Key::Down,
+        KeyCode::PageUp => Key::PageUp,
+        KeyCode::PageDown => Key::PageDown,
+        KeyCode::Home => Key::Home,
+        KeyCode::End => Key::End,
+        KeyCode::Left => Key::Left,
+        KeyCode::Right => Key::Right,
+        KeyCode::Tab | KeyCode::BackTab => Key::Tab,
+        KeyCode::Enter => Key::Enter,
+        KeyCode::Esc => Key::Escape,
+        KeyCode::Char(c) => Key::Char(c),
+        _ => return None,
+    };
+    Some(key)
+}
+
+impl App {
+    fn new(review: Review, rows: usize) -> Self {
+        Self {
+            review,
+            focus: Focus::Groups,
+            group: 0,
+            file: 0,
+            group_top: 0,
+            file_top: 0,
+            rows,
+            status: String::new(),
+            pending: None,
+        }
+    }
+
+    /// Fit the lists to a terminal `rows` high, keeping the cursors in view.
+    fn resize(&mut self, rows: usize) {
+        self.rows = rows;
+        let (list_rows, files_rows) = (self.list_rows(), self.files_rows());
+        scroll_into_view(&mut self.group_top, self.group, list_rows);
+        scroll_into_view(&mut self.file_top, self.file, files_rows);
+    }
+
+    /// Act on `key`; returns what was confirmed, if anything.
+    fn handle(&mut self, key: Key) -> Option<Pending> {
+        if let Some(pending) = self.pending.take() {
+            self.status.clear();
+            return (key == Key::Char('y') || key == Key::Char('Y')).then_some(pending);
+        }
+        self.status.clear();
+        let page = self.list_rows().max(1);
+        match (key, self.focus) {
+            (Key::Up, _) => self.move_cursor(-1),
+            (Key::Down, _) => self.move_cursor(1),
+            (Key::PageUp, _) => self.move_cursor(-(page as isize)),
+            (Key::PageDown, _) => self.move_cursor(page as isize),
+            (Key::Home, _) => self.move_cursor(isize::MIN / 2),
+            (Key::End, _) => self.move_cursor(isize::MAX / 2),
+            (Key::Right | Key::Enter | Key::Tab, Focus::Groups) => self.focus = Focus::Files,
+            (Key::Left | Key::Escape | Key::Tab, Focus::Files) => self.focus = Focus::Groups,
+            (Key::Char('k'), _) => self.mark(Mark::Keep),
+            (Key::Char('d'), _) => self.mark(Mark::Delete),
+            (Key::Char('l'), _) => self.mark(Mark::Link),
+            (Key::Char('o'), _) => self.review.keep_only(self.group, self.file, Mark::Delete),
+            (Key::Char('O'), _) => self.review.keep_only(self.group, self.file, Mark::Link),
+            (Key::Char('r'), _) => self.review.reset(self.group),
+            (Key::Char('a'), _) => {
+                let tally = self.review.tally();
+                if tally.deletes + tally.links == 0 {
+                    self.status = "Nothing is marked yet.".to_string();
+                } else {
+                    self.status = format!(
+                        "Delete {} file(s) and link {}, freeing {}? [y/N]",
+                        tally.deletes,
+                        tally.links,
+                        utils::fmt_size(tally.freed)
+                    );
+                    self.pending = Some(Pending::Apply);
+                }
+            }
+            (Key::Char('q') | Key::Char('\u{3}'), _) => {
+                if !(0..self.review.groups().len()).any(|g| self.review.is_marked(g)) {
+                    return Some(Pending::Quit);
+                }
+                self.status = "Quit without applying the marks? [y/N]".to_string();
+                self.pending = Some(Pending::Quit);
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn move_cursor(&mut self, by: isize) {
+        let step = |at: usize, len: usize| (at as isize + by).clamp(0, len as isize - 1) as usize;
+        match self.focus {
+            Focus::Groups => {
+                self.group = step(self.group, self.review.groups().len());
+                self.file = 0;
+                self.file_top = 0;
+            }
+            Focus::Files => {
+                self.file = step(self.file, self.review.groups()[self.group].files.len());
+            }
+        }
+    }
+
+    fn mark(&mut self, mark: Mark) {
+        match self.review.set(self.group, self.file, mark) {
+            Ok(()) => {
+                // On to the next copy, to go down a group quickly
+                let files = self.review.groups()[self.group].files.len();
+                if self.focus == Focus::Files && self.file + 1 < files {
+                    self.file += 1;
+                }
+            }
+            Err(Refusal::Protected) => {
+                self.status = "That copy is protected and is always kept.".to_string();
+            }
+            Err(Refusal::LastCopy) => {
+                self.status = "Keep at least one copy: mark another one keep first.".to_string();
+            }
+        }
+    }
+
+    /// Rows for the groups list; the files list gets the rest.
+    fn list_rows(&self) -> usize {
+        self.rows.saturating_sub(CHROME_ROWS) / 2
+    }
+
+    fn files_rows(&self) -> usize {
+        self.rows.saturating_sub(CHROME_ROWS + self.list_rows())
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        self.rows = frame.area().height.into();
+        let list_rows = self.list_rows();
+        let files_rows = self.files_rows();
+        scroll_into_view(&mut self.group_top, self.group, list_rows);
+        scroll_into_view(&mut self.file_top, self.file, files_rows);
+        let groups = self.review.groups();
+        let group = &groups[self.group];
+
+        let [title_area, groups_area, separator_area, files_area, status_area, help_area] =
+            Layout::vertical([
+                Constraint::Length(1),
+                Constraint::Length(list_rows as u16),
+                Constraint::Length(1),
+                Constraint::Min(0),
+                Constraint::Length(1),
+                Constraint::Length(1),
+            ])
+            .areas(frame.area());
+        let reverse = Style::new().add_modifier(Modifier::REVERSED);
+
+        let wasted: i64 = groups.iter().map(|g| g.wasted()).sum();
+        let tally = self.review.tally();
+        let title = format!(
+            " deduplifier  {} group(s), {} reclaimable   marked: {} delete, {} link, {} freed",
+            groups.len(),
+            utils::fmt_size(wasted),
+            tally.deletes,
+            tally.links,
+            utils::fmt_size(tally.freed)
+        );
+        frame.render_widget(Paragraph::new(title).style(reverse), title_area);
+
+        let group_lines: Vec<Line> = (self.group_top..groups.len())
+            .take(list_rows)
+            .map(|index| {
+                let g = &groups[index];
+                let size = g.files.first().map_or(0, |f| f.size);
+                let line = format!(
+                    "{} {:>10} wasted  {:>3} x {:>10}  {}",
+                    if self.review.is_marked(index) {
+                        '*'
+                    } else {
+                        ' '
+                    },
+                    utils::fmt_size(g.wasted()),
+                    g.files.len(),
+                    utils::fmt_size(size),
+                    g.files
+                        .first()
+                        .map_or(String::new(), |f| utils::display_db_path(&f.path)
+                            .into_owned())
+                );
+                Line::styled(line, self.cursor_style(index == self.group, Focus::Groups))
+            })
+            .collect();
+        frame.render_widget(Paragraph::new(group_lines), groups_area);
+
+        let separator = format!(
+            "── {} copies of {} ({}) ",
+            group.files.len(),
+            &group.hash[..group.hash.len().min(16)],
+            utils::fmt_size(group.files.first().map_or(0, |f| f.size))
+        );
+        let width = separator_area.width as usize;
+        frame.render_widget(
+            Paragraph::new(format!("{separator:─<width$}")),
+            separator_area,
+        );
+
+        let marks = self.review.marks(self.group);
+        let file_lines: Vec<Line> = (self.file_top..group.files.len())
+            .take(files_rows)
+            .map(|index| {
+                let file = &group.files[index];
+                let mark = match marks[index] {
+                    Mark::Keep if self.review.is_protected(self.group, index) => "KEEP*",
+                    Mark::Keep => "keep",
+                    Mark::Delete => "DELETE",
+                    Mark::Link => "LINK",
+                };
+                let links = match file.hardlinks.len() {
+                    0 => String::new(),
+                    n => format!("  (+{n} hardlink(s))"),
+                };
+                let line = format!(
+                    "  {:<6}  {}  {:>10}  {}{}",
+                    mark,
+                    utils::fmt_mtime(file.modified),
+                    utils::fmt_size(file.size),
+                    utils::display_db_path(&file.path),
+                    links
+                );
+                Line::styled(line, self.cursor_style(index == self.file, Focus::Files))
+            })
+            .collect();
+        frame.render_widget(Paragraph::new(file_lines), files_area);
+
+        frame.render_widget(
+            Paragraph::new(self.status.as_str()).style(Style::new().add_modifier(Modifier::BOLD)),
+            status_area,
+        );
+        let help = " ↑↓ move  Tab switch list  k keep  d delete  l link  o/O keep only this  \
+                    r reset  a apply  q quit";
+        frame.render_widget(Paragraph::new(help).style(reverse), help_area);
+    }
+
+    fn cursor_style(&self, selected: bool, list: Focus) -> Style {
+        match (selected, self.focus == list) {
+            (true, true) => Style::new().add_modifier(Modifier::REVERSED),
+            (true, false) => Style::new().add_modifier(Modifier::BOLD),
+            _ => Style::new(),
+        }
+    }
+}
+
+/// Move `top` so that `cursor` is among the `rows` shown from it.
+fn scroll_into_view(top: &mut usize, cursor: usize, rows: usize) {
+    if cursor < *top {
+        *top = cursor;
+    } else if rows > 0 && cursor >= *top + rows {
+        *top = cursor + 1 - rows;
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::duplicates::{DuplicateFileGroup, FileEntry};
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use std::path::PathBuf;
+
+    fn group(hash: &str, size: i64, paths: &[&str]) -> DuplicateFileGroup {
+        DuplicateFileGroup {
+            hash: hash.to_string(),
+            count: paths.len() as i64,
+            total_size: size * paths.len() as i64,
+            files: paths
+                .iter()
+                .map(|p| FileEntry {
+                    path: p.to_string(),
+                    size,
+                    modified: 0,
+                    hardlinks: Vec::new(),
+                    via_link: false,
+                })
+                .collect(),
+        }
+    }
+
+    /// Twelve groups of three copies, the largest first, the last copy of
+    /// each under the protected `/c`
+    fn app(rows: usize) -> App {
+        let groups = (0..12)
+            .map(|i| {
+                let name = format!("f{i:02}");
+                group(
+                    &format!("hash{i:02}"),
+                    1000 - i,
+                    &[
+                        &format!("/a/{name}"),
+                        &format!("/b/{name}"),
+                        &format!("/c/{name}"),
+                    ],
+                )
+            })
+            .collect();
+        App::new(
+            Review::new(
+                groups,
+                dedupe::ProtectedPaths::new(vec![PathBuf::from("/c")]),
+            ),
+            rows,
+        )
+    }
+
+    fn press(app: &mut App, keys: &[Key]) -> Option<Pending> {
+        keys.iter().map(|&key| app.handle(key)).last().flatten()
+    }
+
+    #[test]
+    fn test_keymap_takes_presses_and_ctrl_c() {
+        let key = |code, modifiers| key_for(KeyEvent::new(code, modifiers));
+        assert_eq!(key(KeyCode::Up, KeyModifiers::NONE), Some(Key::Up));
+        assert_eq!(
+            key(KeyCode::PageDown, KeyModifiers::NONE),
+            Some(Key::PageDown)
+        );
+        assert_eq!(key(KeyCode::BackTab, KeyModifiers::SHIFT), Some(Key::Tab));
+        assert_eq!(key(KeyCode::Esc, KeyModifiers::NONE), Some(Key::Escape));
+        assert_eq!(
+            key(KeyCode::Char('O'), KeyModifiers::SHIFT),
+            Some(Key::Char('O'))
+        );
+        assert_eq!(
+            key(KeyCode::Char('c'), KeyModifiers::CONTROL),
+            Some(Key::Char('\u{3}'))
+        );
+        assert_eq!(key(KeyCode::F(1), KeyModifiers::NONE), None);
+        let mut release = KeyEvent::new(KeyCode::Char('d'), KeyModifiers::NONE);
+        release.kind = KeyEventKind::Release;
+        assert_eq!(key_for(release), None);
+    }
+
+    #[test]
+    fn test_cursor_moves_within_the_lists() {
+        // Four rows for each list
+        let mut app = app(12);
+        press(&mut app, &[Key::Down, Key::Down]);
+        assert_eq!(app.group, 2);
+        press(&mut app, &[Key::Up, Key::Up, Key::Up]);
+        assert_eq!(app.group, 0);
+        press(&mut app, &[Key::PageDown]);
+        assert_eq!(app.group, 4);
+        press(&mut app, &[Key::End]);
+        assert_eq!(app.group, 11);
+
+        press(&mut app, &[Key::Tab, Key::Down, Key::Down, Key::Down]);
+        assert_eq!((app.focus, app.file), (Focus::Files, 2));
+        // Another group starts at its first copy
+        press(&mut app, &[Key::Escape, Key::Home]);
+        assert_eq!((app.focus, app.group, app.file), (Focus::Groups, 0, 0));
+
+        // A taller terminal pages further
+        app.resize(24);
+        press(&mut app, &[Key::PageDown]);
+        assert_eq!(app.group, 10);
+    }
+
+    #[test]
+    fn test_marks_move_on_and_keep_a_copy() {
+        let mut app = app(12);
+        press(&mut app, &[Key::Enter, Key::Char('d')]);
+        assert_eq!(app.review.marks(0), [Mark::Delete, Mark::Keep, Mark::Keep]);
+        assert_eq!(app.file, 1);
+        assert!(app.status.is_empty());
+
+        // The protected copy can't go
+        press(&mut app, &[Key::Down, Key::Char('d')]);
+        assert_eq!(app.status, "That copy is protected and is always kept.");
+
+        // The keeper toggles: keep the first again, then only the second
+        press(&mut app, &[Key::Home, Key::Char('k')]);
+        assert_eq!(app.review.marks(0), [Mark::Keep, Mark::Keep, Mark::Keep]);
+        assert_eq!(app.file, 1);
+        press(&mut app, &[Key::Char('O')]);
+        assert_eq!(app.review.marks(0), [Mark::Link, Mark::Keep, Mark::Keep]);
+        press(&mut app, &[Key::Char('r')]);
+        assert!(!app.review.is_marked(0));
+
+        // Without a protected copy, the last one kept stays
+        let groups = vec![group("pair", 10, &["/a/x", "/b/x"])];
+        let mut app = App::new(Review::new(groups, dedupe::ProtectedPaths::new(vec![])), 12);
+        press(&mut app, &[Key::Enter, Key::Char('d'), Key::Char('d')]);
+        assert_eq!(app.review.marks(0), [Mark::Delete, Mark::Keep]);
+        assert_eq!(
+            app.status,
+            "Keep at least one copy: mark another one keep first."
+        );
+    }
+
+    #[test]
+    fn test_apply_and_quit_ask_first() {
+        let mut app = app(12);
+        assert_eq!(press(&mut app, &[Key::Char('a')]), None);
+        assert_eq!(app.status, "Nothing is marked yet.");
+
+        press(&mut app, &[Key::Char('o')]);
+        assert_eq!(press(&mut app, &[Key::Char('a')]), None);
+        assert!(app.status.starts_with("Delete 1 file(s) and link 0"));
+        // Anything but y takes it back, and the marks stay
+        assert_eq!(press(&mut app, &[Key::Char('n')]), None);
+        assert!(app.status.is_empty() && app.review.is_marked(0));
+        assert_eq!(
+            press(&mut app, &[Key::Char('a'), Key::Char('y')]),
+            Some(Pending::Apply)
+        );
+
+        assert_eq!(press(&mut app, &[Key::Char('\u{3}')]), None);
+        assert_eq!(app.status, "Quit without applying the marks? [y/N]");
+        assert_eq!(press(&mut app, &[Key::Char('Y')]), Some(Pending::Quit));
+        // With nothing marked, there is nothing to lose
+        press(&mut app, &[Key::Char('r')]);
+        assert_eq!(press(&mut app, &[Key::Char('q')]), Some(Pending::Quit));
+    }
+
+    #[test]
+    fn test_draw_fits_the_terminal_it_is_given() {
+        let mut app = app(24);
+        press(&mut app, &[Key::End]);
+        let mut terminal = Terminal::new(TestBackend::new(60, 12)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        assert_eq!(app.rows, 12);
+        // The last group is scrolled into the four rows the groups have
+        assert_eq!(app.group_top, 8);
+        let screen: Vec<String> = terminal
+            .backend()
+            .buffer()
+            .content
+            .chunks(60)
+            .map(|row| row.iter().map(|cell| cell.symbol()).collect())
+            .collect();
+        assert!(screen[0].starts_with(" deduplifier  12 group(s)"));
+        assert!(screen[4].contains("/a/f11"), "{screen:#?}");
+        assert!(screen[5].starts_with("── 3 copies of hash11"));
+        assert!(screen[6].contains("keep") && screen[6].contains("/a/f11"));
+        assert!(screen[8].contains("KEEP*"));
+        assert!(screen[11].starts_with(" ↑↓ move"));
+    }
+}
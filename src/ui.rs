@@ -1,26 +1,220 @@
-use std::io::{self, BufRead, Write};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufRead, IsTerminal, Write};
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use rusqlite::Connection;
 
-use crate::{db, duplicates, file_system, hashing, merge, photos, scan, similar, utils};
+use crate::{
+    audio, chunks, clean, compare, compressed, containment, daemon, db, dedupe, doctor, duplicates,
+    events, file_system, hashing, history, hooks, ignore_rules, logging, manifest, merge, merge_db,
+    names, overlap, payload, perceptual, photos, remote, report, s3, scan, script, serve, similar,
+    stats, text, undo, utils, verify, video, watch, HashStore,
+};
+
+// ---------------------------------------------------------------------------
+// Quiet mode
+// ---------------------------------------------------------------------------
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Silence progress lines and section headers for the rest of the run, so
+/// only results, warnings and errors are printed. Stale-entry prompts are
+/// skipped too (the entries are kept; `clean` removes them).
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+// ---------------------------------------------------------------------------
+// Hooks
+// ---------------------------------------------------------------------------
+
+static HOOKS: OnceLock<hooks::Hooks> = OnceLock::new();
+
+/// Hand what the rest of the run finds to these commands: each duplicate
+/// group `dup-files`, `dup-dirs` and `report` list, and each scan's summary.
+pub fn set_hooks(hooks: hooks::Hooks) {
+    let _ = HOOKS.set(hooks);
+}
+
+/// Hand the event `event` makes to the `--on-duplicate-group` command and
+/// the `--events` stream, if there are any.
+fn group_hook(event: impl FnOnce() -> serde_json::Value) {
+    let command = HOOKS.get().and_then(|h| h.on_duplicate_group.as_deref());
+    if command.is_none() && EVENTS.get().is_none() {
+        return;
+    }
+    let event = event();
+    if let Some(command) = command {
+        run_hook(command, &event);
+    }
+    emit(|| event);
+}
+
+/// A failing hook is warned about; the run carries on.
+fn run_hook(command: &str, event: &serde_json::Value) {
+    if let Err(e) = hooks::run(command, event) {
+        tracing::warn!("hook {:#}", e);
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Events
+// ---------------------------------------------------------------------------
+
+static EVENTS: OnceLock<events::EventStream> = OnceLock::new();
+
+/// Stream what the rest of the run does to `stream` (see `--events`).
+pub fn set_events(stream: events::EventStream) {
+    let _ = EVENTS.set(stream);
+}
+
+/// Write the event `event` makes to the `--events` stream, if there is one.
+pub fn emit(event: impl FnOnce() -> serde_json::Value) {
+    if let Some(stream) = EVENTS.get() {
+        stream.emit(&event());
+    }
+}
+
+/// What scans tell of each file they hash: a `file_hashed` event, when
+/// events are streamed.
+pub fn on_hashed() -> Option<scan::OnHashed> {
+    EVENTS.get()?;
+    Some(scan::OnHashed(Arc::new(|path, size, hash| {
+        emit(|| events::file_hashed(path, size, hash))
+    })))
+}
 
 // ---------------------------------------------------------------------------
 // Scan progress
 // ---------------------------------------------------------------------------
 
-pub fn scan_progress(processed: usize, total: usize, file_name: &str) {
-    // \r - return to start of line; \x1B[K - clear to end of line
-    print!("\r\x1B[K{}/{} - {}", processed, total, file_name);
-    let _ = io::stdout().flush();
+/// A one-line progress bar for a scan or verify: how far through it is, the
+/// rate, an ETA and the current file. A bar given the bytes to get through
+/// measures itself by them, as files are hashed or found unchanged, so its
+/// rate and ETA are in bytes; one without counts files. Drawn only when
+/// stdout is a terminal and not in quiet mode, at most ten times a second.
+pub struct ProgressBar {
+    bar: indicatif::ProgressBar,
+    /// `scan::bytes_scanned` when the bar started, for a bar in bytes
+    bytes_at_start: Option<u64>,
+}
+
+impl ProgressBar {
+    /// A bar counting files, for scans and checks whose bytes aren't known
+    /// up front; its rate is of the bytes hashed meanwhile.
+    pub fn new() -> Self {
+        let hashed_at_start = hashing::bytes_hashed();
+        let style = Self::style("{pos}/{len} files  {hashed_per_sec}  ETA {eta}  {wide_msg}").map(
+            |style| {
+                style.with_key(
+                    "hashed_per_sec",
+                    move |state: &indicatif::ProgressState, w: &mut dyn std::fmt::Write| {
+                        let elapsed = state.elapsed().as_secs_f64();
+                        let hashed = hashing::bytes_hashed() - hashed_at_start;
+                        let rate = if elapsed > 0.0 {
+                            hashed as f64 / elapsed
+                        } else {
+                            0.0
+                        };
+                        let _ = write!(w, "{}/s", utils::fmt_size(rate as i64));
+                    },
+                )
+            },
+        );
+        Self::with_style(indicatif::ProgressBar::no_length(), style, None)
+    }
+
+    /// A bar through the `total_bytes` of a tree `hashing::measure_tree`
+    /// measured.
+    pub fn bytes(total_bytes: u64) -> Self {
+        let style =
+            Self::style("{bytes}/{total_bytes}  {binary_bytes_per_sec}  ETA {eta}  {wide_msg}");
+        Self::with_style(
+            indicatif::ProgressBar::new(total_bytes),
+            style,
+            Some(scan::bytes_scanned()),
+        )
+    }
+
+    /// The bar itself, then `counts`.
+    fn style(counts: &str) -> Option<indicatif::ProgressStyle> {
+        let style = indicatif::ProgressStyle::with_template(&format!("[{{bar:20}}] {counts}"));
+        Some(style.ok()?.progress_chars("#>."))
+    }
+
+    fn with_style(
+        bar: indicatif::ProgressBar,
+        style: Option<indicatif::ProgressStyle>,
+        bytes_at_start: Option<u64>,
+    ) -> Self {
+        let target = if !quiet() && io::stdout().is_terminal() {
+            indicatif::ProgressDrawTarget::stdout_with_hz(10)
+        } else {
+            indicatif::ProgressDrawTarget::hidden()
+        };
+        bar.set_draw_target(target);
+        if let Some(style) = style {
+            bar.set_style(style);
+        }
+        Self {
+            bar,
+            bytes_at_start,
+        }
+    }
+
+    pub fn update(&self, processed: usize, total: usize, file_name: &str) {
+        match self.bytes_at_start {
+            Some(start) => {
+                self.bar.set_position(scan::bytes_scanned() - start);
+                self.bar
+                    .set_message(format!("{processed}/{total} files  {file_name}"));
+            }
+            None => {
+                self.bar.set_length(total as u64);
+                self.bar.set_position(processed as u64);
+                self.bar.set_message(file_name.to_string());
+            }
+        }
+    }
+
+    /// End the bar's line, if one was drawn.
+    pub fn finish(&self) {
+        if !self.bar.is_hidden() && (self.bar.position() > 0 || !self.bar.message().is_empty()) {
+            self.bar.finish();
+        }
+    }
 }
 
 pub fn show_checking_stale() {
+    if quiet() {
+        return;
+    }
     println!("\nChecking for stale database entries (this may take several minutes for large directories)...");
     let _ = io::stdout().flush();
 }
 
+/// Ask for the key of an encrypted database, twice when `confirm` (a new
+/// database would otherwise be locked with a typo).
+#[cfg(feature = "sqlcipher")]
+pub fn prompt_database_key(confirm: bool) -> Result<String> {
+    let key = rpassword::prompt_password("Database key: ")?;
+    if key.is_empty() {
+        anyhow::bail!("no database key given");
+    }
+    if confirm && rpassword::prompt_password("Repeat the key: ")? != key {
+        anyhow::bail!("the keys don't match");
+    }
+    Ok(key)
+}
+
 pub fn prompt_delete_stale(stale_count: i64, root: &Path) -> Result<bool> {
     println!(
         "\n{} file(s) in the database no longer exist on disk under {:?}.",
@@ -46,40 +240,184 @@ pub fn show_skipped_stale() {
 // ---------------------------------------------------------------------------
 
 pub fn show_counting_files(dir: &Path) {
+    if quiet() {
+        return;
+    }
     println!("Counting files in directory: {:?}", dir);
 }
 
-pub fn show_file_count(count: usize) {
-    println!("Found {} files to process", count);
+pub fn show_file_count(count: usize, bytes: u64) {
+    if quiet() {
+        return;
+    }
+    println!(
+        "Found {} files ({}) to process",
+        count,
+        utils::fmt_size(bytes as i64)
+    );
 }
 
 pub fn show_scanning_dir(dir: &Path) {
+    if quiet() {
+        return;
+    }
     println!("Scanning directory: {:?}", dir);
 }
 
-pub fn run_scan(conn: &Connection, directories: &[&Path]) -> Result<()> {
-    let mut total_invalid_paths = 0usize;
-    for &directory in directories {
-        if !directory.exists() {
-            eprintln!(
-                "Warning: Directory {:?} does not exist, skipping",
-                directory
+/// How often `acquire_run_lock` checks whether the other run has finished.
+const LOCK_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// "`deduplifier scan /a` (pid 12, on nas) since 2024-01-02 03:04"
+fn describe_lock_holder(holder: &db::LockHolder) -> String {
+    let host = match &holder.host {
+        Some(host) => format!(", on {host}"),
+        None => String::new(),
+    };
+    format!(
+        "`{}` (pid {}{host}) since {}",
+        holder.command,
+        holder.pid,
+        utils::fmt_mtime(holder.started)
+    )
+}
+
+/// Take the database's run lock for `command`. Another run holding it is an
+/// error or, with `wait`, waited out.
+pub fn acquire_run_lock<'a>(
+    conn: &'a Connection,
+    command: &str,
+    wait: bool,
+) -> Result<db::RunLock<'a>> {
+    let mut waiting_for = None;
+    loop {
+        let holder = match db::try_lock(conn, command)? {
+            Ok(lock) => return Ok(lock),
+            Err(holder) => holder,
+        };
+        if !wait {
+            anyhow::bail!(
+                "the database is in use by {}; try again once it has finished, or pass --wait",
+                describe_lock_holder(&holder)
+            );
+        }
+        if waiting_for.as_ref() != Some(&holder) {
+            tracing::info!(
+                target: logging::NOTICE,
+                "Waiting for {} to finish...",
+                describe_lock_holder(&holder)
             );
+            waiting_for = Some(holder);
+        }
+        std::thread::sleep(LOCK_POLL_INTERVAL);
+    }
+}
+
+/// Warn that a command reading the database may see another run's work
+/// half done.
+pub fn show_run_in_progress(holder: &db::LockHolder) {
+    tracing::warn!(
+        "the database is being changed by {}; what is shown may be incomplete.",
+        describe_lock_holder(holder)
+    );
+}
+
+/// Scan each directory in turn, prompting about stale entries after each.
+/// Returns how many files or directories could not be scanned.
+pub fn run_scan(
+    conn: &Connection,
+    directories: &[&Path],
+    opts: &scan::ScanOptions,
+    resume: bool,
+    label: Option<&str>,
+) -> Result<usize> {
+    // scan_state records which roots this session has finished; a fresh run
+    // starts a new session, --resume carries on with the interrupted one.
+    if !resume {
+        db::clear_scan_state(conn)?;
+    }
+    let stored: Vec<_> = directories.iter().map(|d| utils::path_to_db(d)).collect();
+    let roots: Vec<&str> = stored.iter().map(|r| r.as_ref()).collect();
+    db::add_scan_roots(conn, &roots)?;
+    let completed = db::completed_scan_roots(conn)?;
+    let started = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64;
+    let scan_id = db::begin_scan(conn, started, &roots)?;
+    let timer = Instant::now();
+    let location = db::Location {
+        host: utils::hostname(),
+        volume: label.map(String::from),
+    };
+
+    let mut scan_errors = 0usize;
+    let mut errors: Vec<scan::ScanError> = Vec::new();
+    for &directory in directories {
+        let remote = remote::RemoteRoot::parse(directory)?;
+        let bucket = s3::S3Root::parse(directory)?;
+        if remote.is_none() && bucket.is_none() && !directory.exists() {
+            tracing::warn!("Directory {:?} does not exist, skipping", directory);
+            emit(|| {
+                let path = directory.to_string_lossy();
+                events::error(&path, "not_found", "the directory does not exist")
+            });
+            scan_errors += 1;
             continue;
         }
-        show_counting_files(directory);
-        let total_files = hashing::count_files(directory)?;
-        show_file_count(total_files);
-        show_scanning_dir(directory);
-        let result =
-            scan::scan_directory(conn, directory, total_files, |processed, total, name| {
-                scan_progress(processed, total, name);
-            })?;
-        total_invalid_paths += result.invalid_paths;
-        show_scan_newline();
-        if result.stale_count > 0 {
+        if completed
+            .iter()
+            .any(|c| utils::path_from_db(c) == directory)
+        {
+            show_resume_skipped(directory);
+            continue;
+        }
+        let progress;
+        let root_timer = Instant::now();
+        let result = if let Some(remote) = &remote {
+            emit(|| events::scan_started(scan_id, directory, None, None));
+            show_scanning_dir(directory);
+            progress = ProgressBar::new();
+            remote::scan_remote(conn, remote, opts, |processed, total, name| {
+                progress.update(processed, total, name)
+            })?
+        } else if let Some(bucket) = &bucket {
+            emit(|| events::scan_started(scan_id, directory, None, None));
+            show_scanning_dir(directory);
+            progress = ProgressBar::new();
+            s3::scan_bucket(conn, bucket, opts, |processed, total, name| {
+                progress.update(processed, total, name)
+            })?
+        } else {
+            show_counting_files(directory);
+            let (total_files, total_bytes) = hashing::measure_tree(directory, &opts.walk)?;
+            show_file_count(total_files, total_bytes);
+            emit(|| events::scan_started(scan_id, directory, Some(total_files), Some(total_bytes)));
+            show_scanning_dir(directory);
+            progress = ProgressBar::bytes(total_bytes);
+            scan::scan_directory(
+                conn,
+                directory,
+                total_files,
+                opts,
+                |processed, total, name| progress.update(processed, total, name),
+            )?
+        };
+        for error in &result.errors {
+            emit(|| events::scan_error(error));
+        }
+        emit(|| events::scan_finished(scan_id, &result, root_timer.elapsed()));
+        scan_errors += result.errors.len();
+        errors.extend(result.errors);
+        progress.finish();
+        if result.moved > 0 {
+            show_moved_files(result.moved);
+        }
+        if result.interrupted {
+            show_scan_interrupted();
+            return Err(scan::Interrupted.into());
+        }
+        if result.stale_count > 0 && !quiet() {
             show_checking_stale();
-            let root = std::path::Path::new(&result.root_str);
+            let root = &utils::path_from_db(&result.root_str);
             if prompt_delete_stale(result.stale_count, root)? {
                 db::delete_stale_files(conn, &result.root_str)?;
                 show_deleted_stale(result.stale_count);
@@ -87,62 +425,956 @@ pub fn run_scan(conn: &Connection, directories: &[&Path]) -> Result<()> {
                 show_skipped_stale();
             }
         }
+        db::record_scan_history(conn, scan_id, &result.root_str)?;
+        // Remote files are on their host, not this one, and objects in
+        // their bucket
+        let host = match (&remote, &bucket) {
+            (Some(remote), _) => Some(remote.host.clone()),
+            (_, Some(bucket)) => Some(format!("s3://{}", bucket.bucket)),
+            (None, None) => None,
+        };
+        match host {
+            Some(host) => {
+                let there = db::Location {
+                    host: Some(host),
+                    volume: location.volume.clone(),
+                };
+                db::tag_location(conn, &result.root_str, &there)?;
+            }
+            None => db::tag_location(conn, &result.root_str, &location)?,
+        }
+        db::mark_scan_root_complete(conn, &result.root_str)?;
+    }
+    db::clear_scan_state(conn)?;
+    if scan_errors > 0 {
+        warn_scan_errors(scan_errors, &errors);
+    }
+    if let Some(command) = HOOKS.get().and_then(|h| h.post_scan.as_deref()) {
+        let event = hooks::post_scan_event(conn, scan_id, &errors, timer.elapsed())?;
+        run_hook(command, &event);
+    }
+    Ok(scan_errors)
+}
+
+/// How many skipped paths the end-of-scan summary lists by name.
+const SCAN_ERRORS_SHOWN: usize = 20;
+
+/// Warn that `count` paths couldn't be scanned, listing those of `errors`
+/// with why, after the scan has finished.
+fn warn_scan_errors(count: usize, errors: &[scan::ScanError]) {
+    let lines: Vec<String> = errors
+        .iter()
+        .map(|error| format!("{}: {}", error.path, error.message))
+        .collect();
+    tracing::warn!(
+        "{count} file(s) or directory(ies) could not be scanned.{}",
+        listing(&lines)
+    );
+}
+
+/// `items`, a line each and indented, up to `SCAN_ERRORS_SHOWN` of them, for
+/// the end of a warning.
+fn listing(items: &[impl std::fmt::Display]) -> String {
+    let mut text = String::new();
+    for item in items.iter().take(SCAN_ERRORS_SHOWN) {
+        text.push_str(&format!("\n  {item}"));
+    }
+    if items.len() > SCAN_ERRORS_SHOWN {
+        text.push_str(&format!(
+            "\n  ... and {} more",
+            items.len() - SCAN_ERRORS_SHOWN
+        ));
+    }
+    text
+}
+
+/// Watch `directories` until Ctrl-C sets `opts.cancel`, refreshing the
+/// database after each batch of changes and saying what changed; with
+/// `duplicates`, files that turned up as copies of others are listed too.
+/// Returns how many files or directories could not be scanned.
+pub fn run_watch(
+    conn: &Connection,
+    directories: &[&Path],
+    opts: &scan::ScanOptions,
+    settle: std::time::Duration,
+    duplicates: bool,
+) -> Result<usize> {
+    let mut watcher = watch::Watcher::new(directories)?;
+    show_section(&format!(
+        "Watching {} directory(ies) for changes (Ctrl-C to stop)",
+        directories.len()
+    ));
+    let mut scan_errors = 0;
+    while let Some(changes) = watcher.wait(settle, &opts.cancel)? {
+        let update = watch::refresh(conn, &changes, opts)?;
+        if !quiet() && (update.updated > 0 || update.moved > 0 || update.removed > 0) {
+            let now = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs() as i64;
+            println!(
+                "{}  {} file(s) updated, {} moved, {} removed",
+                utils::fmt_mtime(now),
+                update.updated,
+                update.moved,
+                update.removed
+            );
+        }
+        if duplicates {
+            for duplicate in &update.duplicates {
+                println!("  {}", duplicate.path);
+                for copy in &duplicate.copies {
+                    println!("    same as {}", copy);
+                }
+            }
+        }
+        if !update.errors.is_empty() {
+            warn_scan_errors(update.errors.len(), &update.errors);
+            scan_errors += update.errors.len();
+        }
+        if update.interrupted {
+            break;
+        }
+    }
+    Ok(scan_errors)
+}
+
+/// Print what `daemon --config` would do: the database and log, and each
+/// root with its schedule and when it next comes round.
+pub fn show_daemon_schedule(config: &daemon::DaemonConfig) -> Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs() as i64;
+    if let Some(database) = &config.database {
+        println!("Database: {}", database.display());
+    }
+    match &config.log {
+        Some(log) => println!("Log:      {}", log.display()),
+        None => println!("Log:      standard error"),
+    }
+    for root in &config.roots {
+        let next = match root.schedule.next_after(now) {
+            Some(at) => format!("next {} UTC", utils::fmt_mtime(at)),
+            None => "never comes round".to_string(),
+        };
+        println!("{}", root.path.display());
+        println!("  schedule `{}`, {}", root.schedule_text, next);
+        if !root.exclude.is_empty() {
+            println!("  excluding {}", root.exclude.join(", "));
+        }
+        if root.keep_missing {
+            println!("  keeping missing files");
+        }
     }
-    if total_invalid_paths > 0 {
-        eprintln!(
-            "\nWarning: {} file path(s) with invalid UTF-8 were skipped during this scan.",
-            total_invalid_paths
+    Ok(())
+}
+
+/// Serve `store` on `listen` until `options.cancel` is set, printing each
+/// request answered.
+pub fn run_serve(
+    store: &HashStore,
+    listen: std::net::SocketAddr,
+    options: &serve::ServeOptions,
+) -> Result<()> {
+    let listener = std::net::TcpListener::bind(listen)
+        .with_context(|| format!("could not listen on {listen}"))?;
+    if !listen.ip().is_loopback() {
+        tracing::warn!(
+            "listening on {listen} without authentication; anyone who can reach it can \
+             read every path in the database{}.",
+            if options.allow_scan {
+                " and start scans"
+            } else {
+                ""
+            }
         );
-        eprintln!("Please rename these files and re-run to continue.");
-        std::process::exit(1);
+    }
+    show_section(&format!(
+        "Serving the database on http://{} (Ctrl-C to stop)",
+        listener.local_addr()?
+    ));
+    serve::Server::new(store, options.clone()).serve(&listener, |request, response| {
+        if !quiet() {
+            println!("{} {} {}", request.method, request.path, response.status);
+        }
+    })
+}
+
+pub fn run_prune(conn: &Connection, algorithm: hashing::HashAlgorithm) -> Result<()> {
+    let stats = clean::prune_missing(conn, algorithm)?;
+    show_prune_summary(stats.files_removed, stats.dirs_removed, stats.dirs_rehashed);
+    Ok(())
+}
+
+/// Add `paths` to the protect list.
+pub fn run_protect_add(conn: &Connection, paths: &[std::path::PathBuf]) -> Result<()> {
+    for path in paths {
+        if db::add_protected(conn, path)? {
+            println!("Protected {}", path.display());
+        } else {
+            println!("{} was already protected", path.display());
+        }
+    }
+    Ok(())
+}
+
+/// Take `paths` off the protect list.
+pub fn run_protect_remove(conn: &Connection, paths: &[std::path::PathBuf]) -> Result<()> {
+    for path in paths {
+        if db::remove_protected(conn, path)? {
+            println!("No longer protecting {}", path.display());
+        } else {
+            tracing::warn!("{} was not on the protect list", path.display());
+        }
+    }
+    Ok(())
+}
+
+pub fn run_protect_list(conn: &Connection) -> Result<()> {
+    let paths = db::protected_paths(conn)?;
+    if paths.is_empty() {
+        println!("No protected paths.");
+    }
+    for path in paths {
+        println!("{}", path);
+    }
+    Ok(())
+}
+
+/// Store each of `rules` as an ignore rule of `kind`, or of the kind it looks like.
+pub fn run_ignore_add(
+    conn: &Connection,
+    rules: &[String],
+    kind: Option<ignore_rules::RuleKind>,
+) -> Result<()> {
+    for rule in rules {
+        let kind = kind.unwrap_or_else(|| ignore_rules::RuleKind::guess(rule));
+        let pattern = ignore_rules::normalize(kind, rule)?;
+        if db::add_ignore_rule(conn, kind.name(), &pattern)? {
+            println!("Ignoring {} {}", kind.name(), pattern);
+        } else {
+            println!("{} {} was already ignored", kind.name(), pattern);
+        }
     }
     Ok(())
 }
 
-pub fn show_scan_newline() {
-    println!(); // blank line after progress bar
+/// Drop the ignore rules for `rules`, of `kind` or of any kind.
+pub fn run_ignore_remove(
+    conn: &Connection,
+    rules: &[String],
+    kind: Option<ignore_rules::RuleKind>,
+) -> Result<()> {
+    for rule in rules {
+        // Stored normalized, so look the rule up the same way
+        let kinds = match kind {
+            Some(kind) => vec![kind],
+            None => vec![
+                ignore_rules::RuleKind::Glob,
+                ignore_rules::RuleKind::Hash,
+                ignore_rules::RuleKind::Path,
+            ],
+        };
+        let mut removed = 0;
+        for kind in kinds {
+            if let Ok(pattern) = ignore_rules::normalize(kind, rule) {
+                removed += db::remove_ignore_rule(conn, Some(kind.name()), &pattern)?;
+            }
+        }
+        if removed > 0 {
+            println!("No longer ignoring {}", rule);
+        } else {
+            tracing::warn!("{} was not an ignore rule", rule);
+        }
+    }
+    Ok(())
+}
+
+pub fn run_ignore_list(conn: &Connection) -> Result<()> {
+    let rules = db::ignore_rules(conn)?;
+    if rules.is_empty() {
+        println!("No ignore rules.");
+    }
+    for (kind, pattern) in rules {
+        println!("{:<5} {}", kind, pattern);
+    }
+    Ok(())
+}
+
+pub fn show_prune_summary(files: usize, dirs: usize, rehashed: usize) {
+    println!(
+        "Removed {} missing file(s) and {} missing directory(ies); rehashed {} directory(ies).",
+        files, dirs, rehashed
+    );
+}
+
+pub fn show_resume_skipped(dir: &Path) {
+    if quiet() {
+        return;
+    }
+    println!(
+        "Skipping {:?}: already scanned before the interruption",
+        dir
+    );
+}
+
+pub fn show_moved_files(count: usize) {
+    if quiet() {
+        return;
+    }
+    println!(
+        "Recognised {} moved file(s); updated their paths without rehashing.",
+        count
+    );
+}
+
+pub fn show_scan_interrupted() {
+    tracing::info!(
+        target: logging::NOTICE,
+        "Scan interrupted. Files hashed so far are saved in the database.\n\
+         Run the same command again with --resume to continue."
+    );
+}
+
+pub fn show_unsupported(option: &str) {
+    tracing::warn!("{} is not supported on this platform, ignoring it", option);
 }
 
 // ---------------------------------------------------------------------------
 // Driving functions (run_*) — call logic, handle prompts, drive the loop
 // ---------------------------------------------------------------------------
 
-pub fn run_dup_files(conn: &Connection) -> Result<()> {
-    let groups = duplicates::find_duplicate_files(conn)?;
+pub fn run_dup_files(
+    conn: &Connection,
+    format: report::DupFilesFormat,
+    show_size: bool,
+    include_empty: bool,
+) -> Result<bool> {
+    let mut groups = duplicates::find_duplicate_files(conn)?;
+    if !include_empty {
+        groups.retain(|g| g.total_size > 0);
+    }
+    let locations = report::locations(conn)?;
+    if format == report::DupFilesFormat::Fdupes {
+        print!("{}", report::to_fdupes(&groups, show_size));
+        for group in &groups {
+            group_hook(|| hooks::file_group_event(group, &locations));
+        }
+        return Ok(!groups.is_empty());
+    }
     if groups.is_empty() {
         show_no_duplicate_files();
-        return Ok(());
+        return Ok(false);
     }
     for group in &groups {
-        show_duplicate_file_group(&group.hash, group.count, group.total_size, &group.files);
+        show_duplicate_file_group(
+            &group.hash,
+            group.count,
+            group.total_size,
+            &group.files,
+            &locations,
+        );
+        group_hook(|| hooks::file_group_event(group, &locations));
+    }
+    Ok(true)
+}
+
+/// Print duplicate files and directories straight from the database; nothing
+/// on disk is read or changed. `scope` limits the directory groups to members
+/// under those paths (all of them when empty). Returns whether any group was
+/// reported.
+pub fn run_report(
+    conn: &Connection,
+    scope: &[&Path],
+    filter: duplicates::ReportFilter,
+    format: report::Format,
+    output: Option<&Path>,
+) -> Result<bool> {
+    let report = report::build(conn, scope, filter)?;
+    let found = !report.file_groups.is_empty() || !report.dir_groups.is_empty();
+    match format {
+        report::Format::Text => show_report(&report),
+        report::Format::Json => write_rendered(&report::to_json(&report)?, output)?,
+        report::Format::Html => write_rendered(&report::to_html(&report), output)?,
+    }
+    for group in &report.file_groups {
+        group_hook(|| hooks::file_group_event(group, &report.locations));
+    }
+    for group in &report.dir_groups {
+        group_hook(|| hooks::dir_group_event(group, &report.locations));
+    }
+    Ok(found)
+}
+
+/// List the files whose content exists nowhere else, as text or JSON.
+pub fn run_unique_report(
+    conn: &Connection,
+    scope: &[&Path],
+    format: report::Format,
+    output: Option<&Path>,
+) -> Result<()> {
+    let files = report::unique_files(conn, scope)?;
+    if format == report::Format::Text {
+        show_section("Files with no copy elsewhere");
+        for file in &files {
+            println!("  {}  ({})", file.path, utils::fmt_size(file.size));
+        }
+        let bytes: i64 = files.iter().map(|f| f.size).sum();
+        println!(
+            "\n{} unique file(s), {} in total.",
+            files.len(),
+            utils::fmt_size(bytes)
+        );
+        return Ok(());
+    }
+    let rendered = report::unique_to_json(&files)?;
+    write_rendered(&rendered, output)
+}
+
+/// Write a rendered report to `output`, or print it when there is none. A
+/// reader that stops early (`| head`) ends the output, not with an error.
+fn write_rendered(rendered: &str, output: Option<&Path>) -> Result<()> {
+    match output {
+        Some(path) => {
+            std::fs::write(path, rendered)
+                .with_context(|| format!("writing {}", path.display()))?;
+            show_report_written(path);
+        }
+        None => {
+            let mut out = io::stdout().lock();
+            match writeln!(out, "{}", rendered).and_then(|()| out.flush()) {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                    return Err(e).context("writing the report");
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn show_report_written(path: &Path) {
+    if quiet() {
+        return;
+    }
+    tracing::info!(
+        target: logging::NOTICE,
+        "Report written to {}",
+        path.display()
+    );
+}
+
+/// Write the CSV export to `output`, or to stdout when there is none.
+pub fn run_export(
+    conn: &Connection,
+    output: Option<&Path>,
+    duplicates_only: bool,
+    format: report::ExportFormat,
+) -> Result<()> {
+    let out: Box<dyn Write> = match output {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("creating {}", path.display()))?;
+            Box::new(io::BufWriter::new(file))
+        }
+        None => Box::new(io::stdout().lock()),
+    };
+    let Some(format) = format.manifest() else {
+        return report::write_csv(conn, out, duplicates_only);
+    };
+    if duplicates_only {
+        anyhow::bail!("--duplicates-only only applies to CSV exports");
+    }
+    let written = manifest::write_manifest(conn, out, format)?;
+    if written.unhashed > 0 {
+        tracing::warn!(
+            "{} file(s) only have a provisional hash and were left out; \
+             scan without --prefilter to hash them.",
+            written.unhashed
+        );
+    }
+    Ok(())
+}
+
+/// Seed the database from the checksum manifest at `path`.
+pub fn run_import(
+    conn: &Connection,
+    path: &Path,
+    root: &Path,
+    algorithm: Option<hashing::HashAlgorithm>,
+) -> Result<()> {
+    let imported = manifest::import_manifest(conn, path, root, algorithm)?;
+    println!(
+        "Imported {} file hash(es) from {}; {} already known, {} missing.",
+        imported.files,
+        path.display(),
+        imported.known,
+        imported.missing
+    );
+    if !imported.changed.is_empty() {
+        let changed: Vec<_> = imported.changed.iter().map(|p| p.display()).collect();
+        tracing::warn!(
+            "{} file(s) changed after the manifest was written and were left for the next \
+             scan to hash:{}",
+            changed.len(),
+            listing(&changed)
+        );
+    }
+    if let Some(first) = imported.malformed.first() {
+        tracing::warn!(
+            "skipped {} line(s) that aren't `<hash>  <name>` with a hash of the \
+             database's algorithm (the first is line {}).",
+            imported.malformed.len(),
+            first
+        );
+    }
+    Ok(())
+}
+
+pub fn show_report(report: &report::Report) {
+    show_section("Duplicate files");
+    if report.file_groups.is_empty() && report.covered_file_groups.is_empty() {
+        show_no_duplicate_files();
+    }
+    for group in &report.file_groups {
+        show_duplicate_file_group(
+            &group.hash,
+            group.count,
+            group.total_size,
+            &group.files,
+            &report.locations,
+        );
+    }
+    if !report.covered_file_groups.is_empty() {
+        println!(
+            "\n({} group(s) of files inside the duplicate directories below not shown.)",
+            report.covered_file_groups.len()
+        );
+    }
+
+    show_section("Duplicate directories");
+    if report.dir_groups.is_empty() {
+        show_no_duplicate_dirs();
+    }
+    for group in &report.dir_groups {
+        show_dup_dir_group(group, &report.locations);
+    }
+
+    show_report_summary(report);
+}
+
+pub fn show_report_summary(report: &report::Report) {
+    show_section("Summary");
+    println!(
+        "Files scanned:          {} ({})",
+        report.total_files,
+        utils::fmt_size(report.total_bytes)
+    );
+    if report.covered_file_groups.is_empty() {
+        println!("Duplicate file groups:  {}", report.file_groups.len());
+    } else {
+        println!(
+            "Duplicate file groups:  {} (and {} inside duplicate directories)",
+            report.file_groups.len(),
+            report.covered_file_groups.len()
+        );
+    }
+    println!("Redundant copies:       {}", report.redundant_copies());
+    println!(
+        "Reclaimable:            {} ({} bytes)",
+        utils::fmt_size(report.wasted()),
+        report.wasted()
+    );
+    println!("Duplicate dir groups:   {}", report.dir_groups.len());
+}
+
+/// Rehash the selected files and list any corrupt or unreadable ones; the
+/// caller sets the exit status from the result.
+pub fn run_verify(
+    conn: &Connection,
+    paths: &[&Path],
+    opts: &hashing::HashOptions,
+    threads: usize,
+) -> Result<verify::VerifyResult> {
+    let files = verify::selected_files(conn, paths)?;
+    show_section(&format!("Verifying {} file(s)", files.len()));
+    let progress = ProgressBar::new();
+    let result = verify::verify_files(files, opts, threads, |done, total, path| {
+        progress.update(done, total, path)
+    })?;
+    progress.finish();
+    for (path, error) in &result.errors {
+        tracing::warn!("could not read {}: {}", path, error);
+    }
+    for corrupt in &result.corrupt {
+        println!("CORRUPT: {}", corrupt.path);
+        println!("    stored hash: {}", corrupt.stored);
+        println!("    actual hash: {}", corrupt.actual);
+    }
+    println!(
+        "\n{} intact, {} corrupt; skipped {} changed, {} missing, \
+         {} never fully hashed, {} unreadable.",
+        result.intact,
+        result.corrupt.len(),
+        result.changed,
+        result.missing,
+        result.unhashed,
+        result.errors.len()
+    );
+    Ok(result)
+}
+
+/// List the scan errors recorded in the database.
+pub fn run_errors(conn: &Connection) -> Result<()> {
+    let errors = db::scan_errors(conn)?;
+    if errors.is_empty() {
+        println!("No scan errors recorded.");
+        return Ok(());
+    }
+    show_section(&format!("Scan errors ({})", errors.len()));
+    for error in &errors {
+        println!(
+            "  {}  {:<17}  {}: {}",
+            utils::fmt_mtime(error.time),
+            error.kind,
+            error.path,
+            error.message
+        );
+    }
+    Ok(())
+}
+
+/// Print what `doctor::check` found and how big the database is, then
+/// vacuum it if asked. Returns whether anything is wrong.
+pub fn run_doctor(conn: &Connection, vacuum: bool) -> Result<bool> {
+    let health = doctor::check(conn)?;
+    show_section("Checking database");
+    if health.integrity_errors.is_empty() {
+        println!("Integrity check: ok");
+    } else {
+        println!(
+            "Integrity check: {} problem(s)",
+            health.integrity_errors.len()
+        );
+        for message in &health.integrity_errors {
+            println!("  {}", message);
+        }
+    }
+
+    match health.algorithm {
+        Some(algorithm) => println!("Hash algorithm: {}", algorithm.name()),
+        None => println!("Hash algorithm: none yet"),
+    }
+    if !health.foreign_hashes.is_empty() {
+        println!(
+            "{} row(s) hold hashes that can't be {}; they never match the rest \
+             (start a new database with --database and scan again):",
+            health.foreign_hashes.len(),
+            health.algorithm.map_or("", |a| a.name())
+        );
+        for row in &health.foreign_hashes {
+            let looks_like = row
+                .looks_like
+                .map(|a| format!(", looks like {}", a.name()))
+                .unwrap_or_default();
+            println!(
+                "  {} ({}{})",
+                utils::display_db_path(&row.path),
+                row.hash,
+                looks_like
+            );
+        }
+    }
+
+    if !health.orphaned_directories.is_empty() {
+        println!(
+            "{} directory row(s) hold data but no files are recorded under them \
+             (rescan them, or run `deduplifier clean` if they are gone):",
+            health.orphaned_directories.len()
+        );
+        for path in &health.orphaned_directories {
+            println!("  {}", utils::display_db_path(path));
+        }
+    }
+
+    show_section("Database size");
+    show_storage(&health.storage);
+    if vacuum {
+        db::vacuum(conn)?;
+        let after = db::storage(conn)?;
+        println!(
+            "\nVacuumed: {} -> {}",
+            utils::fmt_size(health.storage.bytes),
+            utils::fmt_size(after.bytes)
+        );
+    }
+
+    if health.is_healthy() {
+        println!("\nNo problems found.");
+    }
+    Ok(!health.is_healthy())
+}
+
+fn show_storage(storage: &db::Storage) {
+    println!(
+        "{} ({} free, reclaimed by --vacuum)",
+        utils::fmt_size(storage.bytes),
+        utils::fmt_size(storage.free_bytes)
+    );
+    for (table, rows) in &storage.tables {
+        println!("  {:<16} {:>10} row(s)", table, rows);
+    }
+}
+
+/// List the recorded scans and what each one found had changed.
+pub fn run_scans(conn: &Connection) -> Result<()> {
+    let scans = db::scans(conn)?;
+    if scans.is_empty() {
+        println!("No scans recorded.");
+        return Ok(());
+    }
+    show_section(&format!("Scans ({})", scans.len()));
+    for scan in &scans {
+        println!(
+            "  {:>4}  {}  +{} ~{} -{}  {}",
+            scan.id,
+            utils::fmt_mtime(scan.time),
+            scan.added,
+            scan.changed,
+            scan.removed,
+            scan.roots.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Print what `history::diff_scans` found between two scans: duplicate
+/// groups that appeared or went away, then the files, then the totals.
+pub fn run_diff(conn: &Connection, from: Option<i64>, to: Option<i64>) -> Result<()> {
+    let scans = db::scans(conn)?;
+    let to = match to {
+        Some(id) => id,
+        None => match scans.last() {
+            Some(scan) => scan.id,
+            None => anyhow::bail!("no scans recorded; run `deduplifier scan` first"),
+        },
+    };
+    let from = match from {
+        Some(id) => id,
+        None => match scans.iter().rev().find(|s| s.id < to) {
+            Some(scan) => scan.id,
+            None => anyhow::bail!("there is no scan before scan {to} to compare it with"),
+        },
+    };
+    let diff = history::diff_scans(conn, from, to)?;
+    println!(
+        "Scan {} ({}) -> scan {} ({})",
+        diff.from.id,
+        utils::fmt_mtime(diff.from.time),
+        diff.to.id,
+        utils::fmt_mtime(diff.to.time)
+    );
+    show_diff_groups("New duplicates", &diff.new_duplicates);
+    show_diff_groups("Resolved duplicates", &diff.resolved_duplicates);
+    show_section(&format!("Appeared ({})", diff.appeared.len()));
+    for file in &diff.appeared {
+        println!("  {}", file.path);
+    }
+    show_section(&format!("Removed ({})", diff.removed.len()));
+    for file in &diff.removed {
+        println!("  {}", file.path);
+    }
+    show_section(&format!("Changed ({})", diff.changed.len()));
+    for change in &diff.changed {
+        println!(
+            "  {} ({} -> {})",
+            change.to.path,
+            utils::fmt_size(change.from.size),
+            utils::fmt_size(change.to.size)
+        );
+    }
+    println!(
+        "\n{} new duplicate group(s), {} resolved, {} file(s) appeared, {} removed, {} changed.",
+        diff.new_duplicates.len(),
+        diff.resolved_duplicates.len(),
+        diff.appeared.len(),
+        diff.removed.len(),
+        diff.changed.len()
+    );
+    Ok(())
+}
+
+fn show_diff_groups(title: &str, groups: &[history::DuplicateGroup]) {
+    show_section(&format!("{} ({})", title, groups.len()));
+    for group in groups {
+        println!("  {} x {}", group.paths.len(), utils::fmt_size(group.size));
+        for path in &group.paths {
+            println!("    {}", path);
+        }
+    }
+}
+
+/// Merge each of `sources` into the database with `merge_db::merge_database`,
+/// reporting what each one added. Two sources with the same file name would
+/// share a name in the database, so that is refused up front.
+pub fn run_merge_db(conn: &Connection, sources: &[std::path::PathBuf]) -> Result<()> {
+    let mut names = std::collections::HashSet::new();
+    for source in sources {
+        let name = merge_db::source_name(source)?;
+        if !names.insert(name.clone()) {
+            anyhow::bail!("two source databases are named {name}; rename one of them");
+        }
+    }
+    for source in sources {
+        show_section(&format!("Merging {}", source.display()));
+        let stats = merge_db::merge_database(conn, source)?;
+        println!(
+            "  {} file(s) and {} directory(ies) merged as {}",
+            stats.files, stats.directories, stats.source
+        );
+    }
+    Ok(())
+}
+
+/// Undo the pending actions selected by `last` and `since`, newest first, and
+/// report each one.
+pub fn run_undo(
+    conn: &Connection,
+    last: Option<usize>,
+    since: Option<i64>,
+    algorithm: hashing::HashAlgorithm,
+) -> Result<()> {
+    let actions = db::pending_actions(conn, last, since)?;
+    show_section(&format!("Undoing {} action(s)", actions.len()));
+    let result = undo::undo_actions(conn, &actions, algorithm)?;
+    for action in &result.undone {
+        println!("  undid {} {}", action.action, action.source);
+    }
+    for (action, reason) in &result.failed {
+        tracing::warn!(
+            "could not undo {} {}: {}",
+            action.action,
+            action.source,
+            reason
+        );
+    }
+    println!(
+        "\n{} undone, {} left pending.",
+        result.undone.len(),
+        result.failed.len()
+    );
+    Ok(())
+}
+
+/// Print what `compare::compare_trees` found: everything missing from one
+/// side or the other, then the totals.
+pub fn run_compare(conn: &Connection, src: &Path, dst: &Path, show_matched: bool) -> Result<()> {
+    let cmp = compare::compare_trees(conn, src, dst)?;
+    let renamed = cmp.matched.iter().filter(|m| m.renamed).count();
+    if show_matched {
+        show_section(&format!("Matched ({})", cmp.matched.len()));
+        for m in &cmp.matched {
+            let note = if m.renamed { "  (different path)" } else { "" };
+            println!("  {} -> {}{}", m.src, m.dst, note);
+        }
+    }
+    show_section(&format!(
+        "Missing from {} ({})",
+        dst.display(),
+        cmp.missing.len()
+    ));
+    for file in &cmp.missing {
+        println!("  {}", file.path);
     }
+    show_section(&format!("Only in {} ({})", dst.display(), cmp.extra.len()));
+    for file in &cmp.extra {
+        println!("  {}", file.path);
+    }
+    println!(
+        "\n{} matched ({} under a different path), {} missing from {}, {} only in {}.",
+        cmp.matched.len(),
+        renamed,
+        cmp.missing.len(),
+        dst.display(),
+        cmp.extra.len(),
+        dst.display()
+    );
+    Ok(())
+}
+
+/// Print where the duplicate bytes are, by extension and by top-level
+/// directory, at most `top` rows each (all when `top` is 0).
+pub fn run_stats(conn: &Connection, top: usize) -> Result<()> {
+    let stats = stats::collect(conn)?;
+    if stats.duplicate_files == 0 {
+        show_no_duplicate_files();
+        return Ok(());
+    }
+    println!(
+        "{} duplicate file(s), {} in total",
+        stats.duplicate_files,
+        utils::fmt_size(stats.duplicate_bytes)
+    );
+    show_section("Duplicate bytes by extension");
+    show_stats_buckets(&stats.by_extension, stats.duplicate_bytes, top);
+    show_section("Duplicate bytes by top-level directory");
+    show_stats_buckets(&stats.by_directory, stats.duplicate_bytes, top);
     Ok(())
 }
 
+pub fn show_stats_buckets(buckets: &[stats::Bucket], total: i64, top: usize) {
+    let shown = if top == 0 { buckets.len() } else { top };
+    for bucket in buckets.iter().take(shown) {
+        println!(
+            "{:>5.1}%  {:>10}  {:>6} file(s)  {}",
+            bucket.percent_of(total),
+            utils::fmt_size(bucket.bytes),
+            bucket.files,
+            bucket.key
+        );
+    }
+    if buckets.len() > shown {
+        println!("... and {} more", buckets.len() - shown);
+    }
+}
+
 pub fn run_dup_dirs(
     conn: &Connection,
     delete: bool,
     canon: Option<&Path>,
     no_confirmation: bool,
     scanned_dirs: &[&Path],
-) -> Result<()> {
-    let duplicate_group_hashes = db::duplicate_directory_groups(conn)?;
+    include_empty: bool,
+) -> Result<bool> {
+    let mut duplicate_group_hashes = db::duplicate_directory_groups(conn)?;
+    if !include_empty {
+        // Directories holding only empty files all look alike
+        duplicate_group_hashes.retain(|g| g.size > 0);
+    }
     if duplicate_group_hashes.is_empty() {
         show_no_duplicate_dirs();
-        return Ok(());
+        return Ok(false);
     }
     let (top_level_groups, covered_count) =
         duplicates::build_top_level_groups(conn, &duplicate_group_hashes, scanned_dirs)?;
     show_dup_dirs_summary(top_level_groups.len(), covered_count);
+    let protected = dedupe::ProtectedPaths::load(conn)?;
+    let locations = report::locations(conn)?;
+    // Copies in a bucket, on another machine or in another database can't be
+    // deleted from here, and can't stand in for a deleted local copy either
+    let off_disk: HashSet<String> = db::remote_paths(conn)?
+        .into_iter()
+        .chain(db::merged_sources(conn)?.into_keys())
+        .collect();
     for group in &top_level_groups {
-        show_dup_dir_group(group);
+        show_dup_dir_group(group, &locations);
+        group_hook(|| hooks::dir_group_event(group, &locations));
         if !delete {
             continue;
         }
         let dirs = &group.members;
         let auto_keep: Option<usize> = if let Some(canon_path) = canon {
             dirs.iter()
-                .position(|e| std::path::Path::new(&e.path).starts_with(canon_path))
+                .position(|e| utils::path_from_db(&e.path).starts_with(canon_path))
         } else {
             None
         };
@@ -150,7 +1382,7 @@ pub fn run_dup_dirs(
             if let Some(canon_path) = canon {
                 let canon_count = dirs
                     .iter()
-                    .filter(|e| std::path::Path::new(&e.path).starts_with(canon_path))
+                    .filter(|e| utils::path_from_db(&e.path).starts_with(canon_path))
                     .count();
                 if canon_count > 1 {
                     show_dup_dir_canon_conflict_warning(canon_count, canon_path);
@@ -175,13 +1407,23 @@ pub fn run_dup_dirs(
             .collect();
         show_dup_dir_deletion_plan(&dirs[keep_idx].path, &to_delete);
         for path in &to_delete {
+            let dir_path = &utils::path_from_db(path);
+            if protected.within_tree(dir_path) {
+                show_dup_dir_protected(path);
+                continue;
+            }
+            if off_disk.contains(*path) || off_disk.contains(&dirs[keep_idx].path) {
+                show_dup_dir_off_disk(path);
+                continue;
+            }
             let auto_confirmed = no_confirmation && auto_keep.is_some();
             if !prompt_confirm_deletion(path, auto_confirmed)? {
                 continue;
             }
-            let dir_path = std::path::Path::new(path);
             if dir_path.exists() {
                 file_system::delete_dir_all(dir_path)?;
+                let kept = &utils::path_from_db(&dirs[keep_idx].path);
+                db::log_action(conn, "delete-dir", dir_path, Some(kept), Some(&group.hash))?;
                 show_dup_dir_deleted(path);
             } else {
                 show_dup_dir_missing(path);
@@ -191,7 +1433,321 @@ pub fn run_dup_dirs(
         }
         show_dup_dir_group_end();
     }
-    Ok(())
+    Ok(!top_level_groups.is_empty())
+}
+
+/// Ask about each duplicate file group under `scope` in turn and return the
+/// plans chosen, or `None` if there are no duplicates.
+pub fn plan_dedupe_interactive(
+    conn: &Connection,
+    scope: &[&Path],
+) -> Result<Option<Vec<dedupe::GroupPlan>>> {
+    let groups = dedupe::groups_in_scope(conn, scope)?;
+    if groups.is_empty() {
+        show_no_duplicate_files();
+        return Ok(None);
+    }
+    let protected = dedupe::ProtectedPaths::load(conn)?;
+    let mut plans: Vec<dedupe::GroupPlan> = Vec::new();
+    for (i, group) in groups.iter().enumerate() {
+        show_dedupe_group(i + 1, groups.len(), group, &protected);
+        let keep: Vec<usize> = match prompt_keep_files(group.files.len())? {
+            dedupe::KeepChoice::Keep(keep) => keep,
+            dedupe::KeepChoice::KeepAll => (0..group.files.len()).collect(),
+            dedupe::KeepChoice::Skip => continue,
+            dedupe::KeepChoice::Quit => break,
+        };
+        plans.push(dedupe::plan_group(group, &keep, &protected, "interactive"));
+    }
+    Ok(Some(plans))
+}
+
+/// Pick the survivors of every duplicate file group under `scope` with
+/// `policy` and show what `opts` will do with the rest. Returns `None` if
+/// there are no duplicates.
+pub fn plan_dedupe_auto(
+    conn: &Connection,
+    scope: &[&Path],
+    policy: &dedupe::KeepPolicy,
+    opts: &dedupe::ApplyOptions,
+) -> Result<Option<Vec<dedupe::GroupPlan>>> {
+    let groups = dedupe::groups_in_scope(conn, scope)?;
+    if groups.is_empty() {
+        show_no_duplicate_files();
+        return Ok(None);
+    }
+    let reason = policy.describe();
+    let protected = dedupe::ProtectedPaths::load(conn)?;
+    let plans: Vec<dedupe::GroupPlan> = groups
+        .iter()
+        .map(|g| dedupe::plan_group(g, &policy.keepers(g, &protected), &protected, &reason))
+        .collect();
+    for plan in &plans {
+        show_dedupe_auto_plan(plan, opts);
+    }
+    Ok(Some(plans))
+}
+
+/// Carry out `plans` as `opts` says. With `confirm`, every removal is listed
+/// first and nothing happens unless the user agrees (a dry run doesn't ask).
+pub fn apply_dedupe(
+    conn: &Connection,
+    plans: &[dedupe::GroupPlan],
+    opts: &dedupe::ApplyOptions,
+    confirm: bool,
+    algorithm: hashing::HashAlgorithm,
+) -> Result<()> {
+    let removals: Vec<&dedupe::Removal> = plans.iter().flat_map(|p| &p.removals).collect();
+    if removals.is_empty() {
+        println!("\nNothing to do.");
+        dedupe::apply_plans(conn, plans, opts, algorithm)?;
+        return Ok(());
+    }
+    if confirm {
+        show_dedupe_plan(&removals, opts);
+        let question = match opts.action {
+            dedupe::Action::Delete if opts.quarantine.is_some() => {
+                "Move these files into quarantine?"
+            }
+            dedupe::Action::Delete if opts.permanent => "Permanently delete these files?",
+            dedupe::Action::Delete => "Move these files to the trash?",
+            dedupe::Action::Hardlink => "Replace these files with hardlinks?",
+            dedupe::Action::Symlink | dedupe::Action::RelativeSymlink => {
+                "Replace these files with symlinks?"
+            }
+            dedupe::Action::Reflink => "Share storage between these files and the kept copies?",
+        };
+        if !file_system::dry_run() && !prompt_yes_no(question)? {
+            println!("Nothing changed.");
+            return Ok(());
+        }
+    }
+    let stats = dedupe::apply_plans(conn, plans, opts, algorithm)?;
+    show_dedupe_stats(&stats, opts);
+    Ok(())
+}
+
+/// Write `plans` to `path` as a `shell` script instead of carrying them out.
+pub fn write_dedupe_script(
+    plans: &[dedupe::GroupPlan],
+    opts: &dedupe::ApplyOptions,
+    shell: script::Shell,
+    path: &Path,
+) -> Result<()> {
+    let commands: usize = plans.iter().map(|p| p.removals.len()).sum();
+    std::fs::write(path, script::render(plans, opts, shell))
+        .with_context(|| format!("writing {}", path.display()))?;
+    #[cfg(unix)]
+    if shell == script::Shell::Sh {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    tracing::info!(
+        target: logging::NOTICE,
+        "Wrote {} command(s) to {}; nothing was changed.",
+        commands,
+        path.display()
+    );
+    Ok(())
+}
+
+pub fn show_dedupe_auto_plan(plan: &dedupe::GroupPlan, opts: &dedupe::ApplyOptions) {
+    if quiet() {
+        return;
+    }
+    println!("\nhash {}:", &plan.hash[..plan.hash.len().min(16)]);
+    for path in &plan.keep {
+        println!("  {:<9} {}", "keep", utils::display_db_path(path));
+    }
+    for removal in &plan.removals {
+        println!(
+            "  {:<9} {}",
+            opts.verb(),
+            utils::display_db_path(&removal.path)
+        );
+    }
+}
+
+pub fn show_dedupe_group(
+    index: usize,
+    total: usize,
+    group: &duplicates::DuplicateFileGroup,
+    protected: &dedupe::ProtectedPaths,
+) {
+    let size = group.files.first().map_or(0, |f| f.size);
+    println!(
+        "\n[{}/{}] {} copies, {} each (hash: {}):",
+        index,
+        total,
+        group.count,
+        utils::fmt_size(size),
+        &group.hash[..group.hash.len().min(16)]
+    );
+    for (i, file) in group.files.iter().enumerate() {
+        let note = if protected.covers_file(file) {
+            "  [protected, always kept]"
+        } else {
+            ""
+        };
+        let link = if file.via_link {
+            ", through a symlink"
+        } else {
+            ""
+        };
+        println!(
+            "  [{}] {}  ({} bytes, modified {}{}){}",
+            i + 1,
+            file.path,
+            file.size,
+            utils::fmt_mtime(file.modified),
+            link,
+            note
+        );
+        for link in &file.hardlinks {
+            println!("        hardlink: {}", link);
+        }
+    }
+}
+
+/// Ask which copies of a group to keep until the answer parses.
+pub fn prompt_keep_files(count: usize) -> Result<dedupe::KeepChoice> {
+    loop {
+        print!("  Keep which? (numbers, f = first, a = all, s = skip, q = quit): ");
+        io::stdout().flush()?;
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line)? == 0 {
+            // End of input: stop asking, as if q had been typed
+            return Ok(dedupe::KeepChoice::Quit);
+        }
+        match dedupe::KeepChoice::parse(&line, count) {
+            Some(choice) => return Ok(choice),
+            None => println!(
+                "  Please enter copy numbers between 1 and {}, f, a, s or q.",
+                count
+            ),
+        }
+    }
+}
+
+pub fn show_dedupe_plan(removals: &[&dedupe::Removal], opts: &dedupe::ApplyOptions) {
+    let freed: i64 = removals.iter().map(|r| r.size).sum();
+    let what = match opts.action {
+        dedupe::Action::Delete if opts.quarantine.is_some() => "moved into quarantine",
+        dedupe::Action::Delete if opts.permanent => "permanently deleted",
+        dedupe::Action::Delete => "moved to the trash",
+        dedupe::Action::Hardlink => "replaced with hardlinks to the kept copy",
+        dedupe::Action::Symlink | dedupe::Action::RelativeSymlink => {
+            "replaced with symlinks to the kept copy"
+        }
+        dedupe::Action::Reflink => "left in place but share storage with the kept copy",
+    };
+    println!(
+        "\n{} file(s), {} in all, will be {}:",
+        removals.len(),
+        utils::fmt_size(freed),
+        what
+    );
+    for removal in removals {
+        println!("  - {}  (copy kept: {})", removal.path, removal.keeper);
+    }
+}
+
+pub fn prompt_yes_no(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut line = String::new();
+    io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim().eq_ignore_ascii_case("y"))
+}
+
+pub fn show_dedupe_stats(stats: &dedupe::DedupeStats, opts: &dedupe::ApplyOptions) {
+    let done = match opts.action {
+        dedupe::Action::Delete if opts.quarantine.is_some() => "Quarantined",
+        dedupe::Action::Delete if opts.permanent => "Deleted",
+        dedupe::Action::Delete => "Trashed",
+        dedupe::Action::Hardlink => "Hardlinked",
+        dedupe::Action::Symlink | dedupe::Action::RelativeSymlink => "Symlinked",
+        dedupe::Action::Reflink => "Reflinked",
+    };
+    let freed = match opts.action {
+        // The kernel's count, which includes extents that were already shared
+        dedupe::Action::Reflink => "deduplicating",
+        // Nothing is freed until the trash is emptied
+        dedupe::Action::Delete if !opts.permanent => "totalling",
+        _ => "freeing",
+    };
+    let done = if file_system::dry_run() {
+        format!("Dry run: would have {}", done.to_lowercase())
+    } else {
+        done.to_string()
+    };
+    println!(
+        "{} {} file(s), {} {}.",
+        done,
+        stats.removed,
+        freed,
+        utils::fmt_size(stats.freed)
+    );
+    let deleted = opts.action == dedupe::Action::Delete && !opts.permanent;
+    if deleted && stats.removed > 0 && !file_system::dry_run() {
+        match &opts.quarantine {
+            Some(dir) => println!(
+                "Delete {} to free the space, or put files back with `deduplifier undo`.",
+                dir.display()
+            ),
+            None => println!("Empty the trash to free the space, or restore files from it."),
+        }
+    }
+    if stats.already_gone > 0 {
+        println!(
+            "{} file(s) were already gone; removed them from the database.",
+            stats.already_gone
+        );
+    }
+    if stats.off_disk > 0 {
+        println!(
+            "Skipped {} file(s) that aren't on this machine's disk.",
+            stats.off_disk
+        );
+    }
+    if stats.keeper_missing > 0 {
+        println!(
+            "Warning: skipped {} file(s) because the copy to keep no longer exists.",
+            stats.keeper_missing
+        );
+    }
+    if stats.cross_device > 0 {
+        println!(
+            "Warning: skipped {} file(s) on a different filesystem than the copy to keep.",
+            stats.cross_device
+        );
+    }
+    if stats.unsupported > 0 {
+        println!(
+            "Warning: skipped {} file(s) on filesystems that can't share extents.",
+            stats.unsupported
+        );
+    }
+    if stats.differs > 0 {
+        println!(
+            "Warning: skipped {} file(s) whose contents no longer match the copy to keep.",
+            stats.differs
+        );
+    }
+    for hash in &stats.mismatched_groups {
+        tracing::warn!(
+            "left group {} alone: a copy's bytes differ from the kept file's \
+             despite the equal hash. A file changed since the scan, or this is a hash \
+             collision; rescan before deduplicating it.",
+            &hash[..hash.len().min(16)]
+        );
+    }
+    if stats.protected > 0 {
+        println!(
+            "Warning: refused to touch {} protected file(s).",
+            stats.protected
+        );
+    }
 }
 
 pub fn run_merge(
@@ -199,6 +1755,7 @@ pub fn run_merge(
     canon: &Path,
     sources: &[&Path],
     no_confirmation: bool,
+    hash_opts: &hashing::HashOptions,
 ) -> Result<()> {
     for &source in sources {
         if source == canon {
@@ -208,17 +1765,19 @@ pub fn run_merge(
         show_merge_header(source, canon);
         let (score, intersection, union) = merge::similarity_score(conn, canon, source)?;
         show_merge_similarity(score, intersection, union);
-        if score < merge::SIMILARITY_THRESHOLD && !no_confirmation {
-            if !prompt_low_similarity(merge::SIMILARITY_THRESHOLD)? {
-                show_merge_skipped();
-                continue;
-            }
+        if score < merge::SIMILARITY_THRESHOLD
+            && !no_confirmation
+            && !prompt_low_similarity(merge::SIMILARITY_THRESHOLD)?
+        {
+            show_merge_skipped();
+            continue;
         }
         let stats = merge::execute_merge(
             conn,
             canon,
             source,
             no_confirmation,
+            hash_opts,
             |rel, dest_abs, dest_mtime, src_abs, src_mtime| {
                 let choice = prompt_merge_conflict(rel, dest_abs, dest_mtime, src_abs, src_mtime)?;
                 Ok(choice)
@@ -294,12 +1853,618 @@ pub fn run_similar(
     Ok(())
 }
 
+// ---------------------------------------------------------------------------
+// Similar images
+// ---------------------------------------------------------------------------
+
+/// Report clusters of near-identical images. Returns whether there were any.
+pub fn run_similar_images(conn: &Connection, threshold: u32, scope: &[&Path]) -> Result<bool> {
+    if !quiet() {
+        println!(
+            "\n=== Finding similar images (within {} of {} bits) ===",
+            threshold,
+            perceptual::MAX_DISTANCE
+        );
+    }
+    let reading = AtomicBool::new(false);
+    let found = perceptual::find_similar_images(conn, threshold, scope, |done, total| {
+        if !quiet() {
+            reading.store(true, Ordering::Relaxed);
+            print!("\r\x1B[K  {}/{} images read", done, total);
+            let _ = io::stdout().flush();
+        }
+    })?;
+    if reading.load(Ordering::Relaxed) {
+        println!();
+    }
+    if found.unreadable > 0 {
+        tracing::warn!(
+            "{} image file(s) could not be decoded and were left out.",
+            found.unreadable
+        );
+    }
+    if found.clusters.is_empty() {
+        println!("No similar images found.");
+        return Ok(false);
+    }
+    println!(
+        "Found {} cluster(s) of similar images.",
+        found.clusters.len()
+    );
+    for cluster in &found.clusters {
+        show_image_cluster(cluster);
+    }
+    Ok(true)
+}
+
+fn show_image_cluster(cluster: &perceptual::Cluster) {
+    println!(
+        "\nSimilar images ({} pictures, total size: {} bytes):",
+        cluster.images.len(),
+        cluster.total_size()
+    );
+    let first = cluster.images[0].hash.dhash;
+    for image in &cluster.images {
+        let bits = perceptual::distance(first, image.hash.dhash);
+        let apart = if bits > 0 {
+            format!(", {} bit(s) apart", bits)
+        } else {
+            String::new()
+        };
+        println!(
+            "  - {} ({}x{}, {} bytes{})",
+            utils::display_db_path(&image.paths[0]),
+            image.hash.width,
+            image.hash.height,
+            image.size,
+            apart
+        );
+        for copy in &image.paths[1..] {
+            println!("      same file: {}", utils::display_db_path(copy));
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Similar audio
+// ---------------------------------------------------------------------------
+
+/// Report clusters of recordings that sound alike. Returns whether there
+/// were any.
+pub fn run_similar_audio(conn: &Connection, threshold: f64, scope: &[&Path]) -> Result<bool> {
+    if !quiet() {
+        println!(
+            "\n=== Finding similar audio (threshold: {:.0}%) ===",
+            threshold * 100.0
+        );
+    }
+    let reading = AtomicBool::new(false);
+    let found = audio::find_similar_audio(conn, threshold, scope, |done, total| {
+        if !quiet() {
+            reading.store(true, Ordering::Relaxed);
+            print!("\r\x1B[K  {}/{} audio files decoded", done, total);
+            let _ = io::stdout().flush();
+        }
+    })?;
+    if reading.load(Ordering::Relaxed) {
+        println!();
+    }
+    if found.unreadable > 0 {
+        tracing::warn!(
+            "{} audio file(s) could not be decoded and were left out.",
+            found.unreadable
+        );
+    }
+    if found.clusters.is_empty() {
+        println!("No similar audio found.");
+        return Ok(false);
+    }
+    println!(
+        "Found {} cluster(s) of similar audio.",
+        found.clusters.len()
+    );
+    for cluster in &found.clusters {
+        show_audio_cluster(cluster);
+    }
+    Ok(true)
+}
+
+fn show_audio_cluster(cluster: &audio::Cluster) {
+    println!(
+        "\nSimilar audio ({} recordings, total size: {} bytes):",
+        cluster.tracks.len(),
+        cluster.total_size()
+    );
+    let first = &cluster.tracks[0].fingerprint;
+    for (i, track) in cluster.tracks.iter().enumerate() {
+        let seconds = track.fingerprint.duration.round() as i64;
+        let alike = if i > 0 {
+            let similarity = audio::similarity(&first.items, &track.fingerprint.items);
+            format!(", {:.0}% alike", similarity * 100.0)
+        } else {
+            String::new()
+        };
+        println!(
+            "  - {} ({}:{:02}, {} bytes{})",
+            utils::display_db_path(&track.paths[0]),
+            seconds / 60,
+            seconds % 60,
+            track.size,
+            alike
+        );
+        for copy in &track.paths[1..] {
+            println!("      same file: {}", utils::display_db_path(copy));
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Similar videos
+// ---------------------------------------------------------------------------
+
+/// Report clusters of videos of the same footage. Returns whether there
+/// were any.
+pub fn run_similar_videos(conn: &Connection, threshold: f64, scope: &[&Path]) -> Result<bool> {
+    if !quiet() {
+        println!(
+            "\n=== Finding similar videos (threshold: {:.0}%) ===",
+            threshold * 100.0
+        );
+    }
+    let reading = AtomicBool::new(false);
+    let found = video::find_similar_videos(conn, threshold, scope, |done, total| {
+        if !quiet() {
+            reading.store(true, Ordering::Relaxed);
+            print!("\r\x1B[K  {}/{} videos sampled", done, total);
+            let _ = io::stdout().flush();
+        }
+    })?;
+    if reading.load(Ordering::Relaxed) {
+        println!();
+    }
+    if found.unreadable > 0 {
+        tracing::warn!(
+            "{} video file(s) could not be sampled and were left out.",
+            found.unreadable
+        );
+    }
+    if found.clusters.is_empty() {
+        println!("No similar videos found.");
+        return Ok(false);
+    }
+    println!(
+        "Found {} cluster(s) of similar videos.",
+        found.clusters.len()
+    );
+    for cluster in &found.clusters {
+        show_video_cluster(cluster);
+    }
+    Ok(true)
+}
+
+fn show_video_cluster(cluster: &video::Cluster) {
+    println!(
+        "\nSimilar videos ({} videos, total size: {} bytes):",
+        cluster.videos.len(),
+        cluster.total_size()
+    );
+    let first = &cluster.videos[0].signature;
+    for (i, found) in cluster.videos.iter().enumerate() {
+        let signature = &found.signature;
+        let seconds = signature.duration.round() as i64;
+        let confidence = if i > 0 {
+            let confidence = video::confidence(first, signature);
+            format!(", {:.0}% confidence", confidence * 100.0)
+        } else {
+            String::new()
+        };
+        println!(
+            "  - {} ({}x{}, {}:{:02}, {} bytes{})",
+            utils::display_db_path(&found.paths[0]),
+            signature.width,
+            signature.height,
+            seconds / 60,
+            seconds % 60,
+            found.size,
+            confidence
+        );
+        for copy in &found.paths[1..] {
+            println!("      same file: {}", utils::display_db_path(copy));
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Similar text
+// ---------------------------------------------------------------------------
+
+/// Report clusters of nearly identical text files. Returns whether there
+/// were any.
+pub fn run_similar_text(conn: &Connection, threshold: f64, scope: &[&Path]) -> Result<bool> {
+    if !quiet() {
+        println!(
+            "\n=== Finding similar text (threshold: {:.0}%) ===",
+            threshold * 100.0
+        );
+    }
+    let reading = AtomicBool::new(false);
+    let found = text::find_similar_text(conn, threshold, scope, |done, total| {
+        if !quiet() {
+            reading.store(true, Ordering::Relaxed);
+            print!("\r\x1B[K  {}/{} text files read", done, total);
+            let _ = io::stdout().flush();
+        }
+    })?;
+    if reading.load(Ordering::Relaxed) {
+        println!();
+    }
+    if found.unreadable > 0 {
+        tracing::warn!(
+            "{} file(s) could not be read as text and were left out.",
+            found.unreadable
+        );
+    }
+    if found.clusters.is_empty() {
+        println!("No similar text found.");
+        return Ok(false);
+    }
+    println!("Found {} cluster(s) of similar text.", found.clusters.len());
+    for cluster in &found.clusters {
+        show_text_cluster(cluster);
+    }
+    Ok(true)
+}
+
+fn show_text_cluster(cluster: &text::Cluster) {
+    println!(
+        "\nSimilar text ({} documents, total size: {} bytes):",
+        cluster.documents.len(),
+        cluster.total_size()
+    );
+    let first = &cluster.documents[0].signature;
+    for (i, document) in cluster.documents.iter().enumerate() {
+        let alike = if i > 0 {
+            let similarity = text::similarity(first, &document.signature);
+            format!(", {:.0}% alike", similarity * 100.0)
+        } else {
+            String::new()
+        };
+        println!(
+            "  - {} ({} bytes{})",
+            utils::display_db_path(&document.paths[0]),
+            document.size,
+            alike
+        );
+        for copy in &document.paths[1..] {
+            println!("      same file: {}", utils::display_db_path(copy));
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Photos differing only in metadata
+// ---------------------------------------------------------------------------
+
+/// Report photos that hold the same picture with different metadata.
+/// Returns whether there were any.
+pub fn run_dup_photos(conn: &Connection, scope: &[&Path]) -> Result<bool> {
+    show_section("Finding photos that differ only in metadata");
+    let reading = AtomicBool::new(false);
+    let found = payload::find_metadata_variants(conn, scope, |done, total| {
+        if !quiet() {
+            reading.store(true, Ordering::Relaxed);
+            print!("\r\x1B[K  {}/{} photos read", done, total);
+            let _ = io::stdout().flush();
+        }
+    })?;
+    if reading.load(Ordering::Relaxed) {
+        println!();
+    }
+    if found.unreadable > 0 {
+        tracing::warn!(
+            "{} photo(s) could not be parsed and were left out.",
+            found.unreadable
+        );
+    }
+    if found.groups.is_empty() {
+        println!("No photos differing only in metadata found.");
+        return Ok(false);
+    }
+    for group in &found.groups {
+        show_photo_group(group);
+    }
+    Ok(true)
+}
+
+fn show_photo_group(group: &payload::PhotoGroup) {
+    let payload = if group.payload.len() >= 16 {
+        &group.payload[..16]
+    } else {
+        &group.payload
+    };
+    println!(
+        "\nSame photo, different metadata (image data: {}, {} files, {} versions):",
+        payload,
+        group.photos.len(),
+        group.variants
+    );
+    let mut versions: Vec<&str> = Vec::new();
+    for photo in &group.photos {
+        let version = match versions.iter().position(|&h| h == photo.hash) {
+            Some(i) => i + 1,
+            None => {
+                versions.push(&photo.hash);
+                versions.len()
+            }
+        };
+        println!(
+            "  - {} (version {}, {} bytes, modified {})",
+            utils::display_db_path(&photo.path),
+            version,
+            photo.size,
+            utils::fmt_mtime(photo.modified)
+        );
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Large files sharing chunks
+// ---------------------------------------------------------------------------
+
+/// Report pairs of files of at least `min_size` bytes that share at least
+/// `threshold` of the smaller one in chunks. Returns whether there were any.
+pub fn run_shared_chunks(
+    conn: &Connection,
+    min_size: i64,
+    threshold: f64,
+    scope: &[&Path],
+) -> Result<bool> {
+    show_section("Finding large files that share most of their content");
+    let reading = AtomicBool::new(false);
+    let found = chunks::find_shared_chunks(conn, min_size, threshold, scope, |done, total| {
+        if !quiet() {
+            reading.store(true, Ordering::Relaxed);
+            print!("\r\x1B[K  {}/{} files chunked", done, total);
+            let _ = io::stdout().flush();
+        }
+    })?;
+    if reading.load(Ordering::Relaxed) {
+        println!();
+    }
+    if found.unreadable > 0 {
+        tracing::warn!(
+            "{} file(s) could not be read and were left out.",
+            found.unreadable
+        );
+    }
+    if found.pairs.is_empty() {
+        println!(
+            "No files sharing at least {:.0}% of their content found.",
+            threshold * 100.0
+        );
+        return Ok(false);
+    }
+    for pair in &found.pairs {
+        show_shared_pair(pair);
+    }
+    Ok(true)
+}
+
+fn show_shared_pair(pair: &chunks::SharedPair) {
+    println!(
+        "\n{} is {}% in {} (about {} shared)",
+        utils::display_db_path(&pair.smaller.paths[0]),
+        percent(pair.fraction()),
+        utils::display_db_path(&pair.larger.paths[0]),
+        utils::fmt_size(pair.shared_bytes)
+    );
+    for file in [&pair.smaller, &pair.larger] {
+        println!(
+            "  - {} ({} bytes)",
+            utils::display_db_path(&file.paths[0]),
+            file.size
+        );
+        for copy in &file.paths[1..] {
+            println!("      same file: {}", utils::display_db_path(copy));
+        }
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Same name, different content
+// ---------------------------------------------------------------------------
+
+/// Report the names (or stems, with `by_stem`) shared by files with
+/// different contents. Returns whether there were any.
+pub fn run_same_name(conn: &Connection, scope: &[&Path], by_stem: bool) -> Result<bool> {
+    show_section("Finding files with the same name but different content");
+    let groups = names::find_same_names(conn, scope, by_stem)?;
+    if groups.is_empty() {
+        println!("No files sharing a name with different content found.");
+        return Ok(false);
+    }
+    println!("Found {} name(s) with different content.", groups.len());
+    for group in &groups {
+        show_name_group(group);
+    }
+    Ok(true)
+}
+
+fn show_name_group(group: &names::NameGroup) {
+    println!(
+        "\n{} ({} files, {} versions):",
+        group.name,
+        group.files.len(),
+        group.versions
+    );
+    let mut versions: Vec<&str> = Vec::new();
+    for file in &group.files {
+        let version = match versions.iter().position(|&h| h == file.hash) {
+            Some(i) => i + 1,
+            None => {
+                versions.push(&file.hash);
+                versions.len()
+            }
+        };
+        println!(
+            "  - {} (version {}, {} bytes, modified {})",
+            utils::display_db_path(&file.path),
+            version,
+            file.size,
+            utils::fmt_mtime(file.modified)
+        );
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Overlapping directories
+// ---------------------------------------------------------------------------
+
+/// Report pairs of directories that share at least `threshold` of one
+/// side's files. Returns whether there were any.
+pub fn run_overlap(conn: &Connection, threshold: f64, scope: &[&Path]) -> Result<bool> {
+    show_section("Finding directories that share most of their files");
+    let overlaps = overlap::find_overlapping_directories(conn, threshold, scope)?;
+    if overlaps.is_empty() {
+        println!(
+            "No directories sharing at least {:.0}% of their files found.",
+            threshold * 100.0
+        );
+        return Ok(false);
+    }
+    for found in &overlaps {
+        show_overlap(found);
+    }
+    Ok(true)
+}
+
+/// Print the directories under `scope` all of whose files have copies
+/// elsewhere (only under `within`, when it isn't empty). Returns whether any
+/// were found.
+pub fn run_contained(conn: &Connection, scope: &[&Path], within: &[&Path]) -> Result<bool> {
+    show_section("Finding directories whose files all exist elsewhere");
+    let found = containment::find_contained_directories(conn, scope, within)?;
+    if found.is_empty() {
+        println!("No directories whose files all have copies elsewhere found.");
+        return Ok(false);
+    }
+    for dir in &found {
+        let place = match &dir.container {
+            Some(container) => format!("all under {}", utils::display_db_path(container)),
+            None => "spread over several directories".to_string(),
+        };
+        println!(
+            "  {} ({} file(s), {}): {}",
+            utils::display_db_path(&dir.path),
+            dir.files,
+            utils::fmt_size(dir.bytes),
+            place
+        );
+    }
+    let bytes: i64 = found.iter().map(|d| d.bytes).sum();
+    println!(
+        "\n{} directory(ies), {} in total, could go without losing any file.",
+        found.len(),
+        utils::fmt_size(bytes)
+    );
+    Ok(true)
+}
+
+/// A share as a whole percentage, rounded down so nearly all never reads as
+/// all.
+fn percent(fraction: f64) -> u32 {
+    (fraction * 100.0).floor() as u32
+}
+
+fn show_overlap(found: &overlap::Overlap) {
+    println!(
+        "\n{} is {}% contained in {} ({} of {} files, {} bytes)",
+        utils::display_db_path(&found.contained),
+        percent(found.contained_fraction()),
+        utils::display_db_path(&found.container),
+        found.shared,
+        found.contained_files,
+        found.shared_bytes
+    );
+    println!(
+        "  {} is {}% contained in {} ({} of {} files)",
+        utils::display_db_path(&found.container),
+        percent(found.container_fraction()),
+        utils::display_db_path(&found.contained),
+        found.shared,
+        found.container_files
+    );
+}
+
+// ---------------------------------------------------------------------------
+// Same content under different compression
+// ---------------------------------------------------------------------------
+
+/// Report files that hold the same content once decompressed. Returns
+/// whether there were any.
+pub fn run_dup_compressed(conn: &Connection, scope: &[&Path]) -> Result<bool> {
+    show_section("Finding the same content under different compression");
+    let reading = AtomicBool::new(false);
+    let found = compressed::find_cross_format_duplicates(conn, scope, |done, total| {
+        if !quiet() {
+            reading.store(true, Ordering::Relaxed);
+            print!("\r\x1B[K  {}/{} compressed files read", done, total);
+            let _ = io::stdout().flush();
+        }
+    })?;
+    if reading.load(Ordering::Relaxed) {
+        println!();
+    }
+    if found.unreadable > 0 {
+        tracing::warn!(
+            "{} compressed file(s) could not be decompressed and were left out.",
+            found.unreadable
+        );
+    }
+    if found.groups.is_empty() {
+        println!("No files with the same content under different compression found.");
+        return Ok(false);
+    }
+    for group in &found.groups {
+        show_content_group(group);
+    }
+    Ok(true)
+}
+
+fn show_content_group(group: &compressed::ContentGroup) {
+    let content = if group.content.len() >= 16 {
+        &group.content[..16]
+    } else {
+        &group.content
+    };
+    println!(
+        "\nSame content, different compression (content: {}, {} files, {} versions):",
+        content,
+        group.files.len(),
+        group.variants
+    );
+    for file in &group.files {
+        println!(
+            "  - {} ({}, {} bytes)",
+            utils::display_db_path(&file.path),
+            file.format.map_or("uncompressed", |f| f.name()),
+            file.size
+        );
+    }
+}
+
 /// Print "\n=== {name} ===" section header.
 pub fn show_section(name: &str) {
+    if quiet() {
+        return;
+    }
     println!("\n=== {} ===", name);
 }
 
 pub fn show_similarity_section(threshold: f64) {
+    if quiet() {
+        return;
+    }
     println!(
         "\n=== Finding similar (near-duplicate) directories (threshold: {:.0}%) ===",
         threshold * 100.0
@@ -319,14 +2484,30 @@ pub fn show_duplicate_file_group(
     count: i64,
     total_size: i64,
     records: &[duplicates::FileEntry],
+    locations: &HashMap<String, String>,
 ) {
     let hash_display = if hash.len() >= 16 { &hash[..16] } else { hash };
     println!(
         "\nDuplicate files (hash: {}, count: {}, total size: {} bytes):",
         hash_display, count, total_size
     );
+    show_spanned_locations(records.iter().map(|r| r.path.as_str()), locations);
     for record in records {
-        println!("  - {} ({} bytes)", record.path, record.size);
+        let note = if record.via_link {
+            ", through a symlink"
+        } else {
+            ""
+        };
+        println!(
+            "  - {} ({} bytes{}{})",
+            utils::display_db_path(&record.path),
+            record.size,
+            note,
+            location_note(locations, &record.path)
+        );
+        for link in &record.hardlinks {
+            println!("      hardlink: {}", utils::display_db_path(link));
+        }
     }
 }
 
@@ -345,7 +2526,35 @@ pub fn show_dup_dirs_summary(top_level: usize, covered: usize) {
     );
 }
 
-pub fn show_dup_dir_group(group: &duplicates::DuplicateDirGroup) {
+/// ", on NAME" for a path `report::locations` knows the host or volume of.
+fn location_note(locations: &HashMap<String, String>, path: &str) -> String {
+    match locations.get(path) {
+        Some(name) => format!(", on {}", name),
+        None => String::new(),
+    }
+}
+
+/// Say which hosts or volumes a group spans, when it is more than one.
+fn show_spanned_locations<'a>(
+    paths: impl Iterator<Item = &'a str>,
+    locations: &HashMap<String, String>,
+) {
+    let mut names: Vec<&str> = paths
+        .filter_map(|p| locations.get(p).map(String::as_str))
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    if let [rest @ .., last] = names.as_slice() {
+        if !rest.is_empty() {
+            println!("  Exists on {} and {}", rest.join(", "), last);
+        }
+    }
+}
+
+pub fn show_dup_dir_group(
+    group: &duplicates::DuplicateDirGroup,
+    locations: &HashMap<String, String>,
+) {
     let hash_display = if group.hash.len() >= 16 {
         &group.hash[..16]
     } else {
@@ -357,8 +2566,15 @@ pub fn show_dup_dir_group(group: &duplicates::DuplicateDirGroup) {
         group.members.len(),
         group.max_size
     );
+    show_spanned_locations(group.members.iter().map(|m| m.path.as_str()), locations);
     for (i, entry) in group.members.iter().enumerate() {
-        println!("  [{}] {} ({} bytes)", i + 1, entry.path, entry.size);
+        println!(
+            "  [{}] {} ({} bytes{})",
+            i + 1,
+            utils::display_db_path(&entry.path),
+            entry.size,
+            location_note(locations, &entry.path)
+        );
     }
 }
 
@@ -428,6 +2644,17 @@ pub fn show_dup_dir_deleted(path: &str) {
     println!("  Deleted '{}'.", path);
 }
 
+pub fn show_dup_dir_protected(path: &str) {
+    println!("  '{}' is or holds a protected path, skipping.", path);
+}
+
+pub fn show_dup_dir_off_disk(path: &str) {
+    println!(
+        "  '{}' or the copy to keep isn't on this machine's disk, skipping.",
+        path
+    );
+}
+
 pub fn show_dup_dir_missing(path: &str) {
     println!("  '{}' no longer exists on disk, skipping.", path);
 }
@@ -727,7 +2954,7 @@ pub fn show_similar_kept_older(rel: &str) {
 
 pub fn show_similar_merge_complete(copied: usize) {
     println!("  Merge complete: {} file(s) copied.", copied);
-    println!("  Note: re-run without --similarity to detect exact duplicates and delete them.");
+    println!("  Note: run dup-dirs to detect exact duplicates and delete them.");
     println!();
 }
 
@@ -758,10 +2985,15 @@ pub fn show_sort_summary(moved: usize, skipped: usize, deleted_dups: usize) {
     );
 }
 
-pub fn run_sort_photos(conn: &Connection, directories: &[&Path], canon: &Path) -> Result<()> {
+pub fn run_sort_photos(
+    conn: &Connection,
+    directories: &[&Path],
+    canon: &Path,
+    hash_opts: &hashing::HashOptions,
+) -> Result<()> {
     for &root in directories {
         show_sort_root_header(root);
-        let stats = photos::sort_root(conn, root, canon, &mut |event| match event {
+        let stats = photos::sort_root(conn, root, canon, hash_opts, &mut |event| match event {
             photos::SortEvent::FileCount(n) => show_sort_file_count(n),
             photos::SortEvent::Duplicate(src) => show_sort_duplicate(src),
             photos::SortEvent::Moved(src, dest) => show_sort_moved(src, dest),
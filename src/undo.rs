@@ -0,0 +1,336 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use rusqlite::Connection;
+
+use crate::{db, file_system, hashing, scan, utils};
+
+/// What `undo_actions` managed.
+#[derive(Debug, Default)]
+pub struct UndoResult {
+    /// Actions reversed (or with nothing to reverse), in the order undone
+    pub undone: Vec<db::ActionRecord>,
+    /// Actions that could not be reversed, with the reason; they stay pending
+    pub failed: Vec<(db::ActionRecord, String)>,
+}
+
+/// Reverse `actions`, which should be newest first so later changes are
+/// unwound before the ones they built on:
+///
+/// - a move, including one into a `dedupe --quarantine` directory, is moved
+///   back;
+/// - a hardlink or symlink is replaced with a copy of the file it pointed at;
+/// - a trashed file is restored from the trash where the platform allows,
+///   and otherwise, like a deleted file, recreated from a surviving copy
+///   with the same hash;
+/// - a deleted directory is copied back from the directory it duplicated;
+/// - a reflink changed no path, so there is nothing to do.
+///
+/// Nothing is overwritten: an action whose source path exists again fails.
+/// The database follows each change and undone actions are marked as such.
+pub fn undo_actions(
+    conn: &Connection,
+    actions: &[db::ActionRecord],
+    algorithm: hashing::HashAlgorithm,
+) -> Result<UndoResult> {
+    let mut result = UndoResult::default();
+    let mut touched: Vec<PathBuf> = Vec::new();
+    for action in actions {
+        match undo_one(conn, action, algorithm) {
+            Ok(paths) => {
+                db::mark_undone(conn, action.id)?;
+                touched.extend(paths);
+                result.undone.push(action.clone());
+            }
+            Err(e) => result.failed.push((action.clone(), format!("{:#}", e))),
+        }
+    }
+    scan::rehash_ancestors(conn, &touched, algorithm)?;
+    Ok(result)
+}
+
+/// Undo one action, returning the paths whose directories need rehashing.
+fn undo_one(
+    conn: &Connection,
+    action: &db::ActionRecord,
+    algorithm: hashing::HashAlgorithm,
+) -> Result<Vec<PathBuf>> {
+    let source = &utils::path_from_db(&action.source);
+    let destination = action.destination.as_deref().map(utils::path_from_db);
+    let destination = destination.as_deref();
+    match action.action.as_str() {
+        "reflink" => Ok(Vec::new()),
+        "move" => {
+            let dest = existing_file(destination)?;
+            ensure_free(source)?;
+            file_system::move_file(dest, source)?;
+            db::move_file(conn, dest, source)?;
+            Ok(vec![dest.to_path_buf(), source.to_path_buf()])
+        }
+        "quarantine" => {
+            // The quarantined copy has no row of its own
+            let dest = existing_file(destination)?;
+            ensure_free(source)?;
+            file_system::move_file(dest, source)?;
+            record_restored(conn, source, action.hash.as_deref())?;
+            Ok(vec![source.to_path_buf()])
+        }
+        "hardlink" | "symlink" => {
+            let dest = existing_file(destination)?;
+            if !is_link_to(source, dest)? {
+                bail!(
+                    "{} is no longer a link to {}",
+                    source.display(),
+                    dest.display()
+                );
+            }
+            file_system::replace_with_copy(source, dest)?;
+            record_restored(conn, source, action.hash.as_deref())?;
+            Ok(Vec::new())
+        }
+        "delete" | "trash" => {
+            ensure_free(source)?;
+            let restored = action.action == "trash" && file_system::restore_from_trash(source)?;
+            if !restored {
+                let copy = surviving_copy(conn, destination, action.hash.as_deref())?;
+                file_system::copy_file(&copy, source)?;
+            }
+            record_restored(conn, source, action.hash.as_deref())?;
+            Ok(vec![source.to_path_buf()])
+        }
+        "delete-dir" => {
+            let Some(dest) = destination.filter(|d| d.is_dir()) else {
+                bail!("the directory it duplicated is gone");
+            };
+            ensure_free(source)?;
+            file_system::copy_tree(dest, source)?;
+            if source.is_dir() {
+                let opts = scan::ScanOptions {
+                    hash: hashing::HashOptions {
+                        algorithm,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                scan::scan_directory(conn, source, 0, &opts, |_, _, _| {})?;
+            }
+            Ok(vec![source.to_path_buf()])
+        }
+        other => bail!("don't know how to undo '{}'", other),
+    }
+}
+
+fn existing_file(path: Option<&Path>) -> Result<&Path> {
+    match path {
+        Some(p) if p.is_file() => Ok(p),
+        Some(p) => bail!("{} is gone", p.display()),
+        None => bail!("no destination was recorded"),
+    }
+}
+
+fn ensure_free(path: &Path) -> Result<()> {
+    if fs::symlink_metadata(path).is_ok() {
+        bail!("{} exists again; not overwriting it", path.display());
+    }
+    Ok(())
+}
+
+/// Whether `path` is a symlink, or a hardlink sharing `target`'s inode.
+fn is_link_to(path: &Path, target: &Path) -> Result<bool> {
+    let link = fs::symlink_metadata(path).with_context(|| format!("reading {}", path.display()))?;
+    if link.file_type().is_symlink() {
+        return Ok(true);
+    }
+    let target = fs::metadata(target)?;
+    let same = match (utils::file_identity(&link), utils::file_identity(&target)) {
+        (Some(a), Some(b)) => a == b,
+        // No inode numbers to compare; trust the log
+        _ => true,
+    };
+    Ok(same)
+}
+
+/// A file to recreate a deleted one from: the recorded kept copy if it still
+/// holds the same content, else any file in the database with that hash.
+fn surviving_copy(conn: &Connection, kept: Option<&Path>, hash: Option<&str>) -> Result<PathBuf> {
+    let Some(hash) = hash else {
+        bail!("no hash was recorded, so no copy can be trusted");
+    };
+    if let Some(kept) = kept.filter(|k| k.is_file()) {
+        if db::get_file(conn, kept)?.is_some_and(|f| f.hash == hash) {
+            return Ok(kept.to_path_buf());
+        }
+    }
+    match db::existing_file_with_hash(conn, hash)? {
+        Some(path) => Ok(utils::path_from_db(&path)),
+        None => bail!("no copy with its content is left"),
+    }
+}
+
+/// Record a file put back at `path` with content `hash`. Nothing is recorded
+/// when the file isn't there (a dry run) or its hash is unknown; the next scan
+/// picks it up.
+fn record_restored(conn: &Connection, path: &Path, hash: Option<&str>) -> Result<()> {
+    let (Some(hash), Ok(metadata)) = (hash, fs::metadata(path)) else {
+        return Ok(());
+    };
+    db::upsert_file(conn, path, hash, metadata.len() as i64, utils::mtime(path)?)?;
+    if let Some((device, inode)) = utils::file_identity(&metadata) {
+        db::update_file_identity(conn, path, device, inode)?;
+    }
+    Ok(())
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dedupe;
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    fn scan_tmp(conn: &Connection, root: &Path) {
+        scan::scan_directory(conn, root, 0, &scan::ScanOptions::default(), |_, _, _| {}).unwrap();
+    }
+
+    /// Dedupe the two copies under `root` with `opts`, keeping `a.txt`.
+    fn dedupe_pair_with(conn: &Connection, root: &Path, opts: &dedupe::ApplyOptions) {
+        fs::write(root.join("a.txt"), b"same").unwrap();
+        fs::write(root.join("b.txt"), b"same").unwrap();
+        scan_tmp(conn, root);
+        let groups = dedupe::groups_in_scope(conn, &[]).unwrap();
+        let plan = dedupe::plan_group(&groups[0], &[0], &Default::default(), "interactive");
+        dedupe::apply_plans(conn, &[plan], opts, hashing::HashAlgorithm::default()).unwrap();
+    }
+
+    fn dedupe_pair(conn: &Connection, root: &Path, action: dedupe::Action) {
+        let opts = dedupe::ApplyOptions {
+            action,
+            permanent: true,
+            verify: true,
+            ..Default::default()
+        };
+        dedupe_pair_with(conn, root, &opts);
+    }
+
+    fn undo_all(conn: &Connection) -> UndoResult {
+        let actions = db::pending_actions(conn, None, None).unwrap();
+        undo_actions(conn, &actions, hashing::HashAlgorithm::default()).unwrap()
+    }
+
+    #[test]
+    fn test_undo_delete_restores_from_kept_copy() {
+        let tmp = tempfile::tempdir().unwrap();
+        let conn = open_test_db();
+        dedupe_pair(&conn, tmp.path(), dedupe::Action::Delete);
+        assert!(!tmp.path().join("b.txt").exists());
+
+        let result = undo_all(&conn);
+        assert_eq!(result.undone.len(), 1);
+        assert_eq!(fs::read(tmp.path().join("b.txt")).unwrap(), b"same");
+        assert_eq!(dedupe::groups_in_scope(&conn, &[]).unwrap().len(), 1);
+        assert!(db::pending_actions(&conn, None, None).unwrap().is_empty());
+
+        // The directory hash matches a fresh scan again
+        let stored = db::get_directory(&conn, tmp.path()).unwrap().unwrap();
+        let fresh = open_test_db();
+        scan_tmp(&fresh, tmp.path());
+        let rescanned = db::get_directory(&fresh, tmp.path()).unwrap().unwrap();
+        assert_eq!(stored.hash, rescanned.hash);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_undo_symlink_restores_independent_file() {
+        let tmp = tempfile::tempdir().unwrap();
+        let conn = open_test_db();
+        dedupe_pair(&conn, tmp.path(), dedupe::Action::Symlink);
+        let b = tmp.path().join("b.txt");
+        assert!(fs::symlink_metadata(&b).unwrap().file_type().is_symlink());
+
+        assert_eq!(undo_all(&conn).undone.len(), 1);
+        assert!(fs::symlink_metadata(&b).unwrap().file_type().is_file());
+        assert_eq!(fs::read(&b).unwrap(), b"same");
+    }
+
+    #[test]
+    fn test_undo_quarantine_moves_back() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("tree");
+        fs::create_dir(&root).unwrap();
+        let quarantine = tmp.path().join("quarantine");
+        let conn = open_test_db();
+        let opts = dedupe::ApplyOptions {
+            quarantine: Some(quarantine.clone()),
+            ..Default::default()
+        };
+        dedupe_pair_with(&conn, &root, &opts);
+        let parked = file_system::mirrored_path(&quarantine, &root.join("b.txt")).unwrap();
+        assert!(parked.is_file());
+
+        assert_eq!(undo_all(&conn).undone.len(), 1);
+        assert!(!parked.exists());
+        assert_eq!(fs::read(root.join("b.txt")).unwrap(), b"same");
+        assert_eq!(dedupe::groups_in_scope(&conn, &[]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_undo_never_overwrites() {
+        let tmp = tempfile::tempdir().unwrap();
+        let conn = open_test_db();
+        dedupe_pair(&conn, tmp.path(), dedupe::Action::Delete);
+        fs::write(tmp.path().join("b.txt"), b"new file").unwrap();
+
+        let result = undo_all(&conn);
+        assert!(result.undone.is_empty());
+        assert!(result.failed[0].1.contains("exists again"));
+        assert_eq!(fs::read(tmp.path().join("b.txt")).unwrap(), b"new file");
+        assert_eq!(db::pending_actions(&conn, None, None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_undo_move_and_deleted_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("keep")).unwrap();
+        fs::write(root.join("keep/x.txt"), b"x").unwrap();
+        fs::write(root.join("moved.txt"), b"m").unwrap();
+        let conn = open_test_db();
+        let deleted_dir = root.join("dup");
+        db::log_action(
+            &conn,
+            "delete-dir",
+            &deleted_dir,
+            Some(&root.join("keep")),
+            None,
+        )
+        .unwrap();
+        db::log_action(
+            &conn,
+            "move",
+            &root.join("was.txt"),
+            Some(&root.join("moved.txt")),
+            None,
+        )
+        .unwrap();
+
+        let result = undo_all(&conn);
+        assert_eq!(result.undone.len(), 2, "{:?}", result.failed);
+        assert_eq!(fs::read(root.join("was.txt")).unwrap(), b"m");
+        assert!(!root.join("moved.txt").exists());
+        assert_eq!(fs::read(deleted_dir.join("x.txt")).unwrap(), b"x");
+        assert!(db::get_directory(&conn, &deleted_dir).unwrap().is_some());
+    }
+}
@@ -1,18 +1,123 @@
+use std::borrow::Cow;
+use std::ffi::OsString;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::UNIX_EPOCH;
 
 use anyhow::{Context, Result};
 
-/// Convert a Path to a &str, returning a clear error if the path contains invalid UTF-8.
-/// Files with invalid paths are skipped (not added to the DB) so the scan can continue.
-pub fn path_to_str(path: &Path) -> Result<&str> {
-    path.to_str().ok_or_else(|| {
-        anyhow::anyhow!(
-            "Path contains invalid UTF-8 characters: {}",
-            path.to_string_lossy()
-        )
-    })
+// Paths are stored as TEXT, but a file name doesn't have to be valid UTF-8
+// (any bytes on Unix, unpaired surrogates on Windows). Such paths are stored
+// with every byte that isn't part of valid UTF-8 written as a character from
+// a private-use block, U+10FE00 plus the byte (so U+10FE80 to U+10FEFF).
+// Characters from that block in a valid name are written byte by byte the
+// same way, so the encoding can always be reversed and two names never share
+// a stored path. Every other path is stored as it is.
+
+/// Added to a raw byte (0x80 to 0xFF) to give the character standing in for it.
+const BYTE_ESCAPE_BASE: u32 = 0x10FE00;
+
+fn is_byte_escape(c: char) -> bool {
+    (BYTE_ESCAPE_BASE + 0x80..=BYTE_ESCAPE_BASE + 0xFF).contains(&(c as u32))
+}
+
+fn push_byte_escapes(out: &mut String, bytes: &[u8]) {
+    for &b in bytes {
+        out.extend(char::from_u32(BYTE_ESCAPE_BASE + b as u32));
+    }
+}
+
+/// The string `path` is stored under in the database; `path_from_db` turns
+/// it back into the same path.
+pub fn path_to_db(path: &Path) -> Cow<'_, str> {
+    if let Some(s) = path.to_str() {
+        if !s.chars().any(is_byte_escape) {
+            return Cow::Borrowed(s);
+        }
+    }
+    let mut out = String::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if is_byte_escape(c) {
+                push_byte_escapes(&mut out, c.encode_utf8(&mut [0; 4]).as_bytes());
+            } else {
+                out.push(c);
+            }
+        }
+        push_byte_escapes(&mut out, chunk.invalid());
+    }
+    Cow::Owned(out)
+}
+
+/// The path stored in the database as `stored` (see `path_to_db`).
+pub fn path_from_db(stored: &str) -> PathBuf {
+    if !stored.chars().any(is_byte_escape) {
+        return PathBuf::from(stored);
+    }
+    let mut bytes = Vec::with_capacity(stored.len());
+    for c in stored.chars() {
+        if is_byte_escape(c) {
+            bytes.push((c as u32 - BYTE_ESCAPE_BASE) as u8);
+        } else {
+            bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        }
+    }
+    PathBuf::from(os_string_from_bytes(bytes))
+}
+
+/// `stored` as it is shown to the user: like `path_from_db`, but with the
+/// bytes that aren't valid UTF-8 written as `\xE9` so two such names can
+/// still be told apart.
+pub fn display_db_path(stored: &str) -> Cow<'_, str> {
+    if !stored.chars().any(is_byte_escape) {
+        return Cow::Borrowed(stored);
+    }
+    let mut out = String::with_capacity(stored.len());
+    for c in stored.chars() {
+        if is_byte_escape(c) {
+            out.push_str(&format!("\\x{:02X}", c as u32 - BYTE_ESCAPE_BASE));
+        } else {
+            out.push(c);
+        }
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(unix)]
+fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+/// Windows names are UTF-16, which Rust stores as WTF-8: UTF-8 that can also
+/// hold an unpaired surrogate, written like a three-byte character.
+#[cfg(windows)]
+fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::windows::ffi::OsStringExt;
+    let mut wide = Vec::new();
+    for chunk in bytes.utf8_chunks() {
+        wide.extend(chunk.valid().encode_utf16());
+        let mut invalid = chunk.invalid();
+        while !invalid.is_empty() {
+            match invalid {
+                [a @ 0xED, b @ 0xA0..=0xBF, c @ 0x80..=0xBF, ..] => {
+                    let (a, b, c) = (*a as u16, *b as u16, *c as u16);
+                    wide.push((a & 0x0F) << 12 | (b & 0x3F) << 6 | (c & 0x3F));
+                    invalid = &invalid[3..];
+                }
+                _ => {
+                    wide.push(0xFFFD);
+                    invalid = &invalid[1..];
+                }
+            }
+        }
+    }
+    OsString::from_wide(&wide)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    OsString::from(String::from_utf8_lossy(&bytes).into_owned())
 }
 
 /// Return the modification time of `path` as a Unix timestamp (seconds).
@@ -28,6 +133,113 @@ pub fn mtime(path: &Path) -> Result<i64> {
     Ok(secs)
 }
 
+/// The link count, owner and permission bits of a file, from its metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStat {
+    pub nlink: i64,
+    pub uid: i64,
+    pub gid: i64,
+    /// Permission bits, including setuid, setgid and sticky, without the
+    /// file type.
+    pub mode: i64,
+}
+
+/// `FileStat` for a file's metadata. Always `None` off Unix.
+#[cfg(unix)]
+pub fn file_stat(metadata: &fs::Metadata) -> Option<FileStat> {
+    use std::os::unix::fs::MetadataExt;
+    Some(FileStat {
+        nlink: metadata.nlink() as i64,
+        uid: metadata.uid() as i64,
+        gid: metadata.gid() as i64,
+        mode: (metadata.mode() & 0o7777) as i64,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn file_stat(_metadata: &fs::Metadata) -> Option<FileStat> {
+    None
+}
+
+/// Return `(device, inode)` for a file's metadata, identifying the data rather
+/// than the path. Always `None` off Unix.
+#[cfg(unix)]
+pub fn file_identity(metadata: &fs::Metadata) -> Option<(i64, i64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev() as i64, metadata.ino() as i64))
+}
+
+#[cfg(not(unix))]
+pub fn file_identity(_metadata: &fs::Metadata) -> Option<(i64, i64)> {
+    None
+}
+
+/// This machine's host name, if it has one that can be read.
+#[cfg(unix)]
+pub fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..len]).into_owned();
+    (!name.is_empty()).then_some(name)
+}
+
+#[cfg(windows)]
+pub fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME")
+        .ok()
+        .filter(|name| !name.is_empty())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn hostname() -> Option<String> {
+    None
+}
+
+/// Whether a process with id `pid` is running on this machine. Where that
+/// can't be told, every process is taken to be running.
+#[cfg(unix)]
+pub fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks; EPERM means it exists under another user
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+pub fn process_alive(_pid: u32) -> bool {
+    true
+}
+
+/// Format a byte count with a binary unit (`512 B`, `1.5 KiB`, `3.0 GiB`).
+pub fn fmt_size(bytes: i64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes.abs() < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value.abs() >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Format a length of time in seconds the way the clock would say it roughly:
+/// `45s`, `3m12s`, `2h05m`.
+pub fn fmt_duration(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs}s"),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
 /// Format a Unix timestamp as a human-readable date/time string (`YYYY-MM-DD HH:MM`).
 pub fn fmt_mtime(secs: i64) -> String {
     let secs_u = secs.max(0) as u64;
@@ -77,11 +289,52 @@ pub fn secs_to_ymd(secs: i64) -> (i32, u32, u32) {
     (y, month, (d + 1) as u32)
 }
 
+/// Convert a UTC date to the Unix timestamp (seconds) of its midnight; the
+/// inverse of `secs_to_ymd`.
+pub fn ymd_to_secs(y: i32, month: u32, day: u32) -> i64 {
+    let mut days: i64 = (1970..y).map(|y| if is_leap(y) { 366 } else { 365 }).sum();
+    for m in 1..month {
+        days += match m {
+            2 if is_leap(y) => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+    }
+    (days + day as i64 - 1) * 86400
+}
+
 /// Returns true if `y` is a leap year.
 pub fn is_leap(y: i32) -> bool {
     (y % 4 == 0 && y % 100 != 0) || y % 400 == 0
 }
 
+/// Union-find over `0..n`.
+pub struct DisjointSets {
+    parent: Vec<usize>,
+}
+
+impl DisjointSets {
+    pub fn new(n: usize) -> Self {
+        Self {
+            parent: (0..n).collect(),
+        }
+    }
+
+    pub fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    pub fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        self.parent[a] = b;
+    }
+}
+
 // ------------------------------------------------------------------
 //
 //
@@ -95,9 +348,37 @@ mod tests {
     use super::*;
 
     #[test]
-    fn test_path_to_str_valid() {
+    fn test_path_to_db_keeps_valid_paths() {
         let path = std::path::Path::new("/some/valid/path.txt");
-        assert_eq!(path_to_str(path).unwrap(), "/some/valid/path.txt");
+        assert!(matches!(
+            path_to_db(path),
+            Cow::Borrowed("/some/valid/path.txt")
+        ));
+        assert_eq!(path_from_db("/some/valid/path.txt"), path);
+    }
+
+    #[test]
+    fn test_path_to_db_round_trips_escape_characters_in_valid_names() {
+        let name = format!("/a/{}b", char::from_u32(BYTE_ESCAPE_BASE + 0x90).unwrap());
+        let stored = path_to_db(Path::new(&name)).into_owned();
+        assert_ne!(stored, name);
+        assert_eq!(path_from_db(&stored), Path::new(&name));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_path_to_db_keeps_invalid_utf8_names_apart() {
+        use std::os::unix::ffi::OsStrExt;
+        let latin1 = Path::new(std::ffi::OsStr::from_bytes(b"/a/caf\xe9"));
+        let other = Path::new(std::ffi::OsStr::from_bytes(b"/a/caf\xe8"));
+        let stored = path_to_db(latin1).into_owned();
+        assert!(stored.starts_with("/a/caf"));
+        assert_ne!(stored, path_to_db(other));
+        assert_ne!(stored, latin1.to_string_lossy());
+        assert_eq!(path_from_db(&stored), latin1);
+        assert_eq!(Path::new(&stored).parent(), Some(Path::new("/a")));
+        assert_eq!(display_db_path(&stored), "/a/caf\\xE9");
+        assert_eq!(display_db_path("/a/café"), "/a/café");
     }
 
     #[test]
@@ -117,6 +398,14 @@ mod tests {
         assert_eq!(secs_to_ymd(11016 * 86400), (2000, 2, 29));
     }
 
+    #[test]
+    fn test_ymd_to_secs_inverts_secs_to_ymd() {
+        assert_eq!(ymd_to_secs(1970, 1, 1), 0);
+        assert_eq!(ymd_to_secs(2009, 1, 5), 1231113600);
+        assert_eq!(ymd_to_secs(2000, 2, 29), 11016 * 86400);
+        assert_eq!(secs_to_ymd(ymd_to_secs(2026, 10, 14)), (2026, 10, 14));
+    }
+
     #[test]
     fn test_secs_to_ymd_negative_clamped() {
         assert_eq!(secs_to_ymd(-1000), (1970, 1, 1));
@@ -130,6 +419,23 @@ mod tests {
         assert!(!is_leap(2001));
     }
 
+    #[test]
+    fn test_fmt_size_units() {
+        assert_eq!(fmt_size(0), "0 B");
+        assert_eq!(fmt_size(1023), "1023 B");
+        assert_eq!(fmt_size(1536), "1.5 KiB");
+        assert_eq!(fmt_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_fmt_duration() {
+        assert_eq!(fmt_duration(0), "0s");
+        assert_eq!(fmt_duration(59), "59s");
+        assert_eq!(fmt_duration(192), "3m12s");
+        assert_eq!(fmt_duration(7500), "2h05m");
+        assert_eq!(fmt_duration(100 * 3600), "100h00m");
+    }
+
     #[test]
     fn test_fmt_mtime_epoch() {
         assert_eq!(fmt_mtime(0), "1970-01-01 00:00");
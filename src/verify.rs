@@ -0,0 +1,252 @@
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc;
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+use rayon::prelude::*;
+use rusqlite::Connection;
+
+use crate::{db, hashing, utils};
+
+/// A file whose content no longer matches its stored hash although its size
+/// and modification time are unchanged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Corrupt {
+    pub path: String,
+    pub stored: String,
+    pub actual: String,
+}
+
+/// What `verify_files` found.
+#[derive(Debug, Default)]
+pub struct VerifyResult {
+    /// Files rehashed and found intact
+    pub intact: usize,
+    pub corrupt: Vec<Corrupt>,
+    /// Files whose size or mtime changed since the scan; a scan will rehash
+    /// them, so a different hash there is an edit, not corruption
+    pub changed: usize,
+    /// Files in the database that are gone from disk
+    pub missing: usize,
+    /// Files with only a provisional `--prefilter` hash, which has nothing to
+    /// compare against
+    pub unhashed: usize,
+    /// Files that could not be read, with the error
+    pub errors: Vec<(String, String)>,
+}
+
+enum Outcome {
+    Intact,
+    Corrupt(String),
+    Changed,
+    Missing,
+    Unhashed,
+    Error(String),
+}
+
+/// The stored files under any of `paths` (every file when empty), ordered by
+/// path. A path naming a single file selects just that file. Files merged in
+/// from another database are on another machine, and files inside archives
+/// have no path of their own to read, so both are left out.
+pub fn selected_files(conn: &Connection, paths: &[&Path]) -> Result<Vec<db::FileRecord>> {
+    let off_disk = db::files_off_disk(conn)?;
+    if paths.is_empty() {
+        let mut files = db::all_files(conn)?;
+        files.retain(|f| !off_disk.contains(&f.path));
+        return Ok(files);
+    }
+    let mut files = Vec::new();
+    for &path in paths {
+        if let Some(file) = db::get_file(conn, path)? {
+            files.push(file);
+        }
+        files.extend(db::files_under(conn, path)?);
+    }
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    files.dedup_by(|a, b| a.path == b.path);
+    files.retain(|f| !off_disk.contains(&f.path));
+    Ok(files)
+}
+
+/// Rehash every file in `files` whose size and mtime still match the
+/// database and compare the result with the stored hash. Nothing is written
+/// back: the point is to notice content that changed without its metadata
+/// changing, i.e. silent corruption. `on_progress` is called after each file
+/// with `(done, total, path)`.
+pub fn verify_files(
+    files: Vec<db::FileRecord>,
+    opts: &hashing::HashOptions,
+    threads: usize,
+    mut on_progress: impl FnMut(usize, usize, &str),
+) -> Result<VerifyResult> {
+    let total = files.len();
+    let mut result = VerifyResult::default();
+    if files.is_empty() {
+        return Ok(result);
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()?;
+    let (tx, rx) = mpsc::sync_channel(pool.current_num_threads());
+
+    thread::scope(|s| {
+        s.spawn(move || {
+            pool.install(|| {
+                files.into_par_iter().for_each_with(tx, |tx, file| {
+                    let outcome = check(&file, opts);
+                    let _ = tx.send((file, outcome));
+                });
+            });
+        });
+        for (done, (file, outcome)) in rx.into_iter().enumerate() {
+            on_progress(done + 1, total, &file.path);
+            match outcome {
+                Outcome::Intact => result.intact += 1,
+                Outcome::Corrupt(actual) => result.corrupt.push(Corrupt {
+                    path: file.path,
+                    stored: file.hash,
+                    actual,
+                }),
+                Outcome::Changed => result.changed += 1,
+                Outcome::Missing => result.missing += 1,
+                Outcome::Unhashed => result.unhashed += 1,
+                Outcome::Error(e) => result.errors.push((file.path, e)),
+            }
+        }
+    });
+    result.corrupt.sort_by(|a, b| a.path.cmp(&b.path));
+    result.errors.sort();
+    Ok(result)
+}
+
+fn check(file: &db::FileRecord, opts: &hashing::HashOptions) -> Outcome {
+    if hashing::is_provisional(&file.hash) {
+        return Outcome::Unhashed;
+    }
+    let path = &utils::path_from_db(&file.path);
+    let metadata = match fs::metadata(path) {
+        Ok(m) if m.is_file() => m,
+        Ok(_) => return Outcome::Missing,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Outcome::Missing,
+        Err(e) => return Outcome::Error(e.to_string()),
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs() as i64);
+    if metadata.len() as i64 != file.size || modified != Some(file.modified) {
+        return Outcome::Changed;
+    }
+    match hashing::compute_file_hash(path, opts) {
+        Ok(actual) if actual == file.hash => Outcome::Intact,
+        Ok(actual) => Outcome::Corrupt(actual),
+        Err(e) => Outcome::Error(format!("{:#}", e)),
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils;
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    /// Store `path` with its real hash, size and mtime, as a scan would.
+    fn record(conn: &Connection, path: &Path, opts: &hashing::HashOptions) {
+        let hash = hashing::compute_file_hash(path, opts).unwrap();
+        let size = fs::metadata(path).unwrap().len() as i64;
+        db::upsert_file(conn, path, &hash, size, utils::mtime(path).unwrap()).unwrap();
+    }
+
+    /// Overwrite `path` with same-length content and put its mtime back.
+    fn corrupt_in_place(path: &Path, content: &[u8]) {
+        let mtime = fs::metadata(path).unwrap().modified().unwrap();
+        fs::write(path, content).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_verify_files_detects_silent_corruption() {
+        let tmp = tempfile::tempdir().unwrap();
+        let good = tmp.path().join("good.txt");
+        let bad = tmp.path().join("bad.txt");
+        fs::write(&good, b"hello").unwrap();
+        fs::write(&bad, b"world").unwrap();
+        let conn = open_test_db();
+        let opts = hashing::HashOptions::default();
+        record(&conn, &good, &opts);
+        record(&conn, &bad, &opts);
+
+        corrupt_in_place(&bad, b"w0rld");
+
+        let files = selected_files(&conn, &[]).unwrap();
+        let result = verify_files(files, &opts, 1, |_, _, _| {}).unwrap();
+        assert_eq!(result.intact, 1);
+        assert_eq!(result.corrupt.len(), 1);
+        assert_eq!(result.corrupt[0].path, bad.to_str().unwrap());
+        assert_eq!(
+            result.corrupt[0].actual,
+            hashing::compute_file_hash(&bad, &opts).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_verify_files_skips_changed_missing_and_unhashed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let edited = tmp.path().join("edited.txt");
+        let gone = tmp.path().join("gone.txt");
+        let unhashed = tmp.path().join("unhashed.txt");
+        for path in [&edited, &gone, &unhashed] {
+            fs::write(path, b"data").unwrap();
+        }
+        let conn = open_test_db();
+        let opts = hashing::HashOptions::default();
+        record(&conn, &edited, &opts);
+        record(&conn, &gone, &opts);
+        db::upsert_file(&conn, &unhashed, &hashing::provisional_hash("x"), 4, 0).unwrap();
+
+        fs::write(&edited, b"longer data").unwrap();
+        fs::remove_file(&gone).unwrap();
+
+        let files = selected_files(&conn, &[]).unwrap();
+        let result = verify_files(files, &opts, 1, |_, _, _| {}).unwrap();
+        assert_eq!(result.changed, 1);
+        assert_eq!(result.missing, 1);
+        assert_eq!(result.unhashed, 1);
+        assert!(result.corrupt.is_empty());
+    }
+
+    #[test]
+    fn test_selected_files_limits_to_paths() {
+        let conn = open_test_db();
+        let p = |s: &str| s.replace('/', std::path::MAIN_SEPARATOR_STR);
+        for path in ["/a/1.txt", "/a/sub/2.txt", "/b/3.txt"] {
+            db::upsert_file(&conn, Path::new(&p(path)), "h", 1, 0).unwrap();
+        }
+        let a = p("/a");
+        let three = p("/b/3.txt");
+        let files = selected_files(&conn, &[Path::new(&a), Path::new(&three)]).unwrap();
+        let paths: Vec<String> = files.into_iter().map(|f| f.path).collect();
+        assert_eq!(paths, vec![p("/a/1.txt"), p("/a/sub/2.txt"), p("/b/3.txt")]);
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{bail, ensure, Context, Result};
+use rayon::prelude::*;
+use rusqlite::Connection;
+
+use crate::{db, lookalike, perceptual, utils};
+
+/// Rows written per transaction while storing signatures.
+const TRANSACTION_BATCH_SIZE: usize = 1000;
+
+/// Extensions of the video formats sampled (lowercase).
+const VIDEO_EXTENSIONS: &[&str] = &[
+    "mp4", "m4v", "mov", "mkv", "webm", "avi", "wmv", "flv", "mpg", "mpeg", "ts", "mts", "3gp",
+];
+
+/// Frames sampled from each video, evenly spread over its length.
+pub const FRAMES: usize = 8;
+
+/// Most two videos' lengths may differ by, in seconds, to count as the same
+/// footage; for long videos a hundredth of their length, whichever is more.
+const DURATION_TOLERANCE: f64 = 2.0;
+
+/// How far apart two lengths of about `duration` seconds may be for the
+/// videos to count as the same footage.
+fn tolerance(duration: f64) -> f64 {
+    DURATION_TOLERANCE.max(duration / 100.0)
+}
+
+/// Bits two frames' hashes differ in when they have nothing to do with each
+/// other, on average: a frame that far from its counterpart adds nothing to
+/// the confidence.
+const UNRELATED_DISTANCE: f64 = 32.0;
+
+/// Whether the file at `path` is a video `signature` should sample, going
+/// by its extension.
+pub fn is_video(path: &Path) -> bool {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .is_some_and(|e| VIDEO_EXTENSIONS.contains(&e.as_str()))
+}
+
+/// A video's length, frame size and the perceptual hashes of its sampled
+/// frames.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    /// In seconds
+    pub duration: f64,
+    pub width: u32,
+    pub height: u32,
+    /// The dHash of each of the `FRAMES` frames, in order
+    pub frames: Vec<u64>,
+}
+
+/// Whether `ffprobe` and `ffmpeg`, which `signature` runs, are on the PATH.
+pub fn tools_available() -> bool {
+    ["ffprobe", "ffmpeg"].iter().all(|tool| {
+        Command::new(tool)
+            .arg("-version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|s| s.success())
+    })
+}
+
+/// The signature of the video at `path`: its length and frame size as
+/// `ffprobe` reports them, and the dHash of `FRAMES` frames `ffmpeg` decodes
+/// at evenly spaced times and shrinks to 9×8 grey pixels. Re-encoding at
+/// another bitrate, codec or resolution leaves the frames looking the same,
+/// so copies of the same footage get nearly the same signature.
+pub fn signature(path: &Path) -> Result<Signature> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "v:0"])
+        .args(["-show_entries", "stream=width,height:format=duration"])
+        .args(["-of", "default=noprint_wrappers=1"])
+        .arg(path)
+        .stderr(Stdio::null())
+        .output()
+        .context("couldn't run ffprobe")?;
+    ensure!(output.status.success(), "ffprobe couldn't read the file");
+    let (duration, width, height) = parse_probe(&String::from_utf8_lossy(&output.stdout))?;
+
+    let mut frames = Vec::with_capacity(FRAMES);
+    for i in 0..FRAMES {
+        let at = duration * (i as f64 + 0.5) / FRAMES as f64;
+        let output = Command::new("ffmpeg")
+            .args(["-v", "error", "-ss", &format!("{at:.3}"), "-i"])
+            .arg(path)
+            .args(["-frames:v", "1", "-vf", "scale=9:8,format=gray"])
+            .args(["-f", "rawvideo", "-"])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .context("couldn't run ffmpeg")?;
+        ensure!(
+            output.status.success() && output.stdout.len() >= 72,
+            "ffmpeg couldn't decode a frame at {at:.1}s"
+        );
+        frames.push(perceptual::dhash_grey(&output.stdout[..72]));
+    }
+    Ok(Signature {
+        duration,
+        width,
+        height,
+        frames,
+    })
+}
+
+/// The length (in seconds), width and height in `ffprobe` output of the
+/// form `width=1920`, `height=1080`, `duration=12.5`, one per line.
+fn parse_probe(output: &str) -> Result<(f64, u32, u32)> {
+    let mut values: HashMap<&str, &str> = HashMap::new();
+    for line in output.lines() {
+        if let Some((key, value)) = line.trim().split_once('=') {
+            values.entry(key).or_insert(value);
+        }
+    }
+    let get = |key: &str| values.get(key).copied().filter(|v| *v != "N/A");
+    let (Some(width), Some(height)) = (get("width"), get("height")) else {
+        bail!("no video stream");
+    };
+    let duration: f64 = get("duration").context("unknown length")?.parse()?;
+    ensure!(duration > 0.0, "no length");
+    Ok((duration, width.parse()?, height.parse()?))
+}
+
+/// How sure it is that two signatures are of the same footage, from 0 to 1:
+/// the closer each pair of sampled frames, the higher. Unrelated videos
+/// score near 0, re-encodings of one video near 1. Videos whose lengths
+/// differ by more than the tolerance score 0.
+pub fn confidence(a: &Signature, b: &Signature) -> f64 {
+    if (a.duration - b.duration).abs() > tolerance(a.duration.max(b.duration))
+        || a.frames.len() != b.frames.len()
+        || a.frames.is_empty()
+    {
+        return 0.0;
+    }
+    let closeness: f64 = a
+        .frames
+        .iter()
+        .zip(&b.frames)
+        .map(|(&x, &y)| {
+            let distance = f64::from(perceptual::distance(x, y));
+            (1.0 - distance / UNRELATED_DISTANCE).max(0.0)
+        })
+        .sum();
+    closeness / a.frames.len() as f64
+}
+
+/// One distinct video: every file with the same content counts once.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Video {
+    /// The files holding it, sorted
+    pub paths: Vec<String>,
+    pub size: i64,
+    pub signature: Signature,
+}
+
+/// Videos of the same footage, the one with the most pixels first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster {
+    pub videos: Vec<Video>,
+}
+
+impl Cluster {
+    pub fn total_size(&self) -> i64 {
+        self.videos
+            .iter()
+            .map(|v| v.size * v.paths.len() as i64)
+            .sum()
+    }
+}
+
+/// What `find_similar_videos` found.
+pub struct SimilarVideos {
+    /// Largest first
+    pub clusters: Vec<Cluster>,
+    /// Video files that couldn't be sampled, and so aren't in any cluster
+    pub unreadable: usize,
+}
+
+/// Cluster the videos among `lookalike::candidates` whose signatures have a
+/// `confidence` of at least `threshold`. A cluster links videos through any
+/// chain of such pairs. Videos are only sampled when the database holds no
+/// signature for their current content; those signatures are stored for
+/// next time. Sampling needs `ffmpeg` and `ffprobe`; without them this fails
+/// unless every video already has a stored signature.
+pub fn find_similar_videos(
+    conn: &Connection,
+    threshold: f64,
+    scope: &[&Path],
+    on_progress: impl Fn(usize, usize) + Sync,
+) -> Result<SimilarVideos> {
+    let files = lookalike::candidates(conn, scope, |_, path| is_video(path))?;
+
+    let mut stored = db::video_signatures(conn)?;
+    let stale: Vec<&db::FileRecord> = files
+        .iter()
+        .filter(|f| stored.get(&f.path).is_none_or(|s| s.hash != f.hash))
+        .collect();
+    if !stale.is_empty() && !tools_available() {
+        bail!("sampling videos needs ffmpeg and ffprobe; install FFmpeg and put both on the PATH");
+    }
+    let done = AtomicUsize::new(0);
+    let fresh: Vec<(&db::FileRecord, Option<Signature>)> = stale
+        .par_iter()
+        .map(|&file| {
+            let signature = signature(&utils::path_from_db(&file.path)).ok();
+            on_progress(done.fetch_add(1, Ordering::Relaxed) + 1, stale.len());
+            (file, signature)
+        })
+        .collect();
+    let mut batch = db::WriteBatch::begin(conn, TRANSACTION_BATCH_SIZE)?;
+    for (file, signature) in fresh {
+        let record = db::VideoSignatureRecord {
+            hash: file.hash.clone(),
+            duration: signature.as_ref().map_or(0.0, |s| s.duration),
+            width: signature.as_ref().map_or(0, |s| s.width.into()),
+            height: signature.as_ref().map_or(0, |s| s.height.into()),
+            frames: signature.map(|s| s.frames),
+        };
+        db::set_video_signature(conn, &file.path, &record)?;
+        stored.insert(file.path.clone(), record);
+        batch.tick()?;
+    }
+    db::prune_video_signatures(conn)?;
+    batch.commit()?;
+
+    let mut unreadable = 0;
+    let mut videos = Vec::new();
+    for copies in lookalike::by_content(&files) {
+        let file = copies[0];
+        let Some((frames, record)) = stored
+            .get(&file.path)
+            .and_then(|s| s.frames.as_ref().map(|frames| (frames, s)))
+        else {
+            unreadable += copies.len();
+            continue;
+        };
+        videos.push(Video {
+            paths: copies.iter().map(|f| f.path.clone()).collect(),
+            size: file.size,
+            signature: Signature {
+                duration: record.duration,
+                width: record.width as u32,
+                height: record.height as u32,
+                frames: frames.clone(),
+            },
+        });
+    }
+    Ok(SimilarVideos {
+        clusters: cluster(videos, threshold),
+        unreadable,
+    })
+}
+
+/// Group `videos` into clusters of two or more linked by pairs with a
+/// confidence of at least `threshold`.
+fn cluster(mut videos: Vec<Video>, threshold: f64) -> Vec<Cluster> {
+    // Sorted by length, only neighbours within the tolerance are compared
+    videos.sort_by(|a, b| a.signature.duration.total_cmp(&b.signature.duration));
+    let mut sets = utils::DisjointSets::new(videos.len());
+    for (i, video) in videos.iter().enumerate() {
+        for (j, other) in videos.iter().enumerate().skip(i + 1) {
+            let apart = other.signature.duration - video.signature.duration;
+            if apart > tolerance(other.signature.duration) {
+                break;
+            }
+            if confidence(&video.signature, &other.signature) >= threshold {
+                sets.union(i, j);
+            }
+        }
+    }
+
+    let mut members: HashMap<usize, Vec<Video>> = HashMap::new();
+    for (index, mut video) in videos.into_iter().enumerate() {
+        video.paths.sort();
+        members.entry(sets.find(index)).or_default().push(video);
+    }
+    let mut clusters: Vec<Cluster> = members
+        .into_values()
+        .filter(|videos| videos.len() > 1)
+        .map(|mut videos| {
+            videos.sort_by(|a, b| {
+                let pixels =
+                    |v: &Video| u64::from(v.signature.width) * u64::from(v.signature.height);
+                pixels(b)
+                    .cmp(&pixels(a))
+                    .then(b.size.cmp(&a.size))
+                    .then_with(|| a.paths.cmp(&b.paths))
+            });
+            Cluster { videos }
+        })
+        .collect();
+    clusters.sort_by(|a, b| {
+        b.total_size()
+            .cmp(&a.total_size())
+            .then_with(|| a.videos[0].paths.cmp(&b.videos[0].paths))
+    });
+    clusters
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(path: &str, size: i64, frames: Vec<u64>, duration: f64, width: u32) -> Video {
+        Video {
+            paths: vec![path.to_string()],
+            size,
+            signature: Signature {
+                duration,
+                width,
+                height: width * 9 / 16,
+                frames,
+            },
+        }
+    }
+
+    fn frames(seed: u64) -> Vec<u64> {
+        (0..FRAMES as u64)
+            .map(|i| (seed * 31 + i).wrapping_mul(0x9E37_79B9_7F4A_7C15))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_probe_reads_the_stream_and_format_entries() {
+        let output = "width=1920\nheight=1080\nduration=12.480000\n";
+        assert_eq!(parse_probe(output).unwrap(), (12.48, 1920, 1080));
+        assert!(parse_probe("duration=3.0\n").is_err());
+        assert!(parse_probe("width=640\nheight=480\nduration=N/A\n").is_err());
+    }
+
+    #[test]
+    fn test_confidence_needs_close_frames_and_a_similar_length() {
+        let a = video("/a.mp4", 1, frames(1), 600.0, 1920).signature;
+        let mut reencoded = a.clone();
+        reencoded.duration = 601.0;
+        for frame in &mut reencoded.frames {
+            *frame ^= 0b101;
+        }
+        let c = 1.0 - 2.0 / UNRELATED_DISTANCE;
+        assert!((confidence(&a, &reencoded) - c).abs() < 1e-9);
+        assert_eq!(confidence(&a, &a), 1.0);
+        let other = video("/b.mp4", 1, frames(2), 600.0, 1920).signature;
+        assert!(confidence(&a, &other) < 0.3, "{}", confidence(&a, &other));
+        let mut longer = a.clone();
+        longer.duration = 620.0;
+        assert_eq!(confidence(&a, &longer), 0.0);
+    }
+
+    #[test]
+    fn test_cluster_puts_the_most_pixels_first() {
+        let mut small = frames(1);
+        small[0] ^= 1;
+        let clusters = cluster(
+            vec![
+                video("/clip-720.mp4", 50, small, 30.5, 1280),
+                video("/clip.mov", 90, frames(1), 30.0, 1920),
+                video("/other.mp4", 10, frames(2), 30.0, 1920),
+            ],
+            0.8,
+        );
+        assert_eq!(clusters.len(), 1);
+        let paths: Vec<&str> = clusters[0]
+            .videos
+            .iter()
+            .map(|v| v.paths[0].as_str())
+            .collect();
+        assert_eq!(paths, vec!["/clip.mov", "/clip-720.mp4"]);
+    }
+
+    #[test]
+    fn test_find_similar_videos_samples_reencoded_copies() {
+        if !tools_available() {
+            eprintln!("ffmpeg not found; skipping");
+            return;
+        }
+        let tmp = tempfile::tempdir().unwrap();
+        let make = |name: &str, source: &str, size: &str| {
+            let status = Command::new("ffmpeg")
+                .args(["-v", "error", "-f", "lavfi", "-i"])
+                .arg(format!("{source}=duration=6:size={size}:rate=10"))
+                .arg(tmp.path().join(name))
+                .status()
+                .unwrap();
+            assert!(status.success());
+        };
+        make("a.mp4", "testsrc", "320x240");
+        make("a-small.mkv", "testsrc", "160x120");
+        make("b.mp4", "mandelbrot", "320x240");
+        std::fs::write(tmp.path().join("broken.mp4"), "not a video").unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        let opts = crate::scan::ScanOptions::default();
+        crate::scan::scan_directory(&conn, tmp.path(), 4, &opts, |_, _, _| {}).unwrap();
+
+        let found = find_similar_videos(&conn, 0.8, &[], |_, _| {}).unwrap();
+        assert_eq!(found.unreadable, 1);
+        assert_eq!(found.clusters.len(), 1);
+        assert_eq!(found.clusters[0].videos[0].signature.width, 320);
+        assert_eq!(found.clusters[0].videos.len(), 2);
+    }
+}
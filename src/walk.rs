@@ -0,0 +1,605 @@
+use std::collections::HashSet;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use regex::Regex;
+
+/// The tool's own ignore file, honoured in every directory.
+pub const DEDUPIGNORE: &str = ".dedupignore";
+
+/// How a scan walks each root: where it stops and which entries it skips.
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// Don't descend into directories on a different filesystem than the root.
+    pub one_file_system: bool,
+    pub filter: PathFilter,
+    /// Also honour `.gitignore` files, as `.dedupignore` always is.
+    pub gitignore: bool,
+    /// How many levels below the root to list files at.
+    pub max_depth: Option<usize>,
+    /// Leave out dotfiles and dot-directories (and hidden ones on Windows).
+    pub skip_hidden: bool,
+    /// Descend into symlinked directories.
+    pub follow_symlinks: bool,
+    pub types: TypeFilter,
+    /// Leave out files smaller than this many bytes; 0 keeps them all.
+    pub min_file_size: u64,
+}
+
+/// The `--exclude`, `--include` and `--exclude-regex` patterns. Excluded
+/// directories are pruned from the walk rather than filtered out afterwards,
+/// so nothing below them is even listed.
+#[derive(Debug, Clone, Default)]
+pub struct PathFilter {
+    exclude: Vec<Pattern>,
+    include: Vec<Pattern>,
+    exclude_regex: Vec<Regex>,
+}
+
+/// One glob. A pattern without a `/` matches an entry's name at any depth
+/// (`node_modules`, `*.tmp`); one with a `/` matches the path relative to the
+/// scanned root (`photos/**/thumbs`, `/build`). A trailing `/` only matches
+/// directories (`target/`).
+#[derive(Debug, Clone)]
+struct Pattern {
+    matcher: GlobMatcher,
+    whole_path: bool,
+    dirs_only: bool,
+}
+
+impl Pattern {
+    fn new(pattern: &str) -> Result<Self> {
+        let (body, dirs_only) = match pattern.strip_suffix('/') {
+            Some(body) => (body, true),
+            None => (pattern, false),
+        };
+        let whole_path = body.contains('/');
+        let body = body.strip_prefix('/').unwrap_or(body);
+        let matcher = GlobBuilder::new(body)
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("invalid glob '{}'", pattern))?
+            .compile_matcher();
+        Ok(Self {
+            matcher,
+            whole_path,
+            dirs_only,
+        })
+    }
+
+    fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.dirs_only && !is_dir {
+            return false;
+        }
+        if self.whole_path {
+            self.matcher.is_match(relative)
+        } else {
+            relative
+                .file_name()
+                .is_some_and(|name| self.matcher.is_match(name))
+        }
+    }
+}
+
+impl PathFilter {
+    /// Compile the patterns, reporting the first one that doesn't parse.
+    pub fn new(exclude: &[String], include: &[String], exclude_regex: &[String]) -> Result<Self> {
+        let globs = |patterns: &[String]| -> Result<Vec<Pattern>> {
+            patterns.iter().map(|p| Pattern::new(p)).collect()
+        };
+        let exclude_regex = exclude_regex
+            .iter()
+            .map(|r| Regex::new(r).with_context(|| format!("invalid regex '{}'", r)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            exclude: globs(exclude)?,
+            include: globs(include)?,
+            exclude_regex,
+        })
+    }
+
+    /// Whether the walk leaves out the entry at `relative` (to the scanned
+    /// root). Directories are only ever left out by an exclusion; `--include`
+    /// narrows down the files, and a file must match one of its patterns
+    /// when any are given.
+    pub fn skips(&self, relative: &Path, is_dir: bool) -> bool {
+        if self.exclude.iter().any(|p| p.matches(relative, is_dir)) {
+            return true;
+        }
+        if !self.exclude_regex.is_empty() {
+            // Always `/`-separated, so one regex works on every platform
+            let text = relative.to_string_lossy().replace('\\', "/");
+            if self.exclude_regex.iter().any(|r| r.is_match(&text)) {
+                return true;
+            }
+        }
+        !is_dir
+            && !self.include.is_empty()
+            && !self.include.iter().any(|p| p.matches(relative, false))
+    }
+}
+
+/// A built-in set of `--exclude` patterns, for `--preset`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Preset {
+    /// Virtual filesystems, temporary files, caches and the trash
+    System,
+    /// Dependencies, build output and tool caches of software projects
+    Dev,
+}
+
+/// Paths anchored with `/` only match when the root of the filesystem is
+/// scanned; the `**/` ones are found under any home directory.
+const SYSTEM_EXCLUDES: &[&str] = &[".Trash/", ".Trash-*/", "**/.local/share/Trash/", ".cache/"];
+
+#[cfg(all(unix, not(target_os = "macos")))]
+const PLATFORM_EXCLUDES: &[&str] = &[
+    "/proc/",
+    "/sys/",
+    "/dev/",
+    "/run/",
+    "/tmp/",
+    "/var/tmp/",
+    "/var/cache/",
+    "/var/run/",
+    "/var/lib/docker/",
+    "lost+found/",
+    "**/.mozilla/firefox/*/storage/",
+    "**/.var/app/*/cache/",
+];
+
+#[cfg(target_os = "macos")]
+const PLATFORM_EXCLUDES: &[&str] = &[
+    "/dev/",
+    "/System/",
+    "/private/var/",
+    "/private/tmp/",
+    ".Trashes/",
+    ".Spotlight-V100/",
+    ".fseventsd/",
+    ".DocumentRevisions-V100/",
+    "**/Library/Caches/",
+    "**/Library/Containers/*/Data/Library/Caches/",
+];
+
+#[cfg(windows)]
+const PLATFORM_EXCLUDES: &[&str] = &[
+    "/Windows/",
+    "$RECYCLE.BIN/",
+    "System Volume Information/",
+    "pagefile.sys",
+    "hiberfil.sys",
+    "swapfile.sys",
+    "**/AppData/Local/Temp/",
+    "**/AppData/Local/Microsoft/Windows/INetCache/",
+    "**/AppData/Local/Google/Chrome/User Data/*/Cache/",
+    "**/AppData/Local/Microsoft/Edge/User Data/*/Cache/",
+    "**/AppData/Local/Mozilla/Firefox/Profiles/*/cache2/",
+];
+
+#[cfg(not(any(unix, windows)))]
+const PLATFORM_EXCLUDES: &[&str] = &[];
+
+const DEV_EXCLUDES: &[&str] = &[
+    "node_modules/",
+    "target/",
+    ".venv/",
+    "venv/",
+    "__pycache__/",
+    ".tox/",
+    ".mypy_cache/",
+    ".pytest_cache/",
+    ".gradle/",
+    ".next/",
+    ".parcel-cache/",
+    ".terraform/",
+    "DerivedData/",
+    "**/.cargo/registry/",
+    "**/.rustup/toolchains/",
+    "**/.npm/_cacache/",
+    "**/.m2/repository/",
+    "**/go/pkg/mod/",
+];
+
+impl Preset {
+    /// The preset's globs, written as for `--exclude`, for the platform this
+    /// was built for.
+    pub fn excludes(self) -> Vec<&'static str> {
+        match self {
+            Preset::System => [SYSTEM_EXCLUDES, PLATFORM_EXCLUDES].concat(),
+            Preset::Dev => DEV_EXCLUDES.to_vec(),
+        }
+    }
+}
+
+/// A broad kind of file, for `--type`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FileKind {
+    Image,
+    Video,
+    Audio,
+    /// PDFs, office documents, e-books and plain text
+    Document,
+    Archive,
+}
+
+impl FileKind {
+    /// The lowercase extensions files of this kind usually carry.
+    fn extensions(self) -> &'static [&'static str] {
+        match self {
+            FileKind::Image => &[
+                "jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "webp", "heic", "heif", "avif",
+                "svg", "ico", "psd", "raw", "dng", "cr2", "cr3", "nef", "arw", "orf", "rw2", "raf",
+            ],
+            FileKind::Video => &[
+                "mp4", "m4v", "mov", "avi", "mkv", "webm", "wmv", "flv", "mpg", "mpeg", "3gp",
+                "mts", "m2ts",
+            ],
+            FileKind::Audio => &[
+                "mp3", "m4a", "aac", "flac", "wav", "ogg", "oga", "opus", "wma", "aif", "aiff",
+            ],
+            FileKind::Document => &[
+                "pdf", "doc", "docx", "xls", "xlsx", "ppt", "pptx", "odt", "ods", "odp", "rtf",
+                "txt", "md", "csv", "epub", "mobi", "tex",
+            ],
+            FileKind::Archive => &[
+                "zip", "tar", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar", "iso", "dmg",
+            ],
+        }
+    }
+
+    /// The kind `infer` recognised from a file's first bytes, if it is one
+    /// of these.
+    fn sniffed(found: &infer::Type) -> Option<Self> {
+        use infer::MatcherType;
+
+        match found.matcher_type() {
+            MatcherType::Image => Some(FileKind::Image),
+            MatcherType::Video => Some(FileKind::Video),
+            MatcherType::Audio => Some(FileKind::Audio),
+            MatcherType::Doc | MatcherType::Book | MatcherType::Text => Some(FileKind::Document),
+            // infer files PDFs with the archives
+            MatcherType::Archive if found.extension() == "pdf" => Some(FileKind::Document),
+            MatcherType::Archive => Some(FileKind::Archive),
+            _ => None,
+        }
+    }
+}
+
+/// The `--ext` and `--type` filters. A file is scanned when it matches any
+/// of them, or when none are given; directories are never left out. With
+/// `sniff`, `--type` goes by the file's content where `infer` recognises it
+/// and by extension otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct TypeFilter {
+    /// Lowercase, without the dot
+    extensions: HashSet<String>,
+    kinds: Vec<FileKind>,
+    sniff: bool,
+}
+
+impl TypeFilter {
+    pub fn new(extensions: &[String], kinds: &[FileKind], sniff: bool) -> Self {
+        Self {
+            extensions: extensions
+                .iter()
+                .map(|e| e.trim_start_matches('.').to_lowercase())
+                .collect(),
+            kinds: kinds.to_vec(),
+            sniff,
+        }
+    }
+
+    /// Whether the file at `path` is one of the wanted types.
+    pub fn admits(&self, path: &Path) -> bool {
+        if self.extensions.is_empty() && self.kinds.is_empty() {
+            return true;
+        }
+        let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        if extension
+            .as_ref()
+            .is_some_and(|e| self.extensions.contains(e))
+        {
+            return true;
+        }
+        if self.kinds.is_empty() {
+            return false;
+        }
+        if self.sniff {
+            if let Ok(Some(found)) = infer::get_from_path(path) {
+                return FileKind::sniffed(&found).is_some_and(|kind| self.kinds.contains(&kind));
+            }
+        }
+        extension.is_some_and(|e| {
+            self.kinds
+                .iter()
+                .any(|kind| kind.extensions().contains(&e.as_str()))
+        })
+    }
+}
+
+/// The `.dedupignore` (and with `--gitignore`, `.gitignore`) files met on
+/// the way down a walk. Each one holds gitignore patterns for the directory
+/// it sits in and everything below, a deeper file overriding a shallower
+/// one; a `.dedupignore` without any patterns prunes its whole directory.
+pub struct IgnoreFiles {
+    gitignore: bool,
+    /// The matchers of the directories above the current entry, with the
+    /// depth of each directory.
+    stack: Vec<(usize, Gitignore)>,
+}
+
+impl IgnoreFiles {
+    pub fn new(opts: &WalkOptions) -> Self {
+        Self {
+            gitignore: opts.gitignore,
+            stack: Vec::new(),
+        }
+    }
+
+    /// Whether the ignore files above `path` leave it out. Entries must come
+    /// in walk order: a directory that is kept has its own ignore files read
+    /// for the entries below it. The root (depth
+    /// 0) is never left out, though an empty `.dedupignore` there still
+    /// keeps out everything below it.
+    pub fn skips(&mut self, path: &Path, depth: usize, is_dir: bool) -> bool {
+        while self.stack.last().is_some_and(|(d, _)| *d >= depth) {
+            self.stack.pop();
+        }
+        for (_, matcher) in self.stack.iter().rev() {
+            match matcher.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => break,
+                Match::None => {}
+            }
+        }
+        if is_dir {
+            match self.load(path) {
+                Some(matcher) => self.stack.push((depth, matcher)),
+                None if depth > 0 => return true,
+                None => self.stack.push((depth, ignore_everything(path))),
+            }
+        }
+        false
+    }
+
+    /// The patterns of `dir`'s ignore files, or `None` when an empty
+    /// `.dedupignore` prunes it. Unreadable files and bad lines are passed
+    /// over, as git does.
+    fn load(&self, dir: &Path) -> Option<Gitignore> {
+        let mut builder = GitignoreBuilder::new(dir);
+        if self.gitignore {
+            builder.add(dir.join(".gitignore"));
+        }
+        let dedupignore = dir.join(DEDUPIGNORE);
+        if dedupignore.is_file() {
+            let (own, _) = Gitignore::new(&dedupignore);
+            if own.is_empty() {
+                return None;
+            }
+            builder.add(dedupignore);
+        }
+        Some(builder.build().unwrap_or_else(|_| Gitignore::empty()))
+    }
+}
+
+fn ignore_everything(dir: &Path) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(dir);
+    builder.add_line(None, "*").expect("valid pattern");
+    builder.build().expect("valid pattern")
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(exclude: &[&str], include: &[&str], regex: &[&str]) -> PathFilter {
+        let owned = |s: &[&str]| s.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        PathFilter::new(&owned(exclude), &owned(include), &owned(regex)).unwrap()
+    }
+
+    #[test]
+    fn test_exclude_names_paths_and_dirs_only() {
+        let f = filter(
+            &["node_modules", "*.tmp", "target/", "docs/*/draft"],
+            &[],
+            &[],
+        );
+        assert!(f.skips(Path::new("app/node_modules"), true));
+        assert!(f.skips(Path::new("a/b/x.tmp"), false));
+        assert!(f.skips(Path::new("rust/target"), true));
+        // `target/` only matches directories
+        assert!(!f.skips(Path::new("rust/target"), false));
+        assert!(f.skips(Path::new("docs/2024/draft"), true));
+        // A path pattern is anchored at the root, and `*` stays within one level
+        assert!(!f.skips(Path::new("old/docs/2024/draft"), true));
+        assert!(!f.skips(Path::new("docs/a/b/draft"), true));
+        assert!(!f.skips(Path::new("src/main.rs"), false));
+    }
+
+    #[test]
+    fn test_include_only_narrows_files() {
+        let f = filter(&[], &["*.jpg", "*.png"], &[]);
+        assert!(!f.skips(Path::new("photos/a.JPG.jpg"), false));
+        assert!(f.skips(Path::new("photos/notes.txt"), false));
+        assert!(!f.skips(Path::new("photos"), true));
+    }
+
+    #[test]
+    fn test_exclude_regex_matches_relative_path() {
+        let f = filter(&[], &[], &[r"(^|/)\.cache(/|$)", r"\.bak$"]);
+        assert!(f.skips(Path::new("home/.cache"), true));
+        assert!(f.skips(Path::new("a/file.bak"), false));
+        assert!(!f.skips(Path::new("a/cache/file"), false));
+    }
+
+    /// The paths below `root` a walk keeps, as `/`-separated relative paths.
+    fn walk_kept(root: &Path, gitignore: bool) -> Vec<String> {
+        let opts = WalkOptions {
+            gitignore,
+            ..WalkOptions::default()
+        };
+        let mut ignores = IgnoreFiles::new(&opts);
+        let mut kept: Vec<String> = walkdir::WalkDir::new(root)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|e| !ignores.skips(e.path(), e.depth(), e.file_type().is_dir()))
+            .map(|e| e.unwrap())
+            .filter(|e| e.depth() > 0)
+            .map(|e| {
+                let relative = e.path().strip_prefix(root).unwrap();
+                relative.to_string_lossy().replace('\\', "/")
+            })
+            .collect();
+        kept.sort();
+        kept
+    }
+
+    #[test]
+    fn test_dedupignore_applies_to_its_subtree() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("code/target/debug")).unwrap();
+        std::fs::create_dir_all(root.join("code/src")).unwrap();
+        std::fs::create_dir_all(root.join("other/target")).unwrap();
+        std::fs::write(
+            root.join("code/.dedupignore"),
+            "target/\n*.log\n!keep.log\n",
+        )
+        .unwrap();
+        std::fs::write(root.join("code/target/debug/app"), "x").unwrap();
+        std::fs::write(root.join("code/src/a.log"), "x").unwrap();
+        std::fs::write(root.join("code/src/keep.log"), "x").unwrap();
+        std::fs::write(root.join("other/target/b"), "x").unwrap();
+
+        assert_eq!(
+            walk_kept(root, false),
+            [
+                "code",
+                "code/.dedupignore",
+                "code/src",
+                "code/src/keep.log",
+                "other",
+                "other/target",
+                "other/target/b",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_empty_dedupignore_prunes_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("cache/deep")).unwrap();
+        std::fs::write(root.join("cache/.dedupignore"), "# nothing to keep\n").unwrap();
+        std::fs::write(root.join("cache/deep/blob"), "x").unwrap();
+        std::fs::write(root.join("a"), "x").unwrap();
+
+        assert_eq!(walk_kept(root, false), ["a"]);
+        // At the root itself it keeps out everything below
+        assert!(walk_kept(&root.join("cache"), false).is_empty());
+    }
+
+    #[test]
+    fn test_gitignore_only_when_asked() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        std::fs::create_dir_all(root.join("node_modules")).unwrap();
+        std::fs::write(root.join(".gitignore"), "node_modules\n").unwrap();
+        std::fs::write(root.join("node_modules/x.js"), "x").unwrap();
+
+        assert_eq!(walk_kept(root, true), [".gitignore"]);
+        assert_eq!(
+            walk_kept(root, false),
+            [".gitignore", "node_modules", "node_modules/x.js"]
+        );
+    }
+
+    #[test]
+    fn test_presets_compile_and_prune_directories() {
+        let patterns: Vec<String> = [Preset::System, Preset::Dev]
+            .iter()
+            .flat_map(|p| p.excludes())
+            .map(String::from)
+            .collect();
+        let f = PathFilter::new(&patterns, &[], &[]).unwrap();
+        assert!(f.skips(Path::new(".cache"), true));
+        assert!(f.skips(Path::new("me/.local/share/Trash"), true));
+        assert!(f.skips(Path::new("code/app/node_modules"), true));
+        assert!(f.skips(Path::new("code/tool/target"), true));
+        assert!(f.skips(Path::new("me/.cargo/registry"), true));
+        // Only directories are pruned
+        assert!(!f.skips(Path::new("notes/target"), false));
+        assert!(!f.skips(Path::new("code/app/src"), true));
+    }
+
+    #[cfg(all(unix, not(target_os = "macos")))]
+    #[test]
+    fn test_system_preset_only_anchors_at_the_root() {
+        let patterns: Vec<String> = Preset::System
+            .excludes()
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let f = PathFilter::new(&patterns, &[], &[]).unwrap();
+        assert!(f.skips(Path::new("proc"), true));
+        assert!(f.skips(Path::new("var/cache"), true));
+        assert!(!f.skips(Path::new("backup/proc"), true));
+        assert!(!f.skips(Path::new("home"), true));
+    }
+
+    #[test]
+    fn test_type_filter_by_extension_and_kind() {
+        let everything = TypeFilter::default();
+        assert!(everything.admits(Path::new("a/notes")));
+
+        let f = TypeFilter::new(&[".RAW".into(), "txt".into()], &[FileKind::Image], false);
+        assert!(f.admits(Path::new("a/IMG_1.JPG")));
+        assert!(f.admits(Path::new("a/b.raw")));
+        assert!(f.admits(Path::new("a/notes.txt")));
+        assert!(!f.admits(Path::new("a/clip.mp4")));
+        assert!(!f.admits(Path::new("a/README")));
+
+        let videos = TypeFilter::new(&[], &[FileKind::Video, FileKind::Audio], false);
+        assert!(videos.admits(Path::new("clip.mkv")));
+        assert!(videos.admits(Path::new("song.flac")));
+        assert!(!videos.admits(Path::new("photo.png")));
+    }
+
+    #[test]
+    fn test_type_filter_sniffs_content() {
+        let dir = tempfile::tempdir().unwrap();
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        // A PNG saved without its extension, and one misnamed as a video
+        std::fs::write(dir.path().join("picture"), png).unwrap();
+        std::fs::write(dir.path().join("fake.mp4"), png).unwrap();
+        std::fs::write(dir.path().join("unknown.jpg"), "not an image").unwrap();
+
+        let sniffing = TypeFilter::new(&[], &[FileKind::Image], true);
+        assert!(sniffing.admits(&dir.path().join("picture")));
+        assert!(sniffing.admits(&dir.path().join("fake.mp4")));
+        // Unrecognised content falls back to the extension
+        assert!(sniffing.admits(&dir.path().join("unknown.jpg")));
+
+        let by_name = TypeFilter::new(&[], &[FileKind::Image], false);
+        assert!(!by_name.admits(&dir.path().join("picture")));
+        assert!(!by_name.admits(&dir.path().join("fake.mp4")));
+    }
+
+    #[test]
+    fn test_invalid_patterns_are_reported() {
+        assert!(PathFilter::new(&["a[".into()], &[], &[]).is_err());
+        assert!(PathFilter::new(&[], &[], &["(".into()]).is_err());
+    }
+}
@@ -0,0 +1,748 @@
+use std::collections::{BTreeSet, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{anyhow, bail, Context, Result};
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::Watcher as _;
+use rusqlite::Connection;
+
+use crate::{archive, db, file_system, hashing, scan, utils};
+
+/// How long one wait for notifications lasts before `Watcher::wait` checks
+/// whether it was cancelled.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Notifications for everything under a set of directories, from inotify,
+/// FSEvents or ReadDirectoryChangesW through `notify`. Directories created
+/// or moved in are watched as they turn up.
+pub struct Watcher {
+    roots: Vec<PathBuf>,
+    /// Each root as it is watched: absolute, with symlinks resolved, as
+    /// notifications name it
+    watched: Vec<PathBuf>,
+    events: mpsc::Receiver<notify::Result<notify::Event>>,
+    /// Kept for as long as the watch lasts
+    _inner: notify::RecommendedWatcher,
+}
+
+/// What changed under one of the watched roots.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Changes {
+    pub root: PathBuf,
+    /// The files and directories created, changed, removed, or renamed from
+    /// or to, ordered by path; none is below another.
+    pub paths: Vec<PathBuf>,
+    /// Files and directories renamed within the root, from and to
+    pub moves: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Watcher {
+    /// Start watching `roots` and every directory below them.
+    pub fn new(roots: &[&Path]) -> Result<Self> {
+        let (tx, events) = mpsc::channel();
+        let mut inner = notify::recommended_watcher(tx).context("could not watch for changes")?;
+        let mut watched = Vec::new();
+        for root in roots {
+            let absolute = fs::canonicalize(root)
+                .with_context(|| format!("could not watch {}", root.display()))?;
+            inner
+                .watch(&absolute, notify::RecursiveMode::Recursive)
+                .map_err(|e| watch_error(e, root))?;
+            watched.push(absolute);
+        }
+        Ok(Self {
+            roots: roots.iter().map(|r| r.to_path_buf()).collect(),
+            watched,
+            events,
+            _inner: inner,
+        })
+    }
+
+    /// Wait for something under the roots to change, then until `settle` has
+    /// passed without another change, so a file being copied or a tree being
+    /// unpacked is picked up once it is done. Returns what changed under each
+    /// root, in the order they were given, or `None` once `cancel` is set. If
+    /// notifications were dropped, the whole of each root counts as changed.
+    pub fn wait(&mut self, settle: Duration, cancel: &AtomicBool) -> Result<Option<Vec<Changes>>> {
+        let mut paths: HashSet<PathBuf> = HashSet::new();
+        let mut moves: Vec<(PathBuf, PathBuf)> = Vec::new();
+        let mut last_change = Instant::now();
+        loop {
+            if cancel.load(Ordering::Relaxed) {
+                return Ok(None);
+            }
+            let event = match self.events.recv_timeout(TICK) {
+                Ok(Ok(event)) => event,
+                Ok(Err(e)) if matches!(e.kind, notify::ErrorKind::MaxFilesWatch) => {
+                    return Err(watch_error(e, Path::new("")));
+                }
+                Ok(Err(e)) => {
+                    // What it was about may have changed unseen
+                    tracing::warn!("while watching: {e}");
+                    match e.paths.is_empty() {
+                        true => paths.extend(self.roots.iter().cloned()),
+                        false => paths.extend(e.paths.iter().flat_map(|p| self.as_given(p))),
+                    }
+                    last_change = Instant::now();
+                    continue;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if !paths.is_empty() && last_change.elapsed() >= settle {
+                        break;
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => bail!("the watch for changes stopped"),
+            };
+            if event.need_rescan() {
+                paths.extend(self.roots.iter().cloned());
+            } else {
+                match event.kind {
+                    // Reads change nothing, and a refresh reading the files
+                    // would count as a change otherwise
+                    EventKind::Access(_) => continue,
+                    EventKind::Modify(ModifyKind::Name(RenameMode::Both))
+                        if event.paths.len() == 2 =>
+                    {
+                        for (root, watched) in self.roots.iter().zip(&self.watched) {
+                            let from = given_under(root, watched, &event.paths[0]);
+                            let to = given_under(root, watched, &event.paths[1]);
+                            moves.extend(from.zip(to));
+                        }
+                    }
+                    _ => {}
+                }
+                paths.extend(event.paths.iter().flat_map(|p| self.as_given(p)));
+            }
+            last_change = Instant::now();
+        }
+        Ok(Some(
+            self.roots
+                .iter()
+                .filter_map(|root| changes_under(root, &paths, &moves))
+                .collect(),
+        ))
+    }
+
+    /// `path`, as a notification names it, under each root it is in as that
+    /// root was given, so it matches the rows scanning the root stored.
+    fn as_given<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = PathBuf> + 'a {
+        self.roots
+            .iter()
+            .zip(&self.watched)
+            .filter_map(move |(root, watched)| given_under(root, watched, path))
+    }
+}
+
+/// `path` under `root` as it was given, if it is below `watched`, the
+/// root's absolute form.
+fn given_under(root: &Path, watched: &Path, path: &Path) -> Option<PathBuf> {
+    let below = path.strip_prefix(watched).ok()?;
+    Some(match below.as_os_str().is_empty() {
+        true => root.to_path_buf(),
+        false => root.join(below),
+    })
+}
+
+/// The changes among `paths` and `moves` that are under `root`, if any.
+fn changes_under(
+    root: &Path,
+    paths: &HashSet<PathBuf>,
+    moves: &[(PathBuf, PathBuf)],
+) -> Option<Changes> {
+    let mut under: Vec<&PathBuf> = paths.iter().filter(|p| p.starts_with(root)).collect();
+    if under.is_empty() {
+        return None;
+    }
+    // Ordered by component, each path comes right before the ones below it
+    under.sort();
+    let mut kept: Vec<PathBuf> = Vec::new();
+    for path in under {
+        if !kept.last().is_some_and(|last| path.starts_with(last)) {
+            kept.push(path.clone());
+        }
+    }
+    Some(Changes {
+        root: root.to_path_buf(),
+        paths: kept,
+        moves: moves
+            .iter()
+            .filter(|(from, to)| from.starts_with(root) && to.starts_with(root))
+            .cloned()
+            .collect(),
+    })
+}
+
+fn watch_error(error: notify::Error, root: &Path) -> anyhow::Error {
+    match error.kind {
+        notify::ErrorKind::MaxFilesWatch => anyhow!(
+            "too many directories to watch; raise the limit with \
+             `sysctl fs.inotify.max_user_watches=<count>`"
+        ),
+        _ => anyhow::Error::new(error).context(format!("could not watch {}", root.display())),
+    }
+}
+
+/// A file that came out of a `refresh` with the same content as others in
+/// the database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewDuplicate {
+    pub path: String,
+    /// The other files with its hash, ordered by path
+    pub copies: Vec<String>,
+}
+
+/// What a `refresh` changed in the database.
+#[derive(Debug, Default)]
+pub struct Update {
+    /// Files that are new or whose content changed
+    pub updated: usize,
+    /// Files renamed within a root, whose rows were repointed
+    pub moved: usize,
+    /// Files that are gone, and were taken out of the database
+    pub removed: usize,
+    /// Updated files that are copies of others, ordered by path. Empty files
+    /// are left out.
+    pub duplicates: Vec<NewDuplicate>,
+    /// Entries that could not be listed, read or hashed
+    pub errors: Vec<scan::ScanError>,
+    /// `opts.cancel` was set part-way; the roots after the one being
+    /// refreshed were left alone.
+    pub interrupted: bool,
+}
+
+/// A new or changed file to hash.
+struct Job {
+    path: PathBuf,
+    path_str: String,
+    size: u64,
+    modified_secs: i64,
+    partial: Option<String>,
+    identity: Option<(i64, i64)>,
+    stat: Option<utils::FileStat>,
+    via_link: bool,
+}
+
+/// Bring the database up to date with `changes`, without asking: the paths
+/// that changed are listed as a scan would list them, files whose size or
+/// modification time changed are hashed, renamed ones keep their rows under
+/// the new path, rows for what is gone are taken out, and then only the
+/// directories at and above the changes are rehashed. A root without a
+/// record yet is gone through whole.
+pub fn refresh(conn: &Connection, changes: &[Changes], opts: &scan::ScanOptions) -> Result<Update> {
+    let mut update = Update::default();
+    let mut hashed: Vec<(String, String)> = Vec::new();
+    for changes in changes {
+        hashed.extend(refresh_root(conn, changes, opts, &mut update)?);
+        if opts.cancelled() {
+            update.interrupted = true;
+            break;
+        }
+    }
+
+    for (path, hash) in hashed {
+        let copies: Vec<String> = db::files_with_hash(conn, &hash)?
+            .into_iter()
+            .map(|f| f.path)
+            .filter(|p| *p != path)
+            .collect();
+        if !copies.is_empty() {
+            update.duplicates.push(NewDuplicate { path, copies });
+        }
+    }
+    update.duplicates.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(update)
+}
+
+/// `refresh` for one root; returns the paths and new hashes of the files
+/// whose content changed, leaving out empty ones.
+fn refresh_root(
+    conn: &Connection,
+    changes: &Changes,
+    opts: &scan::ScanOptions,
+    update: &mut Update,
+) -> Result<Vec<(String, String)>> {
+    let root = &changes.root;
+    let _refresh = tracing::info_span!("refresh", root = %root.display()).entered();
+    let whole = [root.clone()];
+    let paths: &[PathBuf] = match db::get_directory(conn, root)? {
+        Some(_) => &changes.paths,
+        None => &whole,
+    };
+    let started = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)?
+        .as_secs() as i64;
+
+    // Renames first, so the rows are found unchanged under their new paths
+    for (from, to) in &changes.moves {
+        if to.is_dir() {
+            let files = db::files_under(conn, from)?.len();
+            if files > 0 || db::get_directory(conn, from)?.is_some() {
+                tracing::debug!("moved {} -> {}", from.display(), to.display());
+                db::move_tree(conn, from, to)?;
+                update.moved += files;
+            }
+        } else if let Some(record) = db::get_file(conn, from)? {
+            // An archive's members are read again instead
+            if !archive::is_archive(to) && utils::mtime(to).ok() == Some(record.modified) {
+                tracing::debug!("moved {} -> {}", from.display(), to.display());
+                db::move_file(conn, from, to)?;
+                update.moved += 1;
+            }
+        }
+    }
+
+    let mut errors = Vec::new();
+    let mut listed: HashSet<String> = HashSet::new();
+    let mut directories = Vec::new();
+    let mut jobs = Vec::new();
+    let mut archives = Vec::new();
+    for entry in file_system::walk_within(root, &opts.walk, paths) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                errors.push(scan::ScanError::from_walk(root, &e));
+                continue;
+            }
+        };
+        let path = entry.path();
+        // Directories on the way down are left as they are
+        if !paths.iter().any(|p| path.starts_with(p)) {
+            continue;
+        }
+        let path_str = utils::path_to_db(path).into_owned();
+        if path.is_dir() {
+            directories.push(path.to_path_buf());
+            listed.insert(path_str);
+            continue;
+        }
+        if !path.is_file() {
+            continue;
+        }
+        let (metadata, modified) =
+            match fs::metadata(path).and_then(|m| m.modified().map(|t| (m, t))) {
+                Ok(found) => found,
+                Err(e) => {
+                    errors.push(scan::ScanError::io(path, &e));
+                    continue;
+                }
+            };
+        listed.insert(path_str.clone());
+        if !db::should_update_file(conn, path, modified)? {
+            continue;
+        }
+        let modified_secs = modified.duration_since(SystemTime::UNIX_EPOCH)?.as_secs() as i64;
+        if archive::is_archive(path) {
+            db::delete_archive_members(conn, &path_str)?;
+            if opts.scan_archives {
+                archives.push((path.to_path_buf(), path_str.clone(), modified_secs));
+            }
+        }
+        jobs.push(Job {
+            path: path.to_path_buf(),
+            path_str,
+            size: metadata.len(),
+            modified_secs,
+            partial: None,
+            identity: utils::file_identity(&metadata),
+            stat: utils::file_stat(&metadata),
+            via_link: entry.via_link,
+        });
+    }
+
+    // What the walk no longer lists is gone, or left out by now. An archive
+    // member goes with its archive, and whatever couldn't be read stays.
+    let member_of = format!("{}{}", archive::MEMBER_MARKER, std::path::MAIN_SEPARATOR);
+    let kept = |row: &str| {
+        listed.contains(row)
+            || row
+                .split_once(&member_of)
+                .is_some_and(|(archive, _)| listed.contains(archive))
+            || errors
+                .iter()
+                .any(|e: &scan::ScanError| Path::new(row).starts_with(&e.path))
+    };
+    for path in paths {
+        let mut rows: Vec<String> = db::files_under(conn, path)?
+            .into_iter()
+            .map(|f| f.path)
+            .collect();
+        rows.extend(db::get_file(conn, path)?.map(|f| f.path));
+        for row in rows.iter().filter(|r| !kept(r)) {
+            db::remove_file(conn, &utils::path_from_db(row))?;
+            update.removed += 1;
+        }
+        for dir in db::directories_under(conn, path)? {
+            if !kept(&dir.path) {
+                db::remove_directory(conn, &utils::path_from_db(&dir.path))?;
+            }
+        }
+    }
+
+    let mut hashed = Vec::new();
+    let mut sizes = BTreeSet::new();
+    let mut batch = db::WriteBatch::begin(conn, scan::TRANSACTION_BATCH_SIZE)?;
+    scan::hash_in_parallel(
+        jobs,
+        opts,
+        |job| scan::hash_file(&job.path, job.size, &mut job.partial, opts),
+        |job, result| {
+            batch.tick()?;
+            let hash = match result {
+                Ok(hash) => hash,
+                Err(e) => {
+                    errors.push(scan::ScanError::hashing(&job.path, &e));
+                    return Ok(());
+                }
+            };
+            let previous = db::get_file(conn, &job.path)?.map(|f| f.hash);
+            db::upsert_file(conn, &job.path, &hash, job.size as i64, job.modified_secs)?;
+            if let Some(partial) = &job.partial {
+                db::update_partial_hash(conn, &job.path, partial)?;
+            }
+            if let Some((device, inode)) = job.identity {
+                db::update_file_identity(conn, &job.path, device, inode)?;
+            }
+            if let Some(stat) = &job.stat {
+                db::update_file_stat(conn, &job.path, stat)?;
+            }
+            if job.via_link {
+                db::update_file_via_link(conn, &job.path, true)?;
+            }
+            opts.hashed(&job.path, job.size, &hash);
+            sizes.insert(job.size);
+            if previous.as_ref() != Some(&hash) {
+                update.updated += 1;
+                if job.size > 0 {
+                    hashed.push((job.path_str, hash));
+                }
+            }
+            Ok(())
+        },
+    )?;
+    if !opts.cancelled() {
+        db::init_visited_files(conn)?;
+        scan::read_archives(conn, archives, opts, &mut batch, &mut errors)?;
+    }
+    batch.commit()?;
+    let upgraded = hash_provisional(conn, &sizes, opts, &mut errors)?;
+
+    scan::rehash_directories(conn, directories, opts.hash.algorithm)?;
+    scan::rehash_ancestors(conn, paths, opts.hash.algorithm)?;
+    scan::rehash_ancestors(conn, &upgraded, opts.hash.algorithm)?;
+
+    let root_str = utils::path_to_db(root);
+    for error in &errors {
+        db::log_scan_error(
+            conn,
+            started,
+            &root_str,
+            &error.path,
+            error.kind,
+            &error.message,
+        )?;
+    }
+    update.errors.extend(errors);
+    Ok(hashed)
+}
+
+/// Hash in full the files left with a provisional hash (see
+/// `ScanOptions::prefilter`) that have one of `sizes`, so a new copy of one
+/// is found. Returns their paths.
+fn hash_provisional(
+    conn: &Connection,
+    sizes: &BTreeSet<u64>,
+    opts: &scan::ScanOptions,
+    errors: &mut Vec<scan::ScanError>,
+) -> Result<Vec<PathBuf>> {
+    let mut upgraded = Vec::new();
+    for &size in sizes {
+        for row in db::files_with_size(conn, size as i64)? {
+            let path = utils::path_from_db(&row.path);
+            // A file changed since is its own root's to pick up
+            if !hashing::is_provisional(&row.hash) || utils::mtime(&path).ok() != Some(row.modified)
+            {
+                continue;
+            }
+            let mut partial = row.partial_hash;
+            match scan::hash_file(&path, size, &mut partial, opts) {
+                Ok(hash) => {
+                    db::update_file_hash(conn, &path, &hash)?;
+                    opts.hashed(&path, size, &hash);
+                    upgraded.push(path);
+                }
+                Err(e) => errors.push(scan::ScanError::hashing(&path, &e)),
+            }
+        }
+    }
+    Ok(upgraded)
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    fn open_test_db() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        db::setup_schema(&conn).unwrap();
+        conn
+    }
+
+    fn changed(root: &Path, paths: &[&str], moves: &[(&str, &str)]) -> Vec<Changes> {
+        vec![Changes {
+            root: root.to_path_buf(),
+            paths: paths.iter().map(|p| root.join(p)).collect(),
+            moves: moves
+                .iter()
+                .map(|(from, to)| (root.join(from), root.join(to)))
+                .collect(),
+        }]
+    }
+
+    /// Options that count the files hashed
+    fn counting() -> (scan::ScanOptions, Arc<std::sync::atomic::AtomicUsize>) {
+        let count = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counted = count.clone();
+        let opts = scan::ScanOptions {
+            on_hashed: Some(scan::OnHashed(Arc::new(move |_, _, _| {
+                counted.fetch_add(1, Ordering::Relaxed);
+            }))),
+            ..Default::default()
+        };
+        (opts, count)
+    }
+
+    #[test]
+    fn test_refresh_updates_rows_and_reports_new_copies() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_path_buf();
+        fs::create_dir(root.join("sub")).unwrap();
+        fs::write(root.join("a.txt"), "original").unwrap();
+        fs::write(root.join("gone.txt"), "gone").unwrap();
+        let conn = open_test_db();
+        let opts = scan::ScanOptions::default();
+        // Never scanned, so all of it
+        let update = refresh(&conn, &changed(&root, &["sub"], &[]), &opts).unwrap();
+        assert_eq!(update.updated, 2);
+        assert!(update.duplicates.is_empty());
+        let before = db::get_directory(&conn, &root).unwrap().unwrap().hash;
+
+        fs::write(root.join("sub/copy.txt"), "original").unwrap();
+        fs::write(root.join("empty.txt"), "").unwrap();
+        fs::write(root.join("empty too.txt"), "").unwrap();
+        fs::remove_file(root.join("gone.txt")).unwrap();
+        let paths = ["empty too.txt", "empty.txt", "gone.txt", "sub/copy.txt"];
+        let update = refresh(&conn, &changed(&root, &paths, &[]), &opts).unwrap();
+        assert_eq!(update.updated, 3);
+        assert_eq!(update.removed, 1);
+        let a = root.join("a.txt").to_string_lossy().into_owned();
+        let copy = root.join("sub/copy.txt").to_string_lossy().into_owned();
+        assert_eq!(
+            update.duplicates,
+            vec![NewDuplicate {
+                path: copy.clone(),
+                copies: vec![a],
+            }]
+        );
+        assert!(db::get_file(&conn, &root.join("gone.txt"))
+            .unwrap()
+            .is_none());
+        assert!(db::get_file(&conn, Path::new(&copy)).unwrap().is_some());
+        let sub = db::get_directory(&conn, &root.join("sub"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(sub.size, 8);
+        assert_ne!(
+            db::get_directory(&conn, &root).unwrap().unwrap().hash,
+            before
+        );
+
+        // Nothing changed since
+        let update = refresh(&conn, &changed(&root, &["a.txt"], &[]), &opts).unwrap();
+        assert_eq!((update.updated, update.removed), (0, 0));
+    }
+
+    #[test]
+    fn test_refresh_reads_only_the_changed_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_path_buf();
+        fs::create_dir_all(root.join("one/deep")).unwrap();
+        fs::create_dir(root.join("two")).unwrap();
+        fs::write(root.join("one/deep/a.txt"), "a").unwrap();
+        fs::write(root.join("two/b.txt"), "b").unwrap();
+        let conn = open_test_db();
+        let (opts, hashed) = counting();
+        refresh(&conn, &changed(&root, &[""], &[]), &opts).unwrap();
+        assert_eq!(hashed.swap(0, Ordering::Relaxed), 2);
+
+        // Changes not reported are left for later; a new directory is listed
+        // whole and its ancestors rehashed
+        fs::write(root.join("two/b.txt"), "changed").unwrap();
+        fs::create_dir_all(root.join("one/new/inner")).unwrap();
+        fs::write(root.join("one/new/inner/c.txt"), "c").unwrap();
+        let update = refresh(&conn, &changed(&root, &["one/new"], &[]), &opts).unwrap();
+        assert_eq!((update.updated, hashed.load(Ordering::Relaxed)), (1, 1));
+        assert_eq!(
+            db::get_file(&conn, &root.join("two/b.txt"))
+                .unwrap()
+                .unwrap()
+                .size,
+            1
+        );
+        assert_eq!(
+            db::get_directory(&conn, &root.join("one/new/inner"))
+                .unwrap()
+                .unwrap()
+                .size,
+            1
+        );
+        assert_eq!(db::get_directory(&conn, &root).unwrap().unwrap().size, 3);
+
+        // Excluded by now: out of the database, as a scan would leave it
+        let opts = scan::ScanOptions {
+            walk: crate::walk::WalkOptions {
+                filter: crate::walk::PathFilter::new(&["inner".into()], &[], &[]).unwrap(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let update = refresh(&conn, &changed(&root, &["one/new"], &[]), &opts).unwrap();
+        assert_eq!(update.removed, 1);
+        assert!(db::get_directory(&conn, &root.join("one/new/inner"))
+            .unwrap()
+            .is_none());
+        assert_eq!(db::get_directory(&conn, &root).unwrap().unwrap().size, 2);
+    }
+
+    #[test]
+    fn test_refresh_repoints_renamed_files_and_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_path_buf();
+        fs::create_dir_all(root.join("old/sub")).unwrap();
+        fs::write(root.join("old/sub/a.txt"), "a").unwrap();
+        fs::write(root.join("b.txt"), "b").unwrap();
+        let conn = open_test_db();
+        let (opts, hashed) = counting();
+        refresh(&conn, &changed(&root, &[""], &[]), &opts).unwrap();
+        let before = db::get_directory(&conn, &root).unwrap().unwrap();
+        hashed.store(0, Ordering::Relaxed);
+
+        fs::rename(root.join("old"), root.join("new")).unwrap();
+        fs::rename(root.join("b.txt"), root.join("new/b.txt")).unwrap();
+        let changes = changed(
+            &root,
+            &["b.txt", "new", "old"],
+            &[("old", "new"), ("b.txt", "new/b.txt")],
+        );
+        let update = refresh(&conn, &changes, &opts).unwrap();
+        assert_eq!((update.moved, update.updated, update.removed), (2, 0, 0));
+        assert_eq!(hashed.load(Ordering::Relaxed), 0);
+        assert!(db::get_file(&conn, &root.join("new/sub/a.txt"))
+            .unwrap()
+            .is_some());
+        assert!(db::get_file(&conn, &root.join("new/b.txt"))
+            .unwrap()
+            .is_some());
+        assert!(db::directories_under(&conn, &root.join("old"))
+            .unwrap()
+            .is_empty());
+        let after = db::get_directory(&conn, &root).unwrap().unwrap();
+        assert_eq!(after.size, before.size);
+        assert_ne!(after.hash, before.hash);
+    }
+
+    #[test]
+    fn test_changes_are_split_by_root_and_pruned() {
+        let paths: HashSet<PathBuf> = ["/r/a", "/r/a/b", "/r/a-b", "/s/c", "/t"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        let moves = [
+            (PathBuf::from("/r/a/b"), PathBuf::from("/r/a-b")),
+            (PathBuf::from("/r/x"), PathBuf::from("/s/x")),
+        ];
+        let changes = changes_under(Path::new("/r"), &paths, &moves).unwrap();
+        assert_eq!(
+            changes.paths,
+            [PathBuf::from("/r/a"), PathBuf::from("/r/a-b")]
+        );
+        assert_eq!(changes.moves, moves[..1]);
+        assert!(changes_under(Path::new("/u"), &paths, &moves).is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_watcher_reports_changes_in_new_and_moved_directories() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().to_path_buf();
+        let other = tempfile::tempdir().unwrap();
+        let mut watcher = Watcher::new(&[&root, other.path()]).unwrap();
+        let cancel = AtomicBool::new(false);
+        let settle = Duration::from_millis(50);
+
+        fs::create_dir(root.join("new")).unwrap();
+        let changes = watcher.wait(settle, &cancel).unwrap().unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].paths, [root.join("new")]);
+        fs::write(root.join("new/file.txt"), "data").unwrap();
+        let changes = watcher.wait(settle, &cancel).unwrap().unwrap();
+        assert_eq!(changes[0].paths, [root.join("new/file.txt")]);
+
+        // Changes inside a moved directory are reported under its new path
+        fs::rename(root.join("new"), root.join("moved")).unwrap();
+        let changes = watcher.wait(settle, &cancel).unwrap().unwrap();
+        assert_eq!(changes[0].paths, [root.join("moved"), root.join("new")]);
+        assert_eq!(changes[0].moves, [(root.join("new"), root.join("moved"))]);
+        fs::write(root.join("moved/file.txt"), "more").unwrap();
+        let changes = watcher.wait(settle, &cancel).unwrap().unwrap();
+        assert_eq!(changes[0].paths, [root.join("moved/file.txt")]);
+
+        cancel.store(true, Ordering::Relaxed);
+        assert_eq!(watcher.wait(settle, &cancel).unwrap(), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_watcher_names_changes_as_a_relative_root_was_given() {
+        let tmp = tempfile::tempdir_in(".").unwrap();
+        let root = PathBuf::from(tmp.path().file_name().unwrap());
+        assert!(root.is_relative());
+        fs::write(root.join("a.txt"), "before").unwrap();
+        let conn = open_test_db();
+        let opts = scan::ScanOptions::default();
+        refresh(&conn, &changed(&root, &[""], &[]), &opts).unwrap();
+        let before = db::get_file(&conn, &root.join("a.txt")).unwrap().unwrap();
+
+        let mut watcher = Watcher::new(&[&root]).unwrap();
+        // A minute on, as the same second would look unchanged
+        let mut file = fs::File::create(root.join("a.txt")).unwrap();
+        std::io::Write::write_all(&mut file, b"after, and longer").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(before.modified as u64 + 60);
+        file.set_modified(mtime).unwrap();
+        drop(file);
+        let cancel = AtomicBool::new(false);
+        let changes = watcher
+            .wait(Duration::from_millis(50), &cancel)
+            .unwrap()
+            .unwrap();
+        assert_eq!(changes[0].paths, [root.join("a.txt")]);
+        let update = refresh(&conn, &changes, &opts).unwrap();
+        assert_eq!(update.updated, 1);
+        let after = db::get_file(&conn, &root.join("a.txt")).unwrap().unwrap();
+        assert_ne!(after.hash, before.hash);
+        assert_eq!(after.size, 17);
+    }
+}
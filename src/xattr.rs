@@ -0,0 +1,240 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Result;
+
+use crate::file_system;
+use crate::hashing::{self, HashAlgorithm};
+
+/// The extended attribute a file's hash is cached in.
+const NAME: &str = "user.deduplifier";
+
+/// Whether files here can carry the attribute at all.
+pub const SUPPORTED: bool = cfg!(any(target_os = "linux", target_os = "macos"));
+
+/// What a cached hash is only trusted for: the size and modification time,
+/// to the nanosecond, that the file had when it was hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    modified_ns: u128,
+}
+
+impl Stamp {
+    fn of(metadata: &fs::Metadata) -> Option<Self> {
+        let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(Self {
+            size: metadata.len(),
+            modified_ns: modified.as_nanos(),
+        })
+    }
+}
+
+/// The attribute's value: `<algorithm> <size> <mtime in ns> <hash>`.
+fn format_value(algorithm: HashAlgorithm, stamp: Stamp, hash: &str) -> String {
+    format!(
+        "{} {} {} {hash}",
+        algorithm.name(),
+        stamp.size,
+        stamp.modified_ns
+    )
+}
+
+/// The stamp and hash in `value`, if it holds an `algorithm` hash.
+fn parse_value(value: &str, algorithm: HashAlgorithm) -> Option<(Stamp, &str)> {
+    let mut fields = value.split(' ');
+    if fields.next()? != algorithm.name() {
+        return None;
+    }
+    let stamp = Stamp {
+        size: fields.next()?.parse().ok()?,
+        modified_ns: fields.next()?.parse().ok()?,
+    };
+    let hash = fields.next().filter(|h| !h.is_empty())?;
+    match fields.next() {
+        None => Some((stamp, hash)),
+        Some(_) => None,
+    }
+}
+
+/// The `algorithm` hash an earlier scan cached on `path`, as long as the
+/// file's size and modification time are still those in `metadata`.
+fn cached_hash(path: &Path, metadata: &fs::Metadata, algorithm: HashAlgorithm) -> Option<String> {
+    let stamp = Stamp::of(metadata)?;
+    let value = sys::get(path)?;
+    let (cached, hash) = parse_value(std::str::from_utf8(&value).ok()?, algorithm)?;
+    (cached == stamp).then(|| hash.to_string())
+}
+
+/// Cache `hash` on `path`, taken while the file's metadata was `metadata`.
+/// An attribute that already says the same is left as it is, and in a dry
+/// run none is written.
+pub fn store_hash(
+    path: &Path,
+    metadata: &fs::Metadata,
+    algorithm: HashAlgorithm,
+    hash: &str,
+) -> io::Result<()> {
+    let Some(stamp) = Stamp::of(metadata) else {
+        return Ok(());
+    };
+    if file_system::dry_run() {
+        return Ok(());
+    }
+    let value = format_value(algorithm, stamp, hash);
+    if sys::get(path).is_some_and(|v| v == value.as_bytes()) {
+        return Ok(());
+    }
+    sys::set(path, value.as_bytes())
+}
+
+/// `hashing::compute_file_hash`, but read from the attribute when it is still
+/// current and written to it once computed. A filesystem without user
+/// attributes, or a file that can't be written, just goes without.
+pub fn hash_file(path: &Path, opts: &hashing::HashOptions) -> Result<String> {
+    // Taken before reading, so a file written to mid-hash isn't stamped
+    // with its new modification time
+    let metadata = fs::metadata(path)?;
+    if let Some(hash) = cached_hash(path, &metadata, opts.algorithm) {
+        return Ok(hash);
+    }
+    let hash = hashing::compute_file_hash(path, opts)?;
+    let _ = store_hash(path, &metadata, opts.algorithm, &hash);
+    Ok(hash)
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    /// Longer than any value `format_value` writes.
+    const MAX_VALUE: usize = 256;
+
+    fn c_strings(path: &Path) -> io::Result<(CString, CString)> {
+        let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let path = CString::new(path.as_os_str().as_bytes()).map_err(invalid)?;
+        let name = CString::new(super::NAME).map_err(invalid)?;
+        Ok((path, name))
+    }
+
+    pub fn get(path: &Path) -> Option<Vec<u8>> {
+        let (path, name) = c_strings(path).ok()?;
+        let mut value = vec![0u8; MAX_VALUE];
+        let (p, n, v, len) = (
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr(),
+            value.len(),
+        );
+        #[cfg(target_os = "linux")]
+        let read = unsafe { libc::getxattr(p, n, v.cast(), len) };
+        #[cfg(target_os = "macos")]
+        let read = unsafe { libc::getxattr(p, n, v.cast(), len, 0, 0) };
+        // -1 covers a missing attribute, no support and a value too long
+        let read = usize::try_from(read).ok()?;
+        value.truncate(read);
+        Some(value)
+    }
+
+    pub fn set(path: &Path, value: &[u8]) -> io::Result<()> {
+        let (path, name) = c_strings(path)?;
+        let (p, n, v, len) = (path.as_ptr(), name.as_ptr(), value.as_ptr(), value.len());
+        #[cfg(target_os = "linux")]
+        let rc = unsafe { libc::setxattr(p, n, v.cast(), len, 0) };
+        #[cfg(target_os = "macos")]
+        let rc = unsafe { libc::setxattr(p, n, v.cast(), len, 0, 0) };
+        if rc == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    pub fn get(_path: &Path) -> Option<Vec<u8>> {
+        None
+    }
+
+    pub fn set(_path: &Path, _value: &[u8]) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+// ------------------------------------------------------------------
+//
+//
+// TESTS
+//
+//
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_round_trips_for_its_algorithm_only() {
+        let stamp = Stamp {
+            size: 5,
+            modified_ns: 1_700_000_000_123_456_789,
+        };
+        let value = format_value(HashAlgorithm::Blake3, stamp, "abc");
+        assert_eq!(value, "blake3 5 1700000000123456789 abc");
+        assert_eq!(
+            parse_value(&value, HashAlgorithm::Blake3),
+            Some((stamp, "abc"))
+        );
+        assert_eq!(parse_value(&value, HashAlgorithm::Sha256), None);
+        assert_eq!(parse_value("blake3 5 x abc", HashAlgorithm::Blake3), None);
+        assert_eq!(parse_value("blake3 5 1", HashAlgorithm::Blake3), None);
+        assert_eq!(parse_value("blake3 5 1 a b", HashAlgorithm::Blake3), None);
+    }
+
+    #[test]
+    fn test_hash_file_trusts_the_attribute_until_the_file_changes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("a.txt");
+        fs::write(&path, "hello").unwrap();
+        let metadata = fs::metadata(&path).unwrap();
+        let opts = hashing::HashOptions::default();
+        if store_hash(&path, &metadata, opts.algorithm, "cached").is_err() {
+            // No user attributes on this filesystem (or platform)
+            return;
+        }
+        assert_eq!(hash_file(&path, &opts).unwrap(), "cached");
+
+        fs::write(&path, "changed").unwrap();
+        let rehashed = hash_file(&path, &opts).unwrap();
+        assert_eq!(rehashed, hashing::compute_file_hash(&path, &opts).unwrap());
+        // The fresh hash is cached in turn
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(
+            cached_hash(&path, &metadata, opts.algorithm),
+            Some(rehashed)
+        );
+        assert_eq!(cached_hash(&path, &metadata, HashAlgorithm::Xxh3), None);
+    }
+
+    #[test]
+    fn test_dry_run_writes_no_attribute() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("a.txt");
+        fs::write(&path, "hello").unwrap();
+        let opts = hashing::HashOptions::default();
+
+        file_system::set_dry_run(true);
+        let hash = hash_file(&path, &opts);
+        file_system::set_dry_run(false);
+        assert_eq!(hash.unwrap(), hashing::compute_file_hash(&path, &opts).unwrap());
+        assert_eq!(sys::get(&path), None);
+    }
+}